@@ -1,52 +1,48 @@
 #[macro_use]
 extern crate criterion;
 
-use criterion::{BatchSize, Criterion, ParameterizedBenchmark};
+use criterion::{BatchSize, BenchmarkId, Criterion};
 use kvs::{KvStore, KvsEngine, SledKvsEngine};
 use rand::prelude::*;
-use std::iter;
 use tempfile::TempDir;
 
 fn set_bench(c: &mut Criterion) {
-    let bench = ParameterizedBenchmark::new(
-        "kvs",
-        |b, _| {
-            b.iter_batched(
-                || {
-                    let temp_dir = TempDir::new().unwrap();
-                    (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
-                },
-                |(mut store, _temp_dir)| {
-                    for i in 1..(1 << 12) {
-                        store.set(format!("key{}", i), "value".to_string());
-                    }
-                },
-                BatchSize::SmallInput,
-            )
-        },
-        iter::once(()),
-    )
-        .with_function("sled", |b, _| {
-            b.iter_batched(
-                || {
-                    let temp_dir = TempDir::new().unwrap();
-                    (SledKvsEngine::open(&temp_dir.path()).unwrap(), temp_dir)
-                },
-                |(mut db, _temp_dir)| {
-                    for i in 1..(1 << 12) {
-                        db.set(format!("key{}", i), "value".to_string()).unwrap();
-                    }
-                },
-                BatchSize::SmallInput,
-            )
-        });
-    c.bench("set_bench", bench);
+    let mut group = c.benchmark_group("set_bench");
+    group.bench_function("kvs", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(mut store, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    store.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("sled", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (SledKvsEngine::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(mut db, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    db.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
 }
 
 fn get_bench(c: &mut Criterion) {
-    let bench = ParameterizedBenchmark::new(
-        "kvs",
-        |b, i| {
+    let mut group = c.benchmark_group("get_bench");
+    for i in &[8, 12, 16, 20] {
+        group.bench_with_input(BenchmarkId::new("kvs", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
             let mut store = KvStore::open(temp_dir.path()).unwrap();
             for key_i in 1..(1 << i) {
@@ -60,10 +56,8 @@ fn get_bench(c: &mut Criterion) {
                     .get(format!("key{}", rng.gen_range(1, 1 << i)))
                     .unwrap();
             })
-        },
-        vec![8, 12, 16, 20],
-    )
-        .with_function("sled", |b, i| {
+        });
+        group.bench_with_input(BenchmarkId::new("sled", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
             let mut db = SledKvsEngine::open(temp_dir.path()).unwrap();
             for key_i in 1..(1 << i) {
@@ -75,8 +69,9 @@ fn get_bench(c: &mut Criterion) {
                 db.get(format!("key{}", rng.gen_range(1, 1 << i))).unwrap();
             })
         });
-    c.bench("get_bench", bench);
+    }
+    group.finish();
 }
 
 criterion_group!(benches, set_bench, get_bench);
-criterion_main!(benches);
\ No newline at end of file
+criterion_main!(benches);
@@ -0,0 +1,19 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    grpc();
+}
+
+/// Generates the gRPC service and client from `proto/kvs.proto`, with the
+/// `protoc` vendored as a build dependency.
+#[cfg(feature = "grpc")]
+fn grpc() {
+    let protoc = protoc_bin_vendored::protoc_bin_path().expect("no protoc for this platform");
+    std::env::set_var("PROTOC", protoc);
+    println!("cargo:rerun-if-changed=proto/kvs.proto");
+    // `connect` needs the prelude of edition 2021; clients connect an
+    // `Endpoint` themselves
+    tonic_prost_build::configure()
+        .build_transport(false)
+        .compile_protos(&["proto/kvs.proto"], &["proto"])
+        .expect("unable to compile proto/kvs.proto");
+}
@@ -0,0 +1,270 @@
+//! Per-token access control lists.
+//!
+//! An ACL file lists, for each token, the operations it may perform and the
+//! key prefixes it may touch:
+//!
+//! ```toml
+//! [[token]]
+//! name = "reporting"
+//! token = "reader-secret"
+//! operations = ["read"]
+//! prefixes = ["app1:*"]
+//! rate_limit = 100
+//!
+//! [[token]]
+//! token = "admin-secret"
+//! operations = ["read", "write", "admin"]
+//! prefixes = ["*"]
+//! ```
+//!
+//! A trailing `*` in a prefix is optional; `"*"` or `""` allows every key.
+//! `rate_limit`, if given, caps the requests per second of the token, all
+//! its connections together. `name`, if given, identifies the token in the
+//! audit log; entries without one are known as `token #1`, `token #2`...
+//! `tenant`, if given, confines the token to the store of that tenant, see
+//! `Server::with_tenants`.
+use crate::common::Request;
+use crate::errors::{MyError, Result};
+use crate::ratelimit::RateLimiter;
+
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Kind of access a request needs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// Reading values or following changes.
+    Read,
+    /// Setting or removing keys.
+    Write,
+    /// Server-wide operations such as replication.
+    Admin,
+}
+
+/// What one token is allowed to do.
+#[derive(Debug)]
+pub struct Rule {
+    name: String,
+    token: String,
+    operations: Vec<Operation>,
+    prefixes: Vec<String>,
+    limiter: Option<RateLimiter>,
+    tenant: Option<String>,
+}
+
+impl Rule {
+    /// Whether this rule permits `operation` on keys starting with `key`.
+    pub fn allows(&self, operation: Operation, key: &str) -> bool {
+        self.operations.contains(&operation)
+            && self
+                .prefixes
+                .iter()
+                .any(|prefix| key.starts_with(prefix.as_str()))
+    }
+
+    /// Name of the token, as recorded in the audit log.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Tenant the token is confined to, if any.
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    /// Takes one request off the rate limit of the token, returning whether
+    /// it may be served.
+    pub(crate) fn try_acquire(&self) -> bool {
+        self.limiter
+            .as_ref()
+            .is_none_or(|limiter| limiter.try_acquire())
+    }
+}
+
+/// The rules loaded from an ACL file.
+#[derive(Debug, Default)]
+pub struct Acl {
+    rules: Vec<Arc<Rule>>,
+}
+
+impl Acl {
+    /// Loads the ACL file at `path`.
+    pub fn load(path: &Path) -> Result<Acl> {
+        Acl::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parses the TOML content of an ACL file.
+    pub fn parse(input: &str) -> Result<Acl> {
+        let root: toml::Table = toml::from_str(input).map_err(|e| acl_error(&e.to_string()))?;
+        let entries = match root.get("token") {
+            Some(value) => value
+                .as_array()
+                .ok_or_else(|| acl_error("`token` must be an array of tables"))?
+                .clone(),
+            None => Vec::new(),
+        };
+
+        let mut rules = Vec::new();
+        for (index, entry) in entries.into_iter().enumerate() {
+            let entry = entry
+                .as_table()
+                .ok_or_else(|| acl_error("`token` must be an array of tables"))?;
+            let token = entry
+                .get("token")
+                .and_then(toml::Value::as_str)
+                .ok_or_else(|| acl_error("every entry needs a `token` string"))?
+                .to_owned();
+            let name = match entry.get("name") {
+                Some(value) => value
+                    .as_str()
+                    .ok_or_else(|| acl_error("`name` must be a string"))?
+                    .to_owned(),
+                None => format!("token #{}", index + 1),
+            };
+            let operations = strings(entry.get("operations"), "operations")?
+                .iter()
+                .map(|op| match op.as_str() {
+                    "read" => Ok(Operation::Read),
+                    "write" => Ok(Operation::Write),
+                    "admin" => Ok(Operation::Admin),
+                    other => Err(acl_error(&format!("unknown operation `{}`", other))),
+                })
+                .collect::<Result<_>>()?;
+            let prefixes = strings(entry.get("prefixes"), "prefixes")?
+                .into_iter()
+                .map(|prefix| prefix.trim_end_matches('*').to_owned())
+                .collect();
+            let limiter = match entry.get("rate_limit") {
+                Some(value) => Some(RateLimiter::new(
+                    value
+                        .as_integer()
+                        .and_then(|rate| u32::try_from(rate).ok())
+                        .filter(|rate| *rate > 0)
+                        .ok_or_else(|| acl_error("`rate_limit` must be a positive integer"))?,
+                )),
+                None => None,
+            };
+            let tenant = match entry.get("tenant") {
+                Some(value) => Some(
+                    value
+                        .as_str()
+                        .ok_or_else(|| acl_error("`tenant` must be a string"))?
+                        .to_owned(),
+                ),
+                None => None,
+            };
+            rules.push(Arc::new(Rule {
+                name,
+                token,
+                operations,
+                prefixes,
+                limiter,
+                tenant,
+            }));
+        }
+        Ok(Acl { rules })
+    }
+
+    /// The rule of `token`, if it is listed.
+    pub fn rule_for(&self, token: &str) -> Option<Arc<Rule>> {
+        self.rules
+            .iter()
+            .find(|rule| constant_time_eq(rule.token.as_bytes(), token.as_bytes()))
+            .cloned()
+    }
+}
+
+/// The operation `req` performs and the keys (or key prefix) it touches.
+pub fn required(req: &Request) -> (Operation, Vec<&str>) {
+    let operation = match req {
+        Request::Get { .. }
+        | Request::GetPath { .. }
+        | Request::LRange { .. }
+        | Request::HGet { .. }
+        | Request::HGetAll { .. }
+        | Request::SIsMember { .. }
+        | Request::SMembers { .. }
+        | Request::ZRangeByScore { .. }
+        | Request::Exists { .. }
+        | Request::DbSize
+        | Request::Keys { .. }
+        | Request::Export { .. }
+        | Request::Scan { .. }
+        | Request::Ttl { .. }
+        | Request::Metadata { .. }
+        | Request::GetMany { .. }
+        | Request::Watch { .. }
+        | Request::Subscribe { .. }
+        | Request::Select { .. }
+        | Request::FindByIndex { .. } => Operation::Read,
+        Request::Set { .. }
+        | Request::Remove { .. }
+        | Request::SetPath { .. }
+        | Request::LPush { .. }
+        | Request::RPush { .. }
+        | Request::LPop { .. }
+        | Request::HSet { .. }
+        | Request::HDel { .. }
+        | Request::SAdd { .. }
+        | Request::SRem { .. }
+        | Request::ZAdd { .. }
+        | Request::Rename { .. }
+        | Request::GetSet { .. }
+        | Request::GetDel { .. }
+        | Request::SetNx { .. }
+        | Request::SetIfVersion { .. }
+        | Request::RunScript { .. }
+        | Request::Expire { .. }
+        | Request::Persist { .. }
+        | Request::Restore { .. }
+        | Request::Touch { .. }
+        | Request::AcquireLock { .. }
+        | Request::ReleaseLock { .. }
+        | Request::SetMany { .. }
+        | Request::RemoveMany { .. } => Operation::Write,
+        Request::Hello { .. }
+        | Request::Sync
+        | Request::SyncFrom { .. }
+        | Request::Auth { .. }
+        | Request::Stats
+        | Request::SlowLog
+        | Request::Ping
+        | Request::FlushAll { .. }
+        | Request::PurgeTrash
+        | Request::Compact
+        | Request::ReloadConfig
+        | Request::Shutdown
+        | Request::Drain => Operation::Admin,
+        Request::Traced { request, .. } | Request::Tagged { request, .. } => {
+            return required(request)
+        }
+    };
+    (operation, req.keys())
+}
+
+/// Compares two byte strings in time independent of where they differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn strings(value: Option<&toml::Value>, name: &str) -> Result<Vec<String>> {
+    let values = match value {
+        Some(value) => value
+            .as_array()
+            .ok_or_else(|| acl_error(&format!("`{}` must be an array of strings", name)))?,
+        None => return Ok(Vec::new()),
+    };
+    values
+        .iter()
+        .map(|v| {
+            v.as_str()
+                .map(str::to_owned)
+                .ok_or_else(|| acl_error(&format!("`{}` must be an array of strings", name)))
+        })
+        .collect()
+}
+
+fn acl_error(msg: &str) -> MyError {
+    MyError::StringError(format!("Invalid ACL: {}", msg))
+}
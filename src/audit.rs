@@ -0,0 +1,210 @@
+//! Append-only record of the writes clients made, for deployments that
+//! have to account for every change.
+use crate::acl::{self, Operation};
+use crate::common::Request;
+use crate::engine::unix_millis;
+use crate::{MyError, Result};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Size from which the audit log is rotated, unless set otherwise.
+const MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Rotated audit logs kept, unless set otherwise.
+const MAX_FILES: usize = 5;
+
+/// One write a client made, as a line of JSON in the audit log.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// When the write was answered, in milliseconds since the UNIX epoch.
+    pub at_ms: u64,
+    /// Who made it: the name of its ACL token, `server token` for the
+    /// token of the server, or `anonymous` when the server requires none.
+    pub identity: String,
+    /// Address of the client.
+    pub peer: String,
+    /// Tenant the write went to, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Bucket the write went to.
+    pub bucket: String,
+    /// Type of the request, e.g. `Set`.
+    pub request: String,
+    /// Keys the request wrote.
+    pub keys: Vec<String>,
+    /// Values it wrote, unless the log redacts them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<String>>,
+    /// Why the write failed, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A file the server appends an `AuditRecord` to for every write, renamed
+/// to `PATH.1` once it reaches a size, `PATH.1` to `PATH.2` and so on.
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    redact_values: bool,
+    file: Mutex<(File, u64)>,
+}
+
+impl AuditLog {
+    /// Appends to the audit log at `path`, creating it if needed.
+    pub fn open(path: impl Into<PathBuf>) -> Result<AuditLog> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        let len = file.metadata().map_err(MyError::file(&path))?.len();
+        Ok(AuditLog {
+            path,
+            max_bytes: MAX_BYTES,
+            max_files: MAX_FILES,
+            redact_values: false,
+            file: Mutex::new((file, len)),
+        })
+    }
+
+    /// Rotate the log once it reaches `bytes`, rather than 64 MiB.
+    pub fn with_max_bytes(mut self, bytes: u64) -> Self {
+        self.max_bytes = bytes;
+        self
+    }
+
+    /// Keep `files` rotated logs, rather than 5; older ones are removed.
+    pub fn with_max_files(mut self, files: usize) -> Self {
+        self.max_files = files;
+        self
+    }
+
+    /// Leave the values written out of the records.
+    pub fn with_redacted_values(mut self) -> Self {
+        self.redact_values = true;
+        self
+    }
+
+    /// Starts the record of `req` by `identity` from `peer` in `bucket` of
+    /// `tenant`, if it writes; it is appended once `req` is answered.
+    pub(crate) fn start(
+        self: &Arc<Self>,
+        req: &Request,
+        identity: &str,
+        peer: &str,
+        tenant: Option<&str>,
+        bucket: &str,
+    ) -> Option<Audit> {
+        let writes = acl::required(req).0 == Operation::Write;
+        if !writes && req.kind() != "FlushAll" {
+            return None;
+        }
+        let record = AuditRecord {
+            at_ms: 0,
+            identity: identity.to_owned(),
+            peer: peer.to_owned(),
+            tenant: tenant.map(str::to_owned),
+            bucket: bucket.to_owned(),
+            request: req.kind().to_owned(),
+            keys: req.keys().into_iter().map(str::to_owned).collect(),
+            values: match self.redact_values {
+                true => None,
+                false => Some(values(req)),
+            },
+            error: None,
+        };
+        Some(Audit {
+            log: Arc::clone(self),
+            record,
+        })
+    }
+
+    fn write(&self, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        if file.1 > 0 && file.1 + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+            *file = (open_append(&self.path)?, 0);
+        }
+        file.0.write_all(&line).map_err(MyError::file(&self.path))?;
+        file.1 += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shifts the rotated logs up by one, dropping the oldest, and moves
+    /// the log to `PATH.1`.
+    fn rotate(&self) -> Result<()> {
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        let oldest = rotated(self.max_files);
+        if self.max_files == 0 || oldest.exists() {
+            let removed = if self.max_files == 0 {
+                &self.path
+            } else {
+                &oldest
+            };
+            fs::remove_file(removed).map_err(MyError::file(removed))?;
+        }
+        for n in (1..self.max_files).rev() {
+            let from = rotated(n);
+            if from.exists() {
+                fs::rename(&from, rotated(n + 1)).map_err(MyError::file(&from))?;
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, rotated(1)).map_err(MyError::file(&self.path))?;
+        }
+        Ok(())
+    }
+}
+
+/// The record of a write being handled.
+pub(crate) struct Audit {
+    log: Arc<AuditLog>,
+    record: AuditRecord,
+}
+
+impl Audit {
+    /// Appends the record, with `error` if the write failed, rotating the
+    /// log if it is full. Failures are logged, the write being done.
+    pub(crate) fn finish(mut self, error: Option<String>) {
+        self.record.at_ms = unix_millis();
+        self.record.error = error;
+        if let Err(e) = self.log.write(&self.record) {
+            error!("Auditing {} failed: {}", self.record.request, e);
+        }
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(MyError::file(path))
+}
+
+/// The values `req` writes.
+fn values(req: &Request) -> Vec<String> {
+    match req {
+        Request::Set { value, .. }
+        | Request::SetPath { value, .. }
+        | Request::LPush { value, .. }
+        | Request::RPush { value, .. }
+        | Request::HSet { value, .. } => vec![value.clone()],
+        Request::SAdd { member, .. }
+        | Request::SRem { member, .. }
+        | Request::ZAdd { member, .. } => {
+            vec![member.clone()]
+        }
+        Request::SetMany { pairs } => pairs.iter().map(|(_, value)| value.clone()).collect(),
+        Request::Traced { request, .. } | Request::Tagged { request, .. } => values(request),
+        _ => Vec::new(),
+    }
+}
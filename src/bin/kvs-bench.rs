@@ -0,0 +1,327 @@
+use kvs::{
+    KvStore, KvStoreOptions, KvsClient, KvsEngine, KvsReader, LsmEngine, MemEngine, MyError,
+    Result, SledKvsEngine, SyncPolicy, WriteBatch, WriteBuffer,
+};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use structopt::clap::arg_enum;
+use structopt::StructOpt;
+
+const ADDRESS_FORMAT: &str = "IP:PORT";
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-bench",
+    about = "Measures the throughput and latency of a workload"
+)]
+struct Opt {
+    #[structopt(
+    long = "addr",
+    help = "Benchmarks the server at this address instead of an embedded engine",
+    value_name = ADDRESS_FORMAT,
+    parse(try_from_str)
+    )]
+    addr: Option<SocketAddr>,
+    #[structopt(long, help = "Sets the embedded storage engine", value_name = "ENGINE-NAME",
+    possible_values = &Engine::variants(), case_insensitive = true, default_value = "kvs")]
+    engine: Engine,
+    #[structopt(
+        long = "data-dir",
+        help = "Stores the embedded engine's data in this directory [default: a temporary one]",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    data_dir: Option<PathBuf>,
+    #[structopt(
+        long = "sync-policy",
+        help = "When the kvs engine forces writes to disk: never or always",
+        value_name = "POLICY",
+        default_value = "never"
+    )]
+    sync_policy: SyncPolicy,
+    #[structopt(
+        long = "write-buffer",
+        help = "Bytes of the kvs engine's write buffer, or adaptive",
+        value_name = "BYTES",
+        default_value = "8192"
+    )]
+    write_buffer: WriteBuffer,
+    #[structopt(
+        long = "read-buffer",
+        help = "Bytes of the kvs engine's read buffers",
+        value_name = "BYTES",
+        default_value = "8192"
+    )]
+    read_buffer: usize,
+    #[structopt(
+        long = "load-batch",
+        help = "Number of keys loaded in each batch before the workload",
+        value_name = "N",
+        default_value = "1"
+    )]
+    load_batch: usize,
+    #[structopt(
+        long = "ops",
+        help = "Number of operations to run",
+        value_name = "N",
+        default_value = "10000"
+    )]
+    ops: u64,
+    #[structopt(
+        long = "keys",
+        help = "Number of distinct keys",
+        value_name = "N",
+        default_value = "1000"
+    )]
+    keys: u64,
+    #[structopt(
+        long = "value-size",
+        help = "Size of the values written, in bytes",
+        value_name = "BYTES",
+        default_value = "100"
+    )]
+    value_size: usize,
+    #[structopt(
+        long = "read-percent",
+        help = "Share of the operations that are reads",
+        value_name = "PERCENT",
+        default_value = "50"
+    )]
+    read_percent: u64,
+    #[structopt(
+        long = "threads",
+        help = "Number of threads, each with a connection of its own",
+        value_name = "N",
+        default_value = "4"
+    )]
+    threads: u64,
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Engine {
+        kvs,
+        sled,
+        lsm,
+        memory
+    }
+}
+
+/// An operation of the workload.
+enum Op {
+    Get(String),
+    Set(String, String),
+    /// Sets every pair in one batch, to load the keys.
+    Load(Vec<(String, String)>),
+}
+
+/// Runs operations against the store under test; one per thread.
+type Worker = Box<dyn FnMut(Op) -> Result<()> + Send>;
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    if opt.read_percent > 100
+        || opt.threads == 0
+        || opt.keys == 0
+        || opt.load_batch == 0
+        || opt.read_buffer == 0
+    {
+        return Err(MyError::StringError(
+            "Expected a read share of at most 100%, at least one thread, key and key per \
+             batch, and a read buffer"
+                .to_owned(),
+        ));
+    }
+    if let Some(addr) = opt.addr {
+        return bench(&opt, || {
+            let mut client = KvsClient::connect(addr)?;
+            Ok(Box::new(move |op| match op {
+                Op::Get(key) => client.get(key).map(drop),
+                Op::Set(key, value) => client.set(key, value),
+                Op::Load(pairs) => client.set_many(pairs),
+            }) as Worker)
+        });
+    }
+    let (dir, temporary) = match &opt.data_dir {
+        Some(dir) => (dir.clone(), false),
+        None => (
+            std::env::temp_dir().join(format!("kvs-bench-{}", std::process::id())),
+            true,
+        ),
+    };
+    let result = match opt.engine {
+        Engine::kvs => {
+            let options = KvStoreOptions::default()
+                .with_sync_policy(opt.sync_policy)
+                .with_write_buffer(opt.write_buffer)
+                .with_read_buffer(opt.read_buffer);
+            bench_engine(&opt, KvStore::open_with_options(&dir, options)?)
+        }
+        Engine::sled => bench_engine(&opt, SledKvsEngine::open(&dir)?),
+        Engine::lsm => bench_engine(&opt, LsmEngine::open(&dir)?),
+        Engine::memory => bench_engine(&opt, MemEngine::new()),
+    };
+    if temporary && dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    result
+}
+
+/// Benchmarks an embedded engine as the server uses it: writes share it
+/// under a lock and then its syncs, and reads go through its reader.
+fn bench_engine<E: KvsEngine + Send + 'static>(opt: &Opt, mut engine: E) -> Result<()> {
+    let reader = engine.reader();
+    let commit = engine.defer_syncs()?;
+    let engine = Arc::new(Mutex::new(engine));
+    bench(opt, || {
+        let reader = reader.clone();
+        let commit = commit.clone();
+        let engine = Arc::clone(&engine);
+        Ok(Box::new(move |op| match op {
+            Op::Get(key) => reader.get(key).map(drop),
+            Op::Set(key, value) => {
+                engine.lock().unwrap().set(key, value)?;
+                commit.as_ref().map_or(Ok(()), |commit| commit.sync())
+            }
+            Op::Load(pairs) => {
+                let mut batch = WriteBatch::new();
+                for (key, value) in pairs {
+                    batch.set(key, value);
+                }
+                engine.lock().unwrap().write_batch(batch)?;
+                commit.as_ref().map_or(Ok(()), |commit| commit.sync())
+            }
+        }) as Worker)
+    })?;
+    // only the kvs engine has a write buffer
+    let stats = engine.lock().unwrap().stats()?;
+    if stats.write_buffer_bytes > 0 {
+        println!("write buffer: {} bytes", stats.write_buffer_bytes);
+    }
+    Ok(())
+}
+
+/// Loads every key, then runs the workload on `opt.threads` workers and
+/// prints the results.
+fn bench(opt: &Opt, mut worker: impl FnMut() -> Result<Worker>) -> Result<()> {
+    let value = "x".repeat(opt.value_size);
+    let mut loader = worker()?;
+    let started = Instant::now();
+    let keys: Vec<u64> = (0..opt.keys).collect();
+    for batch in keys.chunks(opt.load_batch) {
+        let op = match batch {
+            [key] => Op::Set(format!("key{}", key), value.clone()),
+            keys => Op::Load(
+                keys.iter()
+                    .map(|key| (format!("key{}", key), value.clone()))
+                    .collect(),
+            ),
+        };
+        loader(op)?;
+    }
+    println!(
+        "load: {} keys in {:.3}s",
+        opt.keys,
+        started.elapsed().as_secs_f64()
+    );
+
+    let started = Instant::now();
+    let threads = (0..opt.threads)
+        .map(|thread| {
+            let mut worker = worker()?;
+            // the remainder goes to the first threads
+            let ops = opt.ops / opt.threads + u64::from(thread < opt.ops % opt.threads);
+            let (keys, read_percent, value) = (opt.keys, opt.read_percent, value.clone());
+            let mut rng = Rng::new(thread);
+            Ok(thread::spawn(move || -> Result<(Vec<Duration>, u64)> {
+                let mut latencies = Vec::with_capacity(ops as usize);
+                let mut reads = 0;
+                for _ in 0..ops {
+                    let key = format!("key{}", rng.next() % keys);
+                    let op = if rng.next() % 100 < read_percent {
+                        reads += 1;
+                        Op::Get(key)
+                    } else {
+                        Op::Set(key, value.clone())
+                    };
+                    let op_started = Instant::now();
+                    worker(op)?;
+                    latencies.push(op_started.elapsed());
+                }
+                Ok((latencies, reads))
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut latencies = Vec::with_capacity(opt.ops as usize);
+    let mut reads = 0;
+    for thread in threads {
+        let (thread_latencies, thread_reads) = thread
+            .join()
+            .map_err(|_| MyError::StringError("A benchmark thread panicked".to_owned()))??;
+        latencies.extend(thread_latencies);
+        reads += thread_reads;
+    }
+    let elapsed = started.elapsed();
+    latencies.sort();
+
+    println!(
+        "operations: {} ({} reads, {} writes) in {:.3}s",
+        latencies.len(),
+        reads,
+        latencies.len() as u64 - reads,
+        elapsed.as_secs_f64()
+    );
+    println!(
+        "throughput: {:.0} ops/s",
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "latency: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        percentile(&latencies, 50),
+        percentile(&latencies, 90),
+        percentile(&latencies, 99),
+        latencies.last().copied().unwrap_or_default()
+    );
+    Ok(())
+}
+
+/// The `p`th percentile of the sorted `latencies`.
+fn percentile(latencies: &[Duration], p: usize) -> Duration {
+    if latencies.is_empty() {
+        return Duration::default();
+    }
+    latencies[(latencies.len() - 1) * p / 100]
+}
+
+/// xorshift64*, plenty random for picking keys.
+struct Rng(u64);
+
+impl Rng {
+    fn new(thread: u64) -> Rng {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        // never zero, which xorshift would be stuck at
+        Rng((now ^ thread.wrapping_mul(0x9E37_79B9_7F4A_7C15)) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
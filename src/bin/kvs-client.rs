@@ -1,8 +1,14 @@
 use env_logger::{Env, Target};
-use kvs::{KvsClient, MyError, Result};
+use kvs::{ClientTlsConfig, Event, KvsClient, MyError, ReadConsistency, Result};
 use log::{error, info};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::fmt::Display;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use structopt::clap::{arg_enum, AppSettings};
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
@@ -16,6 +22,33 @@ name = "kvs-client",
 //                          AppSettings::VersionlessSubcommands]"
 )]
 struct Opt {
+    #[structopt(
+        long = "output",
+        help = "Prints results as text, as JSON, or not at all; with json and quiet, \
+                a missing key or a false answer exits with code 2",
+        value_name = "FORMAT",
+        possible_values = &OutputFormat::variants(),
+        case_insensitive = true,
+        default_value = "plain",
+        global = true
+    )]
+    output: OutputFormat,
+    #[structopt(
+        long = "tls-ca",
+        help = "Connects over TLS, trusting the certificates in this PEM file",
+        value_name = "PATH",
+        parse(from_os_str),
+        global = true
+    )]
+    tls_ca: Option<PathBuf>,
+    #[structopt(
+        long = "tls-server-name",
+        help = "Expects a server certificate for this name rather than for the IP address",
+        value_name = "NAME",
+        requires = "tls-ca",
+        global = true
+    )]
+    tls_server_name: Option<String>,
     #[structopt(subcommand)]
     command: Command,
 }
@@ -26,6 +59,1036 @@ enum Command {
     Get {
         #[structopt(name = "KEY", help = "A string key")]
         key: String,
+        #[structopt(
+            long = "consistency",
+            help = "How fresh the value has to be: local, leader or linearizable",
+            value_name = "CONSISTENCY",
+            default_value = "local"
+        )]
+        consistency: ReadConsistency,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "set", about = "Set the value of a string key to a string")]
+    Set {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(name = "VALUE", help = "The string value of the key")]
+        value: String,
+        #[structopt(
+            long = "ttl",
+            help = "Removes the key after this many seconds",
+            value_name = "SECONDS"
+        )]
+        ttl: Option<u64>,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(
+        name = "get-path",
+        about = "Get the part of a key's JSON value at a path"
+    )]
+    GetPath {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(name = "PATH", help = "A JSON path, e.g. $.address.city")]
+        path: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(
+        name = "set-path",
+        about = "Set the part of a key's JSON value at a path"
+    )]
+    SetPath {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(name = "PATH", help = "A JSON path, e.g. $.address.city")]
+        path: String,
+        #[structopt(name = "VALUE", help = "The JSON value to put at the path")]
+        value: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "lpush", about = "Add a value at the front of a list")]
+    LPush {
+        #[structopt(name = "KEY", help = "A list key")]
+        key: String,
+        #[structopt(name = "VALUE", help = "The value to add")]
+        value: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "rpush", about = "Add a value at the back of a list")]
+    RPush {
+        #[structopt(name = "KEY", help = "A list key")]
+        key: String,
+        #[structopt(name = "VALUE", help = "The value to add")]
+        value: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "lpop", about = "Remove the value at the front of a list")]
+    LPop {
+        #[structopt(name = "KEY", help = "A list key")]
+        key: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(
+        name = "lrange",
+        about = "Get the values of a list between two indices, negative ones counting from the back",
+        setting = AppSettings::AllowNegativeNumbers
+    )]
+    LRange {
+        #[structopt(name = "KEY", help = "A list key")]
+        key: String,
+        #[structopt(
+            name = "START",
+            help = "Index of the first value",
+            allow_hyphen_values = true
+        )]
+        start: i64,
+        #[structopt(
+            name = "STOP",
+            help = "Index of the last value",
+            allow_hyphen_values = true
+        )]
+        stop: i64,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "hset", about = "Set a field of a hash")]
+    HSet {
+        #[structopt(name = "KEY", help = "A hash key")]
+        key: String,
+        #[structopt(name = "FIELD", help = "A field of the hash")]
+        field: String,
+        #[structopt(name = "VALUE", help = "The value of the field")]
+        value: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "hget", about = "Get a field of a hash")]
+    HGet {
+        #[structopt(name = "KEY", help = "A hash key")]
+        key: String,
+        #[structopt(name = "FIELD", help = "A field of the hash")]
+        field: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "hdel", about = "Remove a field of a hash")]
+    HDel {
+        #[structopt(name = "KEY", help = "A hash key")]
+        key: String,
+        #[structopt(name = "FIELD", help = "A field of the hash")]
+        field: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "hgetall", about = "Get every field of a hash")]
+    HGetAll {
+        #[structopt(name = "KEY", help = "A hash key")]
+        key: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "sadd", about = "Add a member to a set")]
+    SAdd {
+        #[structopt(name = "KEY", help = "A set key")]
+        key: String,
+        #[structopt(name = "MEMBER", help = "A member of the set")]
+        member: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "srem", about = "Remove a member from a set")]
+    SRem {
+        #[structopt(name = "KEY", help = "A set key")]
+        key: String,
+        #[structopt(name = "MEMBER", help = "A member of the set")]
+        member: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "sismember", about = "Check whether a set holds a member")]
+    SIsMember {
+        #[structopt(name = "KEY", help = "A set key")]
+        key: String,
+        #[structopt(name = "MEMBER", help = "A member of the set")]
+        member: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "smembers", about = "Get every member of a set")]
+    SMembers {
+        #[structopt(name = "KEY", help = "A set key")]
+        key: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(
+        name = "zadd",
+        about = "Set the score of a member of a sorted set",
+        setting = AppSettings::AllowNegativeNumbers
+    )]
+    ZAdd {
+        #[structopt(name = "KEY", help = "A sorted set key")]
+        key: String,
+        #[structopt(name = "SCORE", help = "The score of the member")]
+        score: f64,
+        #[structopt(name = "MEMBER", help = "A member of the sorted set")]
+        member: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(
+        name = "zrange-by-score",
+        about = "Get the members of a sorted set scored between two bounds, both included",
+        setting = AppSettings::AllowNegativeNumbers
+    )]
+    ZRangeByScore {
+        #[structopt(name = "KEY", help = "A sorted set key")]
+        key: String,
+        #[structopt(name = "MIN", help = "The lowest score")]
+        min: f64,
+        #[structopt(name = "MAX", help = "The highest score")]
+        max: f64,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "exists", about = "Check whether a key holds a value")]
+    Exists {
+        #[structopt(name = "KEY", help = "A key of any kind")]
+        key: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "dbsize", about = "Count the keys of a bucket")]
+    DbSize {
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "keys", about = "List the keys matching a glob pattern")]
+    Keys {
+        #[structopt(
+            name = "PATTERN",
+            help = "`*` for any characters, `?` for one, `[a-z]` for one of a class"
+        )]
+        pattern: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(
+        name = "scan",
+        about = "List the keys matching a glob pattern, a page at a time"
+    )]
+    Scan {
+        #[structopt(
+            name = "PATTERN",
+            help = "`*` for any characters, `?` for one, `[a-z]` for one of a class"
+        )]
+        pattern: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(
+        name = "export",
+        about = "Print the pairs matching a glob pattern as JSON `[key, value]` lines"
+    )]
+    Export {
+        #[structopt(
+            name = "PATTERN",
+            help = "`*` for any characters, `?` for one, `[a-z]` for one of a class",
+            default_value = "*"
+        )]
+        pattern: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(
+        name = "watch",
+        about = "Print the changes of keys starting with a prefix as they happen"
+    )]
+    Watch {
+        #[structopt(name = "PREFIX", help = "A key prefix", default_value = "")]
+        prefix: String,
+        #[structopt(
+            long = "count",
+            help = "Exits after this many changes",
+            value_name = "COUNT"
+        )]
+        count: Option<usize>,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "ttl", about = "Get how long a key has left to live")]
+    Ttl {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(
+        name = "metadata",
+        about = "Get when a key was created and last written"
+    )]
+    Metadata {
+        #[structopt(name = "KEY", help = "A key")]
+        key: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "expire", about = "Make a key expire, keeping its value")]
+    Expire {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(name = "SECONDS", help = "Removes the key after this many seconds")]
+        ttl: u64,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(
+        name = "touch",
+        about = "Make several keys expire, keeping their values"
+    )]
+    Touch {
+        #[structopt(name = "SECONDS", help = "Removes the keys after this many seconds")]
+        ttl: u64,
+        #[structopt(name = "KEY", help = "String keys", required = true)]
+        keys: Vec<String>,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "restore", about = "Bring a removed key back from the trash")]
+    Restore {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "purge-trash", about = "Remove the keys of the trash for good")]
+    PurgeTrash {
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "persist", about = "Make a key permanent again")]
+    Persist {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(
+        name = "lock",
+        about = "Take a lock and print its token, failing if it is held"
+    )]
+    Lock {
+        #[structopt(name = "NAME", help = "The key holding the lock")]
+        name: String,
+        #[structopt(name = "SECONDS", help = "Releases the lock after this many seconds")]
+        ttl: u64,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "unlock", about = "Release a lock taken with `lock`")]
+    Unlock {
+        #[structopt(name = "NAME", help = "The key holding the lock")]
+        name: String,
+        #[structopt(name = "TOKEN", help = "The token `lock` printed")]
+        token: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "rename", about = "Move the value of a key to another key")]
+    Rename {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(name = "NEW_KEY", help = "The key to move the value to")]
+        new_key: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "getset", about = "Set a key and print the value it had")]
+    GetSet {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(name = "VALUE", help = "The new value")]
+        value: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "getdel", about = "Remove a key and print the value it had")]
+    GetDel {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(
+        name = "script",
+        about = "Run a WASM script on the server over the keys under a prefix"
+    )]
+    Script {
+        #[structopt(name = "MODULE", help = "The WASM module", parse(from_os_str))]
+        module: PathBuf,
+        #[structopt(name = "ARGS", help = "Arguments of the script")]
+        args: Vec<String>,
+        #[structopt(
+            long = "prefix",
+            help = "The prefix of the keys the script may touch",
+            value_name = "PREFIX"
+        )]
+        prefix: String,
         #[structopt(
         long = "addr",
         help = "Sets the server address",
@@ -34,12 +1097,59 @@ enum Command {
         parse(try_from_str)
         )]
         addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
     },
-    #[structopt(name = "set", about = "Set the value of a string key to a string")]
-    Set {
+    #[structopt(name = "setnx", about = "Set a key only if it holds no value")]
+    SetNx {
         #[structopt(name = "KEY", help = "A string key")]
         key: String,
-        #[structopt(name = "VALUE", help = "The string value of the key")]
+        #[structopt(name = "VALUE", help = "The value")]
+        value: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(
+        name = "set-if-version",
+        about = "Set a key only if it is at a version, printing its new one"
+    )]
+    SetIfVersion {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+            name = "VERSION",
+            help = "The version the key must be at, 0 if it must hold no value"
+        )]
+        version: u64,
+        #[structopt(name = "VALUE", help = "The value")]
         value: String,
         #[structopt(
         long = "addr",
@@ -49,6 +1159,47 @@ enum Command {
         parse(try_from_str)
         )]
         addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "mset", about = "Set several keys at once, all or none")]
+    SetMany {
+        #[structopt(
+            name = "KEY VALUE",
+            help = "Keys, each followed by its value",
+            required = true
+        )]
+        pairs: Vec<String>,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
     },
     #[structopt(name = "rm", about = "Remove a given string key")]
     Remove {
@@ -62,41 +1213,1010 @@ enum Command {
         parse(try_from_str)
         )]
         addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(
+        name = "find",
+        about = "List the keys whose JSON values hold a value at an indexed path"
+    )]
+    Find {
+        #[structopt(name = "PATH", help = "An indexed JSON path, e.g. $.email")]
+        path: String,
+        #[structopt(name = "VALUE", help = "The value at the path")]
+        value: String,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(name = "flushall", about = "Remove every key of a bucket")]
+    FlushAll {
+        #[structopt(long = "yes", help = "Confirms that every key is to be removed")]
+        yes: bool,
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(
+        name = "stats",
+        about = "Show statistics of the server's storage engine"
+    )]
+    Stats {
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+    },
+    #[structopt(name = "compact", about = "Compact the store now")]
+    Compact {
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+        #[structopt(
+            long = "db",
+            help = "Uses this bucket instead of the default one",
+            value_name = "BUCKET"
+        )]
+        db: Option<String>,
+    },
+    #[structopt(
+        name = "reload-config",
+        about = "Have the server read its configuration file again, through its admin listener"
+    )]
+    ReloadConfig {
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+    },
+    #[structopt(
+        name = "shutdown",
+        about = "Stop the server, through its admin listener"
+    )]
+    Shutdown {
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+    },
+    #[structopt(
+        name = "drain",
+        about = "Put the server in lame-duck mode, through its admin listener"
+    )]
+    Drain {
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
+    },
+    #[structopt(name = "ping", about = "Check that the server is up")]
+    Ping {
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "slowlog",
+        about = "Show the requests the server logged as slow"
+    )]
+    SlowLog {
+        #[structopt(
+        long = "addr",
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long = "auth-token",
+            help = "Authenticates with this token",
+            value_name = "TOKEN"
+        )]
+        auth_token: Option<String>,
     },
 }
 
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum OutputFormat {
+        plain,
+        json,
+        quiet
+    }
+}
+
+/// Exit code of a command whose answer is no, e.g. a missing key, in the
+/// `json` and `quiet` output formats.
+const EXIT_NEGATIVE: i32 = 2;
+
+/// Prints the results of a command in the chosen format.
+struct Output {
+    format: OutputFormat,
+}
+
+impl Output {
+    /// Prints `text`, or `json` in the `json` format.
+    fn print(&self, text: impl Display, json: impl FnOnce() -> Value) {
+        match self.format {
+            OutputFormat::plain => info!("{}", text),
+            OutputFormat::json => println!("{}", json()),
+            OutputFormat::quiet => {}
+        }
+    }
+
+    /// Prints `json` in the `json` format only, for commands printing
+    /// nothing otherwise.
+    fn done(&self, json: impl FnOnce() -> Value) {
+        if self.format == OutputFormat::json {
+            println!("{}", json());
+        }
+    }
+
+    /// Prints `value` of `key`, or that it was not found, returning whether
+    /// it was found. `json` holds the other fields to print.
+    fn value(&self, value: Option<String>, mut json: Value) -> bool {
+        let found = value.is_some();
+        json["value"] = json!(value);
+        json["found"] = json!(found);
+        match value {
+            Some(value) => self.print(value, || json),
+            None if self.format == OutputFormat::plain => error!("{}", MyError::KeyNotFound),
+            None => self.done(|| json),
+        }
+        found
+    }
+
+    /// Prints `items`, one per line, or as one JSON array.
+    fn list<T: Serialize>(&self, items: Vec<T>, text: impl Fn(&T) -> String) -> Result<()> {
+        match self.format {
+            OutputFormat::plain => items.iter().for_each(|item| info!("{}", text(item))),
+            OutputFormat::json => println!("{}", serde_json::to_string(&items)?),
+            OutputFormat::quiet => {}
+        }
+        Ok(())
+    }
+}
+
 fn main() {
     let opt = Opt::from_args();
-    if let Err(e) = run(opt) {
-        eprintln!("{}", e);
-        exit(1);
+    let output = Output { format: opt.output };
+    match tls_config(&opt).and_then(|tls| run(opt.command, &output, tls.as_ref())) {
+        Ok(true) => {}
+        Ok(false) if output.format == OutputFormat::plain => {}
+        Ok(false) => exit(EXIT_NEGATIVE),
+        Err(e) => {
+            match output.format {
+                OutputFormat::json => eprintln!("{}", json!({ "error": e.to_string() })),
+                _ => eprintln!("{}", e),
+            }
+            exit(1);
+        }
     }
 }
 
-fn run(opt: Opt) -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
-        .target(Target::Stdout)
+/// Runs `command`, returning whether its answer is yes: false for a missing
+/// key, a set without the member, and the like.
+fn run(command: Command, output: &Output, tls: Option<&ClientTlsConfig>) -> Result<bool> {
+    // logs would be mixed up with the output of scripts
+    let level = match output.format {
+        OutputFormat::plain => "info",
+        _ => "warn",
+    };
+    env_logger::Builder::from_env(Env::default().default_filter_or(level))
+        .target(match output.format {
+            OutputFormat::plain => Target::Stdout,
+            _ => Target::Stderr,
+        })
         .init();
     //let mut kvs = KvStore::open(current_dir()?)?;
 
-    match opt.command {
-        Command::Get { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
-
-            if let Some(value) = client.get(key.clone())? {
-                info!("{}", value);
-            } else {
-                error!("{}", MyError::KeyNotFound)
+    match command {
+        Command::Get {
+            key,
+            consistency,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let mut client = connect(tls, addr, auth_token, db)?;
+            let value = client.get_with_consistency(key.clone(), consistency)?;
+            return Ok(output.value(value, json!({ "key": key })));
+        }
+        Command::Set {
+            key,
+            value,
+            ttl,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let mut client = connect(tls, addr, auth_token, db)?;
+            match ttl {
+                Some(secs) => client.set_with_ttl(key.clone(), value, Duration::from_secs(secs))?,
+                None => client.set(key.clone(), value)?,
+            }
+            output.done(|| json!({ "key": key }));
+        }
+        Command::GetPath {
+            key,
+            path,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let value = connect(tls, addr, auth_token, db)?.get_path(key.clone(), path.clone())?;
+            return Ok(output.value(value, json!({ "key": key, "path": path })));
+        }
+        Command::SetPath {
+            key,
+            path,
+            value,
+            addr,
+            auth_token,
+            db,
+        } => {
+            connect(tls, addr, auth_token, db)?.set_path(key.clone(), path.clone(), value)?;
+            output.done(|| json!({ "key": key, "path": path }));
+        }
+        Command::LPush {
+            key,
+            value,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let len = connect(tls, addr, auth_token, db)?.lpush(key.clone(), value)?;
+            output.print(len, || json!({ "key": key, "len": len }));
+        }
+        Command::RPush {
+            key,
+            value,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let len = connect(tls, addr, auth_token, db)?.rpush(key.clone(), value)?;
+            output.print(len, || json!({ "key": key, "len": len }));
+        }
+        Command::LPop {
+            key,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let value = connect(tls, addr, auth_token, db)?.lpop(key.clone())?;
+            return Ok(output.value(value, json!({ "key": key })));
+        }
+        Command::LRange {
+            key,
+            start,
+            stop,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let values = connect(tls, addr, auth_token, db)?.lrange(key, start, stop)?;
+            output.list(values, String::clone)?;
+        }
+        Command::HSet {
+            key,
+            field,
+            value,
+            addr,
+            auth_token,
+            db,
+        } => {
+            connect(tls, addr, auth_token, db)?.hset(key.clone(), field.clone(), value)?;
+            output.done(|| json!({ "key": key, "field": field }));
+        }
+        Command::HGet {
+            key,
+            field,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let value = connect(tls, addr, auth_token, db)?.hget(key.clone(), field.clone())?;
+            return Ok(output.value(value, json!({ "key": key, "field": field })));
+        }
+        Command::HDel {
+            key,
+            field,
+            addr,
+            auth_token,
+            db,
+        } => {
+            connect(tls, addr, auth_token, db)?.hdel(key.clone(), field.clone())?;
+            output.done(|| json!({ "key": key, "field": field }));
+        }
+        Command::HGetAll {
+            key,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let fields = connect(tls, addr, auth_token, db)?.hgetall(key)?;
+            match output.format {
+                OutputFormat::json => {
+                    let fields: Map<String, Value> = fields
+                        .into_iter()
+                        .map(|(field, value)| (field, Value::String(value)))
+                        .collect();
+                    println!("{}", Value::Object(fields));
+                }
+                _ => output.list(fields, |(field, value)| format!("{}: {}", field, value))?,
+            }
+        }
+        Command::SAdd {
+            key,
+            member,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let added = connect(tls, addr, auth_token, db)?.sadd(key.clone(), member.clone())?;
+            output.done(|| json!({ "key": key, "member": member, "added": added }));
+        }
+        Command::SRem {
+            key,
+            member,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let removed = connect(tls, addr, auth_token, db)?.srem(key.clone(), member.clone())?;
+            if !removed && output.format == OutputFormat::plain {
+                error!("Member not found");
+            }
+            output.done(|| json!({ "key": key, "member": member, "removed": removed }));
+            return Ok(removed);
+        }
+        Command::SIsMember {
+            key,
+            member,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let is_member =
+                connect(tls, addr, auth_token, db)?.sismember(key.clone(), member.clone())?;
+            output.print(
+                is_member,
+                || json!({ "key": key, "member": member, "member_of": is_member }),
+            );
+            return Ok(is_member);
+        }
+        Command::SMembers {
+            key,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let members = connect(tls, addr, auth_token, db)?.smembers(key)?;
+            output.list(members, String::clone)?;
+        }
+        Command::ZAdd {
+            key,
+            score,
+            member,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let added =
+                connect(tls, addr, auth_token, db)?.zadd(key.clone(), member.clone(), score)?;
+            output.done(|| json!({ "key": key, "member": member, "added": added }));
+        }
+        Command::ZRangeByScore {
+            key,
+            min,
+            max,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let members = connect(tls, addr, auth_token, db)?.zrange_by_score(key, min, max)?;
+            let members = members
+                .into_iter()
+                .map(|(member, score)| json!({ "member": member, "score": score }))
+                .collect();
+            output.list(members, |member| {
+                format!(
+                    "{}: {}",
+                    member["member"].as_str().unwrap_or(""),
+                    member["score"]
+                )
+            })?;
+        }
+        Command::Exists {
+            key,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let exists = connect(tls, addr, auth_token, db)?.exists(key.clone())?;
+            output.print(exists, || json!({ "key": key, "exists": exists }));
+            return Ok(exists);
+        }
+        Command::DbSize {
+            addr,
+            auth_token,
+            db,
+        } => {
+            let keys = connect(tls, addr, auth_token, db)?.db_size()?;
+            output.print(keys, || json!({ "keys": keys }));
+        }
+        Command::Keys {
+            pattern,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let keys = connect(tls, addr, auth_token, db)?.keys(pattern)?;
+            output.list(keys, String::clone)?;
+        }
+        Command::Scan {
+            pattern,
+            addr,
+            auth_token,
+            db,
+        } => {
+            // one key per line as they come, whatever the format
+            let mut client = connect(tls, addr, auth_token, db)?;
+            for key in client.scan_iter(pattern) {
+                let key = key?;
+                output.print(&key, || json!(key));
+            }
+        }
+        Command::Export {
+            pattern,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let mut client = connect(tls, addr, auth_token, db)?;
+            for pair in client.export(pattern)? {
+                let pair = pair?;
+                output.print(serde_json::to_string(&pair)?, || json!(pair));
+            }
+        }
+        Command::Watch {
+            prefix,
+            count,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let events = connect(tls, addr, auth_token, db)?.subscribe(prefix)?;
+            for event in events.take(count.unwrap_or(usize::MAX)) {
+                match event? {
+                    Event::Set { key, value } => output.print(
+                        format!("set {} {}", key, value),
+                        || json!({ "event": "set", "key": key, "value": value }),
+                    ),
+                    Event::Removed { key } => output.print(
+                        format!("removed {}", key),
+                        || json!({ "event": "removed", "key": key }),
+                    ),
+                    Event::Pushed { key, value, front } => {
+                        let event = if front { "lpush" } else { "rpush" };
+                        output.print(
+                            format!("{} {} {}", event, key, value),
+                            || json!({ "event": event, "key": key, "value": value }),
+                        )
+                    }
+                    Event::Popped { key } => output.print(
+                        format!("lpop {}", key),
+                        || json!({ "event": "lpop", "key": key }),
+                    ),
+                    Event::FieldSet { key, field, value } => output.print(
+                        format!("hset {} {} {}", key, field, value),
+                        || json!({ "event": "hset", "key": key, "field": field, "value": value }),
+                    ),
+                    Event::FieldRemoved { key, field } => output.print(
+                        format!("hdel {} {}", key, field),
+                        || json!({ "event": "hdel", "key": key, "field": field }),
+                    ),
+                    Event::MemberAdded { key, member } => output.print(
+                        format!("sadd {} {}", key, member),
+                        || json!({ "event": "sadd", "key": key, "member": member }),
+                    ),
+                    Event::MemberRemoved { key, member } => output.print(
+                        format!("srem {} {}", key, member),
+                        || json!({ "event": "srem", "key": key, "member": member }),
+                    ),
+                    Event::Scored { key, member, score } => output.print(
+                        format!("zadd {} {} {}", key, score, member),
+                        || json!({ "event": "zadd", "key": key, "member": member, "score": score }),
+                    ),
+                    Event::Expiry {
+                        key,
+                        expires_at_ms: Some(expires),
+                    } => output.print(
+                        format!("expire {} {}", key, expires),
+                        || json!({ "event": "expire", "key": key, "expires_at_ms": expires }),
+                    ),
+                    Event::Expiry {
+                        key,
+                        expires_at_ms: None,
+                    } => output.print(
+                        format!("persist {}", key),
+                        || json!({ "event": "persist", "key": key }),
+                    ),
+                }
+            }
+        }
+        Command::Ttl {
+            key,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let ttl = connect(tls, addr, auth_token, db)?.ttl(key.clone())?;
+            let ttl_ms = ttl.map(|ttl| ttl.as_millis() as u64);
+            let text = match ttl_ms {
+                Some(ms) => format!("{} ms", ms),
+                None => "No TTL".to_owned(),
+            };
+            output.print(text, || json!({ "key": key, "ttl_ms": ttl_ms }));
+        }
+        Command::Metadata {
+            key,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let meta = connect(tls, addr, auth_token, db)?.metadata(key.clone())?;
+            let millis = |time: Option<SystemTime>| {
+                time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|elapsed| elapsed.as_millis() as u64)
+            };
+            let (created_ms, updated_ms) = (millis(meta.created), millis(meta.updated));
+            let show = |ms: Option<u64>| ms.map_or("unknown".to_owned(), |ms| ms.to_string());
+            output.print(
+                format!(
+                    "created: {}, updated: {} (ms since the UNIX epoch), version: {}",
+                    show(created_ms),
+                    show(updated_ms),
+                    meta.version
+                ),
+                || {
+                    json!({
+                        "key": key,
+                        "created_ms": created_ms,
+                        "updated_ms": updated_ms,
+                        "version": meta.version,
+                    })
+                },
+            );
+        }
+        Command::Expire {
+            key,
+            ttl,
+            addr,
+            auth_token,
+            db,
+        } => {
+            connect(tls, addr, auth_token, db)?.expire(key.clone(), Duration::from_secs(ttl))?;
+            output.done(|| json!({ "key": key }));
+        }
+        Command::Touch {
+            ttl,
+            keys,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let touched =
+                connect(tls, addr, auth_token, db)?.touch(keys, Duration::from_secs(ttl))?;
+            output.print(touched, || json!({ "touched": touched }));
+        }
+        Command::Restore {
+            key,
+            addr,
+            auth_token,
+            db,
+        } => {
+            connect(tls, addr, auth_token, db)?.restore(key.clone())?;
+            output.done(|| json!({ "key": key }));
+        }
+        Command::PurgeTrash {
+            addr,
+            auth_token,
+            db,
+        } => {
+            let purged = connect(tls, addr, auth_token, db)?.purge_trash()?;
+            output.print(purged, || json!({ "purged": purged }));
+        }
+        Command::Persist {
+            key,
+            addr,
+            auth_token,
+            db,
+        } => {
+            connect(tls, addr, auth_token, db)?.persist(key.clone())?;
+            output.done(|| json!({ "key": key }));
+        }
+        Command::Lock {
+            name,
+            ttl,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let mut client = connect(tls, addr, auth_token, db)?;
+            let lock = client.acquire_lock(name.clone(), Duration::from_secs(ttl))?;
+            let token = lock
+                .map(|lock| lock.into_token())
+                .ok_or_else(|| MyError::StringError(format!("Lock {} is held", name)))?;
+            output.print(&token, || json!({ "name": name, "token": token }));
+        }
+        Command::Unlock {
+            name,
+            token,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let released = connect(tls, addr, auth_token, db)?.release_lock(name.clone(), token)?;
+            output.print(released, || json!({ "name": name, "released": released }));
+            return Ok(released);
+        }
+        Command::Rename {
+            key,
+            new_key,
+            addr,
+            auth_token,
+            db,
+        } => {
+            connect(tls, addr, auth_token, db)?.rename(key.clone(), new_key.clone())?;
+            output.done(|| json!({ "key": key, "new_key": new_key }));
+        }
+        Command::GetSet {
+            key,
+            value,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let previous = connect(tls, addr, auth_token, db)?.get_set(key.clone(), value)?;
+            return Ok(output.value(previous, json!({ "key": key })));
+        }
+        Command::GetDel {
+            key,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let previous = connect(tls, addr, auth_token, db)?.get_del(key.clone())?;
+            return Ok(output.value(previous, json!({ "key": key })));
+        }
+        Command::Script {
+            module,
+            args,
+            prefix,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let module = std::fs::read(module)?;
+            let reply = connect(tls, addr, auth_token, db)?.run_script(&module, prefix, args)?;
+            match &reply {
+                Some(reply) => output.print(reply, || json!({ "output": reply })),
+                None => output.done(|| json!({ "output": null })),
+            }
+        }
+        Command::SetNx {
+            key,
+            value,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let set = connect(tls, addr, auth_token, db)?.set_nx(key.clone(), value)?;
+            output.print(set, || json!({ "key": key, "set": set }));
+            return Ok(set);
+        }
+        Command::SetIfVersion {
+            key,
+            version,
+            value,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let version =
+                connect(tls, addr, auth_token, db)?.set_if_version(key.clone(), version, value)?;
+            output.print(version, || json!({ "key": key, "version": version }));
+        }
+        Command::SetMany {
+            pairs,
+            addr,
+            auth_token,
+            db,
+        } => {
+            if pairs.len() % 2 != 0 {
+                return Err(MyError::StringError("Every key needs a value".to_owned()));
+            }
+            let pairs: Vec<_> = pairs
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            let count = pairs.len();
+            connect(tls, addr, auth_token, db)?.set_many(pairs)?;
+            output.done(|| json!({ "keys": count }));
+        }
+        Command::Remove {
+            key,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let mut client = connect(tls, addr, auth_token, db)?;
+            client.remove(key.clone())?;
+            output.done(|| json!({ "key": key }));
+        }
+        Command::Find {
+            path,
+            value,
+            addr,
+            auth_token,
+            db,
+        } => {
+            let keys = connect(tls, addr, auth_token, db)?.find_by_index(path, value)?;
+            output.list(keys, String::clone)?;
+        }
+        Command::FlushAll {
+            yes,
+            addr,
+            auth_token,
+            db,
+        } => {
+            if !yes {
+                return Err(MyError::StringError(
+                    "flushall removes every key; pass --yes to confirm".to_owned(),
+                ));
+            }
+            connect(tls, addr, auth_token, db)?.flush_all()?;
+            output.done(|| json!({}));
+        }
+        Command::Compact {
+            addr,
+            auth_token,
+            db,
+        } => {
+            connect(tls, addr, auth_token, db)?.compact()?;
+            output.done(|| json!({}));
+        }
+        Command::ReloadConfig { addr, auth_token } => {
+            connect(tls, addr, auth_token, None)?.reload_config()?;
+            output.done(|| json!({}));
+        }
+        Command::Shutdown { addr, auth_token } => {
+            connect(tls, addr, auth_token, None)?.shutdown()?;
+            output.done(|| json!({}));
+        }
+        Command::Drain { addr, auth_token } => {
+            connect(tls, addr, auth_token, None)?.drain()?;
+            output.done(|| json!({}));
+        }
+        Command::Stats { addr, auth_token } => {
+            let stats = connect(tls, addr, auth_token, None)?.stats()?;
+            if output.format != OutputFormat::plain {
+                output.done(|| json!(stats));
+                return Ok(true);
+            }
+            info!("keys: {}", stats.key_count);
+            info!("disk usage: {} bytes", stats.disk_usage);
+            info!("uncompacted: {} bytes", stats.uncompacted_bytes);
+            if stats.write_stalled || stats.stalled_writes > 0 {
+                info!(
+                    "write stall: {} ({} writes slowed down)",
+                    if stats.write_stalled { "on" } else { "off" },
+                    stats.stalled_writes
+                );
+            }
+            info!("segments: {}", stats.segment_count);
+            if let Some(ratio) = stats.cache_hit_ratio() {
+                info!(
+                    "cache: {} hits, {} misses ({:.1}% hits)",
+                    stats.cache_hits,
+                    stats.cache_misses,
+                    ratio * 100.0
+                );
+            }
+            match stats.last_compaction.map(|at| at.elapsed()) {
+                Some(Ok(elapsed)) => info!("last compaction: {}s ago", elapsed.as_secs()),
+                Some(Err(_)) => info!("last compaction: just now"),
+                None => info!("last compaction: never"),
+            }
+            if let Some(last) = stats.last_compaction_stats {
+                info!(
+                    "  rewrote {} records in {}ms, reclaiming {} bytes ({})",
+                    last.records_rewritten,
+                    last.duration.as_millis(),
+                    last.bytes_reclaimed,
+                    last.trigger
+                );
+            }
+            info!(
+                "compactions: {}, {} bytes reclaimed",
+                stats.compactions, stats.bytes_reclaimed
+            );
+            for (request, latency) in &stats.latency {
+                info!(
+                    "{}: {} requests, p50 {:?}, p95 {:?}, p99 {:?}, max {:?}",
+                    request,
+                    latency.count,
+                    latency.service.p50,
+                    latency.service.p95,
+                    latency.service.p99,
+                    latency.service.max
+                );
+                info!(
+                    "  queue p99 {:?}, engine p99 {:?}",
+                    latency.queue.p99, latency.engine.p99
+                );
             }
         }
-        Command::Set { key, value, addr } => {
-            let mut client = KvsClient::connect(addr)?;
-            client.set(key, value)?;
+        Command::Ping { addr } => {
+            let pong = connect(tls, addr, None, None)?.ping()?;
+            let text = format!(
+                "PONG from kvs {} ({} engine), up {}s, {}",
+                pong.version,
+                pong.engine,
+                pong.uptime_secs,
+                pong.state.name()
+            );
+            output.print(text, || json!(pong));
         }
-        Command::Remove { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
-            client.remove(key)?;
+        Command::SlowLog { addr, auth_token } => {
+            let requests = connect(tls, addr, auth_token, None)?.slow_log()?;
+            output.list(requests, |request| {
+                let text = format!(
+                    "{} with a {} byte key took {:?}",
+                    request.request, request.key_len, request.elapsed
+                );
+                match &request.request_id {
+                    Some(id) => format!("{} (request {})", text, id),
+                    None => text,
+                }
+            })?;
         }
     }
-    Ok(())
+    Ok(true)
+}
+
+/// What `--tls-ca` and `--tls-server-name` ask for.
+fn tls_config(opt: &Opt) -> Result<Option<ClientTlsConfig>> {
+    let ca = match &opt.tls_ca {
+        Some(ca) => ca,
+        None => return Ok(None),
+    };
+    let mut tls = ClientTlsConfig::from_ca_file(ca)?;
+    if let Some(name) = &opt.tls_server_name {
+        tls = tls.with_server_name(name)?;
+    }
+    Ok(Some(tls))
+}
+
+fn connect(
+    tls: Option<&ClientTlsConfig>,
+    addr: SocketAddr,
+    auth_token: Option<String>,
+    db: Option<String>,
+) -> Result<KvsClient> {
+    let mut builder = KvsClient::builder();
+    if let Some(tls) = tls {
+        builder = builder.with_tls(tls.clone());
+    }
+    if let Some(token) = auth_token {
+        builder = builder.with_auth_token(token);
+    }
+    if let Some(db) = db {
+        builder = builder.with_db(db);
+    }
+    builder.connect(addr)
 }
@@ -0,0 +1,172 @@
+use kvs::{dump_log, KvStore, Result, SizeStats, SIZE_BUCKETS};
+use std::env::current_dir;
+use std::path::PathBuf;
+use std::process::exit;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kvs-dump", about = "Prints the records of a kvs log")]
+struct Opt {
+    #[structopt(
+        name = "DIR",
+        help = "Data directory to read [default: current directory]",
+        parse(from_os_str)
+    )]
+    dir: Option<PathBuf>,
+    #[structopt(
+        long = "key",
+        help = "Only prints writes of this key",
+        value_name = "KEY"
+    )]
+    key: Option<String>,
+    #[structopt(
+        long = "prefix",
+        help = "Only prints writes of keys starting with this prefix",
+        value_name = "PREFIX"
+    )]
+    prefix: Option<String>,
+    #[structopt(long = "json", help = "Prints one JSON object per record")]
+    json: bool,
+    #[structopt(
+        long = "report",
+        help = "Reports how much room the live keys take, by namespace, instead"
+    )]
+    report: bool,
+    #[structopt(
+        long = "at",
+        help = "Prints the values the keys held once the write with this sequence number was made, instead",
+        value_name = "SEQ"
+    )]
+    at: Option<u64>,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(mut opt: Opt) -> Result<()> {
+    let dir = match opt.dir.take() {
+        Some(dir) => dir,
+        None => current_dir()?,
+    };
+    if opt.report {
+        return report(dir, opt.json);
+    }
+    if let Some(seq) = opt.at {
+        return values_at(dir, &opt, seq);
+    }
+    for entry in dump_log(&dir)? {
+        let key = entry.key.as_deref();
+        if opt.key.is_some() && key != opt.key.as_deref() {
+            continue;
+        }
+        if let Some(prefix) = &opt.prefix {
+            if !key.is_some_and(|key| key.starts_with(prefix.as_str())) {
+                continue;
+            }
+        }
+        if opt.json {
+            println!("{}", serde_json::to_string(&entry)?);
+            continue;
+        }
+        let value = entry
+            .value_len
+            .map(|len| format!(" ({} bytes)", len))
+            .unwrap_or_default();
+        println!(
+            "{:>10} seq {:<6} {:<7}{} {}{} checksum {:?}",
+            entry.offset,
+            entry.seq,
+            entry.kind,
+            if entry.batch { " [batch]" } else { "" },
+            key.unwrap_or("-"),
+            value,
+            entry.checksum
+        );
+    }
+    Ok(())
+}
+
+/// Prints the size report of the store in `dir`, opening it, and so
+/// locking it meanwhile.
+fn report(dir: PathBuf, json: bool) -> Result<()> {
+    let report = KvStore::open(dir)?.size_report()?;
+    if json {
+        println!("{}", serde_json::to_string(&report)?);
+        return Ok(());
+    }
+    let mut header = format!("{:<20} {:>10} {:>12}", "NAMESPACE", "KEYS", "BYTES");
+    for bound in SIZE_BUCKETS.iter() {
+        header.push_str(&format!(" {:>9}", format!("<={}", human(*bound))));
+    }
+    header.push_str(&format!(" {:>9}", format!(">{}", human(SIZE_BUCKETS[7]))));
+    println!("{}", header);
+    let rows = report
+        .namespaces
+        .iter()
+        .map(|(namespace, stats)| (namespace.as_str(), stats))
+        .chain(Some(("(total)", &report.total)));
+    for (namespace, stats) in rows {
+        let namespace = match namespace {
+            "" => "(none)",
+            namespace => namespace,
+        };
+        println!("{}", row(namespace, stats));
+    }
+    Ok(())
+}
+
+/// Prints the values the keys picked by `--key` or `--prefix`, or all of
+/// them, held at `seq`, opening the store like `report`.
+fn values_at(dir: PathBuf, opt: &Opt, seq: u64) -> Result<()> {
+    let mut store = KvStore::open(dir)?;
+    let pairs = match (&opt.key, &opt.prefix) {
+        (Some(key), _) => store
+            .get_at(key.clone(), seq)?
+            .map(|value| (key.clone(), value))
+            .into_iter()
+            .collect(),
+        (None, Some(prefix)) => store
+            .scan_at(prefix.clone().., seq)?
+            .into_iter()
+            .take_while(|(key, _)| key.starts_with(prefix.as_str()))
+            .collect(),
+        (None, None) => store.scan_at(.., seq)?,
+    };
+    if opt.key.is_some() && pairs.is_empty() && !opt.json {
+        println!("Key not found");
+    }
+    for (key, value) in pairs {
+        match opt.json {
+            true => println!("{}", serde_json::json!({ "key": key, "value": value })),
+            false => println!("{} {}", key, value),
+        }
+    }
+    Ok(())
+}
+
+fn row(namespace: &str, stats: &SizeStats) -> String {
+    let mut row = format!(
+        "{:<20} {:>10} {:>12}",
+        namespace,
+        stats.keys,
+        stats.key_bytes + stats.bytes
+    );
+    for count in stats.histogram.iter() {
+        row.push_str(&format!(" {:>9}", count));
+    }
+    row
+}
+
+/// `bytes` in KiB or MiB when it is a whole number of them.
+fn human(bytes: u64) -> String {
+    match bytes {
+        bytes if bytes >= 1 << 20 && bytes % (1 << 20) == 0 => format!("{}M", bytes >> 20),
+        bytes if bytes >= 1 << 10 && bytes % (1 << 10) == 0 => format!("{}K", bytes >> 10),
+        bytes => bytes.to_string(),
+    }
+}
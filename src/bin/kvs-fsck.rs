@@ -0,0 +1,59 @@
+use kvs::{fsck, Result};
+use std::env::current_dir;
+use std::path::PathBuf;
+use std::process::exit;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kvs-fsck", about = "Verifies the log of a kvs data directory")]
+struct Opt {
+    #[structopt(
+        name = "DIR",
+        help = "Data directory to check [default: current directory]",
+        parse(from_os_str)
+    )]
+    dir: Option<PathBuf>,
+    #[structopt(long = "repair", help = "Rewrites the log without its corrupt records")]
+    repair: bool,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    match run(opt) {
+        Ok(true) => {}
+        Ok(false) => exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    }
+}
+
+/// Prints the report, returning whether the log is usable as is.
+fn run(opt: Opt) -> Result<bool> {
+    let dir = match opt.dir {
+        Some(dir) => dir,
+        None => current_dir()?,
+    };
+    let report = fsck(&dir, opt.repair)?;
+    println!("records: {}", report.records);
+    println!("corrupt records: {}", report.corrupt_records);
+    println!("live: {} bytes", report.live_bytes);
+    println!("garbage: {} bytes", report.garbage_bytes);
+    for problem in &report.problems {
+        println!("problem at {}", problem);
+    }
+    if report.repaired {
+        println!(
+            "repaired: dropped {} corrupt records",
+            report.corrupt_records
+        );
+    }
+    let remaining = report.problems.len() as u64
+        - if report.repaired {
+            report.corrupt_records
+        } else {
+            0
+        };
+    Ok(remaining == 0)
+}
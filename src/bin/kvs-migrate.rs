@@ -0,0 +1,108 @@
+use kvs::{migrate, KvStore, KvsEngine, LsmEngine, MigrateReport, MyError, Result, SledKvsEngine};
+use std::path::PathBuf;
+use std::process::exit;
+use structopt::clap::arg_enum;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-migrate",
+    about = "Copies the keys of a data directory into another one, of another engine"
+)]
+struct Opt {
+    #[structopt(
+        long = "from",
+        help = "Engine of the source directory",
+        value_name = "ENGINE",
+        possible_values = &Engine::variants()
+    )]
+    from: Engine,
+    #[structopt(
+        long = "to",
+        help = "Engine of the destination directory",
+        value_name = "ENGINE",
+        possible_values = &Engine::variants()
+    )]
+    to: Engine,
+    #[structopt(
+        long = "src",
+        help = "Data directory to copy from",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    src: PathBuf,
+    #[structopt(
+        long = "dst",
+        help = "Data directory to copy into, created if missing",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    dst: PathBuf,
+    #[structopt(long = "quiet", help = "Prints no progress")]
+    quiet: bool,
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Engine {
+        kvs,
+        sled,
+        lsm
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(&opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: &Opt) -> Result<()> {
+    if opt.src == opt.dst {
+        return Err(MyError::StringError(
+            "The source and destination directories must differ".to_owned(),
+        ));
+    }
+    match opt.from {
+        Engine::kvs => run_from(KvStore::open(&opt.src)?, opt),
+        Engine::sled => run_from(SledKvsEngine::open(&opt.src)?, opt),
+        Engine::lsm => run_from(LsmEngine::open(&opt.src)?, opt),
+    }
+}
+
+fn run_from<S: KvsEngine>(src: S, opt: &Opt) -> Result<()> {
+    match opt.to {
+        Engine::kvs => copy(src, KvStore::open(&opt.dst)?, opt),
+        Engine::sled => copy(src, SledKvsEngine::open(&opt.dst)?, opt),
+        Engine::lsm => copy(src, LsmEngine::open(&opt.dst)?, opt),
+    }
+}
+
+/// Copies `src` into `dst`, which must be empty lest keys be mixed.
+fn copy<S: KvsEngine, D: KvsEngine>(mut src: S, mut dst: D, opt: &Opt) -> Result<()> {
+    if !dst.keys_after(String::new(), None, 1)?.is_empty() {
+        return Err(MyError::StringError(format!(
+            "{} already holds keys",
+            opt.dst.display()
+        )));
+    }
+    let report = migrate(&mut src, &mut dst, |report: &MigrateReport| {
+        if !opt.quiet {
+            eprintln!(
+                "copied {} keys ({} bytes), verified {}",
+                report.keys, report.bytes, report.verified
+            );
+        }
+    })?;
+    dst.shutdown()?;
+    println!("keys: {}", report.keys);
+    println!("bytes: {}", report.bytes);
+    println!("verified: {}", report.verified);
+    if report.skipped > 0 {
+        println!("skipped: {} lists, hashes and sets", report.skipped);
+    }
+    Ok(())
+}
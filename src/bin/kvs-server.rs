@@ -1,33 +1,408 @@
-use env_logger::{Env, Target};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
-use kvs::{Result, Server};
-use log::info;
+use kvs::{
+    Acl, AuditLog, CompactionWindow, EvictionPolicy, IndexedEngine, KvStore, KvStoreOptions,
+    KvsEngine, LsmEngine, LsmOptions, MemEngine, SledKvsEngine, SyncPolicy,
+};
+use kvs::{
+    Consistency, MyError, ReloadHandle, Result, Server, ServerConfig, ServerTlsConfig,
+    ShutdownHandle, Tenants, WarmUp,
+};
+use log::{info, warn, LevelFilter};
+use std::convert::TryFrom;
 use std::env::current_dir;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal};
 use std::net::SocketAddr;
-use std::process::exit;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::process::{self, exit};
+use std::sync::{mpsc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
 use structopt::clap::arg_enum;
 use structopt::StructOpt;
+use tracing_subscriber::fmt::{self, writer::BoxMakeWriter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
 
 //const DEFAULT_ENGINE: Engine = Engine::kvs;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
+/// Filter of the logs, which reloading the configuration changes when the
+/// level was set by `--log-level` or `log_level` rather than `RUST_LOG`.
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
 const ADDRESS_FORMAT: &str = "IP:PORT";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
+/// Room a request frame has around the largest key and value.
+const FRAME_OVERHEAD: u64 = 64 * 1024;
+/// Scheduled snapshots kept when `--snapshot-retain` is not given.
+const DEFAULT_SNAPSHOT_RETAIN: usize = 3;
+/// Size from which the audit log is rotated when `--audit-log-max-bytes`
+/// is not given.
+const DEFAULT_AUDIT_LOG_MAX_BYTES: u64 = 64 * 1024 * 1024;
+/// Rotated audit logs kept when `--audit-log-files` is not given.
+const DEFAULT_AUDIT_LOG_FILES: usize = 5;
+/// File in the data directory a replica keeps its position in the change
+/// stream of its leader in.
+const REPLICA_STATE_FILE: &str = "replica.json";
 
-#[derive(StructOpt, Debug)]
+#[derive(StructOpt, Clone, Debug)]
 #[structopt(name = "kvs-server")]
 struct Opt {
+    #[structopt(
+        long = "config",
+        help = "Reads settings from this TOML file; flags take precedence",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    config: Option<PathBuf>,
     #[structopt(
     long = "addr",
-    help = "Sets the server address",
+    help = "Sets the server address [default: 127.0.0.1:4000]",
     value_name = ADDRESS_FORMAT,
-    default_value = DEFAULT_LISTENING_ADDRESS,
     parse(try_from_str)
     )]
-    addr: SocketAddr,
+    addr: Option<SocketAddr>,
+    #[structopt(
+        long = "data-dir",
+        help = "Stores data in this directory [default: current directory]",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    data_dir: Option<PathBuf>,
     #[structopt(long, help = "Sets the storage engine", value_name = "ENGINE-NAME",
     possible_values = &Engine::variants(), case_insensitive = true)]
     engine: Option<Engine>,
+    #[structopt(
+    long = "ws-addr",
+    help = "Also accepts WebSocket clients on this address",
+    value_name = ADDRESS_FORMAT,
+    parse(try_from_str)
+    )]
+    ws_addr: Option<SocketAddr>,
+    #[structopt(
+        long = "ws-origin",
+        help = "Accepts WebSocket upgrades from browser pages of this origin, or * for any (repeatable)",
+        value_name = "ORIGIN"
+    )]
+    ws_origins: Vec<String>,
+    #[cfg(feature = "grpc")]
+    #[structopt(
+    long = "grpc-addr",
+    help = "Also serves the gRPC service of proto/kvs.proto on this address",
+    value_name = ADDRESS_FORMAT,
+    parse(try_from_str)
+    )]
+    grpc_addr: Option<SocketAddr>,
+    #[structopt(
+    long = "admin-addr",
+    help = "Serves stats, compaction, flushall, reload and shutdown on this address, and not the others",
+    value_name = ADDRESS_FORMAT,
+    parse(try_from_str)
+    )]
+    admin_addr: Option<SocketAddr>,
+    #[cfg(unix)]
+    #[structopt(
+        long = "unix-socket",
+        help = "Also accepts clients on a Unix socket at this path",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    unix_socket: Option<PathBuf>,
+    #[structopt(
+        long = "tls-cert",
+        help = "Serves the TCP and admin listeners over TLS with the certificate chain in this PEM file",
+        value_name = "PATH",
+        requires = "tls-key",
+        parse(from_os_str)
+    )]
+    tls_cert: Option<PathBuf>,
+    #[structopt(
+        long = "tls-key",
+        help = "Private key, in a PEM file, of the certificate of --tls-cert",
+        value_name = "PATH",
+        requires = "tls-cert",
+        parse(from_os_str)
+    )]
+    tls_key: Option<PathBuf>,
+    #[structopt(
+    long = "replica-of",
+    help = "Replicates the server at this address and serves reads only",
+    value_name = ADDRESS_FORMAT,
+    parse(try_from_str)
+    )]
+    replica_of: Option<SocketAddr>,
+    #[structopt(long = "read-only", help = "Serves reads only, refusing every write")]
+    read_only: bool,
+    #[structopt(
+        long = "warm-up",
+        help = "Answers health checks as starting while the engine opens"
+    )]
+    warm_up: bool,
+    #[structopt(
+        long = "lame-duck-secs",
+        help = "Shuts down this many seconds after entering lame-duck mode (SIGUSR1 or drain)",
+        value_name = "SECONDS"
+    )]
+    lame_duck_secs: Option<u64>,
+    #[structopt(
+        long = "consistency",
+        help = "Acknowledges writes once replicas have them: async, semi-sync:N (N received) or sync:N (N applied)",
+        value_name = "LEVEL"
+    )]
+    consistency: Option<Consistency>,
+    #[structopt(
+        long = "auth-token",
+        help = "Requires clients to authenticate with this token",
+        value_name = "TOKEN"
+    )]
+    auth_token: Option<String>,
+    #[structopt(
+        long = "acl",
+        help = "Loads per-token permissions from this TOML file",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    acl: Option<PathBuf>,
+    #[structopt(
+        long = "tenants",
+        help = "Hosts the tenants listed in this TOML file, each in a store of its own",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    tenants: Option<PathBuf>,
+    #[structopt(
+        long = "max-connections",
+        help = "Rejects clients beyond this many concurrent connections",
+        value_name = "COUNT"
+    )]
+    max_connections: Option<usize>,
+    #[structopt(
+        long = "rate-limit",
+        help = "Rejects requests beyond this many per second on a connection",
+        value_name = "REQUESTS"
+    )]
+    rate_limit: Option<u32>,
+    #[structopt(
+        long = "idle-timeout",
+        help = "Closes connections idle for this many seconds",
+        value_name = "SECONDS"
+    )]
+    idle_timeout: Option<u64>,
+    #[structopt(
+        long = "request-timeout",
+        help = "Fails requests waiting this many milliseconds for the engine, and drops clients not reading their response within it",
+        value_name = "MILLISECONDS"
+    )]
+    request_timeout: Option<u64>,
+    #[structopt(
+        long = "slow-log-ms",
+        help = "Logs requests taking at least this many milliseconds",
+        value_name = "MILLISECONDS"
+    )]
+    slow_log_ms: Option<u64>,
+    #[structopt(
+        long = "snapshot-interval",
+        help = "Snapshots the store every this many seconds",
+        value_name = "SECONDS"
+    )]
+    snapshot_interval: Option<u64>,
+    #[structopt(
+        long = "snapshot-dir",
+        help = "Keeps scheduled snapshots in this directory [default: DIR/snapshots, DIR the data directory]",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    snapshot_dir: Option<PathBuf>,
+    #[structopt(
+        long = "snapshot-retain",
+        help = "Keeps this many of the most recent scheduled snapshots [default: 3]",
+        value_name = "COUNT"
+    )]
+    snapshot_retain: Option<usize>,
+    #[structopt(
+        long = "audit-log",
+        help = "Appends a record of every write to this file",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    audit_log: Option<PathBuf>,
+    #[structopt(
+        long = "audit-log-max-bytes",
+        help = "Rotates the audit log once it reaches this size [default: 64 MiB]",
+        value_name = "BYTES"
+    )]
+    audit_log_max_bytes: Option<u64>,
+    #[structopt(
+        long = "audit-log-files",
+        help = "Keeps this many rotated audit logs [default: 5]",
+        value_name = "COUNT"
+    )]
+    audit_log_files: Option<usize>,
+    #[structopt(
+        long = "audit-redact-values",
+        help = "Leaves the values written out of the audit log"
+    )]
+    audit_redact_values: bool,
+    #[structopt(
+        long = "log-format",
+        help = "Sets the log format",
+        value_name = "FORMAT",
+        possible_values = &LogFormat::variants(),
+        case_insensitive = true
+    )]
+    log_format: Option<LogFormat>,
+    #[structopt(
+        long = "log-level",
+        help = "Logs records up to this level, overriding RUST_LOG: error, warn, info, debug or trace",
+        value_name = "LEVEL"
+    )]
+    log_level: Option<LevelFilter>,
+    #[structopt(
+        long = "log-file",
+        help = "Appends logs to this file instead of writing them to standard output",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    log_file: Option<PathBuf>,
+    #[cfg(unix)]
+    #[structopt(
+        long = "daemonize",
+        help = "Detaches from the terminal and runs in the background"
+    )]
+    daemonize: bool,
+    #[structopt(
+        long = "pid-file",
+        help = "Writes the process ID to this file while the server runs",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    pid_file: Option<PathBuf>,
+    #[structopt(
+        long = "sync-policy",
+        help = "When the kvs engine forces writes to disk: never or always",
+        value_name = "POLICY"
+    )]
+    sync_policy: Option<SyncPolicy>,
+    #[structopt(
+        long = "compaction-threshold",
+        help = "Stale bytes past which the kvs engine compacts its log",
+        value_name = "BYTES"
+    )]
+    compaction_threshold: Option<u64>,
+    #[structopt(
+        long = "compaction-window",
+        help = "Only compacts within this daily time window in UTC, e.g. 02:00-05:00 (repeatable)",
+        value_name = "HH:MM-HH:MM"
+    )]
+    compaction_windows: Vec<CompactionWindow>,
+    #[structopt(
+        long = "compaction-rate",
+        help = "Writes at most this many bytes per second when compacting",
+        value_name = "BYTES"
+    )]
+    compaction_rate: Option<u64>,
+    #[structopt(
+        long = "value-threshold",
+        help = "Size from which the kvs engine keeps values in a separate value log",
+        value_name = "BYTES"
+    )]
+    value_threshold: Option<u64>,
+    #[structopt(
+        long = "cache-size",
+        help = "Bytes of recently read values the kvs engine keeps in memory",
+        value_name = "BYTES"
+    )]
+    cache_size: Option<u64>,
+    #[structopt(
+        long = "max-key-bytes",
+        help = "Rejects keys longer than this with the kvs engine",
+        value_name = "BYTES"
+    )]
+    max_key_bytes: Option<u64>,
+    #[structopt(
+        long = "max-value-bytes",
+        help = "Rejects values longer than this with the kvs engine, and larger requests",
+        value_name = "BYTES"
+    )]
+    max_value_bytes: Option<u64>,
+    #[structopt(
+        long = "max-disk-bytes",
+        help = "Rejects writes adding data once the kvs engine takes this much disk",
+        value_name = "BYTES"
+    )]
+    max_disk_bytes: Option<u64>,
+    #[structopt(
+        long = "trash-retention",
+        help = "Keeps the string keys removed from the kvs engine in a trash this long",
+        value_name = "SECONDS"
+    )]
+    trash_retention: Option<u64>,
+    #[structopt(
+        long = "soft-stall-bytes",
+        help = "Slows writes down once the kvs engine holds this many stale bytes",
+        value_name = "BYTES"
+    )]
+    soft_stall_bytes: Option<u64>,
+    #[structopt(
+        long = "hard-stall-bytes",
+        help = "Compacts at once, even outside the windows, past this many stale bytes",
+        value_name = "BYTES"
+    )]
+    hard_stall_bytes: Option<u64>,
+    #[structopt(
+        long = "verify-on-start",
+        help = "Checks every record and the rebuilt index of the kvs engine before serving"
+    )]
+    verify_on_start: bool,
+    #[structopt(
+        long = "maxmemory",
+        help = "Bytes of keys and values the memory engine holds at most",
+        value_name = "BYTES"
+    )]
+    maxmemory: Option<u64>,
+    #[structopt(
+        long = "maxmemory-policy",
+        help = "What the memory engine evicts once full: noeviction, lru, random or ttl-first",
+        value_name = "POLICY"
+    )]
+    maxmemory_policy: Option<EvictionPolicy>,
+    #[structopt(
+        long = "index",
+        help = "JSON path of values to index for lookups, e.g. $.email (repeatable)",
+        value_name = "PATH"
+    )]
+    indexes: Vec<String>,
+    #[cfg(feature = "raft")]
+    #[structopt(
+    long = "raft-addr",
+    help = "Joins a Raft cluster, listening for peers on this address",
+    value_name = ADDRESS_FORMAT,
+    parse(try_from_str)
+    )]
+    raft_addr: Option<SocketAddr>,
+    #[cfg(feature = "raft")]
+    #[structopt(
+    long = "raft-peer",
+    help = "Raft address of another cluster member (repeatable)",
+    value_name = ADDRESS_FORMAT,
+    parse(try_from_str)
+    )]
+    raft_peers: Vec<SocketAddr>,
+    #[cfg(feature = "otel")]
+    #[structopt(
+        long = "otlp-endpoint",
+        help = "Exports a span of every request to this OTLP/HTTP collector, e.g. http://localhost:4318/v1/traces",
+        value_name = "URL"
+    )]
+    otlp_endpoint: Option<String>,
+    #[cfg(feature = "uring")]
+    #[structopt(
+        long = "io-uring",
+        help = "Appends to the log of the kvs engine and reads it through io_uring"
+    )]
+    io_uring: bool,
 }
 
 arg_enum! {
@@ -35,7 +410,19 @@ arg_enum! {
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     enum Engine {
         kvs,
-        sled
+        sled,
+        rocksdb,
+        lsm,
+        memory
+    }
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum LogFormat {
+        text,
+        json
     }
 }
 
@@ -47,26 +434,757 @@ fn main() {
     }
 }
 
-fn run(opt: Opt) -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
-        .target(Target::Stdout)
-        .init();
+impl Opt {
+    /// Fills the settings not given on the command line from the
+    /// configuration file, if any.
+    fn merge_config(mut self) -> Result<Opt> {
+        let config = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => return Ok(self),
+        };
+        self.addr = self.addr.or(config.addr);
+        self.data_dir = self.data_dir.or(config.data_dir);
+        if self.engine.is_none() {
+            self.engine = config
+                .engine
+                .map(|e| e.parse())
+                .transpose()
+                .map_err(MyError::StringError)?;
+        }
+        self.ws_addr = self.ws_addr.or(config.ws_addr);
+        if self.ws_origins.is_empty() {
+            self.ws_origins = config.ws_origins;
+        }
+        self.admin_addr = self.admin_addr.or(config.admin_addr);
+        #[cfg(unix)]
+        {
+            self.unix_socket = self.unix_socket.or(config.unix_socket);
+        }
+        #[cfg(not(unix))]
+        if config.unix_socket.is_some() {
+            return Err(MyError::StringError(
+                "Unix sockets are not available on this platform".to_owned(),
+            ));
+        }
+        self.tls_cert = self.tls_cert.or(config.tls_cert);
+        self.tls_key = self.tls_key.or(config.tls_key);
+        self.replica_of = self.replica_of.or(config.replica_of);
+        self.read_only |= config.read_only.unwrap_or(false);
+        self.warm_up |= config.warm_up.unwrap_or(false);
+        self.lame_duck_secs = self.lame_duck_secs.or(config.lame_duck_secs);
+        self.consistency = self.consistency.or(config.consistency);
+        self.auth_token = self.auth_token.or(config.auth_token);
+        self.acl = self.acl.or(config.acl);
+        self.tenants = self.tenants.or(config.tenants);
+        self.max_connections = self.max_connections.or(config.max_connections);
+        self.rate_limit = self.rate_limit.or(config.rate_limit);
+        self.idle_timeout = self.idle_timeout.or(config.idle_timeout);
+        self.request_timeout = self.request_timeout.or(config.request_timeout);
+        self.slow_log_ms = self.slow_log_ms.or(config.slow_log_ms);
+        self.snapshot_interval = self.snapshot_interval.or(config.snapshot_interval);
+        self.snapshot_dir = self.snapshot_dir.or(config.snapshot_dir);
+        self.snapshot_retain = self.snapshot_retain.or(config.snapshot_retain);
+        self.audit_log = self.audit_log.or(config.audit_log);
+        self.audit_log_max_bytes = self.audit_log_max_bytes.or(config.audit_log_max_bytes);
+        self.audit_log_files = self.audit_log_files.or(config.audit_log_files);
+        self.audit_redact_values |= config.audit_redact_values.unwrap_or(false);
+        if self.log_format.is_none() {
+            self.log_format = config
+                .log_format
+                .map(|f| f.parse())
+                .transpose()
+                .map_err(MyError::StringError)?;
+        }
+        self.log_level = self.log_level.or(config.log_level);
+        self.log_file = self.log_file.or(config.log_file);
+        #[cfg(unix)]
+        {
+            self.daemonize |= config.daemonize.unwrap_or(false);
+        }
+        #[cfg(not(unix))]
+        if config.daemonize == Some(true) {
+            return Err(MyError::StringError(
+                "Daemon mode is not available on this platform".to_owned(),
+            ));
+        }
+        self.pid_file = self.pid_file.or(config.pid_file);
+        self.sync_policy = self.sync_policy.or(config.sync_policy);
+        self.compaction_threshold = self.compaction_threshold.or(config.compaction_threshold);
+        if self.compaction_windows.is_empty() {
+            self.compaction_windows = config.compaction_windows;
+        }
+        self.compaction_rate = self.compaction_rate.or(config.compaction_rate);
+        self.value_threshold = self.value_threshold.or(config.value_threshold);
+        self.cache_size = self.cache_size.or(config.cache_size);
+        self.max_key_bytes = self.max_key_bytes.or(config.max_key_bytes);
+        self.max_value_bytes = self.max_value_bytes.or(config.max_value_bytes);
+        self.max_disk_bytes = self.max_disk_bytes.or(config.max_disk_bytes);
+        self.trash_retention = self.trash_retention.or(config.trash_retention);
+        self.soft_stall_bytes = self.soft_stall_bytes.or(config.soft_stall_bytes);
+        self.hard_stall_bytes = self.hard_stall_bytes.or(config.hard_stall_bytes);
+        self.verify_on_start |= config.verify_on_start.unwrap_or(false);
+        self.maxmemory = self.maxmemory.or(config.maxmemory);
+        self.maxmemory_policy = self.maxmemory_policy.or(config.maxmemory_policy);
+        if self.indexes.is_empty() {
+            self.indexes = config.indexes;
+        }
+        #[cfg(feature = "grpc")]
+        {
+            self.grpc_addr = self.grpc_addr.or(config.grpc_addr);
+        }
+        #[cfg(not(feature = "grpc"))]
+        if config.grpc_addr.is_some() {
+            return Err(MyError::StringError(
+                "grpc_addr needs kvs-server built with the `grpc` feature".to_owned(),
+            ));
+        }
+        #[cfg(feature = "raft")]
+        {
+            self.raft_addr = self.raft_addr.or(config.raft_addr);
+            if self.raft_peers.is_empty() {
+                self.raft_peers = config.raft_peers;
+            }
+        }
+        #[cfg(feature = "otel")]
+        {
+            self.otlp_endpoint = self.otlp_endpoint.or(config.otlp_endpoint);
+        }
+        #[cfg(not(feature = "otel"))]
+        if config.otlp_endpoint.is_some() {
+            return Err(MyError::StringError(
+                "otlp_endpoint needs kvs-server built with the `otel` feature".to_owned(),
+            ));
+        }
+        #[cfg(not(feature = "raft"))]
+        if config.raft_addr.is_some() || !config.raft_peers.is_empty() {
+            return Err(MyError::StringError(
+                "Raft settings need kvs-server built with the `raft` feature".to_owned(),
+            ));
+        }
+        #[cfg(feature = "uring")]
+        {
+            self.io_uring |= config.io_uring.unwrap_or(false);
+        }
+        #[cfg(not(feature = "uring"))]
+        if config.io_uring == Some(true) {
+            return Err(MyError::StringError(
+                "io_uring needs kvs-server built with the `uring` feature".to_owned(),
+            ));
+        }
+        Ok(self)
+    }
+
+    fn data_dir(&self) -> Result<PathBuf> {
+        match &self.data_dir {
+            Some(dir) => Ok(dir.clone()),
+            None => Ok(current_dir()?),
+        }
+    }
+
+    fn addr(&self) -> SocketAddr {
+        self.addr
+            .unwrap_or_else(|| DEFAULT_LISTENING_ADDRESS.parse().unwrap())
+    }
+}
+
+fn run(flags: Opt) -> Result<()> {
+    let opt = flags.clone().merge_config()?;
+    // failures up to here still show on the terminal
+    let log_file = opt.log_file.as_deref().map(open_log_file).transpose()?;
+    if let Some(path) = &opt.pid_file {
+        check_not_running(path)?;
+    }
+    #[cfg(unix)]
+    if opt.daemonize {
+        daemonize(log_file.as_ref())?;
+    }
+
+    init_logging(&opt, log_file)?;
+    // removed when `run` returns, after the server shut down
+    let _pid_file = opt.pid_file.as_deref().map(PidFile::create).transpose()?;
+
+    // before the engine starts threads, so that they all inherit the mask
+    let (shutdown_sender, shutdown_receiver) = mpsc::channel();
+    #[cfg(unix)]
+    handle_signals(flags.clone(), opt.log_level.is_some(), shutdown_receiver)?;
+    #[cfg(not(unix))]
+    drop(shutdown_receiver);
 
     info!("Starting up");
     //let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     //info!("Storage engine: {}", engine);
-    info!("Listening on {}", opt.addr);
+    info!("Listening on {}", opt.addr());
 
     let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+    if engine != Engine::memory && (opt.maxmemory.is_some() || opt.maxmemory_policy.is_some()) {
+        return Err(MyError::StringError(
+            "--maxmemory only applies to the memory engine".to_owned(),
+        ));
+    }
+    if engine != Engine::kvs
+        && (opt.max_key_bytes.is_some()
+            || opt.max_value_bytes.is_some()
+            || opt.max_disk_bytes.is_some())
+    {
+        return Err(MyError::StringError(
+            "--max-key-bytes, --max-value-bytes and --max-disk-bytes only apply to the kvs engine"
+                .to_owned(),
+        ));
+    }
+    if engine != Engine::kvs && (opt.soft_stall_bytes.is_some() || opt.hard_stall_bytes.is_some()) {
+        return Err(MyError::StringError(
+            "--soft-stall-bytes and --hard-stall-bytes only apply to the kvs engine".to_owned(),
+        ));
+    }
+    if engine != Engine::kvs && opt.trash_retention.is_some() {
+        return Err(MyError::StringError(
+            "--trash-retention only applies to the kvs engine".to_owned(),
+        ));
+    }
+    #[cfg(feature = "uring")]
+    if engine != Engine::kvs && opt.io_uring {
+        return Err(MyError::StringError(
+            "--io-uring only applies to the kvs engine".to_owned(),
+        ));
+    }
+    if engine != Engine::kvs && opt.verify_on_start {
+        return Err(MyError::StringError(
+            "--verify-on-start only applies to the kvs engine".to_owned(),
+        ));
+    }
+
+    // installed before serving, so that every request has a span
+    #[cfg(feature = "otel")]
+    let exporter = match &opt.otlp_endpoint {
+        Some(endpoint) => {
+            info!("Exporting traces to {}", endpoint);
+            Some(kvs::OtlpExporter::install(endpoint, "kvs-server")?)
+        }
+        None => None,
+    };
+
+    // bound before the engine opens, which may take a while
+    let warm_up = match opt.warm_up {
+        true => Some(WarmUp::start(opt.addr(), &engine.to_string())?),
+        false => None,
+    };
+    let served = match engine {
+        Engine::kvs => {
+            let mut options = KvStoreOptions::default();
+            if let Some(policy) = opt.sync_policy {
+                options = options.with_sync_policy(policy);
+            }
+            if let Some(bytes) = opt.compaction_threshold {
+                options = options.with_compaction_threshold(bytes);
+            }
+            if let Some(bytes) = opt.value_threshold {
+                options = options.with_value_threshold(bytes);
+            }
+            if let Some(bytes) = opt.cache_size {
+                options = options.with_cache_capacity(bytes);
+            }
+            if let Some(bytes) = opt.max_key_bytes {
+                options = options.with_max_key_bytes(bytes);
+            }
+            if let Some(bytes) = opt.max_value_bytes {
+                options = options.with_max_value_bytes(bytes);
+            }
+            if let Some(bytes) = opt.max_disk_bytes {
+                options = options.with_max_disk_bytes(bytes);
+            }
+            if let Some(secs) = opt.trash_retention {
+                options = options.with_trash(Duration::from_secs(secs));
+            }
+            #[cfg(feature = "uring")]
+            if opt.io_uring {
+                options = options.with_io_uring();
+            }
+            if let Some(bytes) = opt.soft_stall_bytes {
+                options = options.with_soft_stall_bytes(bytes);
+            }
+            if let Some(bytes) = opt.hard_stall_bytes {
+                options = options.with_hard_stall_bytes(bytes);
+            }
+            if !opt.compaction_windows.is_empty() {
+                options = options.with_compaction_windows(opt.compaction_windows.clone());
+            }
+            if let Some(bytes) = opt.compaction_rate {
+                options = options.with_compaction_rate(bytes);
+            }
+            let store = if opt.verify_on_start {
+                let (store, report) = KvStore::open_verified(opt.data_dir()?, options)?;
+                info!(
+                    "Verified {} records and {} keys ({} separated values) in {:?}",
+                    report.records, report.keys, report.separated_values, report.elapsed
+                );
+                store
+            } else {
+                KvStore::open_with_options(opt.data_dir()?, options)?
+            };
+            run_engine(store, &opt, &flags, warm_up, shutdown_sender)
+        }
+        Engine::sled => run_engine(
+            SledKvsEngine::open(opt.data_dir()?)?,
+            &opt,
+            &flags,
+            warm_up,
+            shutdown_sender,
+        ),
+        #[cfg(feature = "rocksdb")]
+        Engine::rocksdb => run_engine(
+            kvs::RocksKvsEngine::open(opt.data_dir()?)?,
+            &opt,
+            &flags,
+            warm_up,
+            shutdown_sender,
+        ),
+        #[cfg(not(feature = "rocksdb"))]
+        Engine::rocksdb => Err(MyError::StringError(
+            "kvs-server was built without the rocksdb feature".to_owned(),
+        )),
+        Engine::lsm => {
+            let mut options = LsmOptions::default();
+            if !opt.compaction_windows.is_empty() {
+                options = options.with_compaction_windows(opt.compaction_windows.clone());
+            }
+            if let Some(bytes) = opt.compaction_rate {
+                options = options.with_compaction_rate(bytes);
+            }
+            let store = LsmEngine::open_with_options(opt.data_dir()?, options)?;
+            run_engine(store, &opt, &flags, warm_up, shutdown_sender)
+        }
+        Engine::memory => {
+            let mut engine = MemEngine::new();
+            if let Some(bytes) = opt.maxmemory {
+                let policy = opt.maxmemory_policy.unwrap_or(EvictionPolicy::NoEviction);
+                engine = engine.with_max_memory(bytes, policy);
+            }
+            run_engine(engine, &opt, &flags, warm_up, shutdown_sender)
+        }
+    };
+    // sends the spans of the last requests
+    #[cfg(feature = "otel")]
+    if let Some(exporter) = exporter {
+        if let Err(e) = exporter.shutdown() {
+            warn!("{}", e);
+        }
+    }
+    served
+}
+
+fn open_log_file(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|source| MyError::File {
+            path: path.to_owned(),
+            source,
+        })
+}
+
+/// Forks into the background: the parent exits, and the child leaves the
+/// session of the terminal and forks again, so that it never gets one
+/// back. Standard input then reads from /dev/null, and standard output and
+/// error go to `log`, or to /dev/null. Must run before any thread starts.
+#[cfg(unix)]
+fn daemonize(log: Option<&File>) -> Result<()> {
+    fork_and_exit_parent()?;
+    // SAFETY: `setsid` has no preconditions.
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    fork_and_exit_parent()?;
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    let out = log.unwrap_or(&null);
+    redirect(&null, libc::STDIN_FILENO)?;
+    redirect(out, libc::STDOUT_FILENO)?;
+    redirect(out, libc::STDERR_FILENO)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn fork_and_exit_parent() -> Result<()> {
+    // SAFETY: the process has a single thread, so the child gets a
+    // consistent copy of it.
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error().into()),
+        0 => Ok(()),
+        _ => exit(0),
+    }
+}
+
+/// Makes `fd` refer to `file`.
+#[cfg(unix)]
+fn redirect(file: &File, fd: RawFd) -> io::Result<()> {
+    // SAFETY: both descriptors are open.
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
 
-    match engine {
-        Engine::kvs => run_engine(KvStore::open(current_dir()?)?, opt.addr),
-        Engine::sled => run_engine(SledKvsEngine::open(current_dir()?)?, opt.addr),
+/// Fails if the PID file at `path` names a process that is still running.
+/// A file left by a server that did not shut down cleanly is ignored.
+fn check_not_running(path: &Path) -> Result<()> {
+    let pid = match fs::read_to_string(path) {
+        Ok(content) => content.trim().parse::<u32>().ok(),
+        Err(_) => None,
+    };
+    match pid {
+        Some(pid) if is_running(pid) => Err(MyError::StringError(format!(
+            "kvs-server is already running with PID {} (see {})",
+            pid,
+            path.display()
+        ))),
+        _ => Ok(()),
     }
 }
 
-fn run_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = Server::new(engine);
-    server.open(addr)
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => pid,
+        _ => return false,
+    };
+    // SAFETY: signal 0 only checks that the process exists.
+    let exists = unsafe { libc::kill(pid, 0) } == 0;
+    // or it exists, but belongs to another user
+    exists || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
+
+/// The PID file of the running server, removed when dropped.
+struct PidFile(PathBuf);
+
+impl PidFile {
+    fn create(path: &Path) -> Result<PidFile> {
+        fs::write(path, format!("{}\n", process::id())).map_err(|source| MyError::File {
+            path: path.to_owned(),
+            source,
+        })?;
+        Ok(PidFile(path.to_owned()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            warn!("Cannot remove the PID file {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Blocks SIGINT, SIGTERM and SIGHUP in the calling thread, and so in every
+/// thread it spawns later, then waits for them on a dedicated thread which
+/// shuts down the server it is handed on SIGINT and SIGTERM, and reloads
+/// the configuration file on SIGHUP.
+#[cfg(unix)]
+fn handle_signals(
+    flags: Opt,
+    level_reloadable: bool,
+    server: mpsc::Receiver<(ShutdownHandle, ReloadHandle)>,
+) -> Result<()> {
+    // SAFETY: the set is initialized by `sigemptyset` before use.
+    let signals = unsafe {
+        let mut signals: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::sigaddset(&mut signals, libc::SIGHUP);
+        libc::sigaddset(&mut signals, libc::SIGUSR1);
+        signals
+    };
+    // SAFETY: `signals` is a valid set and the old mask is not requested.
+    let errno = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) };
+    if errno != 0 {
+        return Err(io::Error::from_raw_os_error(errno).into());
+    }
+    thread::spawn(move || {
+        let mut handles = None;
+        loop {
+            let mut signal = 0;
+            // SAFETY: both pointers are valid for the duration of the call.
+            if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+                return;
+            }
+            if handles.is_none() {
+                handles = server.recv().ok();
+            }
+            let (shutdown, reload) = match &handles {
+                Some(handles) => handles,
+                None => return,
+            };
+            if signal == libc::SIGUSR1 {
+                info!("Received SIGUSR1, draining");
+                shutdown.lame_duck();
+                continue;
+            }
+            if signal != libc::SIGHUP {
+                info!("Received signal {}, shutting down", signal);
+                shutdown.shutdown();
+                return;
+            }
+            info!("Received SIGHUP, reloading the configuration");
+            if let Err(e) = reload_config(&flags, level_reloadable, reload) {
+                warn!("Configuration not reloaded: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Reads the configuration file again and applies the settings that can
+/// change while the server runs, flags still taking precedence.
+#[cfg(unix)]
+fn reload_config(flags: &Opt, level_reloadable: bool, server: &ReloadHandle) -> Result<()> {
+    if flags.config.is_none() {
+        info!("No configuration file to reload");
+        return Ok(());
+    }
+    let opt = flags.clone().merge_config()?;
+    // loaded before anything changes, so that a bad file changes nothing
+    let acl = opt.acl.as_deref().map(Acl::load).transpose()?;
+    match opt.log_level {
+        Some(level) if level_reloadable => set_log_level(level)?,
+        Some(_) => warn!("log_level takes effect on restart, RUST_LOG was used at start"),
+        None => {}
+    }
+    server.set_auth_token(opt.auth_token.clone());
+    server.set_acl(acl);
+    server.set_max_connections(opt.max_connections);
+    server.set_rate_limit(opt.rate_limit);
+    server.set_idle_timeout(opt.idle_timeout.map(Duration::from_secs));
+    server.set_request_timeout(opt.request_timeout.map(Duration::from_millis));
+    server.set_slow_log(opt.slow_log_ms.map(Duration::from_millis));
+    if let Some(bytes) = opt.compaction_threshold {
+        if opt.engine.unwrap_or(DEFAULT_ENGINE) == Engine::kvs {
+            server.set_compaction_threshold(bytes)?;
+        }
+    }
+    info!("Configuration reloaded");
+    Ok(())
+}
+
+/// Logs through `tracing`, to `log_file` or standard output, in the
+/// format asked for. Records of the `log` crate, which most of the library
+/// logs with, show within the spans of the connection and the request they
+/// come from, as those of `tracing` do.
+fn init_logging(opt: &Opt, log_file: Option<File>) -> Result<()> {
+    let filter = match opt.log_level {
+        Some(level) => EnvFilter::new(level.to_string()),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+    let ansi = log_file.is_none() && io::stdout().is_terminal();
+    let writer = match log_file {
+        Some(file) => BoxMakeWriter::new(Mutex::new(file)),
+        None => BoxMakeWriter::new(io::stdout),
+    };
+    let format = fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .with_thread_names(true);
+    let format = match opt.log_format {
+        Some(LogFormat::json) => format
+            .json()
+            .flatten_event(true)
+            .with_span_list(true)
+            .boxed(),
+        _ => format.boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(format)
+        .try_init()
+        .map_err(|e| MyError::StringError(format!("Cannot set up logging: {}", e)))?;
+    // the level set by `RUST_LOG` stays
+    if opt.log_level.is_some() {
+        let _ = LOG_FILTER.set(handle);
+    }
+    Ok(())
+}
+
+/// Lets records up to `level` through from now on.
+#[cfg(unix)]
+fn set_log_level(level: LevelFilter) -> Result<()> {
+    if let Some(filter) = LOG_FILTER.get() {
+        filter
+            .reload(EnvFilter::new(level.to_string()))
+            .map_err(|e| MyError::StringError(format!("Cannot change the log level: {}", e)))?;
+        log::set_max_level(level);
+    }
+    Ok(())
+}
+
+fn run_engine<E: KvsEngine + Send + 'static>(
+    engine: E,
+    opt: &Opt,
+    flags: &Opt,
+    warm_up: Option<WarmUp>,
+    shutdown: mpsc::Sender<(ShutdownHandle, ReloadHandle)>,
+) -> Result<()> {
+    if opt.indexes.is_empty() {
+        return serve(engine, opt, flags, warm_up, shutdown);
+    }
+    info!("Indexing {:?}", opt.indexes);
+    let paths: Vec<&str> = opt.indexes.iter().map(String::as_str).collect();
+    serve(
+        IndexedEngine::new(engine, &paths)?,
+        opt,
+        flags,
+        warm_up,
+        shutdown,
+    )
+}
+
+/// Serves `engine` as `opt` says, taking over from `warm_up` if any;
+/// `flags` are the options given on the command line, which take
+/// precedence over the configuration file when it is reloaded.
+fn serve<E: KvsEngine + Send + 'static>(
+    engine: E,
+    opt: &Opt,
+    flags: &Opt,
+    warm_up: Option<WarmUp>,
+    shutdown: mpsc::Sender<(ShutdownHandle, ReloadHandle)>,
+) -> Result<()> {
+    let mut server = Server::new(engine);
+    if let Some(ws_addr) = opt.ws_addr {
+        server = server
+            .with_websocket(ws_addr)
+            .with_websocket_origins(opt.ws_origins.clone());
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_addr) = opt.grpc_addr {
+        server = server.with_grpc(grpc_addr);
+    }
+    if let Some(admin_addr) = opt.admin_addr {
+        server = server.with_admin_listener(admin_addr);
+    }
+    #[cfg(unix)]
+    {
+        let (flags, level_reloadable) = (flags.clone(), opt.log_level.is_some());
+        server = server.with_config_reload(move |reload| {
+            info!("Reloading the configuration on request");
+            reload_config(&flags, level_reloadable, reload)
+        });
+    }
+    #[cfg(not(unix))]
+    let _ = flags;
+    #[cfg(unix)]
+    if let Some(path) = &opt.unix_socket {
+        server = server.with_unix_socket(path.clone());
+    }
+    match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => {
+            server = server.with_tls(ServerTlsConfig::from_pem_files(cert, key)?);
+        }
+        (None, None) => {}
+        _ => {
+            return Err(MyError::StringError(
+                "tls_cert and tls_key go together".to_owned(),
+            ))
+        }
+    }
+    #[cfg(feature = "raft")]
+    if let Some(raft_addr) = opt.raft_addr {
+        info!("Raft member at {}, peers {:?}", raft_addr, opt.raft_peers);
+        server = server.with_raft(kvs::RaftConfig {
+            raft_addr,
+            peers: opt.raft_peers.clone(),
+            client_addr: opt.addr(),
+            dir: opt.data_dir()?.join("raft"),
+        });
+    }
+    if let Some(token) = &opt.auth_token {
+        server = server.with_auth_token(token.clone());
+    }
+    if let Some(path) = &opt.acl {
+        server = server.with_acl(Acl::load(path)?);
+    }
+    if let Some(path) = &opt.tenants {
+        server = server.with_tenants(Tenants::load(path)?);
+    }
+    if let Some(max) = opt.max_connections {
+        server = server.with_max_connections(max);
+    }
+    // frames with room for more than one key and value are refused
+    // unread, so batches have to fit in them as well
+    if let Some(bytes) = opt.max_value_bytes {
+        let len = bytes
+            .saturating_add(opt.max_key_bytes.unwrap_or(0))
+            .saturating_add(FRAME_OVERHEAD);
+        server = server.with_max_message_len(usize::try_from(len).unwrap_or(usize::MAX));
+    }
+    if let Some(rate) = opt.rate_limit {
+        server = server.with_rate_limit(rate);
+    }
+    if let Some(secs) = opt.idle_timeout {
+        server = server.with_idle_timeout(Duration::from_secs(secs));
+    }
+    if let Some(ms) = opt.request_timeout {
+        server = server.with_request_timeout(Duration::from_millis(ms));
+    }
+    if let Some(ms) = opt.slow_log_ms {
+        server = server.with_slow_log(Duration::from_millis(ms));
+    }
+    if let Some(secs) = opt.snapshot_interval {
+        if secs == 0 {
+            return Err(MyError::StringError(
+                "The snapshot interval must be at least 1 second".to_owned(),
+            ));
+        }
+        let dir = match &opt.snapshot_dir {
+            Some(dir) => dir.clone(),
+            None => opt.data_dir()?.join("snapshots"),
+        };
+        let retain = opt.snapshot_retain.unwrap_or(DEFAULT_SNAPSHOT_RETAIN);
+        info!("Snapshotting into {} every {}s", dir.display(), secs);
+        server = server.with_snapshots(dir, Duration::from_secs(secs), retain);
+    }
+    if let Some(path) = &opt.audit_log {
+        let mut audit_log = AuditLog::open(path)?
+            .with_max_bytes(
+                opt.audit_log_max_bytes
+                    .unwrap_or(DEFAULT_AUDIT_LOG_MAX_BYTES),
+            )
+            .with_max_files(opt.audit_log_files.unwrap_or(DEFAULT_AUDIT_LOG_FILES));
+        if opt.audit_redact_values {
+            audit_log = audit_log.with_redacted_values();
+        }
+        info!("Auditing writes to {}", path.display());
+        server = server.with_audit_log(audit_log);
+    }
+    if let Some(leader_addr) = opt.replica_of {
+        info!("Replica of {}", leader_addr);
+        server = server.replica_of(leader_addr);
+        // a memory store starts empty, the position of its content is lost
+        if opt.engine.unwrap_or(DEFAULT_ENGINE) != Engine::memory {
+            server = server.with_replica_state(opt.data_dir()?.join(REPLICA_STATE_FILE));
+        }
+    }
+    if opt.read_only {
+        info!("Serving reads only");
+        server = server.with_read_only();
+    }
+    if let Some(consistency) = opt.consistency {
+        server = server.with_consistency(consistency);
+    }
+    if let Some(secs) = opt.lame_duck_secs {
+        server = server.with_lame_duck_period(Duration::from_secs(secs));
+    }
+    // the receiver is gone when signals are not handled
+    let _ = shutdown.send((server.shutdown_handle(), server.reload_handle()));
+    match warm_up {
+        Some(warm_up) => server.open_after_warm_up(warm_up),
+        None => server.open(opt.addr()),
+    }
 }
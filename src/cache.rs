@@ -0,0 +1,142 @@
+//! The read cache of a `KvsClient`, kept coherent through a subscription to
+//! every change of the store.
+use crate::client::Subscription;
+use crate::errors::Result;
+use crate::transport::Stream;
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Values recently read, dropped as the subscription reports their keys
+/// changing.
+pub(crate) struct ReadCache {
+    state: Arc<Mutex<CacheState>>,
+    /// The connection of the subscription, shut down to end its thread.
+    stream: Stream,
+}
+
+struct CacheState {
+    capacity: usize,
+    /// Value of each key cached, `None` if unset, with its last use.
+    entries: HashMap<String, (Option<String>, u64)>,
+    /// Keys cached by last use, the least recently used first.
+    recency: BTreeMap<u64, String>,
+    uses: u64,
+    /// Key of the read in flight, and whether it changed meanwhile, in
+    /// which case the value read may be stale and is not cached.
+    pending: Option<(String, bool)>,
+    /// Whether the subscription runs; once it ended nothing is cached.
+    live: bool,
+}
+
+impl CacheState {
+    fn invalidate(&mut self, key: &str) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.recency.remove(&used);
+        }
+        if let Some((pending, changed)) = &mut self.pending {
+            *changed |= pending == key;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        if let Some((_, changed)) = &mut self.pending {
+            *changed = true;
+        }
+    }
+}
+
+impl ReadCache {
+    /// A cache of up to `capacity` keys, invalidated by the events of
+    /// `subscription`, to every key, which a thread of its own reads.
+    pub(crate) fn start(subscription: Subscription, capacity: usize) -> Result<ReadCache> {
+        let stream = subscription.stream().try_clone()?;
+        let state = Arc::new(Mutex::new(CacheState {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            uses: 0,
+            pending: None,
+            live: true,
+        }));
+        let invalidated = Arc::clone(&state);
+        thread::spawn(move || {
+            for event in subscription {
+                match event {
+                    Ok(event) => invalidated.lock().unwrap().invalidate(event.key()),
+                    Err(e) => {
+                        warn!("Cache subscription failed: {}", e);
+                        break;
+                    }
+                }
+            }
+            let mut state = invalidated.lock().unwrap();
+            state.live = false;
+            state.clear();
+        });
+        Ok(ReadCache { state, stream })
+    }
+
+    /// The cached value of `key`, `Some(None)` if it is cached as unset.
+    pub(crate) fn get(&self, key: &str) -> Option<Option<String>> {
+        let mut state = self.state.lock().unwrap();
+        state.uses += 1;
+        let uses = state.uses;
+        let (value, used) = state.entries.get_mut(key)?;
+        let value = value.clone();
+        let last_used = std::mem::replace(used, uses);
+        state.recency.remove(&last_used);
+        state.recency.insert(uses, key.to_owned());
+        Some(value)
+    }
+
+    /// Notes that `key` is being read from the server, for `fill`.
+    pub(crate) fn begin(&self, key: &str) {
+        self.state.lock().unwrap().pending = Some((key.to_owned(), false));
+    }
+
+    /// Caches `value` as read for `key` since `begin`, unless the key
+    /// changed meanwhile, evicting the least recently used key if full.
+    pub(crate) fn fill(&self, key: &str, value: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        match state.pending.take() {
+            Some((pending, false)) if pending == key && state.live => (),
+            _ => return,
+        }
+        if state.capacity == 0 {
+            return;
+        }
+        if !state.entries.contains_key(key) && state.entries.len() >= state.capacity {
+            if let Some((_, evicted)) = state.recency.pop_first() {
+                state.entries.remove(&evicted);
+            }
+        }
+        state.invalidate(key);
+        state.uses += 1;
+        let uses = state.uses;
+        state.entries.insert(key.to_owned(), (value, uses));
+        state.recency.insert(uses, key.to_owned());
+    }
+
+    /// Drops `key`, which the client is about to change, so that it reads
+    /// its own writes before the subscription reports them.
+    pub(crate) fn invalidate(&self, key: &str) {
+        self.state.lock().unwrap().invalidate(key);
+    }
+
+    /// Drops every key.
+    pub(crate) fn clear(&self) {
+        self.state.lock().unwrap().clear();
+    }
+}
+
+impl Drop for ReadCache {
+    fn drop(&mut self) {
+        // ends the subscription, and its thread
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
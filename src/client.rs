@@ -1,62 +1,1542 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
+use crate::cache::ReadCache;
+use crate::codec::{Codec, Compression, MessageReader, MessageWriter};
+#[cfg(feature = "otel")]
+use crate::common::TRACE_CONTEXT_SINCE_VERSION;
+use crate::common::{
+    pairs_crc, AuthResponse, ChangeBatch, CountResponse, DbSizeResponse, Event, ExistsResponse,
+    ExportResponse, FindResponse, GetManyResponse, GetResponse, HGetAllResponse, HelloResponse,
+    LockResponse, MemberResponse, MembersResponse, MetadataResponse, PingResponse, Pong,
+    PushResponse, RangeResponse, RemoveResponse, ReplicaAck, Request, ScanResponse, ScoresResponse,
+    SelectResponse, ServerInfo, SetResponse, SlowLogResponse, StatsResponse, StreamPosition,
+    SubscribeResponse, SyncResponse, TtlResponse, VersionResponse, WatchResponse, PROTOCOL_VERSION,
+    RESUMABLE_SYNC_SINCE_VERSION,
+};
+use crate::common::{ReadConsistency, READ_CONSISTENCY_SINCE_VERSION, REQUEST_ID_SINCE_VERSION};
+use crate::engine::{EngineStats, KeyMeta};
 use crate::errors::{MyError, Result};
-use log::info;
-use serde::Deserialize;
-use serde_json::de::{Deserializer, IoRead};
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use crate::retry::{is_transient, RetryPolicy};
+use crate::slowlog::SlowRequest;
+use crate::tls::ClientTlsConfig;
+use crate::transport::{Endpoint, Stream};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufReader, BufWriter};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Duration;
+
+/// Keys the server looks at for each page of `KvsClient::scan_iter`.
+const SCAN_PAGE_KEYS: u32 = 1000;
 
 /// Key value store client
 pub struct KvsClient {
-    writer: BufWriter<TcpStream>,
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    writer: MessageWriter<BufWriter<Stream>>,
+    reader: MessageReader<BufReader<Stream>>,
+    server: ServerInfo,
+    /// Every address the client was given, in the order they are tried.
+    endpoints: Vec<Endpoint>,
+    /// Index in `endpoints` of the server connected to.
+    current: usize,
+    /// How the connection was set up, to set up the next one alike.
+    builder: KvsClientBuilder,
+    /// Random bits drawn on connecting, which the IDs of the requests start
+    /// with, followed by a count of the requests sent.
+    id_prefix: u64,
+    requests_sent: u64,
+    last_request_id: Option<String>,
+    cache: Option<ReadCache>,
+}
+
+/// Serializes as `Request::Tagged`, without cloning the request.
+#[derive(Serialize)]
+enum TaggedRequest<'a> {
+    Tagged {
+        request_id: &'a str,
+        request: &'a Request,
+    },
 }
 
 impl KvsClient {
     /// Connect to `addr` to access `KvsServer`.
+    ///
+    /// `addr` may name several servers, as a list of addresses or a DNS
+    /// name with several records: they are tried in order until one
+    /// answers the handshake, and requests fail over to the next one
+    /// when the connection dies.
+    ///
+    /// A dead connection, e.g. after the server restarted, is replaced
+    /// by a new one as the retry policy allows. A request in flight then
+    /// is sent again only when it had not reached the server, or when it
+    /// is a read, which is safe to repeat; other requests fail, but the
+    /// next ones go over the new connection.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        info!("Try to connect");
+        KvsClient::builder().connect(addr)
+    }
 
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
-        info!("Connected to {:?}", tcp_reader.peer_addr()?);
+    /// Connect to `addr` over TLS, trusting the certificates `tls` does,
+    /// see `Server::with_tls`.
+    pub fn connect_tls<A: ToSocketAddrs>(addr: A, tls: ClientTlsConfig) -> Result<Self> {
+        KvsClient::builder().with_tls(tls).connect(addr)
+    }
 
-        Ok(KvsClient {
-            writer: BufWriter::new(tcp_writer),
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-        })
+    /// Connect to `addr` and authenticate with `token`.
+    ///
+    /// Fails with `MyError::Unauthorized` if the server rejects the token.
+    pub fn connect_with_auth<A: ToSocketAddrs>(addr: A, token: String) -> Result<Self> {
+        KvsClient::builder().with_auth_token(token).connect(addr)
     }
 
-    /// Get the value of a given key from the server.
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
+    /// Connect to the server listening on the Unix socket at `path`, see
+    /// `Server::with_unix_socket`.
+    #[cfg(unix)]
+    pub fn connect_uds<P: AsRef<Path>>(path: P) -> Result<Self> {
+        KvsClient::builder().connect_uds(path)
+    }
+
+    /// Start configuring a client.
+    pub fn builder() -> KvsClientBuilder {
+        KvsClientBuilder::default()
+    }
+
+    /// What the server reported about itself when the client connected.
+    pub fn server_info(&self) -> &ServerInfo {
+        &self.server
+    }
+
+    /// The address of the server the client is connected to, or `None`
+    /// over a Unix socket.
+    pub fn server_addr(&self) -> Option<SocketAddr> {
+        match &self.endpoints[self.current] {
+            Endpoint::Tcp(addr) => Some(*addr),
+            #[cfg(unix)]
+            Endpoint::Unix(_) => None,
+        }
+    }
+
+    /// ID of the last request sent, which the server logs along with the
+    /// request and keeps in its slow log, to find out what became of a
+    /// request that failed; `None` with servers older than protocol
+    /// version 8.
+    pub fn last_request_id(&self) -> Option<&str> {
+        self.last_request_id.as_deref()
+    }
+
+    /// Sends `req` and reads its response. If the connection fails, it is
+    /// replaced by one to the next endpoint that is up, or to the same one
+    /// again, and the request is sent again once if that is safe.
+    fn call<T: DeserializeOwned>(&mut self, req: &Request) -> Result<T> {
+        self.reconnect_if_closed()?;
+        let (result, sent) = match self.send(req) {
+            Ok(()) => (self.reader.receive::<T>(), true),
+            Err(e) => (Err(e), false),
+        };
+        match result {
+            Err(e) if is_transient(&e) => {
+                warn!(
+                    "Lost connection to {} ({}), reconnecting",
+                    self.endpoints[self.current], e
+                );
+                self.reconnect()?;
+                // the server may have applied it before the connection died
+                if sent && !req.is_idempotent() {
+                    return Err(e);
+                }
+                self.exchange(req)
+            }
+            result => result,
+        }
+    }
+
+    /// Replaces the connection if the server closed it since the last
+    /// request. The server has not seen the next request yet, so it is
+    /// always safe to send it over the new one.
+    fn reconnect_if_closed(&mut self) -> Result<()> {
+        if self.reader.get_ref().get_ref().is_closed() {
+            warn!(
+                "Connection to {} was closed, reconnecting",
+                self.endpoints[self.current]
+            );
+            self.reconnect()?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the connection by one to the next endpoint that is up,
+    /// starting over at the current one if it is the only one.
+    fn reconnect(&mut self) -> Result<()> {
+        let next = (self.current + 1) % self.endpoints.len();
+        *self = self.builder.connect_endpoints(&self.endpoints, next)?;
+        Ok(())
+    }
+
+    fn send(&mut self, req: &Request) -> Result<()> {
+        self.write_request(req)?;
+        self.writer.flush()
+    }
+
+    /// Buffers `req`, along with the trace context the caller runs in if
+    /// there is one and the server takes it, under a new request ID if the
+    /// server takes one.
+    fn write_request(&mut self, req: &Request) -> Result<()> {
+        if let Some(cache) = &self.cache {
+            match req {
+                Request::FlushAll { .. } => cache.clear(),
+                req if !req.is_idempotent() => {
+                    req.keys().into_iter().for_each(|key| cache.invalidate(key))
+                }
+                _ => (),
+            }
+        }
+        #[cfg(feature = "otel")]
+        let traced = match self.server.version >= TRACE_CONTEXT_SINCE_VERSION {
+            true => crate::otel::current_context().map(|context| Request::Traced {
+                context,
+                request: Box::new(req.clone()),
+            }),
+            false => None,
+        };
+        #[cfg(feature = "otel")]
+        let req = traced.as_ref().unwrap_or(req);
+        if self.server.version < REQUEST_ID_SINCE_VERSION {
+            return self.writer.send(req);
+        }
+        self.requests_sent += 1;
+        let request_id = format!("{:016x}-{}", self.id_prefix, self.requests_sent);
+        self.writer.send(&TaggedRequest::Tagged {
+            request_id: &request_id,
+            request: req,
+        })?;
+        self.last_request_id = Some(request_id);
+        Ok(())
+    }
+
+    fn exchange<T: DeserializeOwned>(&mut self, req: &Request) -> Result<T> {
+        self.send(req)?;
+        self.reader.receive::<T>()
+    }
+
+    /// Exchanges protocol versions, learns the server's capabilities and
+    /// switches to `codec` and `compression` if the server supports them;
+    /// the first request on every connection.
+    fn hello(
+        &mut self,
+        codec: Codec,
+        compression: Compression,
+        tenant: Option<String>,
+    ) -> Result<()> {
+        let compression = match compression {
+            Compression::None => Vec::new(),
+            compression => vec![compression.name().to_owned()],
+        };
+        let request = Request::Hello {
+            version: PROTOCOL_VERSION,
+            codecs: vec![codec.name().to_owned()],
+            compression,
+            tenant: tenant.clone(),
+        };
+        self.writer.send(&request)?;
         self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
+        self.server = match self.reader.receive::<HelloResponse>()? {
+            HelloResponse::Ok(info) if info.version <= PROTOCOL_VERSION => info,
+            HelloResponse::Ok(info) => {
+                return Err(MyError::StringError(format!(
+                    "Server chose unsupported protocol version {}",
+                    info.version
+                )))
+            }
+            HelloResponse::Err(err) => return Err(MyError::StringError(err)),
+        };
+        // servers without tenants ignore the field
+        if self.server.tenant != tenant {
+            return Err(MyError::StringError(format!(
+                "Server does not host tenant `{}`",
+                tenant.unwrap_or_default()
+            )));
+        }
+        self.writer.negotiated(&self.server);
+        self.reader.negotiated(&self.server);
+        self.reader
+            .expect_echoes(self.server.version >= REQUEST_ID_SINCE_VERSION);
+        Ok(())
+    }
+
+    fn authenticate(&mut self, token: String) -> Result<()> {
+        self.send(&Request::Auth { token })?;
+        match self.reader.receive::<AuthResponse>()? {
+            AuthResponse::Ok(_value) => Ok(()),
+            AuthResponse::Unauthorized => Err(MyError::Unauthorized),
+        }
+    }
+
+    /// Get the value of a given key from the server, or from the cache
+    /// if the client keeps one, see `KvsClientBuilder::with_cache`.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.get_with_consistency(key, ReadConsistency::Local)
+    }
+
+    /// Get the value of a given key, read as fresh as `consistency` asks.
+    ///
+    /// Servers that cannot tell where their leader stands fail reads asking
+    /// for more than `Local`: replicas, and Raft nodes that are not the
+    /// leader, naming it if they know it.
+    pub fn get_with_consistency(
+        &mut self,
+        key: String,
+        consistency: ReadConsistency,
+    ) -> Result<Option<String>> {
+        self.check_consistency(consistency)?;
+        let cached = consistency.is_local() && self.cache.is_some();
+        if cached {
+            let cache = self.cache.as_ref().unwrap();
+            if let Some(value) = cache.get(&key) {
+                return Ok(value);
+            }
+            cache.begin(&key);
+        }
+        let request = Request::Get {
+            key: key.clone(),
+            consistency,
+        };
+        match self.call::<GetResponse>(&request)? {
+            GetResponse::Ok(value) => {
+                if let Some(cache) = self.cache.as_ref().filter(|_| cached) {
+                    cache.fill(&key, value.clone());
+                }
+                Ok(value)
+            }
+            GetResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get the value of a given key as `Bytes`, decoded without going
+    /// through a `String`. The cache of the client is not used.
+    pub fn get_bytes(&mut self, key: String) -> Result<Option<Bytes>> {
+        let request = Request::Get {
+            key,
+            consistency: ReadConsistency::Local,
+        };
+        match self.call::<GetResponse<Bytes>>(&request)? {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Fails if the server would not understand `consistency`, rather than
+    /// let it read locally.
+    fn check_consistency(&self, consistency: ReadConsistency) -> Result<()> {
+        if !consistency.is_local() && self.server.version < READ_CONSISTENCY_SINCE_VERSION {
+            return Err(MyError::StringError(format!(
+                "The server cannot read with `{}` consistency",
+                consistency
+            )));
+        }
+        Ok(())
+    }
+
+    /// Get the JSON text of the part of the JSON value of `key` at `path`,
+    /// e.g. `$.address.city`, without fetching the whole value.
+    pub fn get_path(&mut self, key: String, path: String) -> Result<Option<String>> {
+        let resp = self.call::<GetResponse>(&Request::GetPath { key, path })?;
+        match resp {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Put the JSON text `value` at `path` in the JSON value of `key`,
+    /// patching it on the server.
+    pub fn set_path(&mut self, key: String, path: String, value: String) -> Result<()> {
+        let resp = self.call::<SetResponse>(&Request::SetPath { key, path, value })?;
+        match resp {
+            SetResponse::Ok(()) => Ok(()),
+            SetResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Add `value` at the front of the list `key`, returning the length of
+    /// the list.
+    pub fn lpush(&mut self, key: String, value: String) -> Result<u64> {
+        self.send_push(Request::LPush { key, value })
+    }
+
+    /// Add `value` at the back of the list `key`, returning the length of
+    /// the list.
+    pub fn rpush(&mut self, key: String, value: String) -> Result<u64> {
+        self.send_push(Request::RPush { key, value })
+    }
+
+    fn send_push(&mut self, req: Request) -> Result<u64> {
+        let resp = self.call::<PushResponse>(&req)?;
+        match resp {
+            PushResponse::Ok(len) => Ok(len),
+            PushResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Remove and return the value at the front of the list `key`.
+    pub fn lpop(&mut self, key: String) -> Result<Option<String>> {
+        let resp = self.call::<GetResponse>(&Request::LPop { key })?;
         match resp {
             GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(msg) => Err(MyError::StringError(msg)),
+            GetResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get the values of the list `key` from index `start` to `stop`, both
+    /// included; negative indices count from the back.
+    pub fn lrange(&mut self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        let resp = self.call::<RangeResponse>(&Request::LRange { key, start, stop })?;
+        match resp {
+            RangeResponse::Ok(values) => Ok(values),
+            RangeResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Set `field` of the hash `key` to `value`.
+    pub fn hset(&mut self, key: String, field: String, value: String) -> Result<()> {
+        let resp = self.call::<SetResponse>(&Request::HSet { key, field, value })?;
+        match resp {
+            SetResponse::Ok(()) => Ok(()),
+            SetResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get `field` of the hash `key`.
+    pub fn hget(&mut self, key: String, field: String) -> Result<Option<String>> {
+        let resp = self.call::<GetResponse>(&Request::HGet { key, field })?;
+        match resp {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Remove `field` of the hash `key`.
+    pub fn hdel(&mut self, key: String, field: String) -> Result<()> {
+        let resp = self.call::<RemoveResponse>(&Request::HDel { key, field })?;
+        match resp {
+            RemoveResponse::Ok(()) => Ok(()),
+            RemoveResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get every field of the hash `key` with its value, in field order.
+    pub fn hgetall(&mut self, key: String) -> Result<Vec<(String, String)>> {
+        let resp = self.call::<HGetAllResponse>(&Request::HGetAll { key })?;
+        match resp {
+            HGetAllResponse::Ok(fields) => Ok(fields),
+            HGetAllResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Add `member` to the set `key`, returning whether it was not a member
+    /// yet.
+    pub fn sadd(&mut self, key: String, member: String) -> Result<bool> {
+        self.send_member(Request::SAdd { key, member })
+    }
+
+    /// Remove `member` from the set `key`, returning whether it was a member.
+    pub fn srem(&mut self, key: String, member: String) -> Result<bool> {
+        self.send_member(Request::SRem { key, member })
+    }
+
+    /// Whether `member` belongs to the set `key`.
+    pub fn sismember(&mut self, key: String, member: String) -> Result<bool> {
+        self.send_member(Request::SIsMember { key, member })
+    }
+
+    fn send_member(&mut self, req: Request) -> Result<bool> {
+        let resp = self.call::<MemberResponse>(&req)?;
+        match resp {
+            MemberResponse::Ok(found) => Ok(found),
+            MemberResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get every member of the set `key`, in order.
+    pub fn smembers(&mut self, key: String) -> Result<Vec<String>> {
+        let resp = self.call::<MembersResponse>(&Request::SMembers { key })?;
+        match resp {
+            MembersResponse::Ok(members) => Ok(members),
+            MembersResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Set the score of `member` in the sorted set `key`, returning whether
+    /// it was not a member yet.
+    pub fn zadd(&mut self, key: String, member: String, score: f64) -> Result<bool> {
+        self.send_member(Request::ZAdd { key, member, score })
+    }
+
+    /// Get the members of the sorted set `key` scored from `min` to `max`,
+    /// both included, with their scores, by score.
+    pub fn zrange_by_score(
+        &mut self,
+        key: String,
+        min: f64,
+        max: f64,
+    ) -> Result<Vec<(String, f64)>> {
+        let resp = self.call::<ScoresResponse>(&Request::ZRangeByScore { key, min, max })?;
+        match resp {
+            ScoresResponse::Ok(members) => Ok(members),
+            ScoresResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get the values of several keys in one round trip, in the order of
+    /// `keys`.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let resp = self.call::<GetManyResponse>(&Request::GetMany { keys })?;
+        match resp {
+            GetManyResponse::Ok(values) => Ok(values),
+            GetManyResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get the keys whose JSON values hold `value` at `path`, e.g.
+    /// `$.email`, which the server must index.
+    pub fn find_by_index(&mut self, path: String, value: String) -> Result<Vec<String>> {
+        let resp = self.call::<FindResponse>(&Request::FindByIndex { path, value })?;
+        match resp {
+            FindResponse::Ok(keys) => Ok(keys),
+            FindResponse::Err(err) => Err(err.into()),
         }
     }
 
     /// Set the value of a string key in the server.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = SetResponse::deserialize(&mut self.reader)?;
+        self.send_set(key, value, None)
+    }
+
+    /// Set the value of a string key in the server for `ttl`, after which
+    /// the key reads as removed.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.send_set(key, value, Some(ttl.as_millis() as u64))
+    }
+
+    fn send_set(&mut self, key: String, value: String, ttl_ms: Option<u64>) -> Result<()> {
+        let resp = self.call::<SetResponse>(&Request::Set { key, value, ttl_ms })?;
+        match resp {
+            SetResponse::Ok(_value) => Ok(()),
+            SetResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Set several keys at once: either all of them are set or, on error,
+    /// none.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let resp = self.call::<SetResponse>(&Request::SetMany { pairs })?;
         match resp {
             SetResponse::Ok(_value) => Ok(()),
-            SetResponse::Err(msg) => Err(MyError::StringError(msg)),
+            SetResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Remove several keys at once: either all of them are removed or, if
+    /// one does not exist or on another error, none.
+    pub fn remove_many(&mut self, keys: Vec<String>) -> Result<()> {
+        let resp = self.call::<RemoveResponse>(&Request::RemoveMany { keys })?;
+        match resp {
+            RemoveResponse::Ok(_value) => Ok(()),
+            RemoveResponse::Err(err) => Err(err.into()),
         }
     }
 
     /// Remove a string key in the server.
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
-        let resp = RemoveResponse::deserialize(&mut self.reader)?;
+        let resp = self.call::<RemoveResponse>(&Request::Remove { key })?;
         match resp {
             RemoveResponse::Ok(_value) => Ok(()),
-            RemoveResponse::Err(msg) => Err(MyError::StringError(msg)),
+            RemoveResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Whether `key` holds a value of any kind.
+    pub fn exists(&mut self, key: String) -> Result<bool> {
+        let resp = self.call::<ExistsResponse>(&Request::Exists { key })?;
+        match resp {
+            ExistsResponse::Ok(exists) => Ok(exists),
+            ExistsResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get the number of keys in the selected bucket.
+    pub fn db_size(&mut self) -> Result<u64> {
+        let resp = self.call::<DbSizeResponse>(&Request::DbSize)?;
+        match resp {
+            DbSizeResponse::Ok(len) => Ok(len),
+            DbSizeResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get the keys matching the glob `pattern`, in key order: `*` matches
+    /// any run of characters, `?` any one, `[a-z]` one of a class.
+    pub fn keys(&mut self, pattern: String) -> Result<Vec<String>> {
+        let resp = self.call::<FindResponse>(&Request::Keys { pattern })?;
+        match resp {
+            FindResponse::Ok(keys) => Ok(keys),
+            FindResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get a page of the keys matching the glob `pattern`: the server looks
+    /// at up to `count` keys past `cursor`, `None` to start, and returns
+    /// the matching ones along with the cursor of the next page, `None`
+    /// once every key was looked at.
+    ///
+    /// Pages may be empty before the end when few keys match.
+    pub fn scan(
+        &mut self,
+        cursor: Option<String>,
+        count: u32,
+        pattern: String,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        self.scan_with_consistency(cursor, count, pattern, ReadConsistency::Local)
+    }
+
+    /// Get a page of the keys matching the glob `pattern`, see `scan`, read
+    /// as fresh as `consistency` asks, see `get_with_consistency`.
+    pub fn scan_with_consistency(
+        &mut self,
+        cursor: Option<String>,
+        count: u32,
+        pattern: String,
+        consistency: ReadConsistency,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        self.check_consistency(consistency)?;
+        let resp = self.call::<ScanResponse>(&Request::Scan {
+            cursor,
+            count,
+            pattern,
+            consistency,
+        })?;
+        match resp {
+            ScanResponse::Ok(page) => Ok((page.keys, page.cursor)),
+            ScanResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Export the key/value pairs whose keys match the glob `pattern`, in
+    /// key order. The server streams them a page at a time, and the
+    /// iterator reads each page as it gets to it.
+    ///
+    /// The connection serves nothing else until the export ends, so the
+    /// rest of it is read and dropped if the iterator is dropped first.
+    pub fn export(&mut self, pattern: String) -> Result<Export<'_>> {
+        let first = self.call::<ExportResponse>(&Request::Export { pattern })?;
+        Ok(Export {
+            client: self,
+            next: Some(first),
+            page: Vec::new().into_iter(),
+            done: false,
+        })
+    }
+
+    /// Iterate over the keys matching the glob `pattern`, fetched from the
+    /// server a page at a time with `scan`.
+    ///
+    /// Every key present for the whole walk is returned once; keys set or
+    /// removed meanwhile may or may not be.
+    pub fn scan_iter(&mut self, pattern: String) -> ScanIter<'_> {
+        ScanIter {
+            client: self,
+            pattern,
+            cursor: None,
+            page: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+
+    /// Get how long `key` has left to live, `None` if it does not expire.
+    pub fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        let resp = self.call::<TtlResponse>(&Request::Ttl { key })?;
+        match resp {
+            TtlResponse::Ok(ttl_ms) => Ok(ttl_ms.map(Duration::from_millis)),
+            TtlResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get when `key` was created and last written.
+    pub fn metadata(&mut self, key: String) -> Result<KeyMeta> {
+        let resp = self.call::<MetadataResponse>(&Request::Metadata { key })?;
+        match resp {
+            MetadataResponse::Ok(meta) => Ok(meta),
+            MetadataResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Make `key` expire after `ttl`, keeping its value.
+    pub fn expire(&mut self, key: String, ttl: Duration) -> Result<()> {
+        let ttl_ms = ttl.as_millis() as u64;
+        self.send_update(Request::Expire { key, ttl_ms })
+    }
+
+    /// Make each of `keys` that is set expire after `ttl`, keeping their
+    /// values, in one request; returns how many were.
+    pub fn touch(&mut self, keys: Vec<String>, ttl: Duration) -> Result<u64> {
+        let ttl_ms = ttl.as_millis() as u64;
+        match self.call::<CountResponse>(&Request::Touch { keys, ttl_ms })? {
+            CountResponse::Ok(touched) => Ok(touched),
+            CountResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Make `key` permanent again, keeping its value.
+    pub fn persist(&mut self, key: String) -> Result<()> {
+        self.send_update(Request::Persist { key })
+    }
+
+    fn send_update(&mut self, req: Request) -> Result<()> {
+        let resp = self.call::<SetResponse>(&req)?;
+        match resp {
+            SetResponse::Ok(()) => Ok(()),
+            SetResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Move the value of `key` to `new_key` in one step, overwriting it.
+    pub fn rename(&mut self, key: String, new_key: String) -> Result<()> {
+        self.send_update(Request::Rename { key, new_key })
+    }
+
+    /// Set the value of a string key, returning the value it had in the
+    /// same step.
+    pub fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let resp = self.call::<GetResponse>(&Request::GetSet { key, value })?;
+        match resp {
+            GetResponse::Ok(previous) => Ok(previous),
+            GetResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Set the value of a string key only if the key holds no value,
+    /// returning whether it did.
+    pub fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        self.send_member(Request::SetNx { key, value })
+    }
+
+    /// Set the value of a string key only if it is at `version`, 0 for a
+    /// key that holds no value, returning its new version; see
+    /// `KvsEngine::set_if_version`.
+    pub fn set_if_version(&mut self, key: String, version: u64, value: String) -> Result<u64> {
+        let resp = self.call::<VersionResponse>(&Request::SetIfVersion {
+            key,
+            version,
+            value,
+        })?;
+        match resp {
+            VersionResponse::Ok(version) => Ok(version),
+            VersionResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Remove a string key, returning the value it had in the same step,
+    /// `None` if it was not set.
+    pub fn get_del(&mut self, key: String) -> Result<Option<String>> {
+        let resp = self.call::<GetResponse>(&Request::GetDel { key })?;
+        match resp {
+            GetResponse::Ok(previous) => Ok(previous),
+            GetResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Run the WASM `module` on the server with `args`, reading and
+    /// writing the string keys starting with `prefix` in one step, and
+    /// return what it output. Servers built without the `scripting`
+    /// feature refuse it.
+    pub fn run_script(
+        &mut self,
+        module: &[u8],
+        prefix: String,
+        args: Vec<String>,
+    ) -> Result<Option<String>> {
+        let resp = self.call::<GetResponse>(&Request::RunScript {
+            module: STANDARD.encode(module),
+            prefix,
+            args,
+        })?;
+        match resp {
+            GetResponse::Ok(output) => Ok(output),
+            GetResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Remove every key of the selected bucket.
+    pub fn flush_all(&mut self) -> Result<()> {
+        self.send_update(Request::FlushAll { confirm: true })
+    }
+
+    /// Bring `key` back from the trash of the selected bucket, with the
+    /// value it had when it was removed.
+    pub fn restore(&mut self, key: String) -> Result<()> {
+        self.send_update(Request::Restore { key })
+    }
+
+    /// Empty the trash of the selected bucket for good, returning how
+    /// many keys it held.
+    pub fn purge_trash(&mut self) -> Result<u64> {
+        match self.call::<CountResponse>(&Request::PurgeTrash)? {
+            CountResponse::Ok(purged) => Ok(purged),
+            CountResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Compact the selected bucket now, whatever its compaction threshold.
+    pub fn compact(&mut self) -> Result<()> {
+        self.send_update(Request::Compact)
+    }
+
+    /// Have the server read its configuration file again. Only the admin
+    /// listener of the server serves it.
+    pub fn reload_config(&mut self) -> Result<()> {
+        self.send_update(Request::ReloadConfig)
+    }
+
+    /// Stop the server, once it answered. Only the admin listener of the
+    /// server serves it.
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.send_update(Request::Shutdown)
+    }
+
+    /// Put the server in lame-duck mode, for load balancers to drain it
+    /// before it shuts down. Only the admin listener of the server serves
+    /// it.
+    pub fn drain(&mut self) -> Result<()> {
+        self.send_update(Request::Drain)
+    }
+
+    /// Send the requests that follow to bucket `db`, a keyspace of its own
+    /// on the server; `default` is the one connections start in.
+    pub fn select(&mut self, db: String) -> Result<()> {
+        let resp = self.call::<SelectResponse>(&Request::Select { db: db.clone() })?;
+        match resp {
+            SelectResponse::Ok(_value) => {
+                // select it again after failing over
+                self.builder.db = Some(db);
+                if self.cache.is_some() {
+                    self.cache = self.builder.start_cache(&self.endpoints, self.current)?;
+                }
+                Ok(())
+            }
+            SelectResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Fetch the statistics of the server's storage engine.
+    pub fn stats(&mut self) -> Result<EngineStats> {
+        let resp = self.call::<StatsResponse>(&Request::Stats)?;
+        match resp {
+            StatsResponse::Ok(stats) => Ok(stats),
+            StatsResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Check that the server is up, learning its version, uptime and
+    /// engine. Cheap enough for health checks: the engine is not involved.
+    pub fn ping(&mut self) -> Result<Pong> {
+        let resp = self.call::<PingResponse>(&Request::Ping)?;
+        match resp {
+            PingResponse::Ok(pong) => Ok(pong),
+            PingResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Fetch the most recent requests the server logged as slow.
+    pub fn slow_log(&mut self) -> Result<Vec<SlowRequest>> {
+        let resp = self.call::<SlowLogResponse>(&Request::SlowLog)?;
+        match resp {
+            SlowLogResponse::Ok(requests) => Ok(requests),
+            SlowLogResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Start a batch of requests sent together and answered in order,
+    /// saving a round trip per request.
+    ///
+    /// Example:
+    ///
+    /// ```no_run
+    /// # use kvs::{KvsClient, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// let replies = client
+    ///     .pipeline()
+    ///     .get("k1".to_owned())
+    ///     .set("k2".to_owned(), "v".to_owned())
+    ///     .execute()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+
+    /// Try to take the lock `name`, which expires after `ttl` unless
+    /// released first. Returns `None` if another holder has it.
+    ///
+    /// The lock is the key `name`, holding a token only its holder knows;
+    /// the returned guard releases it when dropped, and gives access to the
+    /// client meanwhile.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvsClient, Result};
+    /// # use std::time::Duration;
+    /// # fn main() -> Result<()> {
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// if let Some(mut lock) = client.acquire_lock("lock:report".to_owned(), Duration::from_secs(30))? {
+    ///     lock.set("report".to_owned(), "done".to_owned())?;
+    ///     lock.release()?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn acquire_lock(&mut self, name: String, ttl: Duration) -> Result<Option<LockGuard<'_>>> {
+        let ttl_ms = ttl.as_millis() as u64;
+        let resp = self.call::<LockResponse>(&Request::AcquireLock {
+            name: name.clone(),
+            ttl_ms,
+        })?;
+        match resp {
+            LockResponse::Ok(Some(token)) => Ok(Some(LockGuard {
+                client: self,
+                name,
+                token,
+                released: false,
+            })),
+            LockResponse::Ok(None) => Ok(None),
+            LockResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Release the lock `name` if `token` still holds it, returning whether
+    /// it did: a lock that expired may have been taken by someone else.
+    pub fn release_lock(&mut self, name: String, token: String) -> Result<bool> {
+        let resp = self.call::<MemberResponse>(&Request::ReleaseLock { name, token })?;
+        match resp {
+            MemberResponse::Ok(released) => Ok(released),
+            MemberResponse::Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Watch `key` for its next change, giving up after `timeout`.
+    ///
+    /// The watch is registered on the server when this returns, so changes
+    /// made afterwards are guaranteed to be observed by `WatchHandle::wait`.
+    pub fn watch(&mut self, key: String, timeout: Duration) -> Result<WatchHandle<'_>> {
+        let timeout_ms = timeout.as_millis() as u64;
+        let resp = self.call::<WatchResponse>(&Request::Watch { key, timeout_ms })?;
+        match resp {
+            WatchResponse::Watching => Ok(WatchHandle { client: self }),
+            WatchResponse::Err(err) => Err(err.into()),
+            _ => Err(MyError::StringError(
+                "Unexpected response to watch request".to_owned(),
+            )),
+        }
+    }
+
+    /// Ask the server for a full snapshot followed by its change stream,
+    /// as a replica does, or for the changes after `from` alone if the
+    /// server still has them; the stream takes acknowledgements back.
+    pub(crate) fn sync(mut self, from: Option<StreamPosition>) -> Result<SyncStart> {
+        let resumable = self.server.version >= RESUMABLE_SYNC_SINCE_VERSION;
+        match from.filter(|_| resumable) {
+            Some(from) => self.send(&Request::SyncFrom { from })?,
+            None => self.send(&Request::Sync)?,
+        }
+        let mut snapshot = Vec::new();
+        let mut events = Vec::new();
+        let mut done = false;
+        let position = loop {
+            match self.reader.receive::<SyncResponse>()? {
+                SyncResponse::Snapshot(pairs) => {
+                    snapshot = pairs;
+                    done = true;
+                    break None;
+                }
+                SyncResponse::Chunk { pairs, crc } => {
+                    if pairs_crc(&pairs) != crc {
+                        return Err(MyError::corrupt("checksum mismatch in a sync chunk"));
+                    }
+                    snapshot.extend(pairs);
+                }
+                SyncResponse::Events(rebuilt) => events.extend(rebuilt),
+                SyncResponse::Done { pairs } if pairs == snapshot.len() as u64 => {
+                    done = true;
+                    if !resumable {
+                        break None;
+                    }
+                }
+                SyncResponse::Done { pairs } => {
+                    return Err(MyError::corrupt(format!(
+                        "sync snapshot of {} pairs ended after {}",
+                        pairs,
+                        snapshot.len()
+                    )))
+                }
+                SyncResponse::Position(position) => break Some(position),
+                SyncResponse::Err(err) => return Err(err.into()),
+            }
+        };
+        Ok(SyncStart {
+            snapshot: Some(snapshot).filter(|_| done),
+            events,
+            position,
+            changes: Subscription {
+                reader: self.reader,
+                acks: Some(self.writer),
+            },
+        })
+    }
+
+    /// Subscribe to changes of keys starting with `prefix`.
+    ///
+    /// The connection is dedicated to the subscription from then on, so the
+    /// client is consumed.
+    pub fn subscribe(mut self, prefix: String) -> Result<Subscription> {
+        self.send(&Request::Subscribe { prefix })?;
+        let resp = self.reader.receive::<SubscribeResponse>()?;
+        match resp {
+            SubscribeResponse::Ok(_value) => Ok(Subscription {
+                reader: self.reader,
+                acks: None,
+            }),
+            SubscribeResponse::Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Options for connecting a `KvsClient`.
+///
+/// Example:
+///
+/// ```no_run
+/// # use kvs::{KvsClient, Result, RetryPolicy};
+/// # use std::time::Duration;
+/// # fn try_main() -> Result<()> {
+/// let policy = RetryPolicy::new(5).with_backoff(Duration::from_millis(50), Duration::from_secs(1));
+/// let mut client = KvsClient::builder()
+///     .with_retry_policy(policy)
+///     .connect("127.0.0.1:4000")?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct KvsClientBuilder {
+    auth_token: Option<String>,
+    retry_policy: RetryPolicy,
+    tls: Option<ClientTlsConfig>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    codec: Codec,
+    compression: Compression,
+    db: Option<String>,
+    tenant: Option<String>,
+    cache_capacity: Option<usize>,
+}
+
+impl KvsClientBuilder {
+    /// Authenticate with `token` once connected.
+    pub fn with_auth_token(mut self, token: String) -> Self {
+        self.auth_token = Some(token);
+        self
+    }
+
+    /// Retry connecting according to `policy` while the server is
+    /// unreachable, e.g. during a restart.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Connect over TLS, see `KvsClient::connect_tls`. Unix sockets are
+    /// connected to without it.
+    pub fn with_tls(mut self, tls: ClientTlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Give up connecting to an address after `timeout`.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail requests whose response does not arrive within `timeout`
+    /// with `MyError::Timeout`.
+    ///
+    /// This also bounds `WatchHandle::wait` and the gaps between events of
+    /// a `Subscription`.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail requests that cannot be sent within `timeout` with
+    /// `MyError::Timeout`.
+    pub fn with_write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Ask the server to exchange messages in `codec` after the handshake.
+    ///
+    /// Servers that do not support it keep to JSON; see
+    /// `ServerInfo::codec` for the one in use.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Ask the server to compress large messages with `compression`, both
+    /// ways, after the handshake.
+    ///
+    /// Servers that do not support it send them as they are; see
+    /// `ServerInfo::compression` for the one in use.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Select bucket `db` once connected, see `KvsClient::select`.
+    pub fn with_db(mut self, db: String) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Work on the store of `tenant` rather than on that of the server,
+    /// see `Server::with_tenants`. Connecting fails if the server does not
+    /// know the tenant, or the token is for another one.
+    pub fn with_tenant(mut self, tenant: String) -> Self {
+        self.tenant = Some(tenant);
+        self
+    }
+
+    /// Cache the values of up to `capacity` keys read with `KvsClient::get`,
+    /// evicting the least recently used ones.
+    ///
+    /// The client subscribes to every change of the store over a second
+    /// connection and drops the keys changed, so that the cache stays
+    /// coherent; its own writes drop theirs at once. A key that expires is
+    /// served from the cache until the server sweeps it. If the
+    /// subscription ends, e.g. the server goes away, the cache empties and
+    /// reads go to the server until the client reconnects.
+    pub fn with_cache(mut self, capacity: usize) -> Self {
+        self.cache_capacity = Some(capacity);
+        self
+    }
+
+    /// Connect to `addr`, see `KvsClient::connect`.
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> Result<KvsClient> {
+        let endpoints: Vec<_> = addr.to_socket_addrs()?.map(Endpoint::Tcp).collect();
+        if endpoints.is_empty() {
+            return Err(MyError::StringError(
+                "Address resolved to nothing".to_owned(),
+            ));
+        }
+        self.connect_endpoints(&endpoints, 0)
+    }
+
+    /// Connect to the Unix socket at `path`, see `KvsClient::connect_uds`.
+    #[cfg(unix)]
+    pub fn connect_uds<P: AsRef<Path>>(&self, path: P) -> Result<KvsClient> {
+        self.connect_endpoints(&[Endpoint::Unix(path.as_ref().to_owned())], 0)
+    }
+
+    /// Connects to the first of `endpoints` that is up, starting at
+    /// `start` and wrapping around, retrying the round according to the
+    /// retry policy.
+    fn connect_endpoints(&self, endpoints: &[Endpoint], start: usize) -> Result<KvsClient> {
+        self.retry_policy.run(|| {
+            let mut last_err = None;
+            for current in (start..endpoints.len()).chain(0..start) {
+                match self.connect_once(endpoints, current) {
+                    Ok(client) => return Ok(client),
+                    Err(e) if is_transient(&e) => {
+                        info!("Endpoint {} is down: {}", endpoints[current], e);
+                        last_err = Some(e);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Err(last_err.unwrap())
+        })
+    }
+
+    /// Connects to `endpoints[current]`; the handshake, authentication and
+    /// bucket selection double as its health check.
+    fn connect_once(&self, endpoints: &[Endpoint], current: usize) -> Result<KvsClient> {
+        info!("Try to connect to {}", endpoints[current]);
+
+        let stream = endpoints[current].connect(self.connect_timeout)?;
+        // set before the handshake, for the timeouts to bound it too
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        let stream_reader = match (&self.tls, stream) {
+            (Some(tls), Stream::Tcp(tcp)) => Stream::Tls(tls.connect(tcp)?),
+            (_, stream) => stream,
+        };
+        let stream_writer = stream_reader.try_clone()?;
+        info!("Connected to {}", endpoints[current]);
+
+        let mut client = KvsClient {
+            writer: MessageWriter::new(BufWriter::new(stream_writer)),
+            reader: MessageReader::new(BufReader::new(stream_reader)),
+            server: ServerInfo::default(),
+            // no failing over until the endpoint proved healthy
+            endpoints: vec![endpoints[current].clone()],
+            current: 0,
+            builder: self.clone(),
+            id_prefix: RandomState::new().build_hasher().finish(),
+            requests_sent: 0,
+            last_request_id: None,
+            cache: None,
+        };
+        client.hello(self.codec, self.compression, self.tenant.clone())?;
+        if let Some(token) = &self.auth_token {
+            client.authenticate(token.clone())?;
+        }
+        if let Some(db) = &self.db {
+            client.select(db.clone())?;
+        }
+        client.cache = self.start_cache(endpoints, current)?;
+        client.endpoints = endpoints.to_vec();
+        client.current = current;
+        Ok(client)
+    }
+
+    /// The cache asked for, kept coherent by a subscription to
+    /// `endpoints[current]`.
+    fn start_cache(&self, endpoints: &[Endpoint], current: usize) -> Result<Option<ReadCache>> {
+        let capacity = match self.cache_capacity {
+            Some(capacity) => capacity,
+            None => return Ok(None),
+        };
+        let builder = KvsClientBuilder {
+            cache_capacity: None,
+            ..self.clone()
+        };
+        let subscription = builder
+            .connect_once(endpoints, current)?
+            .subscribe(String::new())?;
+        ReadCache::start(subscription, capacity).map(Some)
+    }
+}
+
+/// A pending watch registered with `KvsClient::watch`.
+pub struct WatchHandle<'a> {
+    client: &'a mut KvsClient,
+}
+
+impl WatchHandle<'_> {
+    /// Blocks until the watched key changes, returning the change, or
+    /// `None` if the watch timed out first.
+    pub fn wait(self) -> Result<Option<Event>> {
+        let resp = self.client.reader.receive::<WatchResponse>()?;
+        match resp {
+            WatchResponse::Changed(event) => Ok(Some(event)),
+            WatchResponse::TimedOut => Ok(None),
+            WatchResponse::Watching => Err(MyError::StringError(
+                "Unexpected watch acknowledgement".to_owned(),
+            )),
+            WatchResponse::Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// A lock taken with `KvsClient::acquire_lock`, released when dropped.
+///
+/// Dereferences to the client, which stays usable while the lock is held.
+pub struct LockGuard<'a> {
+    client: &'a mut KvsClient,
+    name: String,
+    token: String,
+    released: bool,
+}
+
+impl LockGuard<'_> {
+    /// The token holding the lock, for `KvsClient::release_lock`.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Keeps the lock past the guard, until it expires or its token is
+    /// given to `KvsClient::release_lock`.
+    pub fn into_token(mut self) -> String {
+        self.released = true;
+        std::mem::take(&mut self.token)
+    }
+
+    /// Releases the lock, returning whether it was still held.
+    pub fn release(mut self) -> Result<bool> {
+        self.released = true;
+        let name = std::mem::take(&mut self.name);
+        let token = std::mem::take(&mut self.token);
+        self.client.release_lock(name, token)
+    }
+}
+
+impl Deref for LockGuard<'_> {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client
+    }
+}
+
+impl DerefMut for LockGuard<'_> {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client
+    }
+}
+
+impl Drop for LockGuard<'_> {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        let name = std::mem::take(&mut self.name);
+        let token = std::mem::take(&mut self.token);
+        if let Err(e) = self.client.release_lock(name.clone(), token) {
+            // the lock expires on its own
+            warn!("Failed to release lock {}: {}", name, e);
+        }
+    }
+}
+
+/// Requests queued with `KvsClient::pipeline`.
+///
+/// Nothing is read until every request is written, so keep batches to a few
+/// thousand requests lest the server block on replies no one reads yet.
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<Request>,
+}
+
+impl Pipeline<'_> {
+    /// Queue getting the value of `key`.
+    pub fn get(mut self, key: String) -> Self {
+        self.requests.push(Request::Get {
+            key,
+            consistency: ReadConsistency::Local,
+        });
+        self
+    }
+
+    /// Queue setting `key` to `value`.
+    pub fn set(mut self, key: String, value: String) -> Self {
+        self.requests.push(Request::Set {
+            key,
+            value,
+            ttl_ms: None,
+        });
+        self
+    }
+
+    /// Queue removing `key`.
+    pub fn remove(mut self, key: String) -> Self {
+        self.requests.push(Request::Remove { key });
+        self
+    }
+
+    /// Send the queued requests at once, then read their outcomes in order.
+    ///
+    /// A get yields the value, if any; sets and removes yield `None`. A
+    /// request the server rejects fails on its own, while a connection
+    /// failure fails the whole batch. A connection the server closed since
+    /// the last request is replaced first. The keys written are dropped from
+    /// the cache of the client as their responses are read.
+    pub fn execute(self) -> Result<Vec<Result<Option<String>>>> {
+        let client = self.client;
+        client.reconnect_if_closed()?;
+        for req in &self.requests {
+            client.write_request(req)?;
+        }
+        client.writer.flush()?;
+
+        let mut replies = Vec::with_capacity(self.requests.len());
+        for req in &self.requests {
+            let reply = match req {
+                Request::Get { .. } => match client.reader.receive::<GetResponse>()? {
+                    GetResponse::Ok(value) => Ok(value),
+                    GetResponse::Err(err) => Err(err.into()),
+                },
+                Request::Set { .. } => match client.reader.receive::<SetResponse>()? {
+                    SetResponse::Ok(_value) => Ok(None),
+                    SetResponse::Err(err) => Err(err.into()),
+                },
+                _ => match client.reader.receive::<RemoveResponse>()? {
+                    RemoveResponse::Ok(_value) => Ok(None),
+                    RemoveResponse::Err(err) => Err(err.into()),
+                },
+            };
+            // the write is applied by now, whatever the cache took in while
+            // it was queued
+            if let Some(cache) = client.cache.as_ref().filter(|_| !req.is_idempotent()) {
+                req.keys().into_iter().for_each(|key| cache.invalidate(key));
+            }
+            replies.push(reply);
+        }
+        Ok(replies)
+    }
+}
+
+/// Iterator over the keys matching a pattern, from `KvsClient::scan_iter`.
+pub struct ScanIter<'a> {
+    client: &'a mut KvsClient,
+    pattern: String,
+    cursor: Option<String>,
+    page: std::vec::IntoIter<String>,
+    /// Whether the last page was fetched, or fetching one failed.
+    done: bool,
+}
+
+impl Iterator for ScanIter<'_> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(key) = self.page.next() {
+                return Some(Ok(key));
+            }
+            if self.done {
+                return None;
+            }
+            let pattern = self.pattern.clone();
+            match self
+                .client
+                .scan(self.cursor.take(), SCAN_PAGE_KEYS, pattern)
+            {
+                Ok((keys, cursor)) => {
+                    self.done = cursor.is_none();
+                    self.cursor = cursor;
+                    self.page = keys.into_iter();
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Iterator over the pairs streamed by the server after `KvsClient::export`.
+pub struct Export<'a> {
+    client: &'a mut KvsClient,
+    /// The frame read along with the request, not handled yet.
+    next: Option<ExportResponse>,
+    page: std::vec::IntoIter<(String, String)>,
+    /// Whether the end of the stream was read.
+    done: bool,
+}
+
+impl Export<'_> {
+    fn read_frame(&mut self) -> Result<ExportResponse> {
+        match self.next.take() {
+            Some(frame) => Ok(frame),
+            None => self.client.reader.receive(),
+        }
+    }
+}
+
+impl Iterator for Export<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(pair) = self.page.next() {
+                return Some(Ok(pair));
+            }
+            if self.done {
+                return None;
+            }
+            let frame = self.read_frame();
+            self.done = !matches!(frame, Ok(ExportResponse::Pairs(_)));
+            match frame {
+                Ok(ExportResponse::Pairs(pairs)) => self.page = pairs.into_iter(),
+                Ok(ExportResponse::Done { .. }) => return None,
+                Ok(ExportResponse::Err(err)) => return Some(Err(err.into())),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl Drop for Export<'_> {
+    fn drop(&mut self) {
+        while !self.done {
+            let frame = self.read_frame();
+            self.done = !matches!(frame, Ok(ExportResponse::Pairs(_)));
+        }
+    }
+}
+
+/// What a server answers `KvsClient::sync` with.
+pub(crate) struct SyncStart {
+    /// The snapshot of the store, `None` if the change stream resumed.
+    pub(crate) snapshot: Option<Vec<(String, String)>>,
+    /// The events rebuilding the lists, hashes, sets, sorted sets and
+    /// expiries of the snapshot, applied after its pairs.
+    pub(crate) events: Vec<Event>,
+    /// Where the change stream starts, if it comes in `ChangeBatch`es.
+    pub(crate) position: Option<StreamPosition>,
+    pub(crate) changes: Subscription,
+}
+
+/// Iterator over the events pushed by the server after `KvsClient::subscribe`.
+///
+/// The iterator ends when the server closes the connection.
+pub struct Subscription {
+    reader: MessageReader<BufReader<Stream>>,
+    /// Where a replica acknowledges the events of a sync stream.
+    acks: Option<MessageWriter<BufWriter<Stream>>>,
+}
+
+impl Subscription {
+    pub(crate) fn stream(&self) -> &Stream {
+        self.reader.get_ref().get_ref()
+    }
+
+    /// The next batch of a change stream from version 9, `None` once the
+    /// server closed it.
+    pub(crate) fn next_batch(&mut self) -> Option<Result<ChangeBatch>> {
+        match self.reader.wait() {
+            Ok(true) => Some(self.reader.receive()),
+            Ok(false) => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+
+    /// Tells the leader that `received` events of a sync stream arrived and
+    /// `applied` of them were applied.
+    pub(crate) fn acknowledge(&mut self, received: u64, applied: u64) -> Result<()> {
+        if let Some(acks) = &mut self.acks {
+            acks.send(&ReplicaAck { received, applied })?;
+            acks.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.wait() {
+            Ok(true) => Some(self.reader.receive()),
+            Ok(false) => None,
+            Err(e) => Some(Err(e.into())),
         }
     }
 }
@@ -0,0 +1,115 @@
+use crate::client::KvsClient;
+use crate::errors::{MyError, Result};
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{SocketAddr, ToSocketAddrs};
+
+/// Number of points each server gets on the hash ring. More points spread
+/// keys more evenly at the cost of a larger ring.
+const VIRTUAL_NODES: u32 = 64;
+
+/// Client spreading keys over several independent kvs servers.
+///
+/// Keys are placed with consistent hashing: adding or removing a server only
+/// moves the keys that hash next to its points on the ring, roughly
+/// `1 / servers` of the keyspace. Servers are connected to lazily.
+///
+/// Example:
+///
+/// ```no_run
+/// # use kvs::{KvsClusterClient, Result};
+/// # fn try_main() -> Result<()> {
+/// let mut cluster = KvsClusterClient::connect(&["127.0.0.1:4000", "127.0.0.1:4001"])?;
+/// cluster.set("key".to_owned(), "value".to_owned())?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvsClusterClient {
+    ring: BTreeMap<u64, SocketAddr>,
+    clients: HashMap<SocketAddr, KvsClient>,
+}
+
+impl KvsClusterClient {
+    /// Create a client for the servers at `addrs`.
+    pub fn connect<A: ToSocketAddrs>(addrs: &[A]) -> Result<Self> {
+        let mut cluster = KvsClusterClient {
+            ring: BTreeMap::new(),
+            clients: HashMap::new(),
+        };
+        for addr in addrs {
+            cluster.add_node(addr)?;
+        }
+        Ok(cluster)
+    }
+
+    /// Add a server to the ring.
+    pub fn add_node<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
+        let addr = resolve(addr)?;
+        for i in 0..VIRTUAL_NODES {
+            self.ring.insert(hash(&format!("{}#{}", addr, i)), addr);
+        }
+        Ok(())
+    }
+
+    /// Remove a server from the ring, closing its connection.
+    pub fn remove_node<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
+        let addr = resolve(addr)?;
+        self.ring.retain(|_, node| *node != addr);
+        self.clients.remove(&addr);
+        Ok(())
+    }
+
+    /// The server responsible for `key`, if any server is known.
+    pub fn node_for(&self, key: &str) -> Option<SocketAddr> {
+        let point = hash(key);
+        self.ring
+            .range(point..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, addr)| *addr)
+    }
+
+    /// Get the value of a given key from the server owning it.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.client_for(&key)?.get(key)
+    }
+
+    /// Set the value of a string key on the server owning it.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.client_for(&key)?.set(key, value)
+    }
+
+    /// Remove a string key from the server owning it.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.client_for(&key)?.remove(key)
+    }
+
+    fn client_for(&mut self, key: &str) -> Result<&mut KvsClient> {
+        let addr = self
+            .node_for(key)
+            .ok_or_else(|| MyError::StringError("No server in the cluster".to_owned()))?;
+        match self.clients.entry(addr) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(KvsClient::connect(addr)?)),
+        }
+    }
+}
+
+fn resolve<A: ToSocketAddrs>(addr: A) -> Result<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| MyError::StringError("Address resolved to nothing".to_owned()))
+}
+
+/// 64-bit FNV-1a followed by the splitmix64 finalizer, stable across
+/// processes and Rust versions so that every client places keys identically.
+/// The finalizer spreads similar inputs ("node#1", "node#2") over the ring.
+pub(crate) fn hash(data: &str) -> u64 {
+    let mut hash = data.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
@@ -0,0 +1,343 @@
+use crate::common::{
+    Echoed, Framed, ServerInfo, WireError, CODED_ERRORS_SINCE_VERSION,
+    COLLECTION_EVENTS_SINCE_VERSION, COMPRESSION_SINCE_VERSION, FRAMED_SINCE_VERSION,
+};
+use crate::errors::{MyError, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::io::{self, BufRead, Read, Write};
+use std::str::FromStr;
+
+/// Serialization format of the messages exchanged after the handshake.
+///
+/// JSON is what every client and server speaks; MessagePack is a compact
+/// binary format a client can ask for in its `Hello`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Json,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl Codec {
+    /// Every codec.
+    pub(crate) const ALL: [Codec; 2] = [Codec::Json, Codec::MessagePack];
+
+    /// Name of the codec on the wire and in configuration.
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Json => "json",
+            Codec::MessagePack => "msgpack",
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Codec> {
+        Codec::ALL
+            .iter()
+            .copied()
+            .find(|codec| codec.name() == s)
+            .ok_or_else(|| MyError::StringError(format!("Unknown codec `{}`", s)))
+    }
+}
+
+/// Compression of the large frames exchanged after the handshake.
+///
+/// Off unless a client asks for it in its `Hello` and the server agrees;
+/// then frames of at least 4 KiB are sent compressed both ways, when that
+/// makes them smaller.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl Compression {
+    /// Every compression that can be negotiated.
+    pub(crate) const ALL: [Compression; 1] = [Compression::Zstd];
+
+    /// Name of the compression on the wire and in configuration.
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// The compression agreed in the handshake.
+    pub(crate) fn negotiated(info: &ServerInfo) -> Compression {
+        if info.version < COMPRESSION_SINCE_VERSION {
+            return Compression::None;
+        }
+        info.compression
+            .iter()
+            .find_map(|name| name.parse().ok())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn compress(self, payload: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(payload.to_vec()),
+            Compression::Zstd => Ok(zstd::bulk::compress(payload, 0)?),
+        }
+    }
+
+    /// Inflates `payload`, failing with `MyError::MessageTooLarge` past
+    /// `max_len` bytes.
+    pub(crate) fn decompress(self, payload: &[u8], max_len: usize) -> Result<Vec<u8>> {
+        let mut inflated = Vec::new();
+        match self {
+            Compression::None => inflated.extend_from_slice(payload),
+            Compression::Zstd => {
+                zstd::Decoder::new(payload)?
+                    .take(max_len as u64 + 1)
+                    .read_to_end(&mut inflated)?;
+            }
+        }
+        if inflated.len() > max_len {
+            return Err(MyError::MessageTooLarge(inflated.len()));
+        }
+        Ok(inflated)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Compression> {
+        Compression::ALL
+            .iter()
+            .copied()
+            .find(|compression| compression.name() == s)
+            .ok_or_else(|| MyError::StringError(format!("Unknown compression `{}`", s)))
+    }
+}
+
+/// Reads messages from a connection in its codec, framed once the
+/// handshake agreed on it.
+pub(crate) struct MessageReader<R> {
+    inner: Framed<R>,
+    codec: Codec,
+    framed: bool,
+    /// Whether messages come `Echoed`, as responses to tagged requests.
+    echoed: bool,
+}
+
+impl<R: BufRead> MessageReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        MessageReader {
+            inner: Framed::new(inner),
+            codec: Codec::Json,
+            framed: false,
+            echoed: false,
+        }
+    }
+
+    /// Switches to the codec and framing agreed in the handshake.
+    pub(crate) fn negotiated(&mut self, info: &ServerInfo) {
+        self.codec = info.codec;
+        self.framed = info.version >= FRAMED_SINCE_VERSION;
+        self.inner.set_compression(Compression::negotiated(info));
+    }
+
+    /// Refuses framed messages of more than `len` bytes, before reading
+    /// them.
+    pub(crate) fn set_max_len(&mut self, len: usize) {
+        self.inner.set_max_len(len);
+    }
+
+    pub(crate) fn get_ref(&self) -> &R {
+        self.inner.get_ref()
+    }
+
+    /// Whether a message that fails to decode leaves the next one readable.
+    pub(crate) fn is_framed(&self) -> bool {
+        self.framed
+    }
+
+    /// Blocks until the next message starts arriving. Returns `false` if
+    /// the peer closed the connection instead.
+    pub(crate) fn wait(&mut self) -> io::Result<bool> {
+        Ok(!self.inner.get_mut().fill_buf()?.is_empty())
+    }
+
+    /// Expects the messages to come `Echoed`, as the server sends them from
+    /// protocol version 8 on.
+    pub(crate) fn expect_echoes(&mut self, echoed: bool) {
+        self.echoed = echoed;
+    }
+
+    /// Reads the next message, which must be a `T`, `Echoed` if echoes are
+    /// expected.
+    pub(crate) fn receive<T: DeserializeOwned>(&mut self) -> Result<T> {
+        if !self.echoed {
+            return self.read();
+        }
+        let echoed: Echoed<T> = self.read()?;
+        Ok(echoed.response)
+    }
+
+    fn read<T: DeserializeOwned>(&mut self) -> Result<T> {
+        if self.framed {
+            let frame = self.inner.read_frame()?;
+            return match self.codec {
+                Codec::Json => Ok(serde_json::from_slice(&frame)?),
+                Codec::MessagePack => Ok(rmp_serde::from_slice(&frame)?),
+            };
+        }
+        let inner = self.inner.get_mut();
+        // wait here rather than inside the decoder, so that read timeouts
+        // surface as `MyError::Timeout` whatever the codec
+        inner.fill_buf()?;
+        // a fresh decoder per message: neither format reads past the end of
+        // a value, so nothing buffered is lost in between
+        match self.codec {
+            Codec::Json => Ok(T::deserialize(&mut serde_json::Deserializer::from_reader(
+                inner,
+            ))?),
+            Codec::MessagePack => Ok(T::deserialize(&mut rmp_serde::Deserializer::new(inner))?),
+        }
+    }
+}
+
+/// Writes messages to a connection in its codec, framed once the handshake
+/// agreed on it.
+pub(crate) struct MessageWriter<W> {
+    inner: Framed<W>,
+    codec: Codec,
+    framed: bool,
+    coded_errors: bool,
+    collection_events: bool,
+    /// Whether an error sent so far was fatal, see `MyError::is_fatal`.
+    fatal: Cell<bool>,
+    /// The last error sent, for the span and audit record of its request.
+    last_error: RefCell<Option<String>>,
+    /// Whether every message goes `Echoed`, as the server sends them from
+    /// protocol version 8 on.
+    echoed: bool,
+    /// ID of the request responded to, which messages are `Echoed` with.
+    request_id: Option<String>,
+}
+
+impl<W: Write> MessageWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        MessageWriter {
+            inner: Framed::new(inner),
+            codec: Codec::Json,
+            framed: false,
+            coded_errors: false,
+            collection_events: false,
+            fatal: Cell::new(false),
+            last_error: RefCell::new(None),
+            echoed: false,
+            request_id: None,
+        }
+    }
+
+    /// Switches to the codec and framing agreed in the handshake.
+    pub(crate) fn negotiated(&mut self, info: &ServerInfo) {
+        self.codec = info.codec;
+        self.framed = info.version >= FRAMED_SINCE_VERSION;
+        self.coded_errors = info.version >= CODED_ERRORS_SINCE_VERSION;
+        self.collection_events = info.version >= COLLECTION_EVENTS_SINCE_VERSION;
+        self.inner.set_compression(Compression::negotiated(info));
+    }
+
+    /// Sends errors with their code, for peers that spoke no handshake but
+    /// understand them.
+    #[cfg(feature = "grpc")]
+    pub(crate) fn send_coded_errors(&mut self) {
+        self.coded_errors = true;
+    }
+
+    /// Whether the peer knows the events of lists, hashes, sets, sorted
+    /// sets and expiries.
+    pub(crate) fn collection_events(&self) -> bool {
+        self.collection_events
+    }
+
+    /// `err` in the form the peer understands.
+    pub(crate) fn error(&self, err: &MyError) -> WireError {
+        if err.is_fatal() {
+            self.fatal.set(true);
+        }
+        self.last_error.replace(Some(err.to_string()));
+        if self.coded_errors {
+            WireError::Coded {
+                code: err.code(),
+                message: err.to_string(),
+            }
+        } else {
+            WireError::Message(err.to_string())
+        }
+    }
+
+    /// Whether a fatal error was sent to the peer.
+    pub(crate) fn sent_fatal(&self) -> bool {
+        self.fatal.get()
+    }
+
+    /// The error sent to the peer since the last call, if any.
+    pub(crate) fn take_error(&self) -> Option<String> {
+        self.last_error.take()
+    }
+
+    /// Sends every message `Echoed`, a client expecting them to be.
+    pub(crate) fn send_echoes(&mut self, echoed: bool) {
+        self.echoed = echoed;
+    }
+
+    /// Echoes `request_id` in the messages sent from now on, until the
+    /// next request.
+    pub(crate) fn set_request_id(&mut self, request_id: Option<String>) {
+        self.request_id = request_id;
+    }
+
+    /// ID of the request responded to, if it has one.
+    pub(crate) fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    /// Buffers `message`, `Echoed` if all messages are or the request
+    /// responded to has an ID; nothing is sent before `flush`.
+    pub(crate) fn send<T: Serialize>(&mut self, message: &T) -> Result<()> {
+        if !self.echoed && self.request_id.is_none() {
+            return self.write(message);
+        }
+        self.write(&Echoed {
+            request_id: self.request_id.clone(),
+            response: message,
+        })
+    }
+
+    fn write<T: Serialize>(&mut self, message: &T) -> Result<()> {
+        if self.framed {
+            let payload = match self.codec {
+                Codec::Json => serde_json::to_vec(message)?,
+                Codec::MessagePack => rmp_serde::to_vec_named(message)?,
+            };
+            return self.inner.write_frame(&payload);
+        }
+        let inner = self.inner.get_mut();
+        match self.codec {
+            Codec::Json => serde_json::to_writer(inner, message)?,
+            Codec::MessagePack => rmp_serde::encode::write_named(inner, message)?,
+        }
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        Ok(self.inner.get_mut().flush()?)
+    }
+
+    pub(crate) fn into_inner(self) -> W {
+        self.inner.into_inner()
+    }
+}
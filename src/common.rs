@@ -1,26 +1,1279 @@
-use serde::{Deserialize, Serialize};
+use crate::codec::{Codec, Compression};
+use crate::engine::{EngineStats, KeyMeta};
+use crate::errors::{MyError, Result};
+use crate::glob::literal_prefix;
+use crate::slowlog::SlowRequest;
+use bytes::Bytes;
+use serde::{ser, Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Version of the wire protocol spoken by this crate, exchanged in the
+/// `Hello` handshake.
+///
+/// Version 1 streams bare messages; version 2 sends every message after
+/// the handshake in a `Framed` frame; version 3 adds an `ErrorCode` to
+/// errors; version 4 sends sync snapshots in checksummed chunks; version 5
+/// may compress large frames; version 6 accepts requests carrying a trace
+/// context; version 7 accepts reads asking for a `ReadConsistency`;
+/// version 8 accepts requests carrying an ID, echoed in their responses;
+/// version 9 streams changes to replicas in numbered, checksummed batches
+/// which they can resume; version 10 streams the changes of lists, hashes,
+/// sets, sorted sets and expiries as well.
+pub const PROTOCOL_VERSION: u32 = 10;
+
+/// Oldest protocol version the server still accepts.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// First protocol version with framed messages.
+pub(crate) const FRAMED_SINCE_VERSION: u32 = 2;
+
+/// First protocol version whose errors carry an `ErrorCode`.
+pub(crate) const CODED_ERRORS_SINCE_VERSION: u32 = 3;
+
+/// First protocol version accepting `Request::Traced`, which only clients
+/// built with the `otel` feature send.
+#[cfg_attr(not(feature = "otel"), allow(dead_code))]
+pub(crate) const TRACE_CONTEXT_SINCE_VERSION: u32 = 6;
+
+/// First protocol version whose `Get` and `Scan` requests may ask for a
+/// `ReadConsistency` other than `Local`.
+pub(crate) const READ_CONSISTENCY_SINCE_VERSION: u32 = 7;
+
+/// First protocol version accepting `Request::Tagged`, which clients send
+/// for every request after the handshake.
+pub(crate) const REQUEST_ID_SINCE_VERSION: u32 = 8;
+
+/// First protocol version whose change streams come in `ChangeBatch`es,
+/// numbered so that replicas can resume them with `Request::SyncFrom`.
+pub(crate) const RESUMABLE_SYNC_SINCE_VERSION: u32 = 9;
+
+/// First protocol version whose change streams carry the events of lists,
+/// hashes, sets, sorted sets and expiries, and whose sync snapshots replay
+/// them. Older replicas could not apply them, so they cannot follow.
+pub(crate) const COLLECTION_EVENTS_SINCE_VERSION: u32 = 10;
+
+/// First protocol version whose frames may be compressed.
+pub(crate) const COMPRESSION_SINCE_VERSION: u32 = 5;
+
+/// Payloads, in bytes, from which frames are compressed when the handshake
+/// agreed on a compression.
+pub(crate) const COMPRESSION_THRESHOLD: usize = 4 * 1024;
+
+/// Bit of a frame header marking a compressed payload; lengths never reach
+/// it.
+const COMPRESSED_FLAG: u32 = 1 << 31;
+
+/// Largest message, in bytes, a frame may carry.
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+
+/// Reads or writes length-prefixed frames: the payload length as 4
+/// big-endian bytes, then the payload. Once compression is on, the top bit
+/// of the length marks a compressed payload.
+pub(crate) struct Framed<T> {
+    inner: T,
+    /// Largest frame read, at most `MAX_MESSAGE_LEN`, before and after
+    /// decompression.
+    max_len: usize,
+    compression: Compression,
+}
+
+impl<T> Framed<T> {
+    pub(crate) fn new(inner: T) -> Self {
+        Framed {
+            inner,
+            max_len: MAX_MESSAGE_LEN,
+            compression: Compression::None,
+        }
+    }
+
+    /// Refuses to read frames of more than `len` bytes from now on.
+    pub(crate) fn set_max_len(&mut self, len: usize) {
+        self.max_len = len.min(MAX_MESSAGE_LEN);
+    }
+
+    /// Compresses the payloads written from now on that reach
+    /// `COMPRESSION_THRESHOLD`, and accepts compressed ones.
+    pub(crate) fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    pub(crate) fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub(crate) fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<R: Read> Framed<R> {
+    /// Reads the payload of the next frame.
+    ///
+    /// A frame announcing more than `MAX_MESSAGE_LEN` bytes, or the
+    /// smaller limit set, fails with `MyError::MessageTooLarge` before any
+    /// of its payload is read; so does a compressed one inflating past the
+    /// limit, once read.
+    pub(crate) fn read_frame(&mut self) -> Result<Vec<u8>> {
+        let mut header = [0; 4];
+        self.inner.read_exact(&mut header)?;
+        let header = u32::from_be_bytes(header);
+        let compressed = self.compression != Compression::None && header & COMPRESSED_FLAG != 0;
+        let len = if compressed {
+            (header & !COMPRESSED_FLAG) as usize
+        } else {
+            header as usize
+        };
+        if len > self.max_len {
+            return Err(MyError::MessageTooLarge(len));
+        }
+        // grow with the data actually received rather than trusting `len`
+        let mut payload = Vec::new();
+        (&mut self.inner)
+            .take(len as u64)
+            .read_to_end(&mut payload)?;
+        if payload.len() < len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        if compressed {
+            return self.compression.decompress(&payload, self.max_len);
+        }
+        Ok(payload)
+    }
+}
+
+impl<W: Write> Framed<W> {
+    /// Writes `payload` as one frame, compressed if that is on and pays
+    /// off.
+    pub(crate) fn write_frame(&mut self, payload: &[u8]) -> Result<()> {
+        if payload.len() > MAX_MESSAGE_LEN {
+            return Err(MyError::MessageTooLarge(payload.len()));
+        }
+        if self.compression != Compression::None && payload.len() >= COMPRESSION_THRESHOLD {
+            let compressed = self.compression.compress(payload)?;
+            if compressed.len() < payload.len() {
+                let header = compressed.len() as u32 | COMPRESSED_FLAG;
+                self.inner.write_all(&header.to_be_bytes())?;
+                self.inner.write_all(&compressed)?;
+                return Ok(());
+            }
+        }
+        self.inner
+            .write_all(&(payload.len() as u32).to_be_bytes())?;
+        self.inner.write_all(payload)?;
+        Ok(())
+    }
+}
+
+/// How fresh a `Get` or `Scan` has to be, chosen for each request.
+///
+/// Only replicated servers tell them apart: a standalone server always
+/// reads its latest writes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadConsistency {
+    /// From the node the request reaches, which may be a replica or a Raft
+    /// follower lagging behind.
+    #[default]
+    Local,
+    /// From the leader only. A leader cut off from the cluster may not know
+    /// yet that another one was elected, and answer with stale data.
+    Leader,
+    /// From the leader, once it committed an entry through the Raft log
+    /// after the request came in, so that the read sees every write
+    /// acknowledged before it.
+    Linearizable,
+}
+
+impl ReadConsistency {
+    pub(crate) fn is_local(&self) -> bool {
+        *self == ReadConsistency::Local
+    }
+}
+
+impl FromStr for ReadConsistency {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<ReadConsistency> {
+        match s {
+            "local" => Ok(ReadConsistency::Local),
+            "leader" => Ok(ReadConsistency::Leader),
+            "linearizable" => Ok(ReadConsistency::Linearizable),
+            _ => Err(MyError::StringError(format!(
+                "Unknown read consistency `{}`, expected `local`, `leader` or `linearizable`",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ReadConsistency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadConsistency::Local => write!(f, "local"),
+            ReadConsistency::Leader => write!(f, "leader"),
+            ReadConsistency::Linearizable => write!(f, "linearizable"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Hello {
+        version: u32,
+        /// Codecs the client can switch to after the handshake, preferred
+        /// first.
+        #[serde(default)]
+        codecs: Vec<String>,
+        /// Compressions the client can apply to large frames, preferred
+        /// first.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        compression: Vec<String>,
+        /// Tenant whose store the connection works on, rather than the
+        /// store of the server.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tenant: Option<String>,
+    },
+    Get {
+        key: String,
+        #[serde(default, skip_serializing_if = "ReadConsistency::is_local")]
+        consistency: ReadConsistency,
+    },
+    GetMany {
+        keys: Vec<String>,
+    },
+    Set {
+        key: String,
+        value: String,
+        /// Lifetime of the key in milliseconds, forever if absent.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl_ms: Option<u64>,
+    },
+    Remove {
+        key: String,
+    },
+    /// Reads part of the JSON value of `key`.
+    GetPath {
+        key: String,
+        path: String,
+    },
+    /// Puts the JSON text `value` at `path` in the JSON value of `key`.
+    SetPath {
+        key: String,
+        path: String,
+        value: String,
+    },
+    /// Adds `value` at the front of the list `key`.
+    LPush {
+        key: String,
+        value: String,
+    },
+    /// Adds `value` at the back of the list `key`.
+    RPush {
+        key: String,
+        value: String,
+    },
+    /// Takes the value at the front of the list `key`.
+    LPop {
+        key: String,
+    },
+    /// Reads the values of the list `key` from `start` to `stop`.
+    LRange {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    /// Sets `field` of the hash `key` to `value`.
+    HSet {
+        key: String,
+        field: String,
+        value: String,
+    },
+    /// Reads `field` of the hash `key`.
+    HGet {
+        key: String,
+        field: String,
+    },
+    /// Removes `field` of the hash `key`.
+    HDel {
+        key: String,
+        field: String,
+    },
+    /// Reads every field of the hash `key`.
+    HGetAll {
+        key: String,
+    },
+    /// Adds `member` to the set `key`.
+    SAdd {
+        key: String,
+        member: String,
+    },
+    /// Removes `member` from the set `key`.
+    SRem {
+        key: String,
+        member: String,
+    },
+    /// Whether `member` belongs to the set `key`.
+    SIsMember {
+        key: String,
+        member: String,
+    },
+    /// Reads every member of the set `key`.
+    SMembers {
+        key: String,
+    },
+    /// Sets the score of `member` in the sorted set `key`.
+    ZAdd {
+        key: String,
+        member: String,
+        score: f64,
+    },
+    /// Reads the members of the sorted set `key` scored from `min` to `max`.
+    ZRangeByScore {
+        key: String,
+        min: f64,
+        max: f64,
+    },
+    /// Checks whether `key` holds a value of any kind.
+    Exists {
+        key: String,
+    },
+    /// Counts the keys of the selected bucket.
+    DbSize,
+    /// Lists the keys matching the glob `pattern`.
+    Keys {
+        pattern: String,
+    },
+    /// Streams the key/value pairs whose keys match the glob `pattern`, as
+    /// `ExportResponse` frames ended by `Done`.
+    Export {
+        pattern: String,
+    },
+    /// Lists the keys matching the glob `pattern` a page at a time: up to
+    /// `count` keys are looked at, from the start of the keyspace or past
+    /// `cursor`, the continuation of the previous page.
+    Scan {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cursor: Option<String>,
+        count: u32,
+        pattern: String,
+        #[serde(default, skip_serializing_if = "ReadConsistency::is_local")]
+        consistency: ReadConsistency,
+    },
+    /// Reads how long `key` has left to live.
+    Ttl {
+        key: String,
+    },
+    /// Reads when `key` was created and last written.
+    Metadata {
+        key: String,
+    },
+    /// Makes `key` expire after `ttl_ms` milliseconds.
+    Expire {
+        key: String,
+        ttl_ms: u64,
+    },
+    /// Makes `key` permanent again.
+    Persist {
+        key: String,
+    },
+    /// Brings `key` back from the trash.
+    Restore {
+        key: String,
+    },
+    /// Makes each of `keys` that is set expire after `ttl_ms`
+    /// milliseconds.
+    Touch {
+        keys: Vec<String>,
+        ttl_ms: u64,
+    },
+    /// Takes the lock `name` for `ttl_ms` milliseconds, unless another
+    /// holder has it: the key `name` is set to a new token if it is not
+    /// set.
+    AcquireLock {
+        name: String,
+        ttl_ms: u64,
+    },
+    /// Gives the lock `name` back, if `token` still holds it.
+    ReleaseLock {
+        name: String,
+        token: String,
+    },
+    /// Moves the value of `key` to `new_key`.
+    Rename {
+        key: String,
+        new_key: String,
+    },
+    /// Sets `key` to `value`, answering with the value it had.
+    GetSet {
+        key: String,
+        value: String,
+    },
+    /// Removes `key`, answering with the value it had.
+    GetDel {
+        key: String,
+    },
+    /// Sets `key` to `value` if it holds no value, answering whether it
+    /// did.
+    SetNx {
+        key: String,
+        value: String,
+    },
+    /// Sets `key` to `value` if it is at `version`, answering its new
+    /// version.
+    SetIfVersion {
+        key: String,
+        version: u64,
+        value: String,
+    },
+    /// Runs the WASM `module`, in base64, with `args` over the string keys
+    /// starting with `prefix`, answering with its output; see
+    /// `KvsClient::run_script`.
+    RunScript {
+        module: String,
+        prefix: String,
+        args: Vec<String>,
+    },
+    SetMany {
+        pairs: Vec<(String, String)>,
+    },
+    RemoveMany {
+        keys: Vec<String>,
+    },
+    Subscribe {
+        prefix: String,
+    },
+    Watch {
+        key: String,
+        timeout_ms: u64,
+    },
+    Sync,
+    /// `Sync`, resuming the change stream after `from` instead if the
+    /// server still has the events that follow.
+    SyncFrom {
+        from: StreamPosition,
+    },
+    Auth {
+        token: String,
+    },
+    Stats,
+    SlowLog,
+    /// Checks that the server is up, without going through the engine;
+    /// answered before authentication.
+    Ping,
+    /// Switches the connection to bucket `db`, `default` being the store
+    /// the server was started with.
+    Select {
+        db: String,
+    },
+    /// Looks up the keys whose JSON values hold `value` at the indexed
+    /// `path`.
+    FindByIndex {
+        path: String,
+        value: String,
+    },
+    /// Removes every key of the selected bucket; refused unless `confirm`
+    /// is set, so that it is never sent by mistake.
+    FlushAll {
+        confirm: bool,
+    },
+    /// Empties the trash of the selected bucket, answering how many keys
+    /// it held.
+    PurgeTrash,
+    /// Compacts the selected bucket now, whatever its compaction threshold.
+    Compact,
+    /// Reads the configuration file of the server again, as on SIGHUP.
+    /// Only served on the admin listener.
+    ReloadConfig,
+    /// Stops the server, as on SIGTERM, once answered. Only served on the
+    /// admin listener.
+    Shutdown,
+    /// Puts the server in lame-duck mode, as on SIGUSR1: it keeps serving,
+    /// but answers `Ping` as draining. Only served on the admin listener.
+    Drain,
+    /// `request` sent from within a trace of the client, so that the
+    /// server span of the request joins it.
+    Traced {
+        context: TraceContext,
+        request: Box<Request>,
+    },
+    /// `request` under an ID the client chose, which the server logs with
+    /// it and echoes in every message it sends in response, see `Echoed`.
+    Tagged {
+        request_id: String,
+        request: Box<Request>,
+    },
+}
+
+/// A W3C trace context, as in the HTTP headers of the same names.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceContext {
+    pub traceparent: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tracestate: Option<String>,
+}
+
+impl Request {
+    /// Name of the request type, for logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::Hello { .. } => "Hello",
+            Request::Get { .. } => "Get",
+            Request::GetMany { .. } => "GetMany",
+            Request::Set { .. } => "Set",
+            Request::Remove { .. } => "Remove",
+            Request::GetPath { .. } => "GetPath",
+            Request::SetPath { .. } => "SetPath",
+            Request::LPush { .. } => "LPush",
+            Request::RPush { .. } => "RPush",
+            Request::LPop { .. } => "LPop",
+            Request::LRange { .. } => "LRange",
+            Request::HSet { .. } => "HSet",
+            Request::HGet { .. } => "HGet",
+            Request::HDel { .. } => "HDel",
+            Request::HGetAll { .. } => "HGetAll",
+            Request::SAdd { .. } => "SAdd",
+            Request::SRem { .. } => "SRem",
+            Request::SIsMember { .. } => "SIsMember",
+            Request::SMembers { .. } => "SMembers",
+            Request::ZAdd { .. } => "ZAdd",
+            Request::ZRangeByScore { .. } => "ZRangeByScore",
+            Request::Exists { .. } => "Exists",
+            Request::DbSize => "DbSize",
+            Request::Keys { .. } => "Keys",
+            Request::Export { .. } => "Export",
+            Request::Scan { .. } => "Scan",
+            Request::Ttl { .. } => "Ttl",
+            Request::Metadata { .. } => "Metadata",
+            Request::Expire { .. } => "Expire",
+            Request::Persist { .. } => "Persist",
+            Request::Restore { .. } => "Restore",
+            Request::Touch { .. } => "Touch",
+            Request::AcquireLock { .. } => "AcquireLock",
+            Request::ReleaseLock { .. } => "ReleaseLock",
+            Request::Rename { .. } => "Rename",
+            Request::GetSet { .. } => "GetSet",
+            Request::GetDel { .. } => "GetDel",
+            Request::SetNx { .. } => "SetNx",
+            Request::SetIfVersion { .. } => "SetIfVersion",
+            Request::RunScript { .. } => "RunScript",
+            Request::SetMany { .. } => "SetMany",
+            Request::RemoveMany { .. } => "RemoveMany",
+            Request::Subscribe { .. } => "Subscribe",
+            Request::Watch { .. } => "Watch",
+            Request::Sync => "Sync",
+            Request::SyncFrom { .. } => "SyncFrom",
+            Request::Auth { .. } => "Auth",
+            Request::Stats => "Stats",
+            Request::SlowLog => "SlowLog",
+            Request::Ping => "Ping",
+            Request::Select { .. } => "Select",
+            Request::FindByIndex { .. } => "FindByIndex",
+            Request::FlushAll { .. } => "FlushAll",
+            Request::PurgeTrash => "PurgeTrash",
+            Request::Compact => "Compact",
+            Request::ReloadConfig => "ReloadConfig",
+            Request::Shutdown => "Shutdown",
+            Request::Drain => "Drain",
+            Request::Traced { request, .. } | Request::Tagged { request, .. } => request.kind(),
+        }
+    }
+
+    /// The request without its ID, and the ID if any.
+    pub(crate) fn untagged(self) -> (Request, Option<String>) {
+        match self {
+            Request::Tagged {
+                request_id,
+                request,
+            } => (*request, Some(request_id)),
+            req => (req, None),
+        }
+    }
+
+    /// The request without its trace context, and the context if any.
+    pub(crate) fn untraced(self) -> (Request, Option<TraceContext>) {
+        match self {
+            Request::Traced { context, request } => (*request, Some(context)),
+            req => (req, None),
+        }
+    }
+
+    /// The key, or key prefix, the request is about; empty for requests
+    /// about the whole server or about several keys.
+    pub fn key(&self) -> &str {
+        match self {
+            Request::Get { key, .. }
+            | Request::Set { key, .. }
+            | Request::Remove { key }
+            | Request::GetPath { key, .. }
+            | Request::SetPath { key, .. }
+            | Request::LPush { key, .. }
+            | Request::RPush { key, .. }
+            | Request::LPop { key }
+            | Request::LRange { key, .. }
+            | Request::HSet { key, .. }
+            | Request::HGet { key, .. }
+            | Request::HDel { key, .. }
+            | Request::HGetAll { key }
+            | Request::SAdd { key, .. }
+            | Request::SRem { key, .. }
+            | Request::SIsMember { key, .. }
+            | Request::SMembers { key }
+            | Request::ZAdd { key, .. }
+            | Request::ZRangeByScore { key, .. }
+            | Request::Rename { key, .. }
+            | Request::GetSet { key, .. }
+            | Request::GetDel { key }
+            | Request::SetNx { key, .. }
+            | Request::SetIfVersion { key, .. }
+            | Request::Exists { key }
+            | Request::Ttl { key }
+            | Request::Metadata { key }
+            | Request::Expire { key, .. }
+            | Request::Persist { key }
+            | Request::Restore { key }
+            | Request::AcquireLock { name: key, .. }
+            | Request::ReleaseLock { name: key, .. }
+            | Request::Watch { key, .. } => key,
+            Request::Subscribe { prefix } | Request::RunScript { prefix, .. } => prefix,
+            Request::Keys { pattern }
+            | Request::Export { pattern }
+            | Request::Scan { pattern, .. } => literal_prefix(pattern),
+            Request::Hello { .. }
+            | Request::GetMany { .. }
+            | Request::SetMany { .. }
+            | Request::RemoveMany { .. }
+            | Request::Touch { .. }
+            | Request::Sync
+            | Request::SyncFrom { .. }
+            | Request::Auth { .. }
+            | Request::Stats
+            | Request::SlowLog
+            | Request::Ping
+            | Request::Select { .. }
+            | Request::FindByIndex { .. }
+            | Request::DbSize
+            | Request::FlushAll { .. }
+            | Request::PurgeTrash
+            | Request::Compact
+            | Request::ReloadConfig
+            | Request::Shutdown
+            | Request::Drain => "",
+            Request::Traced { request, .. } | Request::Tagged { request, .. } => request.key(),
+        }
+    }
+
+    /// Every key the request is about: the keys of a multi-key request,
+    /// otherwise just `key`.
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            Request::GetMany { keys }
+            | Request::RemoveMany { keys }
+            | Request::Touch { keys, .. } => keys.iter().map(String::as_str).collect(),
+            Request::SetMany { pairs } => pairs.iter().map(|(key, _)| key.as_str()).collect(),
+            Request::Rename { key, new_key } => vec![key, new_key],
+            // key rules apply within every bucket
+            Request::Select { .. } => Vec::new(),
+            Request::Traced { request, .. } | Request::Tagged { request, .. } => request.keys(),
+            req => vec![req.key()],
+        }
+    }
+
+    /// Whether sending the request twice does the same as sending it once,
+    /// so that it may be sent again when its response was lost.
+    pub fn is_idempotent(&self) -> bool {
+        if let Request::Traced { request, .. } | Request::Tagged { request, .. } = self {
+            return request.is_idempotent();
+        }
+        matches!(
+            self,
+            Request::Get { .. }
+                | Request::GetPath { .. }
+                | Request::GetMany { .. }
+                | Request::LRange { .. }
+                | Request::HGet { .. }
+                | Request::HGetAll { .. }
+                | Request::SIsMember { .. }
+                | Request::SMembers { .. }
+                | Request::ZRangeByScore { .. }
+                | Request::Exists { .. }
+                | Request::DbSize
+                | Request::Keys { .. }
+                | Request::Export { .. }
+                | Request::Scan { .. }
+                | Request::Ttl { .. }
+                | Request::Metadata { .. }
+                | Request::FindByIndex { .. }
+                | Request::Select { .. }
+                | Request::Stats
+                | Request::SlowLog
+                | Request::Ping
+        )
+    }
+}
+
+/// What a server tells clients about itself in the handshake.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// Protocol version used for the rest of the connection: the highest
+    /// both sides speak.
+    pub version: u32,
+    /// Name of the storage engine, e.g. `kvs` or `sled`.
+    pub engine: String,
+    /// Whether requests are refused until the client authenticates.
+    pub auth_required: bool,
+    /// Compression applied to large frames for the rest of the
+    /// connection: none, or the one the server chose among those the
+    /// client offered.
+    #[serde(default)]
+    pub compression: Vec<String>,
+    /// Codec both sides use for the rest of the connection.
+    #[serde(default)]
+    pub codec: Codec,
+    /// Tenant whose store the connection works on, if the client named
+    /// one. Servers without tenants leave it out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+/// What a server answers a `Ping` with.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pong {
+    /// Version of the kvs crate the server was built from.
+    pub version: String,
+    /// Seconds since the server started.
+    pub uptime_secs: u64,
+    /// Name of the storage engine, e.g. `kvs` or `sled`.
+    pub engine: String,
+    /// Where the server is in its life, for load balancers to route by.
+    /// Servers that predate it leave it out, and are serving.
+    #[serde(default)]
+    pub state: ServerState,
+}
+
+/// The lifecycle states of a server, as health checks report them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerState {
+    /// Opening its engine: only the handshake and `Ping` are answered, see
+    /// `WarmUp`.
+    Starting,
+    #[default]
+    Serving,
+    /// In lame-duck mode: still serving, but about to shut down, so load
+    /// balancers should send new clients elsewhere.
+    Draining,
+}
+
+impl ServerState {
+    /// The name of the state in lower case, as on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerState::Starting => "starting",
+            ServerState::Serving => "serving",
+            ServerState::Draining => "draining",
+        }
+    }
 }
 
+/// Kind of failure a server reports, so that clients need not parse
+/// messages to tell them apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    KeyNotFound,
+    Unauthorized,
+    PermissionDenied,
+    TooManyConnections,
+    RateLimited,
+    Timeout,
+    ReadOnly,
+    /// A key or value is longer than the server allows.
+    TooLarge,
+    /// A tenant is at one of its quotas.
+    QuotaExceeded,
+    /// A conditional write found the key at another version.
+    Conflict,
+    /// The request could not be decoded or is too large.
+    InvalidRequest,
+    /// Stored data could not be decoded.
+    Corruption,
+    /// The server is starting, and only answers health checks yet.
+    Unavailable,
+    /// The storage engine failed, e.g. on a disk error.
+    EngineError,
+    /// Anything else, including codes unknown to this version.
+    #[serde(other)]
+    Other,
+}
+
+/// A message sent in response to a request, with the ID of the request if
+/// it was a `Request::Tagged`.
+///
+/// From protocol version 8 on, every message the server sends after the
+/// handshake comes `Echoed`, with no ID for requests that could not be
+/// decoded; over WebSocket, only the responses to tagged requests do.
 #[derive(Debug, Serialize, Deserialize)]
-pub enum GetResponse {
-    Ok(Option<String>),
+pub(crate) struct Echoed<T> {
+    #[serde(default)]
+    pub(crate) request_id: Option<String>,
+    pub(crate) response: T,
+}
+
+/// Error carried by the `Err` variant of every response.
+///
+/// Servers send bare messages to clients that negotiated a protocol version
+/// older than 3, and older servers only send those.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum WireError {
+    Coded { code: ErrorCode, message: String },
+    Message(String),
+}
+
+impl From<WireError> for MyError {
+    fn from(err: WireError) -> MyError {
+        let (code, message) = match err {
+            WireError::Coded { code, message } => (code, message),
+            WireError::Message(message) => return MyError::StringError(message),
+        };
+        match code {
+            ErrorCode::KeyNotFound => MyError::KeyNotFound,
+            ErrorCode::Unauthorized => MyError::Unauthorized,
+            ErrorCode::PermissionDenied => MyError::PermissionDenied,
+            ErrorCode::TooManyConnections => MyError::TooManyConnections,
+            ErrorCode::RateLimited => MyError::RateLimited,
+            ErrorCode::Timeout => MyError::Timeout,
+            ErrorCode::ReadOnly => MyError::ReadOnly,
+            ErrorCode::TooLarge => MyError::TooLarge(message),
+            ErrorCode::QuotaExceeded => MyError::QuotaExceeded(message),
+            ErrorCode::Conflict => MyError::Conflict(message),
+            code => MyError::Server { code, message },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HelloResponse {
+    Ok(ServerInfo),
     Err(String),
 }
 
+/// The value of a key, a `String` or, for `KvsClient::get_bytes`, `Bytes`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetResponse<V = String> {
+    Ok(Option<V>),
+    Err(WireError),
+}
+
+/// A value read as `Bytes`, sent as the string it holds without copying it
+/// into a `String` first.
+pub(crate) struct StrBytes(pub(crate) Bytes);
+
+impl Serialize for StrBytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(std::str::from_utf8(&self.0).map_err(ser::Error::custom)?)
+    }
+}
+
+impl fmt::Debug for StrBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&String::from_utf8_lossy(&self.0), f)
+    }
+}
+
+/// Values in the order of the requested keys.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetManyResponse {
+    Ok(Vec<Option<String>>),
+    Err(WireError),
+}
+
+/// Milliseconds left to live, `None` for a key that does not expire.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TtlResponse {
+    Ok(Option<u64>),
+    Err(WireError),
+}
+
+/// When a key was created and last written.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MetadataResponse {
+    Ok(KeyMeta),
+    Err(WireError),
+}
+
+/// Version of the key written.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum VersionResponse {
+    Ok(u64),
+    Err(WireError),
+}
+
+/// Length of the list pushed to.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PushResponse {
+    Ok(u64),
+    Err(WireError),
+}
+
+/// Values of a list, front to back.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RangeResponse {
+    Ok(Vec<String>),
+    Err(WireError),
+}
+
+/// Fields of a hash with their values, in field order.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HGetAllResponse {
+    Ok(Vec<(String, String)>),
+    Err(WireError),
+}
+
+/// Whether the member was added, removed or found.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MemberResponse {
+    Ok(bool),
+    Err(WireError),
+}
+
+/// Members of a set, in order.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MembersResponse {
+    Ok(Vec<String>),
+    Err(WireError),
+}
+
+/// Members of a sorted set with their scores, by score.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScoresResponse {
+    Ok(Vec<(String, f64)>),
+    Err(WireError),
+}
+
+/// Matching keys, in key order.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FindResponse {
+    Ok(Vec<String>),
+    Err(WireError),
+}
+
+/// A page of keys of a `Scan`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScanPage {
+    /// The matching keys among those looked at, in key order.
+    pub keys: Vec<String>,
+    /// Where the next page starts, `None` once every key was looked at.
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanResponse {
+    Ok(ScanPage),
+    Err(WireError),
+}
+
+/// One frame of the stream answering an `Export`: pages of pairs, in key
+/// order, until `Done` or `Err`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ExportResponse {
+    Pairs(Vec<(String, String)>),
+    /// End of the export, with the number of pairs it held.
+    Done {
+        pairs: u64,
+    },
+    Err(WireError),
+}
+
+/// The token of a lock just taken, `None` if another holder has it.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum LockResponse {
+    Ok(Option<String>),
+    Err(WireError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ExistsResponse {
+    Ok(bool),
+    Err(WireError),
+}
+
+/// Number of keys the request applied to.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CountResponse {
+    Ok(u64),
+    Err(WireError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DbSizeResponse {
+    Ok(u64),
+    Err(WireError),
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse {
     Ok(()),
-    Err(String),
+    Err(WireError),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
-    Err(String),
+    Err(WireError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SubscribeResponse {
+    Ok(()),
+    Err(WireError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WatchResponse {
+    /// The watch is registered; a `Changed` or `TimedOut` follows.
+    Watching,
+    Changed(Event),
+    TimedOut,
+    Err(WireError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AuthResponse {
+    Ok(()),
+    Unauthorized,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatsResponse {
+    Ok(EngineStats),
+    Err(WireError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SlowLogResponse {
+    Ok(Vec<SlowRequest>),
+    Err(WireError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PingResponse {
+    Ok(Pong),
+    Err(WireError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SelectResponse {
+    Ok(()),
+    Err(WireError),
+}
+
+/// Error reply understood by every response type, since they all share the
+/// `Err(WireError)` variant.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ErrorResponse {
+    Err(WireError),
+}
+
+/// Start of a replication stream: a snapshot, whole or in chunks ended by
+/// `Done`, then `Event`s. From version 9, `Position` follows `Done`, or
+/// comes alone if the stream resumes, then `ChangeBatch`es.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SyncResponse {
+    /// The whole snapshot, for peers older than version 4.
+    Snapshot(Vec<(String, String)>),
+    Err(WireError),
+    /// Pairs of the snapshot, in key order, with their `pairs_crc`.
+    Chunk {
+        pairs: Vec<(String, String)>,
+        crc: u32,
+    },
+    /// Lists, hashes, sets and sorted sets of the snapshot, and the
+    /// expiries of its pairs, as the events rebuilding them, from version
+    /// 10.
+    Events(Vec<Event>),
+    /// End of the snapshot, with the number of pairs it held.
+    Done {
+        pairs: u64,
+    },
+    /// Where the change stream that follows starts.
+    Position(StreamPosition),
+}
+
+/// A place in the change stream of a server: after its event `seq`, in
+/// the run of the server named `stream`. Events are numbered from each
+/// start of the server, so a position is only good for the run it names.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamPosition {
+    pub stream: String,
+    pub seq: u64,
+}
+
+/// Events of a change stream from version 9: those numbered `start_seq`
+/// to `end_seq`, with their `events_crc`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangeBatch {
+    pub start_seq: u64,
+    pub end_seq: u64,
+    pub events: Vec<Event>,
+    pub crc: u32,
+}
+
+impl ChangeBatch {
+    pub(crate) fn new(start_seq: u64, events: Vec<Event>) -> ChangeBatch {
+        let end_seq = start_seq + events.len() as u64 - 1;
+        ChangeBatch {
+            crc: events_crc(start_seq, end_seq, &events),
+            start_seq,
+            end_seq,
+            events,
+        }
+    }
+
+    /// Fails unless the batch is intact and follows the event `seq`.
+    pub(crate) fn check(&self, seq: u64) -> Result<()> {
+        if events_crc(self.start_seq, self.end_seq, &self.events) != self.crc {
+            return Err(MyError::corrupt("checksum mismatch in a change batch"));
+        }
+        if self.start_seq != seq + 1
+            || self.end_seq + 1 != self.start_seq + self.events.len() as u64
+        {
+            return Err(MyError::corrupt(format!(
+                "change batch of events {} to {} after event {}",
+                self.start_seq, self.end_seq, seq
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Sent back by a replica over its `Sync` connection: how many events of
+/// the change stream it received, and how many it applied.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplicaAck {
+    pub received: u64,
+    pub applied: u64,
+}
+
+/// The CRC-32 of `pairs`, each key and value prefixed with its length.
+pub(crate) fn pairs_crc(pairs: &[(String, String)]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for (key, value) in pairs {
+        for part in [key, value] {
+            hasher.update(&(part.len() as u64).to_be_bytes());
+            hasher.update(part.as_bytes());
+        }
+    }
+    hasher.finalize()
+}
+
+/// The CRC-32 of a batch of `events` numbered `start_seq` to `end_seq`,
+/// each key and value prefixed with its length, and followed by the number
+/// the event carries, if any.
+fn events_crc(start_seq: u64, end_seq: u64, events: &[Event]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&start_seq.to_be_bytes());
+    hasher.update(&end_seq.to_be_bytes());
+    for event in events {
+        let (tag, parts, number) = match event {
+            Event::Set { key, value } => (b'S', vec![key, value], None),
+            Event::Removed { key } => (b'R', vec![key], None),
+            Event::Pushed { key, value, front } => (b'P', vec![key, value], Some(*front as u64)),
+            Event::Popped { key } => (b'O', vec![key], None),
+            Event::FieldSet { key, field, value } => (b'F', vec![key, field, value], None),
+            Event::FieldRemoved { key, field } => (b'D', vec![key, field], None),
+            Event::MemberAdded { key, member } => (b'A', vec![key, member], None),
+            Event::MemberRemoved { key, member } => (b'M', vec![key, member], None),
+            Event::Scored { key, member, score } => {
+                (b'Z', vec![key, member], Some(score.to_bits()))
+            }
+            Event::Expiry {
+                key,
+                expires_at_ms: Some(expires),
+            } => (b'E', vec![key], Some(*expires)),
+            Event::Expiry {
+                key,
+                expires_at_ms: None,
+            } => (b'N', vec![key], None),
+        };
+        hasher.update(&[tag]);
+        for part in parts {
+            hasher.update(&(part.len() as u64).to_be_bytes());
+            hasher.update(part.as_bytes());
+        }
+        if let Some(number) = number {
+            hasher.update(&number.to_be_bytes());
+        }
+    }
+    hasher.finalize()
+}
+
+/// A change notification pushed to subscribers.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Event {
+    /// `key` was set to `value`.
+    Set { key: String, value: String },
+    /// `key` was removed.
+    Removed { key: String },
+    /// `value` was pushed onto the list `key`, at its front if `front`.
+    Pushed {
+        key: String,
+        value: String,
+        front: bool,
+    },
+    /// The value at the front of the list `key` was popped.
+    Popped { key: String },
+    /// `field` of the hash `key` was set to `value`.
+    FieldSet {
+        key: String,
+        field: String,
+        value: String,
+    },
+    /// `field` of the hash `key` was removed.
+    FieldRemoved { key: String, field: String },
+    /// `member` was added to the set `key`.
+    MemberAdded { key: String, member: String },
+    /// `member` was removed from the set `key`.
+    MemberRemoved { key: String, member: String },
+    /// `member` of the sorted set `key` was scored `score`.
+    Scored {
+        key: String,
+        member: String,
+        score: f64,
+    },
+    /// `key` now expires at `expires_at_ms`, in milliseconds since the
+    /// Unix epoch, or no longer expires if `None`.
+    Expiry {
+        key: String,
+        expires_at_ms: Option<u64>,
+    },
+}
+
+impl Event {
+    /// The key this event is about.
+    pub fn key(&self) -> &str {
+        match self {
+            Event::Set { key, .. }
+            | Event::Removed { key }
+            | Event::Pushed { key, .. }
+            | Event::Popped { key }
+            | Event::FieldSet { key, .. }
+            | Event::FieldRemoved { key, .. }
+            | Event::MemberAdded { key, .. }
+            | Event::MemberRemoved { key, .. }
+            | Event::Scored { key, .. }
+            | Event::Expiry { key, .. } => key,
+        }
+    }
+
+    /// Whether the event is a `Set` or a `Removed`, the only ones peers
+    /// older than protocol version 10 know of.
+    pub(crate) fn is_set_or_removed(&self) -> bool {
+        matches!(self, Event::Set { .. } | Event::Removed { .. })
+    }
 }
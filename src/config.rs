@@ -0,0 +1,186 @@
+//! kvs-server configuration file.
+//!
+//! Every key mirrors the kvs-server flag of the same name, with dashes
+//! replaced by underscores:
+//!
+//! ```toml
+//! addr = "127.0.0.1:4000"
+//! engine = "kvs"
+//! data_dir = "/var/lib/kvs"
+//! auth_token = "${KVS_TOKEN}"
+//! sync_policy = "always"
+//! compaction_threshold = 1048576
+//! ```
+//!
+//! `${NAME}` inside a string is replaced by the environment variable `NAME`.
+//!
+//! On SIGHUP kvs-server reads the file again and applies the new log level,
+//! authentication, connection and rate limits, timeouts, slow-log threshold and
+//! compaction threshold; other keys take effect on restart.
+use crate::engine::{CompactionWindow, EvictionPolicy, SyncPolicy};
+use crate::errors::{MyError, Result};
+use crate::replication::Consistency;
+
+use log::LevelFilter;
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use toml::{Table, Value};
+
+/// Settings read from a kvs-server configuration file. Unset keys are
+/// `None` (or empty) so that command line flags can take precedence.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub addr: Option<SocketAddr>,
+    pub data_dir: Option<PathBuf>,
+    pub engine: Option<String>,
+    pub ws_addr: Option<SocketAddr>,
+    pub ws_origins: Vec<String>,
+    pub grpc_addr: Option<SocketAddr>,
+    pub admin_addr: Option<SocketAddr>,
+    pub unix_socket: Option<PathBuf>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub replica_of: Option<SocketAddr>,
+    pub read_only: Option<bool>,
+    pub warm_up: Option<bool>,
+    /// Seconds.
+    pub lame_duck_secs: Option<u64>,
+    #[serde(deserialize_with = "parsed")]
+    pub consistency: Option<Consistency>,
+    pub auth_token: Option<String>,
+    pub acl: Option<PathBuf>,
+    pub tenants: Option<PathBuf>,
+    pub max_connections: Option<usize>,
+    pub rate_limit: Option<u32>,
+    /// Seconds.
+    pub idle_timeout: Option<u64>,
+    /// Milliseconds.
+    pub request_timeout: Option<u64>,
+    pub slow_log_ms: Option<u64>,
+    /// Seconds.
+    pub snapshot_interval: Option<u64>,
+    pub snapshot_dir: Option<PathBuf>,
+    pub snapshot_retain: Option<usize>,
+    pub audit_log: Option<PathBuf>,
+    pub audit_log_max_bytes: Option<u64>,
+    pub audit_log_files: Option<usize>,
+    pub audit_redact_values: Option<bool>,
+    pub log_format: Option<String>,
+    #[serde(deserialize_with = "parsed")]
+    pub log_level: Option<LevelFilter>,
+    pub log_file: Option<PathBuf>,
+    pub daemonize: Option<bool>,
+    pub pid_file: Option<PathBuf>,
+    pub raft_addr: Option<SocketAddr>,
+    pub raft_peers: Vec<SocketAddr>,
+    pub otlp_endpoint: Option<String>,
+    #[serde(deserialize_with = "parsed")]
+    pub sync_policy: Option<SyncPolicy>,
+    pub compaction_threshold: Option<u64>,
+    #[serde(deserialize_with = "parsed_list")]
+    pub compaction_windows: Vec<CompactionWindow>,
+    /// Bytes per second.
+    pub compaction_rate: Option<u64>,
+    pub value_threshold: Option<u64>,
+    pub cache_size: Option<u64>,
+    pub max_key_bytes: Option<u64>,
+    pub max_value_bytes: Option<u64>,
+    pub max_disk_bytes: Option<u64>,
+    /// Seconds.
+    pub trash_retention: Option<u64>,
+    pub soft_stall_bytes: Option<u64>,
+    pub hard_stall_bytes: Option<u64>,
+    pub verify_on_start: Option<bool>,
+    pub io_uring: Option<bool>,
+    pub maxmemory: Option<u64>,
+    #[serde(deserialize_with = "parsed")]
+    pub maxmemory_policy: Option<EvictionPolicy>,
+    pub indexes: Vec<String>,
+}
+
+impl ServerConfig {
+    /// Loads the configuration file at `path`.
+    pub fn load(path: &Path) -> Result<ServerConfig> {
+        ServerConfig::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parses the TOML content of a configuration file, rejecting unknown
+    /// keys so that typos do not go unnoticed.
+    pub fn parse(input: &str) -> Result<ServerConfig> {
+        let table: Table = toml::from_str(input).map_err(config_error)?;
+        interpolate(Value::Table(table))?
+            .try_into()
+            .map_err(config_error)
+    }
+}
+
+/// Replaces `${NAME}` in the strings of `value` by environment variables.
+fn interpolate(value: Value) -> Result<Value> {
+    Ok(match value {
+        Value::String(s) => Value::String(expand_env(&s)?),
+        Value::Array(items) => {
+            Value::Array(items.into_iter().map(interpolate).collect::<Result<_>>()?)
+        }
+        Value::Table(table) => Value::Table(
+            table
+                .into_iter()
+                .map(|(key, value)| Ok((key, interpolate(value)?)))
+                .collect::<Result<Table>>()?,
+        ),
+        other => other,
+    })
+}
+
+fn expand_env(s: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| MyError::StringError(format!("Unterminated `${{` in `{}`", s)))?;
+        let name = &rest[start + 2..start + end];
+        let value = env::var(name).map_err(|_| {
+            MyError::StringError(format!("Environment variable `{}` is not set", name))
+        })?;
+        expanded.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Deserializes a value written as a string, such as `"always"` or
+/// `"22:00-04:00"`, with its `FromStr` implementation.
+fn parsed<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(de::Error::custom)
+}
+
+/// Deserializes an array of values written as strings, as `parsed` does.
+fn parsed_list<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+fn config_error(e: impl fmt::Display) -> MyError {
+    MyError::StringError(format!("Invalid configuration: {}", e))
+}
@@ -0,0 +1,184 @@
+//! Asynchronous access to storage engines.
+use crate::engine::{KvsEngine, KvsReader};
+use crate::{MyError, Result};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+/// A boxed future, as returned by `AsyncKvsEngine`.
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = Result<T>> + Send>>;
+
+/// Trait for a key value storage engine used from asynchronous code.
+///
+/// Handles are cheap to clone and share the same store.
+pub trait AsyncKvsEngine: Clone + Send + Sync + 'static {
+    /// Gets the string value of a given string key.
+    ///
+    /// Resolves to `None` if the given key does not exist.
+    fn get(&self, key: String) -> BoxFuture<Option<String>>;
+
+    /// Sets the value of a string key to a string.
+    fn set(&self, key: String, value: String) -> BoxFuture<()>;
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It resolves to `KvsError::KeyNotFound` if the given key is not found.
+    fn remove(&self, key: String) -> BoxFuture<()>;
+
+    /// Returns every key/value pair whose key starts with `prefix`, in key
+    /// order.
+    fn scan(&self, prefix: String) -> BoxFuture<Vec<(String, String)>>;
+}
+
+/// Runs a synchronous engine for asynchronous callers: calls run on a fixed
+/// set of threads, so that they block neither the caller nor its executor,
+/// and wait for a free thread when all are busy.
+///
+/// Writes take turns on the engine, while reads go through its reader.
+pub struct BlockingEngine<E: KvsEngine> {
+    engine: Arc<Mutex<E>>,
+    reader: E::Reader,
+    workers: Arc<Workers>,
+}
+
+impl<E: KvsEngine> BlockingEngine<E> {
+    /// Wraps `engine`, with a thread per CPU.
+    pub fn new(engine: E) -> BlockingEngine<E> {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        BlockingEngine::with_threads(engine, threads)
+    }
+
+    /// Wraps `engine`, running up to `threads` calls at once.
+    pub fn with_threads(engine: E, threads: usize) -> BlockingEngine<E> {
+        BlockingEngine {
+            reader: engine.reader(),
+            engine: Arc::new(Mutex::new(engine)),
+            workers: Arc::new(Workers::new(threads)),
+        }
+    }
+}
+
+impl<E: KvsEngine> Clone for BlockingEngine<E> {
+    fn clone(&self) -> Self {
+        BlockingEngine {
+            engine: Arc::clone(&self.engine),
+            reader: self.reader.clone(),
+            workers: Arc::clone(&self.workers),
+        }
+    }
+}
+
+impl<E: KvsEngine + Send + 'static> AsyncKvsEngine for BlockingEngine<E> {
+    fn get(&self, key: String) -> BoxFuture<Option<String>> {
+        let reader = self.reader.clone();
+        self.workers.spawn(move || reader.get(key))
+    }
+
+    fn set(&self, key: String, value: String) -> BoxFuture<()> {
+        let engine = Arc::clone(&self.engine);
+        self.workers
+            .spawn(move || engine.lock().unwrap().set(key, value))
+    }
+
+    fn remove(&self, key: String) -> BoxFuture<()> {
+        let engine = Arc::clone(&self.engine);
+        self.workers
+            .spawn(move || engine.lock().unwrap().remove(key))
+    }
+
+    fn scan(&self, prefix: String) -> BoxFuture<Vec<(String, String)>> {
+        let reader = self.reader.clone();
+        self.workers.spawn(move || reader.scan(prefix))
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads running the calls of a `BlockingEngine` and its clones, which
+/// exit once the last of them is dropped.
+struct Workers {
+    jobs: Sender<Job>,
+}
+
+impl Workers {
+    fn new(threads: usize) -> Workers {
+        let (jobs, queue) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..threads.max(1) {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || work(&queue));
+        }
+        Workers { jobs }
+    }
+
+    /// Queues `call` for the next free thread, resolving to its result.
+    fn spawn<T, F>(&self, call: F) -> BoxFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        let shared = Arc::new(Mutex::new(Shared {
+            result: None,
+            waker: None,
+        }));
+        let task = Arc::clone(&shared);
+        let job: Job = Box::new(move || {
+            // a panic must still resolve the future
+            let result = panic::catch_unwind(AssertUnwindSafe(call))
+                .unwrap_or_else(|_| Err(MyError::StringError("The engine panicked".to_owned())));
+            let mut shared = task.lock().unwrap();
+            shared.result = Some(result);
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+        });
+        // the threads only exit once `jobs` is dropped
+        self.jobs.send(job).expect("no thread to run the call");
+        Box::pin(Blocking { shared })
+    }
+}
+
+/// Runs the jobs of `queue` until every sender is gone.
+fn work(queue: &Mutex<Receiver<Job>>) {
+    loop {
+        // the lock is released before the job runs
+        let job = match queue.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        job();
+    }
+}
+
+/// State shared by a blocking call and the future waiting for it.
+struct Shared<T> {
+    result: Option<Result<T>>,
+    /// Wakes the task last polling the future.
+    waker: Option<Waker>,
+}
+
+/// Future of a call made by `spawn_blocking`.
+struct Blocking<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Future for Blocking<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T>> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Cache of recently read values, evicting the least recently used.
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Values of a store by key, up to a capacity in bytes of keys and values.
+///
+/// Values are kept as `Bytes`, so that a hit hands out the cached value
+/// rather than a copy of it.
+///
+/// Entries carry the sequence number of the write that set the value, so a
+/// lookup for a newer write misses even if a stale value slipped in after
+/// the key was invalidated.
+pub(crate) struct ValueCache {
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct CacheState {
+    capacity: u64,
+    /// Bytes of keys and values cached.
+    size: u64,
+    entries: HashMap<String, CacheEntry>,
+    /// Keys by the time of their last use, least recent first.
+    lru: BTreeMap<u64, String>,
+    /// Ticks on every use.
+    clock: u64,
+}
+
+struct CacheEntry {
+    seq: u64,
+    value: Bytes,
+    used: u64,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: u64) -> ValueCache {
+        ValueCache {
+            state: Mutex::new(CacheState {
+                capacity,
+                size: 0,
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The value the write `seq` set `key` to, if cached.
+    pub(crate) fn get(&self, key: &str, seq: u64) -> Option<Bytes> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.clock += 1;
+        match state.entries.get_mut(key) {
+            Some(entry) if entry.seq == seq => {
+                state.lru.remove(&entry.used);
+                entry.used = state.clock;
+                state.lru.insert(entry.used, key.to_owned());
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(entry.value.clone())
+            }
+            _ => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Caches `value`, set by the write `seq`, evicting the least recently
+    /// used values to make room. Values larger than the cache are skipped.
+    pub(crate) fn insert(&self, key: String, seq: u64, value: Bytes) {
+        let size = entry_size(&key, &value);
+        let mut state = self.state.lock().unwrap();
+        if size > state.capacity {
+            return;
+        }
+        state.remove(&key);
+        while state.size + size > state.capacity {
+            let oldest = match state.lru.keys().next() {
+                Some(used) => *used,
+                None => break,
+            };
+            let key = state.lru[&oldest].clone();
+            state.remove(&key);
+        }
+        state.clock += 1;
+        let used = state.clock;
+        state.lru.insert(used, key.clone());
+        state.entries.insert(key, CacheEntry { seq, value, used });
+        state.size += size;
+    }
+
+    /// Drops the value of `key`, which was just written.
+    pub(crate) fn invalidate(&self, key: &str) {
+        self.state.lock().unwrap().remove(key);
+    }
+
+    /// Drops every value, the store having been cleared.
+    pub(crate) fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.lru.clear();
+        state.size = 0;
+    }
+
+    /// The cached keys, least recently used first.
+    pub(crate) fn keys(&self) -> Vec<String> {
+        self.state.lock().unwrap().lru.values().cloned().collect()
+    }
+
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+impl CacheState {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.used);
+            self.size -= entry_size(key, &entry.value);
+        }
+    }
+}
+
+fn entry_size(key: &str, value: &[u8]) -> u64 {
+    (key.len() + value.len()) as u64
+}
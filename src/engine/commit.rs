@@ -0,0 +1,90 @@
+//! Group commit: one `fsync` of the log for the writes of many writers.
+use crate::engine::io::Ring;
+use crate::Result;
+use std::fs::File;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Makes the writes of a store durable on behalf of the writers waiting for
+/// them, as returned by `KvsEngine::defer_syncs`.
+///
+/// A writer calling `sync` either syncs the log itself, covering every write
+/// handed to the OS so far, or waits for the sync in progress and, if that
+/// one started before its write, for the next. Writers arriving during a
+/// sync thus share the following one.
+pub struct GroupCommit {
+    state: Mutex<CommitState>,
+    /// Submits the syncs of all the files at once, if set.
+    ring: Option<Arc<Ring>>,
+    /// Signalled whenever a sync completes.
+    synced: Condvar,
+}
+
+struct CommitState {
+    /// The files written, replaced when compaction rewrites them.
+    files: Arc<Vec<File>>,
+    /// Number of writes handed to the OS.
+    written: u64,
+    /// Number of writes known to be on disk.
+    synced: u64,
+    /// Whether a writer is syncing the log.
+    syncing: bool,
+}
+
+impl GroupCommit {
+    /// Syncs `files`, in order, through `ring` if given.
+    pub(crate) fn new(files: Vec<File>, ring: Option<Arc<Ring>>) -> GroupCommit {
+        GroupCommit {
+            ring,
+            state: Mutex::new(CommitState {
+                files: Arc::new(files),
+                written: 0,
+                synced: 0,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    /// Records that a write was handed to the OS.
+    pub(crate) fn written(&self) {
+        self.state.lock().unwrap().written += 1;
+    }
+
+    /// Switches to the `files` compaction wrote, which are already on disk
+    /// with every write so far.
+    pub(crate) fn replace_files(&self, files: Vec<File>) {
+        let mut state = self.state.lock().unwrap();
+        state.files = Arc::new(files);
+        state.synced = state.written;
+        self.synced.notify_all();
+    }
+
+    /// Returns once every write made before the call is on disk.
+    pub fn sync(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let target = state.written;
+        while state.synced < target {
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            state.syncing = true;
+            let (files, written) = (Arc::clone(&state.files), state.written);
+            drop(state);
+            let result = match &self.ring {
+                Some(ring) => ring.sync_data(&files),
+                None => files.iter().try_for_each(File::sync_data),
+            };
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if result.is_ok() {
+                // compaction may have moved it further meanwhile
+                state.synced = state.synced.max(written);
+            }
+            // on failure, a waiter tries again
+            self.synced.notify_all();
+            result?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,487 @@
+//! An engine wrapper failing on demand, for testing recovery.
+use crate::engine::{
+    EngineStats, EventListener, GroupCommit, KeyMeta, KvsEngine, KvsReader, WriteBatch,
+};
+use crate::{MyError, Result};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Where a [`FaultyEngine`] may fail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FaultPoint {
+    /// Reads, through the engine or its reader.
+    Read,
+    /// Writes of one key: sets, removes, and the writes to collections.
+    Write,
+    /// `write_batch`.
+    Batch,
+    /// `clear`.
+    Clear,
+    /// `sweep_expired`.
+    Sweep,
+    /// `snapshot`.
+    Snapshot,
+    /// `shutdown`.
+    Shutdown,
+}
+
+/// What a [`FaultyEngine`] does at a fault point.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// Fails with a `MyError::Io` of this kind, writing nothing.
+    Io(io::ErrorKind),
+    /// Sleeps this long, then goes on.
+    Delay(Duration),
+    /// Writes part of what was asked, then fails as `Io` would: the first
+    /// `n` commands of a batch, or the whole of a single write, the caller
+    /// not learning of it. Elsewhere the same as `Io`.
+    PartialWrite(usize),
+}
+
+/// A fault and the calls it applies to.
+struct Injection {
+    fault: Fault,
+    /// Calls to let through first.
+    skip: u64,
+    /// Calls to apply to, then removed; `None` for every call.
+    times: Option<u64>,
+}
+
+/// Calls made at a fault point, and the faults injected there.
+#[derive(Default)]
+struct Point {
+    calls: u64,
+    injections: Vec<Injection>,
+}
+
+/// The faults of a [`FaultyEngine`], shared with the tests driving it, its
+/// buckets and its readers.
+///
+/// Faults are injected at a point, and apply until cleared or used up;
+/// calls are counted at every point, faulty or not.
+#[derive(Clone, Default)]
+pub struct Faults {
+    points: Arc<Mutex<HashMap<FaultPoint, Point>>>,
+}
+
+impl Faults {
+    /// Applies `fault` to every later call at `point`.
+    pub fn inject(&self, point: FaultPoint, fault: Fault) {
+        self.push(point, fault, 0, None);
+    }
+
+    /// Applies `fault` to the `n`th later call at `point` only, counting
+    /// from 1.
+    pub fn inject_nth(&self, point: FaultPoint, n: u64, fault: Fault) {
+        self.push(point, fault, n.saturating_sub(1), Some(1));
+    }
+
+    /// Removes the faults injected at every point.
+    pub fn clear(&self) {
+        for point in self.points.lock().unwrap().values_mut() {
+            point.injections.clear();
+        }
+    }
+
+    /// Calls made at `point` so far.
+    pub fn calls(&self, point: FaultPoint) -> u64 {
+        self.points
+            .lock()
+            .unwrap()
+            .get(&point)
+            .map_or(0, |point| point.calls)
+    }
+
+    fn push(&self, point: FaultPoint, fault: Fault, skip: u64, times: Option<u64>) {
+        let mut points = self.points.lock().unwrap();
+        let injection = Injection { fault, skip, times };
+        points.entry(point).or_default().injections.push(injection);
+    }
+
+    /// Counts a call at `point`, returning the fault it hits, if any. The
+    /// first injection applying to it wins; every other one sees the call
+    /// as let through.
+    fn hit(&self, point: FaultPoint) -> Option<Fault> {
+        let mut points = self.points.lock().unwrap();
+        let point = points.entry(point).or_default();
+        point.calls += 1;
+        let mut hit = None;
+        point.injections.retain_mut(|injection| {
+            if hit.is_some() || injection.skip > 0 {
+                injection.skip = injection.skip.saturating_sub(1);
+                return true;
+            }
+            hit = Some(injection.fault);
+            if let Some(times) = &mut injection.times {
+                *times -= 1;
+                return *times > 0;
+            }
+            true
+        });
+        hit
+    }
+
+    /// Fails, or sleeps, as the fault of a call at `point` requires.
+    fn check(&self, point: FaultPoint) -> Result<()> {
+        match self.hit(point) {
+            None => Ok(()),
+            Some(Fault::Delay(delay)) => {
+                thread::sleep(delay);
+                Ok(())
+            }
+            Some(Fault::Io(kind)) => Err(injected(kind)),
+            Some(Fault::PartialWrite(_)) => Err(injected(io::ErrorKind::Interrupted)),
+        }
+    }
+}
+
+/// Wraps an engine, failing or slowing down its operations as the
+/// [`Faults`] it shares with a test require, so that code built on
+/// `KvsEngine`, the server included, can be tested for how it recovers.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{Fault, FaultPoint, FaultyEngine, Faults, KvsEngine, MemEngine, Result};
+/// # use std::io::ErrorKind;
+/// # fn try_main() -> Result<()> {
+/// let faults = Faults::default();
+/// let mut store = FaultyEngine::new(MemEngine::new(), faults.clone());
+/// faults.inject_nth(FaultPoint::Write, 2, Fault::Io(ErrorKind::Other));
+/// store.set("key1".to_owned(), "value1".to_owned())?;
+/// assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+/// store.set("key3".to_owned(), "value3".to_owned())?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Available with the `testing` feature.
+pub struct FaultyEngine<E: KvsEngine> {
+    engine: E,
+    faults: Faults,
+}
+
+impl<E: KvsEngine> FaultyEngine<E> {
+    /// Wraps `engine`, failing as `faults` require.
+    pub fn new(engine: E, faults: Faults) -> FaultyEngine<E> {
+        FaultyEngine { engine, faults }
+    }
+
+    /// The engine wrapped.
+    pub fn get_ref(&self) -> &E {
+        &self.engine
+    }
+
+    /// The engine wrapped, to break it behind the wrapper's back.
+    pub fn get_mut(&mut self) -> &mut E {
+        &mut self.engine
+    }
+
+    /// Runs the single write `write` unless a fault is injected; a partial
+    /// write runs it and fails all the same.
+    fn write<T>(&mut self, write: impl FnOnce(&mut E) -> Result<T>) -> Result<T> {
+        match self.faults.hit(FaultPoint::Write) {
+            None => write(&mut self.engine),
+            Some(Fault::Delay(delay)) => {
+                thread::sleep(delay);
+                write(&mut self.engine)
+            }
+            Some(Fault::Io(kind)) => Err(injected(kind)),
+            Some(Fault::PartialWrite(_)) => {
+                write(&mut self.engine)?;
+                Err(injected(io::ErrorKind::Interrupted))
+            }
+        }
+    }
+}
+
+/// Reads of a [`FaultyEngine`], failing as its faults require.
+#[derive(Clone)]
+pub struct FaultyReader<R> {
+    reader: R,
+    faults: Faults,
+}
+
+impl<R: KvsReader> KvsReader for FaultyReader<R> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.faults.check(FaultPoint::Read)?;
+        self.reader.get(key)
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.faults.check(FaultPoint::Read)?;
+        self.reader.scan(prefix)
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for FaultyEngine<E> {
+    type Reader = FaultyReader<E::Reader>;
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.write(|engine| engine.set(key, value))
+    }
+
+    fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.write(|engine| engine.set_with_ttl(key, value, ttl))
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.faults.check(FaultPoint::Read)?;
+        self.engine.get(key)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        current: Option<String>,
+        new: Option<String>,
+        ttl: Option<Duration>,
+    ) -> Result<bool> {
+        self.write(|engine| engine.compare_and_swap(key, current, new, ttl))
+    }
+
+    fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        self.write(|engine| engine.set_nx(key, value))
+    }
+
+    fn contains_key(&mut self, key: String) -> Result<bool> {
+        self.faults.check(FaultPoint::Read)?;
+        self.engine.contains_key(key)
+    }
+
+    fn len(&mut self) -> Result<u64> {
+        self.faults.check(FaultPoint::Read)?;
+        self.engine.len()
+    }
+
+    fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.faults.check(FaultPoint::Read)?;
+        self.engine.ttl(key)
+    }
+
+    fn set_if_version(&mut self, key: String, version: u64, value: String) -> Result<u64> {
+        self.write(|engine| engine.set_if_version(key, version, value))
+    }
+
+    fn metadata(&mut self, key: String) -> Result<KeyMeta> {
+        self.faults.check(FaultPoint::Read)?;
+        self.engine.metadata(key)
+    }
+
+    fn expire(&mut self, key: String, ttl: Duration) -> Result<()> {
+        self.write(|engine| engine.expire(key, ttl))
+    }
+
+    fn touch(&mut self, keys: Vec<String>, ttl: Duration) -> Result<u64> {
+        self.write(|engine| engine.touch(keys, ttl))
+    }
+
+    fn persist(&mut self, key: String) -> Result<()> {
+        self.write(|engine| engine.persist(key))
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.write(|engine| engine.remove(key))
+    }
+
+    fn restore(&mut self, key: String) -> Result<()> {
+        self.write(|engine| engine.restore(key))
+    }
+
+    fn purge(&mut self) -> Result<u64> {
+        self.write(|engine| engine.purge())
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        match self.faults.hit(FaultPoint::Batch) {
+            None => self.engine.write_batch(batch),
+            Some(Fault::Delay(delay)) => {
+                thread::sleep(delay);
+                self.engine.write_batch(batch)
+            }
+            Some(Fault::Io(kind)) => Err(injected(kind)),
+            Some(Fault::PartialWrite(n)) => {
+                let mut commands = batch.commands;
+                commands.truncate(n);
+                self.engine.write_batch(WriteBatch { commands })?;
+                Err(injected(io::ErrorKind::Interrupted))
+            }
+        }
+    }
+
+    fn rename(&mut self, key: String, new_key: String) -> Result<()> {
+        self.write(|engine| engine.rename(key, new_key))
+    }
+
+    fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        self.write(|engine| engine.get_set(key, value))
+    }
+
+    fn get_del(&mut self, key: String) -> Result<Option<String>> {
+        self.write(|engine| engine.get_del(key))
+    }
+
+    fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.faults.check(FaultPoint::Read)?;
+        self.engine.scan(prefix)
+    }
+
+    fn keys(&mut self, pattern: String) -> Result<Vec<String>> {
+        self.faults.check(FaultPoint::Read)?;
+        self.engine.keys(pattern)
+    }
+
+    fn keys_after(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        count: usize,
+    ) -> Result<Vec<String>> {
+        self.faults.check(FaultPoint::Read)?;
+        self.engine.keys_after(prefix, after, count)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.faults.check(FaultPoint::Clear)?;
+        self.engine.clear()
+    }
+
+    fn add_listener(&mut self, listener: Arc<dyn EventListener>) -> Result<()> {
+        self.engine.add_listener(listener)
+    }
+
+    fn sweep_expired(&mut self, limit: usize) -> Result<Vec<String>> {
+        self.faults.check(FaultPoint::Sweep)?;
+        self.engine.sweep_expired(limit)
+    }
+
+    fn get_path(&mut self, key: String, path: &str) -> Result<Option<String>> {
+        self.faults.check(FaultPoint::Read)?;
+        self.engine.get_path(key, path)
+    }
+
+    fn set_path(&mut self, key: String, path: &str, value: String) -> Result<()> {
+        self.write(|engine| engine.set_path(key, path, value))
+    }
+
+    fn lpush(&mut self, key: String, value: String) -> Result<u64> {
+        self.write(|engine| engine.lpush(key, value))
+    }
+
+    fn rpush(&mut self, key: String, value: String) -> Result<u64> {
+        self.write(|engine| engine.rpush(key, value))
+    }
+
+    fn lpop(&mut self, key: String) -> Result<Option<String>> {
+        self.write(|engine| engine.lpop(key))
+    }
+
+    fn lrange(&mut self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        self.faults.check(FaultPoint::Read)?;
+        self.engine.lrange(key, start, stop)
+    }
+
+    fn hset(&mut self, key: String, field: String, value: String) -> Result<()> {
+        self.write(|engine| engine.hset(key, field, value))
+    }
+
+    fn hget(&mut self, key: String, field: String) -> Result<Option<String>> {
+        self.faults.check(FaultPoint::Read)?;
+        self.engine.hget(key, field)
+    }
+
+    fn hdel(&mut self, key: String, field: String) -> Result<()> {
+        self.write(|engine| engine.hdel(key, field))
+    }
+
+    fn hgetall(&mut self, key: String) -> Result<Vec<(String, String)>> {
+        self.faults.check(FaultPoint::Read)?;
+        self.engine.hgetall(key)
+    }
+
+    fn sadd(&mut self, key: String, member: String) -> Result<bool> {
+        self.write(|engine| engine.sadd(key, member))
+    }
+
+    fn srem(&mut self, key: String, member: String) -> Result<bool> {
+        self.write(|engine| engine.srem(key, member))
+    }
+
+    fn sismember(&mut self, key: String, member: String) -> Result<bool> {
+        self.faults.check(FaultPoint::Read)?;
+        self.engine.sismember(key, member)
+    }
+
+    fn smembers(&mut self, key: String) -> Result<Vec<String>> {
+        self.faults.check(FaultPoint::Read)?;
+        self.engine.smembers(key)
+    }
+
+    fn zadd(&mut self, key: String, member: String, score: f64) -> Result<bool> {
+        self.write(|engine| engine.zadd(key, member, score))
+    }
+
+    fn zrange_by_score(&mut self, key: String, min: f64, max: f64) -> Result<Vec<(String, f64)>> {
+        self.faults.check(FaultPoint::Read)?;
+        self.engine.zrange_by_score(key, min, max)
+    }
+
+    fn find_by_index(&mut self, path: &str, value: &str) -> Result<Vec<String>> {
+        self.faults.check(FaultPoint::Read)?;
+        self.engine.find_by_index(path, value)
+    }
+
+    /// Buckets share the faults of the engine.
+    fn open_bucket(&self, name: &str) -> Result<Self> {
+        Ok(FaultyEngine::new(
+            self.engine.open_bucket(name)?,
+            self.faults.clone(),
+        ))
+    }
+
+    fn reader(&self) -> FaultyReader<E::Reader> {
+        FaultyReader {
+            reader: self.engine.reader(),
+            faults: self.faults.clone(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        self.engine.name()
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        self.engine.stats()
+    }
+
+    fn set_compaction_threshold(&mut self, bytes: u64) -> Result<()> {
+        self.engine.set_compaction_threshold(bytes)
+    }
+
+    fn compact_now(&mut self) -> Result<()> {
+        self.engine.compact_now()
+    }
+
+    fn defer_syncs(&mut self) -> Result<Option<Arc<GroupCommit>>> {
+        self.engine.defer_syncs()
+    }
+
+    fn snapshot(&mut self, dir: &Path) -> Result<()> {
+        self.faults.check(FaultPoint::Snapshot)?;
+        self.engine.snapshot(dir)
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        self.faults.check(FaultPoint::Shutdown)?;
+        self.engine.shutdown()
+    }
+}
+
+/// The error of an injected fault.
+fn injected(kind: io::ErrorKind) -> MyError {
+    MyError::Io(io::Error::new(kind, "injected fault"))
+}
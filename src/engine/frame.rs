@@ -0,0 +1,284 @@
+//! The envelope records are written to the log in.
+//!
+//! A framed record is a header, then the record as serde_json writes it:
+//! the record separator character (`0x1e`), the length and the CRC-32 of
+//! the record in 8 hex digits each, and a line break. The length, rather
+//! than a delimiter, tells where a record ends, and the checksum covers all
+//! of it. The separator never occurs unescaped in JSON, so a reader lost
+//! after a corrupt header finds the next record by it.
+//!
+//! Logs written before framing hold bare records after a line break, or
+//! before one for removes. They are still read, a bare record lasting up
+//! to the next line break, separator or the end of the log, none of which
+//! JSON holds unescaped either.
+use crate::engine::kvs::Record;
+use crate::{MyError, Result};
+use std::io::{self, BufRead, Read, Write};
+use std::ops::Range;
+
+/// Starts the header of a framed record.
+const MARK: u8 = 0x1e;
+
+/// Bytes of the header of a framed record.
+const HEADER_LEN: usize = 18;
+
+/// How a `KvStore` writes records to its log.
+///
+/// Logs are read whatever the format of their records, so the format can
+/// change between runs; compaction rewrites every record in the current
+/// one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Records in an envelope giving their length and checksum.
+    Framed,
+    /// Bare records after a line break, which versions before framing can
+    /// read.
+    Legacy,
+}
+
+/// Writes `record` in `format`, and returns how many bytes it took.
+pub(crate) fn write_record(
+    writer: &mut impl Write,
+    record: &Record,
+    format: LogFormat,
+) -> Result<u64> {
+    let payload = serde_json::to_vec(record)?;
+    let header = match format {
+        LogFormat::Framed => {
+            if payload.len() > u32::MAX as usize {
+                return Err(MyError::TooLarge(format!(
+                    "Record of {} bytes exceeds the limit of {} bytes",
+                    payload.len(),
+                    u32::MAX
+                )));
+            }
+            writeln!(
+                writer,
+                "\x1e{:08x}{:08x}",
+                payload.len(),
+                crc32fast::hash(&payload)
+            )?;
+            HEADER_LEN
+        }
+        LogFormat::Legacy => {
+            writer.write_all(b"\r\n")?;
+            2
+        }
+    };
+    writer.write_all(&payload)?;
+    Ok((header + payload.len()) as u64)
+}
+
+/// Decodes the record held by `data`, the bytes a pointer spans: a framed
+/// or bare record, or a write inside a batch.
+pub(crate) fn decode_record(data: &[u8]) -> Result<Record> {
+    match RecordReader::new(data, data.len() as u64).next() {
+        Some(frame) => frame?.into_record(),
+        None => Err(MyError::corrupt("record missing")),
+    }
+}
+
+/// A record read from a log, or bytes where one was expected.
+pub(crate) struct Frame {
+    /// Where the record lies, from the start of its header, if framed.
+    pub(crate) range: Range<u64>,
+    pub(crate) format: LogFormat,
+    /// The record, unless the bytes do not decode as one.
+    pub(crate) record: Option<Record>,
+    /// Why the bytes cannot be trusted: a broken envelope, or bytes that
+    /// do not decode. The checksum of the record itself is left to
+    /// `Record::verify`.
+    pub(crate) error: Option<MyError>,
+}
+
+impl Frame {
+    /// The record, or why the bytes cannot be trusted.
+    pub(crate) fn into_record(self) -> Result<Record> {
+        match (self.record, self.error) {
+            (Some(record), None) => Ok(record),
+            (_, Some(e)) => Err(e),
+            (None, None) => Err(MyError::corrupt("record missing")),
+        }
+    }
+}
+
+/// Reads the records of a log in order, framed or bare.
+///
+/// After bytes that do not decode, reading resumes where the envelope says
+/// the record ends, or else at the next line break or separator. A header
+/// that does not parse, or claims more bytes than the log has left, is not
+/// trusted: reading resumes at the next separator after its first byte.
+pub(crate) struct RecordReader<R> {
+    reader: R,
+    offset: u64,
+    /// Bytes in the log.
+    len: u64,
+}
+
+impl<R: BufRead> RecordReader<R> {
+    /// Reads the `len` bytes of a log from `reader`.
+    pub(crate) fn new(reader: R, len: u64) -> RecordReader<R> {
+        RecordReader {
+            reader,
+            offset: 0,
+            len,
+        }
+    }
+
+    /// Skips whitespace, and returns the byte after it, if any.
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        loop {
+            let buf = self.reader.fill_buf()?;
+            let byte = match buf.first() {
+                Some(&byte) => byte,
+                None => return Ok(None),
+            };
+            let spaces = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+            if spaces == 0 {
+                return Ok(Some(byte));
+            }
+            self.reader.consume(spaces);
+            self.offset += spaces as u64;
+        }
+    }
+
+    /// Reads up to the next line break, separator or the end of the log,
+    /// which bare records never hold.
+    fn read_line(&mut self, line: &mut Vec<u8>) -> io::Result<()> {
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(());
+            }
+            let (len, end) = match buf.iter().position(|&b| matches!(b, b'\r' | b'\n' | MARK)) {
+                Some(len) => (len, true),
+                None => (buf.len(), false),
+            };
+            line.extend_from_slice(&buf[..len]);
+            self.reader.consume(len);
+            self.offset += len as u64;
+            if end {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Reads up to `len` bytes, fewer at the end of the log.
+    fn read_up_to(&mut self, len: usize, data: &mut Vec<u8>) -> io::Result<()> {
+        let read = (&mut self.reader).take(len as u64).read_to_end(data)?;
+        self.offset += read as u64;
+        Ok(())
+    }
+
+    /// Reads the header of a framed record, stopping before a separator
+    /// past its first byte, which starts the next record if this one is
+    /// torn.
+    fn read_header(&mut self) -> io::Result<Vec<u8>> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        while header.len() < HEADER_LEN {
+            let buf = self.reader.fill_buf()?;
+            let wanted = buf.len().min(HEADER_LEN - header.len());
+            let len = buf[..wanted]
+                .iter()
+                .enumerate()
+                .position(|(i, &b)| b == MARK && (i > 0 || !header.is_empty()))
+                .unwrap_or(wanted);
+            if len == 0 {
+                break;
+            }
+            header.extend_from_slice(&buf[..len]);
+            self.reader.consume(len);
+            self.offset += len as u64;
+        }
+        Ok(header)
+    }
+
+    /// Skips to the next separator or the end of the log.
+    fn skip_to_mark(&mut self) -> io::Result<()> {
+        let mut rest = Vec::new();
+        while !matches!(self.peek()?, None | Some(MARK)) {
+            rest.clear();
+            self.read_line(&mut rest)?;
+        }
+        Ok(())
+    }
+
+    fn read_framed(&mut self) -> io::Result<(Option<Record>, Option<MyError>)> {
+        let header = self.read_header()?;
+        let (len, crc) = match parse_header(&header) {
+            Some(header) => header,
+            None => {
+                self.skip_to_mark()?;
+                return Ok((None, Some(MyError::corrupt("invalid record header"))));
+            }
+        };
+        if len as u64 > self.len.saturating_sub(self.offset) {
+            // a torn record, or a length gone wrong that would swallow the
+            // records after it
+            self.skip_to_mark()?;
+            return Ok((None, Some(MyError::corrupt("record cut short"))));
+        }
+        let mut payload = Vec::with_capacity(len);
+        self.read_up_to(len, &mut payload)?;
+        if payload.len() < len {
+            return Ok((None, Some(MyError::corrupt("record cut short"))));
+        }
+        let record = serde_json::from_slice(&payload);
+        let error = match &record {
+            _ if crc32fast::hash(&payload) != crc => {
+                Some(MyError::corrupt("checksum mismatch in record header"))
+            }
+            Ok(_) => None,
+            Err(e) => Some(MyError::corrupt(e.to_string())),
+        };
+        Ok((record.ok(), error))
+    }
+}
+
+impl<R: BufRead> Iterator for RecordReader<R> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Result<Frame>> {
+        let first = match self.peek() {
+            Ok(first) => first?,
+            Err(e) => return Some(Err(e.into())),
+        };
+        let start = self.offset;
+        let read = match first {
+            MARK => self.read_framed().map(|read| (LogFormat::Framed, read)),
+            _ => {
+                let mut line = Vec::new();
+                self.read_line(&mut line).map(|()| {
+                    let read = match serde_json::from_slice(&line) {
+                        Ok(record) => (Some(record), None),
+                        Err(e) => (None, Some(e.into())),
+                    };
+                    (LogFormat::Legacy, read)
+                })
+            }
+        };
+        Some(
+            read.map(|(format, (record, error))| Frame {
+                range: start..self.offset,
+                format,
+                record,
+                error,
+            })
+            .map_err(MyError::from),
+        )
+    }
+}
+
+/// The length and checksum of the record a header frames.
+fn parse_header(header: &[u8]) -> Option<(usize, u32)> {
+    if header.len() != HEADER_LEN || header[0] != MARK || header[HEADER_LEN - 1] != b'\n' {
+        return None;
+    }
+    let hex = std::str::from_utf8(&header[1..HEADER_LEN - 1]).ok()?;
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let len = usize::from_str_radix(&hex[..8], 16).ok()?;
+    let crc = u32::from_str_radix(&hex[8..], 16).ok()?;
+    Some((len, crc))
+}
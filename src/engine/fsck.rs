@@ -0,0 +1,398 @@
+//! Offline inspection, verification and repair of a `KvStore` data
+//! directory.
+use crate::engine::frame::{write_record, LogFormat, RecordReader};
+use crate::engine::kvs::{
+    batch_ranges, lock_dir, Command, Record, CLEAN_SHUTDOWN_MARKER, LOG_FILE,
+};
+use crate::{MyError, Result};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Temporary file the repaired log is written to, next to the log.
+const REPAIR_FILE: &str = "repaired_log.json";
+
+/// What `fsck` found in a log.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    /// Number of records, corrupt ones included.
+    pub records: u64,
+    /// Number of records that do not decode or fail their checksum.
+    pub corrupt_records: u64,
+    /// Bytes of the records holding the current value of a key.
+    pub live_bytes: u64,
+    /// Every other byte of the log, which compaction would reclaim.
+    pub garbage_bytes: u64,
+    /// Each problem found, with the byte offset it was found at.
+    pub problems: Vec<String>,
+    /// Whether the log was rewritten without its corrupt records.
+    pub repaired: bool,
+}
+
+impl CheckReport {
+    /// Whether no problem was found.
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// A record of a log, or bytes where one was expected.
+pub(crate) struct Entry {
+    /// Offset of the first byte of the record.
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+    pub(crate) format: LogFormat,
+    /// The record, unless the bytes do not decode as one.
+    pub(crate) record: Option<Record>,
+    /// Why the record cannot be trusted.
+    pub(crate) error: Option<MyError>,
+}
+
+/// Splits a log into its records, framed or not.
+///
+/// After bytes that do not decode, reading resumes where their envelope
+/// says they end, or else at the next line or record separator.
+pub(crate) fn read_log(data: &[u8]) -> Vec<Entry> {
+    RecordReader::new(data, data.len() as u64)
+        // reading from memory cannot fail
+        .filter_map(|frame| frame.ok())
+        .map(|frame| {
+            let error = match (&frame.record, frame.error) {
+                (Some(record), None) => record.verify().err(),
+                (_, error) => error,
+            };
+            Entry {
+                offset: frame.range.start,
+                len: frame.range.end - frame.range.start,
+                format: frame.format,
+                record: frame.record,
+                error,
+            }
+        })
+        .collect()
+}
+
+/// Whether a log record matches its checksum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumStatus {
+    Ok,
+    Mismatch,
+    /// Written before records had checksums.
+    Missing,
+    /// The bytes do not decode as a record.
+    Unreadable,
+}
+
+/// A write in the log, as listed by `dump_log`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LogEntry {
+    /// Offset of the write in the log.
+    pub offset: u64,
+    /// Sequence number of the write, 0 if it has none.
+    pub seq: u64,
+    /// `Set`, `Remove`, `Push`, `Pop`, `HSet`, `HDel`, `SAdd`, `SRem`,
+    /// `ZAdd`, `Merge` or `Touch`; `Batch` for an empty batch and `Corrupt`
+    /// for bytes that do not decode.
+    pub kind: &'static str,
+    pub key: Option<String>,
+    /// Length of the value set, in bytes.
+    pub value_len: Option<usize>,
+    /// Whether the write is part of a batch.
+    pub batch: bool,
+    /// Status of the record holding the write.
+    pub checksum: ChecksumStatus,
+}
+
+/// Lists every write in the log of the data directory `dir`, in log order.
+///
+/// The directory is not locked, so a server may be appending meanwhile.
+pub fn dump_log(dir: impl AsRef<Path>) -> Result<Vec<LogEntry>> {
+    let data = fs::read(log_path(dir.as_ref())?)?;
+    let mut writes = Vec::new();
+    for entry in read_log(&data) {
+        let record = match entry.record {
+            Some(record) => record,
+            None => {
+                writes.push(LogEntry {
+                    offset: entry.offset,
+                    seq: 0,
+                    kind: "Corrupt",
+                    key: None,
+                    value_len: None,
+                    batch: false,
+                    checksum: ChecksumStatus::Unreadable,
+                });
+                continue;
+            }
+        };
+        let checksum = match (record.crc, &entry.error) {
+            (None, _) => ChecksumStatus::Missing,
+            (Some(_), None) => ChecksumStatus::Ok,
+            (Some(_), Some(_)) => ChecksumStatus::Mismatch,
+        };
+        let vlog = record.vlog;
+        let describe = |offset, seq, command: Command, batch| {
+            let (kind, key, value_len) = match command {
+                Command::Set { key, value } => (
+                    "Set",
+                    Some(key),
+                    Some(vlog.map_or(value.len(), |vlog| vlog.len as usize)),
+                ),
+                Command::Remove { key } => ("Remove", Some(key), None),
+                Command::Push { key, value, .. } => ("Push", Some(key), Some(value.len())),
+                Command::Pop { key, .. } => ("Pop", Some(key), None),
+                Command::HSet { key, value, .. } => ("HSet", Some(key), Some(value.len())),
+                Command::HDel { key, .. } => ("HDel", Some(key), None),
+                Command::SAdd { key, member } => ("SAdd", Some(key), Some(member.len())),
+                Command::SRem { key, .. } => ("SRem", Some(key), None),
+                Command::ZAdd { key, .. } => ("ZAdd", Some(key), None),
+                Command::Merge { key, operand } => ("Merge", Some(key), Some(operand.len())),
+                Command::Batch(_) => ("Batch", None, None),
+                Command::Touch { .. } => ("Touch", None, None),
+            };
+            LogEntry {
+                offset,
+                seq,
+                kind,
+                key,
+                value_len,
+                batch,
+                checksum,
+            }
+        };
+        match record.command {
+            Command::Batch(commands) if !commands.is_empty() => {
+                let ranges = batch_ranges(&commands, entry.offset + entry.len)?;
+                let first_seq = match record.seq {
+                    0 => 0,
+                    seq => seq + 1 - commands.len() as u64,
+                };
+                for (i, (command, range)) in commands.into_iter().zip(ranges).enumerate() {
+                    let seq = if first_seq == 0 {
+                        0
+                    } else {
+                        first_seq + i as u64
+                    };
+                    writes.push(describe(range.start, seq, command, true));
+                }
+            }
+            command => writes.push(describe(entry.offset, record.seq, command, false)),
+        }
+    }
+    Ok(writes)
+}
+
+/// The log of the data directory `dir`, which must have one.
+fn log_path(dir: &Path) -> Result<PathBuf> {
+    let path = dir.join(LOG_FILE);
+    if !path.is_file() {
+        return Err(MyError::StringError(format!(
+            "No kvs log in {}",
+            dir.display()
+        )));
+    }
+    Ok(path)
+}
+
+/// Verifies the log in the data directory `dir`: record checksums, the
+/// layout of batches the index relies on, and that the writes of each key
+/// are in order. With `repair`, a log with corrupt records is rewritten
+/// without them.
+///
+/// The directory is locked meanwhile, so this fails with
+/// `MyError::AlreadyLocked` while a server has it open.
+pub fn fsck(dir: impl AsRef<Path>, repair: bool) -> Result<CheckReport> {
+    let dir = dir.as_ref();
+    let path = log_path(dir)?;
+    let _lock = lock_dir(dir)?;
+    let data = fs::read(&path)?;
+    let entries = read_log(&data);
+    let mut report = check_log(&data, &entries)?;
+    if repair && report.corrupt_records > 0 {
+        rewrite(dir, &entries)?;
+        report.repaired = true;
+    }
+    Ok(report)
+}
+
+/// The checks of `fsck` on the log `data`, split into `entries`.
+pub(crate) fn check_log(data: &[u8], entries: &[Entry]) -> Result<CheckReport> {
+    let mut report = CheckReport::default();
+    let mut live = HashMap::new();
+    let mut lists: HashMap<String, VecDeque<u64>> = HashMap::new();
+    let mut hashes: HashMap<String, HashMap<String, u64>> = HashMap::new();
+    let mut sets: HashMap<String, HashMap<String, u64>> = HashMap::new();
+    let mut seqs = HashSet::new();
+    let mut last_seqs: HashMap<String, u64> = HashMap::new();
+    for entry in entries {
+        report.records += 1;
+        let record = match (&entry.record, &entry.error) {
+            (Some(record), None) => record,
+            (_, error) => {
+                report.corrupt_records += 1;
+                let error = error.as_ref().map(|e| e.to_string()).unwrap_or_default();
+                report
+                    .problems
+                    .push(format!("byte {}: {}", entry.offset, error));
+                continue;
+            }
+        };
+        let end = entry.offset + entry.len;
+        // touches write no value, and are garbage once compacted
+        if let Command::Touch { .. } = &record.command {
+            if record.seq != 0 && !seqs.insert(record.seq) {
+                report.problems.push(format!(
+                    "byte {}: sequence number {} used twice",
+                    entry.offset, record.seq
+                ));
+            }
+            continue;
+        }
+        let writes: Vec<(&Command, Range<u64>)> = match &record.command {
+            Command::Batch(commands) => {
+                let ranges = batch_ranges(commands, end)?;
+                for (command, range) in commands.iter().zip(&ranges) {
+                    let slice = &data[range.start as usize..range.end as usize];
+                    if serde_json::from_slice::<Command>(slice).ok().as_ref() != Some(command) {
+                        report.problems.push(format!(
+                            "byte {}: batch laid out differently than the index expects",
+                            entry.offset
+                        ));
+                        break;
+                    }
+                }
+                commands.iter().zip(ranges).collect()
+            }
+            command => vec![(command, entry.offset..end)],
+        };
+        let first_seq = record
+            .seq
+            .saturating_sub((writes.len() as u64).saturating_sub(1));
+        for (i, (command, range)) in writes.into_iter().enumerate() {
+            let key = match command {
+                Command::Set { key, .. }
+                | Command::Remove { key }
+                | Command::Push { key, .. }
+                | Command::Pop { key, .. }
+                | Command::HSet { key, .. }
+                | Command::HDel { key, .. }
+                | Command::SAdd { key, .. }
+                | Command::SRem { key, .. }
+                | Command::ZAdd { key, .. }
+                | Command::Merge { key, .. } => key,
+                Command::Batch(_) | Command::Touch { .. } => {
+                    unreachable!("batches hold single writes")
+                }
+            };
+            if record.seq != 0 {
+                let seq = first_seq + i as u64;
+                if !seqs.insert(seq) {
+                    report.problems.push(format!(
+                        "byte {}: sequence number {} used twice",
+                        entry.offset, seq
+                    ));
+                }
+                if let Some(last) = last_seqs.insert(key.clone(), seq) {
+                    if last > seq {
+                        report.problems.push(format!(
+                            "byte {}: `{}` written at sequence number {} after {}",
+                            entry.offset, key, seq, last
+                        ));
+                    }
+                }
+            }
+            let len = range.end - range.start;
+            match command {
+                Command::Set { .. } => {
+                    lists.remove(key);
+                    hashes.remove(key);
+                    sets.remove(key);
+                    live.insert(key.clone(), len);
+                }
+                // operands are live as long as the value they apply to
+                Command::Merge { .. } => *live.entry(key.clone()).or_default() += len,
+                Command::HSet { field, .. } => {
+                    hashes
+                        .entry(key.clone())
+                        .or_default()
+                        .insert(field.clone(), len);
+                }
+                Command::HDel { field, .. } => {
+                    if let Some(hash) = hashes.get_mut(key) {
+                        hash.remove(field);
+                    }
+                }
+                Command::SAdd { member, .. } => {
+                    sets.entry(key.clone())
+                        .or_default()
+                        .insert(member.clone(), len);
+                }
+                // sorted sets keep the last score of each member, as sets
+                // keep their members
+                Command::ZAdd { member, .. } => {
+                    sets.entry(key.clone())
+                        .or_default()
+                        .insert(member.clone(), len);
+                }
+                Command::SRem { member, .. } => {
+                    if let Some(set) = sets.get_mut(key) {
+                        set.remove(member);
+                    }
+                }
+                Command::Push { front: true, .. } => {
+                    lists.entry(key.clone()).or_default().push_front(len)
+                }
+                Command::Push { front: false, .. } => {
+                    lists.entry(key.clone()).or_default().push_back(len)
+                }
+                Command::Pop { front, .. } => {
+                    if let Some(list) = lists.get_mut(key) {
+                        if *front {
+                            list.pop_front();
+                        } else {
+                            list.pop_back();
+                        }
+                    }
+                }
+                Command::Remove { .. } | Command::Batch(_) | Command::Touch { .. } => {
+                    lists.remove(key);
+                    hashes.remove(key);
+                    sets.remove(key);
+                    live.remove(key);
+                }
+            }
+        }
+    }
+    report.live_bytes = live.values().sum::<u64>()
+        + lists.values().flatten().sum::<u64>()
+        + hashes.values().flat_map(HashMap::values).sum::<u64>()
+        + sets.values().flat_map(HashMap::values).sum::<u64>();
+    report.garbage_bytes = data.len() as u64 - report.live_bytes;
+    Ok(report)
+}
+
+/// Replaces the log in `dir` with the intact records of `entries`.
+fn rewrite(dir: &Path, entries: &[Entry]) -> Result<()> {
+    let temp_path = dir.join(REPAIR_FILE);
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    // records keep their format, which their store chose
+    for entry in entries {
+        if let (Some(record), None) = (&entry.record, &entry.error) {
+            write_record(&mut writer, record, entry.format)?;
+        }
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+    fs::rename(&temp_path, dir.join(LOG_FILE))?;
+    // the marker vouches for the log as it was
+    let marker = dir.join(CLEAN_SHUTDOWN_MARKER);
+    if marker.exists() {
+        fs::remove_file(marker)?;
+    }
+    Ok(())
+}
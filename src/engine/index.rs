@@ -0,0 +1,358 @@
+//! Secondary indexes over fields of JSON values.
+use crate::engine::json_path::JsonPath;
+use crate::engine::{
+    Command, EngineStats, EventListener, GroupCommit, KeyMeta, KvsEngine, WriteBatch, TRASH_PREFIX,
+};
+use crate::{MyError, Result};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Wraps an engine, keeping indexes of the keys whose values are JSON
+/// documents by the value at some paths, for `find_by_index`.
+///
+/// The indexes live in memory: opening rebuilds them from a scan of the
+/// store, and every write through the wrapper updates them. Values that are
+/// not JSON, or lack a path, are left out of its index.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{IndexedEngine, KvsEngine, MemEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// let mut store = IndexedEngine::new(MemEngine::new(), &["$.email"])?;
+/// store.set("user1".to_owned(), r#"{"email":"a@example.com"}"#.to_owned())?;
+/// assert_eq!(store.find_by_index("$.email", "a@example.com")?, vec!["user1"]);
+/// # Ok(())
+/// # }
+/// ```
+pub struct IndexedEngine<E: KvsEngine> {
+    engine: E,
+    paths: Vec<JsonPath>,
+    /// For each path, the keys by the value at that path.
+    indexes: Vec<BTreeMap<String, BTreeSet<String>>>,
+    /// For each key indexed, its value at each path.
+    entries: HashMap<String, Vec<Option<String>>>,
+}
+
+impl<E: KvsEngine> IndexedEngine<E> {
+    /// Wraps `engine`, indexing the values at `paths`, e.g. `$.email`.
+    pub fn new(mut engine: E, paths: &[&str]) -> Result<IndexedEngine<E>> {
+        let paths = paths
+            .iter()
+            .map(|path| path.parse())
+            .collect::<Result<Vec<JsonPath>>>()?;
+        let pairs = engine.scan(String::new())?;
+        let mut indexed = IndexedEngine {
+            engine,
+            indexes: vec![BTreeMap::new(); paths.len()],
+            paths,
+            entries: HashMap::new(),
+        };
+        for (key, value) in pairs {
+            indexed.index(key, Some(&value));
+        }
+        Ok(indexed)
+    }
+
+    /// The engine wrapped.
+    pub fn get_ref(&self) -> &E {
+        &self.engine
+    }
+
+    /// Points the indexes at `value`, the new value of `key`, or drops the
+    /// key from them if it was removed. Keys of the trash are left out.
+    fn index(&mut self, key: String, value: Option<&str>) {
+        if key.starts_with(TRASH_PREFIX) {
+            return;
+        }
+        if let Some(previous) = self.entries.remove(&key) {
+            for (index, field) in self.indexes.iter_mut().zip(previous) {
+                let field = match field {
+                    Some(field) => field,
+                    None => continue,
+                };
+                if let Some(keys) = index.get_mut(&field) {
+                    keys.remove(&key);
+                    if keys.is_empty() {
+                        index.remove(&field);
+                    }
+                }
+            }
+        }
+        let doc = match value.and_then(|value| serde_json::from_str::<Value>(value).ok()) {
+            Some(doc) => doc,
+            None => return,
+        };
+        let fields: Vec<Option<String>> = self
+            .paths
+            .iter()
+            .map(|path| path.get(&doc).map(index_value))
+            .collect();
+        if fields.iter().all(Option::is_none) {
+            return;
+        }
+        for (index, field) in self.indexes.iter_mut().zip(&fields) {
+            if let Some(field) = field {
+                index.entry(field.clone()).or_default().insert(key.clone());
+            }
+        }
+        self.entries.insert(key, fields);
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for IndexedEngine<E> {
+    type Reader = E::Reader;
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.engine.set(key.clone(), value.clone())?;
+        self.index(key, Some(&value));
+        Ok(())
+    }
+
+    fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.engine.set_with_ttl(key.clone(), value.clone(), ttl)?;
+        self.index(key, Some(&value));
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+
+    fn contains_key(&mut self, key: String) -> Result<bool> {
+        self.engine.contains_key(key)
+    }
+
+    fn len(&mut self) -> Result<u64> {
+        self.engine.len()
+    }
+
+    fn keys(&mut self, pattern: String) -> Result<Vec<String>> {
+        self.engine.keys(pattern)
+    }
+
+    fn snapshot(&mut self, dir: &Path) -> Result<()> {
+        self.engine.snapshot(dir)
+    }
+
+    fn keys_after(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        count: usize,
+    ) -> Result<Vec<String>> {
+        self.engine.keys_after(prefix, after, count)
+    }
+
+    fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.engine.ttl(key)
+    }
+
+    fn set_if_version(&mut self, key: String, version: u64, value: String) -> Result<u64> {
+        let version = self
+            .engine
+            .set_if_version(key.clone(), version, value.clone())?;
+        self.index(key, Some(&value));
+        Ok(version)
+    }
+
+    fn metadata(&mut self, key: String) -> Result<KeyMeta> {
+        self.engine.metadata(key)
+    }
+
+    fn expire(&mut self, key: String, ttl: Duration) -> Result<()> {
+        self.engine.expire(key, ttl)
+    }
+
+    fn touch(&mut self, keys: Vec<String>, ttl: Duration) -> Result<u64> {
+        self.engine.touch(keys, ttl)
+    }
+
+    fn persist(&mut self, key: String) -> Result<()> {
+        self.engine.persist(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.engine.remove(key.clone())?;
+        self.index(key, None);
+        Ok(())
+    }
+
+    fn restore(&mut self, key: String) -> Result<()> {
+        self.engine.restore(key.clone())?;
+        let value = self.engine.get(key.clone())?;
+        self.index(key, value.as_deref());
+        Ok(())
+    }
+
+    /// The keys of the trash are not indexed.
+    fn purge(&mut self) -> Result<u64> {
+        self.engine.purge()
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let commands = batch.commands.clone();
+        self.engine.write_batch(batch)?;
+        for command in commands {
+            match command {
+                Command::Set { key, value } => self.index(key, Some(&value)),
+                Command::Remove { key } => self.index(key, None),
+                _ => unreachable!("batches hold sets and removes"),
+            }
+        }
+        Ok(())
+    }
+
+    fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.engine.scan(prefix)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.engine.clear()?;
+        self.indexes.iter_mut().for_each(BTreeMap::clear);
+        self.entries.clear();
+        Ok(())
+    }
+
+    /// Lists are not indexed.
+    fn lpush(&mut self, key: String, value: String) -> Result<u64> {
+        self.engine.lpush(key, value)
+    }
+
+    fn rpush(&mut self, key: String, value: String) -> Result<u64> {
+        self.engine.rpush(key, value)
+    }
+
+    fn lpop(&mut self, key: String) -> Result<Option<String>> {
+        self.engine.lpop(key)
+    }
+
+    fn lrange(&mut self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        self.engine.lrange(key, start, stop)
+    }
+
+    /// Hashes are not indexed either.
+    fn hset(&mut self, key: String, field: String, value: String) -> Result<()> {
+        self.engine.hset(key, field, value)
+    }
+
+    fn hget(&mut self, key: String, field: String) -> Result<Option<String>> {
+        self.engine.hget(key, field)
+    }
+
+    fn hdel(&mut self, key: String, field: String) -> Result<()> {
+        self.engine.hdel(key, field)
+    }
+
+    fn hgetall(&mut self, key: String) -> Result<Vec<(String, String)>> {
+        self.engine.hgetall(key)
+    }
+
+    /// Nor are sets, sorted or not.
+    fn sadd(&mut self, key: String, member: String) -> Result<bool> {
+        self.engine.sadd(key, member)
+    }
+
+    fn srem(&mut self, key: String, member: String) -> Result<bool> {
+        self.engine.srem(key, member)
+    }
+
+    fn sismember(&mut self, key: String, member: String) -> Result<bool> {
+        self.engine.sismember(key, member)
+    }
+
+    fn smembers(&mut self, key: String) -> Result<Vec<String>> {
+        self.engine.smembers(key)
+    }
+
+    fn zadd(&mut self, key: String, member: String, score: f64) -> Result<bool> {
+        self.engine.zadd(key, member, score)
+    }
+
+    fn zrange_by_score(&mut self, key: String, min: f64, max: f64) -> Result<Vec<(String, f64)>> {
+        self.engine.zrange_by_score(key, min, max)
+    }
+
+    /// Matches the text of string fields, and the JSON text of others, e.g.
+    /// `42` or `true`. Expired keys not swept yet are left out.
+    fn find_by_index(&mut self, path: &str, value: &str) -> Result<Vec<String>> {
+        let path: JsonPath = path.parse()?;
+        let index = self
+            .paths
+            .iter()
+            .position(|indexed| *indexed == path)
+            .ok_or_else(|| MyError::StringError(format!("No index on `{}`", path)))?;
+        let keys: Vec<String> = self.indexes[index]
+            .get(value)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        let mut found = Vec::with_capacity(keys.len());
+        for key in keys {
+            if self.engine.get(key.clone())?.is_some() {
+                found.push(key);
+            }
+        }
+        Ok(found)
+    }
+
+    fn add_listener(&mut self, listener: Arc<dyn EventListener>) -> Result<()> {
+        self.engine.add_listener(listener)
+    }
+
+    fn sweep_expired(&mut self, limit: usize) -> Result<Vec<String>> {
+        let expired = self.engine.sweep_expired(limit)?;
+        for key in &expired {
+            self.index(key.clone(), None);
+        }
+        Ok(expired)
+    }
+
+    /// Buckets are indexed at the same paths.
+    fn open_bucket(&self, name: &str) -> Result<Self> {
+        let paths: Vec<String> = self.paths.iter().map(JsonPath::to_string).collect();
+        let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        IndexedEngine::new(self.engine.open_bucket(name)?, &paths)
+    }
+
+    fn reader(&self) -> E::Reader {
+        self.engine.reader()
+    }
+
+    fn name(&self) -> &'static str {
+        self.engine.name()
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        self.engine.stats()
+    }
+
+    fn set_compaction_threshold(&mut self, bytes: u64) -> Result<()> {
+        self.engine.set_compaction_threshold(bytes)
+    }
+
+    fn compact_now(&mut self) -> Result<()> {
+        self.engine.compact_now()
+    }
+
+    fn defer_syncs(&mut self) -> Result<Option<Arc<GroupCommit>>> {
+        self.engine.defer_syncs()
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        self.engine.shutdown()
+    }
+}
+
+/// The key a JSON value is indexed under: the text of a string, the JSON
+/// text of anything else.
+fn index_value(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
@@ -0,0 +1,526 @@
+//! Appends to the log of the kvs engine and reads of its records, through
+//! std IO or, with the `uring` feature on Linux, through io_uring.
+use crate::engine::WriteBuffer;
+use crate::Result;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub(crate) use self::uring::Ring;
+
+/// Stands in for the io_uring of a store where it is not built in; never
+/// constructed.
+#[cfg(not(all(feature = "uring", target_os = "linux")))]
+pub(crate) enum Ring {}
+
+#[cfg(not(all(feature = "uring", target_os = "linux")))]
+impl Ring {
+    pub(crate) fn write_at(&self, _: &File, _: &[u8], _: u64, _: bool) -> io::Result<()> {
+        match *self {}
+    }
+
+    pub(crate) fn read_exact_at(&self, _: &File, _: &mut [u8], _: u64) -> io::Result<()> {
+        match *self {}
+    }
+
+    pub(crate) fn sync_data(&self, _: &[File]) -> io::Result<()> {
+        match *self {}
+    }
+}
+
+/// A ring for a store asking for io_uring, `None` if the kernel refuses
+/// one: the store then uses std IO.
+#[cfg(feature = "uring")]
+pub(crate) fn open_ring() -> Option<Arc<Ring>> {
+    #[cfg(target_os = "linux")]
+    match Ring::new() {
+        Ok(ring) => Some(Arc::new(ring)),
+        Err(e) => {
+            log::warn!("io_uring unavailable ({}), using std IO", e);
+            None
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        log::warn!("io_uring is only available on Linux, using std IO");
+        None
+    }
+}
+
+/// Flushes in a row using a quarter of an adaptive write buffer or less
+/// after which it is halved.
+const SHRINK_AFTER_FLUSHES: u32 = 64;
+
+/// Follows the writes through a buffer sized by `WriteBuffer::Adaptive`.
+#[derive(Clone, Copy)]
+pub(crate) struct Adaptive {
+    min: usize,
+    max: usize,
+    /// Bytes written since the last flush.
+    pending: usize,
+    /// Flushes in a row using a quarter of the buffer or less.
+    small_flushes: u32,
+}
+
+impl Adaptive {
+    /// The capacity the buffer of `capacity` bytes should have once
+    /// flushed.
+    fn after_flush(&mut self, capacity: usize) -> usize {
+        let pending = std::mem::take(&mut self.pending);
+        if pending > capacity {
+            self.small_flushes = 0;
+            return pending.next_power_of_two().min(self.max).max(capacity);
+        }
+        if pending > capacity / 4 {
+            self.small_flushes = 0;
+            return capacity;
+        }
+        self.small_flushes += 1;
+        if self.small_flushes < SHRINK_AFTER_FLUSHES {
+            return capacity;
+        }
+        self.small_flushes = 0;
+        (capacity / 2).max(self.min)
+    }
+}
+
+/// Appends records to the log.
+///
+/// Writes are buffered until `flush`, which hands them to the OS in one
+/// write: a `BufWriter` with std IO, a write submitted at the end of the
+/// log with io_uring.
+pub(crate) enum LogWriter {
+    Std {
+        writer: BufWriter<File>,
+        /// Sizes the buffer, if it is adaptive.
+        adaptive: Option<Adaptive>,
+    },
+    Ring {
+        file: File,
+        ring: Arc<Ring>,
+        buf: Vec<u8>,
+        /// Bytes of the log handed to the OS.
+        len: u64,
+    },
+}
+
+impl LogWriter {
+    /// Opens the log at `path` for appending, creating it if needed.
+    pub(crate) fn open(
+        path: &Path,
+        ring: Option<&Arc<Ring>>,
+        buffer: WriteBuffer,
+    ) -> Result<LogWriter> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(crate::MyError::file(path))?;
+        Ok(match ring {
+            Some(ring) => LogWriter::Ring {
+                len: file.metadata()?.len(),
+                file,
+                ring: Arc::clone(ring),
+                buf: Vec::new(),
+            },
+            None => match buffer {
+                WriteBuffer::Fixed(bytes) => LogWriter::Std {
+                    writer: BufWriter::with_capacity(bytes, file),
+                    adaptive: None,
+                },
+                WriteBuffer::Adaptive { min, max } => LogWriter::Std {
+                    writer: BufWriter::with_capacity(min, file),
+                    adaptive: Some(Adaptive {
+                        min,
+                        max,
+                        pending: 0,
+                        small_flushes: 0,
+                    }),
+                },
+            },
+        })
+    }
+
+    /// Opens the log at `path` in place of this one, e.g. once compacted,
+    /// with a buffer of the same size, which keeps adapting.
+    pub(crate) fn reopen(&self, path: &Path) -> Result<LogWriter> {
+        match self {
+            LogWriter::Std { writer, adaptive } => {
+                let buffer = WriteBuffer::Fixed(writer.capacity());
+                let mut reopened = LogWriter::open(path, None, buffer)?;
+                if let LogWriter::Std {
+                    adaptive: reopened_adaptive,
+                    ..
+                } = &mut reopened
+                {
+                    *reopened_adaptive = *adaptive;
+                }
+                Ok(reopened)
+            }
+            LogWriter::Ring { ring, buf, .. } => {
+                LogWriter::open(path, Some(ring), WriteBuffer::Fixed(buf.capacity()))
+            }
+        }
+    }
+
+    /// Bytes the write buffer holds.
+    pub(crate) fn capacity(&self) -> usize {
+        match self {
+            LogWriter::Std { writer, .. } => writer.capacity(),
+            LogWriter::Ring { buf, .. } => buf.capacity(),
+        }
+    }
+
+    /// Resizes an adaptive buffer after the writes since the last flush,
+    /// which emptied it.
+    fn adapt(&mut self) -> io::Result<()> {
+        if let LogWriter::Std {
+            writer,
+            adaptive: Some(adaptive),
+        } = self
+        {
+            let capacity = adaptive.after_flush(writer.capacity());
+            if capacity != writer.capacity() {
+                // shares the position in the log, and its syncs
+                let file = writer.get_ref().try_clone()?;
+                *writer = BufWriter::with_capacity(capacity, file);
+            }
+        }
+        Ok(())
+    }
+
+    /// Length of the log, buffered writes included.
+    pub(crate) fn end(&mut self) -> io::Result<u64> {
+        match self {
+            LogWriter::Std { writer, .. } => writer.seek(SeekFrom::End(0)),
+            LogWriter::Ring { buf, len, .. } => Ok(*len + buf.len() as u64),
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &File {
+        match self {
+            LogWriter::Std { writer, .. } => writer.get_ref(),
+            LogWriter::Ring { file, .. } => file,
+        }
+    }
+
+    /// Flushes the buffered writes and syncs them to disk; with io_uring,
+    /// the write and the sync go in one submission.
+    pub(crate) fn flush_synced(&mut self) -> io::Result<()> {
+        match self {
+            LogWriter::Std { writer, .. } => {
+                writer.flush()?;
+                writer.get_ref().sync_data()?;
+                self.adapt()
+            }
+            LogWriter::Ring {
+                file,
+                ring,
+                buf,
+                len,
+            } => {
+                ring.write_at(file, buf, *len, true)?;
+                *len += buf.len() as u64;
+                buf.clear();
+                Ok(())
+            }
+        }
+    }
+
+    /// Cuts the log at `len`, dropping buffered writes.
+    pub(crate) fn set_len(&mut self, new_len: u64) -> io::Result<()> {
+        if let LogWriter::Ring { buf, len, .. } = self {
+            buf.clear();
+            *len = new_len;
+        }
+        self.get_ref().set_len(new_len)
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            LogWriter::Std { writer, adaptive } => {
+                let written = writer.write(data)?;
+                if let Some(adaptive) = adaptive {
+                    adaptive.pending += written;
+                }
+                Ok(written)
+            }
+            LogWriter::Ring { buf, .. } => {
+                buf.extend_from_slice(data);
+                Ok(data.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            LogWriter::Std { writer, .. } => {
+                writer.flush()?;
+                self.adapt()
+            }
+            LogWriter::Ring {
+                file,
+                ring,
+                buf,
+                len,
+            } => {
+                if !buf.is_empty() {
+                    ring.write_at(file, buf, *len, false)?;
+                    *len += buf.len() as u64;
+                    buf.clear();
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring {
+    use io_uring::{opcode, squeue, types, IoUring};
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::Mutex;
+
+    /// Entries of the submission queue, the most a submission may hold.
+    const RING_ENTRIES: u32 = 8;
+
+    /// An io_uring, shared by the writer and the readers of a store, which
+    /// take turns submitting and waiting for their completions.
+    pub(crate) struct Ring {
+        state: Mutex<State>,
+    }
+
+    struct State {
+        ring: IoUring,
+        /// Set once entries could be neither submitted nor withdrawn: the
+        /// ring is not used again, lest it run them.
+        broken: bool,
+    }
+
+    impl Ring {
+        pub(crate) fn new() -> io::Result<Ring> {
+            Ok(Ring {
+                state: Mutex::new(State {
+                    ring: IoUring::new(RING_ENTRIES)?,
+                    broken: false,
+                }),
+            })
+        }
+
+        /// Writes `buf` to `file` at `offset`, then syncs its data if
+        /// `sync`, the sync linked to the write in the same submission.
+        pub(crate) fn write_at(
+            &self,
+            file: &File,
+            buf: &[u8],
+            offset: u64,
+            sync: bool,
+        ) -> io::Result<()> {
+            let fd = types::Fd(file.as_raw_fd());
+            let mut written = 0;
+            loop {
+                let rest = &buf[written..];
+                let mut entries = Vec::with_capacity(2);
+                if !rest.is_empty() {
+                    let len = rest.len().min(u32::MAX as usize) as u32;
+                    entries.push(
+                        opcode::Write::new(fd, rest.as_ptr(), len)
+                            .offset(offset + written as u64)
+                            .build(),
+                    );
+                }
+                if sync {
+                    entries.push(
+                        opcode::Fsync::new(fd)
+                            .flags(types::FsyncFlags::DATASYNC)
+                            .build(),
+                    );
+                }
+                if entries.is_empty() {
+                    return Ok(());
+                }
+                let results = self.submit(&entries)?;
+                if rest.is_empty() {
+                    return check(results[0]).map(drop);
+                }
+                match check(results[0])? as usize {
+                    0 => return Err(io::ErrorKind::WriteZero.into()),
+                    n => written += n,
+                }
+                // a short write cancels the sync linked to it, which the
+                // next round submits again
+                if written == buf.len() {
+                    if sync {
+                        check(results[1])?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+
+        /// Fills `buf` from `file` at `offset`.
+        pub(crate) fn read_exact_at(
+            &self,
+            file: &File,
+            buf: &mut [u8],
+            offset: u64,
+        ) -> io::Result<()> {
+            let fd = types::Fd(file.as_raw_fd());
+            let mut read = 0;
+            while read < buf.len() {
+                let rest = &mut buf[read..];
+                let len = rest.len().min(u32::MAX as usize) as u32;
+                let entry = opcode::Read::new(fd, rest.as_mut_ptr(), len)
+                    .offset(offset + read as u64)
+                    .build();
+                match check(self.submit(&[entry])?[0])? as usize {
+                    0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    n => read += n,
+                }
+            }
+            Ok(())
+        }
+
+        /// Syncs the data of `files`, in order, in submissions of up to
+        /// `RING_ENTRIES`.
+        pub(crate) fn sync_data(&self, files: &[File]) -> io::Result<()> {
+            for files in files.chunks(RING_ENTRIES as usize) {
+                let entries: Vec<squeue::Entry> = files
+                    .iter()
+                    .map(|file| {
+                        opcode::Fsync::new(types::Fd(file.as_raw_fd()))
+                            .flags(types::FsyncFlags::DATASYNC)
+                            .build()
+                    })
+                    .collect();
+                for result in self.submit(&entries)? {
+                    check(result)?;
+                }
+            }
+            Ok(())
+        }
+
+        /// Submits `entries`, each linked to the next so that they run in
+        /// order and a failure cancels the rest, and returns their results
+        /// once all completed.
+        ///
+        /// The kernel uses the buffers of the entries until they complete,
+        /// so this only returns early if none was submitted.
+        fn submit(&self, entries: &[squeue::Entry]) -> io::Result<Vec<i32>> {
+            let mut state = self.state.lock().unwrap();
+            if state.broken {
+                return Err(io::Error::other("io_uring ring unusable"));
+            }
+            let entries: Vec<squeue::Entry> = entries
+                .iter()
+                .enumerate()
+                .map(|(i, entry)| {
+                    let entry = entry.clone().user_data(i as u64);
+                    match i + 1 < entries.len() {
+                        true => entry.flags(squeue::Flags::IO_LINK),
+                        false => entry,
+                    }
+                })
+                .collect();
+            // all of them or none, so that no chain is left half queued
+            unsafe { state.ring.submission().push_multiple(&entries) }
+                .map_err(|_| io::Error::other("io_uring submission queue full"))?;
+
+            let mut results = vec![None; entries.len()];
+            let mut reaped = 0;
+            while reaped < entries.len() {
+                match state.ring.submit_and_wait(entries.len() - reaped) {
+                    Ok(_) => {}
+                    // what was submitted still runs: reap it and wait again
+                    Err(e)
+                        if e.kind() == io::ErrorKind::Interrupted
+                            || state.ring.submission().is_empty() => {}
+                    // queued but not submitted, with no way to withdraw them
+                    Err(e) => {
+                        state.broken = true;
+                        return Err(e);
+                    }
+                }
+                for completion in state.ring.completion() {
+                    let result = results.get_mut(completion.user_data() as usize);
+                    if let Some(result @ None) = result {
+                        *result = Some(completion.result());
+                        reaped += 1;
+                    }
+                }
+            }
+            Ok(results.into_iter().flatten().collect())
+        }
+    }
+
+    /// The byte count of a completion, or the error it carries.
+    fn check(result: i32) -> io::Result<u32> {
+        match result {
+            result if result < 0 => Err(io::Error::from_raw_os_error(-result)),
+            result => Ok(result as u32),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::thread;
+        use std::time::Duration;
+
+        // A submission larger than the ring should push nothing and leave
+        // the ring ready for the next one; syncs of more files than the
+        // ring holds go in several.
+        #[test]
+        fn oversized_submission() {
+            // kernels refusing io_uring have nothing to test
+            let ring = match Ring::new() {
+                Ok(ring) => ring,
+                Err(_) => return,
+            };
+            let nops = vec![opcode::Nop::new().build(); RING_ENTRIES as usize + 1];
+            assert!(ring.submit(&nops).is_err());
+            assert_eq!(ring.submit(&nops[..2]).unwrap(), vec![0, 0]);
+
+            let dir = tempfile::TempDir::new().unwrap();
+            let files: Vec<File> = (0..RING_ENTRIES * 2 + 1)
+                .map(|i| File::create(dir.path().join(i.to_string())).unwrap())
+                .collect();
+            ring.sync_data(&files).unwrap();
+        }
+
+        // A signal arriving while waiting for completions should not end
+        // the wait before the entries, which use their buffers, complete.
+        #[test]
+        fn interrupted_wait() {
+            let ring = match Ring::new() {
+                Ok(ring) => ring,
+                Err(_) => return,
+            };
+            extern "C" fn ignore(_: libc::c_int) {}
+            // without SA_RESTART, so that the wait fails with EINTR
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = ignore as *const () as libc::sighandler_t;
+                libc::sigaction(libc::SIGUSR2, &action, std::ptr::null_mut());
+            }
+            let waiter = unsafe { libc::pthread_self() };
+            let signaller = thread::spawn(move || {
+                for _ in 0..5 {
+                    thread::sleep(Duration::from_millis(20));
+                    unsafe { libc::pthread_kill(waiter, libc::SIGUSR2) };
+                }
+            });
+            let timespec = types::Timespec::new().nsec(200_000_000);
+            let timeout = opcode::Timeout::new(&timespec).build();
+            assert_eq!(ring.submit(&[timeout]).unwrap(), vec![-libc::ETIME]);
+            signaller.join().unwrap();
+        }
+    }
+}
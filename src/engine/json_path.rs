@@ -0,0 +1,119 @@
+//! Paths into JSON values, for indexing and reading parts of them.
+use crate::{MyError, Result};
+use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+
+/// A path into a JSON document, such as `$.address.city` or `$.tags[0]`:
+/// `$` for the whole document, followed by object fields and array
+/// indices.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct JsonPath {
+    steps: Vec<Step>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Step {
+    Field(String),
+    Index(usize),
+}
+
+impl JsonPath {
+    /// The part of `doc` at this path, if it has one.
+    pub(crate) fn get<'a>(&self, doc: &'a Value) -> Option<&'a Value> {
+        self.steps.iter().try_fold(doc, |value, step| match step {
+            Step::Field(field) => value.get(field),
+            Step::Index(index) => value.get(index),
+        })
+    }
+
+    /// Puts `value` at this path in `doc`, adding the last field if the
+    /// object lacks it. Array elements must exist.
+    pub(crate) fn set(&self, doc: &mut Value, value: Value) -> Result<()> {
+        let (last, parents) = match self.steps.split_last() {
+            Some(split) => split,
+            None => {
+                *doc = value;
+                return Ok(());
+            }
+        };
+        let missing = || MyError::StringError(format!("No `{}` in the document", self));
+        let parent = parents
+            .iter()
+            .try_fold(doc, |value, step| match step {
+                Step::Field(field) => value.get_mut(field),
+                Step::Index(index) => value.get_mut(index),
+            })
+            .ok_or_else(missing)?;
+        match (last, parent) {
+            (Step::Field(field), Value::Object(object)) => {
+                object.insert(field.clone(), value);
+            }
+            (Step::Index(index), Value::Array(array)) if *index < array.len() => {
+                array[*index] = value;
+            }
+            _ => return Err(missing()),
+        }
+        Ok(())
+    }
+
+    /// The JSON text of the part of the JSON text `doc` at this path, if it
+    /// has one.
+    pub(crate) fn read(&self, doc: &str) -> Result<Option<String>> {
+        Ok(self.get(&parse(doc)?).map(Value::to_string))
+    }
+
+    /// The JSON text `doc` with the JSON text `value` at this path.
+    pub(crate) fn patch(&self, doc: &str, value: &str) -> Result<String> {
+        let value = serde_json::from_str(value)
+            .map_err(|err| MyError::StringError(format!("Invalid JSON value: {}", err)))?;
+        let mut doc = parse(doc)?;
+        self.set(&mut doc, value)?;
+        Ok(doc.to_string())
+    }
+}
+
+fn parse(doc: &str) -> Result<Value> {
+    serde_json::from_str(doc)
+        .map_err(|err| MyError::StringError(format!("The value is not JSON: {}", err)))
+}
+
+impl FromStr for JsonPath {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<JsonPath> {
+        let invalid = || MyError::StringError(format!("Invalid JSON path `{}`", s));
+        let mut rest = s.strip_prefix('$').ok_or_else(invalid)?;
+        let mut steps = Vec::new();
+        while !rest.is_empty() {
+            if let Some(field) = rest.strip_prefix('.') {
+                let end = field.find(['.', '[']).unwrap_or(field.len());
+                if end == 0 {
+                    return Err(invalid());
+                }
+                steps.push(Step::Field(field[..end].to_owned()));
+                rest = &field[end..];
+            } else if let Some(index) = rest.strip_prefix('[') {
+                let end = index.find(']').ok_or_else(invalid)?;
+                steps.push(Step::Index(index[..end].parse().map_err(|_| invalid())?));
+                rest = &index[end + 1..];
+            } else {
+                return Err(invalid());
+            }
+        }
+        Ok(JsonPath { steps })
+    }
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "$")?;
+        for step in &self.steps {
+            match step {
+                Step::Field(field) => write!(f, ".{}", field)?,
+                Step::Index(index) => write!(f, "[{}]", index)?,
+            }
+        }
+        Ok(())
+    }
+}
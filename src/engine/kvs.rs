@@ -1,18 +1,403 @@
 //! Simple in-memory key/value storee responds to command line arguments
-use crate::engine::KvsEngine;
+use crate::engine::cache::ValueCache;
+use crate::engine::frame::{decode_record, write_record, LogFormat, RecordReader};
+use crate::engine::fsck::{check_log, read_log};
+use crate::engine::io::{LogWriter, Ring};
+use crate::engine::sorted_set::SortedSet;
+use crate::engine::{
+    bucket_dir, copy_synced, in_compaction_window, trash_key, unix_millis, version_conflict,
+    CompactionLog, CompactionStats, CompactionTrigger, CompactionWindow, EngineStats,
+    EventListener, GroupCommit, KeyEvent, KeyMeta, KeyOp, KvsEngine, KvsReader, MetricsSink,
+    OpKind, OpMetrics, SizeReport, Throttle, WriteBatch, TRASH_PREFIX,
+};
+use crate::glob::Glob;
 use crate::{MyError, Result};
+use bytes::Bytes;
+use fs2::FileExt;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use serde_json;
-use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::{prelude::*, BufReader, BufWriter, SeekFrom, Write};
-use std::ops::Range;
-use std::path::PathBuf;
+use std::ops::{Bound, Range, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
-/// The size of the log file needed before compaction occurs
+/// Bytes of stale records needed before compaction occurs
 const COMPACT_BYTES: u64 = 1024;
 
+/// How long each write waits once stale records pass the soft stall limit.
+const STALL_DELAY: Duration = Duration::from_millis(1);
+
+/// The log, in the data directory.
+pub(crate) const LOG_FILE: &str = "log.json";
+
+/// Temporary file compaction writes the live records to, next to the log.
+const COMPACTION_FILE: &str = "compacted_log.json";
+
+/// Temporary file `KvStore::bulk_load` writes the log and the loaded
+/// records to, next to the log.
+const BULK_LOAD_FILE: &str = "bulk_load.json";
+
+/// Prefix and suffix of the value logs, next to the log; the generation of
+/// the value log goes in between.
+const VALUE_LOG_PREFIX: &str = "values.";
+const VALUE_LOG_SUFFIX: &str = ".log";
+
+/// File locked by the process that has the store open.
+const LOCK_FILE: &str = "LOCK";
+
+/// File created next to the log when the store is shut down cleanly.
+pub(crate) const CLEAN_SHUTDOWN_MARKER: &str = "clean-shutdown";
+
+/// The index saved next to the log on a clean shutdown, which the next
+/// open loads instead of reading the log.
+const INDEX_FILE: &str = "index.json";
+
+/// When writes are forced to stable storage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Hand writes to the OS and let it decide when to persist them. A
+    /// crash of the machine may lose the latest writes.
+    Never,
+    /// `fsync` the log after every write before acknowledging it.
+    Always,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<SyncPolicy> {
+        match s {
+            "never" => Ok(SyncPolicy::Never),
+            "always" => Ok(SyncPolicy::Always),
+            _ => Err(MyError::StringError(format!(
+                "Unknown sync policy `{}`, expected `never` or `always`",
+                s
+            ))),
+        }
+    }
+}
+
+/// Bytes of the buffers records go through, as many as `BufWriter` and
+/// `BufReader` hold by default.
+const DEFAULT_BUFFER_BYTES: usize = 8 << 10;
+
+/// Bounds of `WriteBuffer::Adaptive` when parsed from `adaptive`.
+const ADAPTIVE_BUFFER_BYTES: (usize, usize) = (4 << 10, 1 << 20);
+
+/// Size of the buffer records go through on their way to the log: every
+/// record is written to it, then handed to the OS in one write, or more
+/// if it does not fit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteBuffer {
+    /// Always that many bytes.
+    Fixed(usize),
+    /// From `min` to `max` bytes, following the writes: grown to hold the
+    /// records flushed at once when they do not fit, as for batches and
+    /// large values, and halved again after a run of flushes using a
+    /// quarter of it or less, as for small single writes.
+    Adaptive { min: usize, max: usize },
+}
+
+impl FromStr for WriteBuffer {
+    type Err = MyError;
+
+    /// `adaptive`, from 4 KiB to 1 MiB, or a number of bytes.
+    fn from_str(s: &str) -> Result<WriteBuffer> {
+        if s == "adaptive" {
+            let (min, max) = ADAPTIVE_BUFFER_BYTES;
+            return Ok(WriteBuffer::Adaptive { min, max });
+        }
+        match s.parse() {
+            Ok(bytes) if bytes > 0 => Ok(WriteBuffer::Fixed(bytes)),
+            _ => Err(MyError::StringError(format!(
+                "Unknown write buffer `{}`, expected `adaptive` or a number of bytes",
+                s
+            ))),
+        }
+    }
+}
+
+/// Tuning knobs of a `KvStore`.
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    compaction_threshold: u64,
+    sync_policy: SyncPolicy,
+    retained_versions: usize,
+    value_threshold: Option<u64>,
+    cache_capacity: u64,
+    max_key_bytes: Option<u64>,
+    max_value_bytes: Option<u64>,
+    max_disk_bytes: Option<u64>,
+    soft_stall_bytes: Option<u64>,
+    hard_stall_bytes: Option<u64>,
+    merge_operator: Option<MergeOperator>,
+    compaction_windows: Vec<CompactionWindow>,
+    compaction_rate: Option<u64>,
+    trash: Option<Duration>,
+    write_buffer: WriteBuffer,
+    read_buffer: usize,
+    log_format: LogFormat,
+    metrics: Option<Metrics>,
+    #[cfg(feature = "uring")]
+    io_uring: bool,
+}
+
+/// Combines the value of a key, if any, with a merge operand.
+type MergeFn = dyn Fn(&str, Option<&str>, &str) -> String + Send + Sync;
+
+#[derive(Clone)]
+struct MergeOperator(Arc<MergeFn>);
+
+impl fmt::Debug for MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MergeOperator")
+    }
+}
+
+#[derive(Clone)]
+struct Metrics(Arc<dyn MetricsSink>);
+
+impl Metrics {
+    /// Reports `op`, started at `started`, to the sink.
+    fn record(&self, op: OpKind, started: Instant, bytes: u64, ok: bool) {
+        self.0.record(&OpMetrics {
+            op,
+            latency: started.elapsed(),
+            bytes,
+            ok,
+        });
+    }
+
+    /// Reports the get of `key`, started at `started`.
+    fn record_get<V: AsRef<[u8]>>(&self, key: &str, value: &Result<Option<V>>, started: Instant) {
+        let value_len = match value {
+            Ok(Some(value)) => value.as_ref().len(),
+            _ => 0,
+        };
+        let bytes = (key.len() + value_len) as u64;
+        self.record(OpKind::Get, started, bytes, value.is_ok());
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MetricsSink")
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compaction_threshold: COMPACT_BYTES,
+            sync_policy: SyncPolicy::Never,
+            retained_versions: 0,
+            value_threshold: None,
+            cache_capacity: 0,
+            max_key_bytes: None,
+            max_value_bytes: None,
+            max_disk_bytes: None,
+            soft_stall_bytes: None,
+            hard_stall_bytes: None,
+            merge_operator: None,
+            compaction_windows: Vec::new(),
+            compaction_rate: None,
+            trash: None,
+            write_buffer: WriteBuffer::Fixed(DEFAULT_BUFFER_BYTES),
+            read_buffer: DEFAULT_BUFFER_BYTES,
+            log_format: LogFormat::Framed,
+            metrics: None,
+            #[cfg(feature = "uring")]
+            io_uring: false,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Compact once stale records (overwritten or removed values) take
+    /// more than `bytes`.
+    pub fn with_compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Only compact automatically within `windows`, e.g. at night; stale
+    /// records past the threshold then wait for the first write within one.
+    /// By default compaction runs whenever the threshold is passed.
+    pub fn with_compaction_windows(mut self, windows: Vec<CompactionWindow>) -> Self {
+        self.compaction_windows = windows;
+        self
+    }
+
+    /// Write at most `bytes` per second on average when compacting, so that
+    /// compaction leaves disk bandwidth to other work. Writes wait for the
+    /// compaction they set off meanwhile. By default it is not throttled.
+    pub fn with_compaction_rate(mut self, bytes: u64) -> Self {
+        self.compaction_rate = Some(bytes);
+        self
+    }
+
+    /// Append to the log and read records through io_uring, submitting
+    /// each write with its sync under `SyncPolicy::Always`, and the syncs
+    /// of a group commit at once. Falls back to std IO, with a warning,
+    /// where the kernel offers no io_uring.
+    #[cfg(feature = "uring")]
+    pub fn with_io_uring(mut self) -> Self {
+        self.io_uring = true;
+        self
+    }
+
+    /// Size the buffer records are written to the log through, 8 KiB by
+    /// default. A larger one takes batches and large values in fewer
+    /// writes; `WriteBuffer::Adaptive` sizes it after the writes. With
+    /// io_uring the buffer grows to the records flushed at once anyway.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the buffer could be empty, or if `min` is above `max`.
+    pub fn with_write_buffer(mut self, buffer: WriteBuffer) -> Self {
+        match buffer {
+            WriteBuffer::Fixed(bytes) => assert!(bytes > 0, "empty write buffer"),
+            WriteBuffer::Adaptive { min, max } => {
+                assert!(0 < min && min <= max, "write buffer bounds out of order")
+            }
+        }
+        self.write_buffer = buffer;
+        self
+    }
+
+    /// Read records and separated values through buffers of `bytes`, 8 KiB
+    /// by default. Every read of a value not cached fills the buffer from
+    /// where the value starts, so a buffer about the size of the values
+    /// read saves reading past them, and a larger one speeds up reading
+    /// neighbouring values.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is 0.
+    pub fn with_read_buffer(mut self, bytes: usize) -> Self {
+        assert!(bytes > 0, "empty read buffer");
+        self.read_buffer = bytes;
+        self
+    }
+
+    /// Write records in `format`. By default they are framed; logs are
+    /// read whatever their format, so a log written before framing is
+    /// framed by its next compaction, and `LogFormat::Legacy` keeps a log
+    /// readable by versions before framing.
+    pub fn with_log_format(mut self, format: LogFormat) -> Self {
+        self.log_format = format;
+        self
+    }
+
+    /// Report how long every get, set, remove and compaction took, and the
+    /// bytes it read or wrote, to `sink`; gets through readers included.
+    pub fn with_metrics_sink(mut self, sink: Arc<dyn MetricsSink>) -> Self {
+        self.metrics = Some(Metrics(sink));
+        self
+    }
+
+    /// Choose when writes are forced to disk.
+    pub fn with_sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Keep the `versions` latest previous versions of every key through
+    /// compaction, for `KvStore::get_history`. By default compaction
+    /// discards them all.
+    pub fn with_retained_versions(mut self, versions: usize) -> Self {
+        self.retained_versions = versions;
+        self
+    }
+
+    /// Store values of at least `bytes` in a value log of their own, the
+    /// log keeping only where they are. Compaction then only copies large
+    /// values once enough of them are stale. By default values stay in the
+    /// log.
+    pub fn with_value_threshold(mut self, bytes: u64) -> Self {
+        self.value_threshold = Some(bytes);
+        self
+    }
+
+    /// Keep up to `bytes` of recently read keys and values in memory, so
+    /// that reading them again does not touch the disk. By default nothing
+    /// is cached.
+    pub fn with_cache_capacity(mut self, bytes: u64) -> Self {
+        self.cache_capacity = bytes;
+        self
+    }
+
+    /// Refuse keys, hash fields included, longer than `bytes` with
+    /// `MyError::TooLarge`. By default keys are unbounded.
+    pub fn with_max_key_bytes(mut self, bytes: u64) -> Self {
+        self.max_key_bytes = Some(bytes);
+        self
+    }
+
+    /// Refuse values, list elements and set members included, longer than
+    /// `bytes` with `MyError::TooLarge`. By default values are unbounded.
+    pub fn with_max_value_bytes(mut self, bytes: u64) -> Self {
+        self.max_value_bytes = Some(bytes);
+        self
+    }
+
+    /// Refuse writes adding data with `MyError::QuotaExceeded` once the log
+    /// and the value log take `bytes` or more, and compacting them does not
+    /// bring them back under. Removals still go through, and reads are
+    /// unaffected. By default the store grows as it likes.
+    pub fn with_max_disk_bytes(mut self, bytes: u64) -> Self {
+        self.max_disk_bytes = Some(bytes);
+        self
+    }
+
+    /// Slow writes down once stale records take `bytes` or more, which
+    /// happens when compaction waits for a window: each write then sleeps
+    /// a millisecond, and `EngineStats::write_stalled` is set. By default
+    /// writes never wait for compaction debt.
+    pub fn with_soft_stall_bytes(mut self, bytes: u64) -> Self {
+        self.soft_stall_bytes = Some(bytes);
+        self
+    }
+
+    /// Compact at the write that brings stale records to `bytes` or more,
+    /// even outside the compaction windows, so that the log stops growing
+    /// with garbage. Writes wait for that compaction.
+    pub fn with_hard_stall_bytes(mut self, bytes: u64) -> Self {
+        self.hard_stall_bytes = Some(bytes);
+        self
+    }
+
+    /// Let `KvStore::merge` append operands to values, which `operator`
+    /// combines with the value of their key, given the key, its value if
+    /// it has one and the operand, when the key is read or the log
+    /// compacted. A store whose log holds operands fails to read their
+    /// keys without one.
+    pub fn with_merge_operator<F>(mut self, operator: F) -> Self
+    where
+        F: Fn(&str, Option<&str>, &str) -> String + Send + Sync + 'static,
+    {
+        self.merge_operator = Some(MergeOperator(Arc::new(operator)));
+        self
+    }
+
+    /// Keep the strings `remove` removes in the trash for `retention`,
+    /// under `TRASH_PREFIX`, so that `restore` can bring them back until
+    /// they expire from it or `purge` empties it. Lists, hashes and sets
+    /// are removed for good, as are the keys of the trash itself. By
+    /// default removals are final.
+    pub fn with_trash(mut self, retention: Duration) -> Self {
+        self.trash = Some(retention);
+        self
+    }
+}
+
 /// The `KvStore` stores string key/value pairs.
 ///
 /// Key/value pairs are stored in a `HashMap` in memory and not persisted to disk.
@@ -25,7 +410,7 @@ const COMPACT_BYTES: u64 = 1024;
 /// # use std::env::current_dir;
 /// # fn try_main() -> Result<()> {
 ///
-/// let mut store = KvStore::new()?;
+/// let mut store = KvStore::open(current_dir()?)?;
 /// store.set("key".to_owned(), "value".to_owned());
 /// let val = store.get("key".to_owned())?;
 /// assert_eq!(val, Some("value".to_owned()));
@@ -34,200 +419,2890 @@ const COMPACT_BYTES: u64 = 1024;
 /// # }
 /// ```
 pub struct KvStore {
-    writer: BufWriter<File>,
-    reader: BufReader<File>,
-    index: BTreeMap<String, Pointer>,
+    writer: LogWriter,
+    /// The io_uring the log is written and read through, if any.
+    ring: Option<Arc<Ring>>,
+    /// The log and index values are read from.
+    view: Arc<View>,
+    /// Where `KvReader`s find the current view.
+    current: Arc<RwLock<Arc<View>>>,
+    /// Recently read values, shared with the readers.
+    cache: Option<Arc<ValueCache>>,
+    /// Previous versions of each key still in the log, oldest first.
+    history: HashMap<String, Vec<Pointer>>,
+    /// The pushes of the values of each list, front to back.
+    lists: HashMap<String, VecDeque<Pointer>>,
+    /// The sets of the fields of each hash, by field.
+    hashes: HashMap<String, BTreeMap<String, Pointer>>,
+    /// The additions of the members of each set, by member.
+    sets: HashMap<String, BTreeMap<String, Pointer>>,
+    /// The last scores of the members of each sorted set.
+    sorted_sets: HashMap<String, SortedSet<Pointer>>,
+    /// Sequence number of the next write.
+    next_seq: u64,
+    /// Sequence number of the last write when the log was last compacted,
+    /// 0 if it never was: the writes up to it may be gone from the log.
+    compacted_seq: u64,
     path: PathBuf,
     uncompacted: u64,
+    /// The value log separated values are appended to, if there is one.
+    values: Option<File>,
+    /// Generation of the value log, bumped whenever compaction rewrites it.
+    value_gen: u64,
+    value_log_len: u64,
+    /// Bytes of stale values in the value log.
+    value_garbage: u64,
+    /// Last key the previous sweep for expired keys looked at, `None` to
+    /// start over.
+    sweep_cursor: Option<String>,
+    /// Whether the clean-shutdown marker is on disk.
+    clean: bool,
+    last_compaction: Option<SystemTime>,
+    compactions: CompactionLog,
+    /// Writes slowed down by the soft stall limit.
+    stalled_writes: u64,
+    options: KvStoreOptions,
+    /// Syncs writes for their callers once `defer_syncs` was called.
+    commit: Option<Arc<GroupCommit>>,
+    listeners: Vec<Arc<dyn EventListener>>,
+    hooks: Hooks,
+    /// Holds the lock on the data directory until the store is dropped.
+    _lock: File,
+}
+
+type WriteHook = dyn Fn(&str, &str) + Send + Sync;
+type RemoveHook = dyn Fn(&str) + Send + Sync;
+
+/// Callbacks of the application embedding the store, see
+/// `KvStore::on_write` and `KvStore::on_remove`.
+#[derive(Default)]
+struct Hooks {
+    on_write: Vec<Box<WriteHook>>,
+    on_remove: Vec<Box<RemoveHook>>,
+}
+
+impl Hooks {
+    fn is_empty(&self) -> bool {
+        self.on_write.is_empty() && self.on_remove.is_empty()
+    }
 }
 
 impl KvsEngine for KvStore {
+    type Reader = KvReader;
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        let command = Command::set(key.clone(), value.clone());
-        let initial_offset = self.writer.seek(SeekFrom::End(0))?;
-        self.writer.write_all(b"\r\n")?;
-        serde_json::to_writer(&mut self.writer, &command)?;
-        self.writer.flush()?;
-        let new_offset = self.writer.seek(SeekFrom::End(0))?;
-        if let Some(pointer) = self
-            .index
-            .insert(key.clone(), (initial_offset..new_offset).into())
-        {
-            self.uncompacted += pointer.len;
-            //println!("Uncompacted {:?}", self.uncompacted);
-        }
-        if new_offset > COMPACT_BYTES {
-            self.compact()?;
-        }
+        let bytes = (key.len() + value.len()) as u64;
+        self.measured(OpKind::Set, bytes, |store| {
+            store.check_size(&key, &value)?;
+            store.check_disk()?;
+            store.write_set(key, value, None)
+        })
+    }
 
-        Ok(())
+    /// The key expires once `ttl` elapses: reads miss it from then on,
+    /// and `sweep_expired` removes it.
+    fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let bytes = (key.len() + value.len()) as u64;
+        self.measured(OpKind::Set, bytes, |store| {
+            store.check_size(&key, &value)?;
+            store.check_disk()?;
+            let expires = unix_millis().saturating_add(ttl.as_millis() as u64);
+            store.write_set(key, value, Some(expires))
+        })
     }
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
     fn get(&mut self, key: String) -> Result<Option<String>> {
-        self.reader.seek(SeekFrom::Start(0))?;
-        if let Some(pointer) = self.index.get(&key) {
-            self.reader.seek(SeekFrom::Start(pointer.pos))?;
-            let cmd_reader = (&mut self.reader).take(pointer.len);
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                Ok(Some(value))
-            } else {
-                Err(MyError::KeyNotFound)
-            }
-        } else {
-            Ok(None)
+        let started = Instant::now();
+        let value = self.read(&key);
+        if let Some(metrics) = &self.options.metrics {
+            metrics.record_get(&key, &value, started);
         }
+        value
     }
 
-    /// Remove a given key.
-    fn remove(&mut self, key: String) -> Result<()> {
-        self.writer.seek(SeekFrom::End(0))?;
-        let command = Command::remove(key.clone());
-        match self.index.remove(&key) {
-            Some(_x) => {
-                serde_json::to_writer(&mut self.writer, &command)?;
-                self.writer.write_all(b"\r\n")?;
-                self.writer.flush()?;
-                return Ok(());
-            }
-            None => return Err(MyError::KeyNotFound),
-        }
+    /// Answered from the index, without reading the log.
+    fn contains_key(&mut self, key: String) -> Result<bool> {
+        Ok(self.kind_of(&key).is_some())
     }
-}
 
-impl KvStore {
-    /// Creates a `KvStore`.
-    pub fn new() -> Result<Self> {
-        let cwd = std::env::current_dir()?;
-        KvStore::open(cwd.as_path())
+    /// Answered from the index, without reading the log.
+    fn len(&mut self) -> Result<u64> {
+        let now = unix_millis();
+        let strings = self
+            .view
+            .index
+            .read()
+            .unwrap()
+            .values()
+            .filter(|pointer| !pointer.is_expired(now))
+            .count();
+        let collections =
+            self.lists.len() + self.hashes.len() + self.sets.len() + self.sorted_sets.len();
+        Ok((strings + collections) as u64)
     }
 
-    /// Open the KvStore at a given path. Return the KvStore.
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let mut path = path.into();
-        std::fs::create_dir_all(&path)?;
+    /// Answered from the index, without reading the log; lists, hashes
+    /// and sets included.
+    fn keys(&mut self, pattern: String) -> Result<Vec<String>> {
+        let glob: Glob = pattern.parse()?;
+        let now = unix_millis();
+        let mut keys: Vec<String> = self
+            .view
+            .index
+            .read()
+            .unwrap()
+            .range(glob.prefix().to_owned()..)
+            .take_while(|(key, _)| key.starts_with(glob.prefix()))
+            .filter(|(key, pointer)| !pointer.is_expired(now) && glob.matches(key))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let collections = self
+            .lists
+            .keys()
+            .chain(self.hashes.keys())
+            .chain(self.sets.keys())
+            .chain(self.sorted_sets.keys());
+        keys.extend(collections.filter(|key| glob.matches(key)).cloned());
+        keys.sort_unstable();
+        Ok(keys)
+    }
 
-        path.push("log");
-        path.set_extension("json");
+    /// Answered from the index, without reading the log; lists, hashes
+    /// and sets included.
+    fn keys_after(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        count: usize,
+    ) -> Result<Vec<String>> {
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix.clone()),
+        };
+        let in_range = |key: &&String| {
+            key.starts_with(&prefix)
+                && match &start {
+                    Bound::Excluded(after) => *key > after,
+                    _ => true,
+                }
+        };
+        let now = unix_millis();
+        let mut keys: Vec<String> = self
+            .view
+            .index
+            .read()
+            .unwrap()
+            .range((start.clone(), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter(|(_, pointer)| !pointer.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .take(count)
+            .collect();
+        let collections = self
+            .lists
+            .keys()
+            .chain(self.hashes.keys())
+            .chain(self.sets.keys())
+            .chain(self.sorted_sets.keys());
+        keys.extend(collections.filter(in_range).cloned());
+        keys.sort_unstable();
+        keys.truncate(count);
+        Ok(keys)
+    }
 
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(false)
-            .open(&path)?;
+    /// Lists, hashes and sets never expire.
+    fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        let now = unix_millis();
+        match self.view.live(&key, now) {
+            Some(pointer) => Ok(pointer
+                .expires
+                .map(|expires| Duration::from_millis(expires - now))),
+            None if self.kind_of(&key).is_some() => Ok(None),
+            None => Err(MyError::KeyNotFound),
+        }
+    }
 
-        let mut kv = KvStore {
-            writer: BufWriter::new(file),
-            reader: BufReader::new(OpenOptions::new().read(true).open(&path)?),
-            index: BTreeMap::new(),
-            path,
-            uncompacted: 0,
+    /// The version of a string is the sequence number of its last write,
+    /// merges included. Lists, hashes and sets cannot be set.
+    fn set_if_version(&mut self, key: String, version: u64, value: String) -> Result<u64> {
+        let current = match self.view.live(&key, unix_millis()) {
+            Some(pointer) => pointer.last_seq(),
+            None => {
+                self.claim(&key, "string")?;
+                0
+            }
         };
+        if current != version {
+            return Err(version_conflict(&key, version, current));
+        }
+        self.set(key, value)?;
+        Ok(self.next_seq - 1)
+    }
 
-        kv.read_file()?;
-        Ok(kv)
+    /// Lists, hashes and sets carry no timestamps nor versions.
+    fn metadata(&mut self, key: String) -> Result<KeyMeta> {
+        match self.view.live(&key, unix_millis()) {
+            Some(pointer) => Ok(KeyMeta::from_millis(
+                pointer.created.or(pointer.updated),
+                pointer.last_updated(),
+                pointer.last_seq(),
+            )),
+            None if self.kind_of(&key).is_some() => Ok(KeyMeta::default()),
+            None => Err(MyError::KeyNotFound),
+        }
     }
 
-    /// Read file and load history of command from the log
-    fn read_file(&mut self) -> Result<()> {
-        let mut buf_reader = BufReader::new(OpenOptions::new().read(true).open(&self.path)?);
-        let mut initial_offset = buf_reader.seek(SeekFrom::Start(0))?;
+    /// Writes the value again with its new expiry.
+    fn expire(&mut self, key: String, ttl: Duration) -> Result<()> {
+        let value = self.read(&key)?.ok_or(MyError::KeyNotFound)?;
+        let expires = unix_millis().saturating_add(ttl.as_millis() as u64);
+        self.write_set(key, value, Some(expires))
+    }
 
-        let mut stream = serde_json::Deserializer::from_reader(buf_reader).into_iter::<Command>();
+    /// Appends one record with the new expiry of the keys, which leaves
+    /// their values where they are; compaction folds it into them.
+    fn touch(&mut self, keys: Vec<String>, ttl: Duration) -> Result<u64> {
+        let now = unix_millis();
+        let mut keys: Vec<String> = keys
+            .into_iter()
+            .filter(|key| self.view.live(key, now).is_some())
+            .collect();
+        keys.sort();
+        keys.dedup();
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let expires = now.saturating_add(ttl.as_millis() as u64);
+        let pointer = self.append(Command::Touch {
+            keys: keys.clone(),
+            expires,
+        })?;
+        // the record is garbage once compaction rewrote the values
+        self.uncompacted += pointer.len;
+        let touched = self.record_touch(&keys, expires, pointer.updated.unwrap_or(now));
+        self.maybe_compact()?;
+        Ok(touched)
+    }
 
-        while let Some(command) = stream.next() {
-            let new_offset = stream.byte_offset() as u64;
-            match command? {
-                Command::Set { key, .. } => {
-                    if let Some(pointer) = self
-                        .index
-                        .insert(key.to_string(), (initial_offset..new_offset).into())
-                    {
-                        self.uncompacted += pointer.len;
-                    }
-                }
-                Command::Remove { key } => {
-                    if let Some(_pointer) = self.index.remove(key.as_str()) {
-                        // the "remove" command itself can be deleted in the next compaction.
-                        // so we add its length to `uncompacted`.
-                        self.uncompacted += new_offset - initial_offset;
+    fn persist(&mut self, key: String) -> Result<()> {
+        match self.view.live(&key, unix_millis()) {
+            Some(pointer) if pointer.expires.is_some() => {
+                let value = self.view.read_value(&pointer)?;
+                self.write_set(key, value, None)
+            }
+            Some(_) => Ok(()),
+            None if self.kind_of(&key).is_some() => Ok(()),
+            None => Err(MyError::KeyNotFound),
+        }
+    }
+
+    /// Remove a given key, whatever it holds. With a trash, a string is
+    /// first copied to it, so that a crash in between leaves both keys
+    /// rather than neither.
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.measured(OpKind::Remove, key.len() as u64, |store| {
+            if store.kind_of(&key).is_none() {
+                return Err(MyError::KeyNotFound);
+            }
+            if let Some(retention) = store.options.trash {
+                if !key.starts_with(TRASH_PREFIX) {
+                    if let Some(value) = store.read(&key)? {
+                        let expires = unix_millis().saturating_add(retention.as_millis() as u64);
+                        store.write_set(trash_key(&key), value, Some(expires))?;
                     }
                 }
+            }
+            store.write_remove(key)
+        })
+    }
+
+    /// Sets the key again before removing it from the trash, so that a
+    /// crash in between leaves both keys rather than neither.
+    fn restore(&mut self, key: String) -> Result<()> {
+        let trashed = trash_key(&key);
+        let value = self.read(&trashed)?.ok_or(MyError::KeyNotFound)?;
+        if self.kind_of(&key).is_some() {
+            return Err(MyError::Conflict(format!(
+                "`{}` was set again since it was removed",
+                key
+            )));
+        }
+        self.write_set(key, value, None)?;
+        self.write_remove(trashed)
+    }
+
+    /// Removes every key of the trash in one batch.
+    fn purge(&mut self) -> Result<u64> {
+        let keys = self.keys_after(TRASH_PREFIX.to_owned(), None, usize::MAX)?;
+        let mut batch = WriteBatch::new();
+        for key in &keys {
+            batch.remove(key.clone());
+        }
+        self.write_batch(batch)?;
+        Ok(keys.len() as u64)
+    }
+
+    /// Writes the whole batch as one log record, so that a crash leaves
+    /// either all of it or, once the torn record is truncated, none of it.
+    ///
+    /// Values of batches stay in the log, whatever their size.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        for command in &batch.commands {
+            if let Command::Set { key, value } = command {
+                self.check_size(key, value)?;
+            }
+        }
+        if batch
+            .commands
+            .iter()
+            .any(|command| matches!(command, Command::Set { .. }))
+        {
+            self.check_disk()?;
+        }
+        batch.check_removes(|key| Ok(self.kind_of(key).is_some()))?;
+        self.mark_dirty()?;
+        let last_seq = self.next_seq + batch.len() as u64 - 1;
+        let record = Record::new(last_seq, Command::Batch(batch.commands))?;
+        let initial_offset = self.writer.end()?;
+        write_record(&mut self.writer, &record, self.options.log_format)?;
+        self.flush_writes()?;
+        let new_offset = self.writer.end()?;
+        self.next_seq = last_seq + 1;
+        if let Command::Batch(commands) = record.command {
+            let first_seq = last_seq + 1 - commands.len() as u64;
+            for (seq, command) in (first_seq..).zip(&commands) {
+                self.notify(command, seq);
+            }
+            let hooked: Vec<String> = match self.hooks.is_empty() {
+                true => Vec::new(),
+                false => commands.iter().map(|c| c.key().to_owned()).collect(),
             };
-            initial_offset = new_offset;
+            self.index_batch(
+                commands,
+                initial_offset..new_offset,
+                last_seq,
+                record.written,
+            )?;
+            self.run_hooks(&hooked)?;
         }
-        //println!("Uncompacted {:?}", self.uncompacted);
+        self.maybe_compact()?;
         Ok(())
     }
 
-    /// Compact file when when the size exceeds the configured one. Compact == remove remove the entries for identical keys
-    fn compact(&mut self) -> Result<()> {
-        let mut path = std::env::current_dir()?;
-        path.push("compacted_log");
-        path.set_extension("json");
+    /// Returns every key/value pair whose key starts with `prefix`.
+    fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.view.scan(&prefix)
+    }
 
-        let temp_file = OpenOptions::new().write(true).create(true).open(&path)?;
+    /// Replaces the log by one holding a single empty batch, which takes
+    /// the next sequence number so that those of the writes cleared are not
+    /// handed out again, and the value log by an empty one. Listeners are
+    /// told of the removal of every key, and hooks of every string key.
+    ///
+    /// Readers keep using the old view meanwhile, as during compaction.
+    fn clear(&mut self) -> Result<()> {
+        self.mark_dirty()?;
+        self.writer.flush()?;
+        let seq = self.next_seq;
+        let temp_path = self.path.with_file_name(COMPACTION_FILE);
+        let mut temp_file = File::create(&temp_path)?;
+        write_record(
+            &mut temp_file,
+            &Record::new(seq, Command::Batch(Vec::new()))?,
+            self.options.log_format,
+        )?;
+        temp_file.sync_all()?;
+        drop(temp_file);
+        let keys: Vec<String> = self.view.index.read().unwrap().keys().cloned().collect();
 
-        let mut writer_temp_file = BufWriter::new(temp_file);
-        self.reader.seek(SeekFrom::Start(0))?;
-        for (_key, pointer) in &mut self.index {
-            self.reader.seek(SeekFrom::Start(pointer.pos))?;
-            let mut cmd_reader = (&mut self.reader).take(pointer.len);
-            let _len = std::io::copy(&mut cmd_reader, &mut writer_temp_file)?;
+        std::fs::rename(&temp_path, &self.path)?;
+        self.writer = self.writer.reopen(&self.path)?;
+        if self.values.is_some() {
+            std::fs::remove_file(self.value_log_path(self.value_gen))?;
+            self.value_gen += 1;
+            let path = self.value_log_path(self.value_gen);
+            self.values = Some(OpenOptions::new().append(true).create(true).open(path)?);
+            self.value_log_len = 0;
+            self.value_garbage = 0;
         }
-        writer_temp_file.flush()?;
+        if let Some(commit) = &self.commit {
+            commit.replace_files(self.synced_files()?);
+        }
+        self.next_seq += 1;
+        self.compacted_seq = seq;
+        self.uncompacted = 0;
+        self.sweep_cursor = None;
+        self.history.clear();
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+        self.switch_view(BTreeMap::new())?;
 
-        //self.reader = BufReader::new(OpenOptions::new().read(true).open(&path)?);
-        //self.writer = writer_temp_file;
+        let collections = self
+            .lists
+            .drain()
+            .map(|(key, _)| key)
+            .chain(self.hashes.drain().map(|(key, _)| key))
+            .chain(self.sets.drain().map(|(key, _)| key))
+            .chain(self.sorted_sets.drain().map(|(key, _)| key))
+            .collect::<Vec<_>>();
+        for key in keys.iter().chain(&collections) {
+            self.notify(&Command::remove(key.clone()), seq);
+        }
+        self.run_hooks(&keys)
+    }
 
-        std::fs::remove_file(&self.path)?;
-        std::fs::rename(&path, &self.path)?;
-        self.uncompacted = 0;
-        //self.path = path;
+    /// Compaction rewrites the log without events.
+    fn add_listener(&mut self, listener: Arc<dyn EventListener>) -> Result<()> {
+        self.listeners.push(listener);
         Ok(())
     }
-}
 
-/// Command is an enum with each possible command of the database. Each enum
-/// command will be serialized to a log file and used as the basis for populating/
-/// updating an in-memory key/value store.
-#[derive(Serialize, Deserialize, Debug)]
-pub enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
-}
+    /// Lists live in the log as a record per push and per pop. Setting or
+    /// removing the key drops the whole list.
+    fn lpush(&mut self, key: String, value: String) -> Result<u64> {
+        self.push(key, value, true)
+    }
 
-impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    fn rpush(&mut self, key: String, value: String) -> Result<u64> {
+        self.push(key, value, false)
     }
 
-    // fn get(key: String) -> Command {
-    //     Command::Get { key }
-    // }
+    fn lpop(&mut self, key: String) -> Result<Option<String>> {
+        let item = match self.lists.get(&key).and_then(VecDeque::front) {
+            Some(item) => item.clone(),
+            None => return Ok(None),
+        };
+        let value = self.view.read_element(&item)?;
+        let pointer = self.append(Command::Pop {
+            key: key.clone(),
+            front: true,
+        })?;
+        self.record_pop(&key, pointer, true);
+        self.maybe_compact()?;
+        Ok(Some(value))
+    }
 
-    fn remove(key: String) -> Command {
-        Command::Remove { key }
+    fn lrange(&mut self, key: String, start: i64, stop: i64) -> Result<Vec<String>> {
+        let list = match self.lists.get(&key) {
+            Some(list) => list,
+            None => return Ok(Vec::new()),
+        };
+        let len = list.len() as i64;
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            len + stop
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop {
+            return Ok(Vec::new());
+        }
+        list.range(start as usize..=stop as usize)
+            .map(|item| self.view.read_element(item))
+            .collect()
     }
-}
 
-/// Represents the position and length of a json-serialized command in the log.
-#[derive(Clone, Debug)]
-struct Pointer {
-    pos: u64,
-    len: u64,
-}
+    /// Each field set or removed is a record of the log, like pushes to
+    /// lists.
+    fn hset(&mut self, key: String, field: String, value: String) -> Result<()> {
+        self.check_size(&key, &value)?;
+        self.check_size(&field, "")?;
+        self.check_disk()?;
+        self.claim(&key, "hash")?;
+        let pointer = self.append(Command::HSet {
+            key: key.clone(),
+            field: field.clone(),
+            value,
+        })?;
+        self.record_hset(key, field, pointer);
+        self.maybe_compact()?;
+        Ok(())
+    }
 
-impl From<Range<u64>> for Pointer {
-    fn from(range: Range<u64>) -> Self {
-        Pointer {
-            pos: range.start,
-            len: range.end - range.start,
+    fn hget(&mut self, key: String, field: String) -> Result<Option<String>> {
+        match self.hashes.get(&key).and_then(|hash| hash.get(&field)) {
+            Some(pointer) => self.view.read_element(pointer).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn hdel(&mut self, key: String, field: String) -> Result<()> {
+        if !self
+            .hashes
+            .get(&key)
+            .is_some_and(|hash| hash.contains_key(&field))
+        {
+            return Err(MyError::KeyNotFound);
+        }
+        let pointer = self.append(Command::HDel {
+            key: key.clone(),
+            field: field.clone(),
+        })?;
+        self.record_hdel(&key, &field, pointer);
+        self.maybe_compact()?;
+        Ok(())
+    }
+
+    fn hgetall(&mut self, key: String) -> Result<Vec<(String, String)>> {
+        self.hashes
+            .get(&key)
+            .into_iter()
+            .flatten()
+            .map(|(field, pointer)| Ok((field.clone(), self.view.read_element(pointer)?)))
+            .collect()
+    }
+
+    /// Only additions of new members and removals of members are logged.
+    fn sadd(&mut self, key: String, member: String) -> Result<bool> {
+        self.check_size(&key, &member)?;
+        self.check_disk()?;
+        self.claim(&key, "set")?;
+        if self
+            .sets
+            .get(&key)
+            .is_some_and(|set| set.contains_key(&member))
+        {
+            return Ok(false);
+        }
+        let pointer = self.append(Command::SAdd {
+            key: key.clone(),
+            member: member.clone(),
+        })?;
+        self.record_sadd(key, member, pointer);
+        self.maybe_compact()?;
+        Ok(true)
+    }
+
+    fn srem(&mut self, key: String, member: String) -> Result<bool> {
+        if !self.sismember(key.clone(), member.clone())? {
+            return Ok(false);
+        }
+        let pointer = self.append(Command::SRem {
+            key: key.clone(),
+            member: member.clone(),
+        })?;
+        self.record_srem(&key, &member, pointer);
+        self.maybe_compact()?;
+        Ok(true)
+    }
+
+    fn sismember(&mut self, key: String, member: String) -> Result<bool> {
+        Ok(self
+            .sets
+            .get(&key)
+            .is_some_and(|set| set.contains_key(&member)))
+    }
+
+    /// Only new scores are logged.
+    fn zadd(&mut self, key: String, member: String, score: f64) -> Result<bool> {
+        if !score.is_finite() {
+            return Err(MyError::StringError(format!(
+                "Scores must be finite, not {}",
+                score
+            )));
+        }
+        self.check_size(&key, &member)?;
+        self.check_disk()?;
+        self.claim(&key, "sorted set")?;
+        let previous = self
+            .sorted_sets
+            .get(&key)
+            .and_then(|set| set.score(&member));
+        if previous == Some(score) {
+            return Ok(false);
+        }
+        let pointer = self.append(Command::ZAdd {
+            key: key.clone(),
+            member: member.clone(),
+            score,
+        })?;
+        self.record_zadd(key, member, score, pointer);
+        self.maybe_compact()?;
+        Ok(previous.is_none())
+    }
+
+    fn zrange_by_score(&mut self, key: String, min: f64, max: f64) -> Result<Vec<(String, f64)>> {
+        Ok(self
+            .sorted_sets
+            .get(&key)
+            .into_iter()
+            .flat_map(|set| set.range(min, max))
+            .map(|(member, score)| (member.to_owned(), score))
+            .collect())
+    }
+
+    fn smembers(&mut self, key: String) -> Result<Vec<String>> {
+        Ok(self
+            .sets
+            .get(&key)
+            .into_iter()
+            .flat_map(|set| set.keys().cloned())
+            .collect())
+    }
+
+    /// Walks the index on from where the previous sweep stopped, wrapping
+    /// around at the end, and appends a tombstone for each expired key.
+    ///
+    /// The stale records count toward the compaction threshold like those
+    /// of any other removal.
+    fn sweep_expired(&mut self, limit: usize) -> Result<Vec<String>> {
+        let now = unix_millis();
+        let view = Arc::clone(&self.view);
+        let index = view.index.read().unwrap();
+        let start = match self.sweep_cursor.take() {
+            Some(key) => Bound::Excluded(key),
+            None => Bound::Unbounded,
+        };
+        let walked: Vec<(&String, &Pointer)> =
+            index.range((start, Bound::Unbounded)).take(limit).collect();
+        if walked.len() == limit {
+            self.sweep_cursor = walked.last().map(|(key, _)| (*key).clone());
+        }
+        let expired: Vec<String> = walked
+            .into_iter()
+            .filter(|(_, pointer)| pointer.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        drop(index);
+        for key in &expired {
+            self.write_remove(key.clone())?;
+        }
+        Ok(expired)
+    }
+
+    fn open_bucket(&self, name: &str) -> Result<KvStore> {
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        KvStore::open_with_options(bucket_dir(dir, name)?, self.options.clone())
+    }
+
+    fn reader(&self) -> KvReader {
+        KvReader {
+            current: Arc::clone(&self.current),
+            cache: self.cache.clone(),
+            metrics: self.options.metrics.clone(),
         }
     }
+
+    fn name(&self) -> &'static str {
+        "kvs"
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        let key_count = self.len()?;
+        Ok(self.compactions.report(EngineStats {
+            key_count,
+            disk_usage: std::fs::metadata(&self.path)?.len() + self.value_log_len,
+            uncompacted_bytes: self.uncompacted,
+            segment_count: 1,
+            last_compaction: self.last_compaction,
+            cache_hits: self.cache.as_ref().map_or(0, |cache| cache.hits()),
+            cache_misses: self.cache.as_ref().map_or(0, |cache| cache.misses()),
+            write_stalled: self.past(self.options.soft_stall_bytes),
+            stalled_writes: self.stalled_writes,
+            write_buffer_bytes: self.writer.capacity() as u64,
+            ..EngineStats::default()
+        }))
+    }
+
+    /// The store compacts at its next write if already past `bytes`;
+    /// buckets opened later get the new threshold too.
+    fn set_compaction_threshold(&mut self, bytes: u64) -> Result<()> {
+        self.options.compaction_threshold = bytes;
+        Ok(())
+    }
+
+    fn compact_now(&mut self) -> Result<()> {
+        self.compact(CompactionTrigger::Requested)
+    }
+
+    /// Only stores syncing every write, by `SyncPolicy::Always`, have
+    /// syncs to defer.
+    fn defer_syncs(&mut self) -> Result<Option<Arc<GroupCommit>>> {
+        if self.options.sync_policy != SyncPolicy::Always {
+            return Ok(None);
+        }
+        if self.commit.is_none() {
+            let files = self.synced_files()?;
+            self.commit = Some(Arc::new(GroupCommit::new(files, self.ring.clone())));
+        }
+        Ok(self.commit.clone())
+    }
+
+    /// Copies the log, and the value log if there is one; no write comes
+    /// in between, the store being borrowed mutably.
+    fn snapshot(&mut self, dir: &Path) -> Result<()> {
+        self.writer.flush()?;
+        std::fs::create_dir_all(dir).map_err(MyError::file(dir))?;
+        copy_synced(&self.path, &dir.join(LOG_FILE))?;
+        if self.values.is_some() {
+            let path = self.value_log_path(self.value_gen);
+            copy_synced(&path, &dir.join(path.file_name().unwrap()))?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the log to disk, saves the index and the keys of the cached
+    /// values next to it, and leaves a clean-shutdown marker, so the next
+    /// `open` can trust the log without verifying it, and load the index
+    /// instead of reading the log.
+    fn shutdown(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if let Some(values) = &self.values {
+            values.sync_all()?;
+        }
+        self.writer.get_ref().sync_all()?;
+        self.save_index()?;
+        File::create(self.marker_path())?.sync_all()?;
+        self.clean = true;
+        Ok(())
+    }
+}
+
+impl KvStore {
+    /// Creates a `KvStore` in the current directory.
+    #[deprecated(note = "open the store in an explicit directory with `KvStore::open`")]
+    pub fn new() -> Result<Self> {
+        let cwd = std::env::current_dir()?;
+        KvStore::open(cwd.as_path())
+    }
+
+    /// Open the KvStore at a given path. Return the KvStore.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Open the store of bucket `name` under the data directory `path`,
+    /// which has a log directory of its own.
+    pub fn open_bucket(path: impl Into<PathBuf>, name: &str) -> Result<KvStore> {
+        KvStore::open(bucket_dir(&path.into(), name)?)
+    }
+
+    /// Open the KvStore at a given path, tuned by `options`, then check it
+    /// through before handing it out: the log as `fsck` does, and the
+    /// index rebuilt from it, by reading every write it points to back
+    /// from the log and every separated value from the value log against
+    /// its checksum.
+    ///
+    /// This reads the whole store, so it takes about as long as copying
+    /// it; meant for restarts after a crash or a disk failure.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::Corrupt` if anything is amiss, after logging
+    /// each problem, rather than serve the damaged store.
+    pub fn open_verified(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<(KvStore, VerifyReport)> {
+        let started = Instant::now();
+        let store = KvStore::open_store(path.into(), options, false)?;
+        // a torn last record is gone by now, truncated on open
+        let data = std::fs::read(&store.path)?;
+        let check = check_log(&data, &read_log(&data))?;
+        let mut report = VerifyReport {
+            records: check.records,
+            problems: check.problems,
+            ..VerifyReport::default()
+        };
+        store.verify_index(&mut report);
+        report.elapsed = started.elapsed();
+        if !report.problems.is_empty() {
+            for problem in &report.problems {
+                error!("{}: {}", store.path.display(), problem);
+            }
+            return Err(MyError::Corrupt {
+                path: Some(store.path.clone()),
+                offset: None,
+                reason: format!(
+                    "{} problems found on open, the first: {}",
+                    report.problems.len(),
+                    report.problems[0]
+                ),
+            });
+        }
+        Ok((store, report))
+    }
+
+    /// Open the KvStore at a given path, tuned by `options`.
+    ///
+    /// After a clean shutdown, the index saved by `shutdown` is loaded
+    /// instead of reading the log, provided the log and the value log have
+    /// the length they had then, and the cache is filled again with the
+    /// values it held.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        KvStore::open_store(path.into(), options, true)
+    }
+
+    /// Opens the store, loading the saved index if `load_index` and there
+    /// is a valid one, rebuilding it from the log otherwise.
+    fn open_store(mut path: PathBuf, options: KvStoreOptions, load_index: bool) -> Result<KvStore> {
+        std::fs::create_dir_all(&path)?;
+        let lock = lock_dir(&path)?;
+
+        path.push(LOG_FILE);
+
+        #[cfg(feature = "uring")]
+        let ring = match options.io_uring {
+            true => crate::engine::io::open_ring(),
+            false => None,
+        };
+        #[cfg(not(feature = "uring"))]
+        let ring = None;
+        let writer = LogWriter::open(&path, ring.as_ref(), options.write_buffer)?;
+
+        let view = Arc::new(View::open(
+            &path,
+            None,
+            BTreeMap::new(),
+            options.merge_operator.clone(),
+            ring.clone(),
+            options.read_buffer,
+        )?);
+        let mut kv = KvStore {
+            writer,
+            ring,
+            current: Arc::new(RwLock::new(Arc::clone(&view))),
+            view,
+            cache: match options.cache_capacity {
+                0 => None,
+                bytes => Some(Arc::new(ValueCache::new(bytes))),
+            },
+            history: HashMap::new(),
+            lists: HashMap::new(),
+            hashes: HashMap::new(),
+            sets: HashMap::new(),
+            sorted_sets: HashMap::new(),
+            next_seq: 1,
+            compacted_seq: 0,
+            path,
+            uncompacted: 0,
+            values: None,
+            value_gen: 0,
+            value_log_len: 0,
+            value_garbage: 0,
+            sweep_cursor: None,
+            clean: false,
+            last_compaction: None,
+            compactions: CompactionLog::default(),
+            stalled_writes: 0,
+            options,
+            commit: None,
+            listeners: Vec::new(),
+            hooks: Hooks::default(),
+            _lock: lock,
+        };
+
+        // the marker only vouches for the log until the next write
+        let marker = kv.marker_path();
+        let clean = marker.exists();
+        if clean {
+            std::fs::remove_file(&marker)?;
+        }
+        // the saved index goes stale with the marker
+        let saved = kv.take_saved_index(clean && load_index)?;
+        let hot_keys = match saved {
+            Some(saved) => kv.restore_index(saved),
+            None => {
+                kv.read_file(!clean)?;
+                Vec::new()
+            }
+        };
+        kv.open_values()?;
+        kv.warm_cache(hot_keys)?;
+        Ok(kv)
+    }
+
+    /// Appends a record setting `key` to `value`, until `expires` if
+    /// given, in milliseconds since the UNIX epoch.
+    fn write_set(&mut self, key: String, value: String, expires: Option<u64>) -> Result<()> {
+        self.mark_dirty()?;
+        let seq = self.next_seq;
+        let record = Record {
+            expires,
+            ..self.separate(seq, key.clone(), value)?
+        };
+        let initial_offset = self.writer.end()?;
+        write_record(&mut self.writer, &record, self.options.log_format)?;
+        self.flush_writes()?;
+        let new_offset = self.writer.end()?;
+        self.next_seq += 1;
+        let pointer = Pointer::new(initial_offset..new_offset, seq).for_record(&record);
+        self.notify(&record.command, seq);
+        self.record_write(key, pointer, false);
+        self.run_hooks(&[record.command.key()])?;
+        self.maybe_compact()?;
+
+        Ok(())
+    }
+
+    /// Appends a tombstone for `key`.
+    fn write_remove(&mut self, key: String) -> Result<()> {
+        self.mark_dirty()?;
+        let seq = self.next_seq;
+        let record = Record::new(seq, Command::remove(key.clone()))?;
+        let initial_offset = self.writer.end()?;
+        write_record(&mut self.writer, &record, self.options.log_format)?;
+        self.flush_writes()?;
+        let new_offset = self.writer.end()?;
+        self.next_seq += 1;
+        self.notify(&record.command, seq);
+        self.record_write(key, Pointer::new(initial_offset..new_offset, seq), true);
+        self.run_hooks(&[record.command.key()])?;
+        self.maybe_compact()?;
+        Ok(())
+    }
+
+    /// Appends a record adding `value` to the list `key`, at its front if
+    /// `front`, and returns the length of the list.
+    fn push(&mut self, key: String, value: String, front: bool) -> Result<u64> {
+        self.check_size(&key, &value)?;
+        self.check_disk()?;
+        self.claim(&key, "list")?;
+        let command = Command::Push {
+            key: key.clone(),
+            value,
+            front,
+        };
+        let pointer = self.append(command)?;
+        let len = self.record_push(key, pointer, front);
+        self.maybe_compact()?;
+        Ok(len)
+    }
+
+    /// Runs `operation`, reporting it as `op` on `bytes` to the metrics
+    /// sink, if any.
+    fn measured<T>(
+        &mut self,
+        op: OpKind,
+        bytes: u64,
+        operation: impl FnOnce(&mut KvStore) -> Result<T>,
+    ) -> Result<T> {
+        let started = Instant::now();
+        let result = operation(self);
+        if let Some(metrics) = &self.options.metrics {
+            metrics.record(op, started, bytes, result.is_ok());
+        }
+        result
+    }
+
+    /// The string value of `key`, as `get` reads it but unreported, for the
+    /// operations that read it on the way.
+    fn read(&self, key: &str) -> Result<Option<String>> {
+        self.view
+            .get(key, self.cache.as_deref())?
+            .map(into_string)
+            .transpose()
+    }
+
+    /// Fails with `MyError::TooLarge` if `key` or `value` is longer than
+    /// the store allows.
+    fn check_size(&self, key: &str, value: &str) -> Result<()> {
+        let limits = [
+            ("Key", key, self.options.max_key_bytes),
+            ("Value", value, self.options.max_value_bytes),
+        ];
+        for (what, text, max) in limits {
+            match max {
+                Some(max) if text.len() as u64 > max => {
+                    return Err(MyError::TooLarge(format!(
+                        "{} of {} bytes exceeds the limit of {} bytes",
+                        what,
+                        text.len(),
+                        max
+                    )))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// What `key` holds: a `string`, a `list`, a `hash`, a `set` or a
+    /// `sorted set`.
+    fn kind_of(&self, key: &str) -> Option<&'static str> {
+        if self.view.contains(key) {
+            Some("string")
+        } else if self.lists.contains_key(key) {
+            Some("list")
+        } else if self.hashes.contains_key(key) {
+            Some("hash")
+        } else if self.sets.contains_key(key) {
+            Some("set")
+        } else if self.sorted_sets.contains_key(key) {
+            Some("sorted set")
+        } else {
+            None
+        }
+    }
+
+    /// Fails unless `key` is free or already holds a `kind`.
+    fn claim(&mut self, key: &str, kind: &str) -> Result<()> {
+        match self.kind_of(key) {
+            Some(held) if held != kind => Err(MyError::WrongType {
+                key: key.to_owned(),
+                held,
+                wanted: kind.to_owned(),
+            }),
+            Some(_) => Ok(()),
+            None => {
+                // an expired string not swept yet would take the new value
+                // with it
+                if self.view.index.read().unwrap().contains_key(key) {
+                    self.write_remove(key.to_owned())?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Appends a record of its own for `command`, and returns where it is.
+    fn append(&mut self, command: Command) -> Result<Pointer> {
+        self.mark_dirty()?;
+        let seq = self.next_seq;
+        let record = Record::new(seq, command)?;
+        let initial_offset = self.writer.end()?;
+        write_record(&mut self.writer, &record, self.options.log_format)?;
+        self.flush_writes()?;
+        let new_offset = self.writer.end()?;
+        self.next_seq += 1;
+        self.notify(&record.command, seq);
+        Ok(Pointer::new(initial_offset..new_offset, seq).for_record(&record))
+    }
+
+    /// Tells the listeners about `command`, written at `seq`.
+    fn notify(&self, command: &Command, seq: u64) {
+        if self.listeners.is_empty() {
+            return;
+        }
+        if let Command::Touch { keys, .. } = command {
+            for key in keys {
+                self.notify_key(key, KeyOp::Touch, seq);
+            }
+            return;
+        }
+        let (key, op) = match command {
+            Command::Set { key, .. } => (key, KeyOp::Set),
+            Command::Remove { key } => (key, KeyOp::Remove),
+            Command::Push { key, .. } => (key, KeyOp::Push),
+            Command::Pop { key, .. } => (key, KeyOp::Pop),
+            Command::HSet { key, .. } => (key, KeyOp::HSet),
+            Command::HDel { key, .. } => (key, KeyOp::HDel),
+            Command::SAdd { key, .. } => (key, KeyOp::SAdd),
+            Command::SRem { key, .. } => (key, KeyOp::SRem),
+            Command::ZAdd { key, .. } => (key, KeyOp::ZAdd),
+            Command::Merge { key, .. } => (key, KeyOp::Merge),
+            Command::Batch(_) => unreachable!("batches are notified write by write"),
+            Command::Touch { .. } => unreachable!("touches are notified key by key"),
+        };
+        self.notify_key(key, op, seq);
+    }
+
+    fn notify_key(&self, key: &str, op: KeyOp, seq: u64) {
+        let event = KeyEvent {
+            key: key.to_owned(),
+            op,
+            seq,
+            timestamp: unix_millis(),
+        };
+        for listener in &self.listeners {
+            listener.on_event(&event);
+        }
+    }
+
+    /// Adds the push at `pointer` to the list `key`, and returns the length
+    /// of the list.
+    fn record_push(&mut self, key: String, pointer: Pointer, front: bool) -> u64 {
+        let list = self.lists.entry(key).or_default();
+        if front {
+            list.push_front(pointer);
+        } else {
+            list.push_back(pointer);
+        }
+        list.len() as u64
+    }
+
+    /// Takes a push off the list `key` for the pop at `pointer`, both
+    /// records becoming stale.
+    fn record_pop(&mut self, key: &str, pointer: Pointer, front: bool) {
+        self.uncompacted += pointer.len;
+        let list = match self.lists.get_mut(key) {
+            Some(list) => list,
+            None => return,
+        };
+        let popped = if front {
+            list.pop_front()
+        } else {
+            list.pop_back()
+        };
+        self.uncompacted += popped.map_or(0, |popped| popped.len);
+        if list.is_empty() {
+            self.lists.remove(key);
+        }
+    }
+
+    /// Points `field` of the hash `key` at the set at `pointer`.
+    fn record_hset(&mut self, key: String, field: String, pointer: Pointer) {
+        let hash = self.hashes.entry(key).or_default();
+        if let Some(previous) = hash.insert(field, pointer) {
+            self.uncompacted += previous.len;
+        }
+    }
+
+    /// Drops `field` of the hash `key` for the removal at `pointer`, both
+    /// records becoming stale.
+    fn record_hdel(&mut self, key: &str, field: &str, pointer: Pointer) {
+        self.uncompacted += pointer.len;
+        let hash = match self.hashes.get_mut(key) {
+            Some(hash) => hash,
+            None => return,
+        };
+        self.uncompacted += hash.remove(field).map_or(0, |removed| removed.len);
+        if hash.is_empty() {
+            self.hashes.remove(key);
+        }
+    }
+
+    /// Adds `member` to the set `key` for the addition at `pointer`.
+    fn record_sadd(&mut self, key: String, member: String, pointer: Pointer) {
+        let set = self.sets.entry(key).or_default();
+        if let Some(previous) = set.insert(member, pointer) {
+            self.uncompacted += previous.len;
+        }
+    }
+
+    /// Scores `member` of the sorted set `key` for the write at `pointer`.
+    fn record_zadd(&mut self, key: String, member: String, score: f64, pointer: Pointer) {
+        let set = self.sorted_sets.entry(key).or_default();
+        if let Some(previous) = set.insert(member, score, pointer) {
+            self.uncompacted += previous.len;
+        }
+    }
+
+    /// Drops `member` of the set `key` for the removal at `pointer`, both
+    /// records becoming stale.
+    fn record_srem(&mut self, key: &str, member: &str, pointer: Pointer) {
+        self.uncompacted += pointer.len;
+        let set = match self.sets.get_mut(key) {
+            Some(set) => set,
+            None => return,
+        };
+        self.uncompacted += set.remove(member).map_or(0, |removed| removed.len);
+        if set.is_empty() {
+            self.sets.remove(key);
+        }
+    }
+
+    /// Adds the merge operand at `pointer` to the value of `key`, or makes
+    /// it the value if `key` has none.
+    ///
+    /// Operands count as uncompacted, since compaction folds them into the
+    /// value.
+    fn record_merge(&mut self, key: String, pointer: Pointer) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&key);
+        }
+        self.uncompacted += pointer.len;
+        let view = Arc::clone(&self.view);
+        let mut index = view.index.write().unwrap();
+        match index.get_mut(&key) {
+            Some(value) => value.merges.push(pointer),
+            None => {
+                index.insert(key, pointer);
+            }
+        }
+    }
+
+    /// Opens the value log of the generation the log points into, if there
+    /// is one or values are to be separated, and removes the others: those
+    /// left behind by a compaction interrupted before or after it replaced
+    /// the log.
+    fn open_values(&mut self) -> Result<()> {
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        for entry in std::fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let gen = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(VALUE_LOG_PREFIX))
+                .and_then(|name| name.strip_suffix(VALUE_LOG_SUFFIX))
+                .and_then(|gen| gen.parse::<u64>().ok());
+            if gen.is_some_and(|gen| gen != self.value_gen) {
+                std::fs::remove_file(dir.join(name))?;
+            }
+        }
+        let path = self.value_log_path(self.value_gen);
+        if self.options.value_threshold.is_some() || path.exists() {
+            let file = OpenOptions::new().append(true).create(true).open(&path)?;
+            self.value_log_len = file.metadata()?.len();
+            self.values = Some(file);
+        }
+        let index = std::mem::take(&mut *self.view.index.write().unwrap());
+        self.switch_view(index)
+    }
+
+    /// Replaces the view by one of the current log and value log, indexed
+    /// by `index`.
+    fn switch_view(&mut self, index: BTreeMap<String, Pointer>) -> Result<()> {
+        let values = self
+            .values
+            .as_ref()
+            .map(|_| self.value_log_path(self.value_gen));
+        self.view = Arc::new(View::open(
+            &self.path,
+            values.as_deref(),
+            index,
+            self.options.merge_operator.clone(),
+            self.ring.clone(),
+            self.options.read_buffer,
+        )?);
+        *self.current.write().unwrap() = Arc::clone(&self.view);
+        Ok(())
+    }
+
+    fn value_log_path(&self, gen: u64) -> PathBuf {
+        self.path
+            .with_file_name(format!("{}{}{}", VALUE_LOG_PREFIX, gen, VALUE_LOG_SUFFIX))
+    }
+
+    /// The record setting `key` to `value`, moving the value to the value
+    /// log if it is large enough.
+    fn separate(&mut self, seq: u64, key: String, value: String) -> Result<Record> {
+        let values = match (&mut self.values, self.options.value_threshold) {
+            (Some(values), Some(threshold)) if value.len() as u64 >= threshold => values,
+            _ => return Record::new(seq, Command::set(key, value)),
+        };
+        // the value goes first, so that the record never points past the
+        // end of the value log
+        values.write_all(value.as_bytes())?;
+        let vlog = ValueRef {
+            gen: self.value_gen,
+            pos: self.value_log_len,
+            len: value.len() as u64,
+            crc: crc32fast::hash(value.as_bytes()),
+        };
+        self.value_log_len += vlog.len;
+        Ok(Record {
+            vlog: Some(vlog),
+            ..Record::new(seq, Command::set(key, String::new()))?
+        })
+    }
+
+    /// The files syncing a write takes: the value log, then the log.
+    fn synced_files(&self) -> Result<Vec<File>> {
+        self.values
+            .iter()
+            .chain(Some(self.writer.get_ref()))
+            .map(|file| Ok(file.try_clone()?))
+            .collect()
+    }
+
+    /// Pushes buffered writes to the OS, and to disk if the sync policy
+    /// asks for it and syncs were not deferred.
+    fn flush_writes(&mut self) -> Result<()> {
+        match &self.commit {
+            Some(commit) => {
+                self.writer.flush()?;
+                commit.written()
+            }
+            None if self.options.sync_policy == SyncPolicy::Always => {
+                if let Some(values) = &self.values {
+                    values.sync_data()?;
+                }
+                self.writer.flush_synced()?
+            }
+            None => self.writer.flush()?,
+        }
+        Ok(())
+    }
+
+    fn marker_path(&self) -> PathBuf {
+        self.path.with_file_name(CLEAN_SHUTDOWN_MARKER)
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.path.with_file_name(INDEX_FILE)
+    }
+
+    /// Writes what reading the log builds to the index file, with the
+    /// lengths of the log and the value log it describes.
+    fn save_index(&self) -> Result<()> {
+        let index = self.view.index.read().unwrap();
+        let saved = SavedIndex {
+            log_len: self.writer.get_ref().metadata()?.len(),
+            value_log_len: self.value_log_len,
+            next_seq: self.next_seq,
+            compacted_seq: self.compacted_seq,
+            uncompacted: self.uncompacted,
+            value_gen: self.value_gen,
+            value_garbage: self.value_garbage,
+            index: Cow::Borrowed(&index),
+            history: Cow::Borrowed(&self.history),
+            lists: Cow::Borrowed(&self.lists),
+            hashes: Cow::Borrowed(&self.hashes),
+            sets: Cow::Borrowed(&self.sets),
+            sorted_sets: self
+                .sorted_sets
+                .iter()
+                .map(|(key, set)| {
+                    let entries = set
+                        .entries()
+                        .map(|(member, score, pointer)| (member.to_owned(), score, pointer.clone()))
+                        .collect();
+                    (key.clone(), entries)
+                })
+                .collect(),
+            hot_keys: self
+                .cache
+                .as_ref()
+                .map_or_else(Vec::new, |cache| cache.keys()),
+        };
+        let path = self.index_path();
+        let mut writer = BufWriter::new(File::create(&path).map_err(MyError::file(&path))?);
+        serde_json::to_writer(&mut writer, &saved)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Removes the index file, returning the index it holds if `load` and
+    /// it still describes the log and the value log. A damaged or stale one
+    /// is logged and ignored, the log being read instead.
+    fn take_saved_index(&self, load: bool) -> Result<Option<SavedIndex<'static>>> {
+        let path = self.index_path();
+        if !path.exists() {
+            return Ok(None);
+        }
+        if !load {
+            std::fs::remove_file(&path)?;
+            return Ok(None);
+        }
+        let data = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+        let saved: SavedIndex = match serde_json::from_slice(&data) {
+            Ok(saved) => saved,
+            Err(e) => {
+                warn!("Ignoring {}: {}", path.display(), e);
+                return Ok(None);
+            }
+        };
+        let log_len = std::fs::metadata(&self.path)?.len();
+        let value_log_len = std::fs::metadata(self.value_log_path(saved.value_gen))
+            .map_or(0, |metadata| metadata.len());
+        if saved.log_len != log_len || saved.value_log_len != value_log_len {
+            warn!(
+                "Ignoring {}: it describes a log of {} bytes, not {}",
+                path.display(),
+                saved.log_len,
+                log_len
+            );
+            return Ok(None);
+        }
+        Ok(Some(saved))
+    }
+
+    /// Takes the index and the rest of what reading the log builds from
+    /// `saved`, returning the keys whose values were cached.
+    fn restore_index(&mut self, saved: SavedIndex) -> Vec<String> {
+        info!(
+            "Loaded the index of {} saved on shutdown",
+            self.path.display()
+        );
+        self.next_seq = saved.next_seq;
+        self.compacted_seq = saved.compacted_seq;
+        self.uncompacted = saved.uncompacted;
+        self.value_gen = saved.value_gen;
+        self.value_garbage = saved.value_garbage;
+        *self.view.index.write().unwrap() = saved.index.into_owned();
+        self.history = saved.history.into_owned();
+        self.lists = saved.lists.into_owned();
+        self.hashes = saved.hashes.into_owned();
+        self.sets = saved.sets.into_owned();
+        for (key, entries) in saved.sorted_sets {
+            let set = self.sorted_sets.entry(key).or_default();
+            for (member, score, pointer) in entries {
+                set.insert(member, score, pointer);
+            }
+        }
+        saved.hot_keys
+    }
+
+    /// Reads the values of `keys` into the cache, least recently used
+    /// first, skipping those gone since.
+    fn warm_cache(&self, keys: Vec<String>) -> Result<()> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok(()),
+        };
+        for key in keys {
+            let pointer = self.view.index.read().unwrap().get(&key).cloned();
+            if let Some(pointer) = pointer {
+                let value = self.view.read_value(&pointer)?;
+                cache.insert(key, pointer.last_seq(), value.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the clean-shutdown marker before the log changes again.
+    fn mark_dirty(&mut self) -> Result<()> {
+        if self.clean {
+            std::fs::remove_file(self.marker_path())?;
+            self.clean = false;
+        }
+        Ok(())
+    }
+
+    /// Read file and load history of command from the log.
+    ///
+    /// With `verify`, used after a crash, a record that cannot be decoded at
+    /// the end of the log is taken as a write torn by the crash: the log is
+    /// truncated before it instead of failing to open. One followed by valid
+    /// records is corruption, and still fails.
+    fn read_file(&mut self, verify: bool) -> Result<()> {
+        let file = OpenOptions::new().read(true).open(&self.path)?;
+        let len = file.metadata()?.len();
+        let reader = BufReader::new(file);
+        let mut initial_offset = 0;
+
+        for frame in RecordReader::new(reader, len) {
+            let frame = frame?;
+            let new_offset = frame.range.end;
+            let record = match frame
+                .into_record()
+                .and_then(|record| record.verify().map(|()| record))
+            {
+                Ok(record) => record,
+                Err(e) if verify && !self.has_record_after(initial_offset)? => {
+                    warn!(
+                        "Truncating log {} at byte {}: {}",
+                        self.path.display(),
+                        initial_offset,
+                        e
+                    );
+                    self.writer.set_len(initial_offset)?;
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            let writes = match &record.command {
+                Command::Batch(commands) => commands.len() as u64,
+                _ => 1,
+            };
+            // logs written before sequence numbers get them on the fly
+            let last_seq = match record.seq {
+                0 => self.next_seq + writes - 1,
+                seq => seq,
+            };
+            self.next_seq = self.next_seq.max(last_seq + 1);
+            if let Some(vlog) = &record.vlog {
+                self.value_gen = vlog.gen;
+            }
+            let pointer = Pointer::new(initial_offset..new_offset, last_seq).for_record(&record);
+            match record.command {
+                Command::Set { key, .. } => self.record_write(key, pointer, false),
+                Command::Remove { key } => self.record_write(key, pointer, true),
+                Command::Push { key, front, .. } => {
+                    self.record_push(key, pointer, front);
+                }
+                Command::Pop { key, front } => self.record_pop(&key, pointer, front),
+                Command::HSet { key, field, .. } => self.record_hset(key, field, pointer),
+                Command::HDel { key, field } => self.record_hdel(&key, &field, pointer),
+                Command::SAdd { key, member } => self.record_sadd(key, member, pointer),
+                Command::SRem { key, member } => self.record_srem(&key, &member, pointer),
+                Command::ZAdd { key, member, score } => {
+                    self.record_zadd(key, member, score, pointer)
+                }
+                Command::Merge { key, .. } => self.record_merge(key, pointer),
+                Command::Touch { keys, expires } => {
+                    self.uncompacted += pointer.len;
+                    self.record_touch(&keys, expires, pointer.updated.unwrap_or(0));
+                }
+                // only compaction writes empty batches
+                Command::Batch(commands) if commands.is_empty() => {
+                    self.compacted_seq = self.compacted_seq.max(last_seq)
+                }
+                Command::Batch(commands) => self.index_batch(
+                    commands,
+                    initial_offset..new_offset,
+                    last_seq,
+                    record.written,
+                )?,
+            };
+            initial_offset = new_offset;
+        }
+        //println!("Uncompacted {:?}", self.uncompacted);
+        Ok(())
+    }
+
+    /// Whether a valid record follows the one starting at `offset`.
+    ///
+    /// Records start with a separator if framed, or on a new line, and
+    /// neither occurs raw inside one, so only those starts are tried.
+    fn has_record_after(&self, offset: u64) -> Result<bool> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset + 1))?;
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest)?;
+        let found = (0..rest.len()).any(|pos| {
+            let start = match rest[pos] {
+                0x1e => pos,
+                b'\n' if pos > 0 && rest[pos - 1] == b'\r' => pos + 1,
+                _ => return false,
+            };
+            let mut records = RecordReader::new(&rest[start..], (rest.len() - start) as u64);
+            matches!(
+                records.next().map(|frame| frame.and_then(|frame| frame.into_record())),
+                Some(Ok(record)) if record.verify().is_ok()
+            )
+        });
+        if found {
+            error!(
+                "Log {} is corrupt at byte {}, with valid records after",
+                self.path.display(),
+                offset
+            );
+        }
+        Ok(found)
+    }
+
+    /// Points `key` at the write at `pointer`, a tombstone if `removed`,
+    /// moving the version it replaces to the history.
+    ///
+    /// Versions past the retention window count as uncompacted.
+    fn record_write(&mut self, key: String, pointer: Pointer, removed: bool) {
+        let view = Arc::clone(&self.view);
+        let mut index = view.index.write().unwrap();
+        self.record_write_in(&mut index, key, pointer, removed);
+    }
+
+    /// `record_write` with the index already locked.
+    fn record_write_in(
+        &mut self,
+        index: &mut BTreeMap<String, Pointer>,
+        key: String,
+        pointer: Pointer,
+        removed: bool,
+    ) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&key);
+        }
+        if let Some(list) = self.lists.remove(&key) {
+            self.uncompacted += list.iter().map(|item| item.len).sum::<u64>();
+        }
+        if let Some(hash) = self.hashes.remove(&key) {
+            self.uncompacted += hash.values().map(|field| field.len).sum::<u64>();
+        }
+        if let Some(set) = self.sets.remove(&key) {
+            self.uncompacted += set.values().map(|member| member.len).sum::<u64>();
+        }
+        if let Some(set) = self.sorted_sets.remove(&key) {
+            self.uncompacted += set.items().map(|member| member.len).sum::<u64>();
+        }
+        // overwriting a live value keeps its creation time
+        let mut pointer = pointer;
+        if !removed && pointer.created.is_none() {
+            let written = pointer.updated.unwrap_or(0);
+            pointer.created = index
+                .get(&key)
+                .filter(|previous| !previous.is_expired(written))
+                .and_then(|previous| previous.created.or(previous.updated));
+        }
+        let previous = if removed {
+            index.remove(&key)
+        } else {
+            index.insert(key.clone(), pointer.clone())
+        };
+        let versions = self.history.entry(key).or_default();
+        let retained = self.options.retained_versions;
+        for version in previous
+            .into_iter()
+            .chain(if removed { Some(pointer) } else { None })
+        {
+            versions.push(version);
+            if versions.len() > retained {
+                let stale = &versions[versions.len() - 1 - retained];
+                self.uncompacted += stale.stale_len();
+                self.value_garbage += stale.value_len;
+            }
+        }
+    }
+
+    /// Makes each of `keys` that was live at `written` expire at `expires`,
+    /// and returns how many were.
+    fn record_touch(&mut self, keys: &[String], expires: u64, written: u64) -> u64 {
+        let mut index = self.view.index.write().unwrap();
+        let mut touched = 0;
+        for key in keys {
+            if let Some(pointer) = index
+                .get_mut(key)
+                .filter(|pointer| !pointer.is_expired(written))
+            {
+                pointer.expires = Some(expires);
+                touched += 1;
+            }
+        }
+        touched
+    }
+
+    /// Indexes the writes of the batch record at `record`, the last of
+    /// which has sequence number `last_seq`, all written at `written`.
+    ///
+    /// Each write is pointed to inside the record, see `batch_ranges`.
+    fn index_batch(
+        &mut self,
+        commands: Vec<Command>,
+        record: Range<u64>,
+        last_seq: u64,
+        written: Option<u64>,
+    ) -> Result<()> {
+        let first_seq = last_seq + 1 - commands.len() as u64;
+        let pointers: Vec<Pointer> = batch_ranges(&commands, record.end)?
+            .into_iter()
+            .zip(first_seq..)
+            .map(|(range, seq)| Pointer {
+                updated: written,
+                ..Pointer::new(range, seq)
+            })
+            .collect();
+
+        // the framing around the writes is garbage from the start
+        let writes: u64 = pointers.iter().map(|pointer| pointer.len).sum();
+        self.uncompacted += record.end - record.start - writes;
+        // under one lock, so that readers see all of the batch or none of it
+        let view = Arc::clone(&self.view);
+        let mut index = view.index.write().unwrap();
+        for (command, pointer) in commands.into_iter().zip(pointers) {
+            match command {
+                Command::Set { key, .. } => self.record_write_in(&mut index, key, pointer, false),
+                Command::Remove { key } => self.record_write_in(&mut index, key, pointer, true),
+                _ => unreachable!("batches hold sets and removes"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns up to `limit` versions of `key`, newest first: its current
+    /// value, if any, then the versions it replaced that are still in the
+    /// log. A version whose value is `None` records that the key was
+    /// removed.
+    ///
+    /// Compaction keeps as many previous versions as
+    /// `KvStoreOptions::with_retained_versions` asks for.
+    pub fn get_history(&mut self, key: String, limit: usize) -> Result<Vec<KeyVersion>> {
+        let current = self.view.index.read().unwrap().get(&key).cloned();
+        let pointers: Vec<Pointer> = self
+            .history
+            .get(&key)
+            .into_iter()
+            .flatten()
+            .chain(&current)
+            .rev()
+            .take(limit)
+            .cloned()
+            .collect();
+        pointers
+            .into_iter()
+            .map(|pointer| {
+                Ok(KeyVersion {
+                    seq: pointer.last_seq(),
+                    value: self.read_version(&pointer)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the value `key` held once the write with sequence number
+    /// `seq` was made, `None` if it did not exist then.
+    ///
+    /// Only string keys keep their versions. Expiry is not replayed: a key
+    /// that had expired by then is found until its removal was written.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::StringError` if the versions of `key` around
+    /// `seq` were compacted away; `KvStoreOptions::with_retained_versions`
+    /// tells how far back compaction keeps them.
+    pub fn get_at(&mut self, key: String, seq: u64) -> Result<Option<String>> {
+        match self.version_at(&key, seq)? {
+            Some(pointer) => self.read_version(&pointer),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the key/value pairs with a key in `range` as they were once
+    /// the write with sequence number `seq` was made, in key order, as
+    /// `KvStore::get_at` resolves them.
+    pub fn scan_at<R: RangeBounds<String>>(
+        &mut self,
+        range: R,
+        seq: u64,
+    ) -> Result<Vec<(String, String)>> {
+        let keys: BTreeSet<String> = self
+            .view
+            .index
+            .read()
+            .unwrap()
+            .keys()
+            .chain(self.history.keys())
+            .filter(|key: &&String| range.contains(*key))
+            .cloned()
+            .collect();
+        let mut pairs = Vec::new();
+        for key in keys {
+            if let Some(pointer) = self.version_at(&key, seq)? {
+                if let Some(value) = self.read_version(&pointer)? {
+                    pairs.push((key, value));
+                }
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// The version of `key` that was current at `seq`, its merges cut
+    /// back to those made by then, `None` if the key did not exist.
+    fn version_at(&self, key: &str, seq: u64) -> Result<Option<Pointer>> {
+        let current = self.view.index.read().unwrap().get(key).cloned();
+        let versions: Vec<Pointer> = self
+            .history
+            .get(key)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .chain(current)
+            .collect();
+        if let Some(version) = versions.iter().rev().find(|version| version.seq <= seq) {
+            let mut version = version.clone();
+            version.merges.retain(|merge| merge.seq <= seq);
+            return Ok(Some(version));
+        }
+        // compaction keeps the latest versions of a key, and with any at
+        // all the removal of a key it drops: a key with none as old as
+        // the compaction did not exist before it
+        let dropped = self.options.retained_versions == 0
+            || versions
+                .first()
+                .is_some_and(|oldest| oldest.seq <= self.compacted_seq);
+        if seq < self.compacted_seq && dropped {
+            return Err(MyError::StringError(format!(
+                "The log was compacted up to sequence number {}, dropping the versions of `{}` at {}",
+                self.compacted_seq, key, seq
+            )));
+        }
+        Ok(None)
+    }
+
+    /// The value written by the version at `pointer`, `None` for a removal.
+    fn read_version(&self, pointer: &Pointer) -> Result<Option<String>> {
+        match self.view.read_command(pointer)? {
+            Command::Remove { .. } => Ok(None),
+            _ => Ok(Some(self.view.read_value(pointer)?)),
+        }
+    }
+
+    /// Tells how much room the live keys take on disk, in all and by
+    /// namespace, to find which part of the keyspace takes it up.
+    ///
+    /// Answered from the index, without reading the log: a key takes the
+    /// bytes of the records of its value, merges included, or of its
+    /// elements.
+    pub fn size_report(&mut self) -> Result<SizeReport> {
+        let now = unix_millis();
+        let index = self.view.index.read().unwrap();
+        let strings = index
+            .iter()
+            .filter(|(_, pointer)| !pointer.is_expired(now))
+            .map(|(key, pointer)| (key.as_str(), pointer.stale_len() + pointer.value_len));
+        let lists = self
+            .lists
+            .iter()
+            .map(|(key, list)| (key.as_str(), list.iter().map(|item| item.len).sum()));
+        let hashes = self
+            .hashes
+            .iter()
+            .chain(&self.sets)
+            .map(|(key, items)| (key.as_str(), items.values().map(|item| item.len).sum()));
+        let sorted_sets = self
+            .sorted_sets
+            .iter()
+            .map(|(key, set)| (key.as_str(), set.items().map(|item| item.len).sum()));
+        Ok(SizeReport::new(
+            strings.chain(lists).chain(hashes).chain(sorted_sets),
+        ))
+    }
+
+    /// Returns the writes with a sequence number above `seq`, in sequence
+    /// order, the writes of batches one by one: what an incremental backup
+    /// taken at `seq`, or a replica that applied the writes up to it, is
+    /// missing.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::StringError` if the log was compacted since
+    /// `seq`, as compaction drops overwritten and removed keys; a full
+    /// backup is needed then.
+    pub fn changes_since(&mut self, seq: u64) -> Result<Vec<Change>> {
+        if seq < self.compacted_seq {
+            return Err(MyError::StringError(format!(
+                "The log was compacted up to sequence number {}, after {}",
+                self.compacted_seq, seq
+            )));
+        }
+        let file = File::open(&self.path)?;
+        let len = file.metadata()?.len();
+        let reader = BufReader::new(file);
+        let mut changes = Vec::new();
+        let mut next_seq = 1;
+        for frame in RecordReader::new(reader, len) {
+            let record = frame?.into_record()?;
+            let commands = match record.command {
+                Command::Batch(commands) => commands,
+                command => vec![command],
+            };
+            // logs written before sequence numbers get them on the fly
+            let last_seq = match record.seq {
+                0 => next_seq + commands.len() as u64 - 1,
+                seq => seq,
+            };
+            next_seq = next_seq.max(last_seq + 1);
+            let first_seq = (last_seq + 1).saturating_sub(commands.len() as u64);
+            for (write_seq, command) in (first_seq..).zip(commands) {
+                if write_seq <= seq {
+                    continue;
+                }
+                let command = match (command, record.vlog) {
+                    (Command::Set { key, .. }, Some(vlog)) => Command::Set {
+                        key,
+                        value: self.view.read_separated_string(&vlog)?,
+                    },
+                    (command, _) => command,
+                };
+                changes.push(Change {
+                    seq: write_seq,
+                    command,
+                    expires: record.expires,
+                });
+            }
+        }
+        changes.sort_by_key(|change| change.seq);
+        Ok(changes)
+    }
+
+    /// Calls `hook` with the key and the new value of every string key
+    /// set, merged into or loaded, once the write is on disk, so that the
+    /// application can keep state derived from the store up to date.
+    ///
+    /// With hooks, every write is synced before it returns, as with
+    /// `SyncPolicy::Always`, or waits for the group commit if syncs were
+    /// deferred. Hooks run on the writing thread, in the order of the
+    /// writes; lists, hashes and sets do not call them.
+    pub fn on_write<F>(&mut self, hook: F)
+    where
+        F: Fn(&str, &str) + Send + Sync + 'static,
+    {
+        self.hooks.on_write.push(Box::new(hook));
+    }
+
+    /// Calls `hook` with every key removed, expired keys included, once
+    /// the removal is on disk; see `on_write`.
+    pub fn on_remove<F>(&mut self, hook: F)
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.hooks.on_remove.push(Box::new(hook));
+    }
+
+    /// Makes the writes so far durable, then calls the hooks for each of
+    /// `keys` with what it holds now.
+    fn run_hooks<K: AsRef<str>>(&self, keys: &[K]) -> Result<()> {
+        if self.hooks.is_empty() {
+            return Ok(());
+        }
+        match &self.commit {
+            Some(commit) => commit.sync()?,
+            None if self.options.sync_policy == SyncPolicy::Always => {}
+            None => {
+                if let Some(values) = &self.values {
+                    values.sync_data()?;
+                }
+                self.writer.get_ref().sync_data()?;
+            }
+        }
+        for key in keys {
+            let key = key.as_ref();
+            match self.view.get(key, None)?.map(into_string).transpose()? {
+                Some(value) => self
+                    .hooks
+                    .on_write
+                    .iter()
+                    .for_each(|hook| hook(key, &value)),
+                None => self.hooks.on_remove.iter().for_each(|hook| hook(key)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends `operand` to the value of the string `key`, to be combined
+    /// with it by the merge operator of the store when the key is read or
+    /// the log compacted, instead of reading, changing and writing back the
+    /// value. A key without a value is merged from nothing, and keeps its
+    /// TTL otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::StringError` if the store has no merge
+    /// operator, see `KvStoreOptions::with_merge_operator`, and with
+    /// `MyError::WrongType` if `key` holds a list, a hash or a set.
+    pub fn merge(&mut self, key: String, operand: String) -> Result<()> {
+        if self.options.merge_operator.is_none() {
+            return Err(MyError::StringError(
+                "The store has no merge operator".to_owned(),
+            ));
+        }
+        self.check_size(&key, &operand)?;
+        self.check_disk()?;
+        self.claim(&key, "string")?;
+        let pointer = self.append(Command::Merge {
+            key: key.clone(),
+            operand,
+        })?;
+        self.record_merge(key.clone(), pointer);
+        self.run_hooks(&[key])?;
+        self.maybe_compact()?;
+        Ok(())
+    }
+
+    /// Sets every key of `pairs` to its value, much faster than as many
+    /// `set`s, and returns how many keys were loaded.
+    ///
+    /// The records are written to a copy of the log with a single sync at
+    /// the end, then the copy replaces the log: a crash leaves either all
+    /// of them or none. Values stay in the log, whatever their size.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::StringError`, loading nothing, unless the keys
+    /// come in ascending order without duplicates, and with
+    /// `MyError::TooLarge` if a key or value is longer than the store
+    /// allows, and with `MyError::QuotaExceeded` if the store is already
+    /// at its disk limit.
+    pub fn bulk_load<I>(&mut self, pairs: I) -> Result<u64>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        self.check_disk()?;
+        let temp_path = self.path.with_file_name(BULK_LOAD_FILE);
+        let loaded = match self.write_bulk_load(&temp_path, pairs) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                let _ = std::fs::remove_file(&temp_path);
+                return Err(e);
+            }
+        };
+        std::fs::rename(&temp_path, &self.path)?;
+        self.writer = self.writer.reopen(&self.path)?;
+        if let Some(commit) = &self.commit {
+            commit.replace_files(self.synced_files()?);
+        }
+
+        let count = loaded.len() as u64;
+        let hooked: Vec<String> = match self.hooks.is_empty() {
+            true => Vec::new(),
+            false => loaded.iter().map(|(key, _)| key.clone()).collect(),
+        };
+        let mut index = self.view.index.read().unwrap().clone();
+        for (key, pointer) in loaded {
+            // listeners are only told the key and the operation
+            self.notify(&Command::set(key.clone(), String::new()), pointer.seq);
+            self.record_write_in(&mut index, key, pointer, false);
+        }
+        self.switch_view(index)?;
+        self.run_hooks(&hooked)?;
+        self.maybe_compact()?;
+        Ok(count)
+    }
+
+    /// Writes the log followed by a record for each of `pairs` to
+    /// `temp_path`, and returns the keys with where their records are.
+    fn write_bulk_load<I>(&mut self, temp_path: &Path, pairs: I) -> Result<Vec<(String, Pointer)>>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        self.mark_dirty()?;
+        self.writer.flush()?;
+        let mut temp_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(temp_path)?;
+        // the records already in the log keep their offsets
+        let mut offset = std::io::copy(&mut File::open(&self.path)?, &mut temp_file)?;
+        let mut writer = BufWriter::new(temp_file);
+        let mut loaded: Vec<(String, Pointer)> = Vec::new();
+        let mut seq = self.next_seq;
+        for (key, value) in pairs {
+            if let Some((previous, _)) = loaded.last() {
+                if *previous >= key {
+                    return Err(MyError::StringError(format!(
+                        "Bulk-loaded keys must be sorted and unique, `{}` follows `{}`",
+                        key, previous
+                    )));
+                }
+            }
+            self.check_size(&key, &value)?;
+            let record = Record::new(seq, Command::set(key.clone(), value))?;
+            let len = write_record(&mut writer, &record, self.options.log_format)?;
+            loaded.push((
+                key,
+                Pointer::new(offset..offset + len, seq).for_record(&record),
+            ));
+            offset += len;
+            seq += 1;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        self.next_seq = seq;
+        Ok(loaded)
+    }
+
+    /// Reads back every write the index, lists, hashes and sets point to,
+    /// adding to `report` the keys checked and what is wrong with them.
+    fn verify_index(&self, report: &mut VerifyReport) {
+        let index = self.view.index.read().unwrap().clone();
+        for (key, pointer) in &index {
+            report.keys += 1;
+            for pointer in std::iter::once(pointer).chain(&pointer.merges) {
+                self.verify_pointer(key, pointer, report);
+            }
+        }
+        for (key, list) in &self.lists {
+            report.keys += 1;
+            for pointer in list {
+                self.verify_pointer(key, pointer, report);
+            }
+        }
+        for (key, elements) in self.hashes.iter().chain(&self.sets) {
+            report.keys += 1;
+            for pointer in elements.values() {
+                self.verify_pointer(key, pointer, report);
+            }
+        }
+        for (key, set) in &self.sorted_sets {
+            report.keys += 1;
+            for pointer in set.items() {
+                self.verify_pointer(key, pointer, report);
+            }
+        }
+    }
+
+    /// Checks that `pointer` leads to a write of `key`, with its separated
+    /// value intact.
+    fn verify_pointer(&self, key: &str, pointer: &Pointer, report: &mut VerifyReport) {
+        if pointer.value_len > 0 {
+            report.separated_values += 1;
+        }
+        match self.view.read_command(pointer) {
+            Ok(command) if command.key() == key => {}
+            Ok(command) => report.problems.push(format!(
+                "byte {}: `{}` is indexed at a write of `{}`",
+                pointer.pos,
+                key,
+                command.key()
+            )),
+            Err(e) => report.problems.push(format!(
+                "byte {}: `{}` does not read back: {}",
+                pointer.pos, key, e
+            )),
+        }
+    }
+
+    /// Fails with `MyError::QuotaExceeded` if the store takes its disk
+    /// limit or more, compacting first if that may reclaim some.
+    fn check_disk(&mut self) -> Result<()> {
+        let max = match self.options.max_disk_bytes {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let mut usage = std::fs::metadata(&self.path)?.len() + self.value_log_len;
+        if usage >= max && self.uncompacted > 0 {
+            self.compact(CompactionTrigger::Threshold)?;
+            usage = std::fs::metadata(&self.path)?.len() + self.value_log_len;
+        }
+        if usage >= max {
+            return Err(MyError::QuotaExceeded(format!(
+                "The store takes {} bytes on disk, its limit is {}",
+                usage, max
+            )));
+        }
+        Ok(())
+    }
+
+    /// Compacts once stale records take more than the compaction threshold,
+    /// unless outside the compaction windows, or once they reach the hard
+    /// stall limit. Past the soft stall limit, writes that do not compact
+    /// are slowed down instead.
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.past(self.options.hard_stall_bytes) {
+            self.compact(CompactionTrigger::WriteStall)?;
+        } else if self.uncompacted > self.options.compaction_threshold
+            && in_compaction_window(&self.options.compaction_windows)
+        {
+            self.compact(CompactionTrigger::Threshold)?;
+        } else if self.past(self.options.soft_stall_bytes) {
+            self.stalled_writes += 1;
+            std::thread::sleep(STALL_DELAY);
+        }
+        Ok(())
+    }
+
+    /// Whether stale records take `limit` or more, if there is a limit.
+    fn past(&self, limit: Option<u64>) -> bool {
+        limit.is_some_and(|limit| self.uncompacted >= limit)
+    }
+
+    /// Rewrites the log with only the live records and the retained
+    /// versions, then switches the writer and the view over to the new file.
+    ///
+    /// Every write is rewritten as a record of its own carrying its
+    /// sequence number, so that writes of batches keep theirs.
+    ///
+    /// Separated values are left where they are, unless more than half of
+    /// the value log is stale: the live ones are then copied to a value log
+    /// of the next generation, which replacing the log switches to.
+    ///
+    /// Readers keep using the old view meanwhile, and those that still hold
+    /// it afterwards keep the old log open until they are done.
+    fn compact(&mut self, trigger: CompactionTrigger) -> Result<()> {
+        let started = Instant::now();
+        let compacted = self.compact_log(trigger);
+        if let Some(metrics) = &self.options.metrics {
+            let log_len = std::fs::metadata(&self.path).map_or(0, |metadata| metadata.len());
+            let bytes = log_len + self.value_log_len;
+            metrics.record(OpKind::Compaction, started, bytes, compacted.is_ok());
+        }
+        compacted
+    }
+
+    /// `compact`, but for reporting it.
+    fn compact_log(&mut self, trigger: CompactionTrigger) -> Result<()> {
+        let started = Instant::now();
+        let mut throttle = Throttle::new(self.options.compaction_rate);
+        let size_before = std::fs::metadata(&self.path)?.len() + self.value_log_len;
+        let mut records_rewritten = 0;
+        let temp_path = self.path.with_file_name(COMPACTION_FILE);
+        let temp_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?;
+
+        let mut writer_temp_file = BufWriter::new(temp_file);
+        let mut offset = 0;
+        let retained = self.options.retained_versions;
+        let mut index = self.view.index.read().unwrap().clone();
+        let mut history = std::mem::take(&mut self.history);
+        history.retain(|_, versions| {
+            versions.drain(..versions.len().saturating_sub(retained));
+            !versions.is_empty()
+        });
+        // a key's retained versions go before its value, keeping the
+        // records of each key in order
+        let value_gen = self.value_gen + 1;
+        let rewrite_values = self.values.is_some() && self.value_garbage * 2 > self.value_log_len;
+        let mut values = match rewrite_values {
+            true => Some(BufWriter::new(File::create(
+                self.value_log_path(value_gen),
+            )?)),
+            false => None,
+        };
+        let mut value_offset = 0;
+        // replaying the pushes left in the order they were made rebuilds
+        // the lists, whatever was popped in between; hash fields and the
+        // members of sets and sorted sets follow in order too, keeping the records of each key in
+        // order
+        let mut lists = std::mem::take(&mut self.lists);
+        let mut hashes = std::mem::take(&mut self.hashes);
+        let mut sets = std::mem::take(&mut self.sets);
+        let mut sorted_sets = std::mem::take(&mut self.sorted_sets);
+        let mut items: Vec<&mut Pointer> = lists
+            .values_mut()
+            .flatten()
+            .chain(hashes.values_mut().flat_map(BTreeMap::values_mut))
+            .chain(sets.values_mut().flat_map(BTreeMap::values_mut))
+            .chain(sorted_sets.values_mut().flat_map(SortedSet::items_mut))
+            .collect();
+        items.sort_by_key(|item| item.seq);
+        let pointers = history
+            .values_mut()
+            .flatten()
+            .chain(index.values_mut())
+            .chain(items);
+        for pointer in pointers {
+            let mut old = self.view.read_record(pointer)?;
+            let seq = pointer.last_seq();
+            // merge operands are folded into the value they apply to
+            if !pointer.merges.is_empty() || matches!(old.command, Command::Merge { .. }) {
+                let value = self.view.read_value(pointer)?;
+                old.command = Command::set(old.command.key().to_owned(), value);
+                old.vlog = None;
+            }
+            let vlog = match (old.vlog, &mut values) {
+                (Some(vlog), Some(values)) => {
+                    values.write_all(&self.view.read_separated(&vlog)?)?;
+                    value_offset += vlog.len;
+                    throttle.wrote(vlog.len);
+                    Some(ValueRef {
+                        gen: value_gen,
+                        pos: value_offset - vlog.len,
+                        ..vlog
+                    })
+                }
+                (vlog, _) => vlog,
+            };
+            let written = pointer.last_updated();
+            let record = Record {
+                vlog,
+                // touches after the write changed it
+                expires: pointer.expires,
+                written,
+                created: pointer.created.filter(|created| Some(*created) != written),
+                ..Record::new(seq, old.command)?
+            };
+            let len = write_record(&mut writer_temp_file, &record, self.options.log_format)?;
+            *pointer = Pointer::new(offset..offset + len, seq).for_record(&record);
+            offset += len;
+            records_rewritten += 1;
+            throttle.wrote(len);
+        }
+        if let Some(mut values) = values {
+            values.flush()?;
+            values.get_ref().sync_all()?;
+        }
+        if self.next_seq > 1 {
+            // an empty batch records how far the log was compacted, and
+            // keeps the sequence numbers of dropped writes from being
+            // handed out again after a restart
+            let record = Record::new(self.next_seq - 1, Command::Batch(Vec::new()))?;
+            write_record(&mut writer_temp_file, &record, self.options.log_format)?;
+        }
+        writer_temp_file.flush()?;
+        writer_temp_file.get_ref().sync_all()?;
+        drop(writer_temp_file);
+
+        // the log now points into the new value log, if any
+        std::fs::rename(&temp_path, &self.path)?;
+        self.writer = self.writer.reopen(&self.path)?;
+        if rewrite_values {
+            std::fs::remove_file(self.value_log_path(self.value_gen))?;
+            self.value_gen = value_gen;
+            let path = self.value_log_path(value_gen);
+            self.values = Some(OpenOptions::new().append(true).open(path)?);
+            self.value_log_len = value_offset;
+            self.value_garbage = 0;
+        }
+        if let Some(commit) = &self.commit {
+            commit.replace_files(self.synced_files()?);
+        }
+        self.switch_view(index)?;
+        self.history = history;
+        self.lists = lists;
+        self.hashes = hashes;
+        self.sets = sets;
+        self.sorted_sets = sorted_sets;
+        self.uncompacted = 0;
+        self.compacted_seq = self.next_seq - 1;
+        self.last_compaction = Some(SystemTime::now());
+        let size_after = std::fs::metadata(&self.path)?.len() + self.value_log_len;
+        self.compactions.record(CompactionStats {
+            bytes_reclaimed: size_before.saturating_sub(size_after),
+            records_rewritten,
+            duration: started.elapsed(),
+            trigger,
+        });
+        Ok(())
+    }
+}
+
+/// Where the writes of the batch record ending at `end` lie.
+///
+/// They are laid out as serde_json wrote them:
+/// `{"seq":n,"crc":c,"Batch":[write,write,...]}`.
+pub(crate) fn batch_ranges(commands: &[Command], end: u64) -> Result<Vec<Range<u64>>> {
+    let mut ranges = Vec::with_capacity(commands.len());
+    // walk back from before the closing `]}`, skipping the commas
+    let mut pos = end.saturating_sub(2);
+    for command in commands.iter().rev() {
+        let len = serde_json::to_vec(command)?.len() as u64;
+        ranges.push(pos.saturating_sub(len)..pos);
+        pos = pos.saturating_sub(len + 1);
+    }
+    ranges.reverse();
+    Ok(ranges)
+}
+
+/// Takes the exclusive advisory lock of the data directory `dir`, failing
+/// with `MyError::AlreadyLocked` if another process has it.
+pub(crate) fn lock_dir(dir: &Path) -> Result<File> {
+    let lock = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(LOCK_FILE))
+        .map_err(MyError::file(&dir.join(LOCK_FILE)))?;
+    match lock.try_lock_exclusive() {
+        Ok(()) => Ok(lock),
+        Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+            Err(MyError::AlreadyLocked {
+                path: dir.to_owned(),
+            })
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A value read as `Bytes`, for the callers that want a `String`.
+fn into_string(value: Bytes) -> Result<String> {
+    Ok(String::from_utf8(value.to_vec())?)
+}
+
+/// A handle reading a `KvStore` from any thread, without waiting for its
+/// writes or compactions.
+///
+/// Each read sees the store as of one write: a scan or a batch is never
+/// seen half-applied.
+#[derive(Clone)]
+pub struct KvReader {
+    current: Arc<RwLock<Arc<View>>>,
+    cache: Option<Arc<ValueCache>>,
+    metrics: Option<Metrics>,
+}
+
+impl KvReader {
+    /// Pins the current view, so that a compaction finishing meanwhile
+    /// does not pull the log from under the read.
+    fn view(&self) -> Arc<View> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+}
+
+impl KvsReader for KvReader {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key)?.map(into_string).transpose()
+    }
+
+    /// Hands out the cached value itself on a hit.
+    fn get_bytes(&self, key: String) -> Result<Option<Bytes>> {
+        let started = Instant::now();
+        let value = self.view().get(&key, self.cache.as_deref());
+        if let Some(metrics) = &self.metrics {
+            metrics.record_get(&key, &value, started);
+        }
+        value
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.view().scan(&prefix)
+    }
+}
+
+/// A log file and the index of the values in it, replaced as a whole by
+/// compaction.
+struct View {
+    path: PathBuf,
+    reader: Mutex<BufReader<File>>,
+    /// Reads the value log, if there is one.
+    values: Option<Mutex<BufReader<File>>>,
+    values_path: Option<PathBuf>,
+    index: RwLock<BTreeMap<String, Pointer>>,
+    merge_operator: Option<MergeOperator>,
+    /// Reads records and separated values instead of the readers, if set.
+    ring: Option<Arc<Ring>>,
+}
+
+impl View {
+    fn open(
+        path: &Path,
+        values: Option<&Path>,
+        index: BTreeMap<String, Pointer>,
+        merge_operator: Option<MergeOperator>,
+        ring: Option<Arc<Ring>>,
+        read_buffer: usize,
+    ) -> Result<View> {
+        let open = |path: &Path| {
+            let file = File::open(path).map_err(MyError::file(path))?;
+            Ok::<_, MyError>(Mutex::new(BufReader::with_capacity(read_buffer, file)))
+        };
+        Ok(View {
+            path: path.to_owned(),
+            reader: open(path)?,
+            values: match values {
+                Some(values) => Some(open(values)?),
+                None => None,
+            },
+            values_path: values.map(Path::to_owned),
+            index: RwLock::new(index),
+            merge_operator,
+            ring,
+        })
+    }
+
+    /// Whether `key` is set and not expired.
+    fn contains(&self, key: &str) -> bool {
+        let index = self.index.read().unwrap();
+        index
+            .get(key)
+            .is_some_and(|pointer| !pointer.is_expired(unix_millis()))
+    }
+
+    /// The pointer to the value of `key`, unless it expired by `now`.
+    fn live(&self, key: &str, now: u64) -> Option<Pointer> {
+        let index = self.index.read().unwrap();
+        index
+            .get(key)
+            .filter(|pointer| !pointer.is_expired(now))
+            .cloned()
+    }
+
+    /// The value of `key`, from `cache` if it has it.
+    fn get(&self, key: &str, cache: Option<&ValueCache>) -> Result<Option<Bytes>> {
+        let pointer = self.index.read().unwrap().get(key).cloned();
+        let pointer = match pointer.filter(|pointer| !pointer.is_expired(unix_millis())) {
+            Some(pointer) => pointer,
+            None => return Ok(None),
+        };
+        let cache = match cache {
+            Some(cache) => cache,
+            None => return Ok(Some(self.read_value(&pointer)?.into())),
+        };
+        if let Some(value) = cache.get(key, pointer.last_seq()) {
+            return Ok(Some(value));
+        }
+        let value = Bytes::from(self.read_value(&pointer)?);
+        cache.insert(key.to_owned(), pointer.last_seq(), value.clone());
+        Ok(Some(value))
+    }
+
+    /// Every key/value pair whose key starts with `prefix`, as of one write.
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        // the pointers stay valid after the lock is released: the log is
+        // only appended to
+        let now = unix_millis();
+        let pointers: Vec<(String, Pointer)> = self
+            .index
+            .read()
+            .unwrap()
+            .range(prefix.to_owned()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(_, pointer)| !pointer.is_expired(now))
+            .map(|(key, pointer)| (key.clone(), pointer.clone()))
+            .collect();
+        pointers
+            .into_iter()
+            .map(|(key, pointer)| Ok((key, self.read_value(&pointer)?)))
+            .collect()
+    }
+
+    /// The value `pointer` sets, combined with the merge operands that
+    /// followed it.
+    fn read_value(&self, pointer: &Pointer) -> Result<String> {
+        let mut value = match self.read_command(pointer)? {
+            Command::Set { value, .. } => value,
+            Command::Merge { key, operand } => self.merge(&key, None, &operand)?,
+            _ => return Err(MyError::KeyNotFound),
+        };
+        for merge in &pointer.merges {
+            if let Command::Merge { key, operand } = self.read_command(merge)? {
+                value = self.merge(&key, Some(&value), &operand)?;
+            }
+        }
+        Ok(value)
+    }
+
+    fn merge(&self, key: &str, value: Option<&str>, operand: &str) -> Result<String> {
+        match &self.merge_operator {
+            Some(MergeOperator(operator)) => Ok(operator(key, value, operand)),
+            None => Err(MyError::StringError(format!(
+                "`{}` has merge operands, but the store has no merge operator",
+                key
+            ))),
+        }
+    }
+
+    /// The value added to a list or a hash by the record at `pointer`.
+    fn read_element(&self, pointer: &Pointer) -> Result<String> {
+        match self.read_record(pointer)?.command {
+            Command::Push { value, .. } | Command::HSet { value, .. } => Ok(value),
+            _ => Err(MyError::Corrupt {
+                path: Some(self.path.clone()),
+                offset: Some(pointer.pos),
+                reason: "no push or hash set".to_owned(),
+            }),
+        }
+    }
+
+    /// The command of the record at `pointer`, with its value read back
+    /// from the value log if it was separated.
+    fn read_command(&self, pointer: &Pointer) -> Result<Command> {
+        let record = self.read_record(pointer)?;
+        match (record.command, record.vlog) {
+            (Command::Set { key, .. }, Some(vlog)) => Ok(Command::Set {
+                key,
+                value: self.read_separated_string(&vlog)?,
+            }),
+            (command, _) => Ok(command),
+        }
+    }
+
+    /// A separated value, checked against its checksum.
+    fn read_separated_string(&self, vlog: &ValueRef) -> Result<String> {
+        String::from_utf8(self.read_separated(vlog)?).map_err(|_| MyError::Corrupt {
+            path: self.values_path.clone(),
+            offset: Some(vlog.pos),
+            reason: "invalid UTF-8 value".to_owned(),
+        })
+    }
+
+    /// The bytes of a separated value, checked against its checksum.
+    fn read_separated(&self, vlog: &ValueRef) -> Result<Vec<u8>> {
+        let values = self
+            .values
+            .as_ref()
+            .ok_or_else(|| MyError::corrupt("value log missing"))?;
+        let mut values = values.lock().unwrap();
+        let mut value = Vec::new();
+        match &self.ring {
+            Some(ring) => {
+                value.resize(vlog.len as usize, 0);
+                ring.read_exact_at(values.get_ref(), &mut value, vlog.pos)?;
+            }
+            None => {
+                values.seek(SeekFrom::Start(vlog.pos))?;
+                (&mut *values).take(vlog.len).read_to_end(&mut value)?;
+            }
+        }
+        if value.len() as u64 != vlog.len || crc32fast::hash(&value) != vlog.crc {
+            return Err(MyError::Corrupt {
+                path: self.values_path.clone(),
+                offset: Some(vlog.pos),
+                reason: "checksum mismatch".to_owned(),
+            });
+        }
+        Ok(value)
+    }
+
+    fn read_record(&self, pointer: &Pointer) -> Result<Record> {
+        let mut reader = self.reader.lock().unwrap();
+        let mut record = vec![0; pointer.len as usize];
+        match &self.ring {
+            Some(ring) => ring.read_exact_at(reader.get_ref(), &mut record, pointer.pos)?,
+            None => {
+                reader.seek(SeekFrom::Start(pointer.pos))?;
+                reader.read_exact(&mut record)?;
+            }
+        }
+        decode_record(&record)
+    }
+}
+
+/// A write, as returned by `KvStore::changes_since`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    /// Sequence number of the write in the log.
+    pub seq: u64,
+    pub command: Command,
+    /// When the key set expires, in milliseconds since the UNIX epoch.
+    pub expires: Option<u64>,
+}
+
+/// What `KvStore::open_verified` checked.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Records of the log, checked against their checksums.
+    pub records: u64,
+    /// Keys whose writes were read back through the index.
+    pub keys: u64,
+    /// Values read back from the value log and checked.
+    pub separated_values: u64,
+    /// Each problem found; the store is only handed out without any.
+    pub problems: Vec<String>,
+    /// How long opening and checking took.
+    pub elapsed: Duration,
+}
+
+/// A version of a key, as returned by `KvStore::get_history`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyVersion {
+    /// Sequence number of the write in the log.
+    pub seq: u64,
+    /// The value written, or `None` if the key was removed.
+    pub value: Option<String>,
+}
+
+/// A log record: a command with the sequence number of its write, or of
+/// the last write of a batch, written in the envelope `LogFormat` asks for.
+///
+/// `crc` is the CRC-32 of the command as serde_json writes it.
+///
+/// Records written before sequence numbers and checksums existed, and the
+/// writes inside a batch, carry neither and decode with `seq` 0 and no
+/// `crc`.
+///
+/// A set whose value was separated carries an empty value and `vlog`,
+/// where the value is in the value log. A set with a TTL carries
+/// `expires`, in milliseconds since the UNIX epoch.
+///
+/// `written` is when the record was appended, in milliseconds since the
+/// UNIX epoch; records older than timestamps have none. A set rewritten by
+/// compaction keeps the time of its write, and carries `created` when the
+/// key was created before.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Record {
+    #[serde(default)]
+    pub(crate) seq: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) crc: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) vlog: Option<ValueRef>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expires: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) written: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) created: Option<u64>,
+    #[serde(flatten)]
+    pub(crate) command: Command,
+}
+
+impl Record {
+    pub(crate) fn new(seq: u64, command: Command) -> Result<Record> {
+        let crc = crc32fast::hash(&serde_json::to_vec(&command)?);
+        Ok(Record {
+            seq,
+            crc: Some(crc),
+            vlog: None,
+            expires: None,
+            written: Some(unix_millis()),
+            created: None,
+            command,
+        })
+    }
+
+    /// Fails with `MyError::Corrupt` if the command does not match the
+    /// checksum.
+    pub(crate) fn verify(&self) -> Result<()> {
+        match self.crc {
+            Some(crc) if crc != crc32fast::hash(&serde_json::to_vec(&self.command)?) => Err(
+                MyError::corrupt(format!("checksum mismatch in record {}", self.seq)),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Where a separated value lies in the value log of generation `gen`, and
+/// the CRC-32 of its bytes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub(crate) struct ValueRef {
+    pub(crate) gen: u64,
+    pub(crate) pos: u64,
+    pub(crate) len: u64,
+    pub(crate) crc: u32,
+}
+
+/// Command is an enum with each possible command of the database. Each enum
+/// command will be serialized to a log file and used as the basis for populating/
+/// updating an in-memory key/value store.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Command {
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    /// Adds `value` to the list `key`, at its front if `front`, else at
+    /// its back.
+    Push {
+        key: String,
+        value: String,
+        front: bool,
+    },
+    /// Takes the value at the front of the list `key` if `front`, else at
+    /// its back.
+    Pop {
+        key: String,
+        front: bool,
+    },
+    /// Sets `field` of the hash `key` to `value`.
+    HSet {
+        key: String,
+        field: String,
+        value: String,
+    },
+    /// Removes `field` of the hash `key`.
+    HDel {
+        key: String,
+        field: String,
+    },
+    /// Adds `member` to the set `key`.
+    SAdd {
+        key: String,
+        member: String,
+    },
+    /// Removes `member` from the set `key`.
+    SRem {
+        key: String,
+        member: String,
+    },
+    /// Sets the score of `member` in the sorted set `key`.
+    ZAdd {
+        key: String,
+        member: String,
+        score: f64,
+    },
+    /// Combines `operand` with the value of the string `key`; see
+    /// `KvStore::merge`.
+    Merge {
+        key: String,
+        operand: String,
+    },
+    /// Writes applied together; see `KvsEngine::write_batch`.
+    Batch(Vec<Command>),
+    /// Makes the strings `keys` expire at `expires`, in milliseconds since
+    /// the UNIX epoch, keeping their values; see `KvsEngine::touch`.
+    Touch {
+        keys: Vec<String>,
+        expires: u64,
+    },
+}
+
+impl Command {
+    fn set(key: String, value: String) -> Command {
+        Command::Set { key, value }
+    }
+
+    // fn get(key: String) -> Command {
+    //     Command::Get { key }
+    // }
+
+    fn remove(key: String) -> Command {
+        Command::Remove { key }
+    }
+
+    /// The key written to; batches and touches have none.
+    fn key(&self) -> &str {
+        match self {
+            Command::Set { key, .. }
+            | Command::Remove { key }
+            | Command::Push { key, .. }
+            | Command::Pop { key, .. }
+            | Command::HSet { key, .. }
+            | Command::HDel { key, .. }
+            | Command::SAdd { key, .. }
+            | Command::SRem { key, .. }
+            | Command::ZAdd { key, .. }
+            | Command::Merge { key, .. } => key,
+            Command::Batch(_) | Command::Touch { .. } => "",
+        }
+    }
+}
+
+/// What reading the log builds, saved on a clean shutdown.
+#[derive(Serialize, Deserialize)]
+struct SavedIndex<'a> {
+    log_len: u64,
+    value_log_len: u64,
+    next_seq: u64,
+    compacted_seq: u64,
+    uncompacted: u64,
+    value_gen: u64,
+    value_garbage: u64,
+    index: Cow<'a, BTreeMap<String, Pointer>>,
+    history: Cow<'a, HashMap<String, Vec<Pointer>>>,
+    lists: Cow<'a, HashMap<String, VecDeque<Pointer>>>,
+    hashes: Cow<'a, HashMap<String, BTreeMap<String, Pointer>>>,
+    sets: Cow<'a, HashMap<String, BTreeMap<String, Pointer>>>,
+    /// Members with their score and the pointer to their addition.
+    sorted_sets: HashMap<String, Vec<(String, f64, Pointer)>>,
+    /// The keys of the cached values, least recently used first.
+    hot_keys: Vec<String>,
+}
+
+/// Represents the position and length of a json-serialized command in the
+/// log, and the sequence number of its write.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Pointer {
+    pos: u64,
+    len: u64,
+    seq: u64,
+    /// Length of the value in the value log, 0 if it is in the log.
+    value_len: u64,
+    /// When the key expires, in milliseconds since the UNIX epoch.
+    expires: Option<u64>,
+    /// The merge operands applied to the value since, in order.
+    merges: Vec<Pointer>,
+    /// When the write was made, in milliseconds since the UNIX epoch.
+    #[serde(default)]
+    updated: Option<u64>,
+    /// When the key was created, if before the write.
+    #[serde(default)]
+    created: Option<u64>,
+}
+
+impl Pointer {
+    fn new(range: Range<u64>, seq: u64) -> Pointer {
+        Pointer {
+            pos: range.start,
+            len: range.end - range.start,
+            seq,
+            value_len: 0,
+            expires: None,
+            merges: Vec::new(),
+            updated: None,
+            created: None,
+        }
+    }
+
+    /// The pointer to `record`, knowing where its value is, when it
+    /// expires and when it was written.
+    fn for_record(mut self, record: &Record) -> Pointer {
+        self.value_len = record.vlog.map_or(0, |vlog| vlog.len);
+        self.expires = record.expires;
+        self.updated = record.written;
+        self.created = record.created;
+        self
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// Sequence number of the last write to the value, merges included.
+    fn last_seq(&self) -> u64 {
+        self.merges.last().map_or(self.seq, |merge| merge.seq)
+    }
+
+    /// When the value was last written, merges included.
+    fn last_updated(&self) -> Option<u64> {
+        self.merges
+            .last()
+            .map_or(self.updated, |merge| merge.updated)
+    }
+
+    /// Bytes of the records of the value, merges included.
+    fn stale_len(&self) -> u64 {
+        self.len + self.merges.iter().map(|merge| merge.len).sum::<u64>()
+    }
 }
@@ -0,0 +1,40 @@
+//! Notifications of the writes an engine applies.
+use serde::{Deserialize, Serialize};
+
+/// What a write did to its key, one kind per log command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyOp {
+    Set,
+    Remove,
+    Push,
+    Pop,
+    HSet,
+    HDel,
+    SAdd,
+    SRem,
+    ZAdd,
+    Merge,
+    /// The expiry of the key changed, not its value.
+    Touch,
+}
+
+/// A write applied to `key`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyEvent {
+    pub key: String,
+    pub op: KeyOp,
+    /// Sequence number of the write in the log.
+    pub seq: u64,
+    /// When the write was applied, in milliseconds since the UNIX epoch.
+    pub timestamp: u64,
+}
+
+/// Receives the events of an engine, registered with
+/// `KvsEngine::add_listener`.
+///
+/// Listeners are called on the writing thread once the write is in the
+/// log, in sequence order, so they should hand events off rather than
+/// block.
+pub trait EventListener: Send + Sync {
+    fn on_event(&self, event: &KeyEvent);
+}
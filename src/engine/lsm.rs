@@ -0,0 +1,947 @@
+//! A log-structured merge tree: writes land in a memtable backed by a
+//! write-ahead log, which is flushed to immutable sorted tables that
+//! leveled compaction merges down.
+use crate::engine::kvs::lock_dir;
+use crate::engine::{
+    bucket_dir, copy_synced, in_compaction_window, Command, CompactionLog, CompactionStats,
+    CompactionTrigger, CompactionWindow, EngineStats, KvsEngine, KvsReader, Throttle, WriteBatch,
+};
+use crate::{MyError, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Instant, SystemTime};
+
+/// Write-ahead log of the memtable, in the data directory.
+const WAL_FILE: &str = "lsm-wal.json";
+
+/// Tables of each level, in the data directory.
+const MANIFEST_FILE: &str = "lsm-manifest.json";
+
+/// Temporary file the manifest is written to before replacing it.
+const MANIFEST_TEMP_FILE: &str = "lsm-manifest.json.tmp";
+
+/// Extension of table files, named after their id.
+const TABLE_EXTENSION: &str = "sst";
+
+/// Number of tables level 0 holds before they are merged into level 1.
+const L0_TABLES: usize = 4;
+
+/// How much larger each level from 1 on may grow than the previous one.
+const LEVEL_RATIO: u64 = 10;
+
+/// Entries between two keys of a table's sparse index.
+const INDEX_INTERVAL: usize = 16;
+
+/// Bloom filter bits per key, for about 1% false positives.
+const BLOOM_BITS_PER_KEY: usize = 10;
+
+/// Hash functions of the bloom filters.
+const BLOOM_HASHES: u32 = 7;
+
+/// A key with its value, or `None` for a removal.
+type Entry = (String, Option<String>);
+
+/// Tuning knobs of an `LsmEngine`.
+#[derive(Clone, Debug)]
+pub struct LsmOptions {
+    memtable_bytes: u64,
+    table_bytes: u64,
+    compaction_windows: Vec<CompactionWindow>,
+    compaction_rate: Option<u64>,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_bytes: 4 * 1024 * 1024,
+            table_bytes: 2 * 1024 * 1024,
+            compaction_windows: Vec::new(),
+            compaction_rate: None,
+        }
+    }
+}
+
+impl LsmOptions {
+    /// Flush the memtable to a table once its keys and values take more
+    /// than `bytes`.
+    pub fn with_memtable_bytes(mut self, bytes: u64) -> Self {
+        self.memtable_bytes = bytes;
+        self
+    }
+
+    /// Split the tables compaction writes at about `bytes`. Level 1 holds
+    /// ten such tables, and every further level ten times more.
+    pub fn with_table_bytes(mut self, bytes: u64) -> Self {
+        self.table_bytes = bytes;
+        self
+    }
+
+    /// Only compact within `windows`, e.g. at night; full memtables are
+    /// still flushed to level 0, which is merged down at the first flush
+    /// within a window. By default compaction runs whenever needed.
+    pub fn with_compaction_windows(mut self, windows: Vec<CompactionWindow>) -> Self {
+        self.compaction_windows = windows;
+        self
+    }
+
+    /// Write at most `bytes` of tables per second on average when
+    /// compacting. Writes wait for the compaction they set off meanwhile.
+    /// By default it is not throttled.
+    pub fn with_compaction_rate(mut self, bytes: u64) -> Self {
+        self.compaction_rate = Some(bytes);
+        self
+    }
+}
+
+/// The `LsmEngine` stores string key/value pairs in a log-structured merge
+/// tree.
+///
+/// Writes go to a write-ahead log and an in-memory sorted memtable. A full
+/// memtable is flushed to a sorted table on level 0; once level 0 has too
+/// many tables they are merged into level 1, and each further level is
+/// merged into the next one table at a time when it outgrows its size.
+/// Tables of a level from 1 on never overlap, so a read checks the memtable,
+/// the tables of level 0, then at most one table per level, skipping those
+/// whose bloom filter rules the key out.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvsEngine, LsmEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// let mut store = LsmEngine::open(std::env::current_dir()?)?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct LsmEngine {
+    dir: PathBuf,
+    state: Arc<RwLock<State>>,
+    wal: BufWriter<File>,
+    /// Id of the next table.
+    next_id: u64,
+    options: LsmOptions,
+    last_compaction: Option<SystemTime>,
+    compactions: CompactionLog,
+    /// Holds the lock on the data directory until the store is dropped.
+    _lock: File,
+}
+
+/// What reads go through, changed under its lock by writes, flushes and
+/// compactions.
+#[derive(Default)]
+struct State {
+    memtable: BTreeMap<String, Option<String>>,
+    /// Bytes of the keys and values written to the memtable.
+    memtable_bytes: u64,
+    /// Level 0 newest first, then every further level ordered by key.
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+impl State {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(value.clone());
+        }
+        // newest first; tables not covering the key are skipped right away
+        for table in self.levels.iter().flatten() {
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        // oldest first, so that newer entries replace older ones
+        let mut entries = BTreeMap::new();
+        for tables in self.levels.iter().rev() {
+            for table in tables.iter().rev() {
+                for entry in table.scan(prefix)? {
+                    let (key, value) = entry?;
+                    entries.insert(key, value);
+                }
+            }
+        }
+        let memtable = self
+            .memtable
+            .range(prefix.to_owned()..)
+            .take_while(|(key, _)| key.starts_with(prefix));
+        for (key, value) in memtable {
+            entries.insert(key.clone(), value.clone());
+        }
+        Ok(entries
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect())
+    }
+}
+
+impl KvsEngine for LsmEngine {
+    type Reader = LsmReader;
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.write(vec![Command::Set { key, value }])
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.state.read().unwrap().get(&key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(MyError::KeyNotFound);
+        }
+        self.write(vec![Command::Remove { key }])
+    }
+
+    /// Logs the batch as one record, so that a crash leaves all of it or,
+    /// once the torn record is dropped, none of it.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let state = self.state.read().unwrap();
+        batch.check_removes(|key| Ok(state.get(key)?.is_some()))?;
+        drop(state);
+        self.write(batch.commands)
+    }
+
+    fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.state.read().unwrap().scan(&prefix)
+    }
+
+    /// Drops every table, which is removed once the last reader using it
+    /// is done, then empties the memtable and its log.
+    fn clear(&mut self) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+        let tables: Vec<Arc<Table>> = state.levels.drain(..).flatten().collect();
+        state.memtable.clear();
+        state.memtable_bytes = 0;
+        self.save_manifest(&state)?;
+        drop(state);
+        for table in tables {
+            table.obsolete.store(true, Ordering::SeqCst);
+        }
+
+        let wal = self.wal.get_mut();
+        wal.set_len(0)?;
+        wal.seek(SeekFrom::Start(0))?;
+        wal.sync_all()?;
+        Ok(())
+    }
+
+    /// Copies the write-ahead log, the manifest and the tables it lists;
+    /// tables are never changed once written, and compactions only run
+    /// during writes.
+    fn snapshot(&mut self, dir: &Path) -> Result<()> {
+        self.wal.flush()?;
+        fs::create_dir_all(dir).map_err(MyError::file(dir))?;
+        copy_synced(&self.dir.join(WAL_FILE), &dir.join(WAL_FILE))?;
+        let manifest = self.dir.join(MANIFEST_FILE);
+        if manifest.exists() {
+            copy_synced(&manifest, &dir.join(MANIFEST_FILE))?;
+        }
+        let state = self.state.read().unwrap();
+        for table in state.levels.iter().flatten() {
+            copy_synced(&table.path, &table_path(dir, table.id))?;
+        }
+        Ok(())
+    }
+
+    fn open_bucket(&self, name: &str) -> Result<LsmEngine> {
+        LsmEngine::open_with_options(bucket_dir(&self.dir, name)?, self.options.clone())
+    }
+
+    fn reader(&self) -> LsmReader {
+        LsmReader {
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "lsm"
+    }
+
+    /// Counting keys merges every level, so it takes a full scan.
+    fn stats(&mut self) -> Result<EngineStats> {
+        let state = self.state.read().unwrap();
+        let tables = state.levels.iter().flatten();
+        Ok(self.compactions.report(EngineStats {
+            key_count: state.scan("")?.len() as u64,
+            disk_usage: fs::metadata(self.dir.join(WAL_FILE))?.len()
+                + tables.clone().map(|table| table.size).sum::<u64>(),
+            uncompacted_bytes: 0,
+            segment_count: tables.count() as u64,
+            last_compaction: self.last_compaction,
+            ..EngineStats::default()
+        }))
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        self.wal.flush()?;
+        self.wal.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+impl LsmEngine {
+    /// Open the LsmEngine at a given path.
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmEngine> {
+        LsmEngine::open_with_options(path, LsmOptions::default())
+    }
+
+    /// Open the LsmEngine at a given path, tuned by `options`.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: LsmOptions) -> Result<LsmEngine> {
+        let dir = path.into();
+        fs::create_dir_all(&dir)?;
+        let lock = lock_dir(&dir)?;
+
+        let manifest = match fs::read(dir.join(MANIFEST_FILE)) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut levels = Vec::with_capacity(manifest.levels.len());
+        for ids in &manifest.levels {
+            let tables = ids
+                .iter()
+                .map(|&id| Table::open(table_path(&dir, id), id).map(Arc::new))
+                .collect::<Result<Vec<_>>>()?;
+            levels.push(tables);
+        }
+        // left behind by a flush or compaction that did not complete
+        let live: HashSet<u64> = manifest.levels.iter().flatten().copied().collect();
+        for file in fs::read_dir(&dir)? {
+            let path = file?.path();
+            let stray = path.extension().is_some_and(|ext| ext == TABLE_EXTENSION)
+                && path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str()?.parse().ok())
+                    .is_some_and(|id| !live.contains(&id));
+            if stray {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let wal_path = dir.join(WAL_FILE);
+        let mut state = State {
+            levels,
+            ..State::default()
+        };
+        replay_wal(&wal_path, &mut state)?;
+        let mut wal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&wal_path)?;
+        wal.seek(SeekFrom::End(0))?;
+
+        Ok(LsmEngine {
+            dir,
+            state: Arc::new(RwLock::new(state)),
+            wal: BufWriter::new(wal),
+            next_id: manifest.next_id.max(1),
+            options,
+            last_compaction: None,
+            compactions: CompactionLog::default(),
+            _lock: lock,
+        })
+    }
+
+    /// Logs `commands` as one record, applies them to the memtable, and
+    /// flushes it if full.
+    fn write(&mut self, commands: Vec<Command>) -> Result<()> {
+        let record = match commands.len() {
+            1 => commands[0].clone(),
+            _ => Command::Batch(commands.clone()),
+        };
+        serde_json::to_writer(&mut self.wal, &record)?;
+        self.wal.write_all(b"\n")?;
+        self.wal.flush()?;
+
+        let mut state = self.state.write().unwrap();
+        apply(&mut state, commands);
+        let full = state.memtable_bytes > self.options.memtable_bytes;
+        drop(state);
+        if full {
+            self.flush()?;
+            if in_compaction_window(&self.options.compaction_windows) {
+                self.compact()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the memtable to a new table on level 0 and empties the log.
+    fn flush(&mut self) -> Result<()> {
+        let entries: Vec<Entry> = self
+            .state
+            .read()
+            .unwrap()
+            .memtable
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        let id = self.take_id();
+        let table = Arc::new(write_table(&self.dir, id, entries)?);
+
+        let mut state = self.state.write().unwrap();
+        if state.levels.is_empty() {
+            state.levels.push(Vec::new());
+        }
+        state.levels[0].insert(0, table);
+        state.memtable.clear();
+        state.memtable_bytes = 0;
+        self.save_manifest(&state)?;
+        drop(state);
+
+        let wal = self.wal.get_mut();
+        wal.set_len(0)?;
+        wal.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+
+    /// Merges level 0 into level 1 while it has too many tables, and a
+    /// table of each further level into the next while that level is too
+    /// large.
+    fn compact(&mut self) -> Result<()> {
+        let mut throttle = Throttle::new(self.options.compaction_rate);
+        loop {
+            let started = Instant::now();
+            let levels = self.state.read().unwrap().levels.clone();
+            let (level, inputs, trigger) = if levels[0].len() > L0_TABLES {
+                (0, levels[0].clone(), CompactionTrigger::TableCount)
+            } else {
+                let mut limit = self.options.table_bytes * LEVEL_RATIO;
+                let oversized = (1..levels.len()).find(|&level| {
+                    let size: u64 = levels[level].iter().map(|table| table.size).sum();
+                    let over = size > limit;
+                    limit *= LEVEL_RATIO;
+                    over
+                });
+                match oversized {
+                    Some(level) => (
+                        level,
+                        vec![Arc::clone(&levels[level][0])],
+                        CompactionTrigger::LevelSize,
+                    ),
+                    None => return Ok(()),
+                }
+            };
+            let target = level + 1;
+            let min = inputs.iter().map(|table| &table.min).min().unwrap().clone();
+            let max = inputs.iter().map(|table| &table.max).max().unwrap().clone();
+            let overlapping: Vec<Arc<Table>> = levels
+                .get(target)
+                .into_iter()
+                .flatten()
+                .filter(|table| table.max >= min && table.min <= max)
+                .cloned()
+                .collect();
+            // removals only need to hide older values below the target
+            let bottom = levels[target.min(levels.len())..]
+                .iter()
+                .skip(1)
+                .all(Vec::is_empty);
+
+            let sources = inputs.iter().chain(&overlapping);
+            let merged = merge(sources.map(|table| table.iter()).collect::<Result<_>>()?);
+            let mut outputs = Vec::new();
+            let mut builder: Option<TableBuilder> = None;
+            let mut records_rewritten = 0;
+            for entry in merged {
+                let (key, value) = entry?;
+                if value.is_none() && bottom {
+                    continue;
+                }
+                let table = match &mut builder {
+                    Some(table) => table,
+                    None => {
+                        let id = self.take_id();
+                        builder.insert(TableBuilder::create(&self.dir, id)?)
+                    }
+                };
+                let offset = table.offset;
+                table.add(&key, &value)?;
+                throttle.wrote(table.offset - offset);
+                records_rewritten += 1;
+                if table.offset >= self.options.table_bytes {
+                    outputs.push(Arc::new(builder.take().unwrap().finish()?));
+                }
+            }
+            if let Some(table) = builder {
+                outputs.push(Arc::new(table.finish()?));
+            }
+
+            let size_before: u64 = inputs.iter().chain(&overlapping).map(|t| t.size).sum();
+            let size_after: u64 = outputs.iter().map(|t| t.size).sum();
+            let mut state = self.state.write().unwrap();
+            let replaced: HashSet<u64> = inputs.iter().chain(&overlapping).map(|t| t.id).collect();
+            while state.levels.len() <= target {
+                state.levels.push(Vec::new());
+            }
+            for tables in &mut state.levels[level..=target] {
+                tables.retain(|table| !replaced.contains(&table.id));
+            }
+            state.levels[target].extend(outputs);
+            state.levels[target].sort_by(|a, b| a.min.cmp(&b.min));
+            self.save_manifest(&state)?;
+            drop(state);
+            // removed once the last reader using them is done
+            for table in inputs.iter().chain(&overlapping) {
+                table.obsolete.store(true, Ordering::SeqCst);
+            }
+            self.last_compaction = Some(SystemTime::now());
+            self.compactions.record(CompactionStats {
+                bytes_reclaimed: size_before.saturating_sub(size_after),
+                records_rewritten,
+                duration: started.elapsed(),
+                trigger,
+            });
+        }
+    }
+
+    fn take_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    /// Records the tables of `state` and the next table id, atomically.
+    fn save_manifest(&self, state: &State) -> Result<()> {
+        let manifest = Manifest {
+            next_id: self.next_id,
+            levels: state
+                .levels
+                .iter()
+                .map(|tables| tables.iter().map(|table| table.id).collect())
+                .collect(),
+        };
+        let temp_path = self.dir.join(MANIFEST_TEMP_FILE);
+        let mut file = File::create(&temp_path)?;
+        serde_json::to_writer(&mut file, &manifest)?;
+        file.sync_all()?;
+        fs::rename(temp_path, self.dir.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+}
+
+/// Applies `commands` to the memtable.
+fn apply(state: &mut State, commands: Vec<Command>) {
+    for command in commands {
+        let (key, value) = match command {
+            Command::Set { key, value } => (key, Some(value)),
+            Command::Remove { key } => (key, None),
+            Command::Batch(commands) => {
+                apply(state, commands);
+                continue;
+            }
+            Command::Push { .. }
+            | Command::Pop { .. }
+            | Command::HSet { .. }
+            | Command::HDel { .. }
+            | Command::SAdd { .. }
+            | Command::SRem { .. }
+            | Command::ZAdd { .. }
+            | Command::Merge { .. }
+            | Command::Touch { .. } => {
+                unreachable!("lsm has no lists, hashes, sets, merges or touches")
+            }
+        };
+        state.memtable_bytes += (key.len() + value.as_ref().map_or(0, String::len)) as u64;
+        state.memtable.insert(key, value);
+    }
+}
+
+/// Loads the writes logged since the last flush into the memtable.
+///
+/// A record cut short by a crash is dropped with the rest of the log.
+fn replay_wal(path: &Path, state: &mut State) -> Result<()> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut stream = serde_json::Deserializer::from_slice(&data).into_iter::<Command>();
+    let mut offset = 0;
+    loop {
+        match stream.next() {
+            Some(Ok(command)) => {
+                apply(state, vec![command]);
+                offset = stream.byte_offset();
+            }
+            Some(Err(e)) if e.is_eof() => {
+                warn!("Dropping a torn record at the end of {}", path.display());
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)?
+                    .set_len(offset as u64)?;
+                return Ok(());
+            }
+            Some(Err(e)) => {
+                return Err(MyError::Corrupt {
+                    path: Some(path.to_owned()),
+                    offset: Some(offset as u64),
+                    reason: e.to_string(),
+                })
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+/// A handle reading an `LsmEngine` from any thread.
+#[derive(Clone)]
+pub struct LsmReader {
+    state: Arc<RwLock<State>>,
+}
+
+impl KvsReader for LsmReader {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.state.read().unwrap().get(&key)
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.state.read().unwrap().scan(&prefix)
+    }
+}
+
+/// Tables of each level, as saved in the manifest.
+#[derive(Default, Serialize, Deserialize)]
+struct Manifest {
+    next_id: u64,
+    levels: Vec<Vec<u64>>,
+}
+
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.{}", id, TABLE_EXTENSION))
+}
+
+/// An immutable file of entries sorted by key.
+///
+/// The entries are JSON arrays, one per line, followed by the footer and
+/// the footer's offset as 8 big-endian bytes.
+struct Table {
+    id: u64,
+    path: PathBuf,
+    min: String,
+    max: String,
+    /// Size of the file.
+    size: u64,
+    /// Where the entries end and the footer starts.
+    data_end: u64,
+    /// Every `INDEX_INTERVAL`th key with its offset.
+    index: Vec<(String, u64)>,
+    bloom: Bloom,
+    reader: Mutex<BufReader<File>>,
+    /// Set once compaction replaced the table, which is then removed when
+    /// dropped.
+    obsolete: AtomicBool,
+}
+
+/// Metadata at the end of a table.
+#[derive(Serialize, Deserialize)]
+struct Footer {
+    min: String,
+    max: String,
+    index: Vec<(String, u64)>,
+    bloom: Bloom,
+}
+
+impl Table {
+    fn open(path: PathBuf, id: u64) -> Result<Table> {
+        let mut file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        let corrupt = || MyError::Corrupt {
+            path: Some(path.clone()),
+            offset: None,
+            reason: "table is truncated".to_owned(),
+        };
+        let footer_end = size.checked_sub(8).ok_or_else(corrupt)?;
+        file.seek(SeekFrom::Start(footer_end))?;
+        let mut offset = [0; 8];
+        file.read_exact(&mut offset)?;
+        let data_end = u64::from_be_bytes(offset);
+        if data_end > footer_end {
+            return Err(corrupt());
+        }
+        file.seek(SeekFrom::Start(data_end))?;
+        let mut footer = vec![0; (footer_end - data_end) as usize];
+        file.read_exact(&mut footer)?;
+        let footer: Footer = serde_json::from_slice(&footer)?;
+        Ok(Table {
+            id,
+            path,
+            min: footer.min,
+            max: footer.max,
+            size,
+            data_end,
+            index: footer.index,
+            bloom: footer.bloom,
+            reader: Mutex::new(BufReader::new(file)),
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    /// Whether `key` falls within the keys of the table.
+    fn covers(&self, key: &str) -> bool {
+        self.min.as_str() <= key && key <= self.max.as_str()
+    }
+
+    /// The entry of `key`, if the table has one.
+    fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        if !self.covers(key) || !self.bloom.contains(key) {
+            return Ok(None);
+        }
+        let block = self
+            .index
+            .partition_point(|(first, _)| first.as_str() <= key);
+        let mut pos = match block {
+            0 => return Ok(None),
+            _ => self.index[block - 1].1,
+        };
+        let mut reader = self.reader.lock().unwrap();
+        reader.seek(SeekFrom::Start(pos))?;
+        let mut line = String::new();
+        while pos < self.data_end {
+            line.clear();
+            pos += reader.read_line(&mut line)? as u64;
+            let (found, value): Entry = serde_json::from_str(&line)?;
+            if found == key {
+                return Ok(Some(value));
+            }
+            if found.as_str() > key {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    /// The entries whose key starts with `prefix`.
+    fn scan<'a>(&self, prefix: &'a str) -> Result<impl Iterator<Item = Result<Entry>> + 'a> {
+        let block = self
+            .index
+            .partition_point(|(first, _)| first.as_str() < prefix);
+        let start = match block {
+            0 => 0,
+            _ => self.index[block - 1].1,
+        };
+        Ok(self
+            .iter_from(start)?
+            .skip_while(move |entry| matches!(entry, Ok((key, _)) if key.as_str() < prefix))
+            .take_while(move |entry| !matches!(entry, Ok((key, _)) if !key.starts_with(prefix))))
+    }
+
+    /// Every entry, in key order.
+    fn iter(&self) -> Result<TableIter> {
+        self.iter_from(0)
+    }
+
+    /// The entries from offset `start` on, read with a file handle of
+    /// their own.
+    fn iter_from(&self, start: u64) -> Result<TableIter> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        reader.seek(SeekFrom::Start(start))?;
+        Ok(TableIter {
+            path: self.path.clone(),
+            reader,
+            pos: start,
+            end: self.data_end,
+        })
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!("Failed to remove table {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Reads the entries of a table in order.
+struct TableIter {
+    path: PathBuf,
+    reader: BufReader<File>,
+    pos: u64,
+    end: u64,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        if self.pos >= self.end {
+            return None;
+        }
+        let mut line = String::new();
+        let entry = match self.reader.read_line(&mut line) {
+            Ok(0) => Err(MyError::Corrupt {
+                path: Some(self.path.clone()),
+                offset: Some(self.pos),
+                reason: "table ends early".to_owned(),
+            }),
+            Ok(len) => {
+                self.pos += len as u64;
+                serde_json::from_str(&line).map_err(MyError::from)
+            }
+            Err(e) => Err(e.into()),
+        };
+        if entry.is_err() {
+            self.pos = self.end;
+        }
+        Some(entry)
+    }
+}
+
+/// Merges sorted `sources`, newest first, keeping the newest entry of each
+/// key.
+fn merge(sources: Vec<TableIter>) -> impl Iterator<Item = Result<Entry>> {
+    let mut sources: Vec<_> = sources.into_iter().map(Iterator::peekable).collect();
+    std::iter::from_fn(move || {
+        // an error is returned as soon as it comes up
+        for source in &mut sources {
+            if let Some(Err(_)) = source.peek() {
+                return source.next();
+            }
+        }
+        let key = sources
+            .iter_mut()
+            .filter_map(|source| match source.peek() {
+                Some(Ok((key, _))) => Some(key.clone()),
+                _ => None,
+            })
+            .min()?;
+        let mut newest = None;
+        for source in &mut sources {
+            if matches!(source.peek(), Some(Ok((next, _))) if *next == key) {
+                let entry = source.next();
+                newest = newest.or(entry);
+            }
+        }
+        newest
+    })
+}
+
+/// Writes `entries`, sorted by key, as table `id`.
+fn write_table(dir: &Path, id: u64, entries: Vec<Entry>) -> Result<Table> {
+    let mut builder = TableBuilder::create(dir, id)?;
+    for (key, value) in &entries {
+        builder.add(key, value)?;
+    }
+    builder.finish()
+}
+
+/// Writes a table entry by entry.
+struct TableBuilder {
+    id: u64,
+    path: PathBuf,
+    writer: BufWriter<File>,
+    /// Bytes of entries written so far.
+    offset: u64,
+    count: usize,
+    min: Option<String>,
+    max: String,
+    index: Vec<(String, u64)>,
+    hashes: Vec<u64>,
+}
+
+impl TableBuilder {
+    fn create(dir: &Path, id: u64) -> Result<TableBuilder> {
+        let path = table_path(dir, id);
+        Ok(TableBuilder {
+            id,
+            writer: BufWriter::new(File::create(&path)?),
+            path,
+            offset: 0,
+            count: 0,
+            min: None,
+            max: String::new(),
+            index: Vec::new(),
+            hashes: Vec::new(),
+        })
+    }
+
+    /// Appends an entry, whose key must follow the previous ones.
+    fn add(&mut self, key: &str, value: &Option<String>) -> Result<()> {
+        if self.count.is_multiple_of(INDEX_INTERVAL) {
+            self.index.push((key.to_owned(), self.offset));
+        }
+        let mut line = serde_json::to_vec(&(key, value))?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        self.offset += line.len() as u64;
+        self.count += 1;
+        self.min.get_or_insert_with(|| key.to_owned());
+        self.max = key.to_owned();
+        self.hashes.push(hash(key));
+        Ok(())
+    }
+
+    /// Writes the footer and syncs the table to disk.
+    fn finish(mut self) -> Result<Table> {
+        let footer = Footer {
+            min: self.min.unwrap_or_default(),
+            max: self.max,
+            index: self.index,
+            bloom: Bloom::new(&self.hashes),
+        };
+        serde_json::to_writer(&mut self.writer, &footer)?;
+        self.writer.write_all(&self.offset.to_be_bytes())?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        drop(self.writer);
+        Table::open(self.path, self.id)
+    }
+}
+
+/// A bloom filter over the keys of a table.
+#[derive(Serialize, Deserialize)]
+struct Bloom {
+    bits: Vec<u64>,
+}
+
+impl Bloom {
+    fn new(hashes: &[u64]) -> Bloom {
+        let words = (hashes.len() * BLOOM_BITS_PER_KEY).div_ceil(64).max(1);
+        let mut bloom = Bloom {
+            bits: vec![0; words],
+        };
+        for &hash in hashes {
+            for bit in bloom.bits_of(hash) {
+                bloom.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        bloom
+    }
+
+    /// Whether `key` may be in the table; `false` means it surely is not.
+    fn contains(&self, key: &str) -> bool {
+        self.bits_of(hash(key))
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// The bits set for a key of hash `hash`, by double hashing.
+    fn bits_of(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 64;
+        let delta = hash.rotate_left(32) | 1;
+        (0..u64::from(BLOOM_HASHES))
+            .map(move |i| (hash.wrapping_add(i.wrapping_mul(delta)) % len) as usize)
+    }
+}
+
+/// 64-bit FNV-1a, stable across Rust versions unlike `DefaultHasher`, as
+/// the bloom filters are saved on disk.
+fn hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
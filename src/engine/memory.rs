@@ -0,0 +1,493 @@
+//! A volatile engine keeping everything in memory.
+use crate::engine::{
+    check_bucket_name, unix_millis, version_conflict, Command, EngineStats, KeyMeta, KvsEngine,
+    KvsReader, WriteBatch,
+};
+use crate::tenant::TENANT_MARK;
+use crate::{MyError, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Keys the `lru` policy samples to pick the one to evict.
+const LRU_SAMPLES: usize = 5;
+
+/// Which keys make room for new ones once a `MemEngine` reaches its
+/// maximum memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Refuse writes that need more memory.
+    NoEviction,
+    /// Evict the least recently used of a few keys sampled at random, which
+    /// approximates evicting the least recently used key.
+    Lru,
+    /// Evict a key at random.
+    Random,
+    /// Evict the key expiring first, refusing writes once no key has a TTL.
+    TtlFirst,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<EvictionPolicy> {
+        match s {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "lru" => Ok(EvictionPolicy::Lru),
+            "random" => Ok(EvictionPolicy::Random),
+            "ttl-first" => Ok(EvictionPolicy::TtlFirst),
+            _ => Err(MyError::StringError(format!(
+                "Unknown eviction policy `{}`, expected `noeviction`, `lru`, `random` or `ttl-first`",
+                s
+            ))),
+        }
+    }
+}
+
+/// The `MemEngine` keeps key/value pairs in a map in memory only, and
+/// loses them when dropped.
+///
+/// It touches no file, which makes it a fast fake for tests and lets a
+/// server run as a cache, bounded by `with_max_memory`.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvsEngine, MemEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// let mut store = MemEngine::new();
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct MemEngine {
+    state: Arc<RwLock<MemState>>,
+    /// Most bytes of keys and values to hold, and how to stay below it.
+    max_memory: Option<(u64, EvictionPolicy)>,
+}
+
+impl MemEngine {
+    /// Creates an empty `MemEngine`.
+    pub fn new() -> MemEngine {
+        MemEngine::default()
+    }
+
+    /// Holds at most `bytes` of keys and values, making room for writes
+    /// as `policy` says.
+    pub fn with_max_memory(mut self, bytes: u64, policy: EvictionPolicy) -> MemEngine {
+        self.max_memory = Some((bytes, policy));
+        self
+    }
+
+    fn insert(&mut self, key: String, value: String, expires: Option<u64>) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+        if let Some((max, policy)) = self.max_memory {
+            let writes = BTreeMap::from([(key.as_str(), entry_size(&key, &value))]);
+            state.make_room(&writes, max, policy)?;
+        }
+        state.insert(key, value, expires);
+        Ok(())
+    }
+}
+
+impl KvsEngine for MemEngine {
+    type Reader = MemReader;
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.insert(key, value, None)
+    }
+
+    fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires = unix_millis().saturating_add(ttl.as_millis() as u64);
+        self.insert(key, value, Some(expires))
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        get(&self.state, key)
+    }
+
+    fn contains_key(&mut self, key: String) -> Result<bool> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .live(&key, unix_millis())
+            .is_some())
+    }
+
+    fn len(&mut self) -> Result<u64> {
+        let state = self.state.read().unwrap();
+        let now = unix_millis();
+        let expired = state
+            .expiring
+            .iter()
+            .take_while(|(expires, _)| *expires <= now)
+            .count();
+        Ok((state.map.len() - expired) as u64)
+    }
+
+    fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        let now = unix_millis();
+        let state = self.state.read().unwrap();
+        let entry = state.live(&key, now).ok_or(MyError::KeyNotFound)?;
+        Ok(entry
+            .expires
+            .map(|expires| Duration::from_millis(expires - now)))
+    }
+
+    fn metadata(&mut self, key: String) -> Result<KeyMeta> {
+        let state = self.state.read().unwrap();
+        let entry = state
+            .live(&key, unix_millis())
+            .ok_or(MyError::KeyNotFound)?;
+        Ok(KeyMeta::from_millis(
+            Some(entry.created),
+            Some(entry.updated),
+            entry.version,
+        ))
+    }
+
+    fn set_if_version(&mut self, key: String, version: u64, value: String) -> Result<u64> {
+        let current = self
+            .state
+            .read()
+            .unwrap()
+            .live(&key, unix_millis())
+            .map_or(0, |entry| entry.version);
+        if current != version {
+            return Err(version_conflict(&key, version, current));
+        }
+        self.insert(key, value, None)?;
+        Ok(self.state.read().unwrap().last_version)
+    }
+
+    fn expire(&mut self, key: String, ttl: Duration) -> Result<()> {
+        let value = self.get(key.clone())?.ok_or(MyError::KeyNotFound)?;
+        let expires = unix_millis().saturating_add(ttl.as_millis() as u64);
+        self.insert(key, value, Some(expires))
+    }
+
+    fn persist(&mut self, key: String) -> Result<()> {
+        let value = self.get(key.clone())?.ok_or(MyError::KeyNotFound)?;
+        self.insert(key, value, None)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+        if state.live(&key, unix_millis()).is_none() {
+            return Err(MyError::KeyNotFound);
+        }
+        state.remove(&key);
+        Ok(())
+    }
+
+    /// Applies the batch under one write lock, so readers see all of it or
+    /// none of it.
+    ///
+    /// Room for the whole batch is made before any of it is applied, from
+    /// keys it does not write.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+        let now = unix_millis();
+        batch.check_removes(|key| Ok(state.live(key, now).is_some()))?;
+        if let Some((max, policy)) = self.max_memory {
+            // the size each key ends up with
+            let mut writes = BTreeMap::new();
+            for command in &batch.commands {
+                match command {
+                    Command::Set { key, value } => {
+                        writes.insert(key.as_str(), entry_size(key, value))
+                    }
+                    Command::Remove { key } => writes.insert(key.as_str(), 0),
+                    _ => unreachable!("batches hold sets and removes"),
+                };
+            }
+            state.make_room(&writes, max, policy)?;
+        }
+        for command in batch.commands {
+            match command {
+                Command::Set { key, value } => state.insert(key, value, None),
+                Command::Remove { key } => {
+                    state.remove(&key);
+                }
+                _ => unreachable!("batches hold sets and removes"),
+            }
+        }
+        Ok(())
+    }
+
+    fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        scan(&self.state, prefix)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        *self.state.write().unwrap() = MemState::default();
+        Ok(())
+    }
+
+    /// Takes the expired keys in order of expiry, so only those count
+    /// toward `limit`.
+    fn sweep_expired(&mut self, limit: usize) -> Result<Vec<String>> {
+        let mut state = self.state.write().unwrap();
+        let now = unix_millis();
+        let expired: Vec<String> = state
+            .expiring
+            .iter()
+            .take_while(|(expires, _)| *expires <= now)
+            .take(limit)
+            .map(|(_, key)| key.clone())
+            .collect();
+        for key in &expired {
+            state.remove(key);
+        }
+        Ok(expired)
+    }
+
+    /// Buckets of a memory store start out empty, like the store itself,
+    /// with a memory limit of their own. So do the stores of tenants.
+    fn open_bucket(&self, name: &str) -> Result<MemEngine> {
+        check_bucket_name(name.strip_prefix(TENANT_MARK).unwrap_or(name))?;
+        Ok(MemEngine {
+            state: Arc::default(),
+            max_memory: self.max_memory,
+        })
+    }
+
+    fn reader(&self) -> MemReader {
+        MemReader {
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            key_count: self.len()?,
+            ..EngineStats::default()
+        })
+    }
+}
+
+/// A handle reading a `MemEngine` from any thread.
+#[derive(Clone, Debug)]
+pub struct MemReader {
+    state: Arc<RwLock<MemState>>,
+}
+
+impl KvsReader for MemReader {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        get(&self.state, key)
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        scan(&self.state, prefix)
+    }
+}
+
+/// The keys and values of a `MemEngine`, and what eviction needs to pick
+/// among them.
+#[derive(Debug)]
+struct MemState {
+    map: BTreeMap<String, Entry>,
+    /// Every key, in no particular order, for picking keys at random.
+    slots: Vec<String>,
+    /// Keys with a TTL, by expiry.
+    expiring: BTreeSet<(u64, String)>,
+    /// Bytes of keys and values held.
+    used: u64,
+    /// Ticks on every use of a key.
+    clock: AtomicU64,
+    /// Version of the last value written.
+    last_version: u64,
+    /// xorshift state for picking keys at random.
+    rng: u64,
+}
+
+impl Default for MemState {
+    fn default() -> Self {
+        MemState {
+            map: BTreeMap::new(),
+            slots: Vec::new(),
+            expiring: BTreeSet::new(),
+            used: 0,
+            clock: AtomicU64::new(0),
+            last_version: 0,
+            // any seed but zero, which xorshift would be stuck at
+            rng: 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: String,
+    /// When the key expires, in milliseconds since the UNIX epoch.
+    expires: Option<u64>,
+    /// When the key was created and last written, in milliseconds since
+    /// the UNIX epoch.
+    created: u64,
+    updated: u64,
+    /// Version of the value, see `KvsEngine::set_if_version`.
+    version: u64,
+    /// Index of the key in `MemState::slots`.
+    slot: usize,
+    /// Tick of the clock at the last use of the key.
+    used: AtomicU64,
+}
+
+impl Entry {
+    fn size(&self, key: &str) -> u64 {
+        entry_size(key, &self.value)
+    }
+}
+
+impl MemState {
+    /// The entry of `key` unless it expired, marked as used.
+    fn live(&self, key: &str, now: u64) -> Option<&Entry> {
+        let entry = self
+            .map
+            .get(key)
+            .filter(|entry| entry.expires.is_none_or(|expires| expires > now))?;
+        let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+        entry.used.store(tick, Ordering::Relaxed);
+        Some(entry)
+    }
+
+    fn insert(&mut self, key: String, value: String, expires: Option<u64>) {
+        let now = unix_millis();
+        let created = self.live(&key, now).map_or(now, |entry| entry.created);
+        self.remove(&key);
+        self.last_version += 1;
+        self.used += entry_size(&key, &value);
+        if let Some(expires) = expires {
+            self.expiring.insert((expires, key.clone()));
+        }
+        let entry = Entry {
+            value,
+            expires,
+            created,
+            updated: now,
+            version: self.last_version,
+            slot: self.slots.len(),
+            used: AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed)),
+        };
+        self.slots.push(key.clone());
+        self.map.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.map.remove(key)?;
+        self.used -= entry.size(key);
+        if let Some(expires) = entry.expires {
+            self.expiring.remove(&(expires, key.to_owned()));
+        }
+        self.slots.swap_remove(entry.slot);
+        if let Some(moved) = self.slots.get(entry.slot) {
+            self.map.get_mut(moved).unwrap().slot = entry.slot;
+        }
+        Some(entry)
+    }
+
+    /// Evicts keys as `policy` says until the `writes`, the size each key
+    /// written ends up with, keep within `max` bytes. The keys written are
+    /// never evicted.
+    fn make_room(
+        &mut self,
+        writes: &BTreeMap<&str, u64>,
+        max: u64,
+        policy: EvictionPolicy,
+    ) -> Result<()> {
+        loop {
+            let used = writes.iter().fold(self.used, |used, (key, size)| {
+                let current = self.map.get(*key).map_or(0, |entry| entry.size(key));
+                used - current + size
+            });
+            if used <= max {
+                return Ok(());
+            }
+            let victim = match policy {
+                EvictionPolicy::NoEviction => None,
+                EvictionPolicy::Lru => self.sample_lru(writes),
+                EvictionPolicy::Random => self.random_key(writes),
+                EvictionPolicy::TtlFirst => self
+                    .expiring
+                    .iter()
+                    .map(|(_, key)| key)
+                    .find(|key| !writes.contains_key(key.as_str()))
+                    .cloned(),
+            };
+            match victim {
+                Some(victim) => self.remove(&victim),
+                None => return Err(out_of_memory(max)),
+            };
+        }
+    }
+
+    /// A key picked at random among those not in `except`.
+    fn random_key(&mut self, except: &BTreeMap<&str, u64>) -> Option<String> {
+        let len = self.slots.len() as u64;
+        let start = self.next_random();
+        // the keys after the one drawn, should it be excluded
+        (0..len)
+            .map(|offset| &self.slots[((start + offset) % len) as usize])
+            .find(|key| !except.contains_key(key.as_str()))
+            .cloned()
+    }
+
+    /// The least recently used of `LRU_SAMPLES` keys picked at random among
+    /// those not in `except`.
+    fn sample_lru(&mut self, except: &BTreeMap<&str, u64>) -> Option<String> {
+        let samples: Vec<String> = (0..LRU_SAMPLES)
+            .filter_map(|_| self.random_key(except))
+            .collect();
+        samples
+            .into_iter()
+            .min_by_key(|key| self.map[key].used.load(Ordering::Relaxed))
+    }
+
+    /// xorshift64*, plenty random for picking keys.
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+fn get(state: &RwLock<MemState>, key: String) -> Result<Option<String>> {
+    let state = state.read().unwrap();
+    Ok(state
+        .live(&key, unix_millis())
+        .map(|entry| entry.value.clone()))
+}
+
+fn scan(state: &RwLock<MemState>, prefix: String) -> Result<Vec<(String, String)>> {
+    let state = state.read().unwrap();
+    let now = unix_millis();
+    Ok(state
+        .map
+        .range(prefix.clone()..)
+        .take_while(|(key, _)| key.starts_with(&prefix))
+        .filter(|(_, entry)| entry.expires.is_none_or(|expires| expires > now))
+        .map(|(key, entry)| (key.clone(), entry.value.clone()))
+        .collect())
+}
+
+/// Memory a key and its value count for.
+fn entry_size(key: &str, value: &str) -> u64 {
+    (key.len() + value.len()) as u64
+}
+
+fn out_of_memory(max: u64) -> MyError {
+    MyError::StringError(format!(
+        "Out of memory: the store is limited to {} bytes",
+        max
+    ))
+}
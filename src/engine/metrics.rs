@@ -0,0 +1,51 @@
+//! Measurements of the operations of a `KvStore`, for applications
+//! embedding it to report as they see fit.
+use std::time::Duration;
+
+/// The operations a `KvStore` measures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OpKind {
+    /// Reads of a string, through the store or a reader of it.
+    Get,
+    /// Sets of a string, with or without a TTL.
+    Set,
+    Remove,
+    /// Compactions, whatever triggered them.
+    Compaction,
+}
+
+impl OpKind {
+    /// The name of the operation in lower case, as in a metric label.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OpKind::Get => "get",
+            OpKind::Set => "set",
+            OpKind::Remove => "remove",
+            OpKind::Compaction => "compaction",
+        }
+    }
+}
+
+/// How an operation went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpMetrics {
+    pub op: OpKind,
+    /// How long the operation took, a write including the compaction it
+    /// triggered.
+    pub latency: Duration,
+    /// Bytes of the key and value read or written; for a compaction, the
+    /// bytes of the log and value log it left.
+    pub bytes: u64,
+    /// Whether the operation succeeded. A get of a missing key succeeds.
+    pub ok: bool,
+}
+
+/// Receives the measurements of every get, set, remove and compaction of a
+/// `KvStore`, registered with `KvStoreOptions::with_metrics_sink`, to feed
+/// Prometheus, StatsD or the like without running the server.
+///
+/// Sinks are called on the thread of the operation once it is done, so
+/// they should count or hand off rather than block.
+pub trait MetricsSink: Send + Sync {
+    fn record(&self, metrics: &OpMetrics);
+}
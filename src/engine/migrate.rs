@@ -0,0 +1,96 @@
+use crate::engine::{KvsEngine, WriteBatch};
+use crate::errors::{MyError, Result};
+
+/// Keys `migrate` reads, writes and verifies at a time.
+const MIGRATE_PAGE_KEYS: usize = 1000;
+
+/// What `migrate` did so far, or in all once it returns.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MigrateReport {
+    /// String keys copied.
+    pub keys: u64,
+    /// Bytes of their keys and values.
+    pub bytes: u64,
+    /// Keys of lists, hashes, sets and sorted sets, which are not copied.
+    pub skipped: u64,
+    /// Copied keys read back from the destination with the value they have
+    /// in the source.
+    pub verified: u64,
+}
+
+/// Copies every live string key of `src`, with its value and expiry, into
+/// `dst`, then reads each back from `dst` to check it arrived whole.
+///
+/// Keys are walked in key order a page at a time, so neither store is held
+/// in memory; `progress` is called after each page, copied then verified.
+/// Keys of other kinds are counted as skipped. `src` should not change
+/// meanwhile: keys written to it during the copy may be missed, and keys
+/// expiring in it are not verified.
+///
+/// # Errors
+///
+/// Fails with `MyError::Corrupt` if a key reads back with another value,
+/// and with `MyError::StringError` if `src` holds keys with an expiry that
+/// `dst` cannot keep.
+pub fn migrate<S: KvsEngine, D: KvsEngine>(
+    src: &mut S,
+    dst: &mut D,
+    mut progress: impl FnMut(&MigrateReport),
+) -> Result<MigrateReport> {
+    let mut report = MigrateReport::default();
+    let mut cursor = None;
+    loop {
+        let keys = src.keys_after(String::new(), cursor, MIGRATE_PAGE_KEYS)?;
+        let mut batch = WriteBatch::new();
+        for key in &keys {
+            let value = match src.get(key.clone()) {
+                Ok(Some(value)) => value,
+                // collections read as no string, or as the wrong type
+                Ok(None) | Err(MyError::WrongType { .. }) => {
+                    report.skipped += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            report.keys += 1;
+            report.bytes += (key.len() + value.len()) as u64;
+            match src.ttl(key.clone())? {
+                Some(ttl) => dst.set_with_ttl(key.clone(), value, ttl)?,
+                None => {
+                    batch.set(key.clone(), value);
+                }
+            }
+        }
+        dst.write_batch(batch)?;
+        progress(&report);
+        cursor = match keys.last() {
+            Some(last) if keys.len() == MIGRATE_PAGE_KEYS => Some(last.clone()),
+            _ => break,
+        };
+    }
+
+    let mut cursor = None;
+    loop {
+        let keys = src.keys_after(String::new(), cursor, MIGRATE_PAGE_KEYS)?;
+        for key in &keys {
+            let expected = match src.get(key.clone()) {
+                Ok(Some(value)) => value,
+                Ok(None) | Err(MyError::WrongType { .. }) => continue,
+                Err(e) => return Err(e),
+            };
+            if dst.get(key.clone())?.as_ref() != Some(&expected) {
+                return Err(MyError::corrupt(format!(
+                    "`{}` did not reach the destination whole",
+                    key
+                )));
+            }
+            report.verified += 1;
+        }
+        progress(&report);
+        cursor = match keys.last() {
+            Some(last) if keys.len() == MIGRATE_PAGE_KEYS => Some(last.clone()),
+            _ => break,
+        };
+    }
+    Ok(report)
+}
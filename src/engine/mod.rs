@@ -1,28 +1,1006 @@
 //! This module define key value storage engines.
 
-use crate::Result;
+use crate::glob::Glob;
+use crate::latency::LatencyStats;
+use crate::tenant::TENANT_MARK;
+use crate::{MyError, Result};
+use bytes::Bytes;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+mod async_engine;
+mod cache;
+mod commit;
+#[cfg(feature = "testing")]
+mod faulty;
+mod frame;
+mod fsck;
+mod index;
+mod io;
+mod json_path;
 mod kvs;
+mod listener;
+mod lsm;
+mod memory;
+mod metrics;
+mod migrate;
+mod partitioned;
+mod report;
+#[cfg(feature = "rocksdb")]
+mod rocks;
 mod sled;
+mod sorted_set;
+mod typed;
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
+pub use self::async_engine::{AsyncKvsEngine, BlockingEngine, BoxFuture};
+pub use self::commit::GroupCommit;
+#[cfg(feature = "testing")]
+pub use self::faulty::{Fault, FaultPoint, Faults, FaultyEngine, FaultyReader};
+pub use self::frame::LogFormat;
+pub use self::fsck::{dump_log, fsck, CheckReport, ChecksumStatus, LogEntry};
+pub use self::index::IndexedEngine;
+pub(crate) use self::json_path::JsonPath;
+pub use self::kvs::{
+    Change, Command, KeyVersion, KvReader, KvStore, KvStoreOptions, SyncPolicy, VerifyReport,
+    WriteBuffer,
+};
+pub use self::listener::{EventListener, KeyEvent, KeyOp};
+pub use self::lsm::{LsmEngine, LsmOptions, LsmReader};
+pub use self::memory::{EvictionPolicy, MemEngine, MemReader};
+pub use self::metrics::{MetricsSink, OpKind, OpMetrics};
+pub use self::migrate::{migrate, MigrateReport};
+pub use self::partitioned::{HashPartitioner, PartitionedKvStore, Partitioner};
+pub use self::report::{SizeReport, SizeStats, SIZE_BUCKETS};
+#[cfg(feature = "rocksdb")]
+pub use self::rocks::{RocksKvsEngine, RocksReader};
+pub use self::sled::{SledKvsEngine, SledReader};
+pub use self::typed::TypedKvStore;
 
 /// Trait for a key value storage engine.
 pub trait KvsEngine {
+    /// Handle reading the engine concurrently with its writes.
+    type Reader: KvsReader;
+
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
     fn set(&mut self, key: String, value: String) -> Result<()>;
 
+    /// Sets `key` to `value` for `ttl`, after which the key reads as
+    /// removed.
+    ///
+    /// # Errors
+    ///
+    /// Engines without expiration fail with `MyError::StringError`.
+    fn set_with_ttl(&mut self, _key: String, _value: String, _ttl: Duration) -> Result<()> {
+        Err(unsupported(self.name(), "expiration"))
+    }
+
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
     fn get(&mut self, key: String) -> Result<Option<String>>;
 
+    /// Sets `key` to `new`, or removes it if `new` is `None`, only if its
+    /// value is `current`, `None` meaning that it is not set. With `ttl`,
+    /// the new value expires after it. Returns whether the swap happened.
+    ///
+    /// The engine being borrowed mutably, no other write comes between
+    /// the comparison and the swap.
+    ///
+    /// # Errors
+    ///
+    /// Engines without expiration fail with `MyError::StringError` when
+    /// given a `ttl`.
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        current: Option<String>,
+        new: Option<String>,
+        ttl: Option<Duration>,
+    ) -> Result<bool> {
+        if self.get(key.clone())? != current {
+            return Ok(false);
+        }
+        match (new, ttl) {
+            (Some(value), Some(ttl)) => self.set_with_ttl(key, value, ttl)?,
+            (Some(value), None) => self.set(key, value)?,
+            (None, _) if current.is_some() => self.remove(key)?,
+            (None, _) => {}
+        }
+        Ok(true)
+    }
+
+    /// Sets `key` to `value` only if it holds no value of any kind, and
+    /// returns whether it did.
+    ///
+    /// The engine being borrowed mutably, no other write comes between
+    /// the check and the write.
+    fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        if self.contains_key(key.clone())? {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Sets `key` to `value` only if it is at `version`, 0 meaning that
+    /// it holds no value, and returns its new version.
+    ///
+    /// Every write to a string key moves it to a higher version, which
+    /// `metadata` reports, so that a client can write back what it read
+    /// only if no other write came in between.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::Conflict` if the key is at another version.
+    /// Engines without versions fail with `MyError::StringError`.
+    fn set_if_version(&mut self, _key: String, _version: u64, _value: String) -> Result<u64> {
+        Err(unsupported(self.name(), "versions"))
+    }
+
+    /// Whether `key` holds a value of any kind.
+    fn contains_key(&mut self, key: String) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Number of keys in the store, of every kind, expired keys not
+    /// swept yet left out.
+    fn len(&mut self) -> Result<u64> {
+        Ok(self.scan(String::new())?.len() as u64)
+    }
+
+    /// Whether the store holds no keys.
+    fn is_empty(&mut self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Returns how long `key` has left to live, `None` if it does not
+    /// expire.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    /// Engines without expiration hold no TTLs.
+    fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.get(key)?.ok_or(MyError::KeyNotFound)?;
+        Ok(None)
+    }
+
+    /// Returns when `key` was created and last written.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    /// Engines without timestamps hold none.
+    fn metadata(&mut self, key: String) -> Result<KeyMeta> {
+        self.get(key)?.ok_or(MyError::KeyNotFound)?;
+        Ok(KeyMeta::default())
+    }
+
+    /// Makes `key` expire after `ttl`, keeping its value.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found;
+    /// only strings expire. Engines without expiration fail with
+    /// `MyError::StringError`.
+    fn expire(&mut self, _key: String, _ttl: Duration) -> Result<()> {
+        Err(unsupported(self.name(), "expiration"))
+    }
+
+    /// Makes each of `keys` that is a string expire after `ttl`, keeping
+    /// its value, and returns how many were; the others are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Engines without expiration fail with `MyError::StringError`.
+    fn touch(&mut self, keys: Vec<String>, ttl: Duration) -> Result<u64> {
+        let mut touched = 0;
+        for key in keys {
+            match self.expire(key, ttl) {
+                Ok(()) => touched += 1,
+                Err(MyError::KeyNotFound) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(touched)
+    }
+
+    /// Makes `key` permanent again, keeping its value.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn persist(&mut self, key: String) -> Result<()> {
+        self.get(key)?.ok_or(MyError::KeyNotFound)?;
+        Ok(())
+    }
+
     /// Removes a given key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     fn remove(&mut self, key: String) -> Result<()>;
+
+    /// Brings `key` back from the trash, with the value it had when it was
+    /// removed, see `KvStoreOptions::with_trash`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the key is not in the trash,
+    /// and `MyError::Conflict` if it was set again since. Engines without
+    /// a trash fail with `MyError::StringError`.
+    fn restore(&mut self, _key: String) -> Result<()> {
+        Err(unsupported(self.name(), "a trash of removed keys"))
+    }
+
+    /// Empties the trash for good, and returns how many keys it held.
+    ///
+    /// # Errors
+    ///
+    /// Engines without a trash fail with `MyError::StringError`.
+    fn purge(&mut self) -> Result<u64> {
+        Err(unsupported(self.name(), "a trash of removed keys"))
+    }
+
+    /// Applies every write of `batch`, in order, or none of them.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound`, without writing anything, if the
+    /// batch removes a key that does not exist at that point.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()>;
+
+    /// Moves the value of `key` to `new_key`, overwriting it, as a batch
+    /// setting `new_key` and removing `key`, so that no reader sees both
+    /// keys or neither. The value keeps its expiry, set on `new_key` once
+    /// the batch is applied.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found;
+    /// only strings can be renamed.
+    fn rename(&mut self, key: String, new_key: String) -> Result<()> {
+        let value = self.get(key.clone())?.ok_or(MyError::KeyNotFound)?;
+        if key == new_key {
+            return Ok(());
+        }
+        let ttl = self.ttl(key.clone())?;
+        let mut batch = WriteBatch::new();
+        batch.set(new_key.clone(), value);
+        batch.remove(key);
+        self.write_batch(batch)?;
+        match ttl {
+            Some(ttl) => self.expire(new_key, ttl),
+            None => Ok(()),
+        }
+    }
+
+    /// Sets `key` to `value` and returns the value it had, `None` if it
+    /// was not set. The new value does not expire, whether or not the old
+    /// one did.
+    ///
+    /// The engine being borrowed mutably, no other write comes between
+    /// the read and the write.
+    fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let previous = self.get(key.clone())?;
+        self.set(key, value)?;
+        Ok(previous)
+    }
+
+    /// Removes `key` and returns the value it had, `None` if it was not
+    /// set, in which case nothing is written.
+    fn get_del(&mut self, key: String) -> Result<Option<String>> {
+        let previous = self.get(key.clone())?;
+        if previous.is_some() {
+            self.remove(key)?;
+        }
+        Ok(previous)
+    }
+
+    /// Returns every key/value pair whose key starts with `prefix`, in key
+    /// order.
+    fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>>;
+
+    /// Returns every key matching the glob `pattern`, in key order: `*`
+    /// matches any run of characters, `?` any one, `[a-z]` one of a class.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::StringError` if the pattern is invalid.
+    fn keys(&mut self, pattern: String) -> Result<Vec<String>> {
+        let glob: Glob = pattern.parse()?;
+        Ok(self
+            .scan(glob.prefix().to_owned())?
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| glob.matches(key))
+            .collect())
+    }
+
+    /// Returns up to `count` keys starting with `prefix` and coming after
+    /// `after`, in key order, for walking the keys a page at a time.
+    fn keys_after(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        count: usize,
+    ) -> Result<Vec<String>> {
+        Ok(self
+            .scan(prefix)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| after.as_ref().is_none_or(|after| key > after))
+            .take(count)
+            .collect())
+    }
+
+    /// Removes every key of the store, of every kind, and makes that
+    /// durable before returning. Other buckets are left alone.
+    fn clear(&mut self) -> Result<()>;
+
+    /// Registers `listener` for an event per write applied from now on,
+    /// expired keys swept included.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::StringError` if the engine has no events.
+    fn add_listener(&mut self, _listener: Arc<dyn EventListener>) -> Result<()> {
+        Err(unsupported(self.name(), "event listeners"))
+    }
+
+    /// Removes the expired keys among the next `limit` keys of the store,
+    /// returning them; successive calls go through the whole store.
+    ///
+    /// Expired keys already read as removed, but only take space until
+    /// swept.
+    fn sweep_expired(&mut self, _limit: usize) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// Gets the JSON text of the part of the JSON value of `key` at
+    /// `path`, e.g. `$.address.city`.
+    ///
+    /// Returns `None` if the key does not exist or its value has nothing at
+    /// `path`.
+    fn get_path(&mut self, key: String, path: &str) -> Result<Option<String>> {
+        let path: JsonPath = path.parse()?;
+        match self.get(key)? {
+            Some(doc) => path.read(&doc),
+            None => Ok(None),
+        }
+    }
+
+    /// Puts the JSON text `value` at `path` in the JSON value of `key`,
+    /// adding the last field of the path if it is missing.
+    ///
+    /// The key is rewritten as by `set`, losing any expiry.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found, and
+    /// `MyError::StringError` if either value is not JSON or the path
+    /// leads nowhere.
+    fn set_path(&mut self, key: String, path: &str, value: String) -> Result<()> {
+        let path: JsonPath = path.parse()?;
+        let doc = self.get(key.clone())?.ok_or(MyError::KeyNotFound)?;
+        let doc = path.patch(&doc, &value)?;
+        self.set(key, doc)
+    }
+
+    /// Adds `value` at the front of the list `key`, creating it if need be,
+    /// and returns the length of the list.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::WrongType` if `key` holds a string, and with
+    /// `MyError::StringError` if the engine has no lists.
+    fn lpush(&mut self, _key: String, _value: String) -> Result<u64> {
+        Err(unsupported(self.name(), "lists"))
+    }
+
+    /// Adds `value` at the back of the list `key`, like `lpush`.
+    fn rpush(&mut self, _key: String, _value: String) -> Result<u64> {
+        Err(unsupported(self.name(), "lists"))
+    }
+
+    /// Removes and returns the value at the front of the list `key`, `None`
+    /// if there is no such list. The key is removed with its last value.
+    fn lpop(&mut self, _key: String) -> Result<Option<String>> {
+        Err(unsupported(self.name(), "lists"))
+    }
+
+    /// Returns the values of the list `key` from index `start` to `stop`,
+    /// both included. Negative indices count from the back, -1 being the
+    /// last value; indices past either end are clamped.
+    fn lrange(&mut self, _key: String, _start: i64, _stop: i64) -> Result<Vec<String>> {
+        Err(unsupported(self.name(), "lists"))
+    }
+
+    /// Sets `field` of the hash `key` to `value`, creating the hash if need
+    /// be.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::WrongType` if `key` holds a string or a list,
+    /// and with `MyError::StringError` if the engine has no hashes.
+    fn hset(&mut self, _key: String, _field: String, _value: String) -> Result<()> {
+        Err(unsupported(self.name(), "hashes"))
+    }
+
+    /// Gets `field` of the hash `key`, `None` if either does not exist.
+    fn hget(&mut self, _key: String, _field: String) -> Result<Option<String>> {
+        Err(unsupported(self.name(), "hashes"))
+    }
+
+    /// Removes `field` of the hash `key`. The key is removed with its last
+    /// field.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the hash has no such field.
+    fn hdel(&mut self, _key: String, _field: String) -> Result<()> {
+        Err(unsupported(self.name(), "hashes"))
+    }
+
+    /// Returns every field of the hash `key` with its value, in field
+    /// order.
+    fn hgetall(&mut self, _key: String) -> Result<Vec<(String, String)>> {
+        Err(unsupported(self.name(), "hashes"))
+    }
+
+    /// Adds `member` to the set `key`, creating it if need be. Returns
+    /// whether it was not a member yet.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::WrongType` if `key` holds another kind of
+    /// value, and with `MyError::StringError` if the engine has no sets.
+    fn sadd(&mut self, _key: String, _member: String) -> Result<bool> {
+        Err(unsupported(self.name(), "sets"))
+    }
+
+    /// Removes `member` from the set `key`, returning whether it was a
+    /// member. The key is removed with its last member.
+    fn srem(&mut self, _key: String, _member: String) -> Result<bool> {
+        Err(unsupported(self.name(), "sets"))
+    }
+
+    /// Whether `member` belongs to the set `key`.
+    fn sismember(&mut self, _key: String, _member: String) -> Result<bool> {
+        Err(unsupported(self.name(), "sets"))
+    }
+
+    /// Returns the members of the set `key`, in order.
+    fn smembers(&mut self, _key: String) -> Result<Vec<String>> {
+        Err(unsupported(self.name(), "sets"))
+    }
+
+    /// Sets the score of `member` in the sorted set `key`, creating it if
+    /// need be. Returns whether it was not a member yet.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::WrongType` if `key` holds another kind of value,
+    /// and with `MyError::StringError` if `score` is not finite or the
+    /// engine has no sorted sets.
+    fn zadd(&mut self, _key: String, _member: String, _score: f64) -> Result<bool> {
+        Err(unsupported(self.name(), "sorted sets"))
+    }
+
+    /// Returns the members of the sorted set `key` scored from `min` to
+    /// `max`, both included, with their scores, by score then member.
+    fn zrange_by_score(
+        &mut self,
+        _key: String,
+        _min: f64,
+        _max: f64,
+    ) -> Result<Vec<(String, f64)>> {
+        Err(unsupported(self.name(), "sorted sets"))
+    }
+
+    /// Returns the keys whose JSON values hold `value` at `path`, e.g.
+    /// `$.email`, in key order.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::StringError` unless the engine indexes `path`;
+    /// see `IndexedEngine`.
+    fn find_by_index(&mut self, path: &str, _value: &str) -> Result<Vec<String>> {
+        Err(MyError::StringError(format!("No index on `{}`", path)))
+    }
+
+    /// Opens the store of bucket `name`, kept apart from this one but in
+    /// the same data directory and with the same settings.
+    fn open_bucket(&self, name: &str) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Returns a handle reading this store from other threads, without
+    /// waiting for writes or compactions in progress.
+    fn reader(&self) -> Self::Reader;
+
+    /// Short name of the engine, e.g. `kvs`, reported to clients.
+    fn name(&self) -> &'static str;
+
+    /// Reports the size and health of the store.
+    fn stats(&mut self) -> Result<EngineStats>;
+
+    /// Compacts once stale records take more than `bytes`, from now on.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::StringError` if the engine has no compaction
+    /// threshold.
+    fn set_compaction_threshold(&mut self, _bytes: u64) -> Result<()> {
+        Err(unsupported(self.name(), "compaction thresholds"))
+    }
+
+    /// Compacts now, whatever the compaction threshold and windows.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::StringError` if the engine cannot be compacted
+    /// on demand.
+    fn compact_now(&mut self) -> Result<()> {
+        Err(unsupported(self.name(), "compaction on demand"))
+    }
+
+    /// Hands making writes durable over to the caller, for group commit:
+    /// from then on writes return once handed to the OS, and are on disk
+    /// once `GroupCommit::sync` returns, which the caller can wait for
+    /// without holding the engine.
+    ///
+    /// Returns `None`, leaving writes as they were, if the engine has
+    /// nothing to sync or syncs on its own.
+    fn defer_syncs(&mut self) -> Result<Option<Arc<GroupCommit>>> {
+        Ok(None)
+    }
+
+    /// Writes a copy of the store as of now to `dir`, created if missing,
+    /// which opens as a store of the same engine. The copy is synced to
+    /// disk before returning.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::StringError` if the engine has no snapshots.
+    fn snapshot(&mut self, _dir: &Path) -> Result<()> {
+        Err(unsupported(self.name(), "snapshots"))
+    }
+
+    /// Makes every write durable before the process exits.
+    ///
+    /// Engines may record that they were shut down cleanly to speed up the
+    /// next start.
+    fn shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Read access to a storage engine, shareable between threads.
+pub trait KvsReader: Clone + Send + Sync + 'static {
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// Gets the value of a given key as `Bytes`, which engines keeping
+    /// values in memory can hand out without copying them.
+    fn get_bytes(&self, key: String) -> Result<Option<Bytes>> {
+        Ok(self.get(key)?.map(Bytes::from))
+    }
+
+    /// Returns every key/value pair whose key starts with `prefix`, in key
+    /// order.
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>>;
+}
+
+/// Name under which clients select the store a server was started with.
+pub const DEFAULT_BUCKET: &str = "default";
+
+/// Prefix of the keys of the trash: a key removed from a store with a
+/// trash is kept as `trash:<key>` until the trash is purged or the key
+/// expires from it.
+pub const TRASH_PREFIX: &str = "trash:";
+
+/// Key `key` is kept under in the trash.
+pub(crate) fn trash_key(key: &str) -> String {
+    format!("{}{}", TRASH_PREFIX, key)
+}
+
+/// Directory of bucket `name` under the data directory `path`, or of the
+/// store of tenant `t` for `@t`.
+pub(crate) fn bucket_dir(path: &Path, name: &str) -> Result<PathBuf> {
+    if let Some(tenant) = name.strip_prefix(TENANT_MARK) {
+        check_bucket_name(tenant)?;
+        return Ok(path.join("tenants").join(tenant));
+    }
+    check_bucket_name(name)?;
+    Ok(path.join("buckets").join(name))
+}
+
+/// Fails unless `name` may name a bucket.
+///
+/// Names are restricted to ASCII letters, digits, `-` and `_`, so that a
+/// bucket cannot reach outside the data directory.
+pub(crate) fn check_bucket_name(name: &str) -> Result<()> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if name.is_empty() || name == DEFAULT_BUCKET || !name.chars().all(valid) {
+        return Err(MyError::StringError(format!(
+            "Invalid bucket name `{}`",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Copies the file at `from` to `to` and syncs the copy, for snapshots.
+pub(crate) fn copy_synced(from: &Path, to: &Path) -> Result<()> {
+    std::fs::copy(from, to).map_err(MyError::file(from))?;
+    File::open(to)
+        .and_then(|file| file.sync_all())
+        .map_err(MyError::file(to))
+}
+
+/// The error of a conditional write finding `key` at `actual` instead of
+/// `expected`.
+pub(crate) fn version_conflict(key: &str, expected: u64, actual: u64) -> MyError {
+    MyError::Conflict(format!(
+        "`{}` is at version {}, not {}",
+        key, actual, expected
+    ))
+}
+
+/// The error of engines without `feature`.
+fn unsupported(engine: &str, feature: &str) -> MyError {
+    MyError::StringError(format!(
+        "The {} engine does not support {}",
+        engine, feature
+    ))
+}
+
+/// The current time in milliseconds since the UNIX epoch, as expiry times
+/// are kept.
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Writes applied together by `KvsEngine::write_batch`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WriteBatch {
+    pub(crate) commands: Vec<Command>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Adds setting `key` to `value`.
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.commands.push(Command::Set { key, value });
+        self
+    }
+
+    /// Adds removing `key`.
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.commands.push(Command::Remove { key });
+        self
+    }
+
+    /// Number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    /// Whether the batch has no writes.
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Fails with `MyError::KeyNotFound` if the batch removes a key that
+    /// neither `exists` in the store nor is set earlier in the batch.
+    pub(crate) fn check_removes(&self, mut exists: impl FnMut(&str) -> Result<bool>) -> Result<()> {
+        let mut pending = HashMap::new();
+        for command in &self.commands {
+            match command {
+                Command::Set { key, .. } => {
+                    pending.insert(key.as_str(), true);
+                }
+                Command::Remove { key } => {
+                    let present = match pending.get(key.as_str()) {
+                        Some(present) => *present,
+                        None => exists(key)?,
+                    };
+                    if !present {
+                        return Err(MyError::KeyNotFound);
+                    }
+                    pending.insert(key.as_str(), false);
+                }
+                _ => unreachable!("batches hold sets and removes"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// When a key was created and last written, as `KvsEngine::metadata`
+/// reports it.
+///
+/// Overwriting a key keeps its creation time; removing it, or letting it
+/// expire, starts it over. Times are `None` for keys written before the
+/// engine kept them, and for lists, hashes and sets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyMeta {
+    /// When the key was set while holding no value.
+    pub created: Option<SystemTime>,
+    /// When its value was last written, merges included.
+    pub updated: Option<SystemTime>,
+    /// Version of the value, as `KvsEngine::set_if_version` expects it; 0
+    /// where the engine keeps none.
+    #[serde(default)]
+    pub version: u64,
+}
+
+impl KeyMeta {
+    /// The metadata of times in milliseconds since the UNIX epoch.
+    pub(crate) fn from_millis(created: Option<u64>, updated: Option<u64>, version: u64) -> KeyMeta {
+        let time = |ms: u64| UNIX_EPOCH + Duration::from_millis(ms);
+        KeyMeta {
+            created: created.map(time),
+            updated: updated.map(time),
+            version,
+        }
+    }
+}
+
+/// Operational statistics of a storage engine.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// Number of live keys.
+    pub key_count: u64,
+    /// Bytes the store occupies on disk.
+    pub disk_usage: u64,
+    /// Bytes of stale records compaction would reclaim.
+    pub uncompacted_bytes: u64,
+    /// Number of data files.
+    pub segment_count: u64,
+    /// When the log was last compacted by this process, if ever.
+    pub last_compaction: Option<SystemTime>,
+    /// Gets answered from the read cache.
+    #[serde(default)]
+    pub cache_hits: u64,
+    /// Gets that missed the read cache and read the value from disk.
+    #[serde(default)]
+    pub cache_misses: u64,
+    /// Compactions run by this process.
+    #[serde(default)]
+    pub compactions: u64,
+    /// Whether writes are slowed down, stale records having reached the
+    /// soft write stall limit.
+    #[serde(default)]
+    pub write_stalled: bool,
+    /// Writes slowed down so far.
+    #[serde(default)]
+    pub stalled_writes: u64,
+    /// Bytes of the buffer writes go through, as `WriteBuffer::Adaptive`
+    /// sized it last.
+    #[serde(default)]
+    pub write_buffer_bytes: u64,
+    /// Bytes those compactions reclaimed in all.
+    #[serde(default)]
+    pub bytes_reclaimed: u64,
+    /// What the last compaction did, if any ran.
+    #[serde(default)]
+    pub last_compaction_stats: Option<CompactionStats>,
+    /// Scheduled snapshots the server took of the store.
+    #[serde(default)]
+    pub snapshots: u64,
+    /// When the last of them was taken, if any was.
+    #[serde(default)]
+    pub last_snapshot: Option<SystemTime>,
+    /// Why the last scheduled snapshot failed, if it did; cleared by the
+    /// next one succeeding.
+    #[serde(default)]
+    pub last_snapshot_error: Option<String>,
+    /// How long the requests the server handled took, by type of request,
+    /// e.g. `Get`.
+    #[serde(default)]
+    pub latency: BTreeMap<String, LatencyStats>,
+}
+
+/// What a single compaction did, to tell whether the compaction
+/// threshold is worth its cost.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CompactionStats {
+    /// Bytes the data files shrank by.
+    pub bytes_reclaimed: u64,
+    /// Records copied over to the compacted files.
+    pub records_rewritten: u64,
+    /// How long the compaction took.
+    pub duration: Duration,
+    /// Why it ran.
+    pub trigger: CompactionTrigger,
+}
+
+/// What set off a compaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompactionTrigger {
+    /// Stale records went past the compaction threshold.
+    Threshold,
+    /// Level 0 of an LSM tree held too many tables.
+    TableCount,
+    /// Stale records reached the hard write stall limit.
+    WriteStall,
+    /// A level of an LSM tree grew past its size.
+    LevelSize,
+    /// Asked for through `KvsEngine::compact_now`.
+    Requested,
+}
+
+impl fmt::Display for CompactionTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CompactionTrigger::Threshold => "compaction threshold",
+            CompactionTrigger::TableCount => "level 0 table count",
+            CompactionTrigger::WriteStall => "hard write stall limit",
+            CompactionTrigger::LevelSize => "level size",
+            CompactionTrigger::Requested => "request",
+        })
+    }
+}
+
+/// Compactions an engine ran, summed up for its stats.
+#[derive(Default)]
+pub(crate) struct CompactionLog {
+    count: u64,
+    bytes_reclaimed: u64,
+    last: Option<CompactionStats>,
+}
+
+impl CompactionLog {
+    /// Logs a finished compaction and adds it to the totals.
+    pub(crate) fn record(&mut self, stats: CompactionStats) {
+        info!(
+            "Compaction ({}) rewrote {} records in {:?}, reclaiming {} bytes",
+            stats.trigger, stats.records_rewritten, stats.duration, stats.bytes_reclaimed
+        );
+        self.count += 1;
+        self.bytes_reclaimed += stats.bytes_reclaimed;
+        self.last = Some(stats);
+    }
+
+    /// Fills in the compaction fields of `stats`.
+    pub(crate) fn report(&self, stats: EngineStats) -> EngineStats {
+        EngineStats {
+            compactions: self.count,
+            bytes_reclaimed: self.bytes_reclaimed,
+            last_compaction_stats: self.last.clone(),
+            ..stats
+        }
+    }
+}
+
+/// A daily stretch of time, in UTC, during which automatic compaction may
+/// run, written `HH:MM-HH:MM`. A window ending before it starts spans
+/// midnight, e.g. `22:00-04:00`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompactionWindow {
+    /// Minutes since midnight.
+    start: u32,
+    end: u32,
+}
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+impl CompactionWindow {
+    /// Whether `time` falls within the window.
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let minute = (secs / 60 % MINUTES_PER_DAY) as u32;
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Whether automatic compaction may run now: always without windows,
+/// otherwise within one of them.
+pub(crate) fn in_compaction_window(windows: &[CompactionWindow]) -> bool {
+    let now = SystemTime::now();
+    windows.is_empty() || windows.iter().any(|window| window.contains(now))
+}
+
+impl FromStr for CompactionWindow {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<CompactionWindow> {
+        let invalid = || {
+            MyError::StringError(format!(
+                "Invalid compaction window `{}`, expected HH:MM-HH:MM",
+                s
+            ))
+        };
+        let minutes = |time: &str| {
+            let (hours, minutes) = time.split_once(':')?;
+            let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+            (hours < 24 && minutes < 60).then(|| hours * 60 + minutes)
+        };
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        Ok(CompactionWindow {
+            start: minutes(start.trim()).ok_or_else(invalid)?,
+            end: minutes(end.trim()).ok_or_else(invalid)?,
+        })
+    }
+}
+
+impl fmt::Display for CompactionWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// Holds a compaction to `rate` bytes written per second on average, by
+/// sleeping whenever it gets ahead.
+pub(crate) struct Throttle {
+    rate: Option<u64>,
+    started: Instant,
+    written: u64,
+}
+
+impl Throttle {
+    /// A throttle for a compaction starting now; `None` does not throttle.
+    pub(crate) fn new(rate: Option<u64>) -> Throttle {
+        Throttle {
+            rate,
+            started: Instant::now(),
+            written: 0,
+        }
+    }
+
+    /// Counts `bytes` more written, then sleeps until the rate allows them.
+    pub(crate) fn wrote(&mut self, bytes: u64) {
+        let rate = match self.rate {
+            Some(rate) if rate > 0 => rate,
+            _ => return,
+        };
+        self.written += bytes;
+        let due = Duration::from_secs_f64(self.written as f64 / rate as f64);
+        if let Some(ahead) = due.checked_sub(self.started.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+impl EngineStats {
+    /// Share of the gets answered from the read cache, if any went
+    /// through it.
+    pub fn cache_hit_ratio(&self) -> Option<f64> {
+        match self.cache_hits + self.cache_misses {
+            0 => None,
+            gets => Some(self.cache_hits as f64 / gets as f64),
+        }
+    }
 }
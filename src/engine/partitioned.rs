@@ -0,0 +1,231 @@
+//! Several kvs stores in one process, each holding a part of the keys.
+use crate::cluster::hash;
+use crate::engine::{KvStore, KvStoreOptions, KvsEngine};
+use crate::{MyError, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// File recording how many partitions a directory was created with.
+const PARTITIONS_FILE: &str = "partitions";
+
+/// Decides which partition of a `PartitionedKvStore` holds each key.
+///
+/// A partitioner must place a key in the same partition every time, in
+/// every process, given the same number of partitions: the keys written
+/// are only found where it placed them.
+pub trait Partitioner: Send + Sync {
+    /// The partition of `key`, below `partitions`.
+    fn partition(&self, key: &str, partitions: usize) -> usize;
+}
+
+/// Spreads keys evenly, by a hash of the whole key stable across processes
+/// and Rust versions.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HashPartitioner;
+
+impl Partitioner for HashPartitioner {
+    fn partition(&self, key: &str, partitions: usize) -> usize {
+        (hash(key) % partitions as u64) as usize
+    }
+}
+
+/// Spreads keys over several `KvStore`s, each in a directory and with a
+/// log and writer lock of its own, so that threads writing keys of
+/// different partitions do not wait for each other.
+///
+/// The store is a handle: clones share the partitions, and can be moved
+/// to other threads. Each operation works on one partition, but `scan`,
+/// `keys`, `len` and `clear`, which visit them all in turn; they see each
+/// partition as it is when they reach it. There are no batches across
+/// partitions, as nothing could make their writes atomic.
+///
+/// Partition `i` is kept in `partition-<i>` under the directory, which
+/// records the number of partitions: opening it with another fails.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{PartitionedKvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// # let dir = std::env::current_dir()?;
+/// let store = PartitionedKvStore::open(dir, 4)?;
+/// let handle = store.clone();
+/// std::thread::spawn(move || handle.set("key".to_owned(), "value".to_owned()))
+///     .join()
+///     .unwrap()?;
+/// assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct PartitionedKvStore {
+    partitions: Arc<Vec<Mutex<KvStore>>>,
+    partitioner: Arc<dyn Partitioner>,
+}
+
+impl PartitionedKvStore {
+    /// Opens the `partitions` stores under `path`, with the default options,
+    /// placing keys with `HashPartitioner`.
+    pub fn open(path: impl Into<PathBuf>, partitions: usize) -> Result<PartitionedKvStore> {
+        PartitionedKvStore::open_with(path, partitions, KvStoreOptions::default(), HashPartitioner)
+    }
+
+    /// Opens the `partitions` stores under `path`, each with `options`,
+    /// placing keys with `partitioner`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::StringError` if `partitions` is 0, or if `path`
+    /// was created with another number of partitions.
+    pub fn open_with(
+        path: impl Into<PathBuf>,
+        partitions: usize,
+        options: KvStoreOptions,
+        partitioner: impl Partitioner + 'static,
+    ) -> Result<PartitionedKvStore> {
+        if partitions == 0 {
+            return Err(MyError::StringError(
+                "A partitioned store needs at least one partition".to_owned(),
+            ));
+        }
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        check_partitions(&path, partitions)?;
+        let stores = (0..partitions)
+            .map(|i| {
+                let store = KvStore::open_with_options(
+                    path.join(format!("partition-{}", i)),
+                    options.clone(),
+                )?;
+                Ok(Mutex::new(store))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(PartitionedKvStore {
+            partitions: Arc::new(stores),
+            partitioner: Arc::new(partitioner),
+        })
+    }
+
+    /// Number of partitions.
+    pub fn partitions(&self) -> usize {
+        self.partitions.len()
+    }
+
+    /// The partition holding `key`.
+    pub fn partition_of(&self, key: &str) -> usize {
+        let partition = self.partitioner.partition(key, self.partitions.len());
+        assert!(
+            partition < self.partitions.len(),
+            "partitioner placed `{}` in partition {} of {}",
+            key,
+            partition,
+            self.partitions.len()
+        );
+        partition
+    }
+
+    /// Runs `f` on the store of the partition holding `key`, e.g. for the
+    /// operations on lists, hashes and sets, other partitions going on
+    /// meanwhile.
+    pub fn with_partition<T>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&mut KvStore) -> Result<T>,
+    ) -> Result<T> {
+        f(&mut self.partitions[self.partition_of(key)].lock().unwrap())
+    }
+
+    /// Sets the value of a string key.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.with_partition(&key.clone(), |store| store.set(key, value))
+    }
+
+    /// Sets the value of a string key, which expires once `ttl` elapses.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.with_partition(&key.clone(), |store| store.set_with_ttl(key, value, ttl))
+    }
+
+    /// Gets the value of a string key, `None` if it is not set.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.with_partition(&key.clone(), |store| store.get(key))
+    }
+
+    /// Removes a key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.with_partition(&key.clone(), |store| store.remove(key))
+    }
+
+    /// Every key/value pair whose key starts with `prefix`, in key order.
+    pub fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for partition in self.partitions.iter() {
+            pairs.extend(partition.lock().unwrap().scan(prefix.clone())?);
+        }
+        pairs.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(pairs)
+    }
+
+    /// The keys matching the glob `pattern`, in order.
+    pub fn keys(&self, pattern: String) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for partition in self.partitions.iter() {
+            keys.extend(partition.lock().unwrap().keys(pattern.clone())?);
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// Number of keys, over every partition.
+    pub fn len(&self) -> Result<u64> {
+        let mut len = 0;
+        for partition in self.partitions.iter() {
+            len += partition.lock().unwrap().len()?;
+        }
+        Ok(len)
+    }
+
+    /// Whether no partition holds a key.
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Removes every key of every partition.
+    pub fn clear(&self) -> Result<()> {
+        for partition in self.partitions.iter() {
+            partition.lock().unwrap().clear()?;
+        }
+        Ok(())
+    }
+}
+
+/// Records `partitions` in `path` if it has no count yet, or fails if it
+/// has another.
+fn check_partitions(path: &Path, partitions: usize) -> Result<()> {
+    let file = path.join(PARTITIONS_FILE);
+    match fs::read_to_string(&file) {
+        Ok(recorded) => match recorded.trim().parse::<usize>() {
+            Ok(recorded) if recorded == partitions => Ok(()),
+            Ok(recorded) => Err(MyError::StringError(format!(
+                "{} holds {} partitions, not {}",
+                path.display(),
+                recorded,
+                partitions
+            ))),
+            Err(_) => Err(MyError::StringError(format!(
+                "Invalid partition count in {}",
+                file.display()
+            ))),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            fs::write(&file, partitions.to_string())?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,82 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Upper bounds, in bytes, of the size buckets of `SizeStats::histogram`:
+/// bucket `i` counts the keys taking at most `SIZE_BUCKETS[i]` bytes and
+/// more than the previous bound, and the last bucket those taking more.
+pub const SIZE_BUCKETS: [u64; 8] = [
+    64,
+    256,
+    1 << 10,
+    4 << 10,
+    16 << 10,
+    64 << 10,
+    256 << 10,
+    1 << 20,
+];
+
+/// Separates the namespace of a key from the rest, see `SizeReport`.
+const NAMESPACE_SEPARATOR: char = ':';
+
+/// How much room a group of keys takes.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SizeStats {
+    pub keys: u64,
+    /// Bytes of the keys themselves.
+    pub key_bytes: u64,
+    /// Bytes of the records of the keys on disk, in the log and the value
+    /// log: their values, or elements, with the framing around them.
+    pub bytes: u64,
+    /// Keys by the bytes they take, see `SIZE_BUCKETS`.
+    pub histogram: [u64; SIZE_BUCKETS.len() + 1],
+}
+
+impl SizeStats {
+    fn add(&mut self, key: &str, bytes: u64) {
+        self.keys += 1;
+        self.key_bytes += key.len() as u64;
+        self.bytes += bytes;
+        let bucket = SIZE_BUCKETS
+            .iter()
+            .position(|bound| bytes <= *bound)
+            .unwrap_or(SIZE_BUCKETS.len());
+        self.histogram[bucket] += 1;
+    }
+}
+
+/// How much room the live keys of a store take, in all and by namespace,
+/// from `KvStore::size_report`.
+///
+/// The namespace of a key is the part before its first `:`, e.g. `user`
+/// for `user:42`; keys without one are in the namespace `""`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SizeReport {
+    pub total: SizeStats,
+    /// Every namespace, those taking the most bytes first.
+    pub namespaces: Vec<(String, SizeStats)>,
+}
+
+impl SizeReport {
+    /// A report of `keys`, each with the bytes it takes.
+    pub(crate) fn new<'a>(keys: impl IntoIterator<Item = (&'a str, u64)>) -> SizeReport {
+        let mut total = SizeStats::default();
+        let mut namespaces: HashMap<&str, SizeStats> = HashMap::new();
+        for (key, bytes) in keys {
+            total.add(key, bytes);
+            let namespace = key.split(NAMESPACE_SEPARATOR).next().unwrap_or_default();
+            let namespace = match namespace.len() == key.len() {
+                true => "",
+                false => namespace,
+            };
+            namespaces.entry(namespace).or_default().add(key, bytes);
+        }
+        let mut namespaces: Vec<(String, SizeStats)> = namespaces
+            .into_iter()
+            .map(|(namespace, stats)| (namespace.to_owned(), stats))
+            .collect();
+        namespaces.sort_by(|(a, a_stats), (b, b_stats)| {
+            b_stats.bytes.cmp(&a_stats.bytes).then_with(|| a.cmp(b))
+        });
+        SizeReport { total, namespaces }
+    }
+}
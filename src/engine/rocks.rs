@@ -0,0 +1,170 @@
+//! Map rocksdb crate
+use crate::engine::{bucket_dir, Command, EngineStats, KvsEngine, KvsReader, WriteBatch};
+use crate::{MyError, Result};
+use rocksdb::{Direction, IteratorMode, DB};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Engine storing the pairs in RocksDB, for data sets larger than the
+/// memory of the host.
+///
+/// Writes go to RocksDB's write-ahead log, which survives the process
+/// crashing; `shutdown` syncs it so that it survives the host too.
+pub struct RocksKvsEngine {
+    db: Arc<DB>,
+    /// Data directory the database lives in.
+    dir: PathBuf,
+}
+
+impl KvsEngine for RocksKvsEngine {
+    type Reader = RocksReader;
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.db.put(key, value)?;
+        Ok(())
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        get(&self.db, key)
+    }
+
+    fn contains_key(&mut self, key: String) -> Result<bool> {
+        Ok(self.db.get_pinned(key)?.is_some())
+    }
+
+    /// Counts the keys, RocksDB only estimating their number.
+    fn len(&mut self) -> Result<u64> {
+        let mut len = 0;
+        for pair in self.db.iterator(IteratorMode::Start) {
+            pair?;
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    /// Remove a given key.
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.db.get_pinned(&key)?.is_none() {
+            return Err(MyError::KeyNotFound);
+        }
+        self.db.delete(key)?;
+        Ok(())
+    }
+
+    /// Applies the batch with a `rocksdb::WriteBatch`, which RocksDB makes
+    /// atomic.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let db = &self.db;
+        batch.check_removes(|key| Ok(db.get_pinned(key)?.is_some()))?;
+        let mut rocks_batch = rocksdb::WriteBatch::default();
+        for command in batch.commands {
+            match command {
+                Command::Set { key, value } => rocks_batch.put(key, value),
+                Command::Remove { key } => rocks_batch.delete(key),
+                _ => unreachable!("batches hold sets and removes"),
+            }
+        }
+        self.db.write(rocks_batch)?;
+        Ok(())
+    }
+
+    /// Returns every key/value pair whose key starts with `prefix`.
+    fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        scan(&self.db, prefix)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        let mut batch = rocksdb::WriteBatch::default();
+        for pair in self.db.iterator(IteratorMode::Start) {
+            batch.delete(pair?.0);
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn open_bucket(&self, name: &str) -> Result<RocksKvsEngine> {
+        RocksKvsEngine::open(bucket_dir(&self.dir, name)?)
+    }
+
+    /// RocksDB serves concurrent readers on its own.
+    fn reader(&self) -> RocksReader {
+        RocksReader {
+            db: Arc::clone(&self.db),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "rocksdb"
+    }
+
+    /// RocksDB compacts on its own and does not report segments.
+    fn stats(&mut self) -> Result<EngineStats> {
+        let disk_usage = self
+            .db
+            .property_int_value("rocksdb.total-sst-files-size")?
+            .unwrap_or(0);
+        Ok(EngineStats {
+            key_count: self.len()?,
+            disk_usage,
+            ..EngineStats::default()
+        })
+    }
+
+    /// Syncs the write-ahead log to disk.
+    fn shutdown(&mut self) -> Result<()> {
+        self.db.flush_wal(true)?;
+        Ok(())
+    }
+}
+
+impl RocksKvsEngine {
+    /// Open the RocksKvsEngine at a given path. Return the `RocksKvsEngine`.
+    pub fn open(path: impl Into<PathBuf>) -> Result<RocksKvsEngine> {
+        let dir = path.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(RocksKvsEngine {
+            db: Arc::new(DB::open_default(dir.join("rocksdb"))?),
+            dir,
+        })
+    }
+}
+
+/// A handle reading a `RocksKvsEngine` from any thread.
+#[derive(Clone)]
+pub struct RocksReader {
+    db: Arc<DB>,
+}
+
+impl KvsReader for RocksReader {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        get(&self.db, key)
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        scan(&self.db, prefix)
+    }
+}
+
+fn get(db: &DB, key: String) -> Result<Option<String>> {
+    Ok(db.get(key)?.map(String::from_utf8).transpose()?)
+}
+
+fn scan(db: &DB, prefix: String) -> Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    for pair in db.iterator(IteratorMode::From(prefix.as_bytes(), Direction::Forward)) {
+        let (key, value) = pair?;
+        if !key.starts_with(prefix.as_bytes()) {
+            break;
+        }
+        pairs.push((
+            String::from_utf8(key.into_vec())?,
+            String::from_utf8(value.into_vec())?,
+        ));
+    }
+    Ok(pairs)
+}
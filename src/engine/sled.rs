@@ -1,13 +1,16 @@
 //! Map sled crate
-use crate::engine::KvsEngine;
+use crate::engine::{bucket_dir, Command, EngineStats, KvsEngine, KvsReader, WriteBatch};
 use crate::{MyError, Result};
 use std::path::PathBuf;
 
 pub struct SledKvsEngine {
     store: sled::Db,
+    /// Data directory the database lives in.
+    dir: PathBuf,
 }
 
 impl KvsEngine for SledKvsEngine {
+    type Reader = SledReader;
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
@@ -21,12 +24,15 @@ impl KvsEngine for SledKvsEngine {
     ///
     /// Returns `None` if the given key does not exist.
     fn get(&mut self, key: String) -> Result<Option<String>> {
-        Ok(self
-            .store
-            .get(key)?
-            .map(|v| v.to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+        get(&self.store, key)
+    }
+
+    fn contains_key(&mut self, key: String) -> Result<bool> {
+        Ok(self.store.contains_key(key)?)
+    }
+
+    fn len(&mut self) -> Result<u64> {
+        Ok(self.store.len() as u64)
     }
     /// Remove a given key.
     fn remove(&mut self, key: String) -> Result<()> {
@@ -34,6 +40,64 @@ impl KvsEngine for SledKvsEngine {
         self.store.flush()?;
         Ok(())
     }
+
+    /// Applies the batch with a `sled::Batch`, which sled makes atomic.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let store = &self.store;
+        batch.check_removes(|key| Ok(store.contains_key(key)?))?;
+        let mut sled_batch = sled::Batch::default();
+        for command in batch.commands {
+            match command {
+                Command::Set { key, value } => sled_batch.insert(key.as_bytes(), value.as_bytes()),
+                Command::Remove { key } => sled_batch.remove(key.as_bytes()),
+                _ => unreachable!("batches hold sets and removes"),
+            }
+        }
+        self.store.apply_batch(sled_batch)?;
+        self.store.flush()?;
+        Ok(())
+    }
+
+    /// Returns every key/value pair whose key starts with `prefix`.
+    fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        scan(&self.store, prefix)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.store.clear()?;
+        self.store.flush()?;
+        Ok(())
+    }
+
+    fn open_bucket(&self, name: &str) -> Result<SledKvsEngine> {
+        SledKvsEngine::open(bucket_dir(&self.dir, name)?)
+    }
+
+    /// sled serves concurrent readers on its own.
+    fn reader(&self) -> SledReader {
+        SledReader {
+            store: self.store.clone(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "sled"
+    }
+
+    /// sled compacts on its own and does not report segments.
+    fn stats(&mut self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            key_count: self.store.len() as u64,
+            disk_usage: self.store.size_on_disk()?,
+            ..EngineStats::default()
+        })
+    }
+
+    /// Flushes sled's buffers to disk.
+    fn shutdown(&mut self) -> Result<()> {
+        self.store.flush()?;
+        Ok(())
+    }
 }
 
 impl SledKvsEngine {
@@ -45,11 +109,48 @@ impl SledKvsEngine {
 
     /// Open the SledKvsEngine at a given path. Return the `SledKvsEngine`.
     pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
-        let mut path = path.into();
-        std::fs::create_dir_all(&path)?;
-        path.push("sled-db");
+        let dir = path.into();
+        std::fs::create_dir_all(&dir)?;
         Ok(SledKvsEngine {
-            store: sled::open(path)?,
+            store: sled::open(dir.join("sled-db"))?,
+            dir,
         })
     }
 }
+
+/// A handle reading a `SledKvsEngine` from any thread.
+#[derive(Clone)]
+pub struct SledReader {
+    store: sled::Db,
+}
+
+impl KvsReader for SledReader {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        get(&self.store, key)
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        scan(&self.store, prefix)
+    }
+}
+
+fn get(store: &sled::Db, key: String) -> Result<Option<String>> {
+    Ok(store
+        .get(key)?
+        .map(|v| v.to_vec())
+        .map(String::from_utf8)
+        .transpose()?)
+}
+
+fn scan(store: &sled::Db, prefix: String) -> Result<Vec<(String, String)>> {
+    store
+        .scan_prefix(prefix)
+        .map(|pair| {
+            let (key, value) = pair?;
+            Ok((
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            ))
+        })
+        .collect()
+}
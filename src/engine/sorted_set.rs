@@ -0,0 +1,85 @@
+//! Members ordered by score, for sorted sets.
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+
+/// A score ordered by `f64::total_cmp`.
+#[derive(Clone, Copy, Debug)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Score) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Score) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Score) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// The members of a sorted set with their scores, each carrying an item,
+/// e.g. where its score was written.
+#[derive(Clone, Debug)]
+pub(crate) struct SortedSet<T> {
+    members: HashMap<String, (f64, T)>,
+    order: BTreeSet<(Score, String)>,
+}
+
+impl<T> Default for SortedSet<T> {
+    fn default() -> SortedSet<T> {
+        SortedSet {
+            members: HashMap::new(),
+            order: BTreeSet::new(),
+        }
+    }
+}
+
+impl<T> SortedSet<T> {
+    /// Sets the score of `member`, returning the item of its previous
+    /// score.
+    pub(crate) fn insert(&mut self, member: String, score: f64, item: T) -> Option<T> {
+        let previous = self.members.insert(member.clone(), (score, item));
+        if let Some((previous, _)) = &previous {
+            self.order.remove(&(Score(*previous), member.clone()));
+        }
+        self.order.insert((Score(score), member));
+        previous.map(|(_, item)| item)
+    }
+
+    pub(crate) fn score(&self, member: &str) -> Option<f64> {
+        self.members.get(member).map(|(score, _)| *score)
+    }
+
+    /// The members scored from `min` to `max`, both included, by score
+    /// then member.
+    pub(crate) fn range(&self, min: f64, max: f64) -> impl Iterator<Item = (&str, f64)> {
+        self.order
+            .range((Score(min), String::new())..)
+            .take_while(move |(score, _)| *score <= Score(max))
+            .map(|(score, member)| (member.as_str(), score.0))
+    }
+
+    /// Every member with its score and item, in no order.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (&str, f64, &T)> {
+        self.members
+            .iter()
+            .map(|(member, (score, item))| (member.as_str(), *score, item))
+    }
+
+    pub(crate) fn items(&self) -> impl Iterator<Item = &T> {
+        self.members.values().map(|(_, item)| item)
+    }
+
+    pub(crate) fn items_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.members.values_mut().map(|(_, item)| item)
+    }
+}
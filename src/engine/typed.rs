@@ -0,0 +1,162 @@
+//! Typed keys and values over the string keys and values of an engine.
+use crate::engine::KvsEngine;
+use crate::{MyError, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::any::type_name;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+/// Wraps an engine, storing keys of type `K` and values of type `V`
+/// instead of strings.
+///
+/// Values are stored as JSON. Keys serializing to a JSON string are stored
+/// as that string, so `String` keys are stored as they are; other keys are
+/// stored as their JSON text, e.g. `42` or `[1,"a"]`.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{MemEngine, Result, TypedKvStore};
+/// # use serde::{Deserialize, Serialize};
+/// #[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// struct User {
+///     name: String,
+///     age: u32,
+/// }
+///
+/// # fn try_main() -> Result<()> {
+/// let mut users: TypedKvStore<_, u64, User> = TypedKvStore::new(MemEngine::new());
+/// let user = User { name: "Ada".to_owned(), age: 36 };
+/// users.set(&1, &user)?;
+/// assert_eq!(users.get(&1)?, Some(user));
+/// # Ok(())
+/// # }
+/// ```
+pub struct TypedKvStore<E, K, V> {
+    engine: E,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<E, K, V> TypedKvStore<E, K, V>
+where
+    E: KvsEngine,
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    /// Wraps `engine`.
+    pub fn new(engine: E) -> TypedKvStore<E, K, V> {
+        TypedKvStore {
+            engine,
+            types: PhantomData,
+        }
+    }
+
+    /// The engine wrapped.
+    pub fn get_ref(&self) -> &E {
+        &self.engine
+    }
+
+    /// The engine wrapped, for the operations the wrapper does not offer.
+    pub fn get_mut(&mut self) -> &mut E {
+        &mut self.engine
+    }
+
+    /// Unwraps the engine.
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+
+    /// The value of `key`, `None` if it is not set.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::StringError` if the value does not decode as a
+    /// `V`.
+    pub fn get(&mut self, key: &K) -> Result<Option<V>> {
+        let key = encode_key(key)?;
+        match self.engine.get(key.clone())? {
+            Some(value) => decode_value(&key, &value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets `key` to `value`.
+    pub fn set(&mut self, key: &K, value: &V) -> Result<()> {
+        self.engine
+            .set(encode_key(key)?, serde_json::to_string(value)?)
+    }
+
+    /// Sets `key` to `value`, expiring after `ttl`.
+    ///
+    /// # Errors
+    ///
+    /// Engines without expiration fail with `MyError::StringError`.
+    pub fn set_with_ttl(&mut self, key: &K, value: &V, ttl: Duration) -> Result<()> {
+        self.engine
+            .set_with_ttl(encode_key(key)?, serde_json::to_string(value)?, ttl)
+    }
+
+    /// Whether `key` is set.
+    pub fn contains_key(&mut self, key: &K) -> Result<bool> {
+        self.engine.contains_key(encode_key(key)?)
+    }
+
+    /// Removes `key`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn remove(&mut self, key: &K) -> Result<()> {
+        self.engine.remove(encode_key(key)?)
+    }
+
+    /// Every key and value of the store, in key order.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::StringError` if a key does not decode as a `K`
+    /// or a value as a `V`: the store should only hold what the wrapper
+    /// wrote.
+    pub fn entries(&mut self) -> Result<Vec<(K, V)>> {
+        self.engine
+            .scan(String::new())?
+            .into_iter()
+            .map(|(key, value)| Ok((decode_key(&key)?, decode_value(&key, &value)?)))
+            .collect()
+    }
+}
+
+fn encode_key<K: Serialize>(key: &K) -> Result<String> {
+    match serde_json::to_value(key)? {
+        Value::String(key) => Ok(key),
+        key => Ok(key.to_string()),
+    }
+}
+
+/// Decodes `key` as a string first, so that string keys looking like JSON
+/// decode as the string they are.
+fn decode_key<K: DeserializeOwned>(key: &str) -> Result<K> {
+    serde_json::from_value(Value::String(key.to_owned()))
+        .or_else(|_| serde_json::from_str(key))
+        .map_err(|e| {
+            MyError::StringError(format!(
+                "Key `{}` is not a {}: {}",
+                key,
+                type_name::<K>(),
+                e
+            ))
+        })
+}
+
+fn decode_value<V: DeserializeOwned>(key: &str, value: &str) -> Result<V> {
+    serde_json::from_str(value).map_err(|e| {
+        MyError::StringError(format!(
+            "`{}` does not hold a {}: {}",
+            key,
+            type_name::<V>(),
+            e
+        ))
+    })
+}
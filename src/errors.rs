@@ -1,33 +1,220 @@
+use crate::common::ErrorCode;
+use std::fmt;
 use std::io::{self};
+use std::path::{Path, PathBuf};
 use std::string;
+use thiserror::Error;
 
 //Error Management
-#[derive(Fail, Debug)]
+#[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum MyError {
-    #[fail(display = "Key not found")]
+    #[error("Key not found")]
     KeyNotFound,
-    #[fail(display = "{}", _0)]
-    Io(#[cause] std::io::Error),
-    #[fail(display = "{}", _0)]
-    DeserializeError(#[cause] serde_json::error::Error),
+    #[error("{0}")]
+    Io(#[source] std::io::Error),
+    /// A file of the store could not be read or written.
+    #[error("{}: {source}", path.display())]
+    File {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{0}")]
+    DeserializeError(#[source] serde_json::error::Error),
     /// Error with a string message
-    #[fail(display = "{}", _0)]
+    #[error("{0}")]
     StringError(String),
-    #[fail(display = "{}", _0)]
-    Sled(#[cause] sled::Error),
-    #[fail(display = "UTF-8 error: {}", _0)]
-    Utf8(#[fail(cause)] string::FromUtf8Error),
+    #[error("{0}")]
+    Sled(#[source] sled::Error),
+    #[cfg(feature = "rocksdb")]
+    #[error("{0}")]
+    RocksDb(#[source] rocksdb::Error),
+    /// The server requires an authentication token and none or a wrong one
+    /// was presented.
+    #[error("Unauthorized")]
+    Unauthorized,
+    /// The authenticated token may not perform this operation on this key.
+    #[error("Permission denied")]
+    PermissionDenied,
+    /// The server already serves as many connections as it allows.
+    #[error("Too many connections")]
+    TooManyConnections,
+    /// The client sent more requests than its rate limit allows.
+    #[error("Rate limit exceeded")]
+    RateLimited,
+    /// A request or a network operation did not complete within its time
+    /// limit.
+    #[error("Request timed out")]
+    Timeout,
+    #[error("UTF-8 error: {0}")]
+    Utf8(#[source] string::FromUtf8Error),
+    /// The server is read-only, e.g. a replica, and refused a write.
+    #[error("Server is read-only")]
+    ReadOnly,
+    /// An error reported by the server without a more specific variant.
+    #[error("{message}")]
+    Server { code: ErrorCode, message: String },
+    /// A key or value is longer than the store allows.
+    #[error("{0}")]
+    TooLarge(String),
+    /// `key` holds a value of another kind than the operation works on,
+    /// e.g. a list for a hash operation.
+    #[error("`{key}` holds a {held}, not a {wanted}")]
+    WrongType {
+        key: String,
+        held: &'static str,
+        wanted: String,
+    },
+    /// A tenant is at one of its quotas, or a store at its disk limit, and
+    /// the request would add data.
+    #[error("{0}")]
+    QuotaExceeded(String),
+    /// A framed message is larger than `MAX_MESSAGE_LEN`, or than the
+    /// server allows.
+    #[error("Message of {0} bytes exceeds the size limit")]
+    MessageTooLarge(usize),
+    /// A message in the MessagePack codec could not be encoded.
+    #[error("{0}")]
+    MessagePackEncode(#[source] rmp_serde::encode::Error),
+    /// A message in the MessagePack codec could not be decoded.
+    #[error("{0}")]
+    MessagePackDecode(#[source] rmp_serde::decode::Error),
+    /// Stored data failed its checksum or could not be decoded, in the
+    /// file at `path` and at byte `offset` of it when known.
+    #[error("Corrupt data{}: {reason}", Location(path.as_deref(), *offset))]
+    Corrupt {
+        path: Option<PathBuf>,
+        offset: Option<u64>,
+        reason: String,
+    },
+    /// A conditional write found the key at another version than the one
+    /// expected, or a key to restore was set again since its removal.
+    #[error("{0}")]
+    Conflict(String),
+    /// Another process has the data directory open.
+    #[error("Data directory {} is locked by another process", path.display())]
+    AlreadyLocked { path: PathBuf },
+    /// A certificate or key could not be used, or a TLS handshake failed.
+    #[error("TLS error: {0}")]
+    Tls(#[source] rustls::Error),
+}
+
+impl MyError {
+    /// How a server reports this error to its clients.
+    pub(crate) fn code(&self) -> ErrorCode {
+        match self {
+            MyError::KeyNotFound => ErrorCode::KeyNotFound,
+            MyError::Unauthorized => ErrorCode::Unauthorized,
+            MyError::PermissionDenied => ErrorCode::PermissionDenied,
+            MyError::TooManyConnections => ErrorCode::TooManyConnections,
+            MyError::RateLimited => ErrorCode::RateLimited,
+            MyError::Timeout => ErrorCode::Timeout,
+            MyError::ReadOnly => ErrorCode::ReadOnly,
+            MyError::TooLarge(_) => ErrorCode::TooLarge,
+            MyError::QuotaExceeded(_) => ErrorCode::QuotaExceeded,
+            MyError::Conflict(_) => ErrorCode::Conflict,
+            MyError::MessageTooLarge(_) => ErrorCode::InvalidRequest,
+            // requests are decoded apart, so these come from stored data
+            MyError::DeserializeError(_)
+            | MyError::MessagePackEncode(_)
+            | MyError::MessagePackDecode(_)
+            | MyError::Utf8(_)
+            | MyError::Corrupt { .. } => ErrorCode::Corruption,
+            MyError::Io(_)
+            | MyError::File { .. }
+            | MyError::Sled(_)
+            | MyError::AlreadyLocked { .. } => ErrorCode::EngineError,
+            #[cfg(feature = "rocksdb")]
+            MyError::RocksDb(_) => ErrorCode::EngineError,
+            MyError::Server { code, .. } => *code,
+            MyError::StringError(_) | MyError::WrongType { .. } | MyError::Tls(_) => {
+                ErrorCode::Other
+            }
+        }
+    }
+
+    /// Whether the store itself failed: its data is corrupt, or its files
+    /// cannot be read or written. Other requests are likely to fail the
+    /// same way, unlike after a bad request or a missing key.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            MyError::Corrupt { .. }
+            | MyError::File { .. }
+            | MyError::Sled(_)
+            | MyError::AlreadyLocked { .. } => true,
+            #[cfg(feature = "rocksdb")]
+            MyError::RocksDb(_) => true,
+            _ => false,
+        }
+    }
+
+    /// `Corrupt` data, where the store does not know.
+    pub(crate) fn corrupt(reason: impl Into<String>) -> MyError {
+        MyError::Corrupt {
+            path: None,
+            offset: None,
+            reason: reason.into(),
+        }
+    }
+
+    /// Turns I/O errors on the file at `path` into `File` errors.
+    pub(crate) fn file(path: &Path) -> impl FnOnce(io::Error) -> MyError + '_ {
+        move |source| MyError::File {
+            path: path.to_owned(),
+            source,
+        }
+    }
+}
+
+/// Where corrupt data is, for messages: ` in PATH at byte OFFSET`.
+struct Location<'a>(Option<&'a Path>, Option<u64>);
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(path) = self.0 {
+            write!(f, " in {}", path.display())?;
+        }
+        if let Some(offset) = self.1 {
+            write!(f, " at byte {}", offset)?;
+        }
+        Ok(())
+    }
 }
 
 impl From<io::Error> for MyError {
     fn from(err: io::Error) -> MyError {
-        MyError::Io(err)
+        match err.kind() {
+            // what socket read and write timeouts report
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => MyError::Timeout,
+            _ => MyError::Io(err),
+        }
     }
 }
 
 impl From<serde_json::error::Error> for MyError {
     fn from(err: serde_json::error::Error) -> MyError {
-        MyError::DeserializeError(err)
+        if err.is_io() {
+            io::Error::from(err).into()
+        } else {
+            MyError::DeserializeError(err)
+        }
+    }
+}
+impl From<rmp_serde::encode::Error> for MyError {
+    fn from(err: rmp_serde::encode::Error) -> MyError {
+        MyError::MessagePackEncode(err)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for MyError {
+    fn from(err: rmp_serde::decode::Error) -> MyError {
+        MyError::MessagePackDecode(err)
+    }
+}
+impl From<rustls::Error> for MyError {
+    fn from(err: rustls::Error) -> MyError {
+        MyError::Tls(err)
     }
 }
 impl From<sled::Error> for MyError {
@@ -35,17 +222,17 @@ impl From<sled::Error> for MyError {
         MyError::Sled(err)
     }
 }
+#[cfg(feature = "rocksdb")]
+impl From<rocksdb::Error> for MyError {
+    fn from(err: rocksdb::Error) -> MyError {
+        MyError::RocksDb(err)
+    }
+}
 impl From<string::FromUtf8Error> for MyError {
     fn from(err: string::FromUtf8Error) -> MyError {
         MyError::Utf8(err)
     }
 }
 
-/*impl fmt::Display for MyError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "An error occurred.")
-    }
-}*/
-
 /// Result type for kvs.
 pub type Result<T> = std::result::Result<T, MyError>;
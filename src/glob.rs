@@ -0,0 +1,152 @@
+//! Glob patterns over keys, for enumerating part of a keyspace.
+use crate::{MyError, Result};
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+
+/// A glob pattern such as `session:*:active`: `*` matches any run of
+/// characters, `?` any single one, and `[a-z]` one of a class, `[!a-z]` or
+/// `[^a-z]` one outside it. `\` makes the next character literal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Glob {
+    tokens: Vec<Token>,
+    prefix: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Literal(char),
+    /// `?`
+    Any,
+    /// `*`
+    AnyRun,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Token {
+    /// Whether the token matches `c` on its own; `*` matches any one.
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Token::Literal(literal) => *literal == c,
+            Token::Any | Token::AnyRun => true,
+            Token::Class { negated, ranges } => {
+                ranges.iter().any(|(low, high)| (*low..=*high).contains(&c)) != *negated
+            }
+        }
+    }
+}
+
+impl Glob {
+    /// Whether `key` matches the whole pattern.
+    pub(crate) fn matches(&self, key: &str) -> bool {
+        let key: Vec<char> = key.chars().collect();
+        let (mut token, mut at) = (0, 0);
+        // the token after the last `*` and how much of the key it took, to
+        // make it take one more character when the rest fails to match
+        let mut backtrack = None;
+        while at < key.len() {
+            match self.tokens.get(token) {
+                Some(Token::AnyRun) => {
+                    token += 1;
+                    backtrack = Some((token, at));
+                }
+                Some(next) if next.matches(key[at]) => {
+                    token += 1;
+                    at += 1;
+                }
+                _ => match backtrack {
+                    Some((after, from)) => {
+                        token = after;
+                        at = from + 1;
+                        backtrack = Some((after, at));
+                    }
+                    None => return false,
+                },
+            }
+        }
+        self.tokens[token..]
+            .iter()
+            .all(|token| *token == Token::AnyRun)
+    }
+
+    /// The literal start of the pattern, which every matching key starts
+    /// with.
+    pub(crate) fn prefix(&self) -> &str {
+        &self.prefix
+    }
+}
+
+/// The start of `pattern` before its first special character.
+pub(crate) fn literal_prefix(pattern: &str) -> &str {
+    let end = pattern.find(['*', '?', '[', '\\']).unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+/// A pattern matching `literal` and nothing else.
+#[cfg(feature = "grpc")]
+pub(crate) fn escape(literal: &str) -> String {
+    let mut pattern = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern
+}
+
+impl FromStr for Glob {
+    type Err = MyError;
+
+    fn from_str(pattern: &str) -> Result<Glob> {
+        let invalid = |reason: &str| {
+            MyError::StringError(format!("Invalid pattern `{}`: {}", pattern, reason))
+        };
+        let mut chars = pattern.chars().peekable();
+        let mut tokens = Vec::new();
+        while let Some(c) = chars.next() {
+            tokens.push(match c {
+                '*' => Token::AnyRun,
+                '?' => Token::Any,
+                '\\' => Token::Literal(chars.next().ok_or_else(|| invalid("trailing `\\`"))?),
+                '[' => parse_class(&mut chars).ok_or_else(|| invalid("unclosed `[`"))?,
+                c => Token::Literal(c),
+            });
+        }
+        Ok(Glob {
+            tokens,
+            prefix: literal_prefix(pattern).to_owned(),
+        })
+    }
+}
+
+/// Parses a class after its `[`, up to its `]`; a `]` right after the `[`
+/// belongs to the class.
+fn parse_class(chars: &mut Peekable<Chars>) -> Option<Token> {
+    let negated = chars.next_if(|c| matches!(c, '!' | '^')).is_some();
+    let mut ranges = Vec::new();
+    loop {
+        let low = match chars.next()? {
+            ']' if !ranges.is_empty() => break,
+            '\\' => chars.next()?,
+            c => c,
+        };
+        let mut high = low;
+        if chars.next_if_eq(&'-').is_some() {
+            high = match chars.next()? {
+                // a `-` before the `]` is literal
+                ']' => {
+                    ranges.push((low, low));
+                    ranges.push(('-', '-'));
+                    break;
+                }
+                '\\' => chars.next()?,
+                c => c,
+            };
+        }
+        ranges.push((low.min(high), low.max(high)));
+    }
+    Some(Token::Class { negated, ranges })
+}
@@ -0,0 +1,362 @@
+//! The gRPC service of `proto/kvs.proto`, for clients in other languages,
+//! and a blocking client of it.
+//!
+//! The service answers through the same code as the TCP listener, so
+//! tokens, ACLs, quotas, replication and the audit log apply alike. A token
+//! goes in the `authorization` metadata, as `Bearer <token>`.
+//!
+//! Available with the `grpc` feature.
+use crate::common::WireError;
+use crate::common::{
+    ErrorCode, GetManyResponse, GetResponse, ReadConsistency, RemoveResponse, Request,
+    ScanResponse, SetResponse,
+};
+use crate::glob;
+use crate::{MyError, Result};
+use proto::kvs_client::KvsClient;
+use proto::kvs_server::{Kvs, KvsServer};
+use proto::{GetReply, GetRequest, KeyValue, RemoveReply, RemoveRequest, ScanRequest};
+use proto::{SetReply, SetRequest};
+use serde::de::DeserializeOwned;
+use std::convert::TryFrom;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Response, Status};
+
+/// The messages and stubs generated from `proto/kvs.proto`.
+pub mod proto {
+    tonic::include_proto!("kvs");
+}
+
+/// How often the server checks whether it was shut down.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+/// Keys a `Scan` looks at for each page it streams.
+const SCAN_PAGE_KEYS: u32 = 1000;
+
+/// Answers a request for the peer at the given address, authenticated with
+/// the token if any, and returns the encoded response.
+pub(crate) type Handler = Arc<dyn Fn(Request, Option<&str>, &str) -> Result<Vec<u8>> + Send + Sync>;
+
+/// Serves the gRPC service on `listener` until `stopped` says so.
+pub(crate) fn serve(
+    listener: TcpListener,
+    handler: Handler,
+    stopped: impl Fn() -> bool + Send + 'static,
+) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async move {
+        listener.set_nonblocking(true)?;
+        let incoming = TcpListenerStream::new(tokio::net::TcpListener::from_std(listener)?);
+        let shutdown = async move {
+            while !stopped() {
+                tokio::time::sleep(SHUTDOWN_POLL).await;
+            }
+        };
+        tonic::transport::Server::builder()
+            .add_service(KvsServer::new(Service { handler }))
+            .serve_with_incoming_shutdown(incoming, shutdown)
+            .await
+            .map_err(|e| MyError::StringError(format!("gRPC server failed: {}", e)))
+    })
+}
+
+struct Service {
+    handler: Handler,
+}
+
+/// Who sent `request`: its token, and its address for the audit log.
+fn caller<T>(request: &tonic::Request<T>) -> (Option<String>, String) {
+    let token = request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_owned);
+    let peer = request
+        .remote_addr()
+        .map_or_else(|| "grpc".to_owned(), |addr| addr.to_string());
+    (token, peer)
+}
+
+/// Answers `req` with `handler` and decodes the response.
+fn answer<T: DeserializeOwned>(
+    handler: &Handler,
+    req: Request,
+    token: Option<&str>,
+    peer: &str,
+) -> std::result::Result<T, Status> {
+    let response = handler(req, token, peer).map_err(|e| status(&e))?;
+    serde_json::from_slice(&response).map_err(|e| Status::internal(e.to_string()))
+}
+
+impl Service {
+    /// Answers `req` on a blocking thread, as engines block.
+    async fn call<T: DeserializeOwned + Send + 'static>(
+        &self,
+        req: Request,
+        (token, peer): (Option<String>, String),
+    ) -> std::result::Result<T, Status> {
+        let handler = Arc::clone(&self.handler);
+        tokio::task::spawn_blocking(move || answer(&handler, req, token.as_deref(), &peer))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+    }
+}
+
+#[tonic::async_trait]
+impl Kvs for Service {
+    async fn get(
+        &self,
+        request: tonic::Request<GetRequest>,
+    ) -> std::result::Result<Response<GetReply>, Status> {
+        let caller = caller(&request);
+        let req = Request::Get {
+            key: request.into_inner().key,
+            consistency: ReadConsistency::default(),
+        };
+        match self.call(req, caller).await? {
+            GetResponse::Ok(value) => Ok(Response::new(GetReply { value })),
+            GetResponse::Err(err) => Err(wire_status(err)),
+        }
+    }
+
+    async fn set(
+        &self,
+        request: tonic::Request<SetRequest>,
+    ) -> std::result::Result<Response<SetReply>, Status> {
+        let caller = caller(&request);
+        let SetRequest { key, value } = request.into_inner();
+        let req = Request::Set {
+            key,
+            value,
+            ttl_ms: None,
+        };
+        match self.call(req, caller).await? {
+            SetResponse::Ok(()) => Ok(Response::new(SetReply {})),
+            SetResponse::Err(err) => Err(wire_status(err)),
+        }
+    }
+
+    async fn remove(
+        &self,
+        request: tonic::Request<RemoveRequest>,
+    ) -> std::result::Result<Response<RemoveReply>, Status> {
+        let caller = caller(&request);
+        let req = Request::Remove {
+            key: request.into_inner().key,
+        };
+        match self.call(req, caller).await? {
+            RemoveResponse::Ok(()) => Ok(Response::new(RemoveReply {})),
+            RemoveResponse::Err(err) => Err(wire_status(err)),
+        }
+    }
+
+    type ScanStream = ReceiverStream<std::result::Result<KeyValue, Status>>;
+
+    /// Streams the pairs a page at a time: the keys of a `Scan` page, then
+    /// their values, skipping the keys removed in between.
+    async fn scan(
+        &self,
+        request: tonic::Request<ScanRequest>,
+    ) -> std::result::Result<Response<Self::ScanStream>, Status> {
+        let (token, peer) = caller(&request);
+        let pattern = format!("{}*", glob::escape(&request.into_inner().prefix));
+        let handler = Arc::clone(&self.handler);
+        let (pairs, stream) = mpsc::channel(SCAN_PAGE_KEYS as usize);
+        tokio::task::spawn_blocking(move || {
+            let mut cursor = None;
+            loop {
+                let page = answer(
+                    &handler,
+                    Request::Scan {
+                        cursor: cursor.take(),
+                        count: SCAN_PAGE_KEYS,
+                        pattern: pattern.clone(),
+                        consistency: ReadConsistency::default(),
+                    },
+                    token.as_deref(),
+                    &peer,
+                );
+                let page = match page {
+                    Ok(ScanResponse::Ok(page)) => Ok(page),
+                    Ok(ScanResponse::Err(err)) => Err(wire_status(err)),
+                    Err(status) => Err(status),
+                }
+                .and_then(|page| {
+                    if page.keys.is_empty() {
+                        return Ok((Vec::new(), page.cursor));
+                    }
+                    let req = Request::GetMany {
+                        keys: page.keys.clone(),
+                    };
+                    match answer(&handler, req, token.as_deref(), &peer)? {
+                        GetManyResponse::Ok(values) => {
+                            let found = page.keys.into_iter().zip(values);
+                            let found = found.filter_map(|(key, value)| Some((key, value?)));
+                            Ok((found.collect(), page.cursor))
+                        }
+                        GetManyResponse::Err(err) => Err(wire_status(err)),
+                    }
+                });
+                let (found, next) = match page {
+                    Ok(page) => page,
+                    Err(status) => {
+                        let _ = pairs.blocking_send(Err(status));
+                        return;
+                    }
+                };
+                for (key, value) in found {
+                    // the client went away
+                    if pairs.blocking_send(Ok(KeyValue { key, value })).is_err() {
+                        return;
+                    }
+                }
+                match next {
+                    Some(next) => cursor = Some(next),
+                    None => return,
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+}
+
+fn wire_status(err: WireError) -> Status {
+    status(&err.into())
+}
+
+/// How the gRPC service reports `err`.
+fn status(err: &MyError) -> Status {
+    let code = match err.code() {
+        ErrorCode::KeyNotFound => Code::NotFound,
+        ErrorCode::Unauthorized => Code::Unauthenticated,
+        ErrorCode::PermissionDenied => Code::PermissionDenied,
+        ErrorCode::TooManyConnections | ErrorCode::RateLimited | ErrorCode::QuotaExceeded => {
+            Code::ResourceExhausted
+        }
+        ErrorCode::Timeout => Code::DeadlineExceeded,
+        ErrorCode::ReadOnly | ErrorCode::Conflict => Code::FailedPrecondition,
+        ErrorCode::TooLarge | ErrorCode::InvalidRequest => Code::InvalidArgument,
+        ErrorCode::Unavailable => Code::Unavailable,
+        ErrorCode::Corruption => Code::DataLoss,
+        ErrorCode::EngineError | ErrorCode::Other => Code::Internal,
+    };
+    Status::new(code, err.to_string())
+}
+
+/// The error a `Status` of the gRPC service stands for.
+fn error(status: Status) -> MyError {
+    match status.code() {
+        Code::NotFound => MyError::KeyNotFound,
+        Code::Unauthenticated => MyError::Unauthorized,
+        Code::PermissionDenied => MyError::PermissionDenied,
+        Code::DeadlineExceeded => MyError::Timeout,
+        _ => MyError::StringError(status.message().to_owned()),
+    }
+}
+
+/// A blocking client of the gRPC service of a server, see
+/// `Server::with_grpc`.
+///
+/// Example:
+///
+/// ```no_run
+/// # use kvs::{GrpcClient, Result};
+/// # fn try_main() -> Result<()> {
+/// let mut client = GrpcClient::connect("127.0.0.1:4002".parse().unwrap())?;
+/// client.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct GrpcClient {
+    runtime: Runtime,
+    client: KvsClient<Channel>,
+    token: Option<String>,
+}
+
+impl GrpcClient {
+    /// Connect to the gRPC service at `addr`.
+    pub fn connect(addr: SocketAddr) -> Result<GrpcClient> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let endpoint = Endpoint::from_shared(format!("http://{}", addr))
+            .map_err(|e| MyError::StringError(e.to_string()))?;
+        let channel = runtime
+            .block_on(endpoint.connect())
+            .map_err(|e| MyError::StringError(format!("gRPC connection failed: {}", e)))?;
+        let client = KvsClient::new(channel);
+        Ok(GrpcClient {
+            runtime,
+            client,
+            token: None,
+        })
+    }
+
+    /// Authenticate every request with `token`.
+    pub fn with_auth_token(mut self, token: String) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Get the value of `key`, `None` if it does not exist.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let request = self.request(GetRequest { key })?;
+        let reply = self.runtime.block_on(self.client.get(request));
+        Ok(reply.map_err(error)?.into_inner().value)
+    }
+
+    /// Set `key` to `value`.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let request = self.request(SetRequest { key, value })?;
+        self.runtime
+            .block_on(self.client.set(request))
+            .map_err(error)?;
+        Ok(())
+    }
+
+    /// Remove `key`.
+    ///
+    /// Fails with `MyError::KeyNotFound` if it does not exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let request = self.request(RemoveRequest { key })?;
+        self.runtime
+            .block_on(self.client.remove(request))
+            .map_err(error)?;
+        Ok(())
+    }
+
+    /// Every key starting with `prefix` and its value, in key order.
+    pub fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        let request = self.request(ScanRequest { prefix })?;
+        let client = &mut self.client;
+        self.runtime.block_on(async move {
+            let mut stream = client.scan(request).await.map_err(error)?.into_inner();
+            let mut pairs = Vec::new();
+            while let Some(pair) = stream.message().await.map_err(error)? {
+                pairs.push((pair.key, pair.value));
+            }
+            Ok(pairs)
+        })
+    }
+
+    fn request<T>(&self, message: T) -> Result<tonic::Request<T>> {
+        let mut request = tonic::Request::new(message);
+        if let Some(token) = &self.token {
+            let value = MetadataValue::try_from(format!("Bearer {}", token))
+                .map_err(|e| MyError::StringError(format!("Invalid token: {}", e)))?;
+            request.metadata_mut().insert("authorization", value);
+        }
+        Ok(request)
+    }
+}
@@ -0,0 +1,208 @@
+//! Composite keys encoded so that string order is tuple order.
+use crate::{MyError, Result};
+
+/// Tag of a string part, which runs to the next `TERMINATOR`.
+const STR_TAG: char = 's';
+/// Tag of a `u64` part, as 16 hex digits.
+const U64_TAG: char = 'u';
+/// Tag of an `i64` part, as 16 hex digits with the sign bit flipped.
+const I64_TAG: char = 'i';
+/// Ends a string part. It sorts before the escaped NUL, so that a string
+/// sorts before those it is a prefix of, and is not a prefix of it, so that
+/// scanning by a string does not reach the strings continuing it with NULs.
+const TERMINATOR: &str = "\0\u{1}";
+/// A NUL inside a string part.
+const ESCAPED_NUL: &str = "\0\u{ff}";
+
+/// One part of a composite key.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum KeyPart {
+    Str(String),
+    U64(u64),
+    I64(i64),
+}
+
+impl From<&str> for KeyPart {
+    fn from(part: &str) -> KeyPart {
+        KeyPart::Str(part.to_owned())
+    }
+}
+
+impl From<String> for KeyPart {
+    fn from(part: String) -> KeyPart {
+        KeyPart::Str(part)
+    }
+}
+
+impl From<u64> for KeyPart {
+    fn from(part: u64) -> KeyPart {
+        KeyPart::U64(part)
+    }
+}
+
+impl From<i64> for KeyPart {
+    fn from(part: i64) -> KeyPart {
+        KeyPart::I64(part)
+    }
+}
+
+/// A key made of several parts, e.g. a user and a timestamp, encoded as a
+/// string key whose order is that of the parts, the first first.
+///
+/// Stores keep their keys in string order, so the keys sharing their first
+/// parts are next to each other: `scan` with the encoding of those parts
+/// as the prefix lists them, in the order of the following parts. Numbers
+/// are encoded with a fixed width, so that 9 comes before 10, and negative
+/// `i64`s before positive ones.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{Key, KvsEngine, MemEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// let mut store = MemEngine::new();
+/// for ts in vec![10u64, 9, 11] {
+///     store.set(Key::from(("user", 42u64, ts)).encode(), "event".to_owned())?;
+/// }
+/// let prefix = Key::from(("user", 42u64)).encode();
+/// let events: Vec<Key> = store
+///     .scan(prefix)?
+///     .iter()
+///     .map(|(key, _)| Key::decode(key))
+///     .collect::<Result<_>>()?;
+/// assert_eq!(events[0], Key::from(("user", 42u64, 9u64)));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Key {
+    parts: Vec<KeyPart>,
+}
+
+impl Key {
+    /// A key without parts, which encodes as the empty string.
+    pub fn new() -> Key {
+        Key::default()
+    }
+
+    /// The key with `part` added at the end.
+    pub fn with(mut self, part: impl Into<KeyPart>) -> Key {
+        self.parts.push(part.into());
+        self
+    }
+
+    /// The parts of the key, in order.
+    pub fn parts(&self) -> &[KeyPart] {
+        &self.parts
+    }
+
+    /// The string key the key is stored under. The encoding of a key is a
+    /// prefix of those of the keys adding parts to it.
+    pub fn encode(&self) -> String {
+        let mut encoded = String::new();
+        for part in &self.parts {
+            match part {
+                KeyPart::Str(part) => {
+                    encoded.push(STR_TAG);
+                    encoded.push_str(&part.replace('\0', ESCAPED_NUL));
+                    encoded.push_str(TERMINATOR);
+                }
+                KeyPart::U64(part) => {
+                    encoded.push(U64_TAG);
+                    encoded.push_str(&format!("{:016x}", part));
+                }
+                KeyPart::I64(part) => {
+                    encoded.push(I64_TAG);
+                    encoded.push_str(&format!("{:016x}", (*part as u64) ^ (1 << 63)));
+                }
+            }
+        }
+        encoded
+    }
+
+    /// The key `encoded` is the encoding of.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::StringError` if `encoded` is not the encoding
+    /// of a key.
+    pub fn decode(encoded: &str) -> Result<Key> {
+        let mut key = Key::new();
+        let mut chars = encoded.chars().peekable();
+        while let Some(tag) = chars.next() {
+            let part = match tag {
+                STR_TAG => {
+                    let mut part = String::new();
+                    loop {
+                        match (chars.next(), chars.peek()) {
+                            (Some('\0'), Some('\u{1}')) => {
+                                chars.next();
+                                break;
+                            }
+                            (Some('\0'), Some('\u{ff}')) => {
+                                chars.next();
+                                part.push('\0');
+                            }
+                            (Some('\0'), _) => return Err(key_error(encoded, "stray NUL")),
+                            (Some(c), _) => part.push(c),
+                            (None, _) => return Err(key_error(encoded, "unterminated string")),
+                        }
+                    }
+                    KeyPart::Str(part)
+                }
+                U64_TAG | I64_TAG => {
+                    let digits: String = chars.by_ref().take(16).collect();
+                    let number = match digits.len() {
+                        16 => u64::from_str_radix(&digits, 16).ok(),
+                        _ => None,
+                    }
+                    .ok_or_else(|| key_error(encoded, "truncated number"))?;
+                    if tag == U64_TAG {
+                        KeyPart::U64(number)
+                    } else {
+                        KeyPart::I64((number ^ (1 << 63)) as i64)
+                    }
+                }
+                tag => return Err(key_error(encoded, &format!("unknown tag `{}`", tag))),
+            };
+            key.parts.push(part);
+        }
+        Ok(key)
+    }
+}
+
+impl<A: Into<KeyPart>> From<(A,)> for Key {
+    fn from((a,): (A,)) -> Key {
+        Key::new().with(a)
+    }
+}
+
+impl<A: Into<KeyPart>, B: Into<KeyPart>> From<(A, B)> for Key {
+    fn from((a, b): (A, B)) -> Key {
+        Key::new().with(a).with(b)
+    }
+}
+
+impl<A: Into<KeyPart>, B: Into<KeyPart>, C: Into<KeyPart>> From<(A, B, C)> for Key {
+    fn from((a, b, c): (A, B, C)) -> Key {
+        Key::new().with(a).with(b).with(c)
+    }
+}
+
+impl<A: Into<KeyPart>, B: Into<KeyPart>, C: Into<KeyPart>, D: Into<KeyPart>> From<(A, B, C, D)>
+    for Key
+{
+    fn from((a, b, c, d): (A, B, C, D)) -> Key {
+        Key::new().with(a).with(b).with(c).with(d)
+    }
+}
+
+impl From<Key> for String {
+    fn from(key: Key) -> String {
+        key.encode()
+    }
+}
+
+fn key_error(encoded: &str, msg: &str) -> MyError {
+    MyError::StringError(format!("Invalid composite key {:?}: {}", encoded, msg))
+}
@@ -0,0 +1,171 @@
+//! Histograms of how long requests take, per type of request.
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Buckets per power of two of microseconds, bounding the error of a
+/// percentile to about 3%.
+const SUB_BUCKETS: u64 = 32;
+const SUB_BUCKET_BITS: u32 = 5;
+
+/// Latencies are recorded up to 2^40 microseconds, about 12 days; longer
+/// ones count as that.
+const MAX_MICROS: u64 = (1 << 40) - 1;
+
+/// Percentiles of a latency histogram.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// How long the requests of one type took since the server started.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LatencyStats {
+    /// Requests recorded.
+    pub count: u64,
+    /// From the request being read to its response being sent.
+    pub service: Percentiles,
+    /// Waiting for the engine lock, held by other requests or by a
+    /// compaction.
+    pub queue: Percentiles,
+    /// The rest of the service time: running the request on the engine and
+    /// sending the response.
+    pub engine: Percentiles,
+}
+
+/// Counts of latencies in buckets growing with them, as in HDR histograms:
+/// the first `SUB_BUCKETS` hold a microsecond each, then every power of two
+/// is split in `SUB_BUCKETS`.
+struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            counts: vec![0; bucket(MAX_MICROS) + 1],
+            total: 0,
+            max: 0,
+        }
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let micros = (latency.as_micros() as u64).min(MAX_MICROS);
+        self.counts[bucket(micros)] += 1;
+        self.total += 1;
+        self.max = self.max.max(micros);
+    }
+
+    /// The latency `quantile` of the recorded ones are at most, rounded up
+    /// to the end of its bucket.
+    fn quantile(&self, quantile: f64) -> Duration {
+        let rank = ((self.total as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(bucket_end(index).min(self.max));
+            }
+        }
+        Duration::from_micros(self.max)
+    }
+
+    fn percentiles(&self) -> Percentiles {
+        Percentiles {
+            p50: self.quantile(0.50),
+            p95: self.quantile(0.95),
+            p99: self.quantile(0.99),
+            max: Duration::from_micros(self.max),
+        }
+    }
+}
+
+/// Index of the bucket holding `micros`.
+fn bucket(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let power = 63 - micros.leading_zeros();
+    let sub_bucket = (micros >> (power - SUB_BUCKET_BITS)) & (SUB_BUCKETS - 1);
+    ((power - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS + sub_bucket) as usize
+}
+
+/// Largest latency in microseconds that bucket `index` holds.
+fn bucket_end(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    let start = (SUB_BUCKETS + index % SUB_BUCKETS) << shift;
+    start + (1 << shift) - 1
+}
+
+/// Service, queueing and engine time of a type of request.
+struct Histograms {
+    service: Histogram,
+    queue: Histogram,
+    engine: Histogram,
+}
+
+/// Latency histograms of every type of request the server handled.
+#[derive(Default)]
+pub(crate) struct Latencies {
+    requests: Mutex<HashMap<&'static str, Histograms>>,
+}
+
+impl Latencies {
+    /// Records a request of type `request` served in `service`, of which
+    /// it waited `queue` for the engine.
+    pub(crate) fn record(&self, request: &'static str, service: Duration, queue: Duration) {
+        let mut requests = self.requests.lock().unwrap();
+        let histograms = requests.entry(request).or_insert_with(|| Histograms {
+            service: Histogram::new(),
+            queue: Histogram::new(),
+            engine: Histogram::new(),
+        });
+        histograms.service.record(service);
+        histograms.queue.record(queue);
+        histograms.engine.record(service.saturating_sub(queue));
+    }
+
+    /// Percentiles of every type of request recorded, by type.
+    pub(crate) fn report(&self) -> BTreeMap<String, LatencyStats> {
+        let requests = self.requests.lock().unwrap();
+        requests
+            .iter()
+            .map(|(request, histograms)| {
+                let stats = LatencyStats {
+                    count: histograms.service.total,
+                    service: histograms.service.percentiles(),
+                    queue: histograms.queue.percentiles(),
+                    engine: histograms.engine.percentiles(),
+                };
+                (request.to_string(), stats)
+            })
+            .collect()
+    }
+}
+
+thread_local! {
+    /// Time the request handled on this thread waited for the engine lock.
+    static QUEUE_TIME: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
+
+/// Adds `waited` to the queueing time of the current request.
+pub(crate) fn add_queue_time(waited: Duration) {
+    QUEUE_TIME.with(|queue| queue.set(queue.get() + waited));
+}
+
+/// The queueing time of the current request, starting the next one at
+/// zero.
+pub(crate) fn take_queue_time() -> Duration {
+    QUEUE_TIME.with(|queue| queue.replace(Duration::ZERO))
+}
@@ -1,19 +1,84 @@
 //#![deny(missing_docs)]
 
+mod acl;
+mod audit;
+mod cache;
 mod client;
+mod cluster;
+mod codec;
 mod common;
+mod config;
 mod engine;
 mod errors;
+mod glob;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod key;
+mod latency;
+#[cfg(feature = "otel")]
+mod otel;
+mod pool;
+mod pubsub;
+#[cfg(feature = "raft")]
+mod raft;
+mod ratelimit;
+mod replication;
+mod retry;
+mod routing;
+#[cfg(feature = "scripting")]
+mod script;
 mod server;
+mod slowlog;
+mod snapshot;
+mod tenant;
+#[cfg(feature = "testing")]
+pub mod testkit;
+mod tls;
+mod transport;
+mod websocket;
 
-extern crate failure;
-#[macro_use]
-extern crate failure_derive;
-
-pub use client::KvsClient;
-pub use engine::{KvStore, KvsEngine, SledKvsEngine};
+pub use acl::Acl;
+pub use audit::{AuditLog, AuditRecord};
+pub use client::{
+    Export, KvsClient, KvsClientBuilder, LockGuard, Pipeline, ScanIter, Subscription, WatchHandle,
+};
+pub use cluster::KvsClusterClient;
+pub use codec::{Codec, Compression};
+pub use common::{
+    ErrorCode, Event, Pong, ReadConsistency, ServerInfo, ServerState, PROTOCOL_VERSION,
+};
+pub use config::ServerConfig;
+pub use engine::{
+    dump_log, fsck, migrate, AsyncKvsEngine, BlockingEngine, BoxFuture, Change, CheckReport,
+    ChecksumStatus, Command, CompactionStats, CompactionTrigger, CompactionWindow, EngineStats,
+    EventListener, EvictionPolicy, GroupCommit, HashPartitioner, IndexedEngine, KeyEvent, KeyMeta,
+    KeyOp, KeyVersion, KvReader, KvStore, KvStoreOptions, KvsEngine, KvsReader, LogEntry,
+    LogFormat, LsmEngine, LsmOptions, LsmReader, MemEngine, MemReader, MetricsSink, MigrateReport,
+    OpKind, OpMetrics, PartitionedKvStore, Partitioner, SizeReport, SizeStats, SledKvsEngine,
+    SledReader, SyncPolicy, TypedKvStore, VerifyReport, WriteBatch, WriteBuffer, SIZE_BUCKETS,
+    TRASH_PREFIX,
+};
+#[cfg(feature = "testing")]
+pub use engine::{Fault, FaultPoint, Faults, FaultyEngine, FaultyReader};
+#[cfg(feature = "rocksdb")]
+pub use engine::{RocksKvsEngine, RocksReader};
 pub use errors::{MyError, Result};
-pub use server::Server;
+#[cfg(feature = "grpc")]
+pub use grpc::{proto, GrpcClient};
+pub use key::{Key, KeyPart};
+pub use latency::{LatencyStats, Percentiles};
+#[cfg(feature = "otel")]
+pub use otel::OtlpExporter;
+pub use pool::{KvsPool, PooledClient};
+#[cfg(feature = "raft")]
+pub use raft::RaftConfig;
+pub use replication::Consistency;
+pub use retry::RetryPolicy;
+pub use routing::KvsReplicaClient;
+pub use server::{ReloadHandle, Server, ShutdownHandle, WarmUp};
+pub use slowlog::SlowRequest;
+pub use tenant::{Tenant, Tenants};
+pub use tls::{ClientTlsConfig, ServerTlsConfig};
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,101 @@
+//! OpenTelemetry tracing: clients send the trace context they run in along
+//! with their requests, and the server makes a span of every request,
+//! exported over OTLP.
+use crate::common::TraceContext;
+use crate::{MyError, Result};
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::{Span, SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::collections::HashMap;
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+/// Sends the spans of this process to an OTLP collector until shut down.
+pub struct OtlpExporter {
+    provider: SdkTracerProvider,
+}
+
+impl OtlpExporter {
+    /// Exports the spans of this process, as `service_name`, to the
+    /// OTLP/HTTP collector at `endpoint`, e.g.
+    /// `http://localhost:4318/v1/traces`. Spans are sent in batches from a
+    /// thread of their own.
+    pub fn install(endpoint: &str, service_name: &str) -> Result<OtlpExporter> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|e| {
+                MyError::StringError(format!("Cannot export traces to {}: {}", endpoint, e))
+            })?;
+        let resource = Resource::builder()
+            .with_service_name(service_name.to_owned())
+            .build();
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource)
+            .build();
+        global::set_tracer_provider(provider.clone());
+        Ok(OtlpExporter { provider })
+    }
+
+    /// Sends the spans not exported yet and stops exporting.
+    pub fn shutdown(self) -> Result<()> {
+        self.provider
+            .shutdown()
+            .map_err(|e| MyError::StringError(format!("Exporting traces failed: {}", e)))
+    }
+}
+
+/// The trace context the calling thread runs in, if it is within a span.
+pub(crate) fn current_context() -> Option<TraceContext> {
+    let context = Context::current();
+    if !context.span().span_context().is_valid() {
+        return None;
+    }
+    let mut headers = HashMap::new();
+    TraceContextPropagator::new().inject_context(&context, &mut headers);
+    Some(TraceContext {
+        traceparent: headers.remove(TRACEPARENT)?,
+        tracestate: headers.remove(TRACESTATE).filter(|state| !state.is_empty()),
+    })
+}
+
+/// A span of the server handling a request, ended when dropped.
+pub(crate) struct ServerSpan(global::BoxedSpan);
+
+impl ServerSpan {
+    /// Starts the span of a request of type `request` from `peer`, in the
+    /// trace of the client if it sent its `context`.
+    pub(crate) fn start(request: &'static str, context: Option<TraceContext>, peer: &str) -> Self {
+        let mut headers = HashMap::new();
+        if let Some(context) = context {
+            headers.insert(TRACEPARENT.to_owned(), context.traceparent);
+            if let Some(state) = context.tracestate {
+                headers.insert(TRACESTATE.to_owned(), state);
+            }
+        }
+        let parent = TraceContextPropagator::new().extract(&headers);
+        let tracer = global::tracer("kvs");
+        let span = tracer
+            .span_builder(request)
+            .with_kind(SpanKind::Server)
+            .with_attributes(vec![
+                KeyValue::new("db.system.name", "kvs"),
+                KeyValue::new("db.operation.name", request),
+                KeyValue::new("network.peer.address", peer.to_owned()),
+            ])
+            .start_with_context(&tracer, &parent);
+        ServerSpan(span)
+    }
+
+    /// Marks the request as failed with `message`.
+    pub(crate) fn set_error(&mut self, message: String) {
+        self.0.set_status(Status::error(message));
+    }
+}
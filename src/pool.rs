@@ -0,0 +1,146 @@
+//! A fixed-size pool of client connections shared between threads.
+use crate::client::{KvsClient, KvsClientBuilder};
+use crate::errors::{MyError, Result};
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Pool of up to `size` connections to one server.
+///
+/// Threads `checkout` a connection, use it as a `KvsClient` and give it back
+/// by dropping it, so they neither pay a connection setup per request nor
+/// serialize on a single socket. Cloning the pool shares it.
+///
+/// Example:
+///
+/// ```no_run
+/// # use kvs::{KvsPool, Result};
+/// # fn try_main() -> Result<()> {
+/// let pool = KvsPool::new("127.0.0.1:4000", 4)?;
+/// pool.checkout()?.set("key".to_owned(), "value".to_owned())?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct KvsPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// Every address `addr` resolved to, for the clients to fail over.
+    endpoints: Vec<SocketAddr>,
+    builder: KvsClientBuilder,
+    size: usize,
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+struct State {
+    idle: Vec<KvsClient>,
+    /// Connections idle or checked out.
+    open: usize,
+}
+
+impl KvsPool {
+    /// Open `size` connections to `addr`.
+    pub fn new<A: ToSocketAddrs>(addr: A, size: usize) -> Result<KvsPool> {
+        KvsPool::with_builder(addr, size, KvsClient::builder())
+    }
+
+    /// Open `size` connections to `addr`, each set up by `builder`.
+    pub fn with_builder<A: ToSocketAddrs>(
+        addr: A,
+        size: usize,
+        builder: KvsClientBuilder,
+    ) -> Result<KvsPool> {
+        let endpoints: Vec<_> = addr.to_socket_addrs()?.collect();
+        if endpoints.is_empty() {
+            return Err(MyError::StringError(
+                "Address resolved to nothing".to_owned(),
+            ));
+        }
+        let idle = (0..size)
+            .map(|_| builder.connect(&endpoints[..]))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(KvsPool {
+            inner: Arc::new(Inner {
+                endpoints,
+                builder,
+                size,
+                state: Mutex::new(State { idle, open: size }),
+                released: Condvar::new(),
+            }),
+        })
+    }
+
+    /// Take a connection, waiting for one to be returned if all are in use.
+    /// Connections discarded earlier are replaced here.
+    pub fn checkout(&self) -> Result<PooledClient> {
+        let mut state = self.inner.state.lock().unwrap();
+        loop {
+            if let Some(client) = state.idle.pop() {
+                return Ok(self.pooled(client));
+            }
+            if state.open < self.inner.size {
+                state.open += 1;
+                drop(state);
+                return match self.inner.builder.connect(&self.inner.endpoints[..]) {
+                    Ok(client) => Ok(self.pooled(client)),
+                    Err(e) => {
+                        self.inner.state.lock().unwrap().open -= 1;
+                        self.inner.released.notify_one();
+                        Err(e)
+                    }
+                };
+            }
+            state = self.inner.released.wait(state).unwrap();
+        }
+    }
+
+    fn pooled(&self, client: KvsClient) -> PooledClient {
+        PooledClient {
+            client: Some(client),
+            pool: self.clone(),
+        }
+    }
+}
+
+/// A connection checked out of a `KvsPool`, returned to it when dropped.
+pub struct PooledClient {
+    client: Option<KvsClient>,
+    pool: KvsPool,
+}
+
+impl PooledClient {
+    /// Close the connection instead of returning it, e.g. after an IO
+    /// error left it unusable. The pool opens a new one when needed.
+    pub fn discard(mut self) {
+        self.client = None;
+    }
+}
+
+impl Deref for PooledClient {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        let mut state = self.pool.inner.state.lock().unwrap();
+        match self.client.take() {
+            Some(client) => state.idle.push(client),
+            None => state.open -= 1,
+        }
+        self.pool.inner.released.notify_one();
+    }
+}
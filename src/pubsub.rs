@@ -0,0 +1,199 @@
+//! Fan-out of key-change events to subscribed connections.
+use crate::common::Event;
+use log::warn;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::ops::Deref;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bytes of keys and values of the latest events kept for replicas to
+/// resume their change stream from.
+const BACKLOG_BYTES: usize = 16 << 20;
+/// Events a subscriber may fall behind by before it is dropped, ending its
+/// stream: a replica then resumes from the backlog.
+const SUBSCRIBER_EVENTS: usize = 4096;
+
+/// An event along with its sequence number.
+pub type Numbered = (u64, Event);
+
+/// Keeps track of subscribers and the key prefix each one listens to.
+pub struct Broker {
+    subscribers: Mutex<Subscribers>,
+    /// Name of this run of the broker, which numbers its events from 1.
+    stream: String,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    by_prefix: HashMap<u64, (String, SyncSender<Event>)>,
+    next_id: u64,
+    /// Subscribers to every event, along with its sequence number.
+    numbered: Vec<SyncSender<Numbered>>,
+    /// Events published so far, the sequence number of the last one.
+    published: u64,
+    /// The latest events, once a numbered subscriber came, up to
+    /// `BACKLOG_BYTES`.
+    backlog: Option<VecDeque<Numbered>>,
+    backlog_bytes: usize,
+}
+
+impl Default for Broker {
+    fn default() -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(started.as_nanos());
+        Broker {
+            subscribers: Mutex::default(),
+            stream: format!("{:016x}", hasher.finish()),
+        }
+    }
+}
+
+impl Broker {
+    /// Registers a subscriber for keys starting with `prefix`, until the
+    /// returned subscription is dropped.
+    pub fn subscribe(&self, prefix: String) -> Subscription<'_> {
+        let (sender, events) = mpsc::sync_channel(SUBSCRIBER_EVENTS);
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let id = subscribers.next_id;
+        subscribers.next_id += 1;
+        subscribers.by_prefix.insert(id, (prefix, sender));
+        Subscription {
+            broker: self,
+            id,
+            events,
+        }
+    }
+
+    /// Registers a subscriber for every event, numbered, returning the
+    /// sequence number of the last event it misses as well.
+    pub fn subscribe_numbered(&self) -> (u64, Receiver<Numbered>) {
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_EVENTS);
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.numbered.push(sender);
+        subscribers.backlog.get_or_insert_with(VecDeque::new);
+        (subscribers.published, receiver)
+    }
+
+    /// Registers a subscriber for every event after `seq`, numbered,
+    /// returning those of the backlog first; `None` if the backlog no
+    /// longer reaches back to `seq`.
+    pub fn resume(&self, seq: u64) -> Option<(Vec<Numbered>, Receiver<Numbered>)> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let backlog = subscribers.backlog.as_ref()?;
+        let oldest = backlog
+            .front()
+            .map_or(subscribers.published + 1, |(seq, _)| *seq);
+        if seq + 1 < oldest || seq > subscribers.published {
+            return None;
+        }
+        let missed = backlog
+            .iter()
+            .filter(|(missed, _)| *missed > seq)
+            .cloned()
+            .collect();
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_EVENTS);
+        subscribers.numbered.push(sender);
+        Some((missed, receiver))
+    }
+
+    /// Sends `event` to every matching subscriber, forgetting those that
+    /// went away or fell behind.
+    pub fn publish(&self, event: &Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.published += 1;
+        let seq = subscribers.published;
+        subscribers.by_prefix.retain(|_, (prefix, sender)| {
+            !event.key().starts_with(prefix.as_str()) || deliver(sender, event.clone())
+        });
+        subscribers
+            .numbered
+            .retain(|sender| deliver(sender, (seq, event.clone())));
+        let Subscribers {
+            backlog,
+            backlog_bytes,
+            ..
+        } = &mut *subscribers;
+        if let Some(backlog) = backlog {
+            *backlog_bytes += event_bytes(event);
+            backlog.push_back((seq, event.clone()));
+            while *backlog_bytes > BACKLOG_BYTES {
+                match backlog.pop_front() {
+                    Some((_, dropped)) => *backlog_bytes -= event_bytes(&dropped),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Sequence number of the last event published.
+    pub fn published(&self) -> u64 {
+        self.subscribers.lock().unwrap().published
+    }
+
+    /// Name of this run of the broker, which positions in its numbered
+    /// events are only good for.
+    pub fn stream(&self) -> &str {
+        &self.stream
+    }
+
+    /// Drops every subscriber, ending their event streams.
+    pub fn close(&self) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.by_prefix.clear();
+        subscribers.numbered.clear();
+    }
+}
+
+/// Events for the keys a subscriber listens to, which it stops listening to
+/// once dropped.
+pub struct Subscription<'a> {
+    broker: &'a Broker,
+    id: u64,
+    events: Receiver<Event>,
+}
+
+impl Deref for Subscription<'_> {
+    type Target = Receiver<Event>;
+
+    fn deref(&self) -> &Receiver<Event> {
+        &self.events
+    }
+}
+
+impl Drop for Subscription<'_> {
+    fn drop(&mut self) {
+        let mut subscribers = self.broker.subscribers.lock().unwrap();
+        subscribers.by_prefix.remove(&self.id);
+    }
+}
+
+/// Sends `event` to a subscriber unless it went away or fell behind.
+fn deliver<T>(sender: &SyncSender<T>, event: T) -> bool {
+    match sender.try_send(event) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            warn!("Dropping a subscriber {} events behind", SUBSCRIBER_EVENTS);
+            false
+        }
+        Err(TrySendError::Disconnected(_)) => false,
+    }
+}
+
+/// Bytes `event` takes in the backlog, counting its key and strings.
+pub(crate) fn event_bytes(event: &Event) -> usize {
+    match event {
+        Event::Set { key, value } | Event::Pushed { key, value, .. } => key.len() + value.len(),
+        Event::FieldSet { key, field, value } => key.len() + field.len() + value.len(),
+        Event::FieldRemoved { key, field } => key.len() + field.len(),
+        Event::MemberAdded { key, member }
+        | Event::MemberRemoved { key, member }
+        | Event::Scored { key, member, .. } => key.len() + member.len(),
+        Event::Removed { key } | Event::Popped { key } | Event::Expiry { key, .. } => key.len(),
+    }
+}
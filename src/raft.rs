@@ -0,0 +1,982 @@
+//! Raft consensus for multi-node deployments.
+//!
+//! Every node keeps a replicated log of `Command`s. Writes are proposed to
+//! the leader, which appends them to its log and replicates them to its
+//! peers; once a majority stored an entry it is committed and every node
+//! applies it to its engine, in log order.
+//!
+//! Applied entries are compacted away: the engine holds what they did, so
+//! the log only keeps the entries after the last applied ones, and a
+//! follower missing compacted entries is sent a snapshot of the engine of
+//! the leader instead. Membership changes are not supported: the cluster is
+//! the fixed set of peers given at startup.
+use crate::engine::Command;
+use crate::errors::{MyError, Result};
+
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Interval between two leader heartbeats.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+/// Lower bound of the randomized election timeout.
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+/// Timeout of a single RPC to a peer.
+const RPC_TIMEOUT: Duration = Duration::from_millis(100);
+/// Timeout of an RPC carrying a snapshot, which the peer loads before
+/// answering.
+const SNAPSHOT_RPC_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a proposal waits to be committed before giving up.
+const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(5);
+/// Applied entries from which the log is compacted.
+const COMPACTION_ENTRIES: u64 = 1024;
+
+/// File the term, vote and snapshot position are saved to.
+const META_FILE: &str = "raft-meta.json";
+/// File the entries after the snapshot are appended to.
+const LOG_FILE: &str = "raft-log";
+/// File of the whole state, log included, before the log had a file of its
+/// own; read once and replaced.
+const LEGACY_STATE_FILE: &str = "raft-state.json";
+
+/// What the log of a node drives: the engine, from which a snapshot is
+/// taken for followers too far behind, and into which one is loaded.
+pub(crate) trait StateMachine: Send + Sync {
+    /// Applies a committed command.
+    fn apply(&self, command: &Command) -> Result<()>;
+
+    /// Every pair of the store.
+    fn snapshot(&self) -> Result<Vec<(String, String)>>;
+
+    /// Replaces the content of the store by `pairs`.
+    fn restore(&self, pairs: Vec<(String, String)>) -> Result<()>;
+}
+
+/// Static description of a node and its cluster.
+#[derive(Clone, Debug)]
+pub struct RaftConfig {
+    /// Address this node listens on for peer RPCs.
+    pub raft_addr: SocketAddr,
+    /// Raft addresses of the other members.
+    pub peers: Vec<SocketAddr>,
+    /// Address clients reach this node's kvs protocol on, advertised to
+    /// followers so they can redirect writes.
+    pub client_addr: SocketAddr,
+    /// Directory holding the persistent Raft state.
+    pub dir: PathBuf,
+}
+
+/// A log entry. `None` is the no-op a new leader appends to commit
+/// entries of earlier terms.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Entry {
+    term: u64,
+    command: Option<Command>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Rpc {
+    RequestVote {
+        term: u64,
+        candidate: SocketAddr,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    AppendEntries {
+        term: u64,
+        leader: SocketAddr,
+        leader_client: SocketAddr,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+    /// The store of the leader as of the entry `last_index`, for a
+    /// follower needing entries compacted away.
+    InstallSnapshot {
+        term: u64,
+        leader_client: SocketAddr,
+        last_index: u64,
+        last_term: u64,
+        pairs: Vec<(String, String)>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum RpcReply {
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    Append {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// State that must survive restarts, but the log.
+#[derive(Default, Serialize, Deserialize)]
+struct Meta {
+    current_term: u64,
+    voted_for: Option<SocketAddr>,
+    last_applied: u64,
+    /// Index of the last entry compacted away, which the engine holds.
+    snapshot_index: u64,
+    snapshot_term: u64,
+}
+
+impl Meta {
+    /// Replaces the file at `path` with the metadata.
+    fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("json.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+/// The whole state as saved before the log had a file of its own.
+#[derive(Deserialize)]
+struct LegacyState {
+    current_term: u64,
+    voted_for: Option<SocketAddr>,
+    log: Vec<Entry>,
+    last_applied: u64,
+}
+
+/// The entries after the snapshot, in memory and appended to a file, each
+/// as its length and CRC-32 then its JSON.
+struct Log {
+    path: PathBuf,
+    file: File,
+    entries: Vec<Entry>,
+    /// Where each entry starts in the file, and where the next will.
+    offsets: Vec<u64>,
+}
+
+impl Log {
+    /// Reads the log at `path`, dropping a record torn by a crash at its
+    /// end: it was never acknowledged, as entries are synced first.
+    fn open(path: PathBuf) -> Result<Log> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let mut entries = Vec::new();
+        let mut offsets = vec![0];
+        let mut rest = &data[..];
+        while let Some((entry, len)) = decode_entry(rest) {
+            entries.push(entry);
+            rest = &rest[len..];
+            offsets.push((data.len() - rest.len()) as u64);
+        }
+        if !rest.is_empty() {
+            warn!(
+                "Dropping {} bytes of a torn Raft log entry at the end of {}",
+                rest.len(),
+                path.display()
+            );
+            file.set_len(*offsets.last().unwrap())?;
+            file.sync_data()?;
+        }
+        Ok(Log {
+            path,
+            file,
+            entries,
+            offsets,
+        })
+    }
+
+    /// Appends `entries` and syncs them.
+    fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        let start = *self.offsets.last().unwrap();
+        let mut buf = Vec::new();
+        let mut offsets = Vec::new();
+        for entry in &entries {
+            encode_entry(entry, &mut buf)?;
+            offsets.push(start + buf.len() as u64);
+        }
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+        self.entries.extend(entries);
+        self.offsets.extend(offsets);
+        Ok(())
+    }
+
+    /// Drops the entries from the `keep`th on.
+    fn truncate(&mut self, keep: usize) -> Result<()> {
+        self.entries.truncate(keep);
+        self.offsets.truncate(keep + 1);
+        self.file.set_len(self.offsets[keep])?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Replaces the log with `entries`, through a file written aside.
+    fn replace(&mut self, entries: Vec<Entry>) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut buf = Vec::new();
+        let mut offsets = vec![0];
+        for entry in &entries {
+            encode_entry(entry, &mut buf)?;
+            offsets.push(buf.len() as u64);
+        }
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.entries = entries;
+        self.offsets = offsets;
+        Ok(())
+    }
+}
+
+fn encode_entry(entry: &Entry, buf: &mut Vec<u8>) -> Result<()> {
+    let json = serde_json::to_vec(entry)?;
+    buf.extend_from_slice(&(json.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&json).to_be_bytes());
+    buf.extend_from_slice(&json);
+    Ok(())
+}
+
+/// The entry at the start of `data` and the bytes it takes, `None` if it
+/// is incomplete or corrupt.
+fn decode_entry(data: &[u8]) -> Option<(Entry, usize)> {
+    let len = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let crc = u32::from_be_bytes(data.get(4..8)?.try_into().ok()?);
+    let json = data.get(8..8 + len)?;
+    if crc32fast::hash(json) != crc {
+        return None;
+    }
+    Some((serde_json::from_slice(json).ok()?, 8 + len))
+}
+
+struct State {
+    meta: Meta,
+    log: Log,
+    role: Role,
+    commit_index: u64,
+    leader_client: Option<SocketAddr>,
+    next_index: HashMap<SocketAddr, u64>,
+    match_index: HashMap<SocketAddr, u64>,
+    election_deadline: Instant,
+    /// Why the entry after `last_applied` failed to apply, if it did.
+    apply_error: Option<String>,
+}
+
+impl State {
+    fn last_log_index(&self) -> u64 {
+        self.meta.snapshot_index + self.log.entries.len() as u64
+    }
+
+    /// The term of the entry at `index`, `None` if it was compacted away
+    /// or is not in the log yet.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        match index.checked_sub(self.meta.snapshot_index)? {
+            0 => Some(self.meta.snapshot_term),
+            offset => self
+                .log
+                .entries
+                .get(offset as usize - 1)
+                .map(|entry| entry.term),
+        }
+    }
+
+    fn last_log_term(&self) -> u64 {
+        self.term_at(self.last_log_index()).unwrap()
+    }
+
+    /// The entries from `index` on, which must not be compacted away.
+    fn entries_from(&self, index: u64) -> &[Entry] {
+        &self.log.entries[(index - self.meta.snapshot_index - 1) as usize..]
+    }
+}
+
+/// A member of a Raft cluster.
+pub struct RaftNode {
+    config: RaftConfig,
+    state: Mutex<State>,
+    applied: Condvar,
+    machine: Box<dyn StateMachine>,
+}
+
+impl RaftNode {
+    /// Loads the persisted state and starts the background threads.
+    ///
+    /// Entries up to the persisted `last_applied` are assumed to be
+    /// reflected in the (durable) state machine already.
+    pub(crate) fn start(
+        config: RaftConfig,
+        machine: Box<dyn StateMachine>,
+    ) -> Result<Arc<RaftNode>> {
+        fs::create_dir_all(&config.dir)?;
+        let (meta, log) = load(&config.dir)?;
+        let last_applied = meta.last_applied;
+        let node = Arc::new(RaftNode {
+            state: Mutex::new(State {
+                meta,
+                log,
+                role: Role::Follower,
+                commit_index: last_applied,
+                leader_client: None,
+                next_index: HashMap::new(),
+                match_index: HashMap::new(),
+                election_deadline: Instant::now() + election_timeout(&config.raft_addr),
+                apply_error: None,
+            }),
+            config,
+            applied: Condvar::new(),
+            machine,
+        });
+        let listener = TcpListener::bind(node.config.raft_addr)?;
+        info!("Raft listening on {}", node.config.raft_addr);
+        let rpc_node = Arc::clone(&node);
+        thread::spawn(move || rpc_node.serve(listener));
+        let tick_node = Arc::clone(&node);
+        thread::spawn(move || tick_node.run());
+        Ok(node)
+    }
+
+    /// Replicates `command` and waits until it is applied locally.
+    ///
+    /// Fails with the current leader's client address when this node is not
+    /// the leader, and with the reason the entry or one before it could
+    /// not be applied, in which case this node applies nothing more until
+    /// it can.
+    pub fn propose(&self, command: Command) -> Result<()> {
+        self.append(Some(command))
+    }
+
+    /// Fails with the current leader's client address unless this node
+    /// believes it is the leader.
+    pub fn check_leader(&self) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        if state.role != Role::Leader {
+            return Err(not_leader(state.leader_client));
+        }
+        Ok(())
+    }
+
+    /// Commits a no-op and waits until it is applied locally, so that the
+    /// engine then holds every write committed before the call. A leader
+    /// that was replaced without knowing fails to commit it.
+    pub fn read_barrier(&self) -> Result<()> {
+        self.append(None)
+    }
+
+    /// Appends `command`, `None` for a no-op, to the log of the leader and
+    /// waits until it is applied locally.
+    fn append(&self, command: Option<Command>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.role != Role::Leader {
+            return Err(not_leader(state.leader_client));
+        }
+        let term = state.meta.current_term;
+        state.log.append(vec![Entry { term, command }])?;
+        let index = state.last_log_index();
+
+        let deadline = Instant::now() + PROPOSAL_TIMEOUT;
+        while state.meta.last_applied < index {
+            if state.meta.current_term != term {
+                return Err(not_leader(state.leader_client));
+            }
+            if let Some(error) = &state.apply_error {
+                return Err(MyError::StringError(error.clone()));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Err(MyError::StringError(
+                    "Timed out waiting for the write to be committed".to_owned(),
+                ));
+            }
+            state = self.applied.wait_timeout(state, remaining).unwrap().0;
+        }
+        // only a leader of a later term replaces entries, and this node
+        // would have seen its term
+        let ours = match state.term_at(index) {
+            Some(entry_term) => entry_term == term,
+            None => state.meta.current_term == term,
+        };
+        if !ours {
+            return Err(not_leader(state.leader_client));
+        }
+        Ok(())
+    }
+
+    /// Number of nodes forming a majority of the cluster.
+    fn quorum(&self) -> usize {
+        let cluster_size = self.config.peers.len() + 1;
+        cluster_size / 2 + 1
+    }
+
+    fn persist(&self, state: &State) -> Result<()> {
+        state.meta.save(&self.config.dir.join(META_FILE))
+    }
+
+    fn run(self: Arc<Self>) {
+        loop {
+            thread::sleep(HEARTBEAT_INTERVAL);
+            let (role, deadline) = {
+                let state = self.state.lock().unwrap();
+                (state.role, state.election_deadline)
+            };
+            let result = match role {
+                Role::Leader => self.replicate(),
+                _ if Instant::now() >= deadline => self.start_election(),
+                _ => Ok(()),
+            };
+            if let Err(e) = result.and_then(|_| self.apply_committed()) {
+                error!("Raft error: {}", e);
+            }
+        }
+    }
+
+    fn start_election(self: &Arc<Self>) -> Result<()> {
+        let (term, last_log_index, last_log_term) = {
+            let mut state = self.state.lock().unwrap();
+            state.role = Role::Candidate;
+            state.meta.current_term += 1;
+            state.meta.voted_for = Some(self.config.raft_addr);
+            state.election_deadline = Instant::now() + election_timeout(&self.config.raft_addr);
+            self.persist(&state)?;
+            (
+                state.meta.current_term,
+                state.last_log_index(),
+                state.last_log_term(),
+            )
+        };
+        info!("Starting election for term {}", term);
+
+        let replies = self.broadcast(|_| Rpc::RequestVote {
+            term,
+            candidate: self.config.raft_addr,
+            last_log_index,
+            last_log_term,
+        });
+        let mut votes = 1;
+        for (_, reply) in replies {
+            if let RpcReply::Vote {
+                term: reply_term,
+                granted,
+            } = reply
+            {
+                if self.observe_term(reply_term)? {
+                    return Ok(());
+                }
+                if granted {
+                    votes += 1;
+                }
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.role == Role::Candidate
+            && state.meta.current_term == term
+            && votes >= self.quorum()
+        {
+            info!("Elected leader for term {} with {} votes", term, votes);
+            state.role = Role::Leader;
+            state.leader_client = Some(self.config.client_addr);
+            let next = state.last_log_index() + 1;
+            for peer in &self.config.peers {
+                state.next_index.insert(*peer, next);
+                state.match_index.insert(*peer, 0);
+            }
+            state.log.append(vec![Entry {
+                term,
+                command: None,
+            }])?;
+            drop(state);
+            self.replicate()?;
+        }
+        Ok(())
+    }
+
+    /// Sends each follower the entries it is missing (or a heartbeat), or
+    /// a snapshot if they were compacted away, then advances the commit
+    /// index.
+    fn replicate(self: &Arc<Self>) -> Result<()> {
+        let mut requests: HashMap<SocketAddr, Rpc> = {
+            let state = self.state.lock().unwrap();
+            if state.role != Role::Leader {
+                return Ok(());
+            }
+            // taken under the state lock, which entries are applied under,
+            // so that the engine is as of `last_applied`
+            let mut snapshot = None;
+            let mut requests = HashMap::new();
+            for peer in &self.config.peers {
+                let next = state.next_index[peer];
+                let rpc = match state.term_at(next - 1) {
+                    Some(prev_log_term) => Rpc::AppendEntries {
+                        term: state.meta.current_term,
+                        leader: self.config.raft_addr,
+                        leader_client: self.config.client_addr,
+                        prev_log_index: next - 1,
+                        prev_log_term,
+                        entries: state.entries_from(next).to_vec(),
+                        leader_commit: state.commit_index,
+                    },
+                    None => {
+                        let pairs = match &snapshot {
+                            Some(pairs) => Vec::clone(pairs),
+                            None => snapshot.insert(self.machine.snapshot()?).clone(),
+                        };
+                        let last_index = state.meta.last_applied;
+                        Rpc::InstallSnapshot {
+                            term: state.meta.current_term,
+                            leader_client: self.config.client_addr,
+                            last_index,
+                            last_term: state.term_at(last_index).unwrap(),
+                            pairs,
+                        }
+                    }
+                };
+                requests.insert(*peer, rpc);
+            }
+            requests
+        };
+        let replies = self.broadcast(|peer| requests.remove(peer).unwrap());
+
+        let mut state = self.state.lock().unwrap();
+        for (peer, reply) in replies {
+            if let RpcReply::Append {
+                term,
+                success,
+                match_index,
+            } = reply
+            {
+                if term > state.meta.current_term {
+                    drop(state);
+                    self.observe_term(term)?;
+                    return Ok(());
+                }
+                if state.role != Role::Leader {
+                    return Ok(());
+                }
+                if success {
+                    state.match_index.insert(peer, match_index);
+                    state.next_index.insert(peer, match_index + 1);
+                } else {
+                    let next = state.next_index[&peer];
+                    state
+                        .next_index
+                        .insert(peer, (match_index + 1).min(next - 1).max(1));
+                }
+            }
+        }
+
+        // the highest index stored on a majority, counting the leader itself
+        let mut matched: Vec<u64> = state.match_index.values().copied().collect();
+        matched.push(state.last_log_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let majority = matched[self.quorum() - 1];
+        if majority > state.commit_index && state.term_at(majority) == Some(state.meta.current_term)
+        {
+            debug!("Commit index advanced to {}", majority);
+            state.commit_index = majority;
+        }
+        Ok(())
+    }
+
+    /// Applies committed entries the state machine has not seen yet, then
+    /// compacts the log once enough were. An entry that fails to apply
+    /// stops there, to be tried again: the entries after it cannot be
+    /// applied without it.
+    fn apply_committed(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.meta.last_applied >= state.commit_index {
+            return Ok(());
+        }
+        let mut result = Ok(());
+        while state.meta.last_applied < state.commit_index {
+            let index = state.meta.last_applied + 1;
+            let entry = &state.entries_from(index)[0];
+            if let Some(command) = &entry.command {
+                if let Err(e) = self.machine.apply(command) {
+                    let error = format!("Failed to apply entry {}: {}", index, e);
+                    state.apply_error = Some(error.clone());
+                    result = Err(MyError::StringError(error));
+                    break;
+                }
+            }
+            state.meta.last_applied = index;
+            state.apply_error = None;
+        }
+        if state.meta.last_applied - state.meta.snapshot_index >= COMPACTION_ENTRIES {
+            self.compact(&mut state)?;
+        }
+        self.persist(&state)?;
+        self.applied.notify_all();
+        result
+    }
+
+    /// Drops the applied entries from the log, which the engine holds.
+    fn compact(&self, state: &mut State) -> Result<()> {
+        let index = state.meta.last_applied;
+        let term = state.term_at(index).unwrap();
+        let kept = state.entries_from(index + 1).to_vec();
+        // the metadata is saved by the caller, and a crash in between
+        // leaves entries that are dropped again on the next compaction
+        state.log.replace(kept)?;
+        state.meta.snapshot_index = index;
+        state.meta.snapshot_term = term;
+        debug!("Compacted the Raft log up to entry {}", index);
+        Ok(())
+    }
+
+    /// Steps down when a peer reports a newer term. Returns whether it did.
+    fn observe_term(&self, term: u64) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        if term <= state.meta.current_term {
+            return Ok(false);
+        }
+        info!("Stepping down: saw term {}", term);
+        state.meta.current_term = term;
+        state.meta.voted_for = None;
+        state.role = Role::Follower;
+        self.persist(&state)?;
+        self.applied.notify_all();
+        Ok(true)
+    }
+
+    /// Sends one RPC to every peer in parallel and collects the replies
+    /// that arrived in time.
+    fn broadcast<F: FnMut(&SocketAddr) -> Rpc>(&self, mut rpc: F) -> Vec<(SocketAddr, RpcReply)> {
+        let handles: Vec<_> = self
+            .config
+            .peers
+            .iter()
+            .map(|peer| {
+                let peer = *peer;
+                let request = rpc(&peer);
+                thread::spawn(move || (peer, call(peer, &request)))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .filter_map(|handle| match handle.join() {
+                Ok((peer, Ok(reply))) => Some((peer, reply)),
+                Ok((peer, Err(e))) => {
+                    debug!("RPC to {} failed: {}", peer, e);
+                    None
+                }
+                Err(_) => None,
+            })
+            .collect()
+    }
+
+    fn serve(self: Arc<Self>, listener: TcpListener) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let node = Arc::clone(&self);
+                    thread::spawn(move || {
+                        if let Err(e) = node.handle_rpc(stream) {
+                            debug!("Raft RPC failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Raft connection failed {}", e),
+            }
+        }
+    }
+
+    fn handle_rpc(&self, stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(RPC_TIMEOUT))?;
+        let rpc: Rpc = serde_json::Deserializer::from_reader(BufReader::new(&stream))
+            .into_iter()
+            .next()
+            .ok_or_else(|| MyError::StringError("Empty Raft RPC".to_owned()))??;
+        let reply = match rpc {
+            Rpc::RequestVote {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => self.on_request_vote(term, candidate, last_log_index, last_log_term)?,
+            Rpc::AppendEntries {
+                term,
+                leader_client,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                ..
+            } => self.on_append_entries(
+                term,
+                leader_client,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            )?,
+            Rpc::InstallSnapshot {
+                term,
+                leader_client,
+                last_index,
+                last_term,
+                pairs,
+            } => self.on_install_snapshot(term, leader_client, last_index, last_term, pairs)?,
+        };
+        let mut writer = BufWriter::new(&stream);
+        serde_json::to_writer(&mut writer, &reply)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn on_request_vote(
+        &self,
+        term: u64,
+        candidate: SocketAddr,
+        last_log_index: u64,
+        last_log_term: u64,
+    ) -> Result<RpcReply> {
+        self.observe_term(term)?;
+        let mut state = self.state.lock().unwrap();
+        let up_to_date =
+            (last_log_term, last_log_index) >= (state.last_log_term(), state.last_log_index());
+        let granted = term == state.meta.current_term
+            && up_to_date
+            && state.meta.voted_for.is_none_or(|v| v == candidate);
+        if granted {
+            state.meta.voted_for = Some(candidate);
+            state.election_deadline = Instant::now() + election_timeout(&self.config.raft_addr);
+            self.persist(&state)?;
+        }
+        Ok(RpcReply::Vote {
+            term: state.meta.current_term,
+            granted,
+        })
+    }
+
+    fn on_append_entries(
+        &self,
+        term: u64,
+        leader_client: SocketAddr,
+        mut prev_log_index: u64,
+        mut prev_log_term: u64,
+        mut entries: Vec<Entry>,
+        leader_commit: u64,
+    ) -> Result<RpcReply> {
+        self.observe_term(term)?;
+        let mut state = self.state.lock().unwrap();
+        let current_term = state.meta.current_term;
+        if term < current_term {
+            return Ok(RpcReply::Append {
+                term: current_term,
+                success: false,
+                match_index: 0,
+            });
+        }
+        state.role = Role::Follower;
+        state.leader_client = Some(leader_client);
+        state.election_deadline = Instant::now() + election_timeout(&self.config.raft_addr);
+
+        // entries up to the snapshot are committed, hence the leader's
+        let snapshot_index = state.meta.snapshot_index;
+        if prev_log_index < snapshot_index {
+            let skipped = (snapshot_index - prev_log_index).min(entries.len() as u64);
+            entries.drain(..skipped as usize);
+            prev_log_index += skipped;
+            if prev_log_index < snapshot_index {
+                return Ok(RpcReply::Append {
+                    term: current_term,
+                    success: true,
+                    match_index: prev_log_index,
+                });
+            }
+            prev_log_term = state.meta.snapshot_term;
+        }
+
+        if state.term_at(prev_log_index) != Some(prev_log_term) {
+            // hint the leader to retry from the end of our log
+            let hint = state.last_log_index().min(prev_log_index.saturating_sub(1));
+            return Ok(RpcReply::Append {
+                term: current_term,
+                success: false,
+                match_index: hint,
+            });
+        }
+
+        let mut new_entries = Vec::new();
+        for (offset, entry) in entries.into_iter().enumerate() {
+            let index = prev_log_index + 1 + offset as u64;
+            if new_entries.is_empty() && index <= state.last_log_index() {
+                if state.term_at(index) == Some(entry.term) {
+                    continue;
+                }
+                let keep = index - state.meta.snapshot_index - 1;
+                state.log.truncate(keep as usize)?;
+            }
+            new_entries.push(entry);
+        }
+        if !new_entries.is_empty() {
+            state.log.append(new_entries)?;
+        }
+        let match_index = state.last_log_index();
+        if leader_commit > state.commit_index {
+            state.commit_index = leader_commit.min(match_index);
+        }
+        drop(state);
+        // stored, whether they apply or not
+        if let Err(e) = self.apply_committed() {
+            error!("Raft error: {}", e);
+        }
+        Ok(RpcReply::Append {
+            term: current_term,
+            success: true,
+            match_index,
+        })
+    }
+
+    /// Loads the store of the leader as of the entry `last_index`, unless
+    /// this node applied it already, and drops the log up to there.
+    fn on_install_snapshot(
+        &self,
+        term: u64,
+        leader_client: SocketAddr,
+        last_index: u64,
+        last_term: u64,
+        pairs: Vec<(String, String)>,
+    ) -> Result<RpcReply> {
+        self.observe_term(term)?;
+        let mut state = self.state.lock().unwrap();
+        let current_term = state.meta.current_term;
+        if term < current_term {
+            return Ok(RpcReply::Append {
+                term: current_term,
+                success: false,
+                match_index: 0,
+            });
+        }
+        state.role = Role::Follower;
+        state.leader_client = Some(leader_client);
+        state.election_deadline = Instant::now() + election_timeout(&self.config.raft_addr);
+        if last_index <= state.meta.last_applied {
+            return Ok(RpcReply::Append {
+                term: current_term,
+                success: true,
+                match_index: last_index,
+            });
+        }
+
+        info!(
+            "Loading a snapshot of the leader up to entry {}",
+            last_index
+        );
+        self.machine.restore(pairs)?;
+        // the entries after the snapshot stay if the log agrees on it
+        let kept = if state.term_at(last_index) == Some(last_term) {
+            state.entries_from(last_index + 1).to_vec()
+        } else {
+            Vec::new()
+        };
+        state.log.replace(kept)?;
+        state.meta.snapshot_index = last_index;
+        state.meta.snapshot_term = last_term;
+        state.meta.last_applied = last_index;
+        state.commit_index = state.commit_index.max(last_index);
+        state.apply_error = None;
+        self.persist(&state)?;
+        self.applied.notify_all();
+        Ok(RpcReply::Append {
+            term: current_term,
+            success: true,
+            match_index: last_index,
+        })
+    }
+}
+
+/// Loads the metadata and log saved in `dir`, moving a state saved whole
+/// by an earlier version to them.
+fn load(dir: &Path) -> Result<(Meta, Log)> {
+    let meta_path = dir.join(META_FILE);
+    let legacy_path = dir.join(LEGACY_STATE_FILE);
+    if !meta_path.exists() && legacy_path.exists() {
+        let legacy: LegacyState =
+            serde_json::from_reader(BufReader::new(File::open(&legacy_path)?))?;
+        let mut log = Log::open(dir.join(LOG_FILE))?;
+        log.replace(legacy.log)?;
+        let meta = Meta {
+            current_term: legacy.current_term,
+            voted_for: legacy.voted_for,
+            last_applied: legacy.last_applied,
+            ..Meta::default()
+        };
+        meta.save(&meta_path)?;
+        fs::remove_file(&legacy_path)?;
+        return Ok((meta, log));
+    }
+    let meta = match File::open(&meta_path) {
+        Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Meta::default(),
+        Err(e) => return Err(e.into()),
+    };
+    Ok((meta, Log::open(dir.join(LOG_FILE))?))
+}
+
+fn call(peer: SocketAddr, rpc: &Rpc) -> Result<RpcReply> {
+    let timeout = match rpc {
+        Rpc::InstallSnapshot { .. } => SNAPSHOT_RPC_TIMEOUT,
+        _ => RPC_TIMEOUT,
+    };
+    let stream = TcpStream::connect_timeout(&peer, RPC_TIMEOUT)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut writer = BufWriter::new(&stream);
+    serde_json::to_writer(&mut writer, rpc)?;
+    writer.flush()?;
+    drop(writer);
+    stream.shutdown(std::net::Shutdown::Write)?;
+    Ok(serde_json::from_reader(BufReader::new(&stream))?)
+}
+
+fn not_leader(leader: Option<SocketAddr>) -> MyError {
+    match leader {
+        Some(addr) => MyError::StringError(format!("Not the leader, try {}", addr)),
+        None => MyError::StringError("Not the leader, no leader elected yet".to_owned()),
+    }
+}
+
+/// A randomized election timeout between one and two times
+/// `ELECTION_TIMEOUT`, so that nodes rarely time out together.
+fn election_timeout(seed: &SocketAddr) -> Duration {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0)
+        .hash(&mut hasher);
+    let jitter = hasher.finish() % ELECTION_TIMEOUT.as_millis() as u64;
+    ELECTION_TIMEOUT + Duration::from_millis(jitter)
+}
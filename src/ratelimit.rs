@@ -0,0 +1,44 @@
+//! Token-bucket rate limiting of client requests.
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Lets `rate` requests per second through on average, in bursts of up to
+/// `rate` requests.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    /// A limiter starting with a full bucket.
+    pub(crate) fn new(rate: u32) -> RateLimiter {
+        RateLimiter {
+            rate: rate as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token for one request, returning whether there was one.
+    pub(crate) fn try_acquire(&self) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.rate);
+        bucket.refilled = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
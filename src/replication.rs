@@ -0,0 +1,475 @@
+//! Leader-follower replication: a replica loads a snapshot of the leader's
+//! store, then applies the leader's change stream as it arrives.
+use crate::client::{KvsClient, Subscription};
+use crate::common::{Event, StreamPosition};
+use crate::engine::{unix_millis, KvsEngine};
+use crate::errors::{MyError, Result};
+use crate::server::Context;
+
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Delay between two attempts to reach a lost leader.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// When a leader acknowledges the writes of its clients.
+///
+/// Only writes announced to replicas wait for them: those of strings, lists,
+/// hashes, sets and sorted sets, and expiries. A write that replicas do not
+/// acknowledge in time fails with `MyError::Timeout`, though the leader
+/// applied it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Consistency {
+    /// Once applied locally; replicas catch up on their own.
+    #[default]
+    Async,
+    /// Once this many replicas received it.
+    SemiSync(usize),
+    /// Once this many replicas applied it.
+    Sync(usize),
+}
+
+impl FromStr for Consistency {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Consistency> {
+        let invalid = || {
+            MyError::StringError(format!(
+                "Unknown consistency `{}`, expected `async`, `semi-sync:N` or `sync:N`",
+                s
+            ))
+        };
+        let (mode, count) = match s.split_once(':') {
+            Some((mode, count)) => (mode, Some(count.parse().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        match (mode, count) {
+            ("async", None) => Ok(Consistency::Async),
+            ("semi-sync", Some(n)) if n > 0 => Ok(Consistency::SemiSync(n)),
+            ("sync", Some(n)) if n > 0 => Ok(Consistency::Sync(n)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Consistency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Consistency::Async => write!(f, "async"),
+            Consistency::SemiSync(n) => write!(f, "semi-sync:{}", n),
+            Consistency::Sync(n) => write!(f, "sync:{}", n),
+        }
+    }
+}
+
+/// How far the replicas streaming from this server got, in sequence
+/// numbers of the events of its broker.
+#[derive(Default)]
+pub(crate) struct Replicas {
+    progress: Mutex<HashMap<u64, Progress>>,
+    next_id: AtomicU64,
+    changed: Condvar,
+}
+
+#[derive(Clone, Copy)]
+struct Progress {
+    received: u64,
+    applied: u64,
+}
+
+impl Replicas {
+    /// Registers a replica that has every event up to `seq`, returning its
+    /// id.
+    pub(crate) fn attach(&self, seq: u64) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let progress = Progress {
+            received: seq,
+            applied: seq,
+        };
+        self.progress.lock().unwrap().insert(id, progress);
+        id
+    }
+
+    pub(crate) fn detach(&self, id: u64) {
+        self.progress.lock().unwrap().remove(&id);
+    }
+
+    /// Records that replica `id` received every event up to `received`
+    /// and applied those up to `applied`.
+    pub(crate) fn advance(&self, id: u64, received: u64, applied: u64) {
+        if let Some(progress) = self.progress.lock().unwrap().get_mut(&id) {
+            *progress = Progress { received, applied };
+        }
+        self.changed.notify_all();
+    }
+
+    /// Blocks until enough replicas for `consistency` have the events up
+    /// to `seq`, failing with `MyError::Timeout` after `timeout`.
+    pub(crate) fn wait(&self, consistency: Consistency, seq: u64, timeout: Duration) -> Result<()> {
+        let (needed, applied) = match consistency {
+            Consistency::Async => return Ok(()),
+            Consistency::SemiSync(n) => (n, false),
+            Consistency::Sync(n) => (n, true),
+        };
+        let deadline = Instant::now() + timeout;
+        let mut progress = self.progress.lock().unwrap();
+        loop {
+            let caught_up = progress
+                .values()
+                .filter(|p| if applied { p.applied } else { p.received } >= seq)
+                .count();
+            if caught_up >= needed {
+                return Ok(());
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                warn!(
+                    "Only {} replicas caught up in time, {} needed",
+                    caught_up, needed
+                );
+                return Err(MyError::Timeout);
+            }
+            progress = self.changed.wait_timeout(progress, remaining).unwrap().0;
+        }
+    }
+}
+
+/// Sequence numbers of the events streamed to one replica and not yet
+/// applied there, turning the counts it acknowledges into sequence
+/// numbers.
+pub(crate) struct Unacked {
+    seqs: VecDeque<u64>,
+    /// Events the replica applied, dropped from `seqs`.
+    applied: u64,
+    /// Sequence number of the last of them, or the one the stream started
+    /// after.
+    applied_seq: u64,
+}
+
+impl Unacked {
+    pub(crate) fn new(seq: u64) -> Unacked {
+        Unacked {
+            seqs: VecDeque::new(),
+            applied: 0,
+            applied_seq: seq,
+        }
+    }
+
+    pub(crate) fn sent(&mut self, seq: u64) {
+        self.seqs.push_back(seq);
+    }
+
+    /// The sequence numbers of the last event received and of the last
+    /// one applied, given their counts; `None` if those do not match what
+    /// was sent.
+    pub(crate) fn acknowledge(&mut self, received: u64, applied: u64) -> Option<(u64, u64)> {
+        let sent = self.applied + self.seqs.len() as u64;
+        if applied < self.applied || received < applied || received > sent {
+            return None;
+        }
+        let received_seq = match received - self.applied {
+            0 => self.applied_seq,
+            n => self.seqs[n as usize - 1],
+        };
+        for _ in self.applied..applied {
+            self.applied_seq = self.seqs.pop_front().unwrap();
+        }
+        self.applied = applied;
+        Some((received_seq, self.applied_seq))
+    }
+}
+
+/// Where a replica is in the change stream of its leader, kept in a file
+/// so that the replica resumes the stream after a restart too.
+pub(crate) struct Follower {
+    leader: SocketAddr,
+    /// File the position is saved to once applied, if any.
+    state: Option<PathBuf>,
+    position: Option<StreamPosition>,
+}
+
+impl Follower {
+    /// Follows `leader` from the position saved at `state`, if any.
+    pub(crate) fn new(leader: SocketAddr, state: Option<PathBuf>) -> Follower {
+        let position = state.as_deref().and_then(|path| match load_position(path) {
+            Ok(position) => position,
+            Err(e) => {
+                warn!("Ignoring replication state {}: {}", path.display(), e);
+                None
+            }
+        });
+        Follower {
+            leader,
+            state,
+            position,
+        }
+    }
+
+    /// Brings the local store in sync with the leader and returns the
+    /// stream of changes that follow: the leader resumes it after the
+    /// position of the replica if it still has the events since, else the
+    /// local store content is replaced with a snapshot of the leader.
+    pub(crate) fn sync<E: KvsEngine>(&mut self, context: &Context<E>) -> Result<Subscription> {
+        let leader = self.leader;
+        let token = context.settings().auth_token.clone();
+        let client = match token {
+            Some(token) => KvsClient::connect_with_auth(leader, token.to_string())?,
+            None => KvsClient::connect(leader)?,
+        };
+        let start = client.sync(self.position.clone())?;
+        let snapshot = match start.snapshot {
+            Some(snapshot) => snapshot,
+            None => {
+                let seq = start.position.as_ref().map_or(0, |position| position.seq);
+                info!(
+                    "Resumed the change stream of leader {} after event {}",
+                    leader, seq
+                );
+                self.position = start.position;
+                return Ok(start.changes);
+            }
+        };
+        info!("Starting full sync from leader {}", leader);
+
+        let mut engine = context.engine.lock();
+        // lists, hashes and sets are rebuilt from the events that follow,
+        // so they go as well
+        let live: HashSet<&str> = snapshot.iter().map(|(key, _)| key.as_str()).collect();
+        for key in engine.keys("*".to_owned())? {
+            if !live.contains(key.as_str()) {
+                engine.remove(key.clone())?;
+                context.broker.publish(&Event::Removed { key });
+            }
+        }
+        let count = snapshot.len();
+        for (key, value) in snapshot {
+            engine.set(key.clone(), value.clone())?;
+            context.broker.publish(&Event::Set { key, value });
+        }
+        for event in start.events {
+            apply_to(&mut *engine, &event)?;
+            context.broker.publish(&event);
+        }
+        drop(engine);
+        context.sync_writes()?;
+        self.position = start.position;
+        self.save()?;
+        info!("Full sync from leader {} done, {} keys", leader, count);
+        Ok(start.changes)
+    }
+
+    /// Applies `changes` until the leader goes away, then resynchronizes
+    /// and starts over. Never returns.
+    ///
+    /// Every event is acknowledged to the leader once received, then once
+    /// applied, for writes waiting on a `Consistency` other than `Async`.
+    /// An event that fails to apply is not: the replica drops the stream
+    /// and takes a full snapshot again, rather than go on diverged.
+    pub(crate) fn follow<E: KvsEngine>(mut self, context: Context<E>, changes: Subscription) {
+        let mut changes = changes;
+        loop {
+            let followed = match self.position {
+                Some(_) => self.apply_batches(&context, &mut changes),
+                None => apply_events(&context, &mut changes),
+            };
+            if let Err(e) = followed {
+                warn!("Replication stream error: {}", e);
+            }
+
+            warn!("Lost connection to leader {}, resyncing", self.leader);
+            changes = loop {
+                thread::sleep(RETRY_DELAY);
+                match self.sync(&context) {
+                    Ok(changes) => break changes,
+                    Err(e) => warn!("Sync from leader {} failed: {}", self.leader, e),
+                }
+            };
+        }
+    }
+
+    /// Applies the batches of a change stream from version 9, checking
+    /// that each is intact and follows the last one, and saves the
+    /// position after each.
+    fn apply_batches<E: KvsEngine>(
+        &mut self,
+        context: &Context<E>,
+        changes: &mut Subscription,
+    ) -> Result<()> {
+        let mut received = 0;
+        while let Some(batch) = changes.next_batch() {
+            let batch = batch?;
+            let position = self.position.as_mut().unwrap();
+            batch.check(position.seq)?;
+            let applied = received;
+            received += batch.events.len() as u64;
+            changes.acknowledge(received, applied)?;
+            for event in batch.events {
+                if let Err(e) = apply(context, event) {
+                    error!("Failed to apply replicated change: {}", e);
+                    // part of the batch is applied, which resuming would
+                    // apply again
+                    self.forget()?;
+                    return Err(e);
+                }
+            }
+            position.seq = batch.end_seq;
+            self.save()?;
+            changes.acknowledge(received, received)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the position, here and in the state file, so that the next
+    /// sync takes a full snapshot.
+    fn forget(&mut self) -> Result<()> {
+        self.position = None;
+        match &self.state {
+            Some(path) => match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Saves the position to the state file, if any, replacing the last.
+    fn save(&self) -> Result<()> {
+        let (path, position) = match (&self.state, &self.position) {
+            (Some(path), Some(position)) => (path, position),
+            _ => return Ok(()),
+        };
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, serde_json::to_vec(position)?)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
+
+/// The position saved at `path`, `None` if there is none.
+fn load_position(path: &Path) -> Result<Option<StreamPosition>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Applies the events of a change stream older than version 9 one by one.
+fn apply_events<E: KvsEngine>(context: &Context<E>, changes: &mut Subscription) -> Result<()> {
+    let mut count = 0;
+    while let Some(event) = changes.next() {
+        let event = event?;
+        count += 1;
+        changes.acknowledge(count, count - 1)?;
+        if let Err(e) = apply(context, event) {
+            error!("Failed to apply replicated change: {}", e);
+            return Err(e);
+        }
+        changes.acknowledge(count, count)?;
+    }
+    Ok(())
+}
+
+fn apply<E: KvsEngine>(context: &Context<E>, event: Event) -> Result<()> {
+    let mut engine = context.engine.lock();
+    apply_to(&mut *engine, &event)?;
+    context.broker.publish(&event);
+    drop(engine);
+    context.sync_writes()
+}
+
+/// Applies `event` to `engine`. Removals of what is already gone succeed,
+/// as the leader may have sent them in its snapshot and its stream both.
+fn apply_to<E: KvsEngine>(engine: &mut E, event: &Event) -> Result<()> {
+    let result = match event.clone() {
+        Event::Set { key, value } => engine.set(key, value),
+        Event::Removed { key } => engine.remove(key),
+        Event::Pushed { key, value, front } if front => engine.lpush(key, value).map(drop),
+        Event::Pushed { key, value, .. } => engine.rpush(key, value).map(drop),
+        Event::Popped { key } => engine.lpop(key).map(drop),
+        Event::FieldSet { key, field, value } => engine.hset(key, field, value),
+        Event::FieldRemoved { key, field } => engine.hdel(key, field),
+        Event::MemberAdded { key, member } => engine.sadd(key, member).map(drop),
+        Event::MemberRemoved { key, member } => engine.srem(key, member).map(drop),
+        Event::Scored { key, member, score } => engine.zadd(key, member, score).map(drop),
+        Event::Expiry {
+            key,
+            expires_at_ms: Some(expires),
+        } => match expires.checked_sub(unix_millis()) {
+            Some(ttl) if ttl > 0 => engine.expire(key, Duration::from_millis(ttl)),
+            _ => engine.remove(key),
+        },
+        Event::Expiry {
+            key,
+            expires_at_ms: None,
+        } => engine.persist(key),
+    };
+    match result {
+        Ok(()) | Err(MyError::KeyNotFound) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The events rebuilding what a snapshot of the pairs of `engine` leaves
+/// out: its lists, hashes, sets and sorted sets, and the expiries of its
+/// strings. Replaying the changes of a list is not idempotent, so the
+/// caller holds the engine lock from the start of the change stream on.
+pub(crate) fn snapshot_events<E: KvsEngine>(engine: &mut E) -> Result<Vec<Event>> {
+    let strings: HashSet<String> = engine
+        .scan(String::new())?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    let now = unix_millis();
+    let mut events = Vec::new();
+    for key in engine.keys("*".to_owned())? {
+        if strings.contains(&key) {
+            if let Ok(Some(ttl)) = engine.ttl(key.clone()) {
+                events.push(Event::Expiry {
+                    key,
+                    expires_at_ms: Some(now.saturating_add(ttl.as_millis() as u64)),
+                });
+            }
+            continue;
+        }
+        // one of these holds the key, the others read as empty
+        for value in engine.lrange(key.clone(), 0, -1)? {
+            events.push(Event::Pushed {
+                key: key.clone(),
+                value,
+                front: false,
+            });
+        }
+        for (field, value) in engine.hgetall(key.clone())? {
+            events.push(Event::FieldSet {
+                key: key.clone(),
+                field,
+                value,
+            });
+        }
+        for member in engine.smembers(key.clone())? {
+            events.push(Event::MemberAdded {
+                key: key.clone(),
+                member,
+            });
+        }
+        for (member, score) in engine.zrange_by_score(key.clone(), f64::MIN, f64::MAX)? {
+            events.push(Event::Scored {
+                key: key.clone(),
+                member,
+                score,
+            });
+        }
+    }
+    Ok(events)
+}
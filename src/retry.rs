@@ -0,0 +1,123 @@
+//! Retrying operations that failed for transient reasons.
+use crate::errors::{MyError, Result};
+
+use std::cell::Cell;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often and how patiently an operation is retried.
+///
+/// The delay before the n-th retry is `initial_backoff * 2^(n-1)`, capped
+/// at `max_backoff`. With jitter, a random part of up to half the delay is
+/// dropped so that clients restarted together do not retry in lockstep.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    /// A single attempt: errors are returned right away.
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Try at most `max_attempts` times in total.
+    pub fn new(max_attempts: u32) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Wait `initial` before the first retry, doubling up to `max`.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Randomize delays, on by default.
+    pub fn with_jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Runs `op` until it succeeds, fails with a non-transient error or
+    /// runs out of attempts.
+    pub(crate) fn run<T>(&self, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(e) if attempt < self.max_attempts && is_transient(&e) => {
+                    let delay = self.delay(attempt);
+                    log::debug!(
+                        "Attempt {} failed ({}), retrying in {:?}",
+                        attempt,
+                        e,
+                        delay
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt - 1);
+        let delay = self
+            .initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |delay| delay.min(self.max_backoff));
+        if self.jitter {
+            delay / 2 + delay.mul_f64(random_fraction() / 2.0)
+        } else {
+            delay
+        }
+    }
+}
+
+/// Whether `err` may go away by trying again, as connection failures do.
+pub(crate) fn is_transient(err: &MyError) -> bool {
+    match err {
+        MyError::Io(_) | MyError::Timeout => true,
+        MyError::DeserializeError(e) => e.is_io() || e.is_eof(),
+        MyError::MessagePackDecode(
+            rmp_serde::decode::Error::InvalidMarkerRead(_)
+            | rmp_serde::decode::Error::InvalidDataRead(_),
+        ) => true,
+        _ => false,
+    }
+}
+
+/// A number in `[0, 1)` from a per-thread xorshift generator; good enough
+/// to spread retries, not for anything else.
+fn random_fraction() -> f64 {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0x9e37_79b9_7f4a_7c15, |d| d.as_nanos() as u64)
+                | 1,
+        );
+    }
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
@@ -0,0 +1,177 @@
+//! Splitting reads and writes between a server and its replicas.
+use crate::client::{KvsClient, KvsClientBuilder};
+use crate::errors::{MyError, Result};
+use crate::retry::is_transient;
+
+use log::warn;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+/// Client reading from the replicas of a server and writing to the server.
+///
+/// Reads go to the replicas in turn, connected to lazily; a replica that
+/// cannot be reached is skipped for the primary. Replicas lag behind the
+/// primary, so a read right after a write may not see it, unless reads are
+/// pinned to the primary for a while after each write with
+/// `with_read_your_writes`.
+///
+/// Example:
+///
+/// ```no_run
+/// # use kvs::{KvsReplicaClient, Result};
+/// # use std::time::Duration;
+/// # fn try_main() -> Result<()> {
+/// let mut client = KvsReplicaClient::connect("127.0.0.1:4000", &["127.0.0.1:4001"])?
+///     .with_read_your_writes(Duration::from_secs(1));
+/// client.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvsReplicaClient {
+    primary: KvsClient,
+    replicas: Vec<SocketAddr>,
+    clients: HashMap<SocketAddr, KvsClient>,
+    builder: KvsClientBuilder,
+    /// Index in `replicas` of the one the next read goes to.
+    next: usize,
+    /// How long reads stay on the primary after a write, if they do.
+    pin: Option<Duration>,
+    last_write: Option<Instant>,
+}
+
+impl KvsReplicaClient {
+    /// Connect to the primary at `primary`, reading from the replicas at
+    /// `replicas`.
+    pub fn connect<P: ToSocketAddrs, R: ToSocketAddrs>(primary: P, replicas: &[R]) -> Result<Self> {
+        KvsReplicaClient::with_builder(primary, replicas, KvsClient::builder())
+    }
+
+    /// Connect to the primary and to the replicas, each set up by
+    /// `builder`.
+    pub fn with_builder<P: ToSocketAddrs, R: ToSocketAddrs>(
+        primary: P,
+        replicas: &[R],
+        builder: KvsClientBuilder,
+    ) -> Result<Self> {
+        let mut addrs = Vec::new();
+        for replica in replicas {
+            addrs.push(resolve(replica)?);
+        }
+        Ok(KvsReplicaClient {
+            primary: builder.connect(primary)?,
+            replicas: addrs,
+            clients: HashMap::new(),
+            builder,
+            next: 0,
+            pin: None,
+            last_write: None,
+        })
+    }
+
+    /// Read from the primary for `pin` after each write, so that reads see
+    /// the writes of this client once replicas take less than `pin` to
+    /// apply them.
+    pub fn with_read_your_writes(mut self, pin: Duration) -> Self {
+        self.pin = Some(pin);
+        self
+    }
+
+    /// The connection to the primary, for the requests not routed here.
+    ///
+    /// Reads stay on the primary for a while after, as after a write.
+    pub fn primary(&mut self) -> &mut KvsClient {
+        self.last_write = Some(Instant::now());
+        &mut self.primary
+    }
+
+    /// Get the value of a given key from a replica.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.read(|client| client.get(key.clone()))
+    }
+
+    /// Get the values of `keys` from a replica, see `KvsClient::get_many`.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.read(|client| client.get_many(keys.clone()))
+    }
+
+    /// Check whether `key` exists on a replica.
+    pub fn exists(&mut self, key: String) -> Result<bool> {
+        self.read(|client| client.exists(key.clone()))
+    }
+
+    /// Get the keys matching the glob `pattern` from a replica, see
+    /// `KvsClient::keys`.
+    pub fn keys(&mut self, pattern: String) -> Result<Vec<String>> {
+        self.read(|client| client.keys(pattern.clone()))
+    }
+
+    /// Get a page of the keys matching the glob `pattern` from a replica,
+    /// see `KvsClient::scan`. Cursors are keys, so pages may come from
+    /// different replicas.
+    pub fn scan(
+        &mut self,
+        cursor: Option<String>,
+        count: u32,
+        pattern: String,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        self.read(|client| client.scan(cursor.clone(), count, pattern.clone()))
+    }
+
+    /// Set the value of a string key on the primary.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.primary().set(key, value)
+    }
+
+    /// Set the value of a string key on the primary, expiring after `ttl`.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.primary().set_with_ttl(key, value, ttl)
+    }
+
+    /// Set several string keys at once on the primary.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.primary().set_many(pairs)
+    }
+
+    /// Remove a string key from the primary.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.primary().remove(key)
+    }
+
+    /// Sends a read to the next replica, or to the primary if there is
+    /// none, reads are pinned to it, or the replica is unreachable.
+    fn read<T, F: FnMut(&mut KvsClient) -> Result<T>>(&mut self, mut f: F) -> Result<T> {
+        let pinned = match (self.pin, self.last_write) {
+            (Some(pin), Some(at)) => at.elapsed() < pin,
+            _ => false,
+        };
+        if pinned || self.replicas.is_empty() {
+            return f(&mut self.primary);
+        }
+        let addr = self.replicas[self.next % self.replicas.len()];
+        self.next = self.next.wrapping_add(1);
+        let result = match self.clients.entry(addr) {
+            Entry::Occupied(entry) => f(entry.into_mut()),
+            Entry::Vacant(entry) => self
+                .builder
+                .connect(addr)
+                .and_then(|client| f(entry.insert(client))),
+        };
+        match result {
+            Err(e) if is_transient(&e) => {
+                warn!("Replica {} is down ({}), reading from the primary", addr, e);
+                self.clients.remove(&addr);
+                f(&mut self.primary)
+            }
+            result => result,
+        }
+    }
+}
+
+fn resolve<A: ToSocketAddrs>(addr: A) -> Result<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| MyError::StringError("Address resolved to nothing".to_owned()))
+}
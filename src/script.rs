@@ -0,0 +1,248 @@
+//! Scripts sent by clients and run on the server: WASM modules, run with
+//! wasmi, that read and write the string keys under a prefix in one step,
+//! for read-modify-write logic such as rate limiters without round trips.
+//!
+//! A module exports its memory as `memory` and a function `run` taking and
+//! returning nothing. It may import from the `kvs` module, pointers and
+//! lengths being `i32`:
+//!
+//! - `arg(index, buf, cap) -> i64` copies argument `index` to `buf`, up to
+//!   `cap` bytes, and returns its length, -1 past the last argument;
+//! - `get(key, key_len, buf, cap) -> i64` does the same with the value of
+//!   `key`, -1 if it is not set;
+//! - `set(key, key_len, value, value_len)` sets `key`;
+//! - `remove(key, key_len) -> i32` removes `key`, returning 1 if it was set;
+//! - `output(buf, len)` sets the answer of the script.
+//!
+//! A value longer than `cap` is cut, and can be read again into a larger
+//! buffer. Keys, values and the answer are UTF-8.
+//!
+//! Writes are kept aside, where `get` reads them back, and become one batch
+//! once `run` returns; a script that traps writes nothing. Every script has
+//! `SCRIPT_FUEL` to spend, about one unit per instruction, and
+//! `SCRIPT_MEMORY` bytes of memory.
+use crate::engine::{KvsReader, WriteBatch};
+use crate::errors::{MyError, Result};
+use std::collections::BTreeMap;
+use wasmi::{
+    Caller, Config, Engine, Error, Extern, Linker, Memory, Module, Store, StoreLimits,
+    StoreLimitsBuilder,
+};
+
+/// Fuel a script may spend.
+const SCRIPT_FUEL: u64 = 10_000_000;
+
+/// Largest memory of a script.
+const SCRIPT_MEMORY: usize = 16 * 1024 * 1024;
+
+/// What the host functions of a running script work on.
+struct Host<R> {
+    reader: R,
+    prefix: String,
+    args: Vec<String>,
+    /// The writes of the script so far, `None` removing the key.
+    writes: BTreeMap<String, Option<String>>,
+    output: Option<String>,
+    /// Why a host function stopped the script, if one did.
+    error: Option<MyError>,
+    limits: StoreLimits,
+}
+
+impl<R: KvsReader> Host<R> {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.writes.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.reader.get(key.to_owned()),
+        }
+    }
+
+    /// Removes `key`, returning whether it was set.
+    fn remove(&mut self, key: String) -> Result<bool> {
+        let was_set = self.get(&key)?.is_some();
+        if self.reader.get(key.clone())?.is_some() {
+            self.writes.insert(key, None);
+        } else {
+            // only set by the script, which the batch need not remove
+            self.writes.remove(&key);
+        }
+        Ok(was_set)
+    }
+}
+
+impl<R> Host<R> {
+    /// Stops the script with `error`.
+    fn fail(&mut self, error: MyError) -> Error {
+        let trap = Error::new(error.to_string());
+        self.error = Some(error);
+        trap
+    }
+}
+
+/// Runs the script `module` with `args`, reading the store through
+/// `reader`, and returns its writes as a batch and its answer.
+///
+/// The store must not change while the script runs, so the caller holds
+/// the engine until the batch is applied.
+///
+/// # Errors
+///
+/// A script touching a key outside `prefix` fails with
+/// `MyError::PermissionDenied`; one that is invalid, traps or runs out of
+/// fuel, with `MyError::StringError`.
+pub(crate) fn run<R: KvsReader>(
+    reader: R,
+    module: &[u8],
+    prefix: String,
+    args: Vec<String>,
+) -> Result<(WriteBatch, Option<String>)> {
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config);
+    let module = Module::new(&engine, module).map_err(script_error)?;
+    let host = Host {
+        reader,
+        prefix,
+        args,
+        writes: BTreeMap::new(),
+        output: None,
+        error: None,
+        limits: StoreLimitsBuilder::new()
+            .memory_size(SCRIPT_MEMORY)
+            .instances(1)
+            .build(),
+    };
+    let mut store = Store::new(&engine, host);
+    store.limiter(|host| &mut host.limits);
+    store.set_fuel(SCRIPT_FUEL).map_err(script_error)?;
+
+    let result = linker(&engine).and_then(|linker| {
+        let instance = linker.instantiate(&mut store, &module)?.start(&mut store)?;
+        let run = instance.get_typed_func::<(), ()>(&store, "run")?;
+        run.call(&mut store, ())
+    });
+    let mut host = store.into_data();
+    if let Err(e) = result {
+        return Err(host.error.take().unwrap_or_else(|| script_error(e)));
+    }
+    let mut batch = WriteBatch::new();
+    for (key, value) in host.writes {
+        match value {
+            Some(value) => batch.set(key, value),
+            None => batch.remove(key),
+        };
+    }
+    Ok((batch, host.output))
+}
+
+/// The `kvs` functions a script may import.
+fn linker<R: KvsReader>(engine: &Engine) -> std::result::Result<Linker<Host<R>>, Error> {
+    let mut linker = Linker::new(engine);
+    linker.func_wrap(
+        "kvs",
+        "arg",
+        |mut caller: Caller<'_, Host<R>>, index: u32, buf: u32, cap: u32| {
+            let arg = caller.data().args.get(index as usize).cloned();
+            copy_out(&mut caller, arg, buf, cap)
+        },
+    )?;
+    linker.func_wrap(
+        "kvs",
+        "get",
+        |mut caller: Caller<'_, Host<R>>, key: u32, key_len: u32, buf: u32, cap: u32| {
+            let key = read_key(&mut caller, key, key_len)?;
+            let value = caller.data().get(&key);
+            let value = value.map_err(|e| caller.data_mut().fail(e))?;
+            copy_out(&mut caller, value, buf, cap)
+        },
+    )?;
+    linker.func_wrap(
+        "kvs",
+        "set",
+        |mut caller: Caller<'_, Host<R>>, key: u32, key_len: u32, value: u32, value_len: u32| {
+            let key = read_key(&mut caller, key, key_len)?;
+            let value = read_str(&caller, value, value_len)?;
+            caller.data_mut().writes.insert(key, Some(value));
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        "kvs",
+        "remove",
+        |mut caller: Caller<'_, Host<R>>, key: u32, key_len: u32| {
+            let key = read_key(&mut caller, key, key_len)?;
+            let host = caller.data_mut();
+            match host.remove(key) {
+                Ok(was_set) => Ok(u32::from(was_set)),
+                Err(e) => Err(host.fail(e)),
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "kvs",
+        "output",
+        |mut caller: Caller<'_, Host<R>>, buf: u32, len: u32| {
+            let output = read_str(&caller, buf, len)?;
+            caller.data_mut().output = Some(output);
+            Ok(())
+        },
+    )?;
+    Ok(linker)
+}
+
+fn memory<R>(caller: &Caller<'_, Host<R>>) -> std::result::Result<Memory, Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| Error::new("the module exports no memory"))
+}
+
+/// Reads the UTF-8 string of `len` bytes at `ptr` in the memory of the
+/// script.
+fn read_str<R>(
+    caller: &Caller<'_, Host<R>>,
+    ptr: u32,
+    len: u32,
+) -> std::result::Result<String, Error> {
+    let data = memory(caller)?.data(caller);
+    let bytes = (ptr as usize)
+        .checked_add(len as usize)
+        .and_then(|end| data.get(ptr as usize..end))
+        .ok_or_else(|| Error::new("out of bounds memory access"))?;
+    String::from_utf8(bytes.to_vec()).map_err(|e| Error::new(e.to_string()))
+}
+
+/// Reads a key, which must be under the prefix of the script.
+fn read_key<R>(
+    caller: &mut Caller<'_, Host<R>>,
+    ptr: u32,
+    len: u32,
+) -> std::result::Result<String, Error> {
+    let key = read_str(caller, ptr, len)?;
+    if !key.starts_with(caller.data().prefix.as_str()) {
+        return Err(caller.data_mut().fail(MyError::PermissionDenied));
+    }
+    Ok(key)
+}
+
+/// Copies `value` to `buf`, up to `cap` bytes, returning its length, or -1
+/// if there is none.
+fn copy_out<R>(
+    caller: &mut Caller<'_, Host<R>>,
+    value: Option<String>,
+    buf: u32,
+    cap: u32,
+) -> std::result::Result<i64, Error> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(-1),
+    };
+    let len = value.len().min(cap as usize);
+    memory(caller)?
+        .write(caller, buf as usize, &value.as_bytes()[..len])
+        .map_err(|e| Error::new(e.to_string()))?;
+    Ok(value.len() as i64)
+}
+
+fn script_error(e: impl std::fmt::Display) -> MyError {
+    MyError::StringError(format!("Script failed: {}", e))
+}
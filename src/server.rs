@@ -1,84 +1,3373 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::engine::KvsEngine;
-use crate::errors::Result;
+use crate::acl::{self, Acl, Rule};
+use crate::audit::{Audit, AuditLog};
+use crate::codec::{Codec, Compression, MessageReader, MessageWriter};
+use crate::common::{
+    pairs_crc, AuthResponse, ChangeBatch, CountResponse, DbSizeResponse, ErrorCode, ErrorResponse,
+    Event, ExistsResponse, ExportResponse, FindResponse, GetManyResponse, GetResponse,
+    HGetAllResponse, HelloResponse, LockResponse, MemberResponse, MembersResponse,
+    MetadataResponse, PingResponse, Pong, PushResponse, RangeResponse, ReadConsistency,
+    RemoveResponse, ReplicaAck, Request, ScanPage, ScanResponse, ScoresResponse, SelectResponse,
+    ServerInfo, ServerState, SetResponse, SlowLogResponse, StatsResponse, StrBytes, StreamPosition,
+    SubscribeResponse, SyncResponse, TtlResponse, VersionResponse, WatchResponse, WireError,
+    COMPRESSION_SINCE_VERSION, MAX_MESSAGE_LEN, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    REQUEST_ID_SINCE_VERSION,
+};
+use crate::engine::{
+    check_bucket_name, unix_millis, Command, GroupCommit, JsonPath, KvsEngine, KvsReader,
+    WriteBatch, DEFAULT_BUCKET,
+};
+use crate::errors::{MyError, Result};
+use crate::glob::Glob;
+#[cfg(feature = "grpc")]
+use crate::grpc;
+use crate::latency::{self, Latencies};
+#[cfg(feature = "otel")]
+use crate::otel;
+use crate::pubsub::{event_bytes, Broker};
+#[cfg(feature = "raft")]
+use crate::raft::{RaftConfig, RaftNode, StateMachine};
+use crate::ratelimit::RateLimiter;
+use crate::replication::{self, Consistency, Follower, Replicas, Unacked};
+#[cfg(feature = "scripting")]
+use crate::script;
+use crate::slowlog::{RequestId, SlowLog};
+use crate::snapshot::Snapshots;
+use crate::tenant::{Tenant, Tenants};
+use crate::tls::ServerTlsConfig;
+#[cfg(unix)]
+use crate::transport::bind_unix;
+use crate::transport::Stream;
+use crate::websocket::{self, Message};
 
-use log::{error, info};
-use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(feature = "scripting")]
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use parking_lot::MutexGuard;
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "raft")]
+use std::collections::HashSet;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, info_span, warn};
+
+/// How long shutdown waits for in-flight requests before giving up on them.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often connections answered during warm-up check whether the server
+/// took over, to close them for clients to reconnect to it.
+const WARM_UP_POLL: Duration = Duration::from_millis(200);
+
+/// How often expired keys are swept.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keys a sweep looks at in each store, bounding how long it holds the
+/// engine.
+const SWEEP_KEYS: usize = 1000;
+
+/// Most keys a `Scan` page looks at, bounding how long it holds the engine.
+const MAX_SCAN_COUNT: usize = 10_000;
+
+/// Keys an `Export` looks at for each frame it sends.
+const EXPORT_PAGE_KEYS: usize = 1000;
+
+/// How long a write waits for replicas when no request timeout is set.
+const REPLICATION_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct Server<E: KvsEngine> {
-    engine: E,
+    context: Context<E>,
+    shutdown: ShutdownHandle,
+    ws_addr: Option<SocketAddr>,
+    ws_origins: Vec<String>,
+    #[cfg(feature = "grpc")]
+    grpc_addr: Option<SocketAddr>,
+    admin_addr: Option<SocketAddr>,
+    #[cfg(unix)]
+    unix_socket: Option<PathBuf>,
+    leader_addr: Option<SocketAddr>,
+    /// File a replica keeps its position in the change stream in.
+    replica_state: Option<PathBuf>,
+    #[cfg(feature = "raft")]
+    raft_config: Option<RaftConfig>,
+}
+
+/// What an authenticated connection may do.
+enum Access {
+    /// Authenticated with the server token, or no authentication configured.
+    Full,
+    /// Authenticated with a token from the ACL.
+    Restricted(Arc<Rule>),
+}
+
+impl Access {
+    /// Who the connection is, in the audit log.
+    fn identity(access: &Option<Access>) -> &str {
+        match access {
+            None => "anonymous",
+            Some(Access::Full) => "server token",
+            Some(Access::Restricted(rule)) => rule.name(),
+        }
+    }
+}
+
+/// The lock over an engine, which requests wait for up to the request
+/// timeout. A request that panics with it releases it, rather than
+/// keep every other request out of the engine.
+pub(crate) type EngineLock<E> = parking_lot::Mutex<E>;
+
+/// Engine, reader, group commit and subscribers of a bucket.
+type Bucket<E> = (
+    Arc<EngineLock<E>>,
+    <E as KvsEngine>::Reader,
+    Option<Arc<GroupCommit>>,
+    Arc<Broker>,
+);
+
+/// Settings a [`ReloadHandle`] can change while the server runs.
+#[derive(Default)]
+pub(crate) struct Settings {
+    pub(crate) auth_token: Option<Arc<String>>,
+    acl: Option<Arc<Acl>>,
+    max_connections: Option<usize>,
+    /// Requests per second each connection may send.
+    rate_limit: Option<u32>,
+    idle_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    slow_log: Option<Arc<SlowLog>>,
+}
+
+/// State shared by every connection handler.
+pub(crate) struct Context<E: KvsEngine> {
+    pub(crate) engine: Arc<EngineLock<E>>,
+    /// Serves reads without taking the engine lock.
+    reader: E::Reader,
+    /// Syncs writes once the engine lock is released, if the engine left
+    /// that to the server.
+    commit: Option<Arc<GroupCommit>>,
+    pub(crate) broker: Arc<Broker>,
+    read_only: bool,
+    /// Whether the store follows a leader, which only replicates its
+    /// default store.
+    replica: bool,
+    settings: Arc<RwLock<Settings>>,
+    /// Certificate the TCP listeners serve clients over TLS with.
+    tls: Option<ServerTlsConfig>,
+    connections: Arc<Connections>,
+    /// Largest framed request read.
+    max_message_len: usize,
+    /// Buckets opened so far, by name.
+    buckets: Arc<Mutex<HashMap<String, Bucket<E>>>>,
+    /// Replicas streaming the changes of `broker`.
+    replicas: Arc<Replicas>,
+    /// How many of them a write waits for.
+    consistency: Consistency,
+    /// When the server was created, for `Ping`.
+    started: Instant,
+    /// Name of the engine, for `Ping` not to wait for the engine lock.
+    engine_name: &'static str,
+    /// Scheduled snapshots of the default store, reported in its stats.
+    snapshots: Option<Arc<Snapshots>>,
+    /// How long requests took, whatever the bucket.
+    latencies: Arc<Latencies>,
+    /// Name of the bucket, for the audit log.
+    bucket: String,
+    audit: Option<Arc<AuditLog>>,
+    /// Tenants clients may work for.
+    tenants: Option<Arc<Tenants>>,
+    /// The tenant whose store this is, if any.
+    tenant: Option<Arc<Tenant>>,
+    /// Whether an admin listener serves the management requests, which
+    /// the other listeners then refuse.
+    admin_listener: bool,
+    /// Whether the connection came through the admin listener.
+    on_admin_listener: bool,
+    /// Stops the server, for `Shutdown`.
+    shutdown: ShutdownHandle,
+    /// Reloads the configuration of the server, for `ReloadConfig`.
+    reload_config: Option<Arc<dyn Fn() -> Result<()> + Send + Sync>>,
+    #[cfg(feature = "raft")]
+    raft: Option<Arc<RaftNode>>,
+}
+
+impl<E: KvsEngine> Clone for Context<E> {
+    fn clone(&self) -> Self {
+        Context {
+            engine: Arc::clone(&self.engine),
+            reader: self.reader.clone(),
+            commit: self.commit.clone(),
+            broker: Arc::clone(&self.broker),
+            read_only: self.read_only,
+            replica: self.replica,
+            settings: Arc::clone(&self.settings),
+            tls: self.tls.clone(),
+            connections: Arc::clone(&self.connections),
+            max_message_len: self.max_message_len,
+            buckets: Arc::clone(&self.buckets),
+            replicas: Arc::clone(&self.replicas),
+            consistency: self.consistency,
+            started: self.started,
+            engine_name: self.engine_name,
+            snapshots: self.snapshots.clone(),
+            latencies: Arc::clone(&self.latencies),
+            bucket: self.bucket.clone(),
+            audit: self.audit.clone(),
+            tenants: self.tenants.clone(),
+            tenant: self.tenant.clone(),
+            admin_listener: self.admin_listener,
+            on_admin_listener: self.on_admin_listener,
+            shutdown: self.shutdown.clone(),
+            reload_config: self.reload_config.clone(),
+            #[cfg(feature = "raft")]
+            raft: self.raft.clone(),
+        }
+    }
+}
+
+/// The engine of a Raft node, which applies the entries of its log.
+#[cfg(feature = "raft")]
+struct RaftMachine<E: KvsEngine> {
+    engine: Arc<EngineLock<E>>,
+    commit: Option<Arc<GroupCommit>>,
+    broker: Arc<Broker>,
+}
+
+#[cfg(feature = "raft")]
+impl<E: KvsEngine> RaftMachine<E> {
+    fn sync(&self) -> Result<()> {
+        self.commit.as_ref().map_or(Ok(()), |commit| commit.sync())
+    }
+}
+
+#[cfg(feature = "raft")]
+impl<E: KvsEngine + Send> StateMachine for RaftMachine<E> {
+    fn apply(&self, command: &Command) -> Result<()> {
+        let mut engine = self.engine.lock();
+        match command.clone() {
+            Command::Set { key, value } => {
+                engine.set(key.clone(), value.clone())?;
+                self.broker.publish(&Event::Set { key, value });
+            }
+            Command::Remove { key } => {
+                engine.remove(key.clone())?;
+                self.broker.publish(&Event::Removed { key });
+            }
+            Command::Batch(commands) => {
+                let batch = WriteBatch { commands };
+                let events = batch_events(&batch);
+                engine.write_batch(batch)?;
+                for event in &events {
+                    self.broker.publish(event);
+                }
+            }
+            Command::Push { .. }
+            | Command::Pop { .. }
+            | Command::HSet { .. }
+            | Command::HDel { .. }
+            | Command::SAdd { .. }
+            | Command::SRem { .. }
+            | Command::ZAdd { .. }
+            | Command::Merge { .. }
+            | Command::Touch { .. } => {
+                unreachable!("collections, merges and touches are not proposed")
+            }
+        }
+        drop(engine);
+        self.sync()
+    }
+
+    fn snapshot(&self) -> Result<Vec<(String, String)>> {
+        self.engine.lock().scan(String::new())
+    }
+
+    fn restore(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut engine = self.engine.lock();
+        let live: HashSet<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+        for key in engine.keys("*".to_owned())? {
+            if !live.contains(key.as_str()) {
+                engine.remove(key.clone())?;
+                self.broker.publish(&Event::Removed { key });
+            }
+        }
+        for (key, value) in pairs {
+            engine.set(key.clone(), value.clone())?;
+            self.broker.publish(&Event::Set { key, value });
+        }
+        drop(engine);
+        self.sync()
+    }
+}
+
+impl<E: KvsEngine + Send + 'static> Server<E> {
+    /// Create a `KvsServer` with a given storage engine.
+    pub fn new(engine: E) -> Self {
+        let shutdown = ShutdownHandle::default();
+        Server {
+            context: Context {
+                reader: engine.reader(),
+                started: Instant::now(),
+                engine_name: engine.name(),
+                commit: None,
+                engine: Arc::new(EngineLock::new(engine)),
+                broker: Arc::new(Broker::default()),
+                read_only: false,
+                replica: false,
+                settings: Arc::new(RwLock::new(Settings::default())),
+                tls: None,
+                connections: Arc::new(Connections::default()),
+                max_message_len: MAX_MESSAGE_LEN,
+                buckets: Arc::new(Mutex::new(HashMap::new())),
+                replicas: Arc::new(Replicas::default()),
+                consistency: Consistency::Async,
+                snapshots: None,
+                latencies: Arc::new(Latencies::default()),
+                bucket: DEFAULT_BUCKET.to_owned(),
+                audit: None,
+                tenants: None,
+                tenant: None,
+                admin_listener: false,
+                on_admin_listener: false,
+                shutdown: shutdown.clone(),
+                reload_config: None,
+                #[cfg(feature = "raft")]
+                raft: None,
+            },
+            shutdown,
+            ws_addr: None,
+            ws_origins: Vec::new(),
+            #[cfg(feature = "grpc")]
+            grpc_addr: None,
+            admin_addr: None,
+            #[cfg(unix)]
+            unix_socket: None,
+            leader_addr: None,
+            replica_state: None,
+            #[cfg(feature = "raft")]
+            raft_config: None,
+        }
+    }
+
+    /// Also serve the gRPC service of `proto/kvs.proto` on `addr`, through
+    /// the same checks as the other listeners. gRPC connections do not
+    /// count against the connection limit, and have no rate limit.
+    #[cfg(feature = "grpc")]
+    pub fn with_grpc(mut self, addr: SocketAddr) -> Self {
+        self.grpc_addr = Some(addr);
+        self
+    }
+
+    /// Join a Raft cluster: writes are replicated through the cluster log
+    /// and only accepted by the elected leader.
+    #[cfg(feature = "raft")]
+    pub fn with_raft(mut self, config: RaftConfig) -> Self {
+        self.raft_config = Some(config);
+        self
+    }
+
+    /// Replicate the server at `addr` instead of accepting writes.
+    ///
+    /// The local store is brought in sync with the leader before the server
+    /// starts listening, then kept up to date from the leader's change stream.
+    pub fn replica_of(mut self, addr: SocketAddr) -> Self {
+        self.leader_addr = Some(addr);
+        self.context.read_only = true;
+        self.context.replica = true;
+        self
+    }
+
+    /// Keep the position of the replica in the change stream of its leader
+    /// in the file at `path` as it applies it, so that after a restart it
+    /// resumes the stream rather than loading a whole snapshot, if the
+    /// leader still has the changes since. The file belongs with the data
+    /// of the store, which it describes.
+    pub fn with_replica_state(mut self, path: impl Into<PathBuf>) -> Self {
+        self.replica_state = Some(path.into());
+        self
+    }
+
+    /// Serve reads only, refusing every write with `MyError::ReadOnly`,
+    /// e.g. to expose a store to analytics consumers safely.
+    pub fn with_read_only(mut self) -> Self {
+        self.context.read_only = true;
+        self
+    }
+
+    /// Serve the clients of the TCP and admin listeners over TLS, proving
+    /// the identity of the server with `tls`. Unix socket, WebSocket and
+    /// gRPC clients are served as before.
+    pub fn with_tls(mut self, tls: ServerTlsConfig) -> Self {
+        self.context.tls = Some(tls);
+        self
+    }
+
+    /// Acknowledge writes once replicas have them as `consistency`
+    /// requires, rather than once applied locally.
+    ///
+    /// Writes that replicas do not acknowledge within the request timeout,
+    /// or 5 seconds, fail with `MyError::Timeout` although applied.
+    pub fn with_consistency(mut self, consistency: Consistency) -> Self {
+        self.context.consistency = consistency;
+        self
+    }
+
+    /// Require clients to authenticate with `token` before any other
+    /// request.
+    pub fn with_auth_token(mut self, token: String) -> Self {
+        self.context.settings_mut().auth_token = Some(Arc::new(token));
+        self
+    }
+
+    /// Let the tokens listed in `acl` authenticate, restricting each to
+    /// its allowed operations and key prefixes.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.context.settings_mut().acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Serve at most `max` connections at once. Further clients are sent a
+    /// `Too many connections` error and disconnected.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.context.settings_mut().max_connections = Some(max);
+        self
+    }
+
+    /// Let every connection send `rate` requests per second, in bursts of
+    /// up to `rate`; requests beyond get a `RateLimited` error. ACL tokens
+    /// may have limits of their own on top.
+    pub fn with_rate_limit(mut self, rate: u32) -> Self {
+        self.context.settings_mut().rate_limit = Some(rate);
+        self
+    }
+
+    /// Refuse framed requests of more than `len` bytes, rather than
+    /// `MAX_MESSAGE_LEN`, before reading them; the client is sent an error
+    /// and disconnected.
+    pub fn with_max_message_len(mut self, len: usize) -> Self {
+        self.context.max_message_len = len;
+        self
+    }
+
+    /// Close connections that send no request for `timeout`.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.context.settings_mut().idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail requests that wait longer than `timeout` for the engine, and
+    /// drop clients that do not read their response within `timeout`.
+    ///
+    /// A request that got the engine is not interrupted, however long the
+    /// engine then takes.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.context.settings_mut().request_timeout = Some(timeout);
+        self
+    }
+
+    /// Log requests taking at least `threshold` and keep the most recent
+    /// ones for the `SlowLog` request.
+    pub fn with_slow_log(mut self, threshold: Duration) -> Self {
+        self.context.settings_mut().slow_log = Some(Arc::new(SlowLog::new(threshold)));
+        self
+    }
+
+    /// Snapshot the store into `dir` every `interval`, keeping the `retain`
+    /// most recent snapshots. Buckets are not snapshotted.
+    pub fn with_snapshots(mut self, dir: PathBuf, interval: Duration, retain: usize) -> Self {
+        self.context.snapshots = Some(Arc::new(Snapshots::new(dir, interval, retain)));
+        self
+    }
+
+    /// Record every write, of every bucket, in `audit_log`.
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.context.audit = Some(Arc::new(audit_log));
+        self
+    }
+
+    /// Host `tenants`, each in a store of its own under `tenants/<name>` in
+    /// the data directory, with the quotas it lists.
+    ///
+    /// Clients name their tenant in the handshake, and ACL tokens given a
+    /// tenant are confined to it. Tenants have no buckets, and report the
+    /// latencies of their own requests in their stats.
+    pub fn with_tenants(mut self, tenants: Tenants) -> Self {
+        self.context.tenants = Some(Arc::new(tenants));
+        self
+    }
+
+    /// Also accept WebSocket clients on `addr`, exchanging the same JSON
+    /// messages as text frames.
+    pub fn with_websocket(mut self, addr: SocketAddr) -> Self {
+        self.ws_addr = Some(addr);
+        self
+    }
+
+    /// Accepts WebSocket upgrades from browser pages of these origins, such
+    /// as `https://example.com`, or of any with `*`. Upgrades carrying
+    /// another `Origin` are refused, and without any only clients outside a
+    /// browser, which send none, can connect.
+    pub fn with_websocket_origins(mut self, origins: Vec<String>) -> Self {
+        self.ws_origins = origins;
+        self
+    }
+
+    /// Serve the requests managing the server on a listener of its own at
+    /// `addr`, so that firewalls and ACLs can guard them apart from the
+    /// data: `Stats`, `SlowLog`, `FlushAll`, `PurgeTrash`, `Compact`,
+    /// `ReloadConfig`, `Shutdown` and `Drain`. The other listeners then refuse
+    /// them, and the admin listener refuses data requests. Its connections
+    /// speak the same protocol, authenticate the same way, and do not count
+    /// against the connection limit, so that operators get in when it is
+    /// reached.
+    ///
+    /// `ReloadConfig`, `Shutdown` and `Drain` are only served there.
+    pub fn with_admin_listener(mut self, addr: SocketAddr) -> Self {
+        self.admin_addr = Some(addr);
+        self.context.admin_listener = true;
+        self
+    }
+
+    /// Answer `ReloadConfig` by calling `reload` with the reload handle of
+    /// the server, as a SIGHUP handler would; without one, the request
+    /// fails.
+    pub fn with_config_reload(
+        mut self,
+        reload: impl Fn(&ReloadHandle) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        let handle = self.reload_handle();
+        self.context.reload_config = Some(Arc::new(move || reload(&handle)));
+        self
+    }
+
+    /// Also accept clients on a Unix socket at `path`, speaking the same
+    /// protocol as over TCP. Who may connect is up to the permissions of
+    /// the socket file and its directory; a token is still required if
+    /// one is configured.
+    #[cfg(unix)]
+    pub fn with_unix_socket(mut self, path: PathBuf) -> Self {
+        self.unix_socket = Some(path);
+        self
+    }
+
+    /// Shut down `period` after entering lame-duck mode, see
+    /// [`ShutdownHandle::lame_duck`], rather than waiting for a shutdown
+    /// request.
+    pub fn with_lame_duck_period(self, period: Duration) -> Self {
+        *self.shutdown.lame_duck_period.lock().unwrap() = Some(period);
+        self
+    }
+
+    /// A handle stopping the server from another thread, e.g. a signal
+    /// handler.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// A handle changing the settings of the server while it runs, e.g.
+    /// when its configuration file is reloaded.
+    pub fn reload_handle(&self) -> ReloadHandle {
+        let engine = Arc::clone(&self.context.engine);
+        let buckets = Arc::clone(&self.context.buckets);
+        ReloadHandle {
+            settings: Arc::clone(&self.context.settings),
+            compaction_threshold: Arc::new(move |bytes| {
+                // buckets first, as when one is opened, so that none is
+                // opened with the old threshold meanwhile
+                let buckets = buckets.lock().unwrap();
+                engine.lock().set_compaction_threshold(bytes)?;
+                for (engine, _, _, _) in buckets.values() {
+                    engine.lock().set_compaction_threshold(bytes)?;
+                }
+                Ok(())
+            }),
+        }
+    }
+
+    /// Serves clients on `addr` until shut down through a
+    /// [`ShutdownHandle`], then lets in-flight requests finish and makes
+    /// the engine durable.
+    ///
+    /// Writers share the syncs of an engine that lets them, rather than
+    /// each syncing under the engine lock.
+    pub fn open<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.open_on(|| Ok(TcpListener::bind(addr)?))
+    }
+
+    /// Serves clients on the address `warm_up` answers health checks on,
+    /// as `open` does. `warm_up` goes on answering them as starting until
+    /// the server is ready to accept connections, replicas having caught
+    /// up with their leader.
+    pub fn open_after_warm_up(mut self, warm_up: WarmUp) -> Result<()> {
+        // the uptime counts from the warm-up
+        self.context.started = warm_up.started;
+        self.open_on(move || warm_up.finish())
+    }
+
+    /// Serves clients on the listener `bind` returns once the rest of the
+    /// server is set up.
+    fn open_on(mut self, bind: impl FnOnce() -> Result<TcpListener>) -> Result<()> {
+        self.context.commit = self.context.engine.lock().defer_syncs()?;
+
+        #[cfg(feature = "raft")]
+        if let Some(config) = self.raft_config.take() {
+            let machine = RaftMachine {
+                engine: Arc::clone(&self.context.engine),
+                commit: self.context.commit.clone(),
+                broker: Arc::clone(&self.context.broker),
+            };
+            self.context.raft = Some(RaftNode::start(config, Box::new(machine))?);
+        }
+
+        if let Some(leader_addr) = self.leader_addr {
+            let mut follower = Follower::new(leader_addr, self.replica_state.take());
+            let changes = follower.sync(&self.context)?;
+            let context = self.context.clone();
+            thread::spawn(move || follower.follow(context, changes));
+        }
+
+        if let Some(ws_addr) = self.ws_addr {
+            let ws_listener = TcpListener::bind(ws_addr)?;
+            info!("WebSocket listening on {}", ws_addr);
+            self.shutdown.watch(&ws_listener)?;
+            let context = self.context.clone();
+            let shutdown = self.shutdown.clone();
+            let origins = Arc::new(self.ws_origins.clone());
+            thread::spawn(move || serve_websocket(ws_listener, context, origins, shutdown));
+        }
+
+        #[cfg(feature = "grpc")]
+        if let Some(grpc_addr) = self.grpc_addr {
+            let grpc_listener = TcpListener::bind(grpc_addr)?;
+            info!("gRPC listening on {}", grpc_addr);
+            let context = self.context.clone();
+            let handler: grpc::Handler =
+                Arc::new(move |req, token, peer| context.answer(req, token, peer));
+            let shutdown = self.shutdown.clone();
+            thread::spawn(move || {
+                if let Err(e) = grpc::serve(grpc_listener, handler, move || shutdown.is_requested())
+                {
+                    error!("{}", e);
+                }
+            });
+        }
+
+        if let Some(admin_addr) = self.admin_addr {
+            let admin_listener = TcpListener::bind(admin_addr)?;
+            info!("Admin listening on {}", admin_addr);
+            self.shutdown.watch(&admin_listener)?;
+            let mut context = self.context.clone();
+            context.on_admin_listener = true;
+            let shutdown = self.shutdown.clone();
+            thread::spawn(move || serve_admin(admin_listener, context, shutdown));
+        }
+
+        #[cfg(unix)]
+        if let Some(path) = self.unix_socket.take() {
+            let unix_listener = bind_unix(&path)?;
+            info!("Listening on Unix socket {}", path.display());
+            self.shutdown.watch_unix(&path);
+            let context = self.context.clone();
+            let shutdown = self.shutdown.clone();
+            thread::spawn(move || serve_unix(unix_listener, &path, context, shutdown));
+        }
+
+        // replicas get the removals of the keys their leader sweeps
+        let (stop_sweeping, stopped) = mpsc::channel::<()>();
+        let sweeper = if self.context.read_only || self.context.is_clustered() {
+            None
+        } else {
+            let context = self.context.clone();
+            Some(thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(SWEEP_INTERVAL) {
+                    context.sweep_expired();
+                }
+            }))
+        };
+
+        let (stop_snapshots, stopped) = mpsc::channel::<()>();
+        let snapshotter = self.context.snapshots.clone().map(|snapshots| {
+            let engine = Arc::clone(&self.context.engine);
+            thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) =
+                    stopped.recv_timeout(snapshots.interval())
+                {
+                    snapshots.take(&engine);
+                }
+            })
+        });
+
+        // accept connections and process each one on its own thread
+        let listener = bind()?;
+        self.shutdown.watch(&listener)?;
+        while !self.shutdown.is_requested() {
+            match listener.accept() {
+                Ok((stream, peer_addr)) => match self.context.admit(Stream::Tcp(stream)) {
+                    Ok((stream, Some(guard))) => {
+                        let context = self.context.clone();
+                        // the thread name tags every log line of the connection
+                        let spawned = thread::Builder::new()
+                            .name(format!("client {}", peer_addr))
+                            .spawn(move || {
+                                if let Err(e) = context.handle_tcp_connection(stream) {
+                                    warn!("Connection closed with error: {}", e);
+                                }
+                                drop(guard);
+                            });
+                        if let Err(e) = spawned {
+                            error!("Connection failed {}", e);
+                        }
+                    }
+                    Ok((stream, None)) => {
+                        warn!("Connection limit reached, rejecting client");
+                        thread::spawn(move || reject(stream));
+                    }
+                    Err(e) => error!("Connection failed {}", e),
+                },
+                Err(e) => error!("Connection failed {}", e),
+            }
+        }
+        // the sweeper must not write once the engines are shut down
+        drop(stop_sweeping);
+        if let Some(sweeper) = sweeper {
+            let _ = sweeper.join();
+        }
+        drop(stop_snapshots);
+        if let Some(snapshotter) = snapshotter {
+            let _ = snapshotter.join();
+        }
+        self.context.drain()
+    }
 }
 
-impl<E: KvsEngine> Server<E> {
-    /// Create a `KvsServer` with a given storage engine.
-    pub fn new(engine: E) -> Self {
-        Server { engine }
+/// Stops a running [`Server`], or puts it in lame-duck mode.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    draining: Arc<AtomicBool>,
+    /// How long lame-duck mode lasts before the server shuts down, if it
+    /// does on its own.
+    lame_duck_period: Arc<Mutex<Option<Duration>>>,
+    listeners: Arc<Mutex<Vec<SocketAddr>>>,
+    #[cfg(unix)]
+    unix_listeners: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+impl ShutdownHandle {
+    /// Asks the server to stop accepting connections and shut down.
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        // wake up the threads blocked in `accept`
+        for addr in self.listeners.lock().unwrap().iter() {
+            wake(*addr);
+        }
+        #[cfg(unix)]
+        for path in self.unix_listeners.lock().unwrap().iter() {
+            let _ = UnixStream::connect(path);
+        }
+    }
+
+    /// Puts the server in lame-duck mode: it goes on serving every
+    /// connection, old and new, but answers `Ping` as draining, for load
+    /// balancers to send clients elsewhere before it shuts down. It shuts
+    /// down once the period set with `Server::with_lame_duck_period`
+    /// passes, if any, and otherwise when asked to.
+    pub fn lame_duck(&self) {
+        if self.draining.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        match *self.lame_duck_period.lock().unwrap() {
+            Some(period) => {
+                warn!("Entering lame-duck mode, shutting down in {:?}", period);
+                let handle = self.clone();
+                thread::spawn(move || {
+                    thread::sleep(period);
+                    handle.shutdown();
+                });
+            }
+            None => warn!("Entering lame-duck mode until shut down"),
+        }
+    }
+
+    /// The state the server reports to health checks.
+    pub fn state(&self) -> ServerState {
+        match self.draining.load(Ordering::SeqCst) {
+            true => ServerState::Draining,
+            false => ServerState::Serving,
+        }
+    }
+
+    fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    fn watch(&self, listener: &TcpListener) -> Result<()> {
+        self.listeners.lock().unwrap().push(listener.local_addr()?);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn watch_unix(&self, path: &Path) {
+        self.unix_listeners.lock().unwrap().push(path.to_owned());
+    }
+}
+
+/// Answers health checks on the address of a [`Server`] while its engine
+/// opens, which takes a while for a large log, so that load balancers see
+/// it starting rather than down.
+///
+/// Connections get through the handshake and have `Ping` answered with the
+/// `starting` state; every other request fails with `Unavailable`. Once
+/// the server takes over, see `Server::open_after_warm_up`, they are closed
+/// for clients to connect again.
+///
+/// Example:
+///
+/// ```no_run
+/// # use kvs::{KvStore, Result, Server, WarmUp};
+/// # fn try_main() -> Result<()> {
+/// let warm_up = WarmUp::start("127.0.0.1:4000", "kvs")?;
+/// let store = KvStore::open("data")?;
+/// Server::new(store).open_after_warm_up(warm_up)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct WarmUp {
+    /// Taken by the server.
+    listener: Option<TcpListener>,
+    addr: SocketAddr,
+    done: Arc<AtomicBool>,
+    accepter: Option<thread::JoinHandle<()>>,
+    started: Instant,
+}
+
+impl WarmUp {
+    /// Listens on `addr`, answering as a server of `engine`, the name of
+    /// its engine.
+    pub fn start<A: ToSocketAddrs>(addr: A, engine: &str) -> Result<WarmUp> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let accepting = listener.try_clone()?;
+        let done = Arc::new(AtomicBool::new(false));
+        let started = Instant::now();
+        let (engine, stopped) = (engine.to_owned(), Arc::clone(&done));
+        let accepter = thread::spawn(move || {
+            for stream in accepting.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("Connection failed {}", e);
+                        continue;
+                    }
+                };
+                let (engine, done) = (engine.clone(), Arc::clone(&stopped));
+                thread::spawn(move || {
+                    if let Err(e) = answer_warming(stream, &engine, started, &done) {
+                        warn!("Connection closed with error: {}", e);
+                    }
+                });
+            }
+        });
+        info!("Warming up on {}", addr);
+        Ok(WarmUp {
+            listener: Some(listener),
+            addr,
+            done,
+            accepter: Some(accepter),
+            started,
+        })
+    }
+
+    /// Stops answering, and hands the listener over to the server.
+    fn finish(mut self) -> Result<TcpListener> {
+        self.stop();
+        Ok(self.listener.take().expect("taken once"))
+    }
+
+    fn stop(&mut self) {
+        self.done.store(true, Ordering::SeqCst);
+        if let Some(accepter) = self.accepter.take() {
+            wake(self.addr);
+            let _ = accepter.join();
+        }
+    }
+}
+
+impl Drop for WarmUp {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Answers the handshake and `Ping` on a connection made during warm-up,
+/// refusing every other request, until `done`.
+fn answer_warming(
+    stream: TcpStream,
+    engine: &str,
+    started: Instant,
+    done: &AtomicBool,
+) -> Result<()> {
+    stream.set_read_timeout(Some(WARM_UP_POLL))?;
+    let mut reader = MessageReader::new(BufReader::new(&stream));
+    let mut writer = MessageWriter::new(BufWriter::new(&stream));
+    while !done.load(Ordering::SeqCst) {
+        match reader.wait() {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => return Err(e.into()),
+        }
+        writer.set_request_id(None);
+        let (req, request_id) = reader.receive::<Request>()?.untagged();
+        let (req, _) = req.untraced();
+        writer.set_request_id(request_id);
+        match req {
+            Request::Hello {
+                version,
+                codecs,
+                compression,
+                ..
+            } => {
+                // requests needing a token are refused anyway
+                negotiate_hello(
+                    &mut reader,
+                    &mut writer,
+                    &codecs,
+                    &compression,
+                    |codec, compression| hello(version, codec, compression, engine, false),
+                )?;
+            }
+            Request::Ping => {
+                let response = PingResponse::Ok(Pong {
+                    version: env!("CARGO_PKG_VERSION").to_owned(),
+                    uptime_secs: started.elapsed().as_secs(),
+                    engine: engine.to_owned(),
+                    state: ServerState::Starting,
+                });
+                writer.send(&response)?;
+            }
+            req => {
+                let error = writer.error(&MyError::Server {
+                    code: ErrorCode::Unavailable,
+                    message: format!("The server is starting, {} is not served yet", req.kind()),
+                });
+                writer.send(&ErrorResponse::Err(error))?;
+            }
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Answers a `Hello` offering `codecs` and `compression`, with the
+/// response `respond` makes of the first of each the server knows, and
+/// switches the connection over to what it agrees to.
+fn negotiate_hello<R: BufRead, W: Write>(
+    reader: &mut MessageReader<R>,
+    writer: &mut MessageWriter<W>,
+    codecs: &[String],
+    compression: &[String],
+    respond: impl FnOnce(Codec, Compression) -> HelloResponse,
+) -> Result<HelloResponse> {
+    let codec = codecs
+        .iter()
+        .find_map(|name| name.parse().ok())
+        .unwrap_or_default();
+    let compression = compression
+        .iter()
+        .find_map(|name| name.parse().ok())
+        .unwrap_or_default();
+    let response = respond(codec, compression);
+    writer.send(&response)?;
+    if let HelloResponse::Ok(info) = &response {
+        writer.negotiated(info);
+        reader.negotiated(info);
+        writer.send_echoes(info.version >= REQUEST_ID_SINCE_VERSION);
+    }
+    Ok(response)
+}
+
+/// Answers a `Hello` for a server of `engine`.
+fn hello(
+    version: u32,
+    codec: Codec,
+    compression: Compression,
+    engine: &str,
+    auth_required: bool,
+) -> HelloResponse {
+    if version < MIN_PROTOCOL_VERSION {
+        return HelloResponse::Err(format!(
+            "Unsupported protocol version {}, the server speaks {} to {}",
+            version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    let version = version.min(PROTOCOL_VERSION);
+    let compression = match compression {
+        // older versions take the flag of compressed frames for a length
+        Compression::None => Vec::new(),
+        _ if version < COMPRESSION_SINCE_VERSION => Vec::new(),
+        compression => vec![compression.name().to_owned()],
+    };
+    HelloResponse::Ok(ServerInfo {
+        version,
+        engine: engine.to_owned(),
+        auth_required,
+        compression,
+        codec,
+        tenant: None,
+    })
+}
+
+/// Wakes up the thread blocked accepting connections on `addr`.
+fn wake(mut addr: SocketAddr) {
+    if addr.ip().is_unspecified() {
+        addr.set_ip(Ipv4Addr::LOCALHOST.into());
+    }
+    let _ = TcpStream::connect(addr);
+}
+
+/// Changes the settings of a running [`Server`].
+///
+/// Changes apply to the requests and connections that follow: open
+/// connections are kept, with the timeouts they started with, and those
+/// already authenticated stay so.
+#[derive(Clone)]
+pub struct ReloadHandle {
+    settings: Arc<RwLock<Settings>>,
+    compaction_threshold: Arc<dyn Fn(u64) -> Result<()> + Send + Sync>,
+}
+
+impl ReloadHandle {
+    /// Requires clients to authenticate with `token`, see
+    /// `Server::with_auth_token`; `None` drops the server token.
+    pub fn set_auth_token(&self, token: Option<String>) {
+        self.settings.write().unwrap().auth_token = token.map(Arc::new);
+    }
+
+    /// Replaces the ACL, see `Server::with_acl`.
+    pub fn set_acl(&self, acl: Option<Acl>) {
+        self.settings.write().unwrap().acl = acl.map(Arc::new);
+    }
+
+    /// Changes the connection limit, see `Server::with_max_connections`.
+    /// Connections beyond a lowered limit are kept.
+    pub fn set_max_connections(&self, max: Option<usize>) {
+        self.settings.write().unwrap().max_connections = max;
+    }
+
+    /// Changes the rate limit of connections, see `Server::with_rate_limit`.
+    pub fn set_rate_limit(&self, rate: Option<u32>) {
+        self.settings.write().unwrap().rate_limit = rate;
+    }
+
+    /// Changes the idle timeout, see `Server::with_idle_timeout`.
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        self.settings.write().unwrap().idle_timeout = timeout;
+    }
+
+    /// Changes the request timeout, see `Server::with_request_timeout`.
+    pub fn set_request_timeout(&self, timeout: Option<Duration>) {
+        self.settings.write().unwrap().request_timeout = timeout;
+    }
+
+    /// Changes the slow-log threshold, see `Server::with_slow_log`,
+    /// keeping the requests already logged; `None` turns the slow log off.
+    pub fn set_slow_log(&self, threshold: Option<Duration>) {
+        let mut settings = self.settings.write().unwrap();
+        settings.slow_log = match (threshold, settings.slow_log.take()) {
+            (Some(threshold), Some(slow_log)) => {
+                slow_log.set_threshold(threshold);
+                Some(slow_log)
+            }
+            (Some(threshold), None) => Some(Arc::new(SlowLog::new(threshold))),
+            (None, _) => None,
+        };
+    }
+
+    /// Changes the compaction threshold of the engine and of its buckets.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `MyError::StringError` if the engine has no such
+    /// threshold.
+    pub fn set_compaction_threshold(&self, bytes: u64) -> Result<()> {
+        (self.compaction_threshold)(bytes)
+    }
+}
+
+impl Settings {
+    /// Whether clients must authenticate.
+    fn requires_auth(&self) -> bool {
+        self.auth_token.is_some() || self.acl.is_some()
+    }
+}
+
+impl<E: KvsEngine> Context<E> {
+    pub(crate) fn settings(&self) -> RwLockReadGuard<'_, Settings> {
+        self.settings.read().unwrap()
+    }
+
+    fn settings_mut(&mut self) -> RwLockWriteGuard<'_, Settings> {
+        self.settings.write().unwrap()
+    }
+
+    /// Registers a new connection, unless the connection limit is reached;
+    /// `stream` is handed back either way.
+    fn admit(&self, stream: Stream) -> Result<(Stream, Option<ConnectionGuard>)> {
+        let mut open = self.connections.open.lock().unwrap();
+        if let Some(max) = self.settings().max_connections {
+            if open.len() >= max {
+                return Ok((stream, None));
+            }
+        }
+        let guard = self.register(&mut open, &stream)?;
+        Ok((stream, Some(guard)))
+    }
+
+    /// Registers `stream` among the open connections, whatever the limit.
+    fn register(
+        &self,
+        open: &mut HashMap<u64, Stream>,
+        stream: &Stream,
+    ) -> Result<ConnectionGuard> {
+        let id = self.connections.next_id.fetch_add(1, Ordering::SeqCst);
+        open.insert(id, stream.try_clone()?);
+        Ok(ConnectionGuard {
+            connections: Arc::clone(&self.connections),
+            id,
+        })
+    }
+
+    /// Closes every connection once its current request is answered, then
+    /// shuts the engine down.
+    fn drain(&self) -> Result<()> {
+        info!("Shutting down");
+        self.broker.close();
+        for (_, _, _, broker) in self.buckets.lock().unwrap().values() {
+            broker.close();
+        }
+        for stream in self.connections.open.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        while !self.connections.open.lock().unwrap().is_empty() {
+            if Instant::now() >= deadline {
+                warn!("Shutting down with requests still in flight");
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.engine.lock().shutdown()?;
+        for (engine, _, _, _) in self.buckets.lock().unwrap().values() {
+            engine.lock().shutdown()?;
+        }
+        info!("Shut down cleanly");
+        Ok(())
+    }
+
+    /// Applies `batch` through Raft when clustered, else to the engine,
+    /// announcing its writes to subscribers.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if self.read_only {
+            return Err(MyError::ReadOnly);
+        }
+        #[cfg(feature = "raft")]
+        if let Some(raft) = &self.raft {
+            // checked ahead, as for single removes, so the log only holds
+            // batches that apply
+            let mut engine = self.lock_engine()?;
+            batch.check_removes(|key| Ok(engine.get(key.to_owned())?.is_some()))?;
+            drop(engine);
+            return raft.propose(Command::Batch(batch.commands));
+        }
+        let events = batch_events(&batch);
+        let mut engine = self.lock_engine()?;
+        engine.write_batch(batch)?;
+        // published under the engine lock, as for single writes
+        for event in &events {
+            self.broker.publish(event);
+        }
+        drop(engine);
+        self.sync_writes()
+    }
+
+    /// Sets the key `name` to a new token for `ttl` if it is not set,
+    /// returning the token, or `None` if another holder has the lock.
+    fn acquire_lock(&self, name: String, ttl: Duration) -> Result<Option<String>> {
+        if ttl.is_zero() {
+            return Err(MyError::StringError(
+                "A lock needs a TTL, lest it be held forever".to_owned(),
+            ));
+        }
+        let token = lock_token();
+        self.write_unreplicated(EXPIRATION_REFUSAL, |engine| {
+            let acquired =
+                engine.compare_and_swap(name.clone(), None, Some(token.clone()), Some(ttl))?;
+            if !acquired {
+                return Ok(None);
+            }
+            // published under the engine lock, as for single writes
+            self.broker.publish(&Event::Set {
+                key: name.clone(),
+                value: token.clone(),
+            });
+            self.publish_expiry(engine, name)?;
+            Ok(Some(token))
+        })
+    }
+
+    /// Removes the key `name` if it still holds `token`, returning whether
+    /// it did.
+    fn release_lock(&self, name: String, token: String) -> Result<bool> {
+        self.write_unreplicated(EXPIRATION_REFUSAL, |engine| {
+            let released = engine.compare_and_swap(name.clone(), Some(token), None, None)?;
+            if released {
+                self.broker.publish(&Event::Removed { key: name });
+            }
+            Ok(released)
+        })
+    }
+
+    /// Moves the value of `key` to `new_key` as one batch, with its
+    /// expiry.
+    fn rename(&self, key: String, new_key: String) -> Result<()> {
+        if self.read_only {
+            return Err(MyError::ReadOnly);
+        }
+        #[cfg(feature = "raft")]
+        if let Some(raft) = &self.raft {
+            let value = self
+                .lock_engine()?
+                .get(key.clone())?
+                .ok_or(MyError::KeyNotFound)?;
+            if key == new_key {
+                return Ok(());
+            }
+            let mut batch = WriteBatch::new();
+            batch.set(new_key, value);
+            batch.remove(key);
+            return raft.propose(Command::Batch(batch.commands));
+        }
+        let mut engine = self.lock_engine()?;
+        let value = engine.get(key.clone())?.ok_or(MyError::KeyNotFound)?;
+        let expires = engine.ttl(key.clone())?.is_some();
+        engine.rename(key.clone(), new_key.clone())?;
+        if key != new_key {
+            // published under the engine lock, as for single writes
+            self.broker.publish(&Event::Set {
+                key: new_key.clone(),
+                value,
+            });
+            self.broker.publish(&Event::Removed { key });
+            if expires {
+                self.publish_expiry(&mut engine, new_key)?;
+            }
+        }
+        drop(engine);
+        self.sync_writes()
+    }
+
+    /// Runs the script `module`, in base64, with the engine locked, then
+    /// applies its writes as one batch, announcing them to subscribers.
+    /// The Raft log has no command reading and writing at once, so
+    /// clusters refuse it.
+    #[cfg(feature = "scripting")]
+    fn run_script(
+        &self,
+        module: &str,
+        prefix: String,
+        args: Vec<String>,
+    ) -> Result<Option<String>> {
+        let module = STANDARD
+            .decode(module)
+            .map_err(|e| MyError::StringError(format!("Invalid script module: {}", e)))?;
+        self.write_unreplicated(SCRIPT_REFUSAL, |engine| {
+            let (batch, output) = script::run(self.reader.clone(), &module, prefix, args)?;
+            let events = batch_events(&batch);
+            engine.write_batch(batch)?;
+            // published under the engine lock, as for single writes
+            for event in &events {
+                self.broker.publish(event);
+            }
+            Ok(output)
+        })
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    fn run_script(&self, _: &str, _: String, _: Vec<String>) -> Result<Option<String>> {
+        Err(MyError::StringError(
+            "kvs-server was built without the scripting feature".to_owned(),
+        ))
+    }
+
+    /// Sets `key` to `value`, returning the value it had. The Raft log has
+    /// no command reading and writing at once, so clusters refuse it.
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        self.write_unreplicated(READ_WRITE_REFUSAL, |engine| {
+            let previous = engine.get_set(key.clone(), value.clone())?;
+            // published under the engine lock, as for single writes
+            self.broker.publish(&Event::Set { key, value });
+            Ok(previous)
+        })
+    }
+
+    /// Removes `key`, returning the value it had. Clusters refuse it, as
+    /// `get_set`.
+    fn get_del(&self, key: String) -> Result<Option<String>> {
+        self.write_unreplicated(READ_WRITE_REFUSAL, |engine| {
+            let previous = engine.get_del(key.clone())?;
+            if previous.is_some() {
+                self.broker.publish(&Event::Removed { key });
+            }
+            Ok(previous)
+        })
+    }
+
+    /// Sets `key` to `value` if it holds no value, returning whether it
+    /// did. Clusters refuse it, as `get_set`.
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        self.write_unreplicated(READ_WRITE_REFUSAL, |engine| {
+            let set = engine.set_nx(key.clone(), value.clone())?;
+            if set {
+                // published under the engine lock, as for single writes
+                self.broker.publish(&Event::Set { key, value });
+            }
+            Ok(set)
+        })
+    }
+
+    /// Sets `key` to `value` if it is at `version`, returning its new
+    /// version. Clusters refuse it, as `get_set`.
+    fn set_if_version(&self, key: String, version: u64, value: String) -> Result<u64> {
+        self.write_unreplicated(SET_IF_VERSION_REFUSAL, |engine| {
+            let version = engine.set_if_version(key.clone(), version, value.clone())?;
+            // published under the engine lock, as for single writes
+            self.broker.publish(&Event::Set { key, value });
+            Ok(version)
+        })
+    }
+
+    /// Removes every key of the bucket, announcing the removal of each to
+    /// subscribers and replicas. The Raft log has no command for it, so
+    /// clusters refuse it.
+    fn flush_all(&self, confirm: bool) -> Result<()> {
+        if !confirm {
+            return Err(MyError::StringError(
+                "FlushAll removes every key and must be confirmed".to_owned(),
+            ));
+        }
+        self.write_unreplicated("FlushAll is not available in Raft mode", |engine| {
+            let keys = engine.keys("*".to_owned())?;
+            engine.clear()?;
+            // published under the engine lock, as for single writes
+            for key in keys {
+                self.broker.publish(&Event::Removed { key });
+            }
+            Ok(())
+        })
+    }
+
+    /// Brings `key` back from the trash, announcing its value to
+    /// subscribers. Clusters refuse it.
+    fn restore(&self, key: String) -> Result<()> {
+        self.write_unreplicated(TRASH_REFUSAL, |engine| {
+            engine.restore(key.clone())?;
+            if let Some(value) = engine.get(key.clone())? {
+                // published under the engine lock, as for single writes
+                self.broker.publish(&Event::Set { key, value });
+            }
+            Ok(())
+        })
+    }
+
+    /// Applies `write` to a list, a hash or a set, and announces the change
+    /// it returns, if any, to subscribers and replicas. The Raft log has no
+    /// commands for them, so clusters refuse them.
+    fn write_collection<T>(
+        &self,
+        write: impl FnOnce(&mut E) -> Result<(T, Option<Event>)>,
+    ) -> Result<T> {
+        self.write_unreplicated(
+            "Lists, hashes and sets are not available in Raft mode",
+            |engine| {
+                let (result, event) = write(engine)?;
+                // published under the engine lock, as for single writes
+                if let Some(event) = event {
+                    self.broker.publish(&event);
+                }
+                Ok(result)
+            },
+        )
+    }
+
+    /// Announces when `key` expires now, as `engine` has it, to
+    /// subscribers and replicas; nothing if the key is gone.
+    fn publish_expiry(&self, engine: &mut E, key: String) -> Result<()> {
+        let expires_at_ms = match engine.ttl(key.clone()) {
+            Ok(ttl) => ttl.map(|ttl| unix_millis().saturating_add(ttl.as_millis() as u64)),
+            Err(MyError::KeyNotFound) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        self.broker.publish(&Event::Expiry { key, expires_at_ms });
+        Ok(())
+    }
+
+    /// Applies `write`, which clusters cannot replicate and refuse with
+    /// `refusal`.
+    fn write_unreplicated<T>(
+        &self,
+        refusal: &str,
+        write: impl FnOnce(&mut E) -> Result<T>,
+    ) -> Result<T> {
+        if self.read_only {
+            return Err(MyError::ReadOnly);
+        }
+        if self.is_clustered() {
+            return Err(MyError::StringError(refusal.to_owned()));
+        }
+        let result = write(&mut *self.lock_engine()?)?;
+        self.sync_writes()?;
+        Ok(result)
+    }
+
+    /// Fails unless this node may serve a read as `consistency` asks, once
+    /// a Raft leader committed a no-op for linearizable ones.
+    fn check_read(&self, consistency: ReadConsistency) -> Result<()> {
+        if consistency.is_local() {
+            return Ok(());
+        }
+        if self.replica {
+            return Err(MyError::StringError(format!(
+                "A replica cannot read with `{}` consistency, its leader can",
+                consistency
+            )));
+        }
+        #[cfg(feature = "raft")]
+        if let Some(raft) = &self.raft {
+            return match consistency {
+                ReadConsistency::Linearizable => raft.read_barrier(),
+                _ => raft.check_leader(),
+            };
+        }
+        Ok(())
+    }
+
+    /// Whether writes go through a Raft cluster.
+    fn is_clustered(&self) -> bool {
+        #[cfg(feature = "raft")]
+        return self.raft.is_some();
+        #[cfg(not(feature = "raft"))]
+        false
+    }
+
+    /// Removes a slice of the expired keys of the store and of every open
+    /// bucket, announcing them to subscribers.
+    fn sweep_expired(&self) {
+        let mut stores = vec![(
+            Arc::clone(&self.engine),
+            self.commit.clone(),
+            Arc::clone(&self.broker),
+        )];
+        for (engine, _, commit, broker) in self.buckets.lock().unwrap().values() {
+            stores.push((Arc::clone(engine), commit.clone(), Arc::clone(broker)));
+        }
+        for (engine, commit, broker) in stores {
+            let mut engine = engine.lock();
+            let result = engine.sweep_expired(SWEEP_KEYS).and_then(|expired| {
+                // published under the engine lock, as for other writes
+                for key in expired {
+                    broker.publish(&Event::Removed { key });
+                }
+                drop(engine);
+                commit.as_ref().map_or(Ok(()), |commit| commit.sync())
+            });
+            if let Err(e) = result {
+                warn!("Sweeping expired keys failed: {}", e);
+            }
+        }
+    }
+
+    /// Returns once the writes made so far are on disk, if the engine left
+    /// syncing them to the server.
+    ///
+    /// Then waits for the replicas the consistency level asks for, unless
+    /// this server is a replica applying its leader's writes.
+    pub(crate) fn sync_writes(&self) -> Result<()> {
+        if let Some(commit) = &self.commit {
+            commit.sync()?;
+        }
+        if self.read_only || self.consistency == Consistency::Async {
+            return Ok(());
+        }
+        let timeout = self.settings().request_timeout;
+        let timeout = timeout.unwrap_or(REPLICATION_TIMEOUT);
+        self.replicas
+            .wait(self.consistency, self.broker.published(), timeout)
     }
 
-    pub fn open<A: ToSocketAddrs>(mut self, addr: A) -> Result<()> {
-        // accept connections and process them serially
-        let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    self.handle_connections(stream)?;
-                }
-                Err(e) => error!("Connection failed {}", e),
+    /// This context with the engine and subscribers of bucket `db` in place
+    /// of the default ones, opening the bucket on first use.
+    fn select(&self, db: &str) -> Result<Context<E>> {
+        if db == DEFAULT_BUCKET {
+            return Ok(self.clone());
+        }
+        if let Some(tenant) = &self.tenant {
+            return Err(MyError::StringError(format!(
+                "Tenant `{}` has no buckets",
+                tenant.name()
+            )));
+        }
+        // the stores of tenants are opened under names no bucket can have
+        check_bucket_name(db)?;
+        self.open_store(db)
+    }
+
+    /// This context with the store of `tenant`, if the connection may use
+    /// it with `access`.
+    fn enter_tenant(&self, name: &str, access: &Option<Access>) -> Result<Context<E>> {
+        if let Some(Access::Restricted(rule)) = access {
+            if rule.tenant().is_some_and(|tenant| tenant != name) {
+                return Err(MyError::PermissionDenied);
             }
         }
+        let tenant = self
+            .tenants
+            .as_ref()
+            .and_then(|tenants| tenants.get(name))
+            .ok_or_else(|| MyError::StringError(format!("Unknown tenant `{}`", name)))?;
+        let mut context = self.open_store(&tenant.store())?;
+        context.latencies = Arc::clone(&tenant.latencies);
+        context.bucket = DEFAULT_BUCKET.to_owned();
+        context.tenant = Some(tenant);
+        Ok(context)
+    }
+
+    /// Refuses `req` if it adds data to a tenant at one of its quotas.
+    /// Returns whether `req` should go on.
+    fn check_quota<W: Write>(&self, req: &Request, writer: &mut MessageWriter<W>) -> Result<bool> {
+        let tenant = match &self.tenant {
+            Some(tenant) if tenant.limits(req) => tenant,
+            _ => return Ok(true),
+        };
+        let checked = self
+            .lock_engine()
+            .and_then(|mut engine| engine.stats())
+            .and_then(|stats| tenant.check_quota(&stats));
+        if let Err(e) = checked {
+            let error = writer.error(&e);
+            writer.send(&ErrorResponse::Err(error))?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// This context with the store opened under bucket name `db`.
+    fn open_store(&self, db: &str) -> Result<Context<E>> {
+        // only the default store is replicated
+        if self.replica {
+            return Err(MyError::StringError(
+                "Buckets are not available on a replica".to_owned(),
+            ));
+        }
+        #[cfg(feature = "raft")]
+        if self.raft.is_some() {
+            return Err(MyError::StringError(
+                "Buckets are not available in Raft mode".to_owned(),
+            ));
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        let (engine, reader, commit, broker) = match buckets.get(db) {
+            Some(bucket) => bucket.clone(),
+            None => {
+                let mut engine = self.lock_engine()?.open_bucket(db)?;
+                info!("Opened bucket {}", db);
+                let reader = engine.reader();
+                let commit = engine.defer_syncs()?;
+                let bucket = (
+                    Arc::new(EngineLock::new(engine)),
+                    reader,
+                    commit,
+                    Arc::new(Broker::default()),
+                );
+                buckets.insert(db.to_owned(), bucket.clone());
+                bucket
+            }
+        };
+        let mut context = self.clone();
+        context.engine = engine;
+        context.reader = reader;
+        context.commit = commit;
+        context.broker = broker;
+        context.replicas = Arc::new(Replicas::default());
+        context.consistency = Consistency::Async;
+        context.snapshots = None;
+        context.bucket = db.to_owned();
+        Ok(context)
+    }
+
+    /// Answers `Select`, pointing `context` at bucket `db`.
+    fn switch_bucket<W: Write>(
+        &self,
+        db: &str,
+        context: &mut Context<E>,
+        writer: &mut MessageWriter<W>,
+    ) -> Result<()> {
+        let response = match self.select(db) {
+            Ok(selected) => {
+                *context = selected;
+                SelectResponse::Ok(())
+            }
+            Err(err) => SelectResponse::Err(writer.error(&err)),
+        };
+        writer.send(&response)?;
+        info!("Response sent: {:?}", response);
         Ok(())
     }
 
-    pub fn handle_connections(&mut self, stream: TcpStream) -> Result<()> {
-        let peer_addr = stream.peer_addr()?;
+    /// Locks the engine, giving up once the request timeout elapses. The
+    /// wait counts as queueing time of the request.
+    fn lock_engine(&self) -> Result<MutexGuard<'_, E>> {
+        let timeout = self.settings().request_timeout;
+        let started = Instant::now();
+        let engine = match timeout {
+            Some(timeout) => self.engine.try_lock_for(timeout),
+            None => Some(self.engine.lock()),
+        };
+        latency::add_queue_time(started.elapsed());
+        engine.ok_or(MyError::Timeout)
+    }
+
+    /// Serves a client of a TCP listener, over TLS if the server has a
+    /// certificate.
+    fn handle_tcp_connection(&self, stream: Stream) -> Result<()> {
+        let stream = match (&self.tls, stream) {
+            (Some(tls), Stream::Tcp(tcp)) => {
+                // a client that never finishes the handshake is idle
+                tcp.set_read_timeout(self.settings().idle_timeout)?;
+                Stream::Tls(tls.accept(tcp)?)
+            }
+            (_, stream) => stream,
+        };
+        self.handle_connection(stream)
+    }
+
+    /// Looks at up to `count` keys past `cursor` starting with the literal
+    /// prefix of `glob`, and returns those matching it.
+    ///
+    /// The cursor is the last key looked at, so it stays valid across
+    /// compactions, which rewrite the log but not the keys: every key
+    /// present for the whole scan is returned once.
+    fn scan_page(&self, glob: &Glob, cursor: Option<String>, count: usize) -> Result<ScanPage> {
+        let walked = self
+            .lock_engine()?
+            .keys_after(glob.prefix().to_owned(), cursor, count)?;
+        let cursor = if walked.len() == count {
+            walked.last().cloned()
+        } else {
+            None
+        };
+        Ok(ScanPage {
+            keys: walked.into_iter().filter(|key| glob.matches(key)).collect(),
+            cursor,
+        })
+    }
+
+    fn handle_connection(&self, stream: Stream) -> Result<()> {
+        let peer_addr = stream.peer()?;
+        let span = info_span!("connection", peer = %peer_addr);
+        let _connection = span.enter();
         info!(
-            "Connection established from {}, waiting for data..., {}",
-            stream.peer_addr()?,
-            stream.local_addr()?
+            "Connection established from {}, waiting for data...",
+            peer_addr
         );
+        let settings = self.settings();
+        stream.set_read_timeout(settings.idle_timeout)?;
+        stream.set_write_timeout(settings.request_timeout)?;
+        let limiter = settings.rate_limit.map(RateLimiter::new);
+        drop(settings);
 
-        let reader = BufReader::new(&stream);
-        let mut bufwriter = BufWriter::new(&stream);
-        let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+        let mut reader = MessageReader::new(BufReader::new(&stream));
+        reader.set_max_len(self.max_message_len);
+        let mut writer = MessageWriter::new(BufWriter::new(&stream));
 
-        //let mut kvs = KvStore::open(current_dir()?)?;
+        // the bucket selected last; `self` keeps the default one
+        let mut context = self.clone();
+        let mut access = None;
+        loop {
+            match reader.wait() {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) if is_timeout(&e) => {
+                    info!("Closing idle connection from {}", peer_addr);
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            }
+            // decoding errors answer no request
+            writer.set_request_id(None);
+            let (req, request_id) = match reader.receive::<Request>() {
+                Ok(req) => req.untagged(),
+                Err(e @ MyError::MessageTooLarge(_)) => {
+                    // the payload is left unread, so the connection cannot go on
+                    let error = writer.error(&e);
+                    writer.send(&ErrorResponse::Err(error))?;
+                    writer.flush()?;
+                    return Err(e);
+                }
+                Err(e @ MyError::DeserializeError(_)) | Err(e @ MyError::MessagePackDecode(_))
+                    if reader.is_framed() =>
+                {
+                    let error = writer.error(&invalid_request(e));
+                    writer.send(&ErrorResponse::Err(error))?;
+                    writer.flush()?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let (req, trace) = req.untraced();
+            writer.set_request_id(request_id);
+            // until the next request is read
+            let span = info_span!("request", kind = req.kind(), id = writer.request_id());
+            let _request = span.enter();
+            if !self.check_listener(&req, &mut writer)?
+                || !self.check_rate(&req, limiter.as_ref(), &access, &mut writer)?
+                || !self.check_access(&req, &mut access, &mut context, &mut writer)?
+                || !context.check_quota(&req, &mut writer)?
+            {
+                writer.flush()?;
+                continue;
+            }
+            info!(
+                "Receive request{} from {}: {:?}",
+                RequestId(writer.request_id()),
+                peer_addr,
+                req
+            );
+            let (kind, started) = (req.kind(), Instant::now());
+            // ended once the response is sent
+            #[cfg(feature = "otel")]
+            let mut span = otel::ServerSpan::start(kind, trace, &peer_addr);
+            #[cfg(not(feature = "otel"))]
+            drop(trace);
+            let audit = context.audit(&req, &access, &peer_addr);
+            // errors of the requests refused above are not this one's
+            writer.take_error();
+            match req {
+                Request::Hello {
+                    version,
+                    codecs,
+                    compression,
+                    tenant,
+                } => {
+                    let response = negotiate_hello(
+                        &mut reader,
+                        &mut writer,
+                        &codecs,
+                        &compression,
+                        |codec, compression| {
+                            self.greet(version, codec, compression, tenant, &access, &mut context)
+                        },
+                    )?;
+                    info!("Response sent: {:?}", response);
+                }
+                Request::Select { db } => {
+                    self.home(&context)
+                        .switch_bucket(&db, &mut context, &mut writer)?
+                }
+                Request::Subscribe { prefix } => {
+                    return context.stream_events(prefix, &mut writer);
+                }
+                Request::Sync => {
+                    return context.stream_sync(&stream, &mut reader, &mut writer, None);
+                }
+                Request::SyncFrom { from } => {
+                    return context.stream_sync(&stream, &mut reader, &mut writer, Some(from));
+                }
+                Request::Watch { key, timeout_ms } => {
+                    context.watch_key(key, Duration::from_millis(timeout_ms), &mut writer)?
+                }
+                Request::Export { pattern } => context.stream_export(&pattern, &mut writer)?,
+                req => context.handle_request(req, &mut writer)?,
+            }
+            let error = writer.take_error();
+            // on record before the client hears of the write
+            if let Some(audit) = audit {
+                audit.finish(error.clone());
+            }
+            writer.flush()?;
+            info!(
+                "{} handled in {:?}{}",
+                kind,
+                started.elapsed(),
+                RequestId(writer.request_id())
+            );
+            #[cfg(feature = "otel")]
+            if let Some(error) = error {
+                span.set_error(error);
+            }
+            // the engine is likely to fail the next requests as well, and
+            // clients with other endpoints move on to them once dropped
+            if writer.sent_fatal() {
+                error!(
+                    "Closing connection from {} after a fatal engine error",
+                    peer_addr
+                );
+                return Ok(());
+            }
+        }
 
-        for req in req_reader {
-            info!("Receive request from {}: {:?}", peer_addr, req);
+        Ok(())
+    }
 
-            match req? {
-                Request::Get { key } => {
-                    let response = match self.engine.get(key) {
-                        Ok(value) => GetResponse::Ok(value),
-                        Err(err) => GetResponse::Err(err.to_string()),
+    /// Executes `req` against the engine and serializes the matching
+    /// response to `writer`.
+    fn handle_request<W: Write>(&self, req: Request, writer: &mut MessageWriter<W>) -> Result<()> {
+        let started = Instant::now();
+        latency::take_queue_time();
+        let key_len = req.keys().iter().map(|key| key.len()).sum();
+        let kind = req.kind();
+        match req {
+            // taken off on receipt, except over WebSocket
+            Request::Traced { request, .. } => return self.handle_request(*request, writer),
+            // taken off on receipt, except within a `Traced` over WebSocket
+            Request::Tagged {
+                request_id,
+                request,
+            } => {
+                writer.set_request_id(Some(request_id));
+                return self.handle_request(*request, writer);
+            }
+            // only reached within a `Traced` over WebSocket, which cannot
+            // choose a tenant
+            Request::Hello { version, .. } => {
+                let response = self.hello(version, Codec::Json, Compression::None);
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Get { key, consistency } => {
+                // no need to wait for writes, or a compaction, in progress
+                let value = self
+                    .check_read(consistency)
+                    .and_then(|()| self.reader.get_bytes(key));
+                let response = match value {
+                    Ok(value) => GetResponse::Ok(value.map(StrBytes)),
+                    Err(err) => GetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::GetMany { keys } => {
+                // under one lock, so that the values are consistent
+                let values = self.lock_engine().and_then(|mut engine| {
+                    keys.into_iter()
+                        .map(|key| engine.get(key))
+                        .collect::<Result<Vec<_>>>()
+                });
+                let response = match values {
+                    Ok(values) => GetManyResponse::Ok(values),
+                    Err(err) => GetManyResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Set { .. } if self.read_only => {
+                let response = SetResponse::Err(writer.error(&MyError::ReadOnly));
+                writer.send(&response)?;
+            }
+            Request::Remove { .. } if self.read_only => {
+                let response = RemoveResponse::Err(writer.error(&MyError::ReadOnly));
+                writer.send(&response)?;
+            }
+            Request::SetPath { .. } if self.read_only => {
+                let response = SetResponse::Err(writer.error(&MyError::ReadOnly));
+                writer.send(&response)?;
+            }
+            Request::GetPath { key, path } => {
+                let part =
+                    path.parse::<JsonPath>()
+                        .and_then(|path| match self.reader.get(key)? {
+                            Some(doc) => path.read(&doc),
+                            None => Ok(None),
+                        });
+                let response = match part {
+                    Ok(part) => GetResponse::Ok(part),
+                    Err(err) => GetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            #[cfg(feature = "raft")]
+            Request::SetPath { key, path, value } if self.raft.is_some() => {
+                let raft = self.raft.as_ref().unwrap();
+                let patched = path.parse::<JsonPath>().and_then(|path| {
+                    let doc = self
+                        .lock_engine()?
+                        .get(key.clone())?
+                        .ok_or(MyError::KeyNotFound)?;
+                    path.patch(&doc, &value)
+                });
+                let response =
+                    match patched.and_then(|doc| raft.propose(Command::Set { key, value: doc })) {
+                        Ok(()) => SetResponse::Ok(()),
+                        Err(err) => SetResponse::Err(writer.error(&err)),
                     };
-                    serde_json::to_writer(&mut bufwriter, &response)?;
-                    bufwriter.flush()?;
-                    info!("Response sent to {:?}: {:?}", peer_addr, response);
-                }
-                Request::Set { key, value } => {
-                    let response = match self.engine.set(key, value) {
-                        Ok(_value) => SetResponse::Ok(()),
-                        Err(err) => SetResponse::Err(err.to_string()),
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            #[cfg(feature = "raft")]
+            Request::Set { key, value, ttl_ms } if self.raft.is_some() => {
+                let raft = self.raft.as_ref().unwrap();
+                let proposed = match ttl_ms {
+                    Some(_) => Err(MyError::StringError(EXPIRATION_REFUSAL.to_owned())),
+                    None => raft.propose(Command::Set { key, value }),
+                };
+                let response = match proposed {
+                    Ok(()) => SetResponse::Ok(()),
+                    Err(err) => SetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            #[cfg(feature = "raft")]
+            Request::Remove { key } if self.raft.is_some() => {
+                let raft = self.raft.as_ref().unwrap();
+                let exists = self
+                    .lock_engine()
+                    .and_then(|mut engine| engine.get(key.clone()));
+                let response = match exists.and_then(|value| match value {
+                    Some(_) => raft.propose(Command::Remove { key }),
+                    None => Err(MyError::KeyNotFound),
+                }) {
+                    Ok(()) => RemoveResponse::Ok(()),
+                    Err(err) => RemoveResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Set { key, value, ttl_ms } => {
+                let event = Event::Set {
+                    key: key.clone(),
+                    value: value.clone(),
+                };
+                // publish under the engine lock so subscribers see changes in
+                // the order they were applied
+                let result = self
+                    .lock_engine()
+                    .and_then(|mut engine| {
+                        match ttl_ms {
+                            Some(ms) => {
+                                engine.set_with_ttl(
+                                    key.clone(),
+                                    value,
+                                    Duration::from_millis(ms),
+                                )?;
+                                self.broker.publish(&event);
+                                self.publish_expiry(&mut engine, key)?;
+                            }
+                            None => {
+                                engine.set(key, value)?;
+                                self.broker.publish(&event);
+                            }
+                        }
+                        Ok(())
+                    })
+                    .and_then(|()| self.sync_writes());
+                let response = match result {
+                    Ok(()) => SetResponse::Ok(()),
+                    Err(err) => SetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::SetPath { key, path, value } => {
+                let result = self
+                    .lock_engine()
+                    .and_then(|mut engine| {
+                        engine.set_path(key.clone(), &path, value)?;
+                        let value = engine.get(key.clone())?.unwrap_or_default();
+                        self.broker.publish(&Event::Set { key, value });
+                        Ok(())
+                    })
+                    .and_then(|()| self.sync_writes());
+                let response = match result {
+                    Ok(()) => SetResponse::Ok(()),
+                    Err(err) => SetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Remove { key } => {
+                let event = Event::Removed { key: key.clone() };
+                let result = self
+                    .lock_engine()
+                    .and_then(|mut engine| {
+                        engine.remove(key)?;
+                        self.broker.publish(&event);
+                        Ok(())
+                    })
+                    .and_then(|()| self.sync_writes());
+                let response = match result {
+                    Ok(()) => RemoveResponse::Ok(()),
+                    Err(err) => RemoveResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::LPush { key, value } => {
+                let result = self.write_collection(|engine| {
+                    let len = engine.lpush(key.clone(), value.clone())?;
+                    let event = Event::Pushed {
+                        key,
+                        value,
+                        front: true,
                     };
-                    serde_json::to_writer(&mut bufwriter, &response)?;
-                    bufwriter.flush()?;
-                    info!("Response sent to {:?}: {:?}", peer_addr, response);
-                }
-                Request::Remove { key } => {
-                    let response = match self.engine.remove(key) {
-                        Ok(_value) => RemoveResponse::Ok(()),
-                        Err(err) => RemoveResponse::Err(err.to_string()),
+                    Ok((len, Some(event)))
+                });
+                let response = match result {
+                    Ok(len) => PushResponse::Ok(len),
+                    Err(err) => PushResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::RPush { key, value } => {
+                let result = self.write_collection(|engine| {
+                    let len = engine.rpush(key.clone(), value.clone())?;
+                    let event = Event::Pushed {
+                        key,
+                        value,
+                        front: false,
                     };
-                    serde_json::to_writer(&mut bufwriter, &response)?;
-                    bufwriter.flush()?;
-                    info!("Response sent to {:?}: {:?}", peer_addr, response);
+                    Ok((len, Some(event)))
+                });
+                let response = match result {
+                    Ok(len) => PushResponse::Ok(len),
+                    Err(err) => PushResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::LPop { key } => {
+                let result = self.write_collection(|engine| {
+                    let value = engine.lpop(key.clone())?;
+                    let event = value.as_ref().map(|_| Event::Popped { key });
+                    Ok((value, event))
+                });
+                let response = match result {
+                    Ok(value) => GetResponse::Ok(value),
+                    Err(err) => GetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::LRange { key, start, stop } => {
+                let values = self
+                    .lock_engine()
+                    .and_then(|mut engine| engine.lrange(key, start, stop));
+                let response = match values {
+                    Ok(values) => RangeResponse::Ok(values),
+                    Err(err) => RangeResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::HSet { key, field, value } => {
+                let result = self.write_collection(|engine| {
+                    engine.hset(key.clone(), field.clone(), value.clone())?;
+                    Ok(((), Some(Event::FieldSet { key, field, value })))
+                });
+                let response = match result {
+                    Ok(()) => SetResponse::Ok(()),
+                    Err(err) => SetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::HGet { key, field } => {
+                let value = self
+                    .lock_engine()
+                    .and_then(|mut engine| engine.hget(key, field));
+                let response = match value {
+                    Ok(value) => GetResponse::Ok(value),
+                    Err(err) => GetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::HDel { key, field } => {
+                let result = self.write_collection(|engine| {
+                    engine.hdel(key.clone(), field.clone())?;
+                    Ok(((), Some(Event::FieldRemoved { key, field })))
+                });
+                let response = match result {
+                    Ok(()) => RemoveResponse::Ok(()),
+                    Err(err) => RemoveResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::HGetAll { key } => {
+                let fields = self
+                    .lock_engine()
+                    .and_then(|mut engine| engine.hgetall(key));
+                let response = match fields {
+                    Ok(fields) => HGetAllResponse::Ok(fields),
+                    Err(err) => HGetAllResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::SAdd { key, member } => {
+                let result = self.write_collection(|engine| {
+                    let added = engine.sadd(key.clone(), member.clone())?;
+                    Ok((
+                        added,
+                        Some(Event::MemberAdded { key, member }).filter(|_| added),
+                    ))
+                });
+                let response = match result {
+                    Ok(added) => MemberResponse::Ok(added),
+                    Err(err) => MemberResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::SRem { key, member } => {
+                let result = self.write_collection(|engine| {
+                    let removed = engine.srem(key.clone(), member.clone())?;
+                    let event = Event::MemberRemoved { key, member };
+                    Ok((removed, Some(event).filter(|_| removed)))
+                });
+                let response = match result {
+                    Ok(removed) => MemberResponse::Ok(removed),
+                    Err(err) => MemberResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::SIsMember { key, member } => {
+                let found = self
+                    .lock_engine()
+                    .and_then(|mut engine| engine.sismember(key, member));
+                let response = match found {
+                    Ok(found) => MemberResponse::Ok(found),
+                    Err(err) => MemberResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::SMembers { key } => {
+                let members = self
+                    .lock_engine()
+                    .and_then(|mut engine| engine.smembers(key));
+                let response = match members {
+                    Ok(members) => MembersResponse::Ok(members),
+                    Err(err) => MembersResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::ZAdd { key, member, score } => {
+                // a new score of a member is a change too
+                let added = self.write_collection(|engine| {
+                    let added = engine.zadd(key.clone(), member.clone(), score)?;
+                    Ok((added, Some(Event::Scored { key, member, score })))
+                });
+                let response = match added {
+                    Ok(added) => MemberResponse::Ok(added),
+                    Err(err) => MemberResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::ZRangeByScore { key, min, max } => {
+                let members = self
+                    .lock_engine()
+                    .and_then(|mut engine| engine.zrange_by_score(key, min, max));
+                let response = match members {
+                    Ok(members) => ScoresResponse::Ok(members),
+                    Err(err) => ScoresResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Exists { key } => {
+                let exists = self
+                    .lock_engine()
+                    .and_then(|mut engine| engine.contains_key(key));
+                let response = match exists {
+                    Ok(exists) => ExistsResponse::Ok(exists),
+                    Err(err) => ExistsResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::DbSize => {
+                let response = match self.lock_engine().and_then(|mut engine| engine.len()) {
+                    Ok(len) => DbSizeResponse::Ok(len),
+                    Err(err) => DbSizeResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Keys { pattern } => {
+                let keys = self
+                    .lock_engine()
+                    .and_then(|mut engine| engine.keys(pattern));
+                let response = match keys {
+                    Ok(keys) => FindResponse::Ok(keys),
+                    Err(err) => FindResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Scan {
+                cursor,
+                count,
+                pattern,
+                consistency,
+            } => {
+                let count = (count as usize).clamp(1, MAX_SCAN_COUNT);
+                let page = self
+                    .check_read(consistency)
+                    .and_then(|()| pattern.parse())
+                    .and_then(|glob| self.scan_page(&glob, cursor, count));
+                let response = match page {
+                    Ok(page) => ScanResponse::Ok(page),
+                    Err(err) => ScanResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Ttl { key } => {
+                let ttl = self.lock_engine().and_then(|mut engine| engine.ttl(key));
+                let response = match ttl {
+                    Ok(ttl) => TtlResponse::Ok(ttl.map(|ttl| ttl.as_millis() as u64)),
+                    Err(err) => TtlResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Metadata { key } => {
+                let meta = self
+                    .lock_engine()
+                    .and_then(|mut engine| engine.metadata(key));
+                let response = match meta {
+                    Ok(meta) => MetadataResponse::Ok(meta),
+                    Err(err) => MetadataResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Expire { key, ttl_ms } => {
+                let result = self.write_unreplicated(EXPIRATION_REFUSAL, |engine| {
+                    engine.expire(key.clone(), Duration::from_millis(ttl_ms))?;
+                    self.publish_expiry(engine, key)
+                });
+                let response = match result {
+                    Ok(()) => SetResponse::Ok(()),
+                    Err(err) => SetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Persist { key } => {
+                let result = self.write_unreplicated(EXPIRATION_REFUSAL, |engine| {
+                    engine.persist(key.clone())?;
+                    self.publish_expiry(engine, key)
+                });
+                let response = match result {
+                    Ok(()) => SetResponse::Ok(()),
+                    Err(err) => SetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Restore { key } => {
+                let response = match self.restore(key) {
+                    Ok(()) => SetResponse::Ok(()),
+                    Err(err) => SetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Touch { keys, ttl_ms } => {
+                let result = self.write_unreplicated(EXPIRATION_REFUSAL, |engine| {
+                    let touched = engine.touch(keys.clone(), Duration::from_millis(ttl_ms))?;
+                    for key in keys {
+                        self.publish_expiry(engine, key)?;
+                    }
+                    Ok(touched)
+                });
+                let response = match result {
+                    Ok(touched) => CountResponse::Ok(touched),
+                    Err(err) => CountResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::AcquireLock { name, ttl_ms } => {
+                let response = match self.acquire_lock(name, Duration::from_millis(ttl_ms)) {
+                    Ok(token) => LockResponse::Ok(token),
+                    Err(err) => LockResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::ReleaseLock { name, token } => {
+                let response = match self.release_lock(name, token) {
+                    Ok(released) => MemberResponse::Ok(released),
+                    Err(err) => MemberResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Rename { key, new_key } => {
+                let response = match self.rename(key, new_key) {
+                    Ok(()) => SetResponse::Ok(()),
+                    Err(err) => SetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::RunScript {
+                module,
+                prefix,
+                args,
+            } => {
+                let response = match self.run_script(&module, prefix, args) {
+                    Ok(output) => GetResponse::Ok(output),
+                    Err(err) => GetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::GetSet { key, value } => {
+                let response = match self.get_set(key, value) {
+                    Ok(previous) => GetResponse::Ok(previous),
+                    Err(err) => GetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::GetDel { key } => {
+                let response = match self.get_del(key) {
+                    Ok(previous) => GetResponse::Ok(previous),
+                    Err(err) => GetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::SetNx { key, value } => {
+                let response = match self.set_nx(key, value) {
+                    Ok(set) => MemberResponse::Ok(set),
+                    Err(err) => MemberResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::SetIfVersion {
+                key,
+                version,
+                value,
+            } => {
+                let response = match self.set_if_version(key, version, value) {
+                    Ok(version) => VersionResponse::Ok(version),
+                    Err(err) => VersionResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::SetMany { pairs } => {
+                let mut batch = WriteBatch::new();
+                for (key, value) in pairs {
+                    batch.set(key, value);
+                }
+                let response = match self.write_batch(batch) {
+                    Ok(()) => SetResponse::Ok(()),
+                    Err(err) => SetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::RemoveMany { keys } => {
+                let mut batch = WriteBatch::new();
+                for key in keys {
+                    batch.remove(key);
+                }
+                let response = match self.write_batch(batch) {
+                    Ok(()) => RemoveResponse::Ok(()),
+                    Err(err) => RemoveResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Subscribe { .. } => {
+                let response = SubscribeResponse::Err(writer.error(&MyError::StringError(
+                    "Subscriptions are not available on this transport".to_owned(),
+                )));
+                writer.send(&response)?;
+            }
+            Request::Watch { .. } => {
+                let response = WatchResponse::Err(writer.error(&MyError::StringError(
+                    "Watches are not available on this transport".to_owned(),
+                )));
+                writer.send(&response)?;
+            }
+            Request::Export { .. } => {
+                let response = ExportResponse::Err(writer.error(&MyError::StringError(
+                    "Exports are not available on this transport".to_owned(),
+                )));
+                writer.send(&response)?;
+            }
+            Request::Select { .. } => {
+                unreachable!("connections switch buckets before dispatch")
+            }
+            Request::Auth { .. } => {
+                // answered by `check_access` before dispatch
+                writer.send(&AuthResponse::Ok(()))?;
+            }
+            Request::Stats => {
+                let response = match self.lock_engine().and_then(|mut engine| engine.stats()) {
+                    Ok(mut stats) => {
+                        if let Some(snapshots) = &self.snapshots {
+                            snapshots.report(&mut stats);
+                        }
+                        stats.latency = self.latencies.report();
+                        StatsResponse::Ok(stats)
+                    }
+                    Err(err) => StatsResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::FlushAll { confirm } => {
+                let response = match self.flush_all(confirm) {
+                    Ok(()) => SetResponse::Ok(()),
+                    Err(err) => SetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::PurgeTrash => {
+                let result = self.write_unreplicated(TRASH_REFUSAL, |engine| engine.purge());
+                let response = match result {
+                    Ok(purged) => CountResponse::Ok(purged),
+                    Err(err) => CountResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Compact => {
+                let compacted = self
+                    .lock_engine()
+                    .and_then(|mut engine| engine.compact_now());
+                let response = match compacted {
+                    Ok(()) => SetResponse::Ok(()),
+                    Err(err) => SetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::ReloadConfig => {
+                let reloaded = match &self.reload_config {
+                    Some(reload) => reload(),
+                    None => Err(MyError::StringError(
+                        "The server has no configuration to reload".to_owned(),
+                    )),
+                };
+                let response = match reloaded {
+                    Ok(()) => SetResponse::Ok(()),
+                    Err(err) => SetResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Shutdown => {
+                warn!("Shutdown requested");
+                let response = SetResponse::Ok(());
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+                // in-flight requests, this one's response included, are
+                // let finish
+                self.shutdown.shutdown();
+            }
+            Request::Drain => {
+                let response = SetResponse::Ok(());
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+                self.shutdown.lame_duck();
+            }
+            Request::FindByIndex { path, value } => {
+                let found = self
+                    .lock_engine()
+                    .and_then(|mut engine| engine.find_by_index(&path, &value));
+                let response = match found {
+                    Ok(keys) => FindResponse::Ok(keys),
+                    Err(err) => FindResponse::Err(writer.error(&err)),
+                };
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::Ping => {
+                let response = PingResponse::Ok(Pong {
+                    version: env!("CARGO_PKG_VERSION").to_owned(),
+                    uptime_secs: self.started.elapsed().as_secs(),
+                    engine: self.engine_name.to_owned(),
+                    state: self.shutdown.state(),
+                });
+                writer.send(&response)?;
+                info!("Response sent: {:?}", response);
+            }
+            Request::SlowLog => {
+                let recent = self.settings().slow_log.as_ref().map(|log| log.recent());
+                let response = SlowLogResponse::Ok(recent.unwrap_or_default());
+                writer.send(&response)?;
+            }
+            Request::Sync | Request::SyncFrom { .. } => {
+                let response = SyncResponse::Err(writer.error(&MyError::StringError(
+                    "Replication is not available on this transport".to_owned(),
+                )));
+                writer.send(&response)?;
+            }
+        };
+        let elapsed = started.elapsed();
+        self.latencies
+            .record(kind, elapsed, latency::take_queue_time());
+        let slow_log = self.settings().slow_log.clone();
+        if let Some(slow_log) = slow_log {
+            slow_log.record(kind, key_len, elapsed, writer.request_id());
+        }
+        Ok(())
+    }
+
+    /// Answers `req` as a connection authenticated with `token` would, and
+    /// returns the response, for the gRPC service.
+    #[cfg(feature = "grpc")]
+    fn answer(&self, req: Request, token: Option<&str>, peer: &str) -> Result<Vec<u8>> {
+        let (mut context, mut access) = (self.clone(), None);
+        if let Some(token) = token {
+            let auth = Request::Auth {
+                token: token.to_owned(),
+            };
+            let mut ignored = MessageWriter::new(io::sink());
+            self.check_access(&auth, &mut access, &mut context, &mut ignored)?;
+        }
+        let mut writer = MessageWriter::new(Vec::new());
+        writer.send_coded_errors();
+        if self.check_listener(&req, &mut writer)?
+            && self.check_access(&req, &mut access, &mut context, &mut writer)?
+            && context.check_quota(&req, &mut writer)?
+        {
+            info!("Receive gRPC request from {}: {:?}", peer, req);
+            let audit = context.audit(&req, &access, peer);
+            context.handle_request(req, &mut writer)?;
+            if let Some(audit) = audit {
+                audit.finish(writer.take_error());
+            }
+        }
+        Ok(writer.into_inner())
+    }
+
+    /// Turns the connection into a one-way stream of events for keys
+    /// starting with `prefix`, until the client goes away. Clients older
+    /// than protocol version 10 only hear of sets and removals.
+    fn stream_events<W: Write>(&self, prefix: String, writer: &mut MessageWriter<W>) -> Result<()> {
+        let events = self.broker.subscribe(prefix);
+        writer.send(&SubscribeResponse::Ok(()))?;
+        writer.flush()?;
+        let all = writer.collection_events();
+        for event in events
+            .iter()
+            .filter(|event| all || event.is_set_or_removed())
+        {
+            writer.send(&event)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Sends the pairs whose keys match `pattern` in frames of a page of
+    /// keys each, then `Done`, so that neither side holds the whole export.
+    ///
+    /// Values are read as each page is sent: a key removed meanwhile is
+    /// left out, and lists, hashes and sets are skipped.
+    fn stream_export<W: Write>(&self, pattern: &str, writer: &mut MessageWriter<W>) -> Result<()> {
+        let glob: Glob = match pattern.parse() {
+            Ok(glob) => glob,
+            Err(err) => return writer.send(&ExportResponse::Err(writer.error(&err))),
+        };
+        let mut exported = 0;
+        let mut cursor = None;
+        loop {
+            let pairs = self
+                .scan_page(&glob, cursor.take(), EXPORT_PAGE_KEYS)
+                .and_then(|page| {
+                    cursor = page.cursor;
+                    let values = page.keys.into_iter().map(|key| {
+                        let value = self.reader.get(key.clone())?;
+                        Ok(value.map(|value| (key, value)))
+                    });
+                    values
+                        .filter_map(Result::transpose)
+                        .collect::<Result<Vec<_>>>()
+                });
+            match pairs {
+                Ok(pairs) if pairs.is_empty() => {}
+                Ok(pairs) => {
+                    exported += pairs.len() as u64;
+                    writer.send(&ExportResponse::Pairs(pairs))?;
+                    writer.flush()?;
+                }
+                Err(err) => return writer.send(&ExportResponse::Err(writer.error(&err))),
+            }
+            if cursor.is_none() {
+                break;
+            }
+        }
+        let response = ExportResponse::Done { pairs: exported };
+        writer.send(&response)?;
+        info!("Response sent: {:?}", response);
+        Ok(())
+    }
+
+    /// Negotiates the protocol version, switching to `codec` afterwards, and
+    /// describes the server.
+    fn hello(&self, version: u32, codec: Codec, compression: Compression) -> HelloResponse {
+        match self.lock_engine() {
+            Ok(engine) => hello(
+                version,
+                codec,
+                compression,
+                engine.name(),
+                self.settings().requires_auth(),
+            ),
+            Err(err) => HelloResponse::Err(err.to_string()),
+        }
+    }
+
+    /// Answers `Hello`, moving `context` to the store of `tenant` if the
+    /// client named one.
+    fn greet(
+        &self,
+        version: u32,
+        codec: Codec,
+        compression: Compression,
+        tenant: Option<String>,
+        access: &Option<Access>,
+        context: &mut Context<E>,
+    ) -> HelloResponse {
+        let mut response = self.hello(version, codec, compression);
+        if let (HelloResponse::Ok(info), Some(name)) = (&mut response, tenant) {
+            match self.enter_tenant(&name, access) {
+                Ok(entered) => {
+                    *context = entered;
+                    info.tenant = Some(name);
+                }
+                Err(err) => return HelloResponse::Err(err.to_string()),
+            }
+        }
+        response
+    }
+
+    /// The context buckets are selected from for a connection at
+    /// `context`: that of its tenant, or this one.
+    fn home(&self, context: &Context<E>) -> Context<E> {
+        match context.tenant {
+            Some(_) => context.clone(),
+            None => self.clone(),
+        }
+    }
+
+    /// Answers `Auth` requests, rejects every other request until the
+    /// connection authenticated, then enforces the ACL of its token.
+    /// Returns whether `req` should be dispatched.
+    ///
+    /// A token given a tenant moves `context` to the store of the tenant,
+    /// and is refused if the connection chose another one.
+    fn check_access<W: Write>(
+        &self,
+        req: &Request,
+        access: &mut Option<Access>,
+        context: &mut Context<E>,
+        writer: &mut MessageWriter<W>,
+    ) -> Result<bool> {
+        if !self.settings().requires_auth() {
+            return Ok(true);
+        }
+        // the handshake tells clients whether they need to authenticate,
+        // and health checkers have no token
+        if let Request::Hello { .. } | Request::Ping = req {
+            return Ok(true);
+        }
+        if let Request::Auth { token } = req {
+            *access = self.authenticate(token);
+            let pinned = match access {
+                Some(Access::Restricted(rule)) => rule.tenant().map(str::to_owned),
+                _ => None,
+            };
+            let current = context.tenant.as_ref().map(|tenant| tenant.name());
+            if let Some(name) = pinned.filter(|name| current != Some(name.as_str())) {
+                match current.is_none().then(|| self.enter_tenant(&name, access)) {
+                    Some(Ok(entered)) => *context = entered,
+                    Some(Err(e)) => {
+                        error!("Cannot enter tenant `{}`: {}", name, e);
+                        *access = None;
+                    }
+                    None => *access = None,
+                }
+            }
+            let response = if access.is_some() {
+                AuthResponse::Ok(())
+            } else {
+                warn!("Rejected authentication attempt");
+                AuthResponse::Unauthorized
+            };
+            writer.send(&response)?;
+            return Ok(false);
+        }
+        let error = match access {
+            None => MyError::Unauthorized,
+            Some(Access::Full) => return Ok(true),
+            Some(Access::Restricted(rule)) => {
+                let (operation, keys) = acl::required(req);
+                if keys.iter().all(|key| rule.allows(operation, key)) {
+                    return Ok(true);
+                }
+                MyError::PermissionDenied
+            }
+        };
+        let error = writer.error(&error);
+        writer.send(&ErrorResponse::Err(error))?;
+        Ok(false)
+    }
+
+    /// Answers `PermissionDenied` to the requests the listener of the
+    /// connection does not serve, see `Server::with_admin_listener`.
+    /// Returns whether `req` should go on.
+    fn check_listener<W: Write>(
+        &self,
+        req: &Request,
+        writer: &mut MessageWriter<W>,
+    ) -> Result<bool> {
+        let served = match req {
+            Request::Hello { .. }
+            | Request::Auth { .. }
+            | Request::Ping
+            | Request::Select { .. } => true,
+            Request::ReloadConfig | Request::Shutdown | Request::Drain => self.on_admin_listener,
+            req if is_management(req) => self.on_admin_listener || !self.admin_listener,
+            _ => !self.on_admin_listener,
+        };
+        if served {
+            return Ok(true);
+        }
+        let message = match self.on_admin_listener {
+            true => format!("{} is not served on the admin listener", req.kind()),
+            false => format!("{} is only served on the admin listener", req.kind()),
+        };
+        let error = writer.error(&MyError::Server {
+            code: ErrorCode::PermissionDenied,
+            message,
+        });
+        writer.send(&ErrorResponse::Err(error))?;
+        Ok(false)
+    }
+
+    /// Takes `req` off the rate limits of the connection and of its token,
+    /// answering `RateLimited` once either is used up. Returns whether `req`
+    /// should go on.
+    fn check_rate<W: Write>(
+        &self,
+        req: &Request,
+        limiter: Option<&RateLimiter>,
+        access: &Option<Access>,
+        writer: &mut MessageWriter<W>,
+    ) -> Result<bool> {
+        // the handshake is not counted, as the connection limit covers it
+        if let Request::Hello { .. } = req {
+            return Ok(true);
+        }
+        let allowed = limiter.is_none_or(RateLimiter::try_acquire)
+            && match access {
+                Some(Access::Restricted(rule)) => rule.try_acquire(),
+                _ => true,
+            };
+        if !allowed {
+            let error = writer.error(&MyError::RateLimited);
+            writer.send(&ErrorResponse::Err(error))?;
+        }
+        Ok(allowed)
+    }
+
+    /// The audit record of `req` from `peer`, if it writes and writes are
+    /// audited.
+    fn audit(&self, req: &Request, access: &Option<Access>, peer: &str) -> Option<Audit> {
+        let log = self.audit.as_ref()?;
+        let tenant = self.tenant.as_ref().map(|tenant| tenant.name());
+        log.start(req, Access::identity(access), peer, tenant, &self.bucket)
+    }
+
+    fn authenticate(&self, token: &str) -> Option<Access> {
+        let settings = self.settings();
+        if let Some(expected) = &settings.auth_token {
+            if acl::constant_time_eq(token.as_bytes(), expected.as_bytes()) {
+                return Some(Access::Full);
+            }
+        }
+        settings
+            .acl
+            .as_ref()
+            .and_then(|acl| acl.rule_for(token))
+            .map(Access::Restricted)
+    }
+
+    /// Sends a snapshot of the whole store followed by every later change,
+    /// or only the changes after `from` if the broker still has them.
+    ///
+    /// The subscription is registered before the snapshot is read from the
+    /// engine reader, so writes go on meanwhile: any write missing from the
+    /// snapshot is in the stream, and writes in both apply twice, in order.
+    ///
+    /// Meanwhile the replica's acknowledgements are read from `reader` for
+    /// writes waiting on them.
+    fn stream_sync<R: BufRead + Send, W: Write>(
+        &self,
+        stream: &Stream,
+        reader: &mut MessageReader<R>,
+        writer: &mut MessageWriter<W>,
+        from: Option<StreamPosition>,
+    ) -> Result<()> {
+        if !writer.collection_events() {
+            let err = MyError::StringError(
+                "Replicas older than protocol version 10 cannot apply the changes of lists, \
+                 hashes, sets and expiries"
+                    .to_owned(),
+            );
+            writer.send(&SyncResponse::Err(writer.error(&err)))?;
+            return writer.flush();
+        }
+        let resumed = from
+            .filter(|from| from.stream == self.broker.stream())
+            .and_then(|from| Some((from.seq, self.broker.resume(from.seq)?)));
+        let (seq, missed, events) = match resumed {
+            Some((seq, (missed, events))) => {
+                writer.send(&SyncResponse::Position(StreamPosition {
+                    stream: self.broker.stream().to_owned(),
+                    seq,
+                }))?;
+                writer.flush()?;
+                info!("Replica resumed after event {}", seq);
+                (seq, missed, events)
+            }
+            None => {
+                // writes publish under the engine lock, so the collections
+                // of the snapshot are exactly those before the event `seq`
+                let mut engine = self.lock_engine()?;
+                let (seq, events) = self.broker.subscribe_numbered();
+                let rebuilt = replication::snapshot_events(&mut *engine);
+                drop(engine);
+                self.send_snapshot(writer, seq, rebuilt)?;
+                (seq, Vec::new(), events)
+            }
+        };
+        let replicas = &self.replicas;
+        let id = replicas.attach(seq);
+        let unacked = Mutex::new(Unacked::new(seq));
+        let result = thread::scope(|scope| {
+            scope.spawn(|| read_acks(reader, replicas, id, &unacked));
+            let result = send_batches(writer, missed, events, &unacked);
+            // ends `read_acks`
+            let _ = stream.shutdown(Shutdown::Read);
+            result
+        });
+        replicas.detach(id);
+        result
+    }
+
+    /// Sends a snapshot of the whole store, the pairs as of the event `seq`
+    /// or later and the `rebuilt` collections and expiries as of `seq`,
+    /// then the position of the stream.
+    fn send_snapshot<W: Write>(
+        &self,
+        writer: &mut MessageWriter<W>,
+        seq: u64,
+        rebuilt: Result<Vec<Event>>,
+    ) -> Result<()> {
+        let (pairs, rebuilt) =
+            match rebuilt.and_then(|rebuilt| Ok((self.reader.scan(String::new())?, rebuilt))) {
+                Ok(snapshot) => snapshot,
+                Err(err) => {
+                    writer.send(&SyncResponse::Err(writer.error(&err)))?;
+                    return writer.flush();
                 }
             };
+        let count = pairs.len() as u64;
+        let mut chunk = Vec::new();
+        let mut chunk_len = 0;
+        for (key, value) in pairs {
+            chunk_len += key.len() + value.len();
+            chunk.push((key, value));
+            if chunk_len >= SYNC_CHUNK_BYTES {
+                send_chunk(writer, std::mem::take(&mut chunk))?;
+                chunk_len = 0;
+            }
+        }
+        if !chunk.is_empty() {
+            send_chunk(writer, chunk)?;
+        }
+        let mut events = Vec::new();
+        let mut events_len = 0;
+        for event in rebuilt {
+            events_len += event_bytes(&event);
+            events.push(event);
+            if events_len >= SYNC_CHUNK_BYTES {
+                writer.send(&SyncResponse::Events(std::mem::take(&mut events)))?;
+                events_len = 0;
+            }
+        }
+        if !events.is_empty() {
+            writer.send(&SyncResponse::Events(events))?;
         }
+        writer.send(&SyncResponse::Done { pairs: count })?;
+        writer.send(&SyncResponse::Position(StreamPosition {
+            stream: self.broker.stream().to_owned(),
+            seq,
+        }))?;
+        writer.flush()?;
+        info!("Replica attached");
+        Ok(())
+    }
 
+    /// Holds the connection until `key` next changes or `timeout` elapses.
+    fn watch_key<W: Write>(
+        &self,
+        key: String,
+        timeout: Duration,
+        writer: &mut MessageWriter<W>,
+    ) -> Result<()> {
+        let events = self.broker.subscribe(key.clone());
+        writer.send(&WatchResponse::Watching)?;
+        writer.flush()?;
+
+        let deadline = Instant::now() + timeout;
+        let response = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match events.recv_timeout(remaining) {
+                // the broker matches on prefix, the watch on the exact key
+                Ok(event)
+                    if event.key() == key
+                        && (writer.collection_events() || event.is_set_or_removed()) =>
+                {
+                    break WatchResponse::Changed(event)
+                }
+                Ok(_) => continue,
+                Err(_) => break WatchResponse::TimedOut,
+            }
+        };
+        writer.send(&response)?;
+        info!("Response sent: {:?}", response);
+        Ok(())
+    }
+
+    fn handle_websocket(&self, stream: Stream, origins: &[String]) -> Result<()> {
+        let peer_addr = stream.peer()?;
+        let span = info_span!("websocket", peer = %peer_addr);
+        let _connection = span.enter();
+        let settings = self.settings();
+        stream.set_read_timeout(settings.idle_timeout)?;
+        stream.set_write_timeout(settings.request_timeout)?;
+        let limiter = settings.rate_limit.map(RateLimiter::new);
+        drop(settings);
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        websocket::accept(&mut reader, &mut writer, origins)?;
+        info!("WebSocket connection established from {}", peer_addr);
+        let mut reader = websocket::Reader::new(reader);
+
+        let mut context = self.clone();
+        let mut access = None;
+        loop {
+            let message = match reader.read_message() {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(MyError::Timeout) => {
+                    info!("Closing idle WebSocket connection from {}", peer_addr);
+                    websocket::write_close(&mut writer)?;
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            match message {
+                Message::Text(text) => {
+                    let mut response = MessageWriter::new(Vec::new());
+                    match serde_json::from_str::<Request>(&text).map(Request::untagged) {
+                        Ok((req, request_id)) => {
+                            response.set_request_id(request_id);
+                            if self.check_listener(&req, &mut response)?
+                                && self.check_rate(
+                                    &req,
+                                    limiter.as_ref(),
+                                    &access,
+                                    &mut response,
+                                )?
+                                && self.check_access(
+                                    &req,
+                                    &mut access,
+                                    &mut context,
+                                    &mut response,
+                                )?
+                                && context.check_quota(&req, &mut response)?
+                            {
+                                info!("Receive WebSocket request from {}: {:?}", peer_addr, req);
+                                let audit = context.audit(&req, &access, &peer_addr);
+                                match req {
+                                    Request::Hello {
+                                        version, tenant, ..
+                                    } => {
+                                        // WebSocket messages are JSON text frames whatever the client asks
+                                        let hello = self.greet(
+                                            version,
+                                            Codec::Json,
+                                            Compression::None,
+                                            tenant,
+                                            &access,
+                                            &mut context,
+                                        );
+                                        response.send(&hello)?;
+                                        info!("Response sent: {:?}", hello);
+                                    }
+                                    Request::Select { db } => self.home(&context).switch_bucket(
+                                        &db,
+                                        &mut context,
+                                        &mut response,
+                                    )?,
+                                    req => context.handle_request(req, &mut response)?,
+                                }
+                                if let Some(audit) = audit {
+                                    audit.finish(response.take_error());
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            let error = response.error(&invalid_request(e.into()));
+                            response.send(&ErrorResponse::Err(error))?;
+                        }
+                    }
+                    let text = String::from_utf8(response.into_inner())?;
+                    websocket::write_text(&mut writer, &text)?;
+                }
+                Message::Ping(payload) => websocket::write_pong(&mut writer, &payload)?,
+                Message::Close => {
+                    websocket::write_close(&mut writer)?;
+                    break;
+                }
+                Message::Binary(_) | Message::Pong(_) => {}
+            }
+        }
+        info!("WebSocket connection from {} closed", peer_addr);
         Ok(())
     }
 }
+
+fn serve_websocket<E: KvsEngine + Send + 'static>(
+    listener: TcpListener,
+    context: Context<E>,
+    origins: Arc<Vec<String>>,
+    shutdown: ShutdownHandle,
+) {
+    while !shutdown.is_requested() {
+        match listener.accept() {
+            Ok((stream, peer_addr)) => match context.admit(Stream::Tcp(stream)) {
+                Ok((stream, Some(guard))) => {
+                    let context = context.clone();
+                    let origins = origins.clone();
+                    let spawned = thread::Builder::new()
+                        .name(format!("ws client {}", peer_addr))
+                        .spawn(move || {
+                            if let Err(e) = context.handle_websocket(stream, &origins) {
+                                warn!("WebSocket connection closed with error: {}", e);
+                            }
+                            drop(guard);
+                        });
+                    if let Err(e) = spawned {
+                        error!("WebSocket connection failed {}", e);
+                    }
+                }
+                Ok((_, None)) => warn!("Connection limit reached, rejecting WebSocket client"),
+                Err(e) => error!("WebSocket connection failed {}", e),
+            },
+            Err(e) => error!("WebSocket connection failed {}", e),
+        }
+    }
+}
+
+/// Serves the clients of the Unix socket at `path` as those of the TCP
+/// listener, removing the socket file on shutdown.
+#[cfg(unix)]
+fn serve_unix<E: KvsEngine + Send + 'static>(
+    listener: UnixListener,
+    path: &Path,
+    context: Context<E>,
+    shutdown: ShutdownHandle,
+) {
+    while !shutdown.is_requested() {
+        match listener.accept() {
+            Ok((stream, _)) => match context.admit(Stream::Unix(stream)) {
+                Ok((stream, Some(guard))) => {
+                    let context = context.clone();
+                    // unix clients have no address, so the thread is named
+                    // after the connection
+                    let spawned = thread::Builder::new()
+                        .name(format!("unix client {}", guard.id))
+                        .spawn(move || {
+                            if let Err(e) = context.handle_connection(stream) {
+                                warn!("Connection closed with error: {}", e);
+                            }
+                            drop(guard);
+                        });
+                    if let Err(e) = spawned {
+                        error!("Connection failed {}", e);
+                    }
+                }
+                Ok((stream, None)) => {
+                    warn!("Connection limit reached, rejecting client");
+                    thread::spawn(move || reject(stream));
+                }
+                Err(e) => error!("Connection failed {}", e),
+            },
+            Err(e) => error!("Connection failed {}", e),
+        }
+    }
+    let _ = std::fs::remove_file(path);
+}
+
+/// Whether `req` manages the server rather than its data, and is served
+/// on the admin listener if there is one.
+fn is_management(req: &Request) -> bool {
+    matches!(
+        req,
+        Request::Stats
+            | Request::SlowLog
+            | Request::FlushAll { .. }
+            | Request::PurgeTrash
+            | Request::Compact
+            | Request::ReloadConfig
+            | Request::Shutdown
+            | Request::Drain
+    )
+}
+
+fn serve_admin<E: KvsEngine + Send + 'static>(
+    listener: TcpListener,
+    context: Context<E>,
+    shutdown: ShutdownHandle,
+) {
+    while !shutdown.is_requested() {
+        let (stream, peer_addr) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Connection failed {}", e);
+                continue;
+            }
+        };
+        let stream = Stream::Tcp(stream);
+        let mut open = context.connections.open.lock().unwrap();
+        let guard = match context.register(&mut open, &stream) {
+            Ok(guard) => guard,
+            Err(e) => {
+                error!("Connection failed {}", e);
+                continue;
+            }
+        };
+        drop(open);
+        let context = context.clone();
+        let spawned = thread::Builder::new()
+            .name(format!("admin {}", peer_addr))
+            .spawn(move || {
+                if let Err(e) = context.handle_tcp_connection(stream) {
+                    warn!("Connection closed with error: {}", e);
+                }
+                drop(guard);
+            });
+        if let Err(e) = spawned {
+            error!("Connection failed {}", e);
+        }
+    }
+}
+
+/// Connections being served, so that shutdown can close them.
+#[derive(Default)]
+struct Connections {
+    open: Mutex<HashMap<u64, Stream>>,
+    next_id: AtomicU64,
+}
+
+/// Unregisters its connection when dropped.
+struct ConnectionGuard {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.open.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Tells a client over the connection limit why it is turned away, then
+/// waits briefly for it to stop sending so the error is not lost to a reset.
+fn reject(stream: Stream) {
+    // sent before any handshake, so without a code
+    let response = ErrorResponse::Err(WireError::Message(MyError::TooManyConnections.to_string()));
+    let _ = serde_json::to_writer(&stream, &response);
+    let _ = stream.shutdown(Shutdown::Write);
+    let _ = stream.set_read_timeout(Some(Duration::from_millis(500)));
+    let _ = io::copy(&mut &stream, &mut io::sink());
+}
+
+/// The events announcing the writes of `batch`.
+fn batch_events(batch: &WriteBatch) -> Vec<Event> {
+    batch
+        .commands
+        .iter()
+        .map(|command| match command {
+            Command::Set { key, value } => Event::Set {
+                key: key.clone(),
+                value: value.clone(),
+            },
+            Command::Remove { key } => Event::Removed { key: key.clone() },
+            _ => unreachable!("batches hold sets and removes"),
+        })
+        .collect()
+}
+
+/// Records the acknowledgements of replica `id` until it goes away.
+/// Sends `missed`, then the events from `events` as they come, in batches
+/// of those waiting, until the broker closes.
+fn send_batches<W: Write>(
+    writer: &mut MessageWriter<W>,
+    missed: Vec<(u64, Event)>,
+    events: Receiver<(u64, Event)>,
+    unacked: &Mutex<Unacked>,
+) -> Result<()> {
+    let mut waiting: VecDeque<(u64, Event)> = missed.into();
+    loop {
+        if waiting.is_empty() {
+            match events.recv() {
+                Ok(event) => waiting.push_back(event),
+                Err(_) => return Ok(()),
+            }
+        }
+        waiting.extend(events.try_iter());
+        let batch: Vec<(u64, Event)> = waiting
+            .drain(..waiting.len().min(CHANGE_BATCH_EVENTS))
+            .collect();
+        let start_seq = batch[0].0;
+        let mut unacked = unacked.lock().unwrap();
+        let events = batch
+            .into_iter()
+            .map(|(seq, event)| {
+                unacked.sent(seq);
+                event
+            })
+            .collect();
+        drop(unacked);
+        writer.send(&ChangeBatch::new(start_seq, events))?;
+        writer.flush()?;
+    }
+}
+
+fn read_acks<R: BufRead>(
+    reader: &mut MessageReader<R>,
+    replicas: &Replicas,
+    id: u64,
+    unacked: &Mutex<Unacked>,
+) {
+    loop {
+        match reader.wait() {
+            Ok(true) => {}
+            // replicas of idle leaders have nothing to acknowledge
+            Err(e) if is_timeout(&e) => continue,
+            Ok(false) | Err(_) => return,
+        }
+        let ack = match reader.receive::<ReplicaAck>() {
+            Ok(ack) => ack,
+            Err(e) => {
+                warn!("Invalid acknowledgement from replica: {}", e);
+                return;
+            }
+        };
+        match unacked
+            .lock()
+            .unwrap()
+            .acknowledge(ack.received, ack.applied)
+        {
+            Some((received, applied)) => replicas.advance(id, received, applied),
+            None => warn!("Replica acknowledged events never sent: {:?}", ack),
+        }
+    }
+}
+
+/// Bytes of keys and values past which a sync chunk is sent.
+const SYNC_CHUNK_BYTES: usize = 1 << 20;
+
+/// Most events sent to a replica in one `ChangeBatch`.
+const CHANGE_BATCH_EVENTS: usize = 256;
+
+fn send_chunk<W: Write>(writer: &mut MessageWriter<W>, pairs: Vec<(String, String)>) -> Result<()> {
+    let crc = pairs_crc(&pairs);
+    writer.send(&SyncResponse::Chunk { pairs, crc })?;
+    writer.flush()
+}
+
+/// Why clusters refuse writes with a TTL: their log does not carry expiry.
+const EXPIRATION_REFUSAL: &str = "Expiration is not available in Raft mode";
+
+/// Why clusters refuse scripts.
+#[cfg(feature = "scripting")]
+const SCRIPT_REFUSAL: &str = "Scripts are not available in Raft mode";
+
+/// Why clusters refuse writes depending on what the key holds.
+const READ_WRITE_REFUSAL: &str = "GetSet, GetDel and SetNx are not available in Raft mode";
+
+/// Why clusters refuse writes depending on the version of the key.
+const SET_IF_VERSION_REFUSAL: &str = "SetIfVersion is not available in Raft mode";
+
+/// Why clusters refuse the requests on the trash, which their log does not
+/// carry.
+const TRASH_REFUSAL: &str = "Restore and PurgeTrash are not available in Raft mode";
+
+/// A token for a lock holder: random bits from the seeds of the standard
+/// hasher, and a count of the tokens handed out so that no two are alike.
+fn lock_token() -> String {
+    static ISSUED: AtomicU64 = AtomicU64::new(0);
+    let issued = ISSUED.fetch_add(1, Ordering::Relaxed);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(issued);
+    format!("{:016x}-{}", hasher.finish(), issued)
+}
+
+/// `err`, met while decoding a request, as reported to the client.
+fn invalid_request(err: MyError) -> MyError {
+    MyError::Server {
+        code: ErrorCode::InvalidRequest,
+        message: err.to_string(),
+    }
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
@@ -0,0 +1,94 @@
+//! Record of requests that took longer than a threshold.
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Number of slow requests kept; older ones are forgotten first.
+const CAPACITY: usize = 128;
+
+/// A request that exceeded the slow-log threshold.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SlowRequest {
+    /// When the request completed.
+    pub at: SystemTime,
+    /// Type of the request, e.g. `Get`.
+    pub request: String,
+    /// Length of the key or prefix the request was about.
+    pub key_len: usize,
+    /// Time spent processing the request.
+    pub elapsed: Duration,
+    /// ID the client gave the request, if any.
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Logs requests slower than a threshold and keeps the most recent ones.
+pub(crate) struct SlowLog {
+    threshold: Mutex<Duration>,
+    recent: Mutex<VecDeque<SlowRequest>>,
+}
+
+impl SlowLog {
+    pub(crate) fn new(threshold: Duration) -> SlowLog {
+        SlowLog {
+            threshold: Mutex::new(threshold),
+            recent: Mutex::new(VecDeque::with_capacity(CAPACITY)),
+        }
+    }
+
+    /// Records the request if it took at least the threshold.
+    pub(crate) fn record(
+        &self,
+        request: &str,
+        key_len: usize,
+        elapsed: Duration,
+        request_id: Option<&str>,
+    ) {
+        if elapsed < *self.threshold.lock().unwrap() {
+            return;
+        }
+        warn!(
+            "Slow request{}: {} with a {} byte key took {:?}",
+            RequestId(request_id),
+            request,
+            key_len,
+            elapsed
+        );
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == CAPACITY {
+            recent.pop_front();
+        }
+        recent.push_back(SlowRequest {
+            at: SystemTime::now(),
+            request: request.to_owned(),
+            key_len,
+            elapsed,
+            request_id: request_id.map(str::to_owned),
+        });
+    }
+
+    /// Logs the requests taking at least `threshold` from now on.
+    pub(crate) fn set_threshold(&self, threshold: Duration) {
+        *self.threshold.lock().unwrap() = threshold;
+    }
+
+    /// The recorded requests, oldest first.
+    pub(crate) fn recent(&self) -> Vec<SlowRequest> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// The ID of a request, for log lines: ` [ID]` if it has one.
+pub(crate) struct RequestId<'a>(pub(crate) Option<&'a str>);
+
+impl fmt::Display for RequestId<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(id) => write!(f, " [{}]", id),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Snapshots of the store taken on a schedule, the oldest ones removed.
+use crate::engine::{EngineStats, KvsEngine};
+use crate::errors::{MyError, Result};
+use crate::server::EngineLock;
+use log::{error, info};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Start of the name of every snapshot directory, followed by when it was
+/// taken in milliseconds since the UNIX epoch.
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+
+/// End of the name of a snapshot being written; renamed away once complete.
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// Takes snapshots into a directory and keeps the most recent ones.
+pub(crate) struct Snapshots {
+    dir: PathBuf,
+    interval: Duration,
+    retain: usize,
+    status: Mutex<Status>,
+}
+
+#[derive(Default)]
+struct Status {
+    taken: u64,
+    last: Option<SystemTime>,
+    last_error: Option<String>,
+}
+
+impl Snapshots {
+    pub(crate) fn new(dir: PathBuf, interval: Duration, retain: usize) -> Snapshots {
+        Snapshots {
+            dir,
+            interval,
+            retain: retain.max(1),
+            status: Mutex::new(Status::default()),
+        }
+    }
+
+    /// How long to wait between snapshots.
+    pub(crate) fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Takes a snapshot of `engine`, holding its lock meanwhile, then
+    /// removes the oldest snapshots beyond the retention count. Failures
+    /// are logged and reported in the stats.
+    pub(crate) fn take<E: KvsEngine>(&self, engine: &EngineLock<E>) {
+        let now = SystemTime::now();
+        let result = self.write(engine, now).and_then(|path| {
+            info!("Snapshot written to {}", path.display());
+            self.rotate()
+        });
+        let mut status = self.status.lock().unwrap();
+        match result {
+            Ok(()) => {
+                status.taken += 1;
+                status.last = Some(now);
+                status.last_error = None;
+            }
+            Err(e) => {
+                error!("Snapshot failed: {}", e);
+                status.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Writes the snapshot under a temporary name, renamed once complete
+    /// so that an interrupted snapshot is never taken for a whole one.
+    fn write<E: KvsEngine>(&self, engine: &EngineLock<E>, now: SystemTime) -> Result<PathBuf> {
+        let millis = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        // zero-padded, so that names sort by age
+        let path = self.dir.join(format!("{}{:016}", SNAPSHOT_PREFIX, millis));
+        let temp_path = path.with_extension(&TEMP_SUFFIX[1..]);
+        engine.lock().snapshot(&temp_path)?;
+        fs::rename(&temp_path, &path).map_err(MyError::file(&temp_path))?;
+        Ok(path)
+    }
+
+    /// Removes the snapshots beyond the `retain` most recent ones, and those
+    /// left incomplete.
+    fn rotate(&self) -> Result<()> {
+        let mut complete = Vec::new();
+        for entry in fs::read_dir(&self.dir).map_err(MyError::file(&self.dir))? {
+            let name = entry?.file_name();
+            let name = match name.to_str() {
+                Some(name) if name.starts_with(SNAPSHOT_PREFIX) => name.to_owned(),
+                _ => continue,
+            };
+            if name.ends_with(TEMP_SUFFIX) {
+                remove(&self.dir.join(name))?;
+            } else {
+                complete.push(name);
+            }
+        }
+        complete.sort_unstable();
+        let stale = complete.len().saturating_sub(self.retain);
+        for name in &complete[..stale] {
+            remove(&self.dir.join(name))?;
+        }
+        Ok(())
+    }
+
+    /// Adds how the snapshots went to `stats`.
+    pub(crate) fn report(&self, stats: &mut EngineStats) {
+        let status = self.status.lock().unwrap();
+        stats.snapshots = status.taken;
+        stats.last_snapshot = status.last;
+        stats.last_snapshot_error = status.last_error.clone();
+    }
+}
+
+fn remove(path: &Path) -> Result<()> {
+    info!("Removing snapshot {}", path.display());
+    fs::remove_dir_all(path).map_err(MyError::file(path))
+}
@@ -0,0 +1,174 @@
+//! Tenants: applications sharing a server, each in a store of its own.
+//!
+//! A tenants file lists them, with the quotas of each:
+//!
+//! ```toml
+//! [[tenant]]
+//! name = "app1"
+//! max_keys = 100000
+//! max_disk_bytes = 1073741824
+//!
+//! [[tenant]]
+//! name = "app2"
+//! ```
+//!
+//! The store of a tenant is kept under `tenants/<name>` in the data
+//! directory. Once a tenant holds `max_keys` keys, or its store takes
+//! `max_disk_bytes` on disk, its requests adding data are refused with
+//! `MyError::QuotaExceeded`; removals still go through.
+use crate::common::Request;
+use crate::engine::{check_bucket_name, EngineStats};
+use crate::errors::{MyError, Result};
+use crate::latency::Latencies;
+
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Prefix of the bucket names the stores of tenants are opened under,
+/// which client bucket names cannot start with.
+pub(crate) const TENANT_MARK: char = '@';
+
+/// One application hosted by the server.
+pub struct Tenant {
+    name: String,
+    max_keys: Option<u64>,
+    max_disk_bytes: Option<u64>,
+    /// How long the requests of the tenant took, reported in its stats.
+    pub(crate) latencies: Arc<Latencies>,
+}
+
+impl Tenant {
+    /// Name of the tenant, as clients and ACL tokens give it.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Keys the tenant may hold, if limited.
+    pub fn max_keys(&self) -> Option<u64> {
+        self.max_keys
+    }
+
+    /// Bytes the store of the tenant may take on disk, if limited.
+    pub fn max_disk_bytes(&self) -> Option<u64> {
+        self.max_disk_bytes
+    }
+
+    /// Whether a quota has to be checked before `req`.
+    pub(crate) fn limits(&self, req: &Request) -> bool {
+        (self.max_keys.is_some() || self.max_disk_bytes.is_some()) && grows(req)
+    }
+
+    /// Fails if the store described by `stats` is at a quota.
+    pub(crate) fn check_quota(&self, stats: &EngineStats) -> Result<()> {
+        if let Some(max) = self.max_keys.filter(|max| stats.key_count >= *max) {
+            return Err(MyError::QuotaExceeded(format!(
+                "Tenant `{}` holds {} keys, its quota",
+                self.name, max
+            )));
+        }
+        if let Some(max) = self.max_disk_bytes.filter(|max| stats.disk_usage >= *max) {
+            return Err(MyError::QuotaExceeded(format!(
+                "Tenant `{}` takes {} bytes on disk, its quota is {}",
+                self.name, stats.disk_usage, max
+            )));
+        }
+        Ok(())
+    }
+
+    /// Bucket name the store of the tenant is opened under.
+    pub(crate) fn store(&self) -> String {
+        format!("{}{}", TENANT_MARK, self.name)
+    }
+}
+
+/// The tenants loaded from a tenants file.
+#[derive(Default)]
+pub struct Tenants {
+    tenants: Vec<Arc<Tenant>>,
+}
+
+impl Tenants {
+    /// Loads the tenants file at `path`.
+    pub fn load(path: &Path) -> Result<Tenants> {
+        Tenants::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parses the TOML content of a tenants file.
+    pub fn parse(input: &str) -> Result<Tenants> {
+        let root: toml::Table = toml::from_str(input).map_err(|e| tenants_error(&e.to_string()))?;
+        let entries = match root.get("tenant") {
+            Some(value) => value
+                .as_array()
+                .ok_or_else(|| tenants_error("`tenant` must be an array of tables"))?
+                .clone(),
+            None => Vec::new(),
+        };
+
+        let mut tenants: Vec<Arc<Tenant>> = Vec::new();
+        for entry in entries {
+            let entry = entry
+                .as_table()
+                .ok_or_else(|| tenants_error("`tenant` must be an array of tables"))?;
+            let name = entry
+                .get("name")
+                .and_then(toml::Value::as_str)
+                .ok_or_else(|| tenants_error("every entry needs a `name` string"))?
+                .to_owned();
+            check_bucket_name(&name).map_err(|e| tenants_error(&e.to_string()))?;
+            if tenants.iter().any(|tenant| tenant.name == name) {
+                return Err(tenants_error(&format!("tenant `{}` is listed twice", name)));
+            }
+            tenants.push(Arc::new(Tenant {
+                max_keys: quota(entry.get("max_keys"), "max_keys")?,
+                max_disk_bytes: quota(entry.get("max_disk_bytes"), "max_disk_bytes")?,
+                name,
+                latencies: Arc::new(Latencies::default()),
+            }));
+        }
+        Ok(Tenants { tenants })
+    }
+
+    /// The tenant called `name`, if it is listed.
+    pub fn get(&self, name: &str) -> Option<Arc<Tenant>> {
+        self.tenants
+            .iter()
+            .find(|tenant| tenant.name == name)
+            .cloned()
+    }
+}
+
+/// Whether `req` may add keys or bytes to the store.
+fn grows(req: &Request) -> bool {
+    match req {
+        Request::Set { .. }
+        | Request::SetPath { .. }
+        | Request::GetSet { .. }
+        | Request::SetNx { .. }
+        | Request::SetIfVersion { .. }
+        | Request::LPush { .. }
+        | Request::RPush { .. }
+        | Request::HSet { .. }
+        | Request::SAdd { .. }
+        | Request::ZAdd { .. }
+        | Request::AcquireLock { .. }
+        | Request::SetMany { .. } => true,
+        Request::Traced { request, .. } | Request::Tagged { request, .. } => grows(request),
+        _ => false,
+    }
+}
+
+fn quota(value: Option<&toml::Value>, name: &str) -> Result<Option<u64>> {
+    match value {
+        Some(value) => value
+            .as_integer()
+            .and_then(|quota| u64::try_from(quota).ok())
+            .map(Some)
+            .ok_or_else(|| tenants_error(&format!("`{}` must be a non-negative integer", name))),
+        None => Ok(None),
+    }
+}
+
+fn tenants_error(msg: &str) -> MyError {
+    MyError::StringError(format!("Invalid tenants file: {}", msg))
+}
@@ -0,0 +1,395 @@
+//! Checks that an engine keeps what it acknowledged when a write fails and
+//! it is opened again, for the tests of `KvsEngine` implementations.
+//!
+//! A [`Workload`] is a random sequence of sets, removes and batches, the
+//! same for the same seed. [`check_restarts`] runs one against an engine,
+//! failing the write under way at random points through a
+//! [`FaultyEngine`], then dropping the engine and opening it again. The
+//! store must then hold every write acknowledged before, what the failed
+//! write got to write, and nothing else; what it did not get to write is
+//! retried, and the workload goes on.
+//!
+//! The faults are injected above the engine, which always closes cleanly:
+//! this does not tear or truncate its files as a crash of the process or
+//! the machine would, which the tests of each engine cover.
+//!
+//! Example:
+//!
+//! ```rust
+//! # use kvs::testkit::{check_restarts, Workload};
+//! # use kvs::{KvStore, Result};
+//! # fn try_main() -> Result<()> {
+//! let temp_dir = tempfile::TempDir::new()?;
+//! let workload = Workload::new(7).with_ops(100).with_restarts(3);
+//! let report = check_restarts(&workload, || KvStore::open(temp_dir.path()))?;
+//! assert_eq!(report.restarts, 3);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Available with the `testing` feature.
+use crate::engine::{Fault, FaultPoint, Faults, FaultyEngine, KvsEngine, WriteBatch};
+use crate::{MyError, Result};
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::thread;
+use std::time::Duration;
+
+/// Times [`check_restarts`] tries to open the engine again.
+const OPEN_ATTEMPTS: u32 = 8;
+
+/// Wait before the second attempt, doubled for each one after.
+const OPEN_BACKOFF: Duration = Duration::from_millis(10);
+
+/// A write of a [`Workload`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    /// Sets `key` to `value`.
+    Set {
+        /// The key written.
+        key: String,
+        /// Its new value.
+        value: String,
+    },
+    /// Removes `key`, which exists.
+    Remove {
+        /// The key removed.
+        key: String,
+    },
+    /// Writes the sets and removes it holds at once.
+    Batch(Vec<Op>),
+}
+
+impl Op {
+    /// Makes the write on `engine`.
+    pub fn apply<E: KvsEngine>(&self, engine: &mut E) -> Result<()> {
+        match self {
+            Op::Set { key, value } => engine.set(key.clone(), value.clone()),
+            Op::Remove { key } => engine.remove(key.clone()),
+            Op::Batch(ops) => {
+                let mut batch = WriteBatch::new();
+                for op in ops {
+                    match op {
+                        Op::Set { key, value } => batch.set(key.clone(), value.clone()),
+                        Op::Remove { key } => batch.remove(key.clone()),
+                        Op::Batch(_) => panic!("batches are not nested"),
+                    };
+                }
+                engine.write_batch(batch)
+            }
+        }
+    }
+
+    /// Makes the write on the expected contents of a store.
+    fn apply_to(&self, model: &mut BTreeMap<String, String>) {
+        match self {
+            Op::Set { key, value } => {
+                model.insert(key.clone(), value.clone());
+            }
+            Op::Remove { key } => {
+                model.remove(key);
+            }
+            Op::Batch(ops) => ops.iter().for_each(|op| op.apply_to(model)),
+        }
+    }
+}
+
+/// A random workload, generated again the same from its seed.
+///
+/// Keys are `testkit:` and a number below `with_keys`; removes only pick
+/// keys that exist, so that every write succeeds on a healthy engine.
+#[derive(Clone, Debug)]
+pub struct Workload {
+    seed: u64,
+    ops: usize,
+    keys: usize,
+    value_size: usize,
+    max_batch: usize,
+    restarts: usize,
+}
+
+impl Workload {
+    /// A workload of 200 writes over 20 keys, with 16-byte values, batches
+    /// of up to 8 writes and 5 restarts.
+    pub fn new(seed: u64) -> Workload {
+        Workload {
+            seed,
+            ops: 200,
+            keys: 20,
+            value_size: 16,
+            max_batch: 8,
+            restarts: 5,
+        }
+    }
+
+    /// Make `ops` writes, counting a batch as one.
+    pub fn with_ops(mut self, ops: usize) -> Self {
+        self.ops = ops;
+        self
+    }
+
+    /// Write to `keys` distinct keys.
+    pub fn with_keys(mut self, keys: usize) -> Self {
+        assert!(keys > 0, "a workload needs keys");
+        self.keys = keys;
+        self
+    }
+
+    /// Write values of `bytes` bytes.
+    pub fn with_value_size(mut self, bytes: usize) -> Self {
+        self.value_size = bytes;
+        self
+    }
+
+    /// Write batches of up to `writes` writes, none at all for 0 or 1.
+    pub fn with_max_batch(mut self, writes: usize) -> Self {
+        self.max_batch = writes;
+        self
+    }
+
+    /// Fail a write and restart the engine `restarts` times during the
+    /// workload, at most once a write.
+    pub fn with_restarts(mut self, restarts: usize) -> Self {
+        self.restarts = restarts;
+        self
+    }
+
+    /// The seed the workload is generated from, to run a failing one again.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The writes of the workload, in order.
+    pub fn ops(&self) -> Vec<Op> {
+        let mut rng = Rng(self.seed);
+        let mut model = BTreeMap::new();
+        (0..self.ops)
+            .map(|_| {
+                let op = match self.max_batch > 1 && rng.below(4) == 0 {
+                    true => {
+                        let len = 2 + rng.below(self.max_batch as u64 - 1) as usize;
+                        let mut batch = model.clone();
+                        let ops = (0..len)
+                            .map(|_| {
+                                let op = self.write(&mut rng, &batch);
+                                op.apply_to(&mut batch);
+                                op
+                            })
+                            .collect();
+                        Op::Batch(ops)
+                    }
+                    false => self.write(&mut rng, &model),
+                };
+                op.apply_to(&mut model);
+                op
+            })
+            .collect()
+    }
+
+    /// A set, or a remove of a key in `model` a third of the time.
+    fn write(&self, rng: &mut Rng, model: &BTreeMap<String, String>) -> Op {
+        if !model.is_empty() && rng.below(3) == 0 {
+            let key = model.keys().nth(rng.below(model.len() as u64) as usize);
+            return Op::Remove {
+                key: key.unwrap().clone(),
+            };
+        }
+        let key = format!("testkit:{}", rng.below(self.keys as u64));
+        let value = (0..self.value_size)
+            .map(|_| (b'a' + rng.below(26) as u8) as char)
+            .collect();
+        Op::Set { key, value }
+    }
+
+    /// The writes to fail and restart after, and the fault each fails with.
+    fn faults(&self, ops: &[Op]) -> BTreeMap<usize, Fault> {
+        // drawn apart from the writes, so that the workload stays the same
+        // whatever the number of restarts
+        let mut rng = Rng(self.seed ^ 0x9e37_79b9_7f4a_7c15);
+        let mut faults = BTreeMap::new();
+        while faults.len() < self.restarts.min(ops.len()) {
+            let at = rng.below(ops.len() as u64) as usize;
+            let fault = match (&ops[at], rng.below(2)) {
+                (_, 0) => Fault::Io(ErrorKind::Other),
+                (Op::Batch(batch), _) => {
+                    Fault::PartialWrite(rng.below(batch.len() as u64 + 1) as usize)
+                }
+                (_, _) => Fault::PartialWrite(1),
+            };
+            faults.entry(at).or_insert(fault);
+        }
+        faults
+    }
+}
+
+/// What [`check_restarts`] went through.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RestartReport {
+    /// Writes made, counting a batch as one, the failed ones included.
+    pub ops: usize,
+    /// Times a write failed and the engine was opened again.
+    pub restarts: usize,
+    /// Keys the store held at the end.
+    pub keys: usize,
+}
+
+/// Runs `workload` against the engine `open` returns, failing writes and
+/// restarting it as the workload says, and checks after each restart, and
+/// at the end, that the store holds what it should.
+///
+/// `open` must open the same store every time, empty the first time; an
+/// engine that forgets its writes, like `MemEngine`, fails the check. A
+/// restart drops the engine right after the failed write and opens it
+/// again, retrying with a backoff while the lock of the old one, as sled
+/// holds for a moment after a drop, fails it.
+///
+/// # Errors
+///
+/// Fails with `MyError::StringError`, naming the seed, on the first key
+/// that differs from what it should hold, and with the error of the engine
+/// if it fails to open, or a write fails without a fault injected.
+pub fn check_restarts<E, F>(workload: &Workload, mut open: F) -> Result<RestartReport>
+where
+    E: KvsEngine,
+    F: FnMut() -> Result<E>,
+{
+    let ops = workload.ops();
+    let injected = workload.faults(&ops);
+    let mut model = BTreeMap::new();
+    let mut faults = Faults::default();
+    let mut engine = FaultyEngine::new(open()?, faults.clone());
+    check_state(engine.get_mut(), &model)
+        .map_err(|e| failed(workload, "before the workload", e))?;
+    let mut report = RestartReport {
+        ops: ops.len(),
+        ..RestartReport::default()
+    };
+    for (at, op) in ops.iter().enumerate() {
+        let fault = match injected.get(&at) {
+            Some(fault) => *fault,
+            None => {
+                op.apply(&mut engine)
+                    .map_err(|e| failed(workload, &format!("at write {}", at), e))?;
+                op.apply_to(&mut model);
+                continue;
+            }
+        };
+        let point = match op {
+            Op::Batch(_) => FaultPoint::Batch,
+            _ => FaultPoint::Write,
+        };
+        faults.inject_nth(point, 1, fault);
+        if op.apply(&mut engine).is_ok() {
+            return Err(failed(
+                workload,
+                &format!("at write {}", at),
+                MyError::StringError(format!("the write succeeded through {:?}", fault)),
+            ));
+        }
+        // what the failed write did not get to write, retried once the
+        // engine is back, so that the writes after it apply
+        let rest = match (op, fault) {
+            (_, Fault::Io(_)) | (_, Fault::Delay(_)) => Some(op.clone()),
+            (Op::Batch(batch), Fault::PartialWrite(n)) => {
+                let n = n.min(batch.len());
+                batch[..n].iter().for_each(|op| op.apply_to(&mut model));
+                Some(Op::Batch(batch[n..].to_vec())).filter(|_| n < batch.len())
+            }
+            (op, Fault::PartialWrite(_)) => {
+                op.apply_to(&mut model);
+                None
+            }
+        };
+        drop(engine);
+        report.restarts += 1;
+        faults = Faults::default();
+        engine = FaultyEngine::new(reopen(&mut open)?, faults.clone());
+        check_state(engine.get_mut(), &model)
+            .map_err(|e| failed(workload, &format!("after restarting at write {}", at), e))?;
+        if let Some(rest) = rest {
+            rest.apply(&mut engine)
+                .map_err(|e| failed(workload, &format!("retrying write {}", at), e))?;
+            rest.apply_to(&mut model);
+        }
+    }
+    check_state(engine.get_mut(), &model).map_err(|e| failed(workload, "after the workload", e))?;
+    report.keys = model.len();
+    Ok(report)
+}
+
+/// Checks that `engine` holds exactly the keys and values of `expected`.
+///
+/// # Errors
+///
+/// Fails with `MyError::StringError` on the first key that differs.
+pub fn check_state<E: KvsEngine>(
+    engine: &mut E,
+    expected: &BTreeMap<String, String>,
+) -> Result<()> {
+    let found: BTreeMap<String, String> = engine.scan(String::new())?.into_iter().collect();
+    let keys = expected.keys().chain(found.keys());
+    for key in keys {
+        if found.get(key) != expected.get(key) {
+            return Err(MyError::StringError(format!(
+                "`{}` holds {:?}, expected {:?}",
+                key,
+                found.get(key),
+                expected.get(key)
+            )));
+        }
+    }
+    for (key, value) in expected {
+        let read = engine.get(key.clone())?;
+        if read.as_ref() != Some(value) {
+            return Err(MyError::StringError(format!(
+                "`{}` reads {:?}, though it scans as {:?}",
+                key, read, value
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Opens the engine again, up to `OPEN_ATTEMPTS` times while it fails with
+/// an IO error, such as the lock of the engine just dropped being held.
+fn reopen<E, F>(open: &mut F) -> Result<E>
+where
+    F: FnMut() -> Result<E>,
+{
+    let mut backoff = OPEN_BACKOFF;
+    for _ in 1..OPEN_ATTEMPTS {
+        match open() {
+            Err(MyError::Io(_)) | Err(MyError::Sled(_)) | Err(MyError::Timeout) => {
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+    open()
+}
+
+fn failed(workload: &Workload, when: &str, e: MyError) -> MyError {
+    MyError::StringError(format!(
+        "Restart check with seed {} failed {}: {}",
+        workload.seed, when, e
+    ))
+}
+
+/// A splitmix64 generator: small, and the same everywhere for a seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number below `n`, which is not 0.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
@@ -0,0 +1,220 @@
+//! TLS over the TCP connections of the protocol, with rustls.
+//!
+//! A connection is shared by the reader and the writer of a client or of a
+//! connection handler, which use clones of its stream: the TLS state sits
+//! behind a lock, which is not held while waiting for the peer to send, so
+//! that writes go on while a read waits.
+use crate::errors::{MyError, Result};
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::ServerConnection;
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Bytes read from the socket at once.
+const READ_BUF_LEN: usize = 16 * 1024;
+
+/// The certificate chain and private key a server proves its identity with,
+/// see `Server::with_tls`.
+#[derive(Clone)]
+pub struct ServerTlsConfig {
+    config: Arc<ServerConfig>,
+}
+
+impl ServerTlsConfig {
+    /// Loads the certificate chain, leaf first, and the private key from
+    /// PEM files.
+    pub fn from_pem_files(cert: &Path, key: &Path) -> Result<ServerTlsConfig> {
+        let certs = CertificateDer::pem_file_iter(cert)
+            .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+            .map_err(|e| pem_error(cert, e))?;
+        let key = PrivateKeyDer::from_pem_file(key).map_err(|e| pem_error(key, e))?;
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        Ok(ServerTlsConfig {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Runs the handshake of a client that connected to a TCP listener.
+    pub(crate) fn accept(&self, tcp: TcpStream) -> Result<TlsStream> {
+        let connection = ServerConnection::new(Arc::clone(&self.config))?;
+        TlsStream::handshake(tcp, connection.into())
+    }
+}
+
+impl fmt::Debug for ServerTlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServerTlsConfig").finish_non_exhaustive()
+    }
+}
+
+/// Which certificates a client trusts, and the name the certificate of the
+/// server must be for, see `KvsClient::connect_tls`.
+#[derive(Clone)]
+pub struct ClientTlsConfig {
+    config: Arc<ClientConfig>,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl ClientTlsConfig {
+    /// Trusts the certificate authorities of a PEM file, or the server's
+    /// self-signed certificate.
+    ///
+    /// The certificate of the server must be for the IP address connected
+    /// to, unless `with_server_name` says otherwise.
+    pub fn from_ca_file(ca: &Path) -> Result<ClientTlsConfig> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(ca).map_err(|e| pem_error(ca, e))? {
+            roots.add(cert.map_err(|e| pem_error(ca, e))?)?;
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(ClientTlsConfig {
+            config: Arc::new(config),
+            server_name: None,
+        })
+    }
+
+    /// Expect a certificate for the DNS name or IP address `name`.
+    pub fn with_server_name(mut self, name: &str) -> Result<Self> {
+        let name = ServerName::try_from(name.to_owned())
+            .map_err(|e| MyError::StringError(format!("Invalid server name {}: {}", name, e)))?;
+        self.server_name = Some(name);
+        Ok(self)
+    }
+
+    /// Runs the handshake with the server at the other end of `tcp`.
+    pub(crate) fn connect(&self, tcp: TcpStream) -> Result<TlsStream> {
+        let name = match &self.server_name {
+            Some(name) => name.clone(),
+            None => ServerName::from(tcp.peer_addr()?.ip()),
+        };
+        let connection = ClientConnection::new(Arc::clone(&self.config), name)?;
+        TlsStream::handshake(tcp, connection.into())
+    }
+}
+
+impl fmt::Debug for ClientTlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ClientTlsConfig")
+            .field("server_name", &self.server_name)
+            .finish_non_exhaustive()
+    }
+}
+
+fn pem_error(path: &Path, e: rustls::pki_types::pem::Error) -> MyError {
+    MyError::StringError(format!("Cannot load {}: {}", path.display(), e))
+}
+
+/// A TCP connection over which the handshake went through.
+#[derive(Debug)]
+pub(crate) struct TlsStream {
+    tcp: TcpStream,
+    connection: Arc<Mutex<Connection>>,
+    /// Taken by a read for as long as it lasts, for the records of two
+    /// reads not to interleave.
+    reading: Arc<Mutex<()>>,
+}
+
+impl TlsStream {
+    fn handshake(mut tcp: TcpStream, mut connection: Connection) -> Result<TlsStream> {
+        while connection.is_handshaking() {
+            connection.complete_io(&mut tcp)?;
+        }
+        Ok(TlsStream {
+            tcp,
+            connection: Arc::new(Mutex::new(connection)),
+            reading: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            tcp: self.tcp.try_clone()?,
+            connection: Arc::clone(&self.connection),
+            reading: Arc::clone(&self.reading),
+        })
+    }
+
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        &self.tcp
+    }
+
+    /// Tells the peer the connection ends, then shuts the socket down.
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            let mut connection = self.connection.lock().unwrap();
+            connection.send_close_notify();
+            // the socket goes down whether the peer got it or not
+            let _ = send_records(&mut connection, &self.tcp);
+        }
+        self.tcp.shutdown(how)
+    }
+}
+
+impl Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let _reading = self.reading.lock().unwrap();
+        let mut records = [0; READ_BUF_LEN];
+        loop {
+            {
+                let mut connection = self.connection.lock().unwrap();
+                match connection.reader().read(buf) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    // the peer closed the socket without a close_notify,
+                    // as clients that exit do
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                    result => return result,
+                }
+            }
+            let len = (&self.tcp).read(&mut records)?;
+            let mut connection = self.connection.lock().unwrap();
+            if len == 0 {
+                return Ok(0);
+            }
+            let mut records = &records[..len];
+            while !records.is_empty() {
+                connection.read_tls(&mut records)?;
+                connection
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+            // e.g. an alert, or the answer to a key update
+            send_records(&mut connection, &self.tcp)?;
+        }
+    }
+}
+
+impl Write for &TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut connection = self.connection.lock().unwrap();
+        let len = connection.writer().write(buf)?;
+        send_records(&mut connection, &self.tcp)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        connection.writer().flush()?;
+        send_records(&mut connection, &self.tcp)
+    }
+}
+
+/// Writes the records `connection` has to send.
+fn send_records(connection: &mut Connection, mut tcp: &TcpStream) -> io::Result<()> {
+    while connection.wants_write() {
+        connection.write_tls(&mut tcp)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,217 @@
+//! The sockets the protocol runs over: TCP, possibly under TLS, and Unix
+//! domain sockets for clients on the same host.
+use crate::tls::TlsStream;
+use std::fmt;
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A connected socket of either kind.
+#[derive(Debug)]
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Tls(TlsStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Tls(stream) => stream.try_clone().map(Stream::Tls),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Tls(stream) => stream.tcp().set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            Stream::Tls(stream) => stream.tcp().set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            Stream::Tls(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    /// Whether the other end closed or reset the connection, checked
+    /// without waiting and without consuming what is there to read.
+    pub(crate) fn is_closed(&self) -> bool {
+        let mut buf = [0; 1];
+        let peeked = match self {
+            Stream::Tcp(stream) => peek(stream, &mut buf),
+            // a close_notify left unread is missed, but the socket
+            // closing after it is not
+            Stream::Tls(stream) => peek(stream.tcp(), &mut buf),
+            // `UnixStream::peek` is not stable
+            #[cfg(unix)]
+            Stream::Unix(stream) => {
+                let flags = libc::MSG_PEEK | libc::MSG_DONTWAIT;
+                let len =
+                    unsafe { libc::recv(stream.as_raw_fd(), buf.as_mut_ptr().cast(), 1, flags) };
+                if len < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(len as usize)
+                }
+            }
+        };
+        match peeked {
+            Ok(len) => len == 0,
+            Err(e) => !matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+            ),
+        }
+    }
+
+    /// The other end, for logs. Unix clients are seldom bound to a path,
+    /// so they are told apart by the socket they came through only.
+    pub(crate) fn peer(&self) -> io::Result<String> {
+        match self {
+            Stream::Tcp(stream) => Ok(stream.peer_addr()?.to_string()),
+            Stream::Tls(stream) => Ok(stream.tcp().peer_addr()?.to_string()),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Ok(match stream.local_addr()?.as_pathname() {
+                Some(path) => format!("unix socket {}", path.display()),
+                None => "unix socket".to_owned(),
+            }),
+        }
+    }
+}
+
+fn peek(stream: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+    stream.set_nonblocking(true)?;
+    let peeked = stream.peek(buf);
+    stream.set_nonblocking(false)?;
+    peeked
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            Stream::Tls(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            Stream::Tls(stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            Stream::Tls(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+/// Where a client finds a server.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Endpoint {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    /// Opens a connection, giving up after `timeout` if any. Connecting to
+    /// a Unix socket does not wait, so the timeout only bounds TCP.
+    pub(crate) fn connect(&self, timeout: Option<Duration>) -> io::Result<Stream> {
+        match self {
+            Endpoint::Tcp(addr) => match timeout {
+                Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+                None => TcpStream::connect(addr),
+            }
+            .map(Stream::Tcp),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => addr.fmt(f),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => path.display().fmt(f),
+        }
+    }
+}
+
+/// Listens on a Unix socket at `path`, first removing the socket file a
+/// server that did not shut down cleanly may have left there. A socket a
+/// live server listens on, or a file of another kind, is left alone.
+#[cfg(unix)]
+pub(crate) fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse && is_stale_socket(path) => {
+            fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        result => result,
+    }
+}
+
+#[cfg(unix)]
+fn is_stale_socket(path: &Path) -> bool {
+    let is_socket = fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+    is_socket && UnixStream::connect(path).is_err()
+}
@@ -0,0 +1,310 @@
+//! Minimal RFC 6455 WebSocket support: the opening handshake plus text,
+//! ping and close frames, which is all browser clients need to speak the
+//! JSON protocol.
+use crate::errors::{MyError, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::io::{BufRead, Read, Write};
+
+/// GUID appended to the client key when computing `Sec-WebSocket-Accept`.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest payload accepted in a single frame.
+const MAX_FRAME_LEN: u64 = 16 * 1024 * 1024;
+
+/// Largest payload of a close, ping or pong frame.
+const MAX_CONTROL_LEN: u64 = 125;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// A complete message received from the peer.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close,
+}
+
+/// Reads the HTTP upgrade request from `reader` and answers it on `writer`.
+///
+/// The request must be a `GET` upgrade of RFC 6455 version 13. Browsers send
+/// the page's `Origin`, which must be one of `origins` (`*` allows any):
+/// otherwise any page could open a connection with the visitor's network
+/// access. Clients outside a browser send no `Origin` and are accepted.
+pub fn accept<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    origins: &[String],
+) -> Result<()> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line)? == 0 {
+        return Err(closed_during_handshake());
+    }
+    let mut key = None;
+    let mut version = None;
+    let mut origin = None;
+    let mut upgrade = false;
+    let mut connection_upgrade = false;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(closed_during_handshake());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.to_owned());
+            } else if name.eq_ignore_ascii_case("sec-websocket-version") {
+                version = Some(value.to_owned());
+            } else if name.eq_ignore_ascii_case("origin") {
+                origin = Some(value.to_owned());
+            } else if name.eq_ignore_ascii_case("upgrade") {
+                upgrade = value.eq_ignore_ascii_case("websocket");
+            } else if name.eq_ignore_ascii_case("connection") {
+                connection_upgrade = value
+                    .split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let is_get = parts.next() == Some("GET")
+        && parts.next().is_some()
+        && parts.next() == Some("HTTP/1.1")
+        && parts.next().is_none();
+    let key = match key {
+        Some(key) if is_get && upgrade && connection_upgrade && valid_key(&key) => key,
+        _ => {
+            return refuse(
+                writer,
+                "400 Bad Request",
+                "",
+                "Not a WebSocket upgrade request",
+            )
+        }
+    };
+    if version.as_deref() != Some("13") {
+        return refuse(
+            writer,
+            "426 Upgrade Required",
+            "Sec-WebSocket-Version: 13\r\n",
+            "Unsupported WebSocket version",
+        );
+    }
+    if let Some(origin) = origin {
+        let allowed = origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(&origin));
+        if !allowed {
+            return refuse(
+                writer,
+                "403 Forbidden",
+                "",
+                &format!("WebSocket origin {} is not allowed", origin),
+            );
+        }
+    }
+
+    write!(
+        writer,
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    )?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Computes the `Sec-WebSocket-Accept` value for a client key.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(HANDSHAKE_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+/// The key of a client is 16 random bytes in base64.
+fn valid_key(key: &str) -> bool {
+    matches!(STANDARD.decode(key), Ok(nonce) if nonce.len() == 16)
+}
+
+fn closed_during_handshake() -> MyError {
+    MyError::StringError("Connection closed during WebSocket handshake".to_owned())
+}
+
+fn refuse<W: Write>(writer: &mut W, status: &str, headers: &str, reason: &str) -> Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\n{}Content-Length: 0\r\n\r\n",
+        status, headers
+    )?;
+    writer.flush()?;
+    Err(MyError::StringError(reason.to_owned()))
+}
+
+/// Reads the messages of a connection. Control frames may arrive between
+/// the fragments of a message, so the fragments read so far are kept
+/// across calls rather than lost when a ping is returned.
+pub struct Reader<R> {
+    reader: R,
+    fragments: Option<(u8, Vec<u8>)>,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(reader: R) -> Self {
+        Reader {
+            reader,
+            fragments: None,
+        }
+    }
+
+    /// Reads the next message, reassembling fragmented text and binary
+    /// frames.
+    ///
+    /// Returns `None` when the peer closed the socket without a close frame.
+    pub fn read_message(&mut self) -> Result<Option<Message>> {
+        loop {
+            let (fin, opcode, payload) = match read_frame(&mut self.reader)? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            match opcode {
+                OP_CLOSE => return Ok(Some(Message::Close)),
+                OP_PING => return Ok(Some(Message::Ping(payload))),
+                OP_PONG => return Ok(Some(Message::Pong(payload))),
+                OP_TEXT | OP_BINARY | OP_CONTINUATION => {
+                    let (opcode, data) = match (self.fragments.take(), opcode) {
+                        (None, OP_CONTINUATION) | (Some(_), OP_TEXT) | (Some(_), OP_BINARY) => {
+                            return Err(protocol_error("Unexpected WebSocket continuation frame"))
+                        }
+                        (None, opcode) => (opcode, payload),
+                        (Some((opcode, mut data)), _) => {
+                            data.extend_from_slice(&payload);
+                            (opcode, data)
+                        }
+                    };
+                    if data.len() as u64 > MAX_FRAME_LEN {
+                        return Err(protocol_error("WebSocket message too large"));
+                    }
+                    if !fin {
+                        self.fragments = Some((opcode, data));
+                        continue;
+                    }
+                    return Ok(Some(if opcode == OP_TEXT {
+                        Message::Text(String::from_utf8(data)?)
+                    } else {
+                        Message::Binary(data)
+                    }));
+                }
+                _ => {
+                    return Err(MyError::StringError(format!(
+                        "Unknown WebSocket opcode {:#x}",
+                        opcode
+                    )))
+                }
+            }
+        }
+    }
+}
+
+/// Writes a single unfragmented text frame.
+pub fn write_text<W: Write>(writer: &mut W, text: &str) -> Result<()> {
+    write_frame(writer, OP_TEXT, text.as_bytes())
+}
+
+/// Answers a ping with a pong carrying the same payload.
+pub fn write_pong<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    write_frame(writer, OP_PONG, payload)
+}
+
+/// Writes an empty close frame.
+pub fn write_close<W: Write>(writer: &mut W) -> Result<()> {
+    write_frame(writer, OP_CLOSE, &[])
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> Result<Option<(bool, u8, Vec<u8>)>> {
+    let mut head = [0u8; 2];
+    match reader.read_exact(&mut head) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    if head[0] & 0x70 != 0 {
+        return Err(protocol_error("Reserved WebSocket frame bits set"));
+    }
+    // Clients must mask every frame, and the server fail the connection
+    // otherwise (RFC 6455 section 5.1)
+    if head[1] & 0x80 == 0 {
+        return Err(protocol_error("Unmasked WebSocket frame from the client"));
+    }
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut buf = [0u8; 2];
+            reader.read_exact(&mut buf)?;
+            u64::from(u16::from_be_bytes(buf))
+        }
+        127 => {
+            let mut buf = [0u8; 8];
+            reader.read_exact(&mut buf)?;
+            u64::from_be_bytes(buf)
+        }
+        len => u64::from(len),
+    };
+    if len > MAX_FRAME_LEN {
+        return Err(protocol_error("WebSocket frame too large"));
+    }
+    if opcode >= OP_CLOSE && (!fin || len > MAX_CONTROL_LEN) {
+        return Err(protocol_error(
+            "Fragmented or oversized WebSocket control frame",
+        ));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Some((fin, opcode, payload)))
+}
+
+fn write_frame<W: Write>(writer: &mut W, opcode: u8, payload: &[u8]) -> Result<()> {
+    let mut head = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => head.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            head.push(126);
+            head.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            head.push(127);
+            head.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    writer.write_all(&head)?;
+    writer.write_all(payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn protocol_error(reason: &str) -> MyError {
+    MyError::StringError(reason.to_owned())
+}
@@ -0,0 +1,91 @@
+use kvs::{AsyncKvsEngine, BlockingEngine, KvStore, MyError, Result, SledKvsEngine};
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake};
+use std::thread::{self, Thread};
+use tempfile::TempDir;
+
+/// Wakes a thread parked in `block_on`.
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Arc::new(Unpark(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+fn exercise(engine: impl AsyncKvsEngine) -> Result<()> {
+    block_on(async {
+        engine.set("key1".to_owned(), "value1".to_owned()).await?;
+        engine.set("key2".to_owned(), "value2".to_owned()).await?;
+        assert_eq!(
+            engine.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        engine.remove("key1".to_owned()).await?;
+        assert_eq!(engine.get("key1".to_owned()).await?, None);
+        assert!(matches!(
+            engine.remove("key1".to_owned()).await,
+            Err(MyError::KeyNotFound)
+        ));
+        assert_eq!(
+            engine.scan("key".to_owned()).await?,
+            vec![("key2".to_owned(), "value2".to_owned())]
+        );
+
+        // calls run concurrently with their caller
+        let writes: Vec<_> = (0..10)
+            .map(|i| engine.set(format!("other{}", i), format!("{}", i)))
+            .collect();
+        for write in writes {
+            write.await?;
+        }
+        assert_eq!(engine.scan("other".to_owned()).await?.len(), 10);
+        Ok(())
+    })
+}
+
+#[test]
+fn blocking_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    exercise(BlockingEngine::new(KvStore::open(temp_dir.path())?))
+}
+
+// A burst of calls should wait for the few threads rather than start more.
+#[test]
+fn blocking_engine_bounded_threads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = BlockingEngine::with_threads(KvStore::open(temp_dir.path())?, 2);
+    block_on(async {
+        let writes: Vec<_> = (0..500)
+            .map(|i| engine.set(format!("key{}", i), format!("{}", i)))
+            .collect();
+        for write in writes {
+            write.await?;
+        }
+        let reads: Vec<_> = (0..500).map(|i| engine.get(format!("key{}", i))).collect();
+        for (i, read) in reads.into_iter().enumerate() {
+            assert_eq!(read.await?, Some(format!("{}", i)));
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn blocking_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    exercise(BlockingEngine::new(SledKvsEngine::open(temp_dir.path())?))
+}
@@ -1,8 +1,9 @@
 use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsClient, KvsEngine, SledKvsEngine, WriteBatch};
 use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -21,28 +22,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -53,35 +54,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -92,28 +93,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -124,7 +125,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -135,7 +136,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -146,7 +147,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -158,13 +159,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stdout(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -179,15 +181,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "titit", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "titit", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -198,15 +201,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "qqq", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "qqq", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -218,19 +222,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -238,7 +243,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -246,7 +251,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -254,7 +259,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -262,7 +267,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -270,7 +275,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -278,7 +283,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -286,7 +291,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -299,26 +304,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -336,3 +342,842 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_memory_engine() {
+    let addr = "127.0.0.1:4039";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key2", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+    // nothing is written to disk
+    assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+}
+
+#[test]
+fn cli_auth_token() {
+    let addr = "127.0.0.1:4017";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr, "--auth-token", "secret"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Unauthorized"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "set",
+            "key1",
+            "value1",
+            "--addr",
+            addr,
+            "--auth-token",
+            "wrong",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Unauthorized"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "set",
+            "key1",
+            "value1",
+            "--addr",
+            addr,
+            "--auth-token",
+            "secret",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--auth-token", "secret"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_acl() {
+    let addr = "127.0.0.1:4018";
+    let temp_dir = TempDir::new().unwrap();
+    let acl = temp_dir.path().join("acl.toml");
+    fs::write(
+        &acl,
+        r#"
+[[token]]
+token = "reader"
+operations = ["read"]
+prefixes = ["app1:*"]
+
+[[token]]
+token = "writer"
+operations = ["read", "write"]
+prefixes = ["app1:"]
+"#,
+    )
+    .unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr])
+        .arg("--acl")
+        .arg(&acl)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str], token: &str| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", addr, "--auth-token", token])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    client(&["set", "app1:key", "value"], "writer")
+        .assert()
+        .success();
+    client(&["get", "app1:key"], "reader")
+        .assert()
+        .success()
+        .stdout(contains("value"));
+    client(&["set", "app1:key", "other"], "reader")
+        .assert()
+        .failure()
+        .stderr(contains("Permission denied"));
+    client(&["get", "app2:key"], "reader")
+        .assert()
+        .failure()
+        .stderr(contains("Permission denied"));
+    client(&["get", "app1:key"], "unknown")
+        .assert()
+        .failure()
+        .stderr(contains("Unauthorized"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_stats() {
+    let addr = "127.0.0.1:4022";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for key in &["key1", "key2", "key1"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, "value", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 2").and(contains("segments: 1")));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_slow_log() {
+    let addr = "127.0.0.1:4023";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr, "--slow-log-ms", "0"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["slowlog", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Set with a 4 byte key took"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_json_logs() {
+    let addr = "127.0.0.1:4024";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr, "--log-format", "json"])
+        .current_dir(&temp_dir)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(200));
+
+    child.kill().expect("server exited before killed");
+    let output = child.wait_with_output().expect("failed to wait on server");
+    let lines: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("log line is not JSON"))
+        .collect();
+    // logged within the spans of the connection and of the request
+    assert!(lines.iter().any(|line| {
+        let message = line["message"].as_str().unwrap_or_default();
+        let spans = line["spans"].as_array().cloned().unwrap_or_default();
+        message.starts_with("Set handled in")
+            && spans.len() == 2
+            && spans[0]["name"] == "connection"
+            && spans[0]["peer"].as_str().is_some()
+            && spans[1]["name"] == "request"
+            && spans[1]["kind"] == "Set"
+            && line["span"]["id"].as_str().is_some()
+    }));
+}
+
+#[test]
+fn cli_config_file() {
+    let addr = "127.0.0.1:4025";
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config,
+        r#"
+# flags given on the command line win
+addr = "127.0.0.1:4099"
+engine = "kvs"
+auth_token = "${KVS_TEST_TOKEN}"
+sync_policy = "always"
+compaction_windows = [
+    '22:00-04:00',  # literal strings and trailing commas are TOML too
+]
+"#,
+    )
+    .unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr])
+        .arg("--config")
+        .arg(&config)
+        .env("KVS_TEST_TOKEN", "secret")
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Unauthorized"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .args(["--auth-token", "secret"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    fs::write(&config, "adr = \"127.0.0.1:4000\"\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown field `adr`"));
+}
+
+#[test]
+fn cli_data_dir() {
+    let addr = "127.0.0.1:4026";
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr])
+        .arg("--data-dir")
+        .arg(&data_dir)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+    assert!(data_dir.join("log.json").exists());
+    assert!(!temp_dir.path().join("log.json").exists());
+}
+
+#[test]
+fn cli_mset() {
+    let addr = "127.0.0.1:4037";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mset", "key1", "value1", "key2", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("value2"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mset", "key3", "value3", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Every key needs a value"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// `kvs-fsck` should report a record failing its checksum, and drop it with
+// `--repair`.
+#[test]
+fn cli_fsck() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    Command::cargo_bin("kvs-fsck")
+        .unwrap()
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("locked"));
+    drop(store);
+
+    Command::cargo_bin("kvs-fsck")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("records: 2").and(contains("corrupt records: 0")));
+
+    let log = temp_dir.path().join("log.json");
+    let data = fs::read_to_string(&log)?.replace("value1", "valueX");
+    fs::write(&log, data)?;
+    Command::cargo_bin("kvs-fsck")
+        .unwrap()
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stdout(contains("checksum mismatch"));
+    Command::cargo_bin("kvs-fsck")
+        .unwrap()
+        .args(["--repair"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("dropped 1 corrupt records"));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// `kvs-migrate` should copy every string key into a store of another engine,
+// over several pages, and refuse to copy into a store holding keys.
+#[test]
+fn cli_migrate() -> kvs::Result<()> {
+    let src_dir = TempDir::new().unwrap();
+    let dst_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(src_dir.path())?;
+    for i in 0..1500 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    store.rpush("list".to_owned(), "item".to_owned())?;
+    drop(store);
+
+    let migrate = |dst: &TempDir| {
+        let mut cmd = Command::cargo_bin("kvs-migrate").unwrap();
+        cmd.args(["--from", "kvs", "--to", "sled", "--src"])
+            .arg(src_dir.path())
+            .arg("--dst")
+            .arg(dst.path());
+        cmd
+    };
+    migrate(&dst_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("keys: 1500")
+                .and(contains("verified: 1500"))
+                .and(contains("skipped: 1")),
+        )
+        .stderr(contains("copied 1000 keys"));
+    migrate(&dst_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already holds keys"));
+
+    let mut sled = SledKvsEngine::open(dst_dir.path())?;
+    assert_eq!(
+        sled.get("key1499".to_owned())?,
+        Some("value1499".to_owned())
+    );
+    assert_eq!(sled.get("list".to_owned())?, None);
+    Ok(())
+}
+
+// `kvs-dump` should list every write, batched or not, filtered by key.
+#[test]
+fn cli_dump() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("seq 1      Set     key1 (6 bytes) checksum Ok")
+                .and(contains("Set     [batch] key2"))
+                .and(contains("seq 3      Remove  [batch] key1")),
+        );
+
+    let output = Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .args(["--json", "--key", "key1"])
+        .arg(temp_dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let lines: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["kind"], "Set");
+    assert_eq!(lines[0]["value_len"], 6);
+    assert_eq!(lines[1]["kind"], "Remove");
+    assert_eq!(lines[1]["batch"], true);
+    assert_eq!(lines[1]["checksum"], "ok");
+
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .arg("--report")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("NAMESPACE").and(contains("(total)")));
+
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .args(["--at", "1"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("key1 value1").and(contains("key2").not()));
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .args(["--at", "3", "--key", "key1"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    Ok(())
+}
+
+#[test]
+fn cli_bench() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args([
+            "--engine",
+            "sled",
+            "--ops",
+            "200",
+            "--keys",
+            "20",
+            "--threads",
+            "2",
+        ])
+        .arg("--data-dir")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(
+            contains("operations: 200")
+                .and(contains("throughput:"))
+                .and(contains("latency: p50")),
+        );
+
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args([
+            "--ops",
+            "100",
+            "--keys",
+            "20",
+            "--load-batch",
+            "10",
+            "--write-buffer",
+            "adaptive",
+        ])
+        .arg("--data-dir")
+        .arg(temp_dir.path().join("kvs"))
+        .assert()
+        .success()
+        .stdout(contains("load: 20 keys").and(contains("write buffer: 4096 bytes")));
+
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--read-percent", "101"])
+        .assert()
+        .failure();
+}
+
+// `kvs-client ping` should answer without a token, even from a server that
+// requires one.
+#[test]
+fn cli_ping() {
+    let addr = "127.0.0.1:4058";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--auth-token", "secret", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ping", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(format!(
+            "PONG from kvs {}",
+            env!("CARGO_PKG_VERSION")
+        )))
+        .stdout(contains("(sled engine)"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A daemonized server should return to the shell at once, log to its log
+// file, and keep its PID file for as long as it runs.
+#[cfg(unix)]
+#[test]
+fn cli_daemonize() {
+    let addr = "127.0.0.1:4064";
+    let temp_dir = TempDir::new().unwrap();
+    let pid_file = temp_dir.path().join("kvs.pid");
+    let log_file = temp_dir.path().join("kvs.log");
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--daemonize"])
+        .arg("--pid-file")
+        .arg(&pid_file)
+        .arg("--log-file")
+        .arg(&log_file)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    thread::sleep(Duration::from_secs(1));
+
+    let pid = fs::read_to_string(&pid_file).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(fs::read_to_string(&log_file)
+        .unwrap()
+        .contains("Starting up"));
+
+    // a second server refuses to start over the PID file of the first
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4065"])
+        .arg("--pid-file")
+        .arg(&pid_file)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already running"));
+
+    Command::new("kill").arg(pid.trim()).assert().success();
+    thread::sleep(Duration::from_secs(1));
+    assert!(!pid_file.exists());
+}
+
+// `kvs-client flushall` should only remove every key with `--yes`.
+#[test]
+fn cli_flushall() {
+    let addr = "127.0.0.1:4066";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for key in ["key1", "key2"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", key, "value", "--addr", addr])
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["flushall", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("--yes"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains("value"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["flushall", "--yes", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// `--output json` should print one parseable result, and `--output quiet`
+// nothing, both exiting with 2 for a missing key.
+#[test]
+fn cli_output_formats() {
+    let addr = "127.0.0.1:4078";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let json = |args: &[&str]| -> serde_json::Value {
+        let output = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr, "--output", "json"])
+            .output()
+            .unwrap();
+        serde_json::from_slice(&output.stdout).unwrap()
+    };
+    assert_eq!(
+        json(&["set", "key1", "value1"]),
+        serde_json::json!({ "key": "key1" })
+    );
+    assert_eq!(
+        json(&["get", "key1"]),
+        serde_json::json!({ "key": "key1", "value": "value1", "found": true })
+    );
+    assert_eq!(json(&["keys", "*"]), serde_json::json!(["key1"]));
+    assert_eq!(json(&["dbsize"]), serde_json::json!({ "keys": 1 }));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", addr, "--output", "json"])
+        .assert()
+        .code(2)
+        .stdout(contains(r#""found":false"#));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--output", "json", "rm", "key2", "--addr", addr])
+        .assert()
+        .code(1)
+        .stderr(contains(r#"{"error":"Key not found"}"#));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr, "--output", "quiet"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["exists", "key2", "--addr", addr, "--output", "quiet"])
+        .assert()
+        .code(2)
+        .stdout(is_empty());
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// `kvs-client watch` should print the changes under its prefix as they
+// happen, and exit after `--count` of them.
+#[test]
+fn cli_watch() {
+    let addr = "127.0.0.1:4079";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let watch = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "app:", "--count", "2", "--addr", addr])
+        .args(["--output", "json"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("app:a".to_owned(), "value1".to_owned()).unwrap();
+    client.set("other".to_owned(), "value2".to_owned()).unwrap();
+    client.remove("app:a".to_owned()).unwrap();
+
+    let output = watch.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let events: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            serde_json::json!({ "event": "set", "key": "app:a", "value": "value1" }),
+            serde_json::json!({ "event": "removed", "key": "app:a" }),
+        ]
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
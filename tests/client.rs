@@ -0,0 +1,1573 @@
+use bytes::Bytes;
+use kvs::{
+    AuditRecord, Codec, Compression, ErrorCode, Event, KvStore, KvsClient, KvsEngine, KvsPool,
+    MyError, Result, RetryPolicy, Server, ServerState, WarmUp, PROTOCOL_VERSION,
+};
+use std::fs;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::{free_addr, server_command, spawn_server, wait_until};
+
+// A client with a retry policy should wait for a server that is still starting.
+#[test]
+fn connect_retries_until_server_is_up() {
+    let addr = free_addr();
+    let temp_dir = TempDir::new().unwrap();
+
+    assert!(KvsClient::connect(&addr).is_err());
+
+    let mut command = server_command(&temp_dir, &["--addr", &addr]);
+    let server = thread::spawn(move || {
+        // the client is already retrying by then
+        thread::sleep(Duration::from_millis(500));
+        command.spawn().unwrap()
+    });
+
+    let policy =
+        RetryPolicy::new(50).with_backoff(Duration::from_millis(50), Duration::from_millis(200));
+    let mut client = KvsClient::builder()
+        .with_retry_policy(policy)
+        .connect(&addr)
+        .unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get_bytes("key1".to_owned()).unwrap(),
+        Some(Bytes::from("value1"))
+    );
+
+    let mut child = server.join().unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Threads sharing a pool should each get a connection of their own.
+#[test]
+fn pool_shared_between_threads() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let pool = KvsPool::new(addr, 2).unwrap();
+    let workers: Vec<_> = (0..8)
+        .map(|i| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                let mut client = pool.checkout().unwrap();
+                client.set(format!("key{}", i), format!("{}", i)).unwrap();
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    let first = pool.checkout().unwrap();
+    first.discard();
+    let mut client = pool.checkout().unwrap();
+    let _second = pool.checkout().unwrap();
+    for i in 0..8 {
+        assert_eq!(
+            client.get(format!("key{}", i)).unwrap(),
+            Some(format!("{}", i))
+        );
+    }
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A server that accepts but never answers should make requests time out
+// instead of hanging.
+#[test]
+fn read_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let result = KvsClient::builder()
+        .with_connect_timeout(Duration::from_secs(1))
+        .with_read_timeout(Duration::from_millis(200))
+        .connect(listener.local_addr().unwrap());
+
+    // the handshake is the first request left unanswered
+    match result {
+        Err(MyError::Timeout) => {}
+        Err(e) => panic!("expected a timeout, got {:?}", e),
+        Ok(_) => panic!("expected a timeout"),
+    }
+}
+
+// Pipelined requests should be answered in the order they were queued,
+// with a failed request not affecting the others.
+#[test]
+fn pipeline() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let replies = client
+        .pipeline()
+        .set("key1".to_owned(), "value1".to_owned())
+        .get("key1".to_owned())
+        .remove("key2".to_owned())
+        .get("key2".to_owned())
+        .execute()
+        .unwrap();
+    assert_eq!(replies.len(), 4);
+    assert_eq!(replies[0].as_ref().unwrap(), &None);
+    assert_eq!(replies[1].as_ref().unwrap(), &Some("value1".to_owned()));
+    assert!(replies[2].is_err());
+    assert_eq!(replies[3].as_ref().unwrap(), &None);
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// The handshake should report the negotiated version and the server's
+// capabilities, and be answered before authentication.
+#[test]
+fn handshake() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(
+        &temp_dir,
+        &["--addr", addr, "--engine", "sled", "--auth-token", "secret"],
+    );
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let info = client.server_info();
+    assert_eq!(info.version, PROTOCOL_VERSION);
+    assert_eq!(info.engine, "sled");
+    assert!(info.auth_required);
+    assert!(client.get("key1".to_owned()).is_err());
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A client asking for MessagePack should get it and work as with JSON.
+#[test]
+fn msgpack_codec() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let builder = KvsClient::builder().with_codec(Codec::MessagePack);
+    let mut client = builder.connect(addr).unwrap();
+    assert_eq!(client.server_info().codec, Codec::MessagePack);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        client.get_bytes("key1".to_owned()).unwrap(),
+        Some(Bytes::from("value1"))
+    );
+    assert_eq!(client.get_bytes("key2".to_owned()).unwrap(), None);
+    assert!(client.remove("key2".to_owned()).is_err());
+    assert_eq!(client.stats().unwrap().key_count, 1);
+
+    let mut events = builder
+        .connect(addr)
+        .unwrap()
+        .subscribe(String::new())
+        .unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(
+        events.next().unwrap().unwrap(),
+        Event::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned()
+        }
+    );
+
+    let json = KvsClient::connect(addr).unwrap();
+    assert_eq!(json.server_info().codec, Codec::Json);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Server errors should come back as the matching `MyError` variants.
+#[test]
+fn typed_errors() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr, "--auth-token", "secret"]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    match client.get("key1".to_owned()) {
+        Err(MyError::Unauthorized) => {}
+        other => panic!("expected Unauthorized, got {:?}", other),
+    }
+
+    let mut client = KvsClient::connect_with_auth(addr, "secret".to_owned()).unwrap();
+    match client.remove("key1".to_owned()) {
+        Err(MyError::KeyNotFound) => {}
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A multi-get should answer every key in order, and be refused as a whole
+// if the ACL forbids any of them.
+#[test]
+fn get_many() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let acl = temp_dir.path().join("acl.toml");
+    fs::write(
+        &acl,
+        r#"
+[[token]]
+token = "reader"
+operations = ["read"]
+prefixes = ["app1:"]
+"#,
+    )
+    .unwrap();
+    let mut child = spawn_server(
+        &temp_dir,
+        &[
+            "--addr",
+            addr,
+            "--auth-token",
+            "secret",
+            "--acl",
+            "acl.toml",
+        ],
+    );
+
+    let mut client = KvsClient::connect_with_auth(addr, "secret".to_owned()).unwrap();
+    client
+        .set("app1:a".to_owned(), "value1".to_owned())
+        .unwrap();
+    client
+        .set("app2:b".to_owned(), "value2".to_owned())
+        .unwrap();
+    let keys = vec![
+        "app2:b".to_owned(),
+        "app1:x".to_owned(),
+        "app1:a".to_owned(),
+    ];
+    assert_eq!(
+        client.get_many(keys.clone()).unwrap(),
+        vec![Some("value2".to_owned()), None, Some("value1".to_owned())]
+    );
+
+    let mut reader = KvsClient::connect_with_auth(addr, "reader".to_owned()).unwrap();
+    match reader.get_many(keys) {
+        Err(MyError::PermissionDenied) => {}
+        other => panic!("expected PermissionDenied, got {:?}", other),
+    }
+    assert_eq!(
+        reader
+            .get_many(vec!["app1:a".to_owned(), "app1:x".to_owned()])
+            .unwrap(),
+        vec![Some("value1".to_owned()), None]
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Keys set in a bucket should only be visible to connections that select it.
+#[test]
+fn select_bucket() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::builder()
+        .with_db("a".to_owned())
+        .connect(addr)
+        .unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert!(temp_dir.path().join("buckets").join("a").is_dir());
+
+    client.select("default".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    client.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    client.select("b".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+
+    let mut other = KvsClient::connect(addr).unwrap();
+    other.select("a".to_owned()).unwrap();
+    assert_eq!(
+        other.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(other.select("../a".to_owned()).is_err());
+    assert_eq!(
+        other.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Keys set with a TTL should read as removed once it elapses, and be swept
+// in the background, which subscribers hear about.
+#[test]
+fn expiring_keys() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let mut events = KvsClient::connect(addr)
+        .unwrap()
+        .subscribe(String::new())
+        .unwrap();
+    client
+        .set_with_ttl(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            Duration::from_millis(200),
+        )
+        .unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+
+    events.next().unwrap().unwrap();
+    assert!(matches!(
+        events.next().unwrap().unwrap(),
+        Event::Expiry { key, expires_at_ms: Some(_) } if key == "key1"
+    ));
+    events.next().unwrap().unwrap();
+    assert_eq!(
+        events.next().unwrap().unwrap(),
+        Event::Removed {
+            key: "key1".to_owned()
+        }
+    );
+    assert_eq!(client.stats().unwrap().key_count, 1);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A server started with --index should look keys up by JSON fields.
+#[test]
+fn find_by_index() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr, "--index", "$.email"]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client
+        .set(
+            "user1".to_owned(),
+            r#"{"email":"a@example.com"}"#.to_owned(),
+        )
+        .unwrap();
+    client
+        .set(
+            "user2".to_owned(),
+            r#"{"email":"b@example.com"}"#.to_owned(),
+        )
+        .unwrap();
+    assert_eq!(
+        client
+            .find_by_index("$.email".to_owned(), "b@example.com".to_owned())
+            .unwrap(),
+        vec!["user2".to_owned()]
+    );
+    assert!(client
+        .find_by_index("$.name".to_owned(), "a".to_owned())
+        .is_err());
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// JSON path commands should read and patch values on the server.
+#[test]
+fn json_paths() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client
+        .set(
+            "user1".to_owned(),
+            r#"{"address":{"city":"Paris"}}"#.to_owned(),
+        )
+        .unwrap();
+    client
+        .set_path(
+            "user1".to_owned(),
+            "$.address.city".to_owned(),
+            r#""Lyon""#.to_owned(),
+        )
+        .unwrap();
+    assert_eq!(
+        client
+            .get_path("user1".to_owned(), "$.address".to_owned())
+            .unwrap(),
+        Some(r#"{"city":"Lyon"}"#.to_owned())
+    );
+    assert!(matches!(
+        client.set_path("user2".to_owned(), "$.a".to_owned(), "1".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// List commands should work as a queue over the network.
+#[test]
+fn lists() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.rpush("jobs".to_owned(), "b".to_owned()).unwrap(), 1);
+    assert_eq!(client.lpush("jobs".to_owned(), "a".to_owned()).unwrap(), 2);
+    assert_eq!(
+        client.lrange("jobs".to_owned(), 0, -1).unwrap(),
+        vec!["a".to_owned(), "b".to_owned()]
+    );
+    assert_eq!(
+        client.lpop("jobs".to_owned()).unwrap(),
+        Some("a".to_owned())
+    );
+    assert_eq!(
+        client.lpop("jobs".to_owned()).unwrap(),
+        Some("b".to_owned())
+    );
+    assert_eq!(client.lpop("jobs".to_owned()).unwrap(), None);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Hash commands should read and write single fields over the network.
+#[test]
+fn hashes() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client
+        .hset("user1".to_owned(), "name".to_owned(), "Ann".to_owned())
+        .unwrap();
+    client
+        .hset("user1".to_owned(), "city".to_owned(), "Paris".to_owned())
+        .unwrap();
+    assert_eq!(
+        client.hget("user1".to_owned(), "name".to_owned()).unwrap(),
+        Some("Ann".to_owned())
+    );
+    client.hdel("user1".to_owned(), "name".to_owned()).unwrap();
+    assert_eq!(
+        client.hgetall("user1".to_owned()).unwrap(),
+        vec![("city".to_owned(), "Paris".to_owned())]
+    );
+    assert!(matches!(
+        client.hdel("user1".to_owned(), "name".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Set commands should report membership over the network.
+#[test]
+fn sets() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(client.sadd("tags".to_owned(), "rust".to_owned()).unwrap());
+    assert!(client.sadd("tags".to_owned(), "db".to_owned()).unwrap());
+    assert!(!client.sadd("tags".to_owned(), "db".to_owned()).unwrap());
+    assert!(client
+        .sismember("tags".to_owned(), "rust".to_owned())
+        .unwrap());
+    assert!(client.srem("tags".to_owned(), "rust".to_owned()).unwrap());
+    assert!(!client.srem("tags".to_owned(), "rust".to_owned()).unwrap());
+    assert_eq!(client.smembers("tags".to_owned()).unwrap(), vec!["db"]);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Sorted set commands should range over scores over the network.
+#[test]
+fn sorted_sets() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(client
+        .zadd("board".to_owned(), "ann".to_owned(), 3.0)
+        .unwrap());
+    assert!(client
+        .zadd("board".to_owned(), "bob".to_owned(), 1.5)
+        .unwrap());
+    assert!(!client
+        .zadd("board".to_owned(), "ann".to_owned(), -1.0)
+        .unwrap());
+    assert_eq!(
+        client
+            .zrange_by_score("board".to_owned(), -1.0, 2.0)
+            .unwrap(),
+        vec![("ann".to_owned(), -1.0), ("bob".to_owned(), 1.5)]
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A rename should move the value over the network.
+#[test]
+fn rename() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.rename("key1".to_owned(), "key2".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    assert_eq!(
+        client.get("key2".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(matches!(
+        client.rename("key1".to_owned(), "key3".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+
+    // the expiry moves along, and subscribers hear of it
+    let mut events = KvsClient::connect(addr)
+        .unwrap()
+        .subscribe(String::new())
+        .unwrap();
+    client
+        .set_with_ttl(
+            "temp".to_owned(),
+            "value".to_owned(),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+    client
+        .rename("temp".to_owned(), "moved".to_owned())
+        .unwrap();
+    assert!(client.ttl("moved".to_owned()).unwrap().is_some());
+    for _ in 0..4 {
+        events.next().unwrap().unwrap();
+    }
+    assert!(matches!(
+        events.next().unwrap().unwrap(),
+        Event::Expiry { key, expires_at_ms: Some(_) } if key == "moved"
+    ));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// GetSet and GetDel should answer with the value the key had before them.
+#[test]
+fn get_set_and_get_del() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        client
+            .get_set("key1".to_owned(), "value1".to_owned())
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        client
+            .get_set("key1".to_owned(), "value2".to_owned())
+            .unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        client.get_del("key1".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    assert_eq!(client.get_del("key1".to_owned()).unwrap(), None);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// SetNx should only write keys holding no value, of any kind.
+#[test]
+fn set_nx() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(client
+        .set_nx("key1".to_owned(), "value1".to_owned())
+        .unwrap());
+    assert!(!client
+        .set_nx("key1".to_owned(), "value2".to_owned())
+        .unwrap());
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    client.rpush("list".to_owned(), "a".to_owned()).unwrap();
+    assert!(!client
+        .set_nx("list".to_owned(), "value".to_owned())
+        .unwrap());
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// TTLs and timestamps should be readable, and TTLs changeable, over the
+// network.
+#[test]
+fn ttl_and_persist() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(client.ttl("key1".to_owned()).unwrap(), None);
+    let meta = client.metadata("key1".to_owned()).unwrap();
+    assert!(meta.created.is_some());
+    assert_eq!(meta.updated, meta.created);
+    assert!(matches!(
+        client.metadata("key2".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+    client
+        .expire("key1".to_owned(), Duration::from_secs(60))
+        .unwrap();
+    assert!(client.ttl("key1".to_owned()).unwrap().is_some());
+    client.persist("key1".to_owned()).unwrap();
+    assert_eq!(client.ttl("key1".to_owned()).unwrap(), None);
+    assert!(matches!(
+        client.ttl("key2".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+    let keys = vec!["key1".to_owned(), "key2".to_owned()];
+    assert_eq!(client.touch(keys, Duration::from_secs(60)).unwrap(), 1);
+    assert!(client.ttl("key1".to_owned()).unwrap().is_some());
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Conditional writes should report conflicts over the network.
+#[test]
+fn set_if_version() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let version = client
+        .set_if_version("key1".to_owned(), 0, "value1".to_owned())
+        .unwrap();
+    assert_eq!(client.metadata("key1".to_owned()).unwrap().version, version);
+    assert!(matches!(
+        client.set_if_version("key1".to_owned(), 0, "value2".to_owned()),
+        Err(MyError::Conflict(_))
+    ));
+    client
+        .set_if_version("key1".to_owned(), version, "value2".to_owned())
+        .unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Keys should be checked and counted over the network, per bucket.
+#[test]
+fn exists_and_db_size() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.lpush("list".to_owned(), "item".to_owned()).unwrap();
+    assert!(client.exists("key1".to_owned()).unwrap());
+    assert!(client.exists("list".to_owned()).unwrap());
+    assert!(!client.exists("key2".to_owned()).unwrap());
+    assert_eq!(client.db_size().unwrap(), 2);
+
+    client.select("bucket".to_owned()).unwrap();
+    assert!(!client.exists("key1".to_owned()).unwrap());
+    assert_eq!(client.db_size().unwrap(), 0);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Only the keys matching a pattern should come back from the server.
+#[test]
+fn keys_and_scan() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client
+        .set("session:1:active".to_owned(), "alice".to_owned())
+        .unwrap();
+    client
+        .set("session:2:idle".to_owned(), "bob".to_owned())
+        .unwrap();
+    client.set("user:1".to_owned(), "alice".to_owned()).unwrap();
+    assert_eq!(
+        client.keys("session:*".to_owned()).unwrap(),
+        vec!["session:1:active", "session:2:idle"]
+    );
+    let keys: Vec<String> = client
+        .scan_iter("*:active".to_owned())
+        .collect::<Result<_>>()
+        .unwrap();
+    assert_eq!(keys, vec!["session:1:active"]);
+    assert!(client.keys("[".to_owned()).is_err());
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A scan should walk the keys a page at a time and return each one once,
+// although compactions rewrite the log between pages.
+#[test]
+fn scan_pages() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr, "--compaction-threshold", "1"]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for i in 0..25 {
+        client
+            .set(format!("key{:02}", i), "value".to_owned())
+            .unwrap();
+    }
+    client.set("other".to_owned(), "value".to_owned()).unwrap();
+
+    let mut keys = Vec::new();
+    let mut cursor = None;
+    loop {
+        let (page, next) = client.scan(cursor, 10, "key*".to_owned()).unwrap();
+        assert!(page.len() <= 10);
+        keys.extend(page);
+        // overwrites leave stale records behind, compacting the log
+        client
+            .set("key00".to_owned(), "new value".to_owned())
+            .unwrap();
+        match next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    let expected: Vec<String> = (0..25).map(|i| format!("key{:02}", i)).collect();
+    assert_eq!(keys, expected);
+
+    let keys: Vec<String> = client
+        .scan_iter("key1?".to_owned())
+        .collect::<Result<_>>()
+        .unwrap();
+    assert_eq!(keys.len(), 10);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// An export should stream every matching pair over several frames, and
+// leave the connection usable when dropped before its end.
+#[test]
+fn export_stream() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let pairs: Vec<(String, String)> = (0..2500)
+        .map(|i| (format!("key{:04}", i), format!("value{}", i)))
+        .collect();
+    client.set_many(pairs.clone()).unwrap();
+    client.set("other".to_owned(), "value".to_owned()).unwrap();
+    client
+        .lpush("key_list".to_owned(), "item".to_owned())
+        .unwrap();
+
+    let exported: Vec<(String, String)> = client
+        .export("key*".to_owned())
+        .unwrap()
+        .collect::<Result<_>>()
+        .unwrap();
+    assert_eq!(exported, pairs);
+
+    let first = client.export("*".to_owned()).unwrap().next();
+    assert_eq!(first.unwrap().unwrap(), pairs[0]);
+    assert_eq!(
+        client.get("other".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    assert!(client
+        .export("[".to_owned())
+        .unwrap()
+        .next()
+        .unwrap()
+        .is_err());
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A lock should have one holder at a time, until released or expired.
+#[test]
+fn locks() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let mut other = KvsClient::connect(addr).unwrap();
+    let ttl = Duration::from_secs(30);
+    let mut lock = client
+        .acquire_lock("lock1".to_owned(), ttl)
+        .unwrap()
+        .unwrap();
+    assert!(other
+        .acquire_lock("lock1".to_owned(), ttl)
+        .unwrap()
+        .is_none());
+    lock.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert!(!other
+        .release_lock("lock1".to_owned(), "wrong".to_owned())
+        .unwrap());
+    let token = lock.token().to_owned();
+    assert!(lock.release().unwrap());
+    assert!(!client.release_lock("lock1".to_owned(), token).unwrap());
+
+    // dropping the guard releases the lock
+    let lock = other.acquire_lock("lock1".to_owned(), ttl).unwrap();
+    assert!(lock.is_some());
+    drop(lock);
+    let lock = client.acquire_lock("lock1".to_owned(), ttl).unwrap();
+    assert!(lock.is_some());
+    lock.unwrap().into_token();
+
+    // a holder that went away loses the lock once it expires
+    let short = Duration::from_millis(100);
+    let token = client
+        .acquire_lock("lock2".to_owned(), short)
+        .unwrap()
+        .unwrap()
+        .into_token();
+    thread::sleep(Duration::from_millis(300));
+    assert!(other
+        .acquire_lock("lock2".to_owned(), ttl)
+        .unwrap()
+        .is_some());
+    assert!(!client.release_lock("lock2".to_owned(), token).unwrap());
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A server with an interval should take snapshots on schedule, keeping the
+// most recent ones, and report them in its stats.
+#[test]
+fn scheduled_snapshots() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(
+        &temp_dir,
+        &[
+            "--addr",
+            addr,
+            "--snapshot-interval",
+            "1",
+            "--snapshot-retain",
+            "2",
+        ],
+    );
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    wait_until(|| client.stats().unwrap().snapshots >= 3);
+    let stats = client.stats().unwrap();
+    assert!(stats.last_snapshot.is_some());
+    assert_eq!(stats.last_snapshot_error, None);
+
+    // one may be in progress under a temporary name
+    let mut snapshots: Vec<_> = fs::read_dir(temp_dir.path().join("snapshots"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_none())
+        .collect();
+    snapshots.sort();
+    assert_eq!(snapshots.len(), 2);
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    let mut store = KvStore::open(snapshots.pop().unwrap()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// Stats should report latency percentiles of every type of request served.
+#[test]
+fn latency_stats() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for i in 0..20 {
+        client.set(format!("key{}", i), "value".to_owned()).unwrap();
+        client.get(format!("key{}", i)).unwrap();
+    }
+    client.remove("key0".to_owned()).unwrap();
+    let latency = client.stats().unwrap().latency;
+    assert_eq!(latency["Set"].count, 20);
+    assert_eq!(latency["Get"].count, 20);
+    assert_eq!(latency["Remove"].count, 1);
+    let set = &latency["Set"];
+    assert!(set.service.p50 <= set.service.p95);
+    assert!(set.service.p95 <= set.service.p99);
+    assert!(set.service.p99 <= set.service.max);
+    assert!(set.queue.max <= set.service.max);
+    assert!(set.engine.max <= set.service.max);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+fn audit_records(path: &std::path::Path) -> Vec<AuditRecord> {
+    fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+// Every write should be recorded in the audit log with who made it, failed
+// ones with their error, and reads should not.
+#[test]
+fn audit_log() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let acl = temp_dir.path().join("acl.toml");
+    fs::write(
+        &acl,
+        r#"
+[[token]]
+name = "app1-writer"
+token = "writer"
+operations = ["read", "write"]
+prefixes = ["app1:"]
+"#,
+    )
+    .unwrap();
+    let audit_log = temp_dir.path().join("audit.log");
+    let mut child = spawn_server(
+        &temp_dir,
+        &[
+            "--addr",
+            addr,
+            "--auth-token",
+            "secret",
+            "--acl",
+            "acl.toml",
+            "--audit-log",
+            "audit.log",
+        ],
+    );
+
+    let mut writer = KvsClient::connect_with_auth(addr, "writer".to_owned()).unwrap();
+    writer
+        .set("app1:a".to_owned(), "value1".to_owned())
+        .unwrap();
+    writer.get("app1:a".to_owned()).unwrap();
+    assert!(writer.remove("app1:missing".to_owned()).is_err());
+    let mut admin = KvsClient::connect_with_auth(addr, "secret".to_owned()).unwrap();
+    admin.select("bucket".to_owned()).unwrap();
+    admin
+        .set_many(vec![
+            ("b".to_owned(), "value2".to_owned()),
+            ("c".to_owned(), "value3".to_owned()),
+        ])
+        .unwrap();
+
+    let records = audit_records(&audit_log);
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0].identity, "app1-writer");
+    assert_eq!(records[0].bucket, "default");
+    assert_eq!(records[0].request, "Set");
+    assert_eq!(records[0].keys, vec!["app1:a".to_owned()]);
+    assert_eq!(records[0].values, Some(vec!["value1".to_owned()]));
+    assert_eq!(records[0].error, None);
+    assert_eq!(records[1].request, "Remove");
+    assert!(records[1].error.is_some());
+    assert_eq!(records[2].identity, "server token");
+    assert_eq!(records[2].bucket, "bucket");
+    assert_eq!(records[2].keys, vec!["b".to_owned(), "c".to_owned()]);
+    assert_eq!(
+        records[2].values,
+        Some(vec!["value2".to_owned(), "value3".to_owned()])
+    );
+    assert!(records.iter().all(|record| !record.peer.is_empty()));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A full audit log should be rotated, keeping the configured number of
+// older files, and redacted records should leave the values out.
+#[test]
+fn audit_log_rotation() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let audit_log = temp_dir.path().join("audit.log");
+    let mut child = spawn_server(
+        &temp_dir,
+        &[
+            "--addr",
+            addr,
+            "--audit-redact-values",
+            "--audit-log-max-bytes",
+            "1000",
+            "--audit-log-files",
+            "2",
+            "--audit-log",
+            "audit.log",
+        ],
+    );
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for i in 0..50 {
+        client
+            .set(format!("key{}", i), "secret".to_owned())
+            .unwrap();
+    }
+
+    let rotated = |n: usize| temp_dir.path().join(format!("audit.log.{}", n));
+    assert!(rotated(2).exists());
+    assert!(!rotated(3).exists());
+    let mut records = audit_records(&rotated(2));
+    records.extend(audit_records(&rotated(1)));
+    records.extend(audit_records(&audit_log));
+    assert!(records.len() < 50);
+    let last = records.last().unwrap();
+    assert_eq!(last.identity, "anonymous");
+    assert_eq!(last.keys, vec!["key49".to_owned()]);
+    for record in &records {
+        assert_eq!(record.values, None);
+    }
+    for path in [rotated(1), rotated(2), audit_log] {
+        assert!(fs::metadata(path).unwrap().len() <= 1000);
+    }
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A client given several endpoints should skip the ones that are down and
+// fail over to the next one when its server dies.
+#[test]
+fn endpoint_failover() {
+    let addrs = [free_addr(), free_addr(), free_addr()];
+    let temp_dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let mut children: Vec<_> = addrs[1..]
+        .iter()
+        .zip(&temp_dirs)
+        .map(|(addr, temp_dir)| spawn_server(temp_dir, &["--addr", addr]))
+        .collect();
+
+    let endpoints: Vec<SocketAddr> = addrs.iter().map(|addr| addr.parse().unwrap()).collect();
+    let mut client = KvsClient::builder()
+        .with_db("bucket".to_owned())
+        .connect(&endpoints[..])
+        .unwrap();
+    assert_eq!(client.server_addr(), Some(endpoints[1]));
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    children[0].kill().expect("server exited before killed");
+    children[0].wait().expect("failed to wait on server");
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(client.server_addr(), Some(endpoints[2]));
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    assert_eq!(
+        client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+    // the bucket is selected again on the new connection
+    let mut other = KvsClient::connect(&addrs[2]).unwrap();
+    assert_eq!(other.get("key2".to_owned()).unwrap(), None);
+
+    children[1].kill().expect("server exited before killed");
+    children[1].wait().expect("failed to wait on server");
+    assert!(client.get("key2".to_owned()).is_err());
+}
+
+// A client should get over a server restart: calls fail while the server is
+// down, and go through a new connection once it is back.
+#[test]
+fn reconnect_after_restart() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let start_server = || spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut child = start_server();
+    let mut client = KvsClient::builder()
+        .with_db("bucket".to_owned())
+        .connect(addr)
+        .unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+    assert!(client.get("key1".to_owned()).is_err());
+
+    let mut child = start_server();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+
+    // a restart between two calls goes unnoticed
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+    let mut child = start_server();
+    client.set("key3".to_owned(), "value3".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    // and so does one before a pipeline
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+    let mut child = start_server();
+    let replies = client.pipeline().get("key3".to_owned()).execute().unwrap();
+    assert_eq!(replies[0].as_ref().unwrap(), &Some("value3".to_owned()));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Clients on the Unix socket should share the store of the TCP ones, and a
+// restarted server should take over the socket file left by a killed one.
+#[cfg(unix)]
+#[test]
+fn unix_socket() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let socket = temp_dir.path().join("kvs.sock");
+    // the Unix socket is bound before the TCP listener
+    let start_server = || spawn_server(&temp_dir, &["--addr", addr, "--unix-socket", "kvs.sock"]);
+    let mut child = start_server();
+
+    let mut tcp_client = KvsClient::connect(addr).unwrap();
+    tcp_client
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap();
+    let mut client = KvsClient::connect_uds(&socket).unwrap();
+    assert_eq!(client.server_addr(), None);
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(
+        tcp_client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+    assert!(socket.exists());
+    let mut child = start_server();
+    let mut client = KvsClient::connect_uds(&socket).unwrap();
+    assert_eq!(
+        client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A client asking for zstd should get it, and large values should make the
+// round trip compressed both ways, readable by clients that did not ask.
+#[test]
+fn zstd_compression() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+
+    let mut client = KvsClient::builder()
+        .with_compression(Compression::Zstd)
+        .with_codec(Codec::MessagePack)
+        .connect(addr)
+        .unwrap();
+    assert_eq!(client.server_info().compression, vec!["zstd".to_owned()]);
+    let value = "value".repeat(100_000);
+    client.set("key1".to_owned(), value.clone()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some(value.clone()));
+    assert_eq!(
+        client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+
+    let mut plain = KvsClient::connect(addr).unwrap();
+    assert!(plain.server_info().compression.is_empty());
+    assert_eq!(plain.get("key1".to_owned()).unwrap(), Some(value));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Tenants should each work on a store of their own, chosen in the handshake
+// or by their token, within their quotas.
+#[test]
+fn tenants() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let acl = temp_dir.path().join("acl.toml");
+    fs::write(
+        &acl,
+        r#"
+[[token]]
+token = "app1-secret"
+operations = ["read", "write"]
+prefixes = ["*"]
+tenant = "app1"
+"#,
+    )
+    .unwrap();
+    let tenants = temp_dir.path().join("tenants.toml");
+    fs::write(
+        &tenants,
+        r#"
+[[tenant]]
+name = "app1"
+max_keys = 2
+
+[[tenant]]
+name = "app2"
+"#,
+    )
+    .unwrap();
+    let mut child = spawn_server(
+        &temp_dir,
+        &[
+            "--addr",
+            addr,
+            "--auth-token",
+            "secret",
+            "--acl",
+            "acl.toml",
+            "--tenants",
+            "tenants.toml",
+        ],
+    );
+
+    let tenant = |name: &str, token: &str| {
+        KvsClient::builder()
+            .with_tenant(name.to_owned())
+            .with_auth_token(token.to_owned())
+            .connect(addr)
+    };
+    let mut app1 = tenant("app1", "secret").unwrap();
+    assert_eq!(app1.server_info().tenant, Some("app1".to_owned()));
+    app1.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let mut app2 = tenant("app2", "secret").unwrap();
+    assert_eq!(app2.get("key1".to_owned()).unwrap(), None);
+    let mut server = KvsClient::connect_with_auth(addr, "secret".to_owned()).unwrap();
+    assert_eq!(server.get("key1".to_owned()).unwrap(), None);
+    assert!(temp_dir.path().join("tenants").join("app1").is_dir());
+
+    // the token of a tenant goes to its store, and no other
+    let mut pinned = KvsClient::connect_with_auth(addr, "app1-secret".to_owned()).unwrap();
+    assert_eq!(
+        pinned.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(tenant("app2", "app1-secret").is_err());
+    assert!(tenant("app3", "secret").is_err());
+    assert!(app1.select("bucket".to_owned()).is_err());
+
+    app1.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert!(matches!(
+        app1.set("key3".to_owned(), "value3".to_owned()),
+        Err(MyError::QuotaExceeded(_))
+    ));
+    app1.remove("key2".to_owned()).unwrap();
+    app1.set("key3".to_owned(), "value3".to_owned()).unwrap();
+    app2.set("key3".to_owned(), "value3".to_owned()).unwrap();
+    assert_eq!(app1.stats().unwrap().key_count, 2);
+    assert_eq!(app2.stats().unwrap().key_count, 1);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Requests should carry IDs the server records in its slow log.
+#[test]
+fn request_ids() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr, "--slow-log-ms", "0"]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let set_id = client.last_request_id().unwrap().to_owned();
+    client.get("key1".to_owned()).unwrap();
+    assert_ne!(client.last_request_id(), Some(set_id.as_str()));
+
+    let slow = client.slow_log().unwrap();
+    let set = slow.iter().find(|req| req.request == "Set").unwrap();
+    assert_eq!(set.request_id.as_deref(), Some(set_id.as_str()));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A read-only server should serve reads and refuse writes.
+#[test]
+fn read_only_server() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+    let mut child = spawn_server(&temp_dir, &["--addr", addr, "--read-only"]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(matches!(
+        client.set("key1".to_owned(), "value2".to_owned()),
+        Err(MyError::ReadOnly)
+    ));
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(MyError::ReadOnly)
+    ));
+    assert!(matches!(
+        client.set_if_version("key2".to_owned(), 0, "value2".to_owned()),
+        Err(MyError::ReadOnly)
+    ));
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A server with a trash should let clients restore removed keys and purge it.
+#[test]
+fn trash_restore() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr, "--trash-retention", "60"]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    client.remove("key1".to_owned()).unwrap();
+    client.remove("key2".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    client.restore("key1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(matches!(
+        client.restore("key1".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+    assert_eq!(client.purge_trash().unwrap(), 1);
+    assert!(matches!(
+        client.restore("key2".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A client cache should serve repeated reads and follow changes made by others.
+#[test]
+fn client_cache() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr, "--slow-log-ms", "0"]);
+
+    let mut cached = KvsClient::builder().with_cache(100).connect(addr).unwrap();
+    let mut other = KvsClient::connect(addr).unwrap();
+    other.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    let gets = |client: &mut KvsClient| {
+        let slow = client.slow_log().unwrap();
+        slow.iter().filter(|req| req.request == "Get").count()
+    };
+    let before = gets(&mut other);
+    for _ in 0..3 {
+        assert_eq!(
+            cached.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+    }
+    assert_eq!(gets(&mut other), before + 1);
+
+    let wait_for = |client: &mut KvsClient, expected: Option<&str>| {
+        wait_until(|| client.get("key1".to_owned()).unwrap().as_deref() == expected)
+    };
+    other.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    wait_for(&mut cached, Some("value2"));
+    other.remove("key1".to_owned()).unwrap();
+    wait_for(&mut cached, None);
+
+    cached.set("key1".to_owned(), "value3".to_owned()).unwrap();
+    assert_eq!(
+        cached.get("key1".to_owned()).unwrap(),
+        Some("value3".to_owned())
+    );
+    cached
+        .pipeline()
+        .set("key1".to_owned(), "value4".to_owned())
+        .execute()
+        .unwrap();
+    assert_eq!(
+        cached.get("key1".to_owned()).unwrap(),
+        Some("value4".to_owned())
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// With an admin listener, management requests should only be served there,
+// and data requests only on the data address.
+#[test]
+fn admin_listener() {
+    let (addr, admin_addr) = (&free_addr(), &free_addr());
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr, "--admin-addr", admin_addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    assert!(matches!(client.stats(), Err(MyError::PermissionDenied)));
+    assert!(matches!(client.compact(), Err(MyError::PermissionDenied)));
+    assert!(matches!(client.shutdown(), Err(MyError::PermissionDenied)));
+
+    let mut admin = KvsClient::connect(admin_addr).unwrap();
+    assert!(matches!(
+        admin.get("key1".to_owned()),
+        Err(MyError::PermissionDenied)
+    ));
+    assert!(admin.stats().unwrap().uncompacted_bytes > 0);
+    admin.compact().unwrap();
+    assert_eq!(admin.stats().unwrap().uncompacted_bytes, 0);
+    // nothing to reload without a configuration file
+    admin.reload_config().unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+
+    admin.shutdown().unwrap();
+    assert!(child.wait().unwrap().success());
+}
+
+// A warming up server should answer health checks as starting and refuse
+// the rest, then serve; in lame-duck mode, it should report draining while
+// still serving, and shut down once the period passes.
+#[test]
+fn warm_up_and_lame_duck() -> Result<()> {
+    let (addr, admin_addr) = (&free_addr(), &free_addr());
+    let warm_up = WarmUp::start(addr, "kvs")?;
+    let mut client = KvsClient::connect(addr)?;
+    let pong = client.ping()?;
+    assert_eq!(pong.state, ServerState::Starting);
+    assert_eq!(pong.engine, "kvs");
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(MyError::Server {
+            code: ErrorCode::Unavailable,
+            ..
+        })
+    ));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = Server::new(KvStore::open(temp_dir.path())?)
+        .with_admin_listener(admin_addr.parse().unwrap())
+        .with_lame_duck_period(Duration::from_secs(1));
+    let handle = thread::spawn(move || server.open_after_warm_up(warm_up));
+    // the admin listener is bound once the engine is open
+    wait_until(|| TcpStream::connect(admin_addr).is_ok());
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.ping()?.state, ServerState::Serving);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(client.drain(), Err(MyError::PermissionDenied)));
+
+    KvsClient::connect(admin_addr)?.drain()?;
+    assert_eq!(client.ping()?.state, ServerState::Draining);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    let mut late = KvsClient::connect(addr)?;
+    assert_eq!(late.ping()?.state, ServerState::Draining);
+    assert_eq!(late.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    handle.join().unwrap()
+}
@@ -0,0 +1,68 @@
+use assert_cmd::prelude::*;
+use kvs::KvsClusterClient;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Adding a node should only move a fraction of the keys.
+#[test]
+fn consistent_hashing_moves_few_keys() {
+    let mut cluster =
+        KvsClusterClient::connect(&["127.0.0.1:5001", "127.0.0.1:5002", "127.0.0.1:5003"]).unwrap();
+    let keys: Vec<String> = (0..1000).map(|i| format!("key{}", i)).collect();
+    let before: Vec<_> = keys.iter().map(|k| cluster.node_for(k)).collect();
+
+    cluster.add_node("127.0.0.1:5004").unwrap();
+    let moved = keys
+        .iter()
+        .zip(&before)
+        .filter(|(k, owner)| cluster.node_for(k) != **owner)
+        .count();
+    assert!(moved > 0 && moved < 500, "{} keys moved", moved);
+
+    cluster.remove_node("127.0.0.1:5004").unwrap();
+    let after: Vec<_> = keys.iter().map(|k| cluster.node_for(k)).collect();
+    assert_eq!(before, after);
+}
+
+// Keys should be readable back through the cluster client.
+#[test]
+fn cluster_routes_requests() {
+    let addrs = ["127.0.0.1:4015", "127.0.0.1:4016"];
+    let mut children = Vec::new();
+    let mut dirs = Vec::new();
+    for addr in &addrs {
+        let temp_dir = TempDir::new().unwrap();
+        children.push(
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(["--addr", addr])
+                .current_dir(&temp_dir)
+                .spawn()
+                .unwrap(),
+        );
+        dirs.push(temp_dir);
+    }
+    thread::sleep(Duration::from_secs(1));
+
+    let mut cluster = KvsClusterClient::connect(&addrs).unwrap();
+    for i in 0..20 {
+        cluster
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    for i in 0..20 {
+        assert_eq!(
+            cluster.get(format!("key{}", i)).unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+    cluster.remove("key0".to_owned()).unwrap();
+    assert_eq!(cluster.get("key0".to_owned()).unwrap(), None);
+
+    for mut child in children {
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    }
+}
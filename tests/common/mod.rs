@@ -0,0 +1,69 @@
+//! Helpers shared by the integration tests.
+#![allow(dead_code)]
+
+use assert_cmd::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// How long a server may take to start accepting connections.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A loopback address with a port no one listens on, for a test server.
+pub fn free_addr() -> String {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap()
+        .to_string()
+}
+
+/// The kvs-server command running in `temp_dir` with `args`.
+pub fn server_command(temp_dir: &TempDir, args: &[&str]) -> Command {
+    let mut command = Command::cargo_bin("kvs-server").unwrap();
+    command.args(args).current_dir(temp_dir);
+    command
+}
+
+/// Starts kvs-server in `temp_dir` with `args`, which name its `--addr`,
+/// and waits until it accepts connections there.
+pub fn spawn_server(temp_dir: &TempDir, args: &[&str]) -> Child {
+    let addr = args
+        .iter()
+        .position(|arg| *arg == "--addr")
+        .and_then(|i| args.get(i + 1))
+        .expect("kvs-server needs an --addr");
+    let mut child = server_command(temp_dir, args).spawn().unwrap();
+    wait_until_listening(&mut child, addr);
+    child
+}
+
+/// Waits until the server `child` accepts connections at `addr`, failing if
+/// it exits or takes too long.
+pub fn wait_until_listening(child: &mut Child, addr: &str) {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while TcpStream::connect(addr).is_err() {
+        if let Some(status) = child.try_wait().unwrap() {
+            panic!("kvs-server exited with {} before listening", status);
+        }
+        assert!(
+            Instant::now() < deadline,
+            "kvs-server is not listening on {}",
+            addr
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Waits until `condition` holds, failing after a few seconds.
+pub fn wait_until(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while !condition() {
+        assert!(
+            Instant::now() < deadline,
+            "timed out waiting for a condition"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,98 @@
+#![cfg(feature = "testing")]
+
+use kvs::{
+    Fault, FaultPoint, Faults, FaultyEngine, KvStore, KvsClient, KvsEngine, KvsReader, MemEngine,
+    Result, Server, WriteBatch,
+};
+use std::io::ErrorKind;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Faults should apply to the calls they were injected for, at their point
+// only, until cleared.
+#[test]
+fn injected_faults() -> Result<()> {
+    let faults = Faults::default();
+    let mut store = FaultyEngine::new(MemEngine::new(), faults.clone());
+    let reader = store.reader();
+
+    faults.inject_nth(FaultPoint::Write, 2, Fault::Io(ErrorKind::Other));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(faults.calls(FaultPoint::Write), 3);
+
+    faults.inject(FaultPoint::Read, Fault::Io(ErrorKind::TimedOut));
+    assert!(store.get("key1".to_owned()).is_err());
+    assert!(reader.get("key1".to_owned()).is_err());
+    store.remove("key3".to_owned())?;
+    faults.clear();
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    faults.inject(FaultPoint::Write, Fault::PartialWrite(0));
+    assert!(store.set("key4".to_owned(), "value4".to_owned()).is_err());
+    faults.clear();
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    faults.inject_nth(
+        FaultPoint::Read,
+        1,
+        Fault::Delay(Duration::from_millis(100)),
+    );
+    let started = Instant::now();
+    store.get("key1".to_owned())?;
+    assert!(started.elapsed() >= Duration::from_millis(100));
+
+    Ok(())
+}
+
+// A batch cut short by a failure should leave the store, once reopened,
+// with the commands written before it and none after.
+#[test]
+fn partial_batch_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = Faults::default();
+    let mut store = FaultyEngine::new(KvStore::open(temp_dir.path())?, faults.clone());
+    store.set("key0".to_owned(), "value0".to_owned())?;
+
+    faults.inject(FaultPoint::Batch, Fault::PartialWrite(1));
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "value1".to_owned())
+        .remove("key0".to_owned());
+    assert!(store.write_batch(batch).is_err());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A server should report engine failures to the client and go on serving
+// once the engine recovers.
+#[test]
+fn server_reports_engine_faults() -> Result<()> {
+    let addr = "127.0.0.1:4077";
+    let faults = Faults::default();
+    let server = Server::new(FaultyEngine::new(MemEngine::new(), faults.clone()));
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.open(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    faults.inject(FaultPoint::Write, Fault::Io(ErrorKind::Other));
+    faults.inject(FaultPoint::Read, Fault::Io(ErrorKind::Other));
+    assert!(client.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert!(client.get("key1".to_owned()).is_err());
+
+    faults.clear();
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
@@ -0,0 +1,60 @@
+#![cfg(feature = "grpc")]
+
+use kvs::{GrpcClient, KvStore, KvsClient, MyError, Result, Server};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::free_addr;
+
+// The gRPC service should serve the same store as the TCP listener, with
+// its token, and stream the pairs under a prefix.
+#[test]
+fn grpc_service() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, grpc_addr): (SocketAddr, SocketAddr) =
+        (free_addr().parse().unwrap(), free_addr().parse().unwrap());
+    let server = Server::new(KvStore::open(temp_dir.path())?)
+        .with_grpc(grpc_addr)
+        .with_auth_token("secret".to_owned());
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.open(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut anonymous = GrpcClient::connect(grpc_addr)?;
+    assert!(matches!(
+        anonymous.get("key1".to_owned()),
+        Err(MyError::Unauthorized)
+    ));
+
+    let mut client = GrpcClient::connect(grpc_addr)?.with_auth_token("secret".to_owned());
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key*2".to_owned(), "value2".to_owned())?;
+    client.set("other".to_owned(), "value3".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        client.scan("key".to_owned())?,
+        vec![
+            ("key*2".to_owned(), "value2".to_owned()),
+            ("key1".to_owned(), "value1".to_owned()),
+        ]
+    );
+    assert_eq!(
+        client.scan("key*".to_owned())?,
+        vec![("key*2".to_owned(), "value2".to_owned())]
+    );
+    client.remove("key1".to_owned())?;
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+
+    let mut tcp = KvsClient::connect_with_auth(addr, "secret".to_owned())?;
+    assert_eq!(tcp.get("other".to_owned())?, Some("value3".to_owned()));
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
@@ -1,4 +1,18 @@
-use kvs::{KvStore, KvsEngine, Result};
+use bytes::Bytes;
+use kvs::{
+    Change, Command, CompactionTrigger, CompactionWindow, EventListener, EvictionPolicy,
+    IndexedEngine, Key, KeyEvent, KeyMeta, KeyOp, KeyPart, KeyVersion, KvStore, KvStoreOptions,
+    KvsEngine, KvsReader, LogFormat, MemEngine, MetricsSink, MyError, OpKind, OpMetrics,
+    PartitionedKvStore, Partitioner, Result, SyncPolicy, TypedKvStore, WriteBatch, WriteBuffer,
+    TRASH_PREFIX,
+};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -124,3 +138,2578 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should list live pairs whose key starts with the prefix, in key order
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("user:2".to_owned(), "bob".to_owned())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.set("user:3".to_owned(), "carol".to_owned())?;
+    store.remove("user:3".to_owned())?;
+
+    assert_eq!(
+        store.scan("user:".to_owned())?,
+        vec![
+            ("user:1".to_owned(), "alice".to_owned()),
+            ("user:2".to_owned(), "bob".to_owned()),
+        ]
+    );
+    assert_eq!(store.scan(String::new())?.len(), 3);
+
+    Ok(())
+}
+
+// A record torn by a crash should be dropped when reopening
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("log.json"))?;
+    log.write_all(b"\r\n{\"Set\":{\"key\":\"key2\",\"va")?;
+    drop(log);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Records should be framed whatever their values hold, and a framed
+// record torn by a crash dropped when reopening
+#[test]
+fn framed_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("log.json");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let value = "line\r\nbreak\u{1e}\"}".to_owned();
+    store.set("key1".to_owned(), value.clone())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let data = std::fs::read(&log_path)?;
+    assert_eq!(data[0], 0x1e);
+    assert_eq!(data.iter().filter(|&&b| b == 0x1e).count(), 3);
+    assert!(!data.windows(2).any(|window| window == b"\r\n"));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    let log = OpenOptions::new().write(true).open(&log_path)?;
+    log.set_len(data.len() as u64 - 5)?;
+    drop(log);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Legacy logs should be read, appended to in the format asked for, and
+// framed by compaction
+#[test]
+fn legacy_log_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("log.json");
+    let legacy = KvStoreOptions::default().with_log_format(LogFormat::Legacy);
+    let mut store = KvStore::open_with_options(temp_dir.path(), legacy)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+    let data = std::fs::read(&log_path)?;
+    assert!(data.starts_with(b"\r\n{"));
+    assert!(!data.contains(&0x1e));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let data = std::fs::read(&log_path)?;
+    assert_eq!(data.iter().filter(|&&b| b == 0x1e).count(), 1);
+    assert!(kvs::fsck(temp_dir.path(), false)?.is_clean());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.compact_now()?;
+    drop(store);
+    let data = std::fs::read(&log_path)?;
+    assert!(data.starts_with(&[0x1e]));
+    assert!(!data.windows(2).any(|window| window == b"\r\n"));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A clean shutdown should leave a marker that the next open consumes
+#[test]
+fn clean_shutdown_marker() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let marker = temp_dir.path().join("clean-shutdown");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.shutdown()?;
+    assert!(marker.exists());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!marker.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A clean shutdown should save the index, which the next open loads with
+// the cached values, unless the log changed since
+#[test]
+fn saved_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let index = temp_dir.path().join("index.json");
+    let options = KvStoreOptions::default().with_cache_capacity(1024);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..3 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.rpush("list".to_owned(), "a".to_owned())?;
+    store.hset("hash".to_owned(), "field".to_owned(), "value".to_owned())?;
+    store.sadd("set".to_owned(), "member".to_owned())?;
+    store.zadd("zset".to_owned(), "member".to_owned(), 1.5)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    let uncompacted = store.stats()?.uncompacted_bytes;
+    store.shutdown()?;
+    assert!(index.exists());
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert!(!index.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    let stats = store.stats()?;
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 0));
+    assert_eq!(stats.uncompacted_bytes, uncompacted);
+    assert_eq!(stats.key_count, 5);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(
+        store.lrange("list".to_owned(), 0, -1)?,
+        vec!["a".to_owned()]
+    );
+    assert_eq!(
+        store.hget("hash".to_owned(), "field".to_owned())?,
+        Some("value".to_owned())
+    );
+    assert!(store.sismember("set".to_owned(), "member".to_owned())?);
+    assert_eq!(
+        store.zrange_by_score("zset".to_owned(), 0.0, 2.0)?,
+        vec![("member".to_owned(), 1.5)]
+    );
+    store.shutdown()?;
+    let stale = std::fs::read(&index)?;
+    drop(store);
+
+    // an index saved before the last writes is ignored
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key3".to_owned(), "value".to_owned())?;
+    store.shutdown()?;
+    drop(store);
+    std::fs::write(&index, stale)?;
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(!index.exists());
+    assert_eq!(store.get("key3".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.stats()?.cache_misses, 1);
+
+    Ok(())
+}
+
+// Writes after a compaction should go to the compacted log and survive reopening
+#[test]
+fn writes_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().with_compaction_threshold(256);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    for iter in 0..20 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert!(store.stats()?.last_compaction.is_some());
+    store.remove("key0".to_owned())?;
+    store.set("key1".to_owned(), "last".to_owned())?;
+    assert!(!temp_dir.path().join("compacted_log.json").exists());
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("last".to_owned()));
+    assert_eq!(store.get("key9".to_owned())?, Some("19".to_owned()));
+
+    Ok(())
+}
+
+// A write batch should apply all its writes, survive reopening, and apply
+// nothing when one of its removes fails
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .set("key3".to_owned(), "value3".to_owned())
+        .remove("key1".to_owned())
+        .set("key3".to_owned(), "value4".to_owned());
+    store.write_batch(batch)?;
+
+    let mut failing = WriteBatch::new();
+    failing
+        .set("key5".to_owned(), "value5".to_owned())
+        .remove("key1".to_owned());
+    assert!(store.write_batch(failing).is_err());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, None);
+
+    // compaction keeps the writes of batches
+    for iter in 0..100 {
+        let mut batch = WriteBatch::new();
+        batch.set("key4".to_owned(), format!("{}", iter));
+        batch.set("key6".to_owned(), format!("{}", iter));
+        store.write_batch(batch)?;
+    }
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key6".to_owned())?, Some("99".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn rename() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.rename("key1".to_owned(), "key2".to_owned())?;
+    store.rename("key2".to_owned(), "key2".to_owned())?;
+    assert!(matches!(
+        store.rename("key1".to_owned(), "key3".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    // the expiry moves along with the value
+    store.set_with_ttl(
+        "temp".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.rename("temp".to_owned(), "moved".to_owned())?;
+    let ttl = store
+        .ttl("moved".to_owned())?
+        .expect("the expiry was dropped");
+    assert!(ttl > Duration::from_secs(50) && ttl <= Duration::from_secs(60));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert!(store.ttl("moved".to_owned())?.is_some());
+
+    let mut store = MemEngine::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.rename("key1".to_owned(), "key3".to_owned())?;
+    assert_eq!(
+        store.scan(String::new())?,
+        vec![("key3".to_owned(), "value1".to_owned())]
+    );
+    Ok(())
+}
+
+// A batch torn by a crash should be dropped as a whole
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("log.json"))?;
+    log.write_all(
+        b"\r\n{\"Batch\":[{\"Set\":{\"key\":\"key2\",\"value\":\"value2\"}},{\"Remove\":",
+    )?;
+    drop(log);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Buckets should keep separate keyspaces in directories of their own
+#[test]
+fn open_bucket() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let mut bucket = KvStore::open_bucket(temp_dir.path(), "a")?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    bucket.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(temp_dir.path().join("buckets").join("a").is_dir());
+
+    let mut other = store.open_bucket("b")?;
+    assert_eq!(other.get("key1".to_owned())?, None);
+    drop(bucket);
+    let mut bucket = store.open_bucket("a")?;
+    assert_eq!(bucket.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert!(KvStore::open_bucket(temp_dir.path(), "../escape").is_err());
+    assert!(KvStore::open_bucket(temp_dir.path(), "").is_err());
+
+    Ok(())
+}
+
+// History should list the versions of a key still in the log, newest first
+#[test]
+fn get_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value4".to_owned());
+    batch.set("key2".to_owned(), "value5".to_owned());
+    store.write_batch(batch)?;
+
+    let version = |seq, value: Option<&str>| KeyVersion {
+        seq,
+        value: value.map(str::to_owned),
+    };
+    let expected = vec![
+        version(5, Some("value4")),
+        version(4, None),
+        version(3, Some("value3")),
+        version(1, Some("value1")),
+    ];
+    assert_eq!(store.get_history("key1".to_owned(), 10)?, expected);
+    assert_eq!(store.get_history("key1".to_owned(), 2)?, expected[..2]);
+    assert_eq!(store.get_history("key3".to_owned(), 10)?, vec![]);
+
+    // sequence numbers survive a restart
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_history("key1".to_owned(), 10)?, expected);
+    store.set("key3".to_owned(), "value6".to_owned())?;
+    assert_eq!(
+        store.get_history("key3".to_owned(), 10)?,
+        vec![version(7, Some("value6"))]
+    );
+
+    Ok(())
+}
+
+// Compaction should keep the configured number of previous versions, and
+// never hand out a sequence number twice
+#[test]
+fn history_through_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_compaction_threshold(256)
+        .with_retained_versions(2);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 1..=100 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let history = store.get_history("key1".to_owned(), 10)?;
+    assert!(history.len() >= 3);
+    assert_eq!(history[0].value, Some("100".to_owned()));
+    assert_eq!(history[1].value, Some("99".to_owned()));
+    assert_eq!(history[2].value, Some("98".to_owned()));
+
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let history = store.get_history("key1".to_owned(), 10)?;
+    assert!(history.len() >= 3);
+    assert_eq!(history[0].seq, 100);
+    assert_eq!(history[2].seq, 98);
+    store.set("key3".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get_history("key3".to_owned(), 1)?[0].seq, 103);
+
+    // without retention, compaction leaves only the current value
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().with_compaction_threshold(256);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 1..=100 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "value".to_owned())?;
+    let history = store.get_history("key1".to_owned(), 200)?;
+    assert!(history.len() < 100);
+    assert_eq!(
+        history[0],
+        KeyVersion {
+            seq: 102,
+            value: Some("value".to_owned())
+        }
+    );
+
+    Ok(())
+}
+
+// Should resolve keys as of a past sequence number, until compaction drops
+// the versions around it
+#[test]
+fn time_travel() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    assert_eq!(store.get_at("key1".to_owned(), 0)?, None);
+    assert_eq!(
+        store.get_at("key1".to_owned(), 2)?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get_at("key1".to_owned(), 3)?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(
+        store.get_at("key2".to_owned(), 3)?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.get_at("key2".to_owned(), 4)?, None);
+    assert_eq!(
+        store.scan_at(.., 2)?,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned())
+        ]
+    );
+    assert_eq!(
+        store.scan_at("key2".to_owned().., 3)?,
+        vec![("key2".to_owned(), "value2".to_owned())]
+    );
+
+    // without retention, compaction keeps only the current values
+    store.compact_now()?;
+    assert!(store.get_at("key1".to_owned(), 2).is_err());
+    assert!(store.scan_at(.., 2).is_err());
+    assert_eq!(
+        store.get_at("key1".to_owned(), 4)?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(store.get_at("key2".to_owned(), 4)?, None);
+
+    // with it, as far back as the versions it keeps
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().with_retained_versions(1);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 1..=3 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    store.compact_now()?;
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get_at("key1".to_owned(), 2)?, Some("2".to_owned()));
+    assert!(store.get_at("key1".to_owned(), 1).is_err());
+    assert_eq!(store.get_at("key2".to_owned(), 1)?, None);
+    assert_eq!(
+        store.get_at("key2".to_owned(), 4)?,
+        Some("value".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should return the writes after a sequence number, until a compaction
+// drops some of them
+#[test]
+fn changes_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.remove("key2".to_owned());
+    store.write_batch(batch)?;
+
+    let change = |seq, command| Change {
+        seq,
+        command,
+        expires: None,
+    };
+    let expected = vec![
+        change(
+            3,
+            Command::Remove {
+                key: "key1".to_owned(),
+            },
+        ),
+        change(
+            4,
+            Command::Set {
+                key: "key3".to_owned(),
+                value: "value3".to_owned(),
+            },
+        ),
+        change(
+            5,
+            Command::Remove {
+                key: "key2".to_owned(),
+            },
+        ),
+    ];
+    assert_eq!(store.changes_since(2)?, expected);
+    assert_eq!(store.changes_since(0)?.len(), 5);
+    assert_eq!(store.changes_since(5)?, vec![]);
+
+    // the sequence numbers survive a restart
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.changes_since(2)?, expected);
+    drop(store);
+
+    // compaction drops overwritten keys, only later writes can be returned
+    let options = KvStoreOptions::default().with_compaction_threshold(256);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 1..=100 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    assert!(store.changes_since(2).is_err());
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(store.changes_since(2).is_err());
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(
+        store.changes_since(105)?,
+        vec![change(
+            106,
+            Command::Set {
+                key: "key4".to_owned(),
+                value: "value4".to_owned(),
+            },
+        )]
+    );
+
+    Ok(())
+}
+
+// Readers should see every batch whole, while writes and compactions go on
+#[test]
+fn reads_concurrent_with_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().with_compaction_threshold(256);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "0".to_owned())?;
+    store.set("key2".to_owned(), "0".to_owned())?;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let reader = store.reader();
+            let done = Arc::clone(&done);
+            thread::spawn(move || -> Result<()> {
+                while !done.load(Ordering::SeqCst) {
+                    let pairs = reader.scan("key".to_owned())?;
+                    assert_eq!(pairs.len(), 2);
+                    assert_eq!(pairs[0].1, pairs[1].1);
+                    assert!(reader.get("key1".to_owned())?.is_some());
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for iter in 1..=500 {
+        let mut batch = WriteBatch::new();
+        batch.set("key1".to_owned(), format!("{}", iter));
+        batch.set("key2".to_owned(), format!("{}", iter));
+        store.write_batch(batch)?;
+    }
+    assert!(store.stats()?.last_compaction.is_some());
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+
+    let reader = store.reader();
+    assert_eq!(reader.get("key2".to_owned())?, Some("500".to_owned()));
+    assert_eq!(
+        reader.get_bytes("key2".to_owned())?,
+        Some(Bytes::from("500"))
+    );
+    Ok(())
+}
+
+// Writers sharing syncs should still find all their writes after a restart,
+// compactions included
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.defer_syncs()?.is_none());
+    drop(store);
+
+    let options = KvStoreOptions::default()
+        .with_sync_policy(SyncPolicy::Always)
+        .with_compaction_threshold(512);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let commit = store.defer_syncs()?.expect("syncs should be deferred");
+    let store = Arc::new(Mutex::new(store));
+    let writers: Vec<_> = (0..8)
+        .map(|writer| {
+            let store = Arc::clone(&store);
+            let commit = Arc::clone(&commit);
+            thread::spawn(move || -> Result<()> {
+                for iter in 0..50 {
+                    let key = format!("key{}-{}", writer, iter % 5);
+                    store.lock().unwrap().set(key, format!("{}", iter))?;
+                    commit.sync()?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for writer in 0..8 {
+        for key in 0..5 {
+            assert_eq!(
+                store.get(format!("key{}-{}", writer, key))?,
+                Some(format!("{}", 45 + key))
+            );
+        }
+    }
+    Ok(())
+}
+
+// The memory engine should behave like the others, minus persistence
+#[test]
+fn mem_engine() -> Result<()> {
+    let mut store = MemEngine::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("other".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.remove("missing".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+
+    let mut batch = WriteBatch::new();
+    batch.remove("key1".to_owned());
+    batch.remove("missing".to_owned());
+    assert!(matches!(
+        store.write_batch(batch),
+        Err(MyError::KeyNotFound)
+    ));
+    let mut batch = WriteBatch::new();
+    batch.remove("key1".to_owned());
+    batch.set("key3".to_owned(), "value4".to_owned());
+    store.write_batch(batch)?;
+
+    let reader = store.reader();
+    assert_eq!(
+        reader.scan("key".to_owned())?,
+        vec![
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "value4".to_owned()),
+        ]
+    );
+    assert_eq!(store.stats()?.key_count, 3);
+
+    let mut bucket = store.open_bucket("cache")?;
+    assert_eq!(bucket.get("key2".to_owned())?, None);
+    assert!(store.open_bucket("../escape").is_err());
+    Ok(())
+}
+
+// A directory should only be opened by one store at a time
+#[test]
+fn data_dir_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(MyError::AlreadyLocked { .. }) => {}
+        other => panic!("expected AlreadyLocked, got {:?}", other.map(|_| ())),
+    }
+    drop(store);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+// A corrupt record followed by valid ones should fail to open rather than
+// lose the writes after it
+#[test]
+fn corrupt_record_mid_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("log.json"))?;
+    log.write_all(b"\r\n{\"Set\":{\"key\":\"key2\",\"va")?;
+    log.write_all(b"\r\n{\"Set\":{\"key\":\"key3\",\"value\":\"value3\"}}")?;
+    drop(log);
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+// A torn header, or one claiming more than the log holds, should not
+// swallow the records after it
+#[test]
+fn corrupt_record_headers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("log.json");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 1..=3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let data = std::fs::read(&log_path)?;
+    let starts: Vec<usize> = (0..data.len()).filter(|&i| data[i] == 0x1e).collect();
+    assert_eq!(starts.len(), 3);
+    let mut spliced = data[..starts[1]].to_vec();
+    spliced.extend_from_slice(b"\x1e0000");
+    spliced.extend_from_slice(&data[starts[1]..starts[2]]);
+    spliced.extend_from_slice(b"\x1effffffff00000000\n");
+    spliced.extend_from_slice(&data[starts[2]..]);
+    std::fs::write(&log_path, spliced)?;
+
+    let report = kvs::fsck(temp_dir.path(), true)?;
+    assert_eq!(report.records, 5);
+    assert_eq!(report.corrupt_records, 2);
+    assert!(report.repaired);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 1..=3 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// Large values should live in the value log, and survive overwrites,
+// compactions and restarts
+#[test]
+fn separated_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_value_threshold(100)
+        .with_retained_versions(1);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let large = |iter: usize| format!("{:0>200}", iter);
+    store.set("small".to_owned(), "value".to_owned())?;
+    for iter in 0..100 {
+        store.set("large".to_owned(), large(iter))?;
+    }
+    assert_eq!(store.get("large".to_owned())?, Some(large(99)));
+    assert_eq!(
+        store.scan("".to_owned())?,
+        vec![
+            ("large".to_owned(), large(99)),
+            ("small".to_owned(), "value".to_owned())
+        ]
+    );
+    let history: Vec<_> = store
+        .get_history("large".to_owned(), 2)?
+        .into_iter()
+        .map(|version| version.value)
+        .collect();
+    assert_eq!(history, vec![Some(large(99)), Some(large(98))]);
+    assert!(store.stats()?.last_compaction.is_some());
+
+    // the log holds no large value, and the value log little garbage
+    let log_len = std::fs::metadata(temp_dir.path().join("log.json"))?.len();
+    assert!(log_len < 100 * 200);
+    let disk_usage = store.stats()?.disk_usage;
+    assert!(disk_usage - log_len < 50 * 200);
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("large".to_owned())?, Some(large(99)));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(
+        store.get_history("large".to_owned(), 2)?[1].value,
+        Some(large(98))
+    );
+    drop(store);
+
+    // without the option, separated values are still read back
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large(99)));
+    store.set("large".to_owned(), large(100))?;
+    store.remove("large".to_owned())?;
+    assert_eq!(store.get("large".to_owned())?, None);
+    Ok(())
+}
+
+// Keys set with a TTL should read as removed once it elapses, and sweeping
+// should remove them for good
+#[test]
+fn expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let ttl = Duration::from_millis(100);
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), ttl)?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.sweep_expired(10)?.is_empty());
+
+    thread::sleep(ttl);
+    let reader = store.reader();
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    assert_eq!(reader.scan("key".to_owned())?.len(), 2);
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+    drop(store);
+
+    // the expiry survives a restart, and sweeps walk the store in slices
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    let uncompacted = store.stats()?.uncompacted_bytes;
+    assert_eq!(store.sweep_expired(2)?, vec!["key1".to_owned()]);
+    assert!(store.sweep_expired(2)?.is_empty());
+    assert_eq!(store.stats()?.key_count, 2);
+    assert!(store.stats()?.uncompacted_bytes > uncompacted);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // setting the key again makes it permanent
+    store.set_with_ttl("key2".to_owned(), "value2".to_owned(), ttl)?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    thread::sleep(ttl);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// TTLs should be readable, and changeable without touching values
+#[test]
+fn ttl_and_persist() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.rpush("list".to_owned(), "a".to_owned())?;
+    assert_eq!(store.ttl("key1".to_owned())?, None);
+    assert_eq!(store.ttl("list".to_owned())?, None);
+    assert!(matches!(
+        store.ttl("key2".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+    assert!(matches!(
+        store.expire("key2".to_owned(), Duration::from_secs(1)),
+        Err(MyError::KeyNotFound)
+    ));
+
+    store.expire("key1".to_owned(), Duration::from_secs(60))?;
+    let ttl = store.ttl("key1".to_owned())?.unwrap();
+    assert!(ttl > Duration::from_secs(59) && ttl <= Duration::from_secs(60));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.ttl("key1".to_owned())?.is_some());
+    store.persist("key1".to_owned())?;
+    assert_eq!(store.ttl("key1".to_owned())?, None);
+    store.expire("key1".to_owned(), Duration::from_millis(50))?;
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(matches!(
+        store.persist("key1".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+
+    let mut store = MemEngine::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.expire("key1".to_owned(), Duration::from_secs(60))?;
+    assert!(store.ttl("key1".to_owned())?.is_some());
+    store.persist("key1".to_owned())?;
+    assert_eq!(store.ttl("key1".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Touching keys should extend their TTLs in one record without their values,
+// across restarts and compaction
+#[test]
+fn touch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().with_compaction_threshold(2048);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let value = "x".repeat(1000);
+    for key in ["key1", "key2"] {
+        store.set_with_ttl(key.to_owned(), value.clone(), Duration::from_millis(100))?;
+    }
+    store.rpush("list".to_owned(), "a".to_owned())?;
+    let log = temp_dir.path().join("log.json");
+    let before = std::fs::metadata(&log)?.len();
+    let keys = ["key1", "key2", "key2", "list", "key3"];
+    let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+    assert_eq!(store.touch(keys, Duration::from_secs(60))?, 2);
+    assert!(std::fs::metadata(&log)?.len() - before < 1000);
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert!(store.ttl("key2".to_owned())?.unwrap() > Duration::from_secs(59));
+    for _ in 0..100 {
+        store.touch(vec!["key1".to_owned()], Duration::from_secs(120))?;
+    }
+    assert!(std::fs::metadata(&log)?.len() < 5000);
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(store.ttl("key1".to_owned())?.unwrap() > Duration::from_secs(119));
+    assert!(store.ttl("key2".to_owned())?.unwrap() > Duration::from_secs(58));
+    assert_eq!(store.get("key2".to_owned())?, Some(value));
+
+    let mut store = MemEngine::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let keys = vec!["key1".to_owned(), "key2".to_owned()];
+    assert_eq!(store.touch(keys, Duration::from_secs(60))?, 1);
+    assert!(store.ttl("key1".to_owned())?.is_some());
+    Ok(())
+}
+
+// Keys should keep their creation time across overwrites, compaction and
+// restarts, and start over once removed
+#[test]
+fn key_metadata() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().with_compaction_threshold(1024);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let before = SystemTime::now() - Duration::from_millis(1);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let meta = store.metadata("key1".to_owned())?;
+    let created = meta.created.unwrap();
+    assert!(created >= before && created <= SystemTime::now());
+    assert_eq!(meta.updated, meta.created);
+
+    thread::sleep(Duration::from_millis(5));
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value2".to_owned());
+    store.write_batch(batch)?;
+    let meta = store.metadata("key1".to_owned())?;
+    assert_eq!(meta.created, Some(created));
+    let updated = meta.updated.unwrap();
+    assert!(updated > created);
+
+    // compaction keeps both times, whatever the filler it drops
+    for iter in 0..100 {
+        store.set("filler".to_owned(), format!("value{}", iter))?;
+    }
+    assert!(store.stats()?.compactions > 0);
+    let meta = KeyMeta {
+        created: Some(created),
+        updated: Some(updated),
+        version: 2,
+    };
+    assert_eq!(store.metadata("key1".to_owned())?, meta);
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.metadata("key1".to_owned())?, meta);
+
+    store.remove("key1".to_owned())?;
+    assert!(matches!(
+        store.metadata("key1".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert!(store.metadata("key1".to_owned())?.created.unwrap() > created);
+    store.rpush("list".to_owned(), "a".to_owned())?;
+    assert_eq!(store.metadata("list".to_owned())?, KeyMeta::default());
+
+    let mut store = MemEngine::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let created = store.metadata("key1".to_owned())?.created;
+    thread::sleep(Duration::from_millis(5));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let meta = store.metadata("key1".to_owned())?;
+    assert_eq!(meta.created, created);
+    assert!(meta.updated > created);
+    Ok(())
+}
+
+// Conditional writes should go through only at the version last read,
+// which every write moves up
+#[test]
+fn set_if_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let version = store.set_if_version("key1".to_owned(), 0, "value1".to_owned())?;
+    assert_eq!(store.metadata("key1".to_owned())?.version, version);
+    assert!(matches!(
+        store.set_if_version("key1".to_owned(), 0, "value2".to_owned()),
+        Err(MyError::Conflict(_))
+    ));
+    let version = store.set_if_version("key1".to_owned(), version, "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert!(matches!(
+        store.set_if_version("key1".to_owned(), version, "value4".to_owned()),
+        Err(MyError::Conflict(_))
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    let version = store.metadata("key1".to_owned())?.version;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.metadata("key1".to_owned())?.version, version);
+    let next = store.set_if_version("key1".to_owned(), version, "value4".to_owned())?;
+    assert!(next > version);
+    store.rpush("list".to_owned(), "a".to_owned())?;
+    assert!(matches!(
+        store.set_if_version("list".to_owned(), 0, "value".to_owned()),
+        Err(MyError::WrongType { .. })
+    ));
+
+    let mut store = MemEngine::new();
+    let version = store.set_if_version("key1".to_owned(), 0, "value1".to_owned())?;
+    store.set_if_version("key1".to_owned(), version, "value2".to_owned())?;
+    assert!(matches!(
+        store.set_if_version("key1".to_owned(), version, "value3".to_owned()),
+        Err(MyError::Conflict(_))
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+struct Metrics(Mutex<Vec<OpMetrics>>);
+
+impl MetricsSink for Metrics {
+    fn record(&self, metrics: &OpMetrics) {
+        self.0.lock().unwrap().push(*metrics);
+    }
+}
+
+// The metrics sink should get every get, set, remove and compaction, with
+// the bytes read or written and whether it succeeded
+#[test]
+fn metrics_sink() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let metrics = Arc::new(Metrics(Mutex::new(Vec::new())));
+    let options = KvStoreOptions::default().with_metrics_sink(metrics.clone());
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl("key2".to_owned(), "v".to_owned(), Duration::from_secs(60))?;
+    store.get("key1".to_owned())?;
+    store.reader().get("key3".to_owned())?;
+    assert!(store.remove("key3".to_owned()).is_err());
+    store.remove("key1".to_owned())?;
+    store.compact_now()?;
+    // operations made of gets and sets report those
+    store.get_set("key2".to_owned(), "value2".to_owned())?;
+
+    let recorded = metrics.0.lock().unwrap();
+    let ops: Vec<(OpKind, u64, bool)> = recorded
+        .iter()
+        .map(|metrics| (metrics.op, metrics.bytes, metrics.ok))
+        .collect();
+    assert_eq!(
+        ops[..6],
+        [
+            (OpKind::Set, 10, true),
+            (OpKind::Set, 5, true),
+            (OpKind::Get, 10, true),
+            (OpKind::Get, 4, true),
+            (OpKind::Remove, 4, false),
+            (OpKind::Remove, 4, true),
+        ]
+    );
+    assert_eq!(ops[7..], [(OpKind::Get, 5, true), (OpKind::Set, 10, true)]);
+    assert_eq!(recorded[6].op, OpKind::Compaction);
+    assert_eq!(recorded[6].op.name(), "compaction");
+    assert!(recorded[6].ok);
+    assert!(recorded[6].bytes > 0);
+    Ok(())
+}
+
+struct Recorder(Mutex<Vec<KeyEvent>>);
+
+impl EventListener for Recorder {
+    fn on_event(&self, event: &KeyEvent) {
+        self.0.lock().unwrap().push(event.clone());
+    }
+}
+
+// Listeners should hear of every write, in sequence order
+#[test]
+fn event_listeners() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    let recorder = Arc::new(Recorder(Mutex::new(Vec::new())));
+    store.add_listener(recorder.clone())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .remove("key1".to_owned());
+    store.write_batch(batch)?;
+    store.rpush("list".to_owned(), "a".to_owned())?;
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(2));
+    store.sweep_expired(10)?;
+
+    let events = recorder.0.lock().unwrap();
+    let writes: Vec<(&str, KeyOp, u64)> = events
+        .iter()
+        .map(|event| (event.key.as_str(), event.op, event.seq))
+        .collect();
+    assert_eq!(
+        writes,
+        vec![
+            ("key1", KeyOp::Set, 2),
+            ("key2", KeyOp::Set, 3),
+            ("key1", KeyOp::Remove, 4),
+            ("list", KeyOp::Push, 5),
+            ("key3", KeyOp::Set, 6),
+            ("key3", KeyOp::Remove, 7),
+        ]
+    );
+    assert!(events.iter().all(|event| event.timestamp > 0));
+    assert!(MemEngine::new().add_listener(recorder.clone()).is_err());
+    Ok(())
+}
+
+// Cached values should be served until written again, and count as hits
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().with_cache_capacity(64);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let reader = store.reader();
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.stats()?;
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+    assert_eq!(stats.cache_hit_ratio(), Some(0.5));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(reader.get("key1".to_owned())?, None);
+
+    // least recently used values make room for new ones
+    for key in 0..20 {
+        store.set(format!("key{}", key), format!("value{}", key))?;
+        store.get(format!("key{}", key))?;
+    }
+    store.get("key0".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.cache_misses, 23);
+    assert_eq!(store.get("key19".to_owned())?, Some("value19".to_owned()));
+    assert_eq!(store.stats()?.cache_hits, 2);
+
+    // readers hand out the cached value itself
+    assert_eq!(
+        reader.get_bytes("key19".to_owned())?,
+        Some(Bytes::from("value19"))
+    );
+    assert_eq!(store.stats()?.cache_hits, 3);
+    Ok(())
+}
+
+// A memory store bounded in size should evict keys as its policy says, or
+// refuse writes
+#[test]
+fn mem_engine_eviction() -> Result<()> {
+    // keys and values of 10 bytes, 5 of which fit
+    let entry = |key: usize| (format!("key{:02}", key), format!("val{:02}", key));
+    let mut store = MemEngine::new().with_max_memory(50, EvictionPolicy::NoEviction);
+    for key in 0..5 {
+        let (key, value) = entry(key);
+        store.set(key, value)?;
+    }
+    let (key, value) = entry(5);
+    assert!(store.set(key, value).is_err());
+    let mut batch = WriteBatch::new();
+    batch.remove("key00".to_owned());
+    batch.set("key05".to_owned(), "val05".to_owned());
+    store.write_batch(batch)?;
+    assert_eq!(store.stats()?.key_count, 5);
+
+    for policy in [EvictionPolicy::Lru, EvictionPolicy::Random] {
+        let mut store = MemEngine::new().with_max_memory(50, policy);
+        for key in 0..20 {
+            let (key, value) = entry(key);
+            store.set(key, value)?;
+        }
+        assert_eq!(store.stats()?.key_count, 5);
+        assert_eq!(store.get("key19".to_owned())?, Some("val19".to_owned()));
+    }
+
+    let mut store = MemEngine::new().with_max_memory(50, EvictionPolicy::TtlFirst);
+    for key in 0..4 {
+        let (key, value) = entry(key);
+        store.set(key, value)?;
+    }
+    let (key, value) = entry(4);
+    store.set_with_ttl(key, value, Duration::from_secs(60))?;
+    let (key, value) = entry(5);
+    store.set(key, value)?;
+    assert_eq!(store.get("key04".to_owned())?, None);
+    let (key, value) = entry(6);
+    assert!(store.set(key, value).is_err());
+    Ok(())
+}
+
+// The memory engine should expire keys like the kvs engine
+#[test]
+fn mem_engine_expiring_keys() -> Result<()> {
+    let mut store = MemEngine::new();
+    let ttl = Duration::from_millis(50);
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), ttl)?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    thread::sleep(ttl);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.scan("key".to_owned())?.len(), 1);
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+    assert_eq!(store.stats()?.key_count, 1);
+    assert_eq!(store.sweep_expired(10)?, vec!["key1".to_owned()]);
+    assert_eq!(store.stats()?.key_count, 1);
+    Ok(())
+}
+
+// Secondary indexes should follow writes and be rebuilt on open
+#[test]
+fn indexed_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let paths = ["$.email", "$.address.city", "$.tags[0]"];
+    let mut store = IndexedEngine::new(KvStore::open(temp_dir.path())?, &paths)?;
+
+    store.set(
+        "user1".to_owned(),
+        r#"{"email":"a@example.com","address":{"city":"Paris"},"tags":[1]}"#.to_owned(),
+    )?;
+    store.set(
+        "user2".to_owned(),
+        r#"{"email":"b@example.com","address":{"city":"Paris"}}"#.to_owned(),
+    )?;
+    store.set("plain".to_owned(), "not json".to_owned())?;
+    assert_eq!(
+        store.find_by_index("$.email", "a@example.com")?,
+        vec!["user1"]
+    );
+    assert_eq!(
+        store.find_by_index("$.address.city", "Paris")?,
+        vec!["user1", "user2"]
+    );
+    assert_eq!(store.find_by_index("$.tags[0]", "1")?, vec!["user1"]);
+    assert!(store.find_by_index("$.name", "a").is_err());
+
+    // overwrites and removes move keys out of the old entries
+    store.set(
+        "user1".to_owned(),
+        r#"{"email":"c@example.com","address":{"city":"Lyon"}}"#.to_owned(),
+    )?;
+    store.remove("user2".to_owned())?;
+    assert!(store.find_by_index("$.email", "a@example.com")?.is_empty());
+    assert!(store.find_by_index("$.address.city", "Paris")?.is_empty());
+    assert_eq!(
+        store.find_by_index("$.address.city", "Lyon")?,
+        vec!["user1"]
+    );
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set(
+            "user3".to_owned(),
+            r#"{"email":"c@example.com"}"#.to_owned(),
+        )
+        .remove("plain".to_owned());
+    store.write_batch(batch)?;
+    assert_eq!(
+        store.find_by_index("$.email", "c@example.com")?,
+        vec!["user1", "user3"]
+    );
+    drop(store);
+
+    let mut store = IndexedEngine::new(KvStore::open(temp_dir.path())?, &paths)?;
+    assert_eq!(
+        store.find_by_index("$.email", "c@example.com")?,
+        vec!["user1", "user3"]
+    );
+    assert_eq!(
+        store.find_by_index("$.address.city", "Lyon")?,
+        vec!["user1"]
+    );
+    Ok(())
+}
+
+// Parts of JSON values should be read and patched in place
+#[test]
+fn json_paths() -> Result<()> {
+    let mut store = MemEngine::new();
+    store.set(
+        "user1".to_owned(),
+        r#"{"name":"Ann","address":{"city":"Paris"},"tags":["a","b"]}"#.to_owned(),
+    )?;
+    assert_eq!(
+        store.get_path("user1".to_owned(), "$.address.city")?,
+        Some(r#""Paris""#.to_owned())
+    );
+    assert_eq!(
+        store.get_path("user1".to_owned(), "$.tags[1]")?,
+        Some(r#""b""#.to_owned())
+    );
+    assert_eq!(store.get_path("user1".to_owned(), "$.age")?, None);
+    assert_eq!(store.get_path("user2".to_owned(), "$.age")?, None);
+
+    store.set_path("user1".to_owned(), "$.address.city", r#""Lyon""#.to_owned())?;
+    store.set_path("user1".to_owned(), "$.age", "42".to_owned())?;
+    store.set_path("user1".to_owned(), "$.tags[0]", r#""c""#.to_owned())?;
+    assert_eq!(
+        store.get("user1".to_owned())?,
+        Some(r#"{"address":{"city":"Lyon"},"age":42,"name":"Ann","tags":["c","b"]}"#.to_owned())
+    );
+
+    assert!(matches!(
+        store.set_path("user2".to_owned(), "$.age", "1".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+    assert!(store
+        .set_path("user1".to_owned(), "$.tags[5]", "1".to_owned())
+        .is_err());
+    assert!(store
+        .set_path("user1".to_owned(), "$.zip.code", "1".to_owned())
+        .is_err());
+    assert!(store
+        .set_path("user1".to_owned(), "$.age", "not json".to_owned())
+        .is_err());
+    assert!(store.get_path("user1".to_owned(), "age").is_err());
+    Ok(())
+}
+
+// Lists should survive reopening and compaction
+#[test]
+fn lists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.rpush("queue".to_owned(), "b".to_owned())?, 1);
+    assert_eq!(store.rpush("queue".to_owned(), "c".to_owned())?, 2);
+    assert_eq!(store.lpush("queue".to_owned(), "a".to_owned())?, 3);
+    assert_eq!(
+        store.lrange("queue".to_owned(), 0, -1)?,
+        vec!["a", "b", "c"]
+    );
+    assert_eq!(store.lrange("queue".to_owned(), -2, 10)?, vec!["b", "c"]);
+    assert!(store.lrange("queue".to_owned(), 2, 1)?.is_empty());
+    assert_eq!(store.lpop("queue".to_owned())?, Some("a".to_owned()));
+    assert_eq!(store.get("queue".to_owned())?, None);
+
+    store.set("string".to_owned(), "value".to_owned())?;
+    assert!(store.lpush("string".to_owned(), "a".to_owned()).is_err());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.lrange("queue".to_owned(), 0, -1)?, vec!["b", "c"]);
+    assert_eq!(store.stats()?.key_count, 2);
+    // enough pushes and pops to compact the log a few times
+    for i in 0..300 {
+        store.lpush("queue".to_owned(), format!("front{}", i))?;
+        store.rpush("queue".to_owned(), format!("back{}", i))?;
+        store.lpop("queue".to_owned())?;
+    }
+    assert_eq!(store.lrange("queue".to_owned(), 0, 0)?, vec!["b"]);
+    assert_eq!(store.lrange("queue".to_owned(), -1, -1)?, vec!["back299"]);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.lrange("queue".to_owned(), 0, -1)?.len(), 302);
+    assert_eq!(store.lrange("queue".to_owned(), 0, 1)?, vec!["b", "c"]);
+    while store.lpop("queue".to_owned())?.is_some() {}
+    assert_eq!(store.stats()?.key_count, 1);
+
+    // setting or removing the key drops the list
+    store.rpush("list".to_owned(), "a".to_owned())?;
+    store.set("list".to_owned(), "value".to_owned())?;
+    assert!(store.lrange("list".to_owned(), 0, -1)?.is_empty());
+    store.remove("list".to_owned())?;
+    store.rpush("list".to_owned(), "a".to_owned())?;
+    store.remove("list".to_owned())?;
+    assert!(matches!(
+        store.remove("list".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+    drop(store);
+
+    let report = kvs::fsck(temp_dir.path(), false)?;
+    assert!(report.problems.is_empty(), "{:?}", report.problems);
+    Ok(())
+}
+
+// Hash fields should be written one record at a time and survive reopening
+// and compaction
+#[test]
+fn hashes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.hset("user1".to_owned(), "name".to_owned(), "Ann".to_owned())?;
+    store.hset("user1".to_owned(), "city".to_owned(), "Paris".to_owned())?;
+    store.hset("user1".to_owned(), "city".to_owned(), "Lyon".to_owned())?;
+    assert_eq!(
+        store.hget("user1".to_owned(), "city".to_owned())?,
+        Some("Lyon".to_owned())
+    );
+    assert_eq!(store.hget("user1".to_owned(), "age".to_owned())?, None);
+    assert_eq!(store.hget("user2".to_owned(), "name".to_owned())?, None);
+    assert_eq!(
+        store.hgetall("user1".to_owned())?,
+        vec![
+            ("city".to_owned(), "Lyon".to_owned()),
+            ("name".to_owned(), "Ann".to_owned())
+        ]
+    );
+    assert!(matches!(
+        store.hdel("user1".to_owned(), "age".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+
+    store.set("string".to_owned(), "value".to_owned())?;
+    store.rpush("list".to_owned(), "a".to_owned())?;
+    assert!(store
+        .hset("string".to_owned(), "a".to_owned(), "b".to_owned())
+        .is_err());
+    assert!(store
+        .hset("list".to_owned(), "a".to_owned(), "b".to_owned())
+        .is_err());
+    assert!(store.rpush("user1".to_owned(), "a".to_owned()).is_err());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.hgetall("user1".to_owned())?.len(), 2);
+    assert_eq!(store.stats()?.key_count, 3);
+    // enough field updates to compact the log a few times
+    for i in 0..300 {
+        store.hset("user1".to_owned(), "visits".to_owned(), i.to_string())?;
+    }
+    store.hdel("user1".to_owned(), "name".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.hgetall("user1".to_owned())?,
+        vec![
+            ("city".to_owned(), "Lyon".to_owned()),
+            ("visits".to_owned(), "299".to_owned())
+        ]
+    );
+    store.hdel("user1".to_owned(), "city".to_owned())?;
+    store.hdel("user1".to_owned(), "visits".to_owned())?;
+    assert!(store.hgetall("user1".to_owned())?.is_empty());
+    assert!(matches!(
+        store.remove("user1".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+    store.hset("user2".to_owned(), "name".to_owned(), "Bob".to_owned())?;
+    store.remove("user2".to_owned())?;
+    assert_eq!(store.hget("user2".to_owned(), "name".to_owned())?, None);
+    drop(store);
+
+    let report = kvs::fsck(temp_dir.path(), false)?;
+    assert!(report.problems.is_empty(), "{:?}", report.problems);
+    Ok(())
+}
+
+#[test]
+fn sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.sadd("tags".to_owned(), "rust".to_owned())?);
+    assert!(store.sadd("tags".to_owned(), "db".to_owned())?);
+    assert!(!store.sadd("tags".to_owned(), "rust".to_owned())?);
+    assert!(store.sismember("tags".to_owned(), "db".to_owned())?);
+    assert!(!store.sismember("tags".to_owned(), "go".to_owned())?);
+    assert!(!store.sismember("other".to_owned(), "db".to_owned())?);
+    assert_eq!(store.smembers("tags".to_owned())?, vec!["db", "rust"]);
+    assert!(!store.srem("tags".to_owned(), "go".to_owned())?);
+
+    store.set("string".to_owned(), "value".to_owned())?;
+    store.hset("hash".to_owned(), "a".to_owned(), "b".to_owned())?;
+    assert!(store.sadd("string".to_owned(), "a".to_owned()).is_err());
+    assert!(store.sadd("hash".to_owned(), "a".to_owned()).is_err());
+    assert!(store.rpush("tags".to_owned(), "a".to_owned()).is_err());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.smembers("tags".to_owned())?, vec!["db", "rust"]);
+    assert_eq!(store.stats()?.key_count, 3);
+    // enough churn to compact the log a few times
+    for i in 0..300 {
+        store.sadd("tags".to_owned(), format!("tag{}", i % 3))?;
+        store.srem("tags".to_owned(), format!("tag{}", (i + 1) % 3))?;
+    }
+    store.srem("tags".to_owned(), "db".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.smembers("tags".to_owned())?,
+        vec!["rust", "tag1", "tag2"]
+    );
+    for member in &["rust", "tag1", "tag2"] {
+        assert!(store.srem("tags".to_owned(), member.to_string())?);
+    }
+    assert!(store.smembers("tags".to_owned())?.is_empty());
+    assert!(matches!(
+        store.remove("tags".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+    store.sadd("colors".to_owned(), "red".to_owned())?;
+    store.set("colors".to_owned(), "red".to_owned())?;
+    assert!(!store.sismember("colors".to_owned(), "red".to_owned())?);
+    drop(store);
+
+    let report = kvs::fsck(temp_dir.path(), false)?;
+    assert!(report.problems.is_empty(), "{:?}", report.problems);
+    Ok(())
+}
+
+#[test]
+fn sorted_sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.zadd("board".to_owned(), "ann".to_owned(), 30.0)?);
+    assert!(store.zadd("board".to_owned(), "bob".to_owned(), -2.5)?);
+    assert!(store.zadd("board".to_owned(), "cid".to_owned(), 30.0)?);
+    assert!(!store.zadd("board".to_owned(), "bob".to_owned(), 12.0)?);
+    assert_eq!(
+        store.zrange_by_score("board".to_owned(), f64::MIN, f64::MAX)?,
+        vec![
+            ("bob".to_owned(), 12.0),
+            ("ann".to_owned(), 30.0),
+            ("cid".to_owned(), 30.0)
+        ]
+    );
+    assert_eq!(
+        store.zrange_by_score("board".to_owned(), 12.0, 29.0)?,
+        vec![("bob".to_owned(), 12.0)]
+    );
+    assert!(store
+        .zrange_by_score("board".to_owned(), 31.0, 40.0)?
+        .is_empty());
+    assert!(store
+        .zrange_by_score("other".to_owned(), 0.0, 1.0)?
+        .is_empty());
+    assert!(store
+        .zadd("board".to_owned(), "ann".to_owned(), f64::NAN)
+        .is_err());
+    store.sadd("tags".to_owned(), "a".to_owned())?;
+    assert!(store.zadd("tags".to_owned(), "a".to_owned(), 1.0).is_err());
+    assert!(store.sadd("board".to_owned(), "a".to_owned()).is_err());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.key_count, 2);
+    // enough new scores to compact the log a few times
+    for i in 0..300 {
+        store.zadd("board".to_owned(), "bob".to_owned(), f64::from(i))?;
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.zrange_by_score("board".to_owned(), 30.0, f64::INFINITY)?,
+        vec![
+            ("ann".to_owned(), 30.0),
+            ("cid".to_owned(), 30.0),
+            ("bob".to_owned(), 299.0)
+        ]
+    );
+    store.remove("board".to_owned())?;
+    assert!(store
+        .zrange_by_score("board".to_owned(), 0.0, 1.0)?
+        .is_empty());
+    drop(store);
+
+    let report = kvs::fsck(temp_dir.path(), false)?;
+    assert!(report.problems.is_empty(), "{:?}", report.problems);
+    Ok(())
+}
+
+// Keys and values past the size limits should be refused before anything
+// is written
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_max_key_bytes(8)
+        .with_max_value_bytes(16);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "a".repeat(16))?;
+    assert!(matches!(
+        store.set("key1".to_owned(), "a".repeat(17)),
+        Err(MyError::TooLarge(_))
+    ));
+    assert!(matches!(
+        store.set("k".repeat(9), "value".to_owned()),
+        Err(MyError::TooLarge(_))
+    ));
+    assert!(matches!(
+        store.rpush("list".to_owned(), "a".repeat(17)),
+        Err(MyError::TooLarge(_))
+    ));
+    assert!(matches!(
+        store.hset("hash".to_owned(), "f".repeat(9), "value".to_owned()),
+        Err(MyError::TooLarge(_))
+    ));
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value".to_owned());
+    batch.set("key3".to_owned(), "a".repeat(17));
+    assert!(matches!(
+        store.write_batch(batch),
+        Err(MyError::TooLarge(_))
+    ));
+
+    assert_eq!(store.get("key1".to_owned())?, Some("a".repeat(16)));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(
+        store.lrange("list".to_owned(), 0, -1)?,
+        Vec::<String>::new()
+    );
+    assert_eq!(store.stats()?.key_count, 1);
+
+    Ok(())
+}
+
+// A store at its disk limit should refuse writes adding data, still serve
+// reads and removals, and take writes again once compaction makes room
+#[test]
+fn disk_quota() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().with_max_disk_bytes(4096);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let mut written = 0;
+    loop {
+        match store.set(format!("key{}", written), "a".repeat(100)) {
+            Ok(()) => written += 1,
+            Err(MyError::QuotaExceeded(_)) => break,
+            Err(e) => return Err(e),
+        }
+        assert!(written < 100, "the disk limit was never reached");
+    }
+    assert!(store.stats()?.disk_usage >= 4096);
+    assert!(matches!(
+        store.rpush("list".to_owned(), "value".to_owned()),
+        Err(MyError::QuotaExceeded(_))
+    ));
+    assert_eq!(store.get("key0".to_owned())?, Some("a".repeat(100)));
+
+    for i in 0..written / 2 {
+        store.remove(format!("key{}", i))?;
+    }
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert!(store.stats()?.disk_usage < 4096);
+    assert_eq!(
+        store.get(format!("key{}", written - 1))?,
+        Some("a".repeat(100))
+    );
+
+    Ok(())
+}
+
+// Each compaction should be counted, with what it reclaimed and rewrote
+#[test]
+fn compaction_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().with_compaction_threshold(1024);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.last_compaction_stats, None);
+
+    for iter in 0..50 {
+        for key_id in 0..5 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    let stats = store.stats()?;
+    assert!(stats.compactions > 0);
+    let last = stats.last_compaction_stats.unwrap();
+    assert_eq!(last.trigger, CompactionTrigger::Threshold);
+    assert_eq!(last.records_rewritten, 5);
+    assert!(last.bytes_reclaimed > 512);
+    assert!(stats.bytes_reclaimed >= last.bytes_reclaimed);
+
+    Ok(())
+}
+
+// Compaction should wait for a window to run in, and take at least as long
+// as its rate allows
+#[test]
+fn compaction_windows_and_rate() -> Result<()> {
+    let window: CompactionWindow = "22:00-04:00".parse()?;
+    let at =
+        |hours: u64, minutes: u64| UNIX_EPOCH + Duration::from_secs(hours * 3600 + minutes * 60);
+    assert!(window.contains(at(23, 0)));
+    assert!(window.contains(at(24 + 3, 59)));
+    assert!(!window.contains(at(4, 0)));
+    assert!(!window.contains(at(12, 0)));
+    assert_eq!(window.to_string(), "22:00-04:00");
+    assert!("24:00-01:00".parse::<CompactionWindow>().is_err());
+    assert!("02:00".parse::<CompactionWindow>().is_err());
+
+    // a window of a minute that is not the current one
+    let minute = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 60
+        % 1440;
+    let time = |minute: u64| format!("{:02}:{:02}", minute / 60 % 24, minute % 60);
+    let later = format!("{}-{}", time(minute + 60), time(minute + 61));
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_compaction_threshold(1024)
+        .with_compaction_windows(vec![later.parse()?]);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..50 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(store.stats()?.compactions, 0);
+
+    // a few hundred bytes of live records at 1000 bytes per second
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_compaction_threshold(4096)
+        .with_compaction_rate(1000);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..50 {
+        for key_id in 0..5 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    let last = store.stats()?.last_compaction_stats.unwrap();
+    assert_eq!(last.records_rewritten, 5);
+    assert!(last.duration >= Duration::from_millis(200));
+    Ok(())
+}
+
+// Writes should slow down past the soft stall limit while compaction waits
+// for its window, and compact past the hard one
+#[test]
+fn write_stalls() -> Result<()> {
+    let minute = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 60
+        % 1440;
+    let time = |minute: u64| format!("{:02}:{:02}", minute / 60 % 24, minute % 60);
+    let later = format!("{}-{}", time(minute + 60), time(minute + 61));
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_compaction_threshold(1024)
+        .with_compaction_windows(vec![later.parse()?])
+        .with_soft_stall_bytes(2048)
+        .with_hard_stall_bytes(8192);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "0".to_owned())?;
+    let stats = store.stats()?;
+    assert!(!stats.write_stalled);
+    assert_eq!(stats.stalled_writes, 0);
+
+    let mut iter = 1;
+    while !store.stats()?.write_stalled {
+        store.set("key".to_owned(), format!("{}", iter))?;
+        iter += 1;
+    }
+    let started = Instant::now();
+    for _ in 0..10 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+        iter += 1;
+    }
+    assert!(started.elapsed() >= Duration::from_millis(10));
+    assert!(store.stats()?.stalled_writes >= 10);
+    assert_eq!(store.stats()?.compactions, 0);
+
+    while store.stats()?.compactions == 0 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+        iter += 1;
+    }
+    let stats = store.stats()?;
+    assert!(!stats.write_stalled);
+    assert_eq!(
+        stats.last_compaction_stats.unwrap().trigger,
+        CompactionTrigger::WriteStall
+    );
+    assert_eq!(store.get("key".to_owned())?, Some(format!("{}", iter - 1)));
+    Ok(())
+}
+
+// Bulk-loaded keys should be readable, survive reopening and overwrite
+// existing ones, while unsorted input should load nothing
+#[test]
+fn bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key0001".to_owned(), "old".to_owned())?;
+    store.set("other".to_owned(), "kept".to_owned())?;
+
+    let pairs = (0..1000).map(|i| (format!("key{:04}", i), format!("value{}", i)));
+    assert_eq!(store.bulk_load(pairs)?, 1000);
+    assert_eq!(store.get("key0001".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        store.get("key0999".to_owned())?,
+        Some("value999".to_owned())
+    );
+    assert_eq!(store.get("other".to_owned())?, Some("kept".to_owned()));
+    assert!(!temp_dir.path().join("bulk_load.json").exists());
+
+    let unsorted = vec![
+        ("b".to_owned(), "1".to_owned()),
+        ("a".to_owned(), "2".to_owned()),
+    ];
+    assert!(matches!(
+        store.bulk_load(unsorted),
+        Err(MyError::StringError(_))
+    ));
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert!(!temp_dir.path().join("bulk_load.json").exists());
+
+    store.set("key1000".to_owned(), "after".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.key_count, 1002);
+    assert_eq!(
+        store.get("key0500".to_owned())?,
+        Some("value500".to_owned())
+    );
+    assert_eq!(store.get("key1000".to_owned())?, Some("after".to_owned()));
+
+    Ok(())
+}
+
+fn add(_key: &str, value: Option<&str>, operand: &str) -> String {
+    let value: i64 = value.map_or(0, |value| value.parse().unwrap());
+    (value + operand.parse::<i64>().unwrap()).to_string()
+}
+
+// Merge operands should be combined with the value on reads, survive
+// reopening and be folded into the value by compaction
+#[test]
+fn merge_operator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_compaction_threshold(1 << 20)
+        .with_merge_operator(add);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    store.merge("counter".to_owned(), "+5".to_owned())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("5".to_owned()));
+    store.set("other".to_owned(), "10".to_owned())?;
+    store.merge("other".to_owned(), "-3".to_owned())?;
+    store.merge("other".to_owned(), "+1".to_owned())?;
+    assert_eq!(store.get("other".to_owned())?, Some("8".to_owned()));
+    assert_eq!(
+        store.scan(String::new())?,
+        vec![
+            ("counter".to_owned(), "5".to_owned()),
+            ("other".to_owned(), "8".to_owned())
+        ]
+    );
+    store.lpush("list".to_owned(), "a".to_owned())?;
+    assert!(store.merge("list".to_owned(), "1".to_owned()).is_err());
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("other".to_owned())?, Some("8".to_owned()));
+    store.set_compaction_threshold(0)?;
+    store.merge("counter".to_owned(), "+2".to_owned())?;
+    assert!(store.stats()?.last_compaction.is_some());
+    assert_eq!(store.get("counter".to_owned())?, Some("7".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, Some("8".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("7".to_owned()));
+    assert!(store.merge("counter".to_owned(), "+1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Hooks should see every string write with its new value, and removals
+#[test]
+fn write_and_remove_hooks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let writes = Arc::clone(&seen);
+    store.on_write(move |key, value| {
+        writes
+            .lock()
+            .unwrap()
+            .push(format!("write {}={}", key, value))
+    });
+    let removes = Arc::clone(&seen);
+    store.on_remove(move |key| removes.lock().unwrap().push(format!("remove {}", key)));
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.set("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch)?;
+    store.rpush("list".to_owned(), "a".to_owned())?;
+    store.remove("list".to_owned())?;
+
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![
+            "write key1=value1",
+            "remove key1",
+            "write key2=value2",
+            "write key3=value3",
+            "remove list",
+        ]
+    );
+    Ok(())
+}
+
+// Errors should say where corrupt data is and what kind a key holds, and
+// only corruption should count as fatal
+#[test]
+fn structured_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().with_value_threshold(100);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("large".to_owned(), "x".repeat(200))?;
+    store.rpush("list".to_owned(), "a".to_owned())?;
+
+    match store.hset("list".to_owned(), "field".to_owned(), "value".to_owned()) {
+        Err(err @ MyError::WrongType { .. }) => {
+            assert!(!err.is_fatal());
+            assert_eq!(err.to_string(), "`list` holds a list, not a hash");
+        }
+        other => panic!("expected WrongType, got {:?}", other),
+    }
+    assert!(!MyError::KeyNotFound.is_fatal());
+
+    let value_log = temp_dir.path().join("values.0.log");
+    let mut file = OpenOptions::new().write(true).open(&value_log)?;
+    file.write_all(b"y")?;
+    drop(file);
+    match store.get("large".to_owned()) {
+        Err(err @ MyError::Corrupt { .. }) => {
+            assert!(err.is_fatal());
+            if let MyError::Corrupt { path, offset, .. } = &err {
+                assert_eq!(path.as_deref(), Some(value_log.as_path()));
+                assert_eq!(*offset, Some(0));
+            }
+        }
+        other => panic!("expected Corrupt, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// Opening verified should read every key back, and refuse a store whose
+// separated values no longer match their checksums
+#[test]
+fn open_verified() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().with_value_threshold(100);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("large".to_owned(), "x".repeat(200))?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.rpush("list".to_owned(), "a".to_owned())?;
+    store.hset("hash".to_owned(), "field".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let (store, report) = KvStore::open_verified(temp_dir.path(), options.clone())?;
+    assert_eq!(report.keys, 4);
+    assert_eq!(report.separated_values, 1);
+    assert!(report.problems.is_empty());
+    drop(store);
+
+    let value_log = temp_dir.path().join("values.0.log");
+    let mut file = OpenOptions::new().write(true).open(value_log)?;
+    file.write_all(b"y")?;
+    drop(file);
+    assert!(matches!(
+        KvStore::open_verified(temp_dir.path(), options.clone()),
+        Err(MyError::Corrupt { .. })
+    ));
+    // a plain open only finds out on reading the value
+    KvStore::open_with_options(temp_dir.path(), options)?;
+
+    Ok(())
+}
+
+// Clearing should drop every key, separated values and collections
+// included, for good, while later writes are kept as usual
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().with_value_threshold(16);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "a".repeat(100))?;
+    store.rpush("list".to_owned(), "item".to_owned())?;
+    let reader = store.reader();
+
+    store.clear()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(store.lrange("list".to_owned(), 0, -1)?.is_empty());
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    assert_eq!(store.stats()?.key_count, 0);
+    // replicas and backups behind the clear need a full copy
+    assert!(store.changes_since(1).is_err());
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.scan(String::new())?.len(), 1);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.changes_since(4)?.len(), 1);
+
+    let mut indexed = IndexedEngine::new(MemEngine::new(), &["$.email"])?;
+    indexed.set(
+        "user1".to_owned(),
+        r#"{"email":"a@example.com"}"#.to_owned(),
+    )?;
+    indexed.clear()?;
+    assert_eq!(indexed.get("user1".to_owned())?, None);
+    assert!(indexed
+        .find_by_index("$.email", "a@example.com")?
+        .is_empty());
+    Ok(())
+}
+
+// Keys of every kind should be found and counted, expired ones left out
+#[test]
+fn contains_key_and_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.hset("hash".to_owned(), "field".to_owned(), "value".to_owned())?;
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+    assert!(store.contains_key("key1".to_owned())?);
+    assert!(store.contains_key("hash".to_owned())?);
+    assert!(!store.contains_key("short".to_owned())?);
+    assert!(!store.contains_key("key2".to_owned())?);
+    assert_eq!(store.len()?, 2);
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.len()?, 1);
+
+    let mut store = MemEngine::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+    assert!(store.contains_key("key1".to_owned())?);
+    assert!(!store.contains_key("short".to_owned())?);
+    assert_eq!(store.len()?, 1);
+    Ok(())
+}
+
+// Keys should be filtered by glob patterns, collections included
+#[test]
+fn keys_glob() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in &[
+        "session:1:active",
+        "session:2:idle",
+        "session:10:active",
+        "user:1",
+    ] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    store.lpush("session:3:active".to_owned(), "item".to_owned())?;
+    assert_eq!(
+        store.keys("session:*:active".to_owned())?,
+        vec!["session:10:active", "session:1:active", "session:3:active"]
+    );
+    assert_eq!(
+        store.keys("session:?:*".to_owned())?,
+        vec!["session:1:active", "session:2:idle", "session:3:active"]
+    );
+    assert_eq!(
+        store.keys("session:[!1]:*".to_owned())?,
+        vec!["session:2:idle", "session:3:active"]
+    );
+    assert_eq!(store.keys("[t-v]ser:1".to_owned())?, vec!["user:1"]);
+    assert_eq!(store.keys("*".to_owned())?.len(), 5);
+    assert!(store.keys("user:\\*".to_owned())?.is_empty());
+    assert!(store.keys("session:[1".to_owned()).is_err());
+
+    let mut store = MemEngine::new();
+    store.set("a*b".to_owned(), "value".to_owned())?;
+    store.set("axb".to_owned(), "value".to_owned())?;
+    assert_eq!(store.keys("a\\*b".to_owned())?, vec!["a*b"]);
+    assert_eq!(store.keys("a*b".to_owned())?, vec!["a*b", "axb"]);
+    assert_eq!(store.keys("a[]x]b".to_owned())?, vec!["axb"]);
+    Ok(())
+}
+
+// Pages of keys should follow one another, collections included
+#[test]
+fn keys_after() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "value".to_owned())?;
+    store.set("key1".to_owned(), "value".to_owned())?;
+    store.lpush("key2".to_owned(), "item".to_owned())?;
+    store.set("key3".to_owned(), "value".to_owned())?;
+    store.sadd("key4".to_owned(), "member".to_owned())?;
+    assert_eq!(
+        store.keys_after("key".to_owned(), None, 3)?,
+        vec!["key1", "key2", "key3"]
+    );
+    assert_eq!(
+        store.keys_after("key".to_owned(), Some("key3".to_owned()), 3)?,
+        vec!["key4"]
+    );
+    assert_eq!(
+        store.keys_after("key".to_owned(), Some("a".to_owned()), 1)?,
+        vec!["key1"]
+    );
+    Ok(())
+}
+
+// Swaps should only happen when the key holds the expected value
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.compare_and_swap("key1".to_owned(), None, Some("a".to_owned()), None)?);
+    assert!(!store.compare_and_swap("key1".to_owned(), None, Some("b".to_owned()), None)?);
+    assert!(!store.compare_and_swap(
+        "key1".to_owned(),
+        Some("b".to_owned()),
+        Some("c".to_owned()),
+        None
+    )?);
+    assert!(store.compare_and_swap(
+        "key1".to_owned(),
+        Some("a".to_owned()),
+        Some("c".to_owned()),
+        None
+    )?);
+    assert_eq!(store.get("key1".to_owned())?, Some("c".to_owned()));
+    assert!(store.compare_and_swap("key1".to_owned(), Some("c".to_owned()), None, None)?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    assert!(store.compare_and_swap(
+        "key2".to_owned(),
+        None,
+        Some("a".to_owned()),
+        Some(Duration::from_millis(1))
+    )?);
+    thread::sleep(Duration::from_millis(10));
+    assert!(store.compare_and_swap("key2".to_owned(), None, Some("b".to_owned()), None)?);
+    Ok(())
+}
+
+// A snapshot should open as a store holding the data at the time it was
+// taken, values in the value log included
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().with_value_threshold(8);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "a longer value2".to_owned())?;
+    let snapshot_dir = temp_dir.path().join("snapshot");
+    store.snapshot(&snapshot_dir)?;
+    store.set("key1".to_owned(), "changed".to_owned())?;
+
+    let mut snapshot = KvStore::open_with_options(&snapshot_dir, options)?;
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        snapshot.get("key2".to_owned())?,
+        Some("a longer value2".to_owned())
+    );
+
+    let mut memory = MemEngine::new();
+    assert!(memory.snapshot(&snapshot_dir).is_err());
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct User {
+    name: String,
+    age: u32,
+}
+
+// Typed keys and values should round-trip through the store, string keys
+// stored as they are
+#[test]
+fn typed_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut users: TypedKvStore<_, u64, User> = TypedKvStore::new(KvStore::open(temp_dir.path())?);
+    let ada = User {
+        name: "Ada".to_owned(),
+        age: 36,
+    };
+    users.set(&2, &ada)?;
+    users.set(
+        &1,
+        &User {
+            name: "Alan".to_owned(),
+            age: 41,
+        },
+    )?;
+    assert_eq!(users.get(&2)?, Some(ada.clone()));
+    assert_eq!(users.get(&3)?, None);
+    assert_eq!(
+        users
+            .entries()?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    users.remove(&1)?;
+    assert!(!users.contains_key(&1)?);
+
+    let mut store = users.into_inner();
+    assert!(store.get("2".to_owned())?.is_some());
+    store.set("3".to_owned(), "not a user".to_owned())?;
+    let mut users: TypedKvStore<_, u64, User> = TypedKvStore::new(store);
+    assert!(matches!(users.get(&3), Err(MyError::StringError(_))));
+
+    let mut names: TypedKvStore<_, String, u32> = TypedKvStore::new(users.into_inner());
+    names.set(&"7".to_owned(), &7)?;
+    assert_eq!(names.get(&"7".to_owned())?, Some(7));
+    assert_eq!(names.get_mut().get("7".to_owned())?, Some("7".to_owned()));
+    Ok(())
+}
+
+// Composite keys sort like their parts and scan by their first parts
+#[test]
+fn composite_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let keys = vec![
+        Key::from(("user", 9u64, -5i64)),
+        Key::from(("user", 9u64, 3i64)),
+        Key::from(("user", 10u64, i64::MIN)),
+        Key::from(("user", 10u64, i64::MAX)),
+        Key::from(("user\0", 0u64)),
+        Key::from(("user\0a", 0u64)),
+        Key::from(("usera", 0u64)),
+    ];
+    for key in keys.iter().rev() {
+        store.set(key.encode(), "value".to_owned())?;
+    }
+    let stored: Vec<Key> = store
+        .scan(String::new())?
+        .iter()
+        .map(|(key, _)| Key::decode(key))
+        .collect::<Result<_>>()?;
+    assert_eq!(stored, keys);
+
+    let user = store.scan(Key::from(("user", 10u64)).encode())?;
+    assert_eq!(user.len(), 2);
+    assert_eq!(Key::decode(&user[0].0)?, keys[2]);
+    assert_eq!(store.scan(Key::from(("user",)).encode())?.len(), 4);
+
+    let key = Key::new().with("a\0b").with(7u64);
+    assert_eq!(
+        Key::decode(&key.encode())?.parts(),
+        &[KeyPart::Str("a\0b".to_owned()), KeyPart::U64(7)]
+    );
+    assert!(Key::decode("user").is_err());
+    assert!(Key::decode("u00ff").is_err());
+    Ok(())
+}
+
+// The size report should count the live keys by namespace, in size buckets
+#[test]
+fn size_report() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("user:{}", i), "x".repeat(10))?;
+    }
+    store.set("blob:big".to_owned(), "x".repeat(100_000))?;
+    store.set("blob:gone".to_owned(), "x".repeat(100_000))?;
+    store.remove("blob:gone".to_owned())?;
+    store.set("plain".to_owned(), "value".to_owned())?;
+    store.rpush("queue:jobs".to_owned(), "job".to_owned())?;
+    store.rpush("queue:jobs".to_owned(), "job".to_owned())?;
+
+    let report = store.size_report()?;
+    assert_eq!(report.total.keys, 13);
+    let names: Vec<&str> = report
+        .namespaces
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect();
+    assert_eq!(names, vec!["blob", "user", "queue", ""]);
+    let (_, blob) = &report.namespaces[0];
+    assert_eq!(blob.keys, 1);
+    assert_eq!(blob.key_bytes, 8);
+    assert!(blob.bytes > 100_000);
+    assert_eq!(blob.histogram[6], 1);
+    let (_, user) = &report.namespaces[1];
+    assert_eq!(user.keys, 10);
+    // a record takes more than its value: 64 to 256 bytes here
+    assert_eq!(user.histogram[1], 10);
+    assert_eq!(report.total.histogram.iter().sum::<u64>(), 13);
+    drop(report);
+
+    // reopening rebuilds the same index
+    let report = store.size_report()?;
+    drop(store);
+    assert_eq!(KvStore::open(temp_dir.path())?.size_report()?, report);
+    Ok(())
+}
+
+// Removed strings should wait in the trash until restored, purged or expired
+#[test]
+fn trash() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().with_trash(Duration::from_secs(60));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.rpush("list".to_owned(), "item".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.remove("list".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(
+        store.get(format!("{}key1", TRASH_PREFIX))?,
+        Some("value1".to_owned())
+    );
+    assert!(store.ttl(format!("{}key1", TRASH_PREFIX))?.is_some());
+    assert_eq!(store.keys(format!("{}*", TRASH_PREFIX))?.len(), 2);
+
+    // the trash survives reopening
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.restore("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.ttl("key1".to_owned())?, None);
+    assert_eq!(store.get(format!("{}key1", TRASH_PREFIX))?, None);
+    assert!(matches!(
+        store.restore("key1".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+    assert!(matches!(
+        store.restore("list".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+
+    store.set("key2".to_owned(), "newer".to_owned())?;
+    assert!(matches!(
+        store.restore("key2".to_owned()),
+        Err(MyError::Conflict(_))
+    ));
+    assert_eq!(store.purge()?, 1);
+    assert_eq!(store.purge()?, 0);
+    assert_eq!(
+        store.keys(format!("{}*", TRASH_PREFIX))?,
+        Vec::<String>::new()
+    );
+
+    // keys of the trash are removed for good
+    store.remove("key2".to_owned())?;
+    store.remove(format!("{}key2", TRASH_PREFIX))?;
+    assert_eq!(store.len()?, 1);
+
+    // without a trash, removals are final
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.len()?, 0);
+    assert!(matches!(
+        MemEngine::new().restore("key1".to_owned()),
+        Err(MyError::StringError(_))
+    ));
+    Ok(())
+}
+
+// A partitioned store should spread keys over its partitions, from many threads
+#[test]
+fn partitioned_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = PartitionedKvStore::open(temp_dir.path(), 4)?;
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    store.set(format!("key{}-{}", t, i), format!("value{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    assert_eq!(store.len()?, 400);
+    assert_eq!(store.get("key2-7".to_owned())?, Some("value7".to_owned()));
+    store.remove("key2-7".to_owned())?;
+    assert!(matches!(
+        store.remove("key2-7".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+    let scanned = store.scan("key1-".to_owned())?;
+    assert_eq!(scanned.len(), 100);
+    assert!(scanned.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(store.keys("key3-9?".to_owned())?.len(), 10);
+
+    // every partition holds some of the keys, in a store of its own
+    for i in 0..4 {
+        let partition = temp_dir.path().join(format!("partition-{}", i));
+        assert!(partition.join("log.json").exists());
+    }
+    store.with_partition("key0-0", |partition| {
+        assert!(partition.len()? < 200);
+        Ok(())
+    })?;
+
+    // the count of partitions is kept with them
+    drop(store);
+    assert!(PartitionedKvStore::open(temp_dir.path(), 3).is_err());
+    let store = PartitionedKvStore::open(temp_dir.path(), 4)?;
+    assert_eq!(store.len()?, 399);
+    drop(store);
+
+    // a partitioner of our own places keys by their first character
+    struct ByInitial;
+    impl Partitioner for ByInitial {
+        fn partition(&self, key: &str, partitions: usize) -> usize {
+            key.bytes().next().unwrap_or(0) as usize % partitions
+        }
+    }
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        PartitionedKvStore::open_with(temp_dir.path(), 2, KvStoreOptions::default(), ByInitial)?;
+    store.set("a1".to_owned(), "value".to_owned())?;
+    store.set("a2".to_owned(), "value".to_owned())?;
+    assert_eq!(store.partition_of("a1"), store.partition_of("a2"));
+    store.with_partition("a", |partition| {
+        assert_eq!(partition.len()?, 2);
+        Ok(())
+    })?;
+    Ok(())
+}
+
+// An adaptive write buffer should grow for batches and shrink back for
+// small writes, and small read buffers should still read whole values.
+#[test]
+fn adaptive_buffers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_compaction_threshold(u64::MAX)
+        .with_write_buffer(WriteBuffer::Adaptive {
+            min: 1024,
+            max: 64 << 10,
+        })
+        .with_read_buffer(16);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.stats()?.write_buffer_bytes, 1024);
+
+    let mut batch = WriteBatch::new();
+    for i in 0..200 {
+        batch.set(format!("key{}", i), "x".repeat(100));
+    }
+    store.write_batch(batch)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let grown = store.stats()?.write_buffer_bytes;
+    assert!(grown > 16 << 10, "{} bytes", grown);
+    assert!(grown <= 64 << 10, "{} bytes", grown);
+
+    for i in 0..200 {
+        store.set("key".to_owned(), i.to_string())?;
+    }
+    assert!(store.stats()?.write_buffer_bytes < grown);
+    assert_eq!(store.get("key0".to_owned())?, Some("x".repeat(100)));
+    assert_eq!(store.get("key".to_owned())?, Some("199".to_owned()));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key199".to_owned())?, Some("x".repeat(100)));
+    assert_eq!(store.stats()?.write_buffer_bytes, 8 << 10);
+    Ok(())
+}
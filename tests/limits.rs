@@ -0,0 +1,176 @@
+use assert_cmd::prelude::*;
+use kvs::{KvsClient, MyError};
+use std::fs;
+use std::io::Read;
+use std::net::TcpStream;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Clients beyond the connection limit should get an error until a slot frees.
+#[test]
+fn max_connections() {
+    let addr = "127.0.0.1:4019";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--max-connections", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut first = KvsClient::connect(addr).unwrap();
+    first.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    // refused at the handshake
+    let err = KvsClient::connect(addr).err().unwrap();
+    assert_eq!(err.to_string(), "Too many connections");
+
+    drop(first);
+    thread::sleep(Duration::from_millis(200));
+    let mut third = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        third.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Connections sending nothing for the idle timeout should be closed.
+#[test]
+fn idle_timeout() {
+    let addr = "127.0.0.1:4020";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--idle-timeout", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let mut idle = TcpStream::connect(addr).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(idle.read(&mut [0; 1]).unwrap(), 0);
+
+    // the client notices and connects again
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Requests beyond the rate limit of the connection or of its token should
+// get an error until the limit refills.
+#[test]
+fn rate_limit() {
+    let addr = "127.0.0.1:4055";
+    let temp_dir = TempDir::new().unwrap();
+    let acl = temp_dir.path().join("acl.toml");
+    fs::write(
+        &acl,
+        r#"
+[[token]]
+token = "limited"
+operations = ["read", "write"]
+prefixes = ["*"]
+rate_limit = 3
+
+[[token]]
+token = "free"
+operations = ["read", "write"]
+prefixes = ["*"]
+"#,
+    )
+    .unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--rate-limit", "5"])
+        .arg("--acl")
+        .arg(&acl)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // the authentication counts for the connection only
+    let mut free = KvsClient::connect_with_auth(addr, "free".to_owned()).unwrap();
+    for _ in 0..4 {
+        assert_eq!(free.get("key1".to_owned()).unwrap(), None);
+    }
+    assert!(matches!(
+        free.get("key1".to_owned()),
+        Err(MyError::RateLimited)
+    ));
+
+    // connections of a token share its limit
+    let mut first = KvsClient::connect_with_auth(addr, "limited".to_owned()).unwrap();
+    for _ in 0..3 {
+        assert_eq!(first.get("key1".to_owned()).unwrap(), None);
+    }
+    let mut second = KvsClient::connect_with_auth(addr, "limited".to_owned()).unwrap();
+    assert!(matches!(
+        second.get("key1".to_owned()),
+        Err(MyError::RateLimited)
+    ));
+
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(free.get("key1".to_owned()).unwrap(), None);
+    assert_eq!(second.get("key1".to_owned()).unwrap(), None);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Values past the size limit should get a dedicated error, and requests
+// too large to hold one should be refused before being read.
+#[test]
+fn size_limits() {
+    let addr = "127.0.0.1:4056";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--max-key-bytes", "16"])
+        .args(["--max-value-bytes", "1024"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "a".repeat(1024)).unwrap();
+    let err = client.set("key1".to_owned(), "a".repeat(1025)).unwrap_err();
+    assert!(matches!(err, MyError::TooLarge(_)));
+    assert_eq!(
+        err.to_string(),
+        "Value of 1025 bytes exceeds the limit of 1024 bytes"
+    );
+    assert!(matches!(
+        client.set("k".repeat(17), "value".to_owned()),
+        Err(MyError::TooLarge(_))
+    ));
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("a".repeat(1024))
+    );
+
+    // the frame is refused by its header, and the connection closed
+    let err = client
+        .set("key1".to_owned(), "a".repeat(128 * 1024))
+        .unwrap_err();
+    assert!(err.to_string().ends_with("bytes exceeds the size limit"));
+    // the next request goes over a new connection
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("a".repeat(1024))
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
@@ -0,0 +1,183 @@
+use kvs::{KvsEngine, KvsReader, LsmEngine, LsmOptions, MyError, Result, WriteBatch};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use tempfile::TempDir;
+
+/// Options flushing and compacting after a few writes.
+fn small() -> LsmOptions {
+    LsmOptions::default()
+        .with_memtable_bytes(256)
+        .with_table_bytes(512)
+}
+
+fn table_count(temp_dir: &TempDir) -> usize {
+    fs::read_dir(temp_dir.path())
+        .unwrap()
+        .filter(|file| {
+            let path = file.as_ref().unwrap().path();
+            path.extension().is_some_and(|ext| ext == "sst")
+        })
+        .count()
+}
+
+// Writes should be read back, from the memtable and after a restart from
+// the write-ahead log
+#[test]
+fn lsm_get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(matches!(
+        store.remove("key2".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    let mut store = LsmEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(table_count(&temp_dir), 0);
+    Ok(())
+}
+
+// Flushed and compacted tables should keep the latest value of every key,
+// and removals should hide older values on deeper levels
+#[test]
+fn lsm_flush_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmEngine::open_with_options(temp_dir.path(), small())?;
+    for round in 0..20 {
+        for key in 0..200 {
+            store.set(format!("key{:03}", key), format!("value{}-{}", round, key))?;
+        }
+    }
+    for key in (0..200).step_by(5) {
+        store.remove(format!("key{:03}", key))?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.key_count, 160);
+    assert!(stats.last_compaction.is_some());
+    assert!(stats.compactions > 0);
+    assert!(stats.last_compaction_stats.unwrap().records_rewritten > 0);
+    assert!(table_count(&temp_dir) as u64 == stats.segment_count);
+
+    let check = |store: &mut LsmEngine| -> Result<()> {
+        for key in 0..200 {
+            let expected = match key % 5 {
+                0 => None,
+                _ => Some(format!("value19-{}", key)),
+            };
+            assert_eq!(store.get(format!("key{:03}", key))?, expected);
+        }
+        let pairs = store.scan("key01".to_owned())?;
+        let keys: Vec<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(
+            keys,
+            ["key011", "key012", "key013", "key014", "key016", "key017", "key018", "key019"]
+        );
+        assert_eq!(store.get("missing".to_owned())?, None);
+        Ok(())
+    };
+    check(&mut store)?;
+    let reader = store.reader();
+    assert_eq!(reader.scan(String::new())?.len(), 160);
+    drop(reader);
+    drop(store);
+
+    let mut store = LsmEngine::open_with_options(temp_dir.path(), small())?;
+    check(&mut store)
+}
+
+// A batch should apply as a whole, and a torn one be dropped on restart
+#[test]
+fn lsm_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("missing".to_owned());
+    assert!(matches!(
+        store.write_batch(batch),
+        Err(MyError::KeyNotFound)
+    ));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    store.write_batch(batch)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // a crash in the middle of logging the next batch
+    let mut wal = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("lsm-wal.json"))?;
+    wal.write_all(br#"{"Batch":[{"Set":{"key":"key3","value":"va"#)?;
+    drop(wal);
+
+    let mut store = LsmEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let mut store = LsmEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Clearing should drop the memtable and every table, for good
+#[test]
+fn lsm_clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmEngine::open_with_options(temp_dir.path(), small())?;
+    for key in 0..100 {
+        store.set(format!("key{:03}", key), format!("value{}", key))?;
+    }
+    assert!(table_count(&temp_dir) > 0);
+
+    store.clear()?;
+    assert_eq!(store.get("key001".to_owned())?, None);
+    assert!(store.scan(String::new())?.is_empty());
+    assert_eq!(table_count(&temp_dir), 0);
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    drop(store);
+
+    let mut store = LsmEngine::open_with_options(temp_dir.path(), small())?;
+    assert_eq!(
+        store.scan(String::new())?,
+        vec![("key100".to_owned(), "value100".to_owned())]
+    );
+    Ok(())
+}
+
+// A snapshot should open as a store holding the data at the time it was
+// taken, both flushed tables and the write-ahead log
+#[test]
+fn lsm_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = LsmEngine::open_with_options(temp_dir.path(), small())?;
+    for key in 0..100 {
+        store.set(format!("key{:03}", key), format!("value{}", key))?;
+    }
+    assert!(table_count(&temp_dir) > 0);
+    let snapshot_dir = temp_dir.path().join("snapshot");
+    store.snapshot(&snapshot_dir)?;
+    store.remove("key001".to_owned())?;
+
+    let mut snapshot = LsmEngine::open_with_options(&snapshot_dir, small())?;
+    assert_eq!(snapshot.scan(String::new())?.len(), 100);
+    assert_eq!(
+        snapshot.get("key001".to_owned())?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
@@ -0,0 +1,93 @@
+#![cfg(all(feature = "otel", unix))]
+
+use assert_cmd::prelude::*;
+use kvs::KvsClient;
+use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState};
+use opentelemetry::Context;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Answers every OTLP/HTTP export on `listener` with success, sending the
+/// request bodies to `bodies`.
+fn collect(listener: TcpListener, bodies: mpsc::Sender<Vec<u8>>) {
+    for stream in listener.incoming() {
+        let mut stream = BufReader::new(stream.unwrap());
+        'requests: loop {
+            let mut content_length = 0;
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if stream.read_line(&mut line).unwrap() == 0 {
+                    break 'requests;
+                }
+                if line == "\r\n" {
+                    break;
+                }
+                let lower = line.to_ascii_lowercase();
+                if let Some(value) = lower.strip_prefix("content-length:") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            stream.read_exact(&mut body).unwrap();
+            let _ = bodies.send(body);
+            let response = "HTTP/1.1 200 OK\r\nContent-Type: application/x-protobuf\r\nContent-Length: 0\r\n\r\n";
+            stream.get_mut().write_all(response.as_bytes()).unwrap();
+        }
+    }
+}
+
+// A request sent within a trace should be exported as a server span of that
+// trace.
+#[test]
+fn request_spans_join_client_trace() {
+    let addr = "127.0.0.1:4074";
+    let collector = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}/v1/traces", collector.local_addr().unwrap());
+    let (bodies, exported) = mpsc::channel();
+    thread::spawn(move || collect(collector, bodies));
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--otlp-endpoint", &endpoint])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let trace_id = TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap();
+    let parent = SpanContext::new(
+        trace_id,
+        SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+        TraceFlags::SAMPLED,
+        true,
+        TraceState::default(),
+    );
+    let mut client = KvsClient::connect(addr).unwrap();
+    {
+        let _context = Context::current().with_remote_span_context(parent).attach();
+        client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    }
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    // spans are flushed on shutdown
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    child.wait().expect("failed to wait on server");
+    let exported: Vec<u8> = exported
+        .recv_timeout(Duration::from_secs(5))
+        .expect("no spans exported");
+    let trace_id = trace_id.to_bytes();
+    assert!(exported
+        .windows(trace_id.len())
+        .any(|bytes| bytes == trace_id));
+    assert!(exported.windows(3).any(|bytes| bytes == b"Set"));
+}
@@ -0,0 +1,125 @@
+use assert_cmd::prelude::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server(addr: &str, temp_dir: &TempDir) -> Child {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child
+}
+
+fn read_frame(stream: &mut TcpStream) -> Value {
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).unwrap();
+    let mut payload = vec![0; u32::from_be_bytes(header) as usize];
+    stream.read_exact(&mut payload).unwrap();
+    serde_json::from_slice(&payload).unwrap()
+}
+
+fn write_frame(stream: &mut TcpStream, message: &Value) {
+    let payload = serde_json::to_vec(message).unwrap();
+    stream
+        .write_all(&(payload.len() as u32).to_be_bytes())
+        .unwrap();
+    stream.write_all(&payload).unwrap();
+}
+
+// Clients that skip the handshake should still be served unframed messages.
+#[test]
+fn unframed_without_handshake() {
+    let addr = "127.0.0.1:4033";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = start_server(addr, &temp_dir);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, r#"{{"Set":{{"key":"key1","value":"value1"}}}}"#).unwrap();
+    write!(stream, r#"{{"Get":{{"key":"key1"}}}}"#).unwrap();
+    let mut reader = serde_json::Deserializer::from_reader(BufReader::new(&stream)).into_iter();
+    let set: Value = reader.next().unwrap().unwrap();
+    let get: Value = reader.next().unwrap().unwrap();
+    assert_eq!(set, json!({ "Ok": null }));
+    assert_eq!(get, json!({ "Ok": "value1" }));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// After a version 2 handshake, malformed frames should get an error reply
+// and oversized ones should close the connection.
+#[test]
+fn framed_after_handshake() {
+    let addr = "127.0.0.1:4034";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = start_server(addr, &temp_dir);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, r#"{{"Hello":{{"version":2}}}}"#).unwrap();
+    // unbuffered, so that nothing past the reply is consumed
+    let hello = Value::deserialize(&mut serde_json::Deserializer::from_reader(&stream)).unwrap();
+    assert_eq!(hello["Ok"]["version"], 2);
+
+    write_frame(&mut stream, &json!({ "Get": { "key": "key1" } }));
+    assert_eq!(read_frame(&mut stream), json!({ "Ok": null }));
+
+    write_frame(&mut stream, &json!({ "Bogus": {} }));
+    assert!(read_frame(&mut stream)["Err"].is_string());
+    write_frame(&mut stream, &json!({ "Get": { "key": "key1" } }));
+    assert_eq!(read_frame(&mut stream), json!({ "Ok": null }));
+
+    stream.write_all(&u32::MAX.to_be_bytes()).unwrap();
+    let error = read_frame(&mut stream);
+    assert!(error["Err"].as_str().unwrap().contains("exceeds"));
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Once zstd is agreed in a version 5 handshake, large replies should come
+// compressed, flagged by the top bit of their length, while small ones and
+// uncompressed requests go through as before.
+#[test]
+fn compressed_frames() {
+    let addr = "127.0.0.1:4060";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = start_server(addr, &temp_dir);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        r#"{{"Hello":{{"version":5,"compression":["lz4","zstd"]}}}}"#
+    )
+    .unwrap();
+    let hello = Value::deserialize(&mut serde_json::Deserializer::from_reader(&stream)).unwrap();
+    assert_eq!(hello["Ok"]["compression"], json!(["zstd"]));
+
+    let value = "value".repeat(10_000);
+    write_frame(
+        &mut stream,
+        &json!({ "Set": { "key": "key1", "value": value } }),
+    );
+    assert_eq!(read_frame(&mut stream), json!({ "Ok": null }));
+
+    write_frame(&mut stream, &json!({ "Get": { "key": "key1" } }));
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).unwrap();
+    let header = u32::from_be_bytes(header);
+    assert_ne!(header & 1 << 31, 0);
+    let len = (header & !(1 << 31)) as usize;
+    assert!(len < value.len() / 10);
+    stream.read_exact(&mut vec![0; len]).unwrap();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
@@ -0,0 +1,89 @@
+use assert_cmd::prelude::*;
+use kvs::{Event, KvsClient};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Subscribers should only receive events for keys matching their prefix.
+#[test]
+fn subscribe_prefix() {
+    let addr = "127.0.0.1:4008";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut events = KvsClient::connect(addr)
+        .unwrap()
+        .subscribe("user:".to_owned())
+        .unwrap();
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("user:1".to_owned(), "alice".to_owned()).unwrap();
+    client.set("other:1".to_owned(), "bob".to_owned()).unwrap();
+    client.remove("user:1".to_owned()).unwrap();
+
+    assert_eq!(
+        events.next().unwrap().unwrap(),
+        Event::Set {
+            key: "user:1".to_owned(),
+            value: "alice".to_owned()
+        }
+    );
+    assert_eq!(
+        events.next().unwrap().unwrap(),
+        Event::Removed {
+            key: "user:1".to_owned()
+        }
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A watch should fire on the next change of its key and time out otherwise.
+#[test]
+fn watch_key() {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut watcher = KvsClient::connect(addr).unwrap();
+    let handle = watcher
+        .watch("key1".to_owned(), Duration::from_secs(5))
+        .unwrap();
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key10".to_owned(), "other".to_owned()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        handle.wait().unwrap(),
+        Some(Event::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned()
+        })
+    );
+
+    let handle = watcher
+        .watch("key1".to_owned(), Duration::from_millis(100))
+        .unwrap();
+    assert_eq!(handle.wait().unwrap(), None);
+    // the connection stays usable after a watch
+    assert_eq!(
+        watcher.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
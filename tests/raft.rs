@@ -0,0 +1,128 @@
+#![cfg(feature = "raft")]
+
+use assert_cmd::prelude::*;
+use kvs::{KvsClient, ReadConsistency};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const CLIENT_ADDRS: [&str; 3] = ["127.0.0.1:4012", "127.0.0.1:4013", "127.0.0.1:4014"];
+const RAFT_ADDRS: [&str; 3] = ["127.0.0.1:4112", "127.0.0.1:4113", "127.0.0.1:4114"];
+
+fn spawn_node(i: usize, temp_dir: &TempDir) -> Child {
+    let mut args = vec!["--addr", CLIENT_ADDRS[i], "--raft-addr", RAFT_ADDRS[i]];
+    for (j, peer) in RAFT_ADDRS.iter().enumerate() {
+        if j != i {
+            args.push("--raft-peer");
+            args.push(peer);
+        }
+    }
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&args)
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap()
+}
+
+/// Sets `key` through whichever live node accepts writes, returning its index.
+fn set_on_leader(alive: &[usize], key: &str, value: &str) -> usize {
+    for _ in 0..50 {
+        for &i in alive {
+            let mut client = KvsClient::connect(CLIENT_ADDRS[i]).unwrap();
+            if client.set(key.to_owned(), value.to_owned()).is_ok() {
+                return i;
+            }
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    panic!("no leader elected");
+}
+
+// Writes go through the elected leader and reach every node, also after
+// the leader dies.
+#[test]
+fn cluster_replicates_writes() {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let mut nodes: Vec<Child> = (0..3).map(|i| spawn_node(i, &dirs[i])).collect();
+    thread::sleep(Duration::from_secs(1));
+
+    let leader = set_on_leader(&[0, 1, 2], "key1", "value1");
+    thread::sleep(Duration::from_millis(300));
+    for addr in &CLIENT_ADDRS {
+        let mut client = KvsClient::connect(addr).unwrap();
+        assert_eq!(
+            client.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+    }
+    // only the leader serves linearizable reads
+    for (i, addr) in CLIENT_ADDRS.iter().enumerate() {
+        let mut client = KvsClient::connect(addr).unwrap();
+        let read = client.get_with_consistency("key1".to_owned(), ReadConsistency::Linearizable);
+        if i == leader {
+            assert_eq!(read.unwrap(), Some("value1".to_owned()));
+        } else {
+            assert!(read.is_err());
+        }
+    }
+
+    nodes[leader].kill().expect("node exited before killed");
+    nodes[leader].wait().expect("failed to wait on node");
+    let alive: Vec<usize> = (0..3).filter(|&i| i != leader).collect();
+    set_on_leader(&alive, "key2", "value2");
+    thread::sleep(Duration::from_millis(300));
+    for &i in &alive {
+        let mut client = KvsClient::connect(CLIENT_ADDRS[i]).unwrap();
+        assert_eq!(
+            client.get("key2".to_owned()).unwrap(),
+            Some("value2".to_owned())
+        );
+    }
+
+    // enough writes for the log to be compacted, from clients in parallel
+    // as each waits for a heartbeat
+    let leader = set_on_leader(&alive, "key3", "value3");
+    let writers: Vec<_> = (0..40)
+        .map(|writer| {
+            thread::spawn(move || {
+                let mut client = KvsClient::connect(CLIENT_ADDRS[leader]).unwrap();
+                for i in 0..30 {
+                    let key = format!("bulk{}-{}", writer, i);
+                    client.set(key, "value".to_owned()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    // the node that was down missed compacted entries, and catches up
+    // from a snapshot
+    let dead = (0..3).find(|i| !alive.contains(i)).unwrap();
+    nodes[dead] = spawn_node(dead, &dirs[dead]);
+    let mut caught_up = false;
+    for _ in 0..50 {
+        thread::sleep(Duration::from_millis(200));
+        let mut client = match KvsClient::connect(CLIENT_ADDRS[dead]) {
+            Ok(client) => client,
+            Err(_) => continue,
+        };
+        if client.get("bulk39-29".to_owned()).unwrap().is_some() {
+            assert_eq!(
+                client.get("key2".to_owned()).unwrap(),
+                Some("value2".to_owned())
+            );
+            caught_up = true;
+            break;
+        }
+    }
+    assert!(caught_up, "restarted node did not catch up");
+
+    for node in &mut nodes {
+        node.kill().expect("node exited before killed");
+        node.wait().expect("failed to wait on node");
+    }
+}
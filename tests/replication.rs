@@ -0,0 +1,312 @@
+use kvs::{KvStore, KvsClient, KvsEngine, KvsReplicaClient, MyError, ReadConsistency};
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::{free_addr, spawn_server, wait_until};
+
+// A replica should start from the leader's data and follow its changes.
+#[test]
+fn replica_follows_leader() {
+    let (leader_addr, replica_addr) = (&free_addr(), &free_addr());
+    let leader_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    let mut leader = spawn_server(&leader_dir, &["--addr", leader_addr]);
+
+    let mut client = KvsClient::connect(leader_addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let mut replica = spawn_server(
+        &replica_dir,
+        &["--addr", replica_addr, "--replica-of", leader_addr],
+    );
+    let mut replica_client = KvsClient::connect(replica_addr).unwrap();
+    assert_eq!(
+        replica_client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    client.remove("key1".to_owned()).unwrap();
+    wait_until(|| replica_client.get("key1".to_owned()).unwrap().is_none());
+    assert_eq!(
+        replica_client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+    assert_eq!(replica_client.get("key1".to_owned()).unwrap(), None);
+
+    // replicas refuse writes, and reads that must be fresh
+    assert!(replica_client
+        .set("key3".to_owned(), "value3".to_owned())
+        .is_err());
+    assert!(replica_client
+        .get_with_consistency("key2".to_owned(), ReadConsistency::Leader)
+        .is_err());
+    assert_eq!(
+        client
+            .get_with_consistency("key2".to_owned(), ReadConsistency::Linearizable)
+            .unwrap(),
+        Some("value2".to_owned())
+    );
+
+    replica.kill().expect("replica exited before killed");
+    replica.wait().expect("failed to wait on replica");
+    leader.kill().expect("leader exited before killed");
+    leader.wait().expect("failed to wait on leader");
+}
+
+// A snapshot larger than a sync chunk should reach the replica whole.
+#[test]
+fn replica_syncs_chunked_snapshot() {
+    let (leader_addr, replica_addr) = (&free_addr(), &free_addr());
+    let leader_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    let mut leader = spawn_server(&leader_dir, &["--addr", leader_addr]);
+
+    let mut client = KvsClient::connect(leader_addr).unwrap();
+    // three chunks of 1 MiB, the last one short
+    let value = "x".repeat(100 * 1024);
+    for i in 0..25 {
+        client.set(format!("key{:02}", i), value.clone()).unwrap();
+    }
+
+    let mut replica = spawn_server(
+        &replica_dir,
+        &["--addr", replica_addr, "--replica-of", leader_addr],
+    );
+    let mut replica_client = KvsClient::connect(replica_addr).unwrap();
+    assert_eq!(replica_client.stats().unwrap().key_count, 25);
+    assert_eq!(replica_client.get("key24".to_owned()).unwrap(), Some(value));
+
+    replica.kill().expect("replica exited before killed");
+    replica.wait().expect("failed to wait on replica");
+    leader.kill().expect("leader exited before killed");
+    leader.wait().expect("failed to wait on leader");
+}
+
+// With `sync:1`, writes should be acknowledged only once a replica applied
+// them, and time out while none is attached.
+#[test]
+fn sync_consistency_waits_for_replica() {
+    let (leader_addr, replica_addr) = (&free_addr(), &free_addr());
+    let leader_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    let mut leader = spawn_server(
+        &leader_dir,
+        &[
+            "--addr",
+            leader_addr,
+            "--consistency",
+            "sync:1",
+            "--request-timeout",
+            "500",
+        ],
+    );
+
+    let mut client = KvsClient::connect(leader_addr).unwrap();
+    assert!(matches!(
+        client.set("key1".to_owned(), "value1".to_owned()),
+        Err(MyError::Timeout)
+    ));
+    // applied by the leader all the same
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    let mut replica = spawn_server(
+        &replica_dir,
+        &["--addr", replica_addr, "--replica-of", leader_addr],
+    );
+    let mut replica_client = KvsClient::connect(replica_addr).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    client.remove("key1".to_owned()).unwrap();
+    assert_eq!(
+        replica_client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+    assert_eq!(replica_client.get("key1".to_owned()).unwrap(), None);
+
+    replica.kill().expect("replica exited before killed");
+    replica.wait().expect("failed to wait on replica");
+    // until the leader notices the replica is gone
+    wait_until(|| {
+        matches!(
+            client.set("key3".to_owned(), "value3".to_owned()),
+            Err(MyError::Timeout)
+        )
+    });
+
+    leader.kill().expect("leader exited before killed");
+    leader.wait().expect("failed to wait on leader");
+}
+
+// Reads should go to the replicas, and stay on the primary for a while
+// after a write when reading one's writes is required.
+#[test]
+fn replica_client_routes_reads() {
+    let leader_addr = &free_addr();
+    let replica_addrs = [free_addr(), free_addr()];
+    let leader_dir = TempDir::new().unwrap();
+    let replica_dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let mut leader = spawn_server(&leader_dir, &["--addr", leader_addr]);
+    let mut replicas: Vec<_> = replica_addrs
+        .iter()
+        .zip(&replica_dirs)
+        .map(|(addr, dir)| spawn_server(dir, &["--addr", addr, "--replica-of", leader_addr]))
+        .collect();
+
+    let mut client = KvsReplicaClient::connect(leader_addr, &replica_addrs).unwrap();
+    let mut pinned = KvsReplicaClient::connect(leader_addr, &replica_addrs)
+        .unwrap()
+        .with_read_your_writes(Duration::from_secs(60));
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    pinned.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(
+        pinned.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+    for addr in &replica_addrs {
+        let mut replica_client = KvsClient::connect(addr).unwrap();
+        wait_until(|| replica_client.get("key2".to_owned()).unwrap().is_some());
+    }
+
+    leader.kill().expect("leader exited before killed");
+    leader.wait().expect("failed to wait on leader");
+    for _ in &replicas {
+        assert_eq!(
+            client.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+    }
+    let (keys, _) = client.scan(None, 10, "key*".to_owned()).unwrap();
+    assert_eq!(keys, vec!["key1".to_owned(), "key2".to_owned()]);
+    // pinned to the primary, which is gone
+    assert!(pinned.get("key2".to_owned()).is_err());
+
+    for replica in &mut replicas {
+        replica.kill().expect("replica exited before killed");
+        replica.wait().expect("failed to wait on replica");
+    }
+}
+
+// A restarted replica should resume the change stream where it stopped,
+// rather than load the whole store again.
+#[test]
+fn replica_resumes_change_stream() {
+    let (leader_addr, replica_addr) = (&free_addr(), &free_addr());
+    let leader_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    let mut leader = spawn_server(&leader_dir, &["--addr", leader_addr]);
+    let replica_args = ["--addr", replica_addr, "--replica-of", leader_addr];
+
+    let mut client = KvsClient::connect(leader_addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let mut replica = spawn_server(&replica_dir, &replica_args);
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    let mut replica_client = KvsClient::connect(replica_addr).unwrap();
+    wait_until(|| replica_client.get("key2".to_owned()).unwrap().is_some());
+    replica.kill().expect("replica exited before killed");
+    replica.wait().expect("failed to wait on replica");
+    assert!(replica_dir.path().join("replica.json").exists());
+
+    client.set("key3".to_owned(), "value3".to_owned()).unwrap();
+    client.remove("key1".to_owned()).unwrap();
+    // a full sync would drop a key the leader does not have
+    let mut store = KvStore::open(replica_dir.path()).unwrap();
+    store.set("local".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+
+    let mut replica = spawn_server(&replica_dir, &replica_args);
+    let mut replica_client = KvsClient::connect(replica_addr).unwrap();
+    // the changes it missed follow once it serves
+    wait_until(|| {
+        let (keys, _) = replica_client.scan(None, 10, "*".to_owned()).unwrap();
+        keys == ["key2", "key3", "local"]
+    });
+
+    replica.kill().expect("replica exited before killed");
+    replica.wait().expect("failed to wait on replica");
+    leader.kill().expect("leader exited before killed");
+    leader.wait().expect("failed to wait on leader");
+}
+
+// Lists, hashes, sets, sorted sets and expiries should reach a replica,
+// from the snapshot and from the change stream.
+#[test]
+fn replica_follows_collections_and_expiries() {
+    let (leader_addr, replica_addr) = (&free_addr(), &free_addr());
+    let leader_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    let mut leader = spawn_server(&leader_dir, &["--addr", leader_addr]);
+
+    let mut client = KvsClient::connect(leader_addr).unwrap();
+    client.rpush("list".to_owned(), "a".to_owned()).unwrap();
+    client.rpush("list".to_owned(), "b".to_owned()).unwrap();
+    client
+        .hset("hash".to_owned(), "field1".to_owned(), "value1".to_owned())
+        .unwrap();
+    client
+        .set_with_ttl(
+            "temp".to_owned(),
+            "value".to_owned(),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+
+    let mut replica = spawn_server(
+        &replica_dir,
+        &["--addr", replica_addr, "--replica-of", leader_addr],
+    );
+    let mut replica_client = KvsClient::connect(replica_addr).unwrap();
+    assert_eq!(
+        replica_client.lrange("list".to_owned(), 0, -1).unwrap(),
+        vec!["a".to_owned(), "b".to_owned()]
+    );
+    assert_eq!(
+        replica_client.hgetall("hash".to_owned()).unwrap(),
+        vec![("field1".to_owned(), "value1".to_owned())]
+    );
+    assert!(replica_client.ttl("temp".to_owned()).unwrap().is_some());
+
+    client.lpush("list".to_owned(), "c".to_owned()).unwrap();
+    client.lpop("list".to_owned()).unwrap();
+    client.lpop("list".to_owned()).unwrap();
+    client.hdel("hash".to_owned(), "field1".to_owned()).unwrap();
+    client.sadd("set".to_owned(), "member".to_owned()).unwrap();
+    client
+        .zadd("zset".to_owned(), "member".to_owned(), 2.5)
+        .unwrap();
+    client.persist("temp".to_owned()).unwrap();
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    client
+        .expire("key".to_owned(), Duration::from_secs(60))
+        .unwrap();
+    wait_until(|| replica_client.ttl("key".to_owned()).unwrap().is_some());
+
+    assert_eq!(
+        replica_client.lrange("list".to_owned(), 0, -1).unwrap(),
+        vec!["b".to_owned()]
+    );
+    assert!(replica_client
+        .hgetall("hash".to_owned())
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        replica_client.smembers("set".to_owned()).unwrap(),
+        vec!["member".to_owned()]
+    );
+    assert_eq!(
+        replica_client
+            .zrange_by_score("zset".to_owned(), 0.0, 10.0)
+            .unwrap(),
+        vec![("member".to_owned(), 2.5)]
+    );
+    assert_eq!(replica_client.ttl("temp".to_owned()).unwrap(), None);
+    assert!(replica_client.ttl("key".to_owned()).unwrap().is_some());
+
+    replica.kill().expect("replica exited before killed");
+    replica.wait().expect("failed to wait on replica");
+    leader.kill().expect("leader exited before killed");
+    leader.wait().expect("failed to wait on leader");
+}
@@ -0,0 +1,63 @@
+#![cfg(feature = "rocksdb")]
+
+use kvs::{KvsEngine, KvsReader, MyError, Result, RocksKvsEngine, WriteBatch};
+use tempfile::TempDir;
+
+// The RocksDB engine should behave as the others do, and keep its pairs
+// when reopened.
+#[test]
+fn rocksdb_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = RocksKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.name(), "rocksdb");
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("other".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.contains_key("key2".to_owned())?);
+    assert_eq!(store.len()?, 3);
+    assert_eq!(
+        store.scan("key".to_owned())?,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+
+    store.remove("key1".to_owned())?;
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(MyError::KeyNotFound)
+    ));
+    let reader = store.reader();
+    assert_eq!(reader.get("key1".to_owned())?, None);
+
+    // a batch with a remove of a missing key applies nothing
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.remove("missing".to_owned());
+    assert!(matches!(
+        store.write_batch(batch),
+        Err(MyError::KeyNotFound)
+    ));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "value3".to_owned());
+    batch.remove("key2".to_owned());
+    store.write_batch(batch)?;
+    assert_eq!(
+        reader.scan("key".to_owned())?,
+        vec![("key3".to_owned(), "value3".to_owned())]
+    );
+
+    store.shutdown()?;
+    drop(reader);
+    drop(store);
+    let mut store = RocksKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.stats()?.key_count, 2);
+    store.clear()?;
+    assert_eq!(store.len()?, 0);
+    Ok(())
+}
@@ -0,0 +1,106 @@
+#![cfg(feature = "scripting")]
+
+use kvs::{KvsClient, MyError};
+use tempfile::TempDir;
+
+mod common;
+use common::{free_addr, spawn_server};
+
+/// Lets a key grow by an `x` on each run, up to three, then answers
+/// `limited`: a rate limiter counting in the length of the value.
+const LIMITER: &str = r#"
+(module
+  (import "kvs" "arg" (func $arg (param i32 i32 i32) (result i64)))
+  (import "kvs" "get" (func $get (param i32 i32 i32 i32) (result i64)))
+  (import "kvs" "set" (func $set (param i32 i32 i32 i32)))
+  (import "kvs" "output" (func $output (param i32 i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 200) "limited")
+  (func (export "run")
+    (local $key_len i32)
+    (local $len i32)
+    (local.set $key_len
+      (i32.wrap_i64 (call $arg (i32.const 0) (i32.const 0) (i32.const 64))))
+    (local.set $len
+      (i32.wrap_i64
+        (call $get (i32.const 0) (local.get $key_len) (i32.const 100) (i32.const 64))))
+    (if (i32.lt_s (local.get $len) (i32.const 0))
+      (then (local.set $len (i32.const 0))))
+    (if (i32.ge_s (local.get $len) (i32.const 3))
+      (then
+        (call $output (i32.const 200) (i32.const 7))
+        (return)))
+    (i32.store8 (i32.add (i32.const 100) (local.get $len)) (i32.const 120))
+    (local.set $len (i32.add (local.get $len) (i32.const 1)))
+    (call $set (i32.const 0) (local.get $key_len) (i32.const 100) (local.get $len))
+    (call $output (i32.const 100) (local.get $len))))
+"#;
+
+/// Sets `rate:bob`, then traps.
+const TRAPPING: &str = r#"
+(module
+  (import "kvs" "set" (func $set (param i32 i32 i32 i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "rate:bob")
+  (func (export "run")
+    (call $set (i32.const 0) (i32.const 8) (i32.const 0) (i32.const 8))
+    unreachable))
+"#;
+
+/// Never returns.
+const LOOPING: &str = r#"
+(module
+  (memory (export "memory") 1)
+  (func (export "run")
+    (loop $forever (br $forever))))
+"#;
+
+// A script should read and write the keys under its prefix in one step,
+// and write nothing when it fails.
+#[test]
+fn run_script() {
+    let addr = &free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = spawn_server(&temp_dir, &["--addr", addr]);
+    let mut client = KvsClient::connect(addr).unwrap();
+    let limiter = wat::parse_str(LIMITER).unwrap();
+    let run = |client: &mut KvsClient, key: &str| {
+        client.run_script(&limiter, "rate:".to_owned(), vec![key.to_owned()])
+    };
+
+    for expected in &["x", "xx", "xxx", "limited", "limited"] {
+        assert_eq!(
+            run(&mut client, "rate:alice").unwrap().as_deref(),
+            Some(*expected)
+        );
+    }
+    assert_eq!(
+        client.get("rate:alice".to_owned()).unwrap(),
+        Some("xxx".to_owned())
+    );
+
+    // keys outside the prefix are out of reach
+    assert!(matches!(
+        run(&mut client, "other"),
+        Err(MyError::PermissionDenied)
+    ));
+    assert_eq!(client.get("other".to_owned()).unwrap(), None);
+
+    let trapping = wat::parse_str(TRAPPING).unwrap();
+    assert!(client
+        .run_script(&trapping, "rate:".to_owned(), Vec::new())
+        .is_err());
+    assert_eq!(client.get("rate:bob".to_owned()).unwrap(), None);
+
+    let looping = wat::parse_str(LOOPING).unwrap();
+    match client.run_script(&looping, String::new(), Vec::new()) {
+        Err(MyError::Server { message, .. }) => assert!(message.contains("fuel"), "{}", message),
+        result => panic!("the script was not stopped: {:?}", result),
+    }
+    assert!(client
+        .run_script(b"not wasm", String::new(), Vec::new())
+        .is_err());
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
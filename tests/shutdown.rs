@@ -0,0 +1,95 @@
+#![cfg(unix)]
+
+use assert_cmd::prelude::*;
+use kvs::{KvsClient, MyError};
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// SIGTERM should stop the server cleanly, leaving the store ready to reopen.
+#[test]
+fn sigterm_shuts_down_cleanly() {
+    let addr = "127.0.0.1:4021";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    let status = child.wait().expect("failed to wait on server");
+    assert!(status.success());
+    assert!(temp_dir.path().join("clean-shutdown").exists());
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(!temp_dir.path().join("clean-shutdown").exists());
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// SIGHUP should apply the new settings of the configuration file to new
+// requests, keeping the connections already open.
+#[test]
+fn sighup_reloads_config() {
+    let addr = "127.0.0.1:4054";
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(&config, "auth_token = \"old\"\n").unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .arg("--config")
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut open = KvsClient::connect_with_auth(addr, "old".to_owned()).unwrap();
+    open.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    fs::write(&config, "auth_token = \"new\"\nslow_log_ms = 0\n").unwrap();
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGHUP) };
+    thread::sleep(Duration::from_millis(500));
+
+    assert_eq!(
+        open.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(matches!(
+        KvsClient::connect_with_auth(addr, "old".to_owned()),
+        Err(MyError::Unauthorized)
+    ));
+    let mut client = KvsClient::connect_with_auth(addr, "new".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert!(!client.slow_log().unwrap().is_empty());
+
+    // a broken file changes nothing
+    fs::write(&config, "auth_token = \n").unwrap();
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGHUP) };
+    thread::sleep(Duration::from_millis(500));
+    KvsClient::connect_with_auth(addr, "new".to_owned()).unwrap();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
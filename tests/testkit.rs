@@ -0,0 +1,80 @@
+#![cfg(feature = "testing")]
+
+use kvs::testkit::{check_restarts, check_state, Op, Workload};
+use kvs::{KvStore, KvStoreOptions, LsmEngine, MemEngine, Result, SledKvsEngine};
+use std::collections::BTreeMap;
+use tempfile::TempDir;
+
+// A workload should come out the same for a seed, with removes of existing
+// keys only, and replay onto the state it describes.
+#[test]
+fn workloads() -> Result<()> {
+    let workload = Workload::new(1).with_ops(300).with_keys(5);
+    let ops = workload.ops();
+    assert_eq!(ops.len(), 300);
+    assert_eq!(ops, Workload::new(1).with_ops(300).with_keys(5).ops());
+    assert_ne!(ops, Workload::new(2).with_ops(300).with_keys(5).ops());
+    assert!(ops.iter().any(|op| matches!(op, Op::Batch(_))));
+    assert!(ops.iter().any(|op| matches!(op, Op::Remove { .. })));
+    assert!(!Workload::new(1)
+        .with_max_batch(1)
+        .ops()
+        .iter()
+        .any(|op| matches!(op, Op::Batch(_))));
+
+    let mut store = MemEngine::new();
+    let mut expected = BTreeMap::new();
+    for op in &ops {
+        op.apply(&mut store)?;
+        let writes = match op {
+            Op::Batch(batch) => batch.clone(),
+            op => vec![op.clone()],
+        };
+        for write in writes {
+            match write {
+                Op::Set { key, value } => expected.insert(key, value),
+                Op::Remove { key } => expected.remove(&key),
+                Op::Batch(_) => unreachable!(),
+            };
+        }
+    }
+    assert!(expected.len() <= 5);
+    check_state(&mut store, &expected)?;
+    expected.insert("testkit:missing".to_owned(), "value".to_owned());
+    assert!(check_state(&mut store, &expected).is_err());
+    Ok(())
+}
+
+// The engines on disk should keep every acknowledged write through failed
+// writes and restarts, compaction included.
+#[test]
+fn engines_restart() -> Result<()> {
+    for seed in 0..4 {
+        let workload = Workload::new(seed).with_restarts(10);
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default().with_compaction_threshold(1024);
+        let report = check_restarts(&workload, || {
+            KvStore::open_with_options(temp_dir.path(), options.clone())
+        })?;
+        assert_eq!(report.ops, 200);
+        assert_eq!(report.restarts, 10);
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check_restarts(&workload, || LsmEngine::open(temp_dir.path()))?;
+    }
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_restarts(&Workload::new(0), || SledKvsEngine::open(temp_dir.path()))?;
+    Ok(())
+}
+
+// An engine forgetting its writes should fail the check, naming the seed.
+#[test]
+fn forgetful_engine_fails() {
+    let workload = Workload::new(42).with_restarts(1);
+    let error = check_restarts(&workload, || Ok(MemEngine::new())).unwrap_err();
+    assert!(error.to_string().contains("seed 42"));
+
+    let report = check_restarts(&workload.with_restarts(0), || Ok(MemEngine::new())).unwrap();
+    assert_eq!(report.restarts, 0);
+    assert!(report.keys > 0);
+}
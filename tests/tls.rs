@@ -0,0 +1,52 @@
+use kvs::{ClientTlsConfig, KvStore, KvsClient, Result, Server, ServerTlsConfig};
+use std::fs;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+use common::free_addr;
+
+// Clients trusting the certificate of the server talk to it over TLS, and
+// others cannot talk to it at all.
+#[test]
+fn tls_connections() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let cert =
+        rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned(), "kvs.example".to_owned()])
+            .unwrap();
+    let (cert_path, key_path) = (
+        temp_dir.path().join("cert.pem"),
+        temp_dir.path().join("key.pem"),
+    );
+    fs::write(&cert_path, cert.cert.pem())?;
+    fs::write(&key_path, cert.signing_key.serialize_pem())?;
+
+    let addr: SocketAddr = free_addr().parse().unwrap();
+    let server = Server::new(KvStore::open(temp_dir.path().join("data"))?)
+        .with_tls(ServerTlsConfig::from_pem_files(&cert_path, &key_path)?);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.open(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let tls = ClientTlsConfig::from_ca_file(&cert_path)?;
+    let mut client = KvsClient::connect_tls(addr, tls.clone())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let mut named = KvsClient::connect_tls(addr, tls.clone().with_server_name("kvs.example")?)?;
+    assert_eq!(named.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // the certificate is not for that name
+    assert!(KvsClient::connect_tls(addr, tls.with_server_name("other.example")?).is_err());
+    // nor trusted by a client trusting another one
+    let other = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()]).unwrap();
+    let other_path = temp_dir.path().join("other.pem");
+    fs::write(&other_path, other.cert.pem())?;
+    assert!(KvsClient::connect_tls(addr, ClientTlsConfig::from_ca_file(&other_path)?).is_err());
+    assert!(KvsClient::connect(addr).is_err());
+
+    shutdown.shutdown();
+    handle.join().unwrap()
+}
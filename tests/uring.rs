@@ -0,0 +1,64 @@
+#![cfg(feature = "uring")]
+
+use kvs::{KvStore, KvStoreOptions, KvsEngine, KvsReader, Result, SyncPolicy};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::TempDir;
+
+// A store writing and reading through io_uring should keep the same data,
+// separated values, compactions and synced writes included
+#[test]
+fn io_uring_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .with_io_uring()
+        .with_sync_policy(SyncPolicy::Always)
+        .with_value_threshold(64)
+        .with_compaction_threshold(4096);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let large = "v".repeat(100);
+    for iter in 0..50 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        store.set("large".to_owned(), format!("{}{}", large, iter))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert!(store.stats()?.compactions > 0);
+    let reader = store.reader();
+    assert_eq!(reader.get("key1".to_owned())?, Some("49".to_owned()));
+    assert_eq!(store.get("large".to_owned())?, Some(format!("{}49", large)));
+
+    let commit = store.defer_syncs()?.expect("syncs should be deferred");
+    let store = Arc::new(Mutex::new(store));
+    let writers: Vec<_> = (0..4)
+        .map(|writer| {
+            let store = Arc::clone(&store);
+            let commit = Arc::clone(&commit);
+            thread::spawn(move || -> Result<()> {
+                for iter in 0..20 {
+                    let key = format!("writer{}", writer);
+                    store.lock().unwrap().set(key, format!("{}", iter))?;
+                    commit.sync()?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key9".to_owned())?, Some("49".to_owned()));
+    assert_eq!(store.get("large".to_owned())?, Some(format!("{}49", large)));
+    for writer in 0..4 {
+        assert_eq!(
+            store.get(format!("writer{}", writer))?,
+            Some("19".to_owned())
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::Child;
+use tempfile::TempDir;
+
+mod common;
+use common::free_addr;
+
+const MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+fn send_frame(stream: &mut TcpStream, head: u8, payload: &[u8]) {
+    let mut frame = vec![head, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&MASK);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ MASK[i % 4]));
+    stream.write_all(&frame).unwrap();
+}
+
+fn send_text(stream: &mut TcpStream, text: &str) {
+    send_frame(stream, 0x81, text.as_bytes());
+}
+
+fn read_frame(reader: &mut impl Read) -> (u8, Vec<u8>) {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head).unwrap();
+    let mut payload = vec![0u8; (head[1] & 0x7F) as usize];
+    reader.read_exact(&mut payload).unwrap();
+    (head[0], payload)
+}
+
+fn read_text(reader: &mut impl Read) -> String {
+    let (head, payload) = read_frame(reader);
+    assert_eq!(head, 0x81);
+    String::from_utf8(payload).unwrap()
+}
+
+/// Sends an upgrade request with `headers` and returns the status line and
+/// headers of the answer.
+fn handshake(ws_addr: &str, headers: &str) -> (TcpStream, BufReader<TcpStream>, String) {
+    let mut stream = TcpStream::connect(ws_addr).unwrap();
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+         {}\r\n",
+        ws_addr, headers
+    )
+    .unwrap();
+
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut response = String::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line == "\r\n" || line.is_empty() {
+            break;
+        }
+        response.push_str(&line);
+    }
+    (stream, reader, response)
+}
+
+fn spawn_server(temp_dir: &TempDir, ws_addr: &str) -> Child {
+    let addr = &free_addr();
+    common::spawn_server(
+        temp_dir,
+        &[
+            "--addr",
+            addr,
+            "--ws-addr",
+            ws_addr,
+            "--ws-origin",
+            "https://app.example.com",
+        ],
+    )
+}
+
+// A browser-style client should be able to upgrade and run commands.
+#[test]
+fn websocket_set_get() {
+    let temp_dir = TempDir::new().unwrap();
+    let ws_addr = &free_addr();
+    let mut child = spawn_server(&temp_dir, ws_addr);
+
+    let (mut stream, mut reader, response) = handshake(
+        ws_addr,
+        "Sec-WebSocket-Version: 13\r\n\
+         Origin: https://app.example.com\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 101"));
+    assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+    send_text(&mut stream, r#"{"Set":{"key":"key1","value":"value1"}}"#);
+    assert_eq!(read_text(&mut reader), r#"{"Ok":null}"#);
+    send_text(&mut stream, r#"{"Get":{"key":"key1"}}"#);
+    assert_eq!(read_text(&mut reader), r#"{"Ok":"value1"}"#);
+    send_text(&mut stream, r#"{"Get":{"key":"key2"}}"#);
+    assert_eq!(read_text(&mut reader), r#"{"Ok":null}"#);
+
+    // A ping between the fragments of a message is answered, and the
+    // message still reassembled
+    send_frame(&mut stream, 0x01, br#"{"Get":"#);
+    send_frame(&mut stream, 0x89, b"hi");
+    send_frame(&mut stream, 0x80, br#"{"key":"key1"}}"#);
+    assert_eq!(read_frame(&mut reader), (0x8A, b"hi".to_vec()));
+    assert_eq!(read_text(&mut reader), r#"{"Ok":"value1"}"#);
+
+    // An unmasked frame fails the connection
+    stream.write_all(&[0x81, 0x02, b'{', b'}']).unwrap();
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// Upgrades from other origins, or of another protocol version, are refused.
+#[test]
+fn websocket_refused_handshakes() {
+    let temp_dir = TempDir::new().unwrap();
+    let ws_addr = &free_addr();
+    let mut child = spawn_server(&temp_dir, ws_addr);
+
+    let (_, _, response) = handshake(
+        ws_addr,
+        "Sec-WebSocket-Version: 13\r\n\
+         Origin: https://evil.example.com\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 403"));
+
+    let (_, _, response) = handshake(ws_addr, "Sec-WebSocket-Version: 8\r\n");
+    assert!(response.starts_with("HTTP/1.1 426"));
+    assert!(response.contains("Sec-WebSocket-Version: 13"));
+
+    // Clients outside a browser send no origin
+    let (_, _, response) = handshake(ws_addr, "Sec-WebSocket-Version: 13\r\n");
+    assert!(response.starts_with("HTTP/1.1 101"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}